@@ -27,6 +27,13 @@ fn chunk_slice(read: &'static [u8], chunker: impl Chunker) {
     }
 }
 
+fn chunk_borrowed(read: &'static [u8], chunker: impl Chunker) {
+    let iterator = chunker.chunk_borrowed(read);
+    for chunk in iterator {
+        black_box(chunk).unwrap();
+    }
+}
+
 fn bench_fastcdc(c: &mut Criterion) {
     let (zeros, random) = get_test_data(SIZE);
     // Intentinally leak zeros and random to get an &'static
@@ -54,6 +61,14 @@ fn bench_fastcdc(c: &mut Criterion) {
         b.iter(|| chunk_slice(random, FastCDC::default()))
     });
 
+    group.bench_function("borrowed zeros", |b| {
+        b.iter(|| chunk_borrowed(zeros, FastCDC::default()))
+    });
+
+    group.bench_function("borrowed random", |b| {
+        b.iter(|| chunk_borrowed(random, FastCDC::default()))
+    });
+
     group.finish();
 }
 
@@ -82,8 +97,52 @@ fn bench_buzhash(c: &mut Criterion) {
 
     group.bench_function("sliced random", |b| b.iter(|| chunk_slice(random, chunker)));
 
+    group.bench_function("borrowed zeros", |b| {
+        b.iter(|| chunk_borrowed(zeros, chunker))
+    });
+
+    group.bench_function("borrowed random", |b| {
+        b.iter(|| chunk_borrowed(random, chunker))
+    });
+
+    group.finish();
+}
+
+fn bench_static_size(c: &mut Criterion) {
+    let (zeros, random) = get_test_data(SIZE);
+    // Intentinally leak zeros and random to get an &'static
+    let zeros: &'static [u8] = Box::leak(Box::new(zeros));
+    let random: &'static [u8] = Box::leak(Box::new(random));
+    let mut group = c.benchmark_group("static_size");
+
+    let chunker = StaticSize::default();
+
+    group.throughput(Throughput::Bytes(SIZE as u64));
+    group.measurement_time(Duration::new(30, 0));
+    group.sample_size(30);
+
+    group.bench_function("boxed zeros", |b| {
+        b.iter(|| chunk_boxed(black_box(zeros), chunker))
+    });
+
+    group.bench_function("boxed random", |b| {
+        b.iter(|| chunk_boxed(black_box(random), chunker))
+    });
+
+    group.bench_function("sliced zeros", |b| b.iter(|| chunk_slice(zeros, chunker)));
+
+    group.bench_function("sliced random", |b| b.iter(|| chunk_slice(random, chunker)));
+
+    group.bench_function("borrowed zeros", |b| {
+        b.iter(|| chunk_borrowed(zeros, chunker))
+    });
+
+    group.bench_function("borrowed random", |b| {
+        b.iter(|| chunk_borrowed(random, chunker))
+    });
+
     group.finish();
 }
 
-criterion_group!(benches, bench_fastcdc, bench_buzhash);
+criterion_group!(benches, bench_fastcdc, bench_buzhash, bench_static_size);
 criterion_main!(benches);
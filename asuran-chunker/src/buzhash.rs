@@ -1,9 +1,14 @@
-use super::{Chunker, ChunkerError};
+#[cfg(feature = "std")]
+use super::{BorrowedChunks, Chunker, ChunkerError};
+use super::{SliceChunker, SliceChunks};
 
+use alloc::boxed::Box;
 use rand::{Rng, SeedableRng};
 use rand_chacha::ChaCha20Rng;
 
+#[cfg(feature = "std")]
 use std::collections::VecDeque;
+#[cfg(feature = "std")]
 use std::io::Read;
 
 /// Settings for a `BuzHash` `Chunker`
@@ -43,17 +48,102 @@ impl BuzHash {
     }
 }
 
+/// Mask bits used by `BuzHash::with_default`, for chunks averaging 2MiB
+pub const BUZHASH_DEFAULT_MASK_BITS: u32 = 21;
+
+/// Longest chunk `BuzHash::with_default` will produce, which is also the longest any of the
+/// chunkers produce with their default settings
+pub const BUZHASH_DEFAULT_MAX_SIZE: usize = 1 << (BUZHASH_DEFAULT_MASK_BITS + 2);
+
 impl BuzHash {
     pub fn with_default(nonce: u64) -> BuzHash {
-        Self::new(nonce, 4095, 21)
+        Self::new(nonce, 4095, BUZHASH_DEFAULT_MASK_BITS)
     }
 
-    #[cfg(test)]
+    #[cfg(all(test, feature = "std"))]
     fn with_default_testing(nonce: u64) -> BuzHash {
         Self::new(nonce, 4095, 14)
     }
 }
 
+impl SliceChunker for BuzHash {
+    fn slice_chunks<'a>(&self, slice: &'a [u8]) -> SliceChunks<'a> {
+        Box::new(BuzHashSliceChunks {
+            settings: *self,
+            slice,
+            offset: 0,
+            count: 0,
+            hash: 0,
+        })
+    }
+}
+
+/// `no_std` version of `BuzHashChunker`, over an in memory slice
+///
+/// As the whole input is available, the rolling hash's window is read directly out of the slice,
+/// rather than being buffered.
+pub struct BuzHashSliceChunks<'a> {
+    /// Settings for this `Chunker`
+    settings: BuzHash,
+    /// The slice being chunked
+    slice: &'a [u8],
+    /// Offset of the next byte to be hashed
+    offset: usize,
+    /// Bytes in the hash window
+    count: u32,
+    /// The current hash value
+    hash: u64,
+}
+
+impl<'a> BuzHashSliceChunks<'a> {
+    /// Hashes the byte at `offset` and returns the new hash value
+    fn hash_byte(&mut self) -> u64 {
+        let byte = self.slice[self.offset];
+        let hash = self.hash.rotate_left(1);
+        let tail = self.settings.table[byte as usize];
+        if self.count >= self.settings.window_size {
+            // Every byte before this one has been hashed, so the byte leaving the window is
+            // window_size bytes back
+            let head = self.slice[self.offset - self.settings.window_size as usize];
+            let head = self.settings.table[head as usize].rotate_left(self.settings.window_size);
+            self.hash = hash ^ head ^ tail;
+        } else {
+            self.count += 1;
+            self.hash = hash ^ tail;
+        }
+        self.hash
+    }
+}
+
+impl<'a> Iterator for BuzHashSliceChunks<'a> {
+    type Item = &'a [u8];
+    fn next(&mut self) -> Option<&'a [u8]> {
+        let start = self.offset;
+        let remaining = self.slice.len() - start;
+        if remaining == 0 {
+            None
+        } else if remaining <= self.settings.min_size {
+            // Too little is left to produce a min size chunk, so the rest is gathered up, without
+            // being hashed
+            self.offset = self.slice.len();
+            Some(&self.slice[start..])
+        } else {
+            let mut split = false;
+            while !split
+                && self.offset - start < self.settings.max_size
+                && self.offset < self.slice.len()
+            {
+                let hash = self.hash_byte();
+                self.offset += 1;
+                split = (hash & self.settings.mask == 0)
+                    && (self.offset - start >= self.settings.min_size);
+            }
+            Some(&self.slice[start..self.offset])
+        }
+    }
+}
+
+#[cfg(feature = "std")]
 impl Chunker for BuzHash {
     type Chunks = BuzHashChunker;
     fn chunk_boxed(&self, read: Box<dyn Read + Send + 'static>) -> Self::Chunks {
@@ -67,8 +157,12 @@ impl Chunker for BuzHash {
             eof: false,
         }
     }
+    fn chunk_borrowed<'a>(&self, slice: &'a [u8]) -> BorrowedChunks<'a> {
+        Box::new(self.slice_chunks(slice).map(Ok))
+    }
 }
 
+#[cfg(feature = "std")]
 pub struct BuzHashChunker {
     /// Settings for this `Chunker`
     settings: BuzHash,
@@ -85,6 +179,7 @@ pub struct BuzHashChunker {
     eof: bool,
 }
 
+#[cfg(feature = "std")]
 impl BuzHashChunker {
     /// Hashes one byte and returns the new hash value
     fn hash_byte(&mut self, byte: u8) -> u64 {
@@ -174,6 +269,7 @@ impl BuzHashChunker {
     }
 }
 
+#[cfg(feature = "std")]
 impl Iterator for BuzHashChunker {
     type Item = Result<Vec<u8>, ChunkerError>;
     fn next(&mut self) -> Option<Result<Vec<u8>, ChunkerError>> {
@@ -256,7 +352,7 @@ const TABLE: [u64; 256] = [
     0x305c_2dc4_ae74_30a7, 0xa592_fff4_6764_dca5, 0x6a3c_7ca0_1fb1_6edf, 0x2ea8_3c49_5a48_5b32,
 ];
 
-#[cfg(test)]
+#[cfg(all(test, feature = "std"))]
 mod tests {
     use super::*;
     use rand::RngCore;
@@ -285,6 +381,15 @@ mod tests {
         assert!(chunks.len() > 1);
     }
 
+    // Empty input should produce no chunks at all, rather than an empty chunk or an error
+    #[test]
+    fn empty_input() {
+        let chunker = BuzHash::with_default_testing(0);
+        assert_eq!(chunker.chunk(Cursor::new(Vec::<u8>::new())).count(), 0);
+        assert_eq!(chunker.chunk_slice(Vec::<u8>::new()).count(), 0);
+        assert_eq!(chunker.chunk_borrowed(&[]).count(), 0);
+    }
+
     // Data should be identical after reassembaly by simple concatenation
     #[test]
     fn reassemble_data() {
@@ -331,6 +436,11 @@ mod tests {
         }
     }
 
+    #[test]
+    fn default_max_size() {
+        assert_eq!(BuzHash::with_default(0).max_size, BUZHASH_DEFAULT_MAX_SIZE);
+    }
+
     // Verifies that this `Chunker`, at most, produces 1 under-sized chunk
     #[test]
     fn min_size() {
@@ -351,4 +461,48 @@ mod tests {
 
         assert!(undersized_count <= 1);
     }
+
+    // The borrowed path should produce exactly the same chunks as the owned path
+    #[test]
+    fn borrowed_matches_owned() {
+        let data = get_test_data();
+        let chunker = BuzHash::with_default_testing(0);
+        let owned_chunks = chunker
+            .chunk_slice(data.clone())
+            .map(|x| x.unwrap())
+            .collect::<Vec<_>>();
+        let borrowed_chunks = chunker
+            .chunk_borrowed(&data)
+            .map(|x| x.unwrap().to_vec())
+            .collect::<Vec<_>>();
+        assert_eq!(owned_chunks, borrowed_chunks);
+    }
+
+    // The no_std slice path should produce exactly the same chunks as the Read path, including
+    // at the edges around min_size and max_size
+    #[test]
+    fn slice_matches_read() {
+        let chunker = BuzHash::with_default_testing(0);
+        let data = get_test_data();
+        for length in &[
+            0,
+            1,
+            chunker.min_size,
+            chunker.min_size + 1,
+            chunker.max_size,
+            chunker.max_size * 2 + chunker.min_size,
+            data.len(),
+        ] {
+            let data = &data[..*length];
+            let read_chunks = chunker
+                .chunk(Cursor::new(data.to_vec()))
+                .map(|x| x.unwrap())
+                .collect::<Vec<_>>();
+            let slice_chunks = chunker
+                .slice_chunks(data)
+                .map(<[u8]>::to_vec)
+                .collect::<Vec<_>>();
+            assert_eq!(read_chunks, slice_chunks);
+        }
+    }
 }
@@ -1,5 +1,13 @@
-use super::{Chunker, ChunkerError};
+#[cfg(feature = "std")]
+use super::{BorrowedChunks, Chunker, ChunkerError};
+use super::{SliceChunker, SliceChunks};
 
+use alloc::boxed::Box;
+use core::num::NonZeroU64;
+use rand::{Rng, SeedableRng};
+use rand_chacha::ChaCha20Rng;
+
+#[cfg(feature = "std")]
 use std::io::Read;
 
 /// Settings for a fastcdc `Chunker`
@@ -7,44 +15,187 @@ use std::io::Read;
 /// These are limited to `usize`, and not `u64`, because this implementation makes
 /// extensive use of in memory buffers of size `max_size`
 ///
-/// This chunker, unlike `BuzHash` does not support any attempted mitigation of
-/// chunk based fingerprinting attacks. Those who are concerned about such an
-/// attack may wish to use the `BuzHash` chunker until such a time that a better
-/// repository format that does not leak information about chunk sizes can be
-/// developed.
+/// With a `nonce` of 0, this uses the fixed gear table from the `fastcdc` crate.
+/// Any other `nonce` derives a random gear table from it, in the same manner that
+/// `BuzHash` derives its lookup table, to help provide resistance against a chunk
+/// size based fingerprinting attack. As with `BuzHash`, this is a tenuous
+/// mitigation, and changing the nonce changes every chunk boundary.
+///
+/// The unkeyed table is only available with the `std` feature. Without it, `FastCDC::new` and the
+/// `Default` impl are not available, and a `FastCDC` can only be constructed with a non-zero
+/// `nonce`, through `FastCDC::keyed`.
 #[derive(Clone, Copy)]
 pub struct FastCDC {
     pub min_size: usize,
     pub max_size: usize,
     pub avg_size: usize,
+    /// Nonce the gear table is derived from, 0 uses the unkeyed table
+    ///
+    /// This is private so that the unkeyed table can not be selected without the `std` feature.
+    nonce: u64,
+}
+
+impl FastCDC {
+    /// Creates a new `FastCDC` with the given size settings, deriving its gear table from
+    /// `nonce`, or using the unkeyed table if `nonce` is 0
+    #[cfg(feature = "std")]
+    pub fn new(min_size: usize, avg_size: usize, max_size: usize, nonce: u64) -> FastCDC {
+        FastCDC {
+            min_size,
+            max_size,
+            avg_size,
+            nonce,
+        }
+    }
+
+    /// Creates a new `FastCDC` with the given size settings, deriving its gear table from
+    /// `nonce`
+    ///
+    /// Unlike `FastCDC::new`, this is available without the `std` feature.
+    pub fn keyed(min_size: usize, avg_size: usize, max_size: usize, nonce: NonZeroU64) -> FastCDC {
+        FastCDC {
+            min_size,
+            max_size,
+            avg_size,
+            nonce: nonce.get(),
+        }
+    }
+
+    /// Returns the nonce the gear table is derived from, 0 indicating the unkeyed table
+    pub fn nonce(&self) -> u64 {
+        self.nonce
+    }
+
+    /// Derives the keyed gear table for this chunker's nonce, or `None` if the unkeyed table is
+    /// to be used
+    fn gear(&self) -> Option<Box<[u64; 256]>> {
+        if self.nonce == 0 {
+            None
+        } else {
+            let mut table = Box::new([0_u64; 256]);
+            let mut rng = ChaCha20Rng::seed_from_u64(self.nonce);
+            for item in table.iter_mut() {
+                *item = rng.gen();
+            }
+            Some(table)
+        }
+    }
+
+    /// Splits a slice into chunks with the unkeyed gear table from the `fastcdc` crate
+    #[cfg(feature = "std")]
+    fn unkeyed_chunks<'a>(&self, slice: &'a [u8]) -> SliceChunks<'a> {
+        let slicer = fastcdc::FastCDC::new(slice, self.min_size, self.avg_size, self.max_size);
+        Box::new(slicer.map(move |chunk| &slice[chunk.offset..chunk.offset + chunk.length]))
+    }
+
+    /// Without the `std` feature, a `FastCDC` can only be constructed with a non-zero nonce, so
+    /// the unkeyed table is never selected
+    #[cfg(not(feature = "std"))]
+    fn unkeyed_chunks<'a>(&self, _slice: &'a [u8]) -> SliceChunks<'a> {
+        unreachable!("FastCDC can only be constructed with a non-zero nonce without std")
+    }
+
+    /// Finds the length of the next chunk at the start of `source` using a keyed gear table
+    ///
+    /// This is the normalized chunking variant of the `FastCDC` algorithm, with a harder to
+    /// satisfy mask before `avg_size` and an easier one after it.
+    fn keyed_cut(&self, gear: &[u64; 256], source: &[u8]) -> usize {
+        let mut remaining = source.len();
+        if remaining <= self.min_size {
+            return remaining;
+        }
+        let mut center = self.avg_size;
+        if remaining > self.max_size {
+            remaining = self.max_size;
+        } else if remaining < center {
+            center = remaining;
+        }
+        // The hash is shifted left, so its high bits depend on the most bytes
+        let bits = 63 - (self.avg_size.max(2) as u64).leading_zeros();
+        let high_bits = |count: u32| {
+            u64::max_value()
+                .checked_shr(count)
+                .map_or(u64::max_value(), |x| !x)
+        };
+        let mask_small = high_bits(bits + 1);
+        let mask_large = high_bits(bits - 1);
+
+        let mut hash = 0_u64;
+        for (index, byte) in source
+            .iter()
+            .enumerate()
+            .take(remaining)
+            .skip(self.min_size)
+        {
+            hash = (hash << 1).wrapping_add(gear[usize::from(*byte)]);
+            let mask = if index < center {
+                mask_small
+            } else {
+                mask_large
+            };
+            if hash & mask == 0 {
+                return index + 1;
+            }
+        }
+        remaining
+    }
 }
 
+impl SliceChunker for FastCDC {
+    fn slice_chunks<'a>(&self, slice: &'a [u8]) -> SliceChunks<'a> {
+        if let Some(gear) = self.gear() {
+            let settings = *self;
+            let mut offset = 0;
+            return Box::new(core::iter::from_fn(move || {
+                if offset >= slice.len() {
+                    None
+                } else {
+                    let start = offset;
+                    offset += settings.keyed_cut(&gear, &slice[start..]);
+                    Some(&slice[start..offset])
+                }
+            }));
+        }
+        self.unkeyed_chunks(slice)
+    }
+}
+
+#[cfg(feature = "std")]
 impl Chunker for FastCDC {
     type Chunks = FastCDCChunker;
     fn chunk_boxed(&self, read: Box<dyn Read + Send + 'static>) -> Self::Chunks {
         FastCDCChunker {
             settings: *self,
+            gear: self.gear(),
             buffer: vec![0_u8; self.max_size],
             length: 0,
             read,
             eof: false,
         }
     }
+    fn chunk_borrowed<'a>(&self, slice: &'a [u8]) -> BorrowedChunks<'a> {
+        Box::new(self.slice_chunks(slice).map(Ok))
+    }
 }
 
+#[cfg(feature = "std")]
 impl Default for FastCDC {
     fn default() -> Self {
         FastCDC {
             min_size: 32_768,
             avg_size: 65_536,
             max_size: 131_072,
+            nonce: 0,
         }
     }
 }
 
+#[cfg(feature = "std")]
 pub struct FastCDCChunker {
     /// The settings used for this `Chunker`
     settings: FastCDC,
+    /// The keyed gear table, if this chunker has a non-zero nonce
+    gear: Option<Box<[u64; 256]>>,
     /// The in memory buffer used to hack the chosen FastCDC implementation into working
     ///
     /// This must always be kept at a size of `max_size`
@@ -57,6 +208,7 @@ pub struct FastCDCChunker {
     eof: bool,
 }
 
+#[cfg(feature = "std")]
 impl FastCDCChunker {
     /// Drains a specified number of bytes from the reader, and refills it back up to `max_size` with
     /// zeros.
@@ -149,6 +301,9 @@ impl FastCDCChunker {
         // Check to see if we are empty, if so, return early
         if self.is_empty() {
             Err(ChunkerError::Empty)
+        } else if let Some(gear) = &self.gear {
+            let length = self.settings.keyed_cut(gear, &self.buffer[..self.length]);
+            self.drain_bytes(length)
         } else {
             // Attempt to produce our slice
             let mut slicer = fastcdc::FastCDC::new(
@@ -169,6 +324,7 @@ impl FastCDCChunker {
     }
 }
 
+#[cfg(feature = "std")]
 impl Iterator for FastCDCChunker {
     type Item = Result<Vec<u8>, ChunkerError>;
 
@@ -182,7 +338,7 @@ impl Iterator for FastCDCChunker {
     }
 }
 
-#[cfg(test)]
+#[cfg(all(test, feature = "std"))]
 mod tests {
     use super::*;
     use rand::prelude::*;
@@ -211,6 +367,22 @@ mod tests {
         assert!(chunks.len() > 1);
     }
 
+    // Empty input should produce no chunks at all, rather than an empty chunk or an error
+    #[test]
+    fn empty_input() {
+        for chunker in &[
+            FastCDC::default(),
+            FastCDC {
+                nonce: 7,
+                ..FastCDC::default()
+            },
+        ] {
+            assert_eq!(chunker.chunk(Cursor::new(Vec::<u8>::new())).count(), 0);
+            assert_eq!(chunker.chunk_slice(Vec::<u8>::new()).count(), 0);
+            assert_eq!(chunker.chunk_borrowed(&[]).count(), 0);
+        }
+    }
+
     // Data should be identical after reassembaly by simple concatenation
     #[test]
     fn reassemble_data() {
@@ -277,4 +449,109 @@ mod tests {
 
         assert!(undersized_count <= 1);
     }
+
+    // The borrowed path should produce exactly the same chunks as the owned path
+    #[test]
+    fn borrowed_matches_owned() {
+        let data = get_test_data();
+        let chunker = FastCDC::default();
+        let owned_chunks = chunker
+            .chunk_slice(data.clone())
+            .map(|x| x.unwrap())
+            .collect::<Vec<_>>();
+        let borrowed_chunks = chunker
+            .chunk_borrowed(&data)
+            .map(|x| x.unwrap().to_vec())
+            .collect::<Vec<_>>();
+        assert_eq!(owned_chunks, borrowed_chunks);
+    }
+
+    // Different nonces should move the chunk boundaries, while each nonce remains deterministic
+    #[test]
+    fn nonce_changes_boundaries() {
+        let data = get_test_data();
+        let lengths = |nonce: u64| {
+            let settings = FastCDC::default();
+            FastCDC::new(
+                settings.min_size,
+                settings.avg_size,
+                settings.max_size,
+                nonce,
+            )
+            .chunk_slice(data.clone())
+            .map(|x| x.unwrap().len())
+            .collect::<Vec<_>>()
+        };
+        let first = lengths(1);
+        let second = lengths(2);
+        assert_eq!(first, lengths(1));
+        assert_eq!(second, lengths(2));
+        assert_ne!(first, second);
+        assert_ne!(first, lengths(0));
+        assert_eq!(first.iter().sum::<usize>(), data.len());
+        // Keyed construction derives the same table
+        let settings = FastCDC::default();
+        let keyed = FastCDC::keyed(
+            settings.min_size,
+            settings.avg_size,
+            settings.max_size,
+            NonZeroU64::new(1).unwrap(),
+        );
+        assert_eq!(keyed.nonce(), 1);
+        assert_eq!(
+            keyed
+                .chunk_slice(data.clone())
+                .map(|x| x.unwrap().len())
+                .collect::<Vec<_>>(),
+            first
+        );
+    }
+
+    // A keyed chunker should still respect the size limits, and agree with its borrowed path
+    #[test]
+    fn keyed_sizes_and_borrowed() {
+        let data = get_test_data();
+        let settings = FastCDC::default();
+        let chunker = FastCDC::new(settings.min_size, settings.avg_size, settings.max_size, 42);
+        let owned_chunks = chunker
+            .chunk(Cursor::new(data.clone()))
+            .map(|x| x.unwrap())
+            .collect::<Vec<_>>();
+        let borrowed_chunks = chunker
+            .chunk_borrowed(&data)
+            .map(|x| x.unwrap().to_vec())
+            .collect::<Vec<_>>();
+        assert_eq!(owned_chunks, borrowed_chunks);
+        assert_eq!(owned_chunks.concat(), data);
+        let undersized = owned_chunks
+            .iter()
+            .filter(|x| x.len() < settings.min_size)
+            .count();
+        assert!(undersized <= 1);
+        assert!(owned_chunks.iter().all(|x| x.len() <= settings.max_size));
+    }
+
+    // The no_std slice path should produce exactly the same chunks as the Read path, for both the
+    // unkeyed and keyed gear tables
+    #[test]
+    fn slice_matches_read() {
+        let data = get_test_data();
+        for chunker in &[
+            FastCDC::default(),
+            FastCDC {
+                nonce: 7,
+                ..FastCDC::default()
+            },
+        ] {
+            let read_chunks = chunker
+                .chunk(Cursor::new(data.clone()))
+                .map(|x| x.unwrap())
+                .collect::<Vec<_>>();
+            let slice_chunks = chunker
+                .slice_chunks(&data)
+                .map(<[u8]>::to_vec)
+                .collect::<Vec<_>>();
+            assert_eq!(read_chunks, slice_chunks);
+        }
+    }
 }
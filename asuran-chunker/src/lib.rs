@@ -1,4 +1,9 @@
 //! API for describing types that can slice data into component slices in a repeatable manner
+//!
+//! The `Read` based `Chunker` API requires the `std` feature, which is on by default. Without it,
+//! this crate is `no_std`, only requiring `alloc`, and chunking is limited to in memory slices
+//! through `SliceChunker`.
+#![cfg_attr(not(feature = "std"), no_std)]
 #![warn(clippy::all)]
 #![warn(clippy::pedantic)]
 #![allow(clippy::must_use_candidate)]
@@ -7,43 +12,77 @@
 #![allow(clippy::pub_enum_variant_names)]
 #![allow(clippy::missing_errors_doc)]
 
+extern crate alloc;
+
 pub mod buzhash;
 pub mod fastcdc;
+#[cfg(feature = "std")]
+pub mod predefined_split;
 pub mod static_size;
 
 pub use self::buzhash::*;
 pub use self::fastcdc::*;
+#[cfg(feature = "std")]
+pub use self::predefined_split::*;
 pub use self::static_size::*;
 
+use alloc::boxed::Box;
+#[cfg(feature = "std")]
 use thiserror::Error;
 
+#[cfg(feature = "std")]
 use std::io;
 
 #[cfg(feature = "streams")]
 use futures::channel::mpsc;
 #[cfg(feature = "streams")]
 use futures::sink::SinkExt;
-#[cfg(feature = "streams")]
+#[cfg(all(feature = "streams", not(target_arch = "wasm32")))]
 use smol::block_on;
-#[cfg(feature = "streams")]
+#[cfg(all(feature = "streams", not(target_arch = "wasm32")))]
 use std::thread;
 
+#[cfg(all(feature = "streams", target_arch = "wasm32", not(feature = "wasm")))]
+compile_error!("The streams feature requires the wasm feature on wasm32");
+
+#[cfg(feature = "std")]
 #[derive(Error, Debug)]
 pub enum ChunkerError {
     #[error("Provider IO error")]
     IOError(#[from] io::Error),
     #[error("Internal Chunker Error")]
     InternalError(String),
+    /// Signals the end of the input to a chunker's iterator, and is never yielded by it
     #[error("Slicer incorrectly applied to empty data")]
     Empty,
 }
 
+#[cfg(feature = "std")]
 use std::io::{Cursor, Read};
 
+/// An iterator over chunks borrowed from a slice, as returned by `Chunker::chunk_borrowed`
+#[cfg(feature = "std")]
+pub type BorrowedChunks<'a> = Box<dyn Iterator<Item = Result<&'a [u8], ChunkerError>> + 'a>;
+
+/// An iterator over chunks borrowed from a slice, as returned by `SliceChunker::slice_chunks`
+pub type SliceChunks<'a> = Box<dyn Iterator<Item = &'a [u8]> + 'a>;
+
+/// Describes something that can slice in memory data in a defined, repeatable manner, without
+/// needing `std`
+///
+/// This is the core of the built in chunkers, and is available without the `std` feature. Slicing
+/// can not fail, so chunks are yielded directly. Chunkers implementing both this and `Chunker`
+/// must produce identical boundaries from both, and must meet the same properties.
+pub trait SliceChunker {
+    /// Splits a slice into chunks that borrow from it
+    fn slice_chunks<'a>(&self, slice: &'a [u8]) -> SliceChunks<'a>;
+}
+
 /// Describes something that can slice objects in a defined, repeatable manner
 ///
 /// Chunkers must meet the following properties:
-/// 1.) Data must be split into one or more chunks
+/// 1.) Non-empty data must be split into one or more chunks, and empty data must produce no chunks
+///  at all, rather than an empty chunk or an error
 /// 2.) Data must be identical to original after a simple reconstruction by concatenation
 /// 3.) The same data and settings must produce the same slices every time
 /// 4.) Chunkers (that have a max size) should not produce any chunks larger than their `max_size`
@@ -61,6 +100,7 @@ use std::io::{Cursor, Read};
 ///
 /// The Send bound on the Read is likely temporary, it is currently required to make the streams
 /// feature work properly.
+#[cfg(feature = "std")]
 pub trait Chunker: Clone {
     /// The return type of the functions in this trait is an iterator over the chunks of their
     /// input.
@@ -87,6 +127,24 @@ pub trait Chunker: Clone {
         let boxed: Box<dyn Read + Send + 'static> = Box::new(cursor);
         self.chunk_boxed(boxed)
     }
+    /// Splits a slice into chunks that borrow from it, rather than allocating a fresh `Vec` for
+    /// each chunk.
+    ///
+    /// The chunk boundaries produced must be identical to those produced by `chunk_slice` over the
+    /// same data.
+    ///
+    /// The default implementation copies the slice once and runs it through `chunk_slice`, only
+    /// using the resulting chunks to locate the boundaries. Implementations that operate directly
+    /// on slices are strongly encouraged to override this.
+    fn chunk_borrowed<'a>(&self, slice: &'a [u8]) -> BorrowedChunks<'a> {
+        let owned = self.chunk_slice(slice.to_vec());
+        let mut offset = 0;
+        Box::new(owned.map(move |chunk| {
+            let start = offset;
+            offset += chunk?.len();
+            Ok(&slice[start..offset])
+        }))
+    }
 }
 
 /// Asynchronous version of `Chunker`
@@ -128,15 +186,9 @@ where
         read: Box<dyn Read + Send + 'static>,
         queue_depth: usize,
     ) -> mpsc::Receiver<Result<Vec<u8>, ChunkerError>> {
-        let (mut input, output) = mpsc::channel(queue_depth);
+        let (input, output) = mpsc::channel(queue_depth);
         let iter = self.chunk_boxed(read);
-        thread::spawn(move || {
-            for chunk in iter {
-                // If we are here, and sending to the channel fails, we have no sensible way to
-                // recover, as we have lost communication with the outside world
-                block_on(input.send(chunk)).expect("Chunker to communicate with outside world.");
-            }
-        });
+        feed(iter, input);
         output
     }
     fn async_chunk<R: Read + Send + 'static>(
@@ -144,15 +196,9 @@ where
         read: R,
         queue_depth: usize,
     ) -> mpsc::Receiver<Result<Vec<u8>, ChunkerError>> {
-        let (mut input, output) = mpsc::channel(queue_depth);
+        let (input, output) = mpsc::channel(queue_depth);
         let iter = self.chunk(read);
-        thread::spawn(move || {
-            for chunk in iter {
-                // If we are here, and sending to the channel fails, we have no sensible way to
-                // recover, as we have lost communication with the outside world
-                block_on(input.send(chunk)).expect("Chunker to communicate with outside world.");
-            }
-        });
+        feed(iter, input);
         output
     }
     fn async_chunk_slice<R: AsRef<[u8]> + Send + 'static>(
@@ -160,15 +206,47 @@ where
         slice: R,
         queue_depth: usize,
     ) -> mpsc::Receiver<Result<Vec<u8>, ChunkerError>> {
-        let (mut input, output) = mpsc::channel(queue_depth);
+        let (input, output) = mpsc::channel(queue_depth);
         let iter = self.chunk_slice(slice);
-        thread::spawn(move || {
-            for chunk in iter {
-                // If we are here, and sending to the channel fails, we have no sensible way to
-                // recover, as we have lost communication with the outside world
-                block_on(input.send(chunk)).expect("Chunker to communicate with outside world.");
-            }
-        });
+        feed(iter, input);
         output
     }
 }
+
+/// Sends the chunks produced by an iterator down a channel, in the background
+///
+/// Chunking is blocking, so this is done on a dedicated thread.
+#[cfg(all(feature = "streams", not(target_arch = "wasm32")))]
+fn feed<I>(iter: I, mut input: mpsc::Sender<Result<Vec<u8>, ChunkerError>>)
+where
+    I: Iterator<Item = Result<Vec<u8>, ChunkerError>> + Send + 'static,
+{
+    thread::spawn(move || {
+        for chunk in iter {
+            // If we are here, and sending to the channel fails, we have no sensible way to
+            // recover, as we have lost communication with the outside world
+            block_on(input.send(chunk)).expect("Chunker to communicate with outside world.");
+        }
+    });
+}
+
+/// Sends the chunks produced by an iterator down a channel, in the background
+///
+/// There are no threads in the browser, so this is done in a task on its event loop instead. The
+/// task yields whenever the channel is full, so the consumer gets a chance to run.
+#[cfg(all(feature = "streams", target_arch = "wasm32"))]
+fn feed<I>(iter: I, mut input: mpsc::Sender<Result<Vec<u8>, ChunkerError>>)
+where
+    I: Iterator<Item = Result<Vec<u8>, ChunkerError>> + 'static,
+{
+    wasm_bindgen_futures::spawn_local(async move {
+        for chunk in iter {
+            // If we are here, and sending to the channel fails, we have no sensible way to
+            // recover, as we have lost communication with the outside world
+            input
+                .send(chunk)
+                .await
+                .expect("Chunker to communicate with outside world.");
+        }
+    });
+}
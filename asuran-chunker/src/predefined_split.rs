@@ -0,0 +1,156 @@
+use super::{Chunker, ChunkerError};
+
+use std::io::Read;
+
+/// Settings for a `Chunker` that splits at a fixed list of offsets
+///
+/// This chunker performs no content analysis at all, it simply splits the input at each of
+/// the given absolute byte offsets, and emits whatever remains after the last one as the final
+/// chunk. This is mostly useful for reproducing chunking bugs with a minimal fixture, and for
+/// formats whose chunk boundaries must line up with known record boundaries, such as tar.
+///
+/// This chunker has no minimum or maximum size, chunks are exactly as large as the gaps between
+/// the split points.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct PredefinedSplit {
+    /// Offsets to split at, sorted, deduplicated, and all non-zero
+    splits: Vec<u64>,
+}
+
+impl PredefinedSplit {
+    /// Creates a new `PredefinedSplit` that will split its input at each of the given offsets
+    ///
+    /// Offsets are sorted and deduplicated, and an offset of 0 is ignored, as it would only
+    /// produce an empty chunk. Offsets beyond the end of the input are ignored.
+    pub fn new(splits: impl IntoIterator<Item = u64>) -> PredefinedSplit {
+        let mut splits: Vec<u64> = splits.into_iter().filter(|x| *x > 0).collect();
+        splits.sort_unstable();
+        splits.dedup();
+        PredefinedSplit { splits }
+    }
+
+    /// Returns the offsets this chunker splits at
+    pub fn splits(&self) -> &[u64] {
+        &self.splits
+    }
+}
+
+impl Chunker for PredefinedSplit {
+    type Chunks = PredefinedSplitChunker;
+    fn chunk_boxed(&self, read: Box<dyn Read + Send + 'static>) -> Self::Chunks {
+        PredefinedSplitChunker {
+            splits: self.splits.clone().into_iter(),
+            read,
+            offset: 0,
+        }
+    }
+}
+
+pub struct PredefinedSplitChunker {
+    /// Offsets that have yet to be split at
+    splits: std::vec::IntoIter<u64>,
+    /// Data this `Chunker` is slicing over
+    read: Box<dyn Read + Send + 'static>,
+    /// Absolute offset of the next byte to be read
+    offset: u64,
+}
+
+impl Iterator for PredefinedSplitChunker {
+    type Item = Result<Vec<u8>, ChunkerError>;
+    fn next(&mut self) -> Option<Self::Item> {
+        let mut buffer = Vec::new();
+        let result = match self.splits.next() {
+            Some(split) => (&mut self.read)
+                .take(split - self.offset)
+                .read_to_end(&mut buffer),
+            // Past the last split point, the rest of the data is the final chunk
+            None => self.read.read_to_end(&mut buffer),
+        };
+        match result {
+            Ok(0) => None,
+            Ok(len) => {
+                self.offset += len as u64;
+                Some(Ok(buffer))
+            }
+            Err(err) => Some(Err(err.into())),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rand::prelude::*;
+    use std::io::Cursor;
+
+    fn get_test_data() -> Vec<u8> {
+        let mut vec = vec![0_u8; 10_000];
+        rand::thread_rng().fill_bytes(&mut vec);
+        vec
+    }
+
+    // Chunks should end exactly at the requested offsets
+    #[test]
+    fn splits_at_offsets() {
+        let data = get_test_data();
+        let chunker = PredefinedSplit::new(vec![5000, 100, 512, 100, 0]);
+        assert_eq!(chunker.splits(), &[100, 512, 5000]);
+        let lengths = chunker
+            .chunk(Cursor::new(data))
+            .map(|x| x.unwrap().len())
+            .collect::<Vec<_>>();
+        assert_eq!(lengths, vec![100, 412, 4488, 5000]);
+    }
+
+    // Offsets at or past the end of the data should not produce empty chunks
+    #[test]
+    fn splits_past_end() {
+        let data = get_test_data();
+        let chunker = PredefinedSplit::new(vec![4000, 10_000, 20_000]);
+        let lengths = chunker
+            .chunk_slice(data)
+            .map(|x| x.unwrap().len())
+            .collect::<Vec<_>>();
+        assert_eq!(lengths, vec![4000, 6000]);
+    }
+
+    // Data should be identical after reassembaly by simple concatenation
+    #[test]
+    fn reassemble_data() {
+        let data = get_test_data();
+        let chunker = PredefinedSplit::new(vec![1, 2, 3, 1024, 9999]);
+        let chunks = chunker
+            .chunk(Cursor::new(data.clone()))
+            .map(|x| x.unwrap())
+            .collect::<Vec<_>>();
+        assert_eq!(chunks.len(), 6);
+        let rebuilt: Vec<u8> = chunks.concat();
+        assert_eq!(data, rebuilt);
+
+        let borrowed = chunker
+            .chunk_borrowed(&data)
+            .map(|x| x.unwrap().to_vec())
+            .collect::<Vec<_>>();
+        assert_eq!(chunks, borrowed);
+    }
+
+    // With no split points, the whole input is one chunk
+    #[test]
+    fn no_splits() {
+        let data = get_test_data();
+        let chunks = PredefinedSplit::new(Vec::new())
+            .chunk(Cursor::new(data.clone()))
+            .map(|x| x.unwrap())
+            .collect::<Vec<_>>();
+        assert_eq!(chunks, vec![data]);
+    }
+
+    // Empty input should produce no chunks at all, rather than an empty chunk or an error
+    #[test]
+    fn empty_input() {
+        let chunker = PredefinedSplit::new(vec![100, 512]);
+        assert_eq!(chunker.chunk(Cursor::new(Vec::<u8>::new())).count(), 0);
+        assert_eq!(chunker.chunk_slice(Vec::<u8>::new()).count(), 0);
+        assert_eq!(chunker.chunk_borrowed(&[]).count(), 0);
+    }
+}
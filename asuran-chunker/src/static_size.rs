@@ -1,5 +1,10 @@
-use super::{Chunker, ChunkerError};
+#[cfg(feature = "std")]
+use super::{BorrowedChunks, Chunker, ChunkerError};
+use super::{SliceChunker, SliceChunks};
 
+use alloc::boxed::Box;
+
+#[cfg(feature = "std")]
 use std::io::{BufReader, Bytes, Read};
 
 /// Settings for a static chunk length `Chunker`
@@ -12,15 +17,38 @@ pub struct StaticSize {
     pub len: usize,
 }
 
+impl SliceChunker for StaticSize {
+    fn slice_chunks<'a>(&self, slice: &'a [u8]) -> SliceChunks<'a> {
+        Box::new(slice.chunks(self.len))
+    }
+}
+
+#[cfg(feature = "std")]
 impl Chunker for StaticSize {
     type Chunks = StaticSizeChunker;
     fn chunk_boxed(&self, read: Box<dyn Read + Send + 'static>) -> Self::Chunks {
         StaticSizeChunker {
             settings: *self,
-            internal: BufReader::new(read).bytes(),
-            next: None,
+            source: Source::Read {
+                internal: BufReader::new(read).bytes(),
+                next: None,
+            },
         }
     }
+    /// Splits the slice directly into `len` sized blocks, skipping the `Cursor`
+    /// and byte-by-byte reading the default implementation would incur.
+    fn chunk_slice<R: AsRef<[u8]> + Send + 'static>(&self, slice: R) -> Self::Chunks {
+        StaticSizeChunker {
+            settings: *self,
+            source: Source::Slice {
+                slice: Box::new(slice),
+                offset: 0,
+            },
+        }
+    }
+    fn chunk_borrowed<'a>(&self, slice: &'a [u8]) -> BorrowedChunks<'a> {
+        Box::new(self.slice_chunks(slice).map(Ok))
+    }
 }
 
 impl Default for StaticSize {
@@ -30,43 +58,78 @@ impl Default for StaticSize {
     }
 }
 
+/// The data a `StaticSizeChunker` is slicing over
+#[cfg(feature = "std")]
+enum Source {
+    /// A `Read`, consumed a byte at a time
+    Read {
+        internal: Bytes<BufReader<Box<dyn Read + Send + 'static>>>,
+        next: Option<std::io::Result<u8>>,
+    },
+    /// An in-memory (or memory mapped) slice, and the offset of the next chunk in it
+    Slice {
+        slice: Box<dyn AsRef<[u8]> + Send + 'static>,
+        offset: usize,
+    },
+}
+
+#[cfg(feature = "std")]
 pub struct StaticSizeChunker {
     /// Settings for this `Chunker`
     settings: StaticSize,
-    /// `Read` this `Chunker` is slicing over
-    internal: Bytes<BufReader<Box<dyn Read + Send + 'static>>>,
-    next: Option<std::io::Result<u8>>,
+    /// Data this `Chunker` is slicing over
+    source: Source,
 }
 
+#[cfg(feature = "std")]
 impl Iterator for StaticSizeChunker {
     type Item = Result<Vec<u8>, ChunkerError>;
     fn next(&mut self) -> Option<Self::Item> {
-        let mut buffer = Vec::new();
-        let mut next = if self.next.is_some() {
-            self.next.take()
-        } else {
-            self.internal.next()
-        };
-        while next.is_some() && buffer.len() < self.settings.len {
-            // This unwrap is safe because we just verified that it is a Some(T)
-            let byte = next.unwrap();
-            let byte = match byte {
-                Ok(byte) => byte,
-                Err(err) => return Some(Err(err.into())),
-            };
-            buffer.push(byte);
-            next = self.internal.next();
-        }
-        if buffer.is_empty() {
-            None
-        } else {
-            self.next = next;
-            Some(Ok(buffer))
+        let len = self.settings.len;
+        match &mut self.source {
+            Source::Read {
+                internal,
+                next: stored,
+            } => {
+                let mut buffer = Vec::new();
+                let mut next = if stored.is_some() {
+                    stored.take()
+                } else {
+                    internal.next()
+                };
+                while next.is_some() && buffer.len() < len {
+                    // This unwrap is safe because we just verified that it is a Some(T)
+                    let byte = next.unwrap();
+                    let byte = match byte {
+                        Ok(byte) => byte,
+                        Err(err) => return Some(Err(err.into())),
+                    };
+                    buffer.push(byte);
+                    next = internal.next();
+                }
+                if buffer.is_empty() {
+                    None
+                } else {
+                    *stored = next;
+                    Some(Ok(buffer))
+                }
+            }
+            Source::Slice { slice, offset } => {
+                let data = (**slice).as_ref();
+                if *offset >= data.len() {
+                    None
+                } else {
+                    let end = std::cmp::min(*offset + len, data.len());
+                    let buffer = data[*offset..end].to_vec();
+                    *offset = end;
+                    Some(Ok(buffer))
+                }
+            }
         }
     }
 }
 
-#[cfg(test)]
+#[cfg(all(test, feature = "std"))]
 mod tests {
     use super::*;
     use rand::prelude::*;
@@ -93,6 +156,15 @@ mod tests {
         assert!(chunks.len() > 1);
     }
 
+    // Empty input should produce no chunks at all, rather than an empty chunk or an error
+    #[test]
+    fn empty_input() {
+        let chunker = StaticSize::default();
+        assert_eq!(chunker.chunk(Cursor::new(Vec::<u8>::new())).count(), 0);
+        assert_eq!(chunker.chunk_slice(Vec::<u8>::new()).count(), 0);
+        assert_eq!(chunker.chunk_borrowed(&[]).count(), 0);
+    }
+
     // Data should be identical after reassembaly by simple concatenation
     #[test]
     fn reassemble_data() {
@@ -159,4 +231,52 @@ mod tests {
 
         assert!(undersized_count <= 1);
     }
+
+    // The slice fast path should produce exactly the same blocks as the `Read` path
+    #[test]
+    fn slice_matches_read() {
+        let data = get_test_data();
+        let chunker = StaticSize::default();
+        let read_chunks = chunker
+            .chunk(Cursor::new(data.clone()))
+            .map(|x| x.unwrap())
+            .collect::<Vec<_>>();
+        let slice_chunks = chunker
+            .chunk_slice(data)
+            .map(|x| x.unwrap())
+            .collect::<Vec<_>>();
+        assert_eq!(read_chunks, slice_chunks);
+    }
+
+    // The borrowed path should produce exactly the same chunks as the owned path
+    #[test]
+    fn borrowed_matches_owned() {
+        let data = get_test_data();
+        let chunker = StaticSize::default();
+        let owned_chunks = chunker
+            .chunk_slice(data.clone())
+            .map(|x| x.unwrap())
+            .collect::<Vec<_>>();
+        let borrowed_chunks = chunker
+            .chunk_borrowed(&data)
+            .map(|x| x.unwrap().to_vec())
+            .collect::<Vec<_>>();
+        assert_eq!(owned_chunks, borrowed_chunks);
+    }
+
+    // The no_std slice path should produce exactly the same chunks as the Read path
+    #[test]
+    fn slice_matches_read() {
+        let data = get_test_data();
+        let chunker = StaticSize::default();
+        let read_chunks = chunker
+            .chunk(Cursor::new(data.clone()))
+            .map(|x| x.unwrap())
+            .collect::<Vec<_>>();
+        let slice_chunks = chunker
+            .slice_chunks(&data)
+            .map(<[u8]>::to_vec)
+            .collect::<Vec<_>>();
+        assert_eq!(read_chunks, slice_chunks);
+    }
 }
@@ -1,10 +1,13 @@
+use asuran::bench::{bench_chunkers as run_chunker_benchmarks, generate_dataset};
 use asuran::prelude::*;
+use asuran::tune::{auto_tune as run_auto_tune, read_sample};
 
-use anyhow::Result;
+use anyhow::{anyhow, Context, Result};
 use prettytable::{cell, row, Table};
 
 use std::collections::HashMap;
 use std::io::{self, Write};
+use std::path::PathBuf;
 use std::time::{Duration, Instant};
 
 const ONE_MIB: usize = 1_048_576;
@@ -53,13 +56,18 @@ than any of these.
     io::stdout().flush()?;
 
     let mut map: HashMap<Encryption, Vec<(HMAC, f64)>> = HashMap::new();
-    let encryptions = vec![Encryption::new_aes256ctr(), Encryption::new_chacha20()];
+    let encryptions = vec![
+        Encryption::new_aes256ctr(),
+        Encryption::new_chacha20(),
+        Encryption::new_xchacha20poly1305(),
+    ];
     let hmacs = vec![
         HMAC::SHA256,
         HMAC::Blake2b,
         HMAC::Blake2bp,
         HMAC::Blake3,
         HMAC::SHA3,
+        HMAC::Blake3XOF,
     ];
     for enc in encryptions.clone() {
         let mut results: Vec<(HMAC, f64)> = Vec::new();
@@ -113,10 +121,127 @@ choose the fastest combination that does not include SHA2."
     Ok(())
 }
 
+pub async fn bench_chunkers(size: usize) -> Result<()> {
+    println!(
+        "                       === asuran-cli bench-chunkers ===
+
+This command will provide benchmarks of the raw single threaded performance of
+each of Asuran's chunkers, along with how well their output deduplicates.
+
+The dataset is {} MiB of random data, with its second half repeating the first,
+offset by a single byte. Content defined chunkers should be able to deduplicate
+most of the second half, while fixed size chunkers will not.
+
+                          === Beginning Benchmarks ===\n",
+        size
+    );
+    io::stdout().flush()?;
+
+    let data = generate_dataset(size * ONE_MIB, 0);
+    let results = run_chunker_benchmarks(&data);
+    println!("                                === Results ===\n");
+    let mut table = Table::new();
+    table.set_titles(row![
+        "   Chunker   ",
+        "     Speed     ",
+        "  Chunks  ",
+        "  Unique  ",
+        "  Avg. Size  ",
+        "  Dedup Ratio  "
+    ]);
+    for result in results {
+        table.add_row(row![
+            result.name,
+            format!("{:.2} MiB/s", result.throughput),
+            result.chunk_count,
+            result.unique_chunks,
+            format!("{:.1} KiB", result.average_chunk_size / 1024.0),
+            format!("{:.3}", result.dedup_ratio)
+        ]);
+    }
+    table.printstd();
+    Ok(())
+}
+
+pub async fn auto_tune(sample: PathBuf, size: usize) -> Result<()> {
+    println!(
+        "                         === asuran-cli auto-tune ===
+
+This command will benchmark combinations of Asuran's compression and HMAC
+algorithms on up to {} MiB of sample data read from {:?}, and recommend
+the combination that best balances throughput against the space saved.
+
+                          === Beginning Benchmarks ===\n",
+        size, sample
+    );
+    io::stdout().flush()?;
+
+    let data = read_sample(&sample, size * ONE_MIB)
+        .with_context(|| format!("Unable to read sample data from {:?}", sample))?;
+    if data.is_empty() {
+        return Err(anyhow!("No sample data found at {:?}", sample));
+    }
+    let report = run_auto_tune(&data, Encryption::new_aes256ctr());
+    println!("                                === Results ===\n");
+    let mut table = Table::new();
+    table.set_titles(row![
+        "  Compression  ",
+        "   HMAC Type   ",
+        "     Speed     ",
+        "  Ratio  "
+    ]);
+    for result in &report.results {
+        table.add_row(row![
+            compression_to_str(result.compression),
+            hmac_to_str(result.hmac),
+            format!("{:.2} MiB/s", result.throughput),
+            format!("{:.3}", result.ratio)
+        ]);
+    }
+    table.printstd();
+
+    let settings = report.recommendation;
+    println!(
+        "\n                            === Recommendation ===\n
+Compression: {}
+HMAC: {}
+
+To create a repository with these settings, run:
+
+    asuran-cli new{} --compression {} --hmac {:?} <REPO>",
+        compression_to_str(settings.compression),
+        hmac_to_str(settings.hmac),
+        compression_level(settings.compression)
+            .map(|level| format!(" --compression-level {}", level))
+            .unwrap_or_default(),
+        settings.compression.name(),
+        settings.hmac,
+    );
+    Ok(())
+}
+
+fn compression_level(compression: Compression) -> Option<i64> {
+    match compression {
+        Compression::ZStd { level } | Compression::Auto { level } => Some(level.into()),
+        Compression::LZ4 { level }
+        | Compression::LZMA { level }
+        | Compression::LZ4Frame { level } => Some(level.into()),
+        Compression::NoCompression => None,
+    }
+}
+
+fn compression_to_str(compression: Compression) -> String {
+    match compression_level(compression) {
+        Some(level) => format!("{} (level {})", compression.name(), level),
+        None => compression.name().to_string(),
+    }
+}
+
 fn encryption_to_str(encryption: &Encryption) -> &'static str {
     match encryption {
         Encryption::AES256CTR { .. } => "AES256-CTR",
         Encryption::ChaCha20 { .. } => "ChaCha20",
+        Encryption::XChaCha20Poly1305 { .. } => "XChaCha20-Poly1305",
         _ => unimplemented!(),
     }
 }
@@ -128,5 +253,7 @@ fn hmac_to_str(hmac: HMAC) -> &'static str {
         HMAC::Blake2bp => "BLAKE2bp",
         HMAC::Blake3 => "BLAKE3",
         HMAC::SHA3 => "SHA3",
+        HMAC::Blake3XOF => "BLAKE3 XOF",
+        HMAC::Custom(_) => "Custom",
     }
 }
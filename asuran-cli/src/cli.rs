@@ -3,16 +3,21 @@ The `cli` module provides the data types used for parsing the command line
 arguements, as well as some utility functions for converting those types to
 their equivlants in `asuran` proper.
 */
+use asuran::backup::StoreOptions;
+use asuran::manifest::{ActiveArchive, Archive, ArchiveError, GlobFilter, StoredArchive};
 use asuran::repository::backend::object_wrappers::BackendObject;
-use asuran::repository::{self, Backend, Key};
+use asuran::repository::encryption::{PublicKey, RecipientError, SecretKey};
+use asuran::repository::{self, Backend, BackendClone, Key, Repository};
+use asuran::restore::{ExtractOptions, ExtractPolicy};
 
 use anyhow::{anyhow, Context, Result};
 use clap::{arg_enum, AppSettings};
-use repository::backend::{flatfile, multifile};
+use repository::backend::sftp::{RetryPolicy, SFTPSettings};
+use repository::backend::{flatfile, multifile, Durability};
 use structopt::StructOpt;
 
 use std::env;
-use std::fs::metadata;
+use std::fs;
 use std::path::PathBuf;
 
 /// The version + git commit + build date string the program idenitifes itself
@@ -35,6 +40,8 @@ arg_enum! {
         MultiFile,
         FlatFile,
         SFTP,
+        WebDAV,
+        HTTP,
     }
 }
 
@@ -48,6 +55,7 @@ arg_enum! {
     pub enum Encryption {
         AES256CTR,
         ChaCha20,
+        XChaCha20Poly1305,
         None,
     }
 }
@@ -61,7 +69,9 @@ arg_enum! {
    pub enum Compression {
        ZStd,
        LZ4,
+       LZ4Frame,
        LZMA,
+       Auto,
        None
    }
 }
@@ -78,6 +88,69 @@ arg_enum! {
         Blake2bp,
         Blake3,
         SHA3,
+        Blake3XOF,
+    }
+}
+
+arg_enum! {
+    /// A named bundle of chunk settings the user has selected
+    ///
+    /// These are a 1-to-1 corrospondance with the preset constructors on
+    /// `ChunkSettings` in the `asuran` crate.
+    #[derive(Debug, Clone, Copy, PartialEq, Eq)]
+    pub enum Preset {
+        Fast,
+        Secure,
+        Archival,
+    }
+}
+
+arg_enum! {
+    /// What to do when a file being extracted already exists
+    ///
+    /// These are a 1-to-1 corrospondance with the `ExtractPolicy` enum variants
+    /// in the `asuran` crate.
+    #[derive(Debug, Clone, Copy, PartialEq, Eq)]
+    pub enum OnConflict {
+        Overwrite,
+        Skip,
+        Fail,
+        Rename,
+    }
+}
+
+impl From<OnConflict> for ExtractPolicy {
+    fn from(on_conflict: OnConflict) -> ExtractPolicy {
+        match on_conflict {
+            OnConflict::Overwrite => ExtractPolicy::Overwrite,
+            OnConflict::Skip => ExtractPolicy::SkipExisting,
+            OnConflict::Fail => ExtractPolicy::Fail,
+            OnConflict::Rename => ExtractPolicy::RenameExisting,
+        }
+    }
+}
+
+arg_enum! {
+    /// The format command output is written in
+    ///
+    /// `Table` is intended for humans, while `JSON` provides a stable structured
+    /// format for scripting.
+    #[derive(Debug, Clone, Copy, PartialEq, Eq)]
+    pub enum OutputFormat {
+        Table,
+        JSON,
+    }
+}
+
+arg_enum! {
+    /// The format errors are reported in
+    ///
+    /// `JSON` writes a single object with a stable `kind` and a `message` to
+    /// stderr.
+    #[derive(Debug, Clone, Copy, PartialEq, Eq)]
+    pub enum ErrorFormat {
+        Text,
+        JSON,
     }
 }
 
@@ -88,24 +161,44 @@ pub enum Command {
     List {
         #[structopt(flatten)]
         repo_opts: RepoOpt,
+        /// Only list archives with this tag. May be given multiple times, in which
+        /// case only archives with all of the given tags are listed.
+        #[structopt(long = "tag")]
+        tags: Vec<String>,
     },
     /// Creates a new archive in a repository
     Store {
         #[structopt(flatten)]
         repo_opts: RepoOpt,
+        #[structopt(flatten)]
+        store_opts: StoreOpt,
         /// Location of the directory to store
         #[structopt(name = "TARGET")]
         target: PathBuf,
         /// Name for the new archive. Defaults to an ISO date/time stamp
         #[structopt(short, long)]
         name: Option<String>,
+        /// Tag to attach to the new archive. May be given multiple times.
+        #[structopt(long = "tag")]
+        tags: Vec<String>,
+        /// Free-form comment to attach to the new archive
+        #[structopt(long)]
+        comment: Option<String>,
+        /// Hex encoded X25519 public key to seal the new archive to. May be given
+        /// multiple times.
+        ///
+        /// Sealed archives can only be read with the secret key of one of their
+        /// recipients, while their chunks are still deduplicated with the rest of
+        /// the repository.
+        #[structopt(long = "recipient")]
+        recipients: Vec<PublicKey>,
     },
     /// Extracts an archive from a repository
     Extract {
         #[structopt(flatten)]
         repo_opts: RepoOpt,
         #[structopt(flatten)]
-        glob_opts: GlobOpt,
+        extract_opts: ExtractOpt,
         /// Location to restore to
         #[structopt(name = "TARGET")]
         target: PathBuf,
@@ -118,6 +211,12 @@ pub enum Command {
         /// restore command.
         #[structopt(short = "P", long)]
         preview: bool,
+        /// Resume an interrupted extraction, skipping files it already restored.
+        ///
+        /// Progress is recorded in a <TARGET>.asuran-progress file next to the
+        /// target, which is removed once the extraction finishes.
+        #[structopt(long)]
+        resume: bool,
     },
     /// Creates a new repository
     New {
@@ -126,6 +225,22 @@ pub enum Command {
     },
     /// Runs benchmarks on all combinations of asuran's supported crypto primitives.
     BenchCrypto,
+    /// Runs benchmarks on each of asuran's chunkers over a generated dataset.
+    BenchChunkers {
+        /// Size of the generated dataset, in MiB
+        #[structopt(short, long, default_value = "64")]
+        size: usize,
+    },
+    /// Benchmarks combinations of compression and HMAC algorithms on a sample of
+    /// your data, and recommends settings for a new repository.
+    AutoTune {
+        /// File, or directory of files, to read the sample from
+        #[structopt(name = "SAMPLE")]
+        sample: PathBuf,
+        /// Maximum amount of the sample to read, in MiB
+        #[structopt(short, long, default_value = "16")]
+        size: usize,
+    },
     /// Lists the contents of an archive, with optional glob filters
     Contents {
         #[structopt(flatten)]
@@ -136,17 +251,104 @@ pub enum Command {
         #[structopt(name = "ARCHIVE")]
         archive: String,
     },
+    /// Searches every archive in a repository for files matching a glob
+    Find {
+        #[structopt(flatten)]
+        repo_opts: RepoOpt,
+        #[structopt(flatten)]
+        glob_opts: GlobOpt,
+        /// Glob to match the paths of files against
+        #[structopt(name = "PATTERN")]
+        pattern: String,
+    },
+    /// Exports the objects in an archive as plain files in a directory
+    ExportObjects {
+        #[structopt(flatten)]
+        repo_opts: RepoOpt,
+        /// Name or ID of the archive to export
+        #[structopt(name = "ARCHIVE")]
+        archive: String,
+        /// Directory to export the objects into
+        #[structopt(name = "DIR")]
+        target: PathBuf,
+    },
+    /// Writes the contents of an archive to stdout as a tar stream
+    ExportTar {
+        #[structopt(flatten)]
+        repo_opts: RepoOpt,
+        /// Name or ID of the archive to export
+        #[structopt(name = "ARCHIVE")]
+        archive: String,
+    },
+    /// Creates a new archive from a tar stream read from stdin
+    ImportTar {
+        #[structopt(flatten)]
+        repo_opts: RepoOpt,
+        /// Name for the new archive. Defaults to an ISO date/time stamp
+        #[structopt(short, long)]
+        name: Option<String>,
+    },
+    /// Reports statistics about the contents of a repository
+    Stats {
+        #[structopt(flatten)]
+        repo_opts: RepoOpt,
+    },
+    /// Rewrites every chunk in a repository with the selected compression and encryption
+    ///
+    /// The selected settings also become the defaults for new chunks. The HMAC can not be
+    /// changed, as that would change the id of every chunk.
+    Recompress {
+        #[structopt(flatten)]
+        repo_opts: RepoOpt,
+    },
+    /// Copies every archive in a repository into a new repository, possibly of a different type
+    ///
+    /// The new repository is created with the selected chunk settings and the same password.
+    /// Archive names and timestamps are preserved.
+    Migrate {
+        #[structopt(flatten)]
+        repo_opts: RepoOpt,
+        /// Location of the new repository to copy the archives into
+        #[structopt(name = "DESTINATION")]
+        destination: PathBuf,
+        /// Type of the new repository
+        #[structopt(
+            long,
+            default_value = "FlatFile",
+            case_insensitive(true),
+            possible_values(&RepositoryType::variants())
+        )]
+        destination_type: RepositoryType,
+    },
+    /// Dumps the metadata of a single chunk, for debugging
+    Inspect {
+        #[structopt(flatten)]
+        repo_opts: RepoOpt,
+        /// ID, or a unique hex prefix of the ID, of the chunk to inspect
+        #[structopt(name = "CHUNK")]
+        prefix: String,
+    },
 }
 
 impl Command {
-    pub fn repo_opts(&self) -> &RepoOpt {
+    /// Returns the repository options of the command, or `None` for commands, such as the
+    /// benchmarks, that do not interact with a repository
+    pub fn repo_opts(&self) -> Option<&RepoOpt> {
         match self {
-            Self::List { repo_opts, .. } => repo_opts,
-            Self::Store { repo_opts, .. } => repo_opts,
-            Self::Extract { repo_opts, .. } => repo_opts,
-            Self::New { repo_opts, .. } => repo_opts,
-            Self::Contents {repo_opts, ..} => repo_opts,
-            Self::BenchCrypto => unimplemented!("asuran-cli bench does not interact with a repository, and does not have repository options."),
+            Self::List { repo_opts, .. }
+            | Self::Store { repo_opts, .. }
+            | Self::Extract { repo_opts, .. }
+            | Self::New { repo_opts, .. }
+            | Self::Contents { repo_opts, .. }
+            | Self::Find { repo_opts, .. }
+            | Self::ExportObjects { repo_opts, .. }
+            | Self::ExportTar { repo_opts, .. }
+            | Self::ImportTar { repo_opts, .. }
+            | Self::Stats { repo_opts, .. }
+            | Self::Inspect { repo_opts, .. }
+            | Self::Recompress { repo_opts, .. }
+            | Self::Migrate { repo_opts, .. } => Some(repo_opts),
+            Self::BenchCrypto | Self::BenchChunkers { .. } | Self::AutoTune { .. } => None,
         }
     }
 }
@@ -167,6 +369,103 @@ pub struct GlobOpt {
     /// operation.
     #[structopt(short = "E", long)]
     pub exclude: Option<Vec<String>>,
+    /// Files to read more include patterns from, one per line.
+    ///
+    /// Blank lines, and lines starting with #, are ignored.
+    #[structopt(long)]
+    pub include_from: Option<Vec<PathBuf>>,
+    /// Files to read more exclude patterns from, one per line.
+    ///
+    /// Blank lines, and lines starting with #, are ignored.
+    #[structopt(long)]
+    pub exclude_from: Option<Vec<PathBuf>>,
+}
+
+impl GlobOpt {
+    /// Builds a `GlobFilter` out of the user's patterns
+    pub fn filter(&self) -> Result<GlobFilter> {
+        let includes = self.include.as_deref().unwrap_or_default();
+        let excludes = self.exclude.as_deref().unwrap_or_default();
+        let include_files = self.include_from.as_deref().unwrap_or_default();
+        let exclude_files = self.exclude_from.as_deref().unwrap_or_default();
+        GlobFilter::from_files(includes, excludes, include_files, exclude_files)
+            .context("Invalid glob patterns")
+    }
+}
+
+/// Options controlling which files the store command stores, and how it stores them
+#[derive(Debug, StructOpt, Clone)]
+pub struct StoreOpt {
+    #[structopt(flatten)]
+    pub glob_opts: GlobOpt,
+    /// Skip the contents of directories tagged with a CACHEDIR.TAG file, or
+    /// containing a .nobackup file
+    #[structopt(long)]
+    pub exclude_caches: bool,
+    /// Name of per-directory ignore files, such as .asuranignore, holding
+    /// .gitignore style patterns that apply to the directory they are in.
+    ///
+    /// Rules from each directory stack with those of its parents.
+    #[structopt(long)]
+    pub ignore_file: Option<String>,
+    /// Detect and record the MIME type of each stored file.
+    ///
+    /// Requires an extra read of the start of every file.
+    #[structopt(long)]
+    pub detect_content_type: bool,
+    /// Files smaller than this many bytes are stored directly in the archive's
+    /// metadata, rather than as chunks. Zero disables this.
+    #[structopt(long, default_value = "0")]
+    pub inline_threshold: u64,
+    /// Maximum number of files to store at once
+    #[structopt(long, default_value = "30")]
+    pub concurrency: usize,
+}
+
+impl StoreOpt {
+    /// Builds the `StoreOptions` for `store_directory` out of the user's options
+    pub fn store_options(&self) -> Result<StoreOptions> {
+        Ok(StoreOptions {
+            globs: self.glob_opts.filter()?,
+            exclude_caches: self.exclude_caches,
+            ignore_file: self.ignore_file.clone(),
+            detect_content_type: self.detect_content_type,
+            inline_threshold: self.inline_threshold,
+            concurrency: self.concurrency,
+        })
+    }
+}
+
+/// Options controlling which files the extract command restores, and how it restores them
+#[derive(Debug, StructOpt, Clone)]
+pub struct ExtractOpt {
+    #[structopt(flatten)]
+    pub glob_opts: GlobOpt,
+    /// What to do when a file being extracted already exists.
+    ///
+    /// Rename moves the existing file aside to <name>.<n>.
+    #[structopt(
+        long,
+        default_value = "Fail",
+        case_insensitive(true),
+        possible_values(&OnConflict::variants())
+    )]
+    pub on_conflict: OnConflict,
+    /// Leave holes in place of blocks of zeros in restored files, on platforms
+    /// that support sparse files
+    #[structopt(long)]
+    pub sparse: bool,
+}
+
+impl ExtractOpt {
+    /// Builds the `ExtractOptions` for extraction out of the user's options
+    pub fn extract_options(&self) -> Result<ExtractOptions> {
+        Ok(ExtractOptions {
+            globs: self.glob_opts.filter()?,
+            policy: self.on_conflict.into(),
+            sparse: self.sparse,
+        })
+    }
 }
 
 /// Options that are shared among all repository commands
@@ -220,6 +519,20 @@ pub struct RepoOpt {
         possible_values(&HMAC::variants())
     )]
     pub hmac: HMAC,
+    /// Selects a preset bundle of chunk settings.
+    ///
+    /// fast is LZ4 level 1, ChaCha20, and BLAKE3. secure is ZStd level 19,
+    /// XChaCha20Poly1305, and BLAKE3. archival is LZMA level 9,
+    /// XChaCha20Poly1305, and SHA256.
+    ///
+    /// Overrides --encryption, --compression, --compression-level, and --hmac
+    /// if set.
+    #[structopt(
+        long,
+        case_insensitive(true),
+        possible_values(&Preset::variants())
+    )]
+    pub preset: Option<Preset>,
     /// Password to use for SFTP connection for SFTP backend.
     ///
     /// Will attempt to use ssh-agent authentication if not set.
@@ -230,6 +543,38 @@ pub struct RepoOpt {
     /// Will default to 22 if not specified
     #[structopt(long, env = "ASURAN_SFTP_PORT")]
     pub sftp_port: Option<u16>,
+    /// Number of times to reconnect and retry an operation if the SFTP connection drops.
+    ///
+    /// Set to 0 to fail on the first dropped connection.
+    #[structopt(long, env = "ASURAN_SFTP_RETRIES", default_value = "3")]
+    pub sftp_retries: u32,
+    /// Username to use for HTTP basic authentication with the WebDAV backend.
+    ///
+    /// Requires webdav_password to also be set.
+    #[structopt(long, env = "ASURAN_WEBDAV_USER")]
+    pub webdav_user: Option<String>,
+    /// Password to use for HTTP basic authentication with the WebDAV backend.
+    #[structopt(long, env = "ASURAN_WEBDAV_PASSWORD", hide_env_values = true)]
+    pub webdav_password: Option<String>,
+    /// Bearer token to use for authentication with the WebDAV backend.
+    ///
+    /// Takes priority over webdav_user/webdav_password if set.
+    #[structopt(long, env = "ASURAN_WEBDAV_TOKEN", hide_env_values = true)]
+    pub webdav_token: Option<String>,
+    /// Skip fsyncing repository files for MultiFile and FlatFile repositories.
+    ///
+    /// This can make backups much faster, but a crash or power loss may lose
+    /// data that was reported as written, or corrupt the repository. Only use
+    /// this for throwaway repositories.
+    #[structopt(long)]
+    pub no_fsync: bool,
+    /// File holding the hex encoded X25519 secret key to open sealed archives
+    /// with.
+    ///
+    /// Sealed archives this key is not a recipient of are listed as sealed,
+    /// and skipped by commands that read the contents of every archive.
+    #[structopt(long, env = "ASURAN_IDENTITY")]
+    pub identity: Option<PathBuf>,
 }
 
 /// Struct for holding the options the user has selected
@@ -255,20 +600,52 @@ pub struct Opt {
     /// Defaults to 0, which corresponds to the number of CPUs on the system.
     #[structopt(short = "T", long, default_value = "0", global = true)]
     pub pipeline_tasks: usize,
+    /// Number of requests to queue up for the repository backend.
+    ///
+    /// This is independent of the number of pipeline tasks. A deep queue with
+    /// few tasks suits high-latency backends, such as SFTP, while a shallow
+    /// queue with many tasks suits local disks. Defaults to 0, which
+    /// corresponds to eight times the number of pipeline tasks.
+    #[structopt(long, default_value = "0", global = true)]
+    pub queue_depth: usize,
+    /// Format to write the output of list, contents, and stats in
+    #[structopt(
+        long,
+        default_value = "Table",
+        global = true,
+        case_insensitive(true),
+        possible_values(&OutputFormat::variants())
+    )]
+    pub format: OutputFormat,
+    /// Format to report errors in
+    #[structopt(
+        long,
+        default_value = "Text",
+        global = true,
+        case_insensitive(true),
+        possible_values(&ErrorFormat::variants())
+    )]
+    pub error_format: ErrorFormat,
 }
 
 impl Opt {
-    pub fn get_chunk_settings(&self) -> repository::ChunkSettings {
-        self.command.repo_opts().get_chunk_settings()
+    pub fn get_chunk_settings(&self) -> Result<repository::ChunkSettings> {
+        Ok(self.repo_opts()?.get_chunk_settings())
     }
     pub async fn open_repo_backend(&self) -> Result<(BackendObject, Key)> {
-        self.command
-            .repo_opts()
-            .open_repo_backend(self.pipeline_tasks() * 8)
+        self.repo_opts()?
+            .open_repo_backend(self.queue_depth())
             .await
     }
-    pub fn repo_opts(&self) -> &RepoOpt {
-        self.command.repo_opts()
+    /// Returns the repository options of the command
+    ///
+    /// # Errors
+    ///
+    /// Will return `Err` if the command does not interact with a repository
+    pub fn repo_opts(&self) -> Result<&RepoOpt> {
+        self.command
+            .repo_opts()
+            .ok_or_else(|| anyhow!("This command does not interact with a repository"))
     }
     pub fn pipeline_tasks(&self) -> usize {
         if self.pipeline_tasks == 0 {
@@ -277,12 +654,57 @@ impl Opt {
             self.pipeline_tasks
         }
     }
+    /// Reads the secret key out of the user's identity file, if they provided one
+    pub fn identity(&self) -> Result<Option<SecretKey>> {
+        match &self.repo_opts()?.identity {
+            Some(path) => {
+                let contents = fs::read_to_string(path)
+                    .with_context(|| format!("Unable to read identity file {:?}", path))?;
+                let identity = contents
+                    .parse::<SecretKey>()
+                    .with_context(|| format!("Invalid secret key in identity file {:?}", path))?;
+                Ok(Some(identity))
+            }
+            None => Ok(None),
+        }
+    }
+    /// Returns the depth of the queue to use for the repository backend
+    ///
+    /// Falls back to eight times the number of pipeline tasks if no depth was given.
+    pub fn queue_depth(&self) -> usize {
+        if self.queue_depth == 0 {
+            self.pipeline_tasks() * 8
+        } else {
+            self.queue_depth
+        }
+    }
 }
 
 impl RepoOpt {
+    /// Builds the settings for connecting to the SFTP repository at `repo`, a
+    /// user@hostname:path string
+    pub fn sftp_settings(&self, repo: &str) -> Result<SFTPSettings> {
+        let (username, hostname, path) = parse_ssh_path(repo)?;
+        let mut settings = SFTPSettings::new(hostname, username, path);
+        settings.port = self.sftp_port;
+        settings.password = self.sftp_password.clone();
+        settings.set_retry_policy(RetryPolicy {
+            max_attempts: self.sftp_retries,
+            ..RetryPolicy::default()
+        });
+        Ok(settings)
+    }
+
     /// Generates an `asuran::repostiory::ChunkSettings` from the options the
     /// user has selected
     pub fn get_chunk_settings(&self) -> repository::ChunkSettings {
+        match self.preset {
+            Some(Preset::Fast) => return repository::ChunkSettings::fast(),
+            Some(Preset::Secure) => return repository::ChunkSettings::secure(),
+            Some(Preset::Archival) => return repository::ChunkSettings::archival(),
+            None => {}
+        }
+
         let compression = match self.compression {
             Compression::ZStd => self
                 .compression_level
@@ -292,6 +714,14 @@ impl RepoOpt {
                 .compression_level
                 .map(|x| repository::Compression::LZ4 { level: x })
                 .unwrap_or(repository::Compression::LZ4 { level: 4 }),
+            Compression::LZ4Frame => self
+                .compression_level
+                .map(|x| repository::Compression::LZ4Frame { level: x })
+                .unwrap_or(repository::Compression::LZ4Frame { level: 4 }),
+            Compression::Auto => self
+                .compression_level
+                .map(|x| repository::Compression::Auto { level: x as i32 })
+                .unwrap_or(repository::Compression::Auto { level: 3 }),
             Compression::None => repository::Compression::NoCompression,
             Compression::LZMA => self
                 .compression_level
@@ -302,6 +732,7 @@ impl RepoOpt {
         let encryption = match self.encryption {
             Encryption::AES256CTR => repository::Encryption::new_aes256ctr(),
             Encryption::ChaCha20 => repository::Encryption::new_chacha20(),
+            Encryption::XChaCha20Poly1305 => repository::Encryption::new_xchacha20poly1305(),
             Encryption::None => repository::Encryption::NoEncryption,
         };
 
@@ -311,6 +742,7 @@ impl RepoOpt {
             HMAC::Blake2bp => repository::HMAC::Blake2bp,
             HMAC::Blake3 => repository::HMAC::Blake3,
             HMAC::SHA3 => repository::HMAC::SHA3,
+            HMAC::Blake3XOF => repository::HMAC::Blake3XOF,
         };
 
         repository::ChunkSettings {
@@ -320,6 +752,15 @@ impl RepoOpt {
         }
     }
 
+    /// Returns the durability requested for the repository's files
+    pub fn durability(&self) -> Durability {
+        if self.no_fsync {
+            Durability::None
+        } else {
+            Durability::Full
+        }
+    }
+
     /// Attempts to open up a connection to the repostiory, based on the information
     /// passed in the Options
     ///
@@ -327,26 +768,14 @@ impl RepoOpt {
     ///
     /// Will return Err if
     ///
-    /// 1. The give repository path is of the wrong type (i.e a folder when a FlatFile
-    ///    was requested)
+    /// 1. There is no repository of the requested type at the given location
     /// 2. Some other error defined in the repostiory implementation occurs trying to open it
     pub async fn open_repo_backend(&self, queue_depth: usize) -> Result<(BackendObject, Key)> {
         match self.repository_type {
             RepositoryType::MultiFile => {
-                // Ensure that the repository path exsits and is a folder
-                if !self.repo.exists() {
-                    return Err(anyhow!(
-                        "Attempted to open a repository at a path that does not exist."
-                    ));
-                }
-                let md = metadata(&self.repo).with_context(|| {
-                    format!(
-                        "IO error when attempting to open MultiFile at {:?}",
-                        &self.repo
-                    )
-                })?;
-                if !md.is_dir() {
-                    return Err(anyhow!("Attempted to open a MultiFile repository, but the path provided was not a folder."));
+                // Ensure that there is a repository there before asking for anything else
+                if !multifile::MultiFile::exists(&self.repo) {
+                    return Err(anyhow!("No MultiFile repository found at {:?}", &self.repo));
                 }
 
                 // First, attempt to read the multifile key
@@ -360,49 +789,39 @@ impl RepoOpt {
                         "Unable to decrypt key material, possibly due to an invalid password"
                     })?;
 
-                // Actually open the repository, and wrap it in a dynamic backend
-                let chunk_settings = self.get_chunk_settings();
-                let multifile = multifile::MultiFile::open_defaults(
+                // Actually open the repository, and wrap it in a dynamic backend. The repository's
+                // own chunk settings are used, the user's only apply to the chunks they write.
+                let multifile = multifile::MultiFile::open_with_durability(
                     &self.repo,
-                    Some(chunk_settings),
+                    None,
                     &key,
                     queue_depth,
+                    self.durability(),
                 )
                 .await
                 .with_context(|| "Exeprienced an internal backend error.")?;
                 Ok((multifile.get_object_handle(), key))
             }
             RepositoryType::FlatFile => {
-                // First, make sure the repository exists and is a file
-                if !self.repo.exists() {
-                    return Err(anyhow!(
-                        "Attempted to open a repository path that does not exist"
-                    ));
-                }
-                let md = metadata(&self.repo).with_context(|| {
-                    format!(
-                        "IO error when attempting to open FlatFile at {:?}",
-                        &self.repo
-                    )
-                })?;
-                if !md.is_file() {
-                    return Err(anyhow!("Attempted to open a FlatFile repository, but the path provided was not a folder"));
+                // First, make sure there is a repository there
+                if !flatfile::FlatFile::exists(&self.repo) {
+                    return Err(anyhow!("No FlatFile repository found at {:?}", &self.repo));
                 }
 
-                // Attempt to open up the flatfile backend
-                let chunk_settings = self.get_chunk_settings();
                 // Attempt to read and decrypt the key
                 let key = flatfile::FlatFile::load_encrypted_key(&self.repo)
                     .with_context(|| "Failed to read key from flatfile.")?;
                 let key = key.decrypt(self.password.as_bytes()).with_context(|| {
                     "Unable to decrypt key material, possibly due to an invalid password"
                 })?;
-                let flatfile = flatfile::FlatFile::new(
+                // Attempt to open up the flatfile backend
+                let flatfile = flatfile::FlatFile::new_with_durability(
                     &self.repo,
-                    Some(chunk_settings),
+                    None,
                     None,
                     key.clone(),
                     queue_depth,
+                    self.durability(),
                 )
                 .with_context(|| "Internal backen d error opening flatfile.")?;
                 let flatfile = flatfile.get_object_handle();
@@ -411,27 +830,149 @@ impl RepoOpt {
             RepositoryType::SFTP => {
                 use asuran::repository::backend::sftp::*;
                 let repo_str = self.repo.to_str().context("Non utf-8 in sftp path")?;
-                let (username, hostname, path) = parse_ssh_path(repo_str)?;
-                let settings = SFTPSettings {
-                    hostname,
-                    port: self.sftp_port,
-                    username,
-                    password: self.sftp_password.clone(),
-                    path,
-                };
+                let settings = self.sftp_settings(repo_str)?;
+                if !SFTP::exists(settings.clone()).context("Unable to reach the repository")? {
+                    return Err(anyhow!("No SFTP repository found at {:?}", &self.repo));
+                }
                 let key = SFTP::read_key(settings.clone())
                     .context("Unable to read repository key material")?
                     .decrypt(self.password.as_bytes())
                     .context(
                         "Failed to decrypt key material, possibly due to an invalid password",
                     )?;
-                let chunk_settings = self.get_chunk_settings();
-                let sftp = SFTP::connect(settings, key.clone(), Some(chunk_settings), queue_depth)
+                let sftp = SFTP::connect(settings, key.clone(), None, queue_depth)
                     .context("Failed to connect to SFTP backend")?;
                 Ok((sftp.get_object_handle(), key))
             }
+            RepositoryType::WebDAV => {
+                #[cfg(feature = "webdav")]
+                {
+                    use asuran::repository::backend::webdav::*;
+                    let settings = self.webdav_settings()?;
+                    let key = WebDav::read_key(settings.clone())
+                        .context("Unable to read repository key material")?
+                        .decrypt(self.password.as_bytes())
+                        .context(
+                            "Failed to decrypt key material, possibly due to an invalid password",
+                        )?;
+                    let webdav = WebDav::connect(settings, key.clone(), None, queue_depth)
+                        .context("Failed to connect to WebDAV backend")?;
+                    Ok((webdav.get_object_handle(), key))
+                }
+                #[cfg(not(feature = "webdav"))]
+                {
+                    Err(anyhow!("asuran-cli was not compiled with WebDAV support"))
+                }
+            }
+            RepositoryType::HTTP => {
+                #[cfg(feature = "http")]
+                {
+                    use asuran::repository::backend::http::*;
+                    let url = self
+                        .repo
+                        .to_str()
+                        .context("Non utf-8 in HTTP url")?
+                        .to_string();
+                    let settings = HttpSettings { url };
+                    let key = Http::read_key(settings.clone())
+                        .context("Unable to read repository key material")?
+                        .decrypt(self.password.as_bytes())
+                        .context(
+                            "Failed to decrypt key material, possibly due to an invalid password",
+                        )?;
+                    let http = Http::connect(settings, key.clone(), queue_depth)
+                        .context("Failed to connect to HTTP backend")?;
+                    Ok((http.get_object_handle(), key))
+                }
+                #[cfg(not(feature = "http"))]
+                {
+                    Err(anyhow!("asuran-cli was not compiled with HTTP support"))
+                }
+            }
         }
     }
+
+    /// Builds the settings for the WebDAV backend from the repository URL and the
+    /// provided credentials
+    ///
+    /// The repository is located at the root of the given URL.
+    #[cfg(feature = "webdav")]
+    pub fn webdav_settings(&self) -> Result<asuran::repository::backend::webdav::WebDavSettings> {
+        use asuran::repository::backend::webdav::{WebDavAuth, WebDavSettings};
+        let url = self
+            .repo
+            .to_str()
+            .context("Non utf-8 in WebDAV url")?
+            .to_string();
+        let auth = if let Some(token) = &self.webdav_token {
+            WebDavAuth::Bearer(token.clone())
+        } else if let Some(username) = &self.webdav_user {
+            let password = self
+                .webdav_password
+                .clone()
+                .context("A WebDAV username was provided, but no password was")?;
+            WebDavAuth::Basic {
+                username: username.clone(),
+                password,
+            }
+        } else {
+            WebDavAuth::Anonymous
+        };
+        Ok(WebDavSettings {
+            url,
+            auth,
+            path: String::new(),
+        })
+    }
+}
+
+/// Loads an archive, unsealing it with `identity` if it is sealed
+///
+/// Returns `None` for sealed archives `identity` can not open, or all sealed archives if no
+/// identity was provided.
+pub async fn load_archive(
+    repo: &mut Repository<impl BackendClone>,
+    stored_archive: &StoredArchive,
+    identity: Option<&SecretKey>,
+) -> Result<Option<ActiveArchive>> {
+    let result = match identity {
+        Some(identity) => stored_archive.load_as(repo, identity).await,
+        None => stored_archive.load(repo).await,
+    };
+    unsealed(result)
+}
+
+/// Loads the metadata of an archive, unsealing it with `identity` if it is sealed, see
+/// `load_archive`
+pub async fn load_archive_metadata(
+    repo: &mut Repository<impl BackendClone>,
+    stored_archive: &StoredArchive,
+    identity: Option<&SecretKey>,
+) -> Result<Option<Archive>> {
+    let result = match identity {
+        Some(identity) => stored_archive.load_metadata_as(repo, identity).await,
+        None => stored_archive.load_metadata(repo).await,
+    };
+    unsealed(result)
+}
+
+/// Turns the errors for sealed archives that could not be opened into `None`
+fn unsealed<T>(result: std::result::Result<T, ArchiveError>) -> Result<Option<T>> {
+    match result {
+        Ok(archive) => Ok(Some(archive)),
+        Err(ArchiveError::Sealed) | Err(ArchiveError::Recipient(RecipientError::NotARecipient)) => {
+            Ok(None)
+        }
+        Err(e) => Err(e.into()),
+    }
+}
+
+/// The error reported when the user asks for a sealed archive they can not open
+pub fn sealed_archive_error(stored_archive: &StoredArchive) -> anyhow::Error {
+    anyhow!(
+        "Archive {} is sealed, and can only be opened with the --identity of one of its recipients",
+        stored_archive.id().to_hex()
+    )
 }
 
 /// Takes a string of type user@host:/path, with optional user, and returns a tuple of strings of
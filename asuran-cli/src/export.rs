@@ -0,0 +1,73 @@
+use crate::cli::{load_archive, sealed_archive_error, Opt};
+
+use asuran::export::*;
+use asuran::manifest::*;
+use asuran::repository::encryption::SecretKey;
+use asuran::repository::*;
+
+use anyhow::{anyhow, Result};
+
+use std::io::{stdout, BufWriter};
+use std::path::PathBuf;
+
+/// Finds the archive in the repository matching the user provided id, index, or name
+///
+/// Sealed archives are unsealed with `identity`
+async fn find_archive(
+    repo: &mut Repository<impl BackendClone>,
+    archive_name: &str,
+    identity: Option<&SecretKey>,
+) -> Result<ActiveArchive> {
+    let mut manifest = Manifest::load(repo);
+    match manifest.find_archive(repo, archive_name).await? {
+        Some(stored_archive) => load_archive(repo, &stored_archive, identity)
+            .await?
+            .ok_or_else(|| sealed_archive_error(&stored_archive)),
+        None => Err(anyhow!(
+            "Provided archive name, {}, does not match any archives in the repository.",
+            archive_name
+        )),
+    }
+}
+
+/// Exports the objects in the user provided archive as plain files in the user provided
+/// directory
+pub async fn export_objects(options: Opt, archive_name: String, target: PathBuf) -> Result<()> {
+    // Open the repository
+    let (backend, key) = options.open_repo_backend().await?;
+    let chunk_settings = options.get_chunk_settings()?;
+    let mut repo = Repository::with(backend, chunk_settings, key, options.pipeline_tasks())?;
+    let archive = find_archive(&mut repo, &archive_name, options.identity()?.as_ref()).await?;
+    if !options.quiet {
+        println!(
+            "Exporting archive {} taken at {}",
+            archive.name(),
+            archive.timestamp().to_rfc2822()
+        );
+    }
+    export_archive(&archive, &mut repo, &target).await?;
+    repo.close().await;
+    Ok(())
+}
+
+/// Writes the user provided archive to stdout as a tar stream
+pub async fn export_tar(options: Opt, archive_name: String) -> Result<()> {
+    // Open the repository
+    let (backend, key) = options.open_repo_backend().await?;
+    let chunk_settings = options.get_chunk_settings()?;
+    let mut repo = Repository::with(backend, chunk_settings, key, options.pipeline_tasks())?;
+    let archive = find_archive(&mut repo, &archive_name, options.identity()?.as_ref()).await?;
+    // stdout is carrying the tar stream, so status goes to stderr
+    if !options.quiet {
+        eprintln!(
+            "Exporting archive {} taken at {}",
+            archive.name(),
+            archive.timestamp().to_rfc2822()
+        );
+    }
+    let stdout = stdout();
+    let output = BufWriter::new(stdout.lock());
+    archive_to_tar(&archive, &mut repo, output).await?;
+    repo.close().await;
+    Ok(())
+}
@@ -0,0 +1,47 @@
+use crate::cli::{GlobOpt, Opt, OutputFormat};
+use crate::output::{print_json, ContentsEntry, FindResult};
+
+use asuran::manifest::*;
+use asuran::repository::*;
+
+use anyhow::{Context, Result};
+use prettytable::{cell, row, Table};
+
+/// Searches every archive in the repository for files matching the user provided glob, and
+/// prints out where they were found, along with their sizes
+pub async fn find(options: Opt, pattern: String, glob_opts: GlobOpt) -> Result<()> {
+    // Open the repository
+    let (backend, key) = options.open_repo_backend().await?;
+    let chunk_settings = options.get_chunk_settings()?;
+    let mut repo = Repository::with(backend, chunk_settings, key, options.pipeline_tasks())?;
+    let pattern = GlobFilter::new(&[pattern], &[] as &[&str]).context("Invalid glob pattern")?;
+    let filter = glob_opts.filter()?;
+    // Each archive's listing is only loaded once, by the search itself
+    let results: Vec<FindResult> = repo
+        .find(&pattern, &filter)
+        .await?
+        .into_iter()
+        .map(|(stored_archive, name, nodes)| FindResult {
+            archive: name,
+            id: stored_archive.id().to_hex(),
+            files: nodes.into_iter().map(ContentsEntry::from).collect(),
+        })
+        .collect();
+    match options.format {
+        OutputFormat::JSON => {
+            print_json(&results)?;
+        }
+        OutputFormat::Table => {
+            let mut table = Table::new();
+            table.add_row(row!["Archive", "Path", "Size"]);
+            for result in &results {
+                for file in &result.files {
+                    table.add_row(row![result.archive, file.path, file.total_length]);
+                }
+            }
+            table.printstd();
+        }
+    }
+    repo.close().await;
+    Ok(())
+}
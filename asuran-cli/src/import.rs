@@ -0,0 +1,38 @@
+use crate::cli::Opt;
+
+use asuran::chunker::*;
+use asuran::import::*;
+use asuran::repository::*;
+
+use anyhow::Result;
+use chrono::prelude::*;
+
+use std::io::{stdin, BufReader};
+
+/// Creates a new archive in a repository from a tar stream read from stdin
+pub async fn import_tar(options: Opt, name: Option<String>) -> Result<()> {
+    // Open the repository
+    let (backend, key) = options.open_repo_backend().await?;
+    let chunk_settings = options.get_chunk_settings()?;
+    let mut repo = Repository::with(backend, chunk_settings, key, options.pipeline_tasks())?;
+    // Make sure we have a name for the archive, defaulting to the current
+    // date/time if the user did not provide us one
+    let name = name.unwrap_or_else(|| {
+        Local::now()
+            .with_timezone(Local::now().offset())
+            .to_rfc2822()
+    });
+    // TOOD: Allow chunker configuration
+    let chunker = FastCDC::default();
+    let input = BufReader::new(stdin());
+    let archive = tar_to_archive(input, &chunker, &mut repo, &name).await?;
+    if !options.quiet {
+        println!(
+            "Imported archive {} taken at {}",
+            name,
+            archive.timestamp().to_rfc2822()
+        );
+    }
+    repo.close().await;
+    Ok(())
+}
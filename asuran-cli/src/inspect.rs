@@ -0,0 +1,48 @@
+use crate::cli::{Opt, OutputFormat};
+use crate::output::{print_json, ChunkDescription};
+
+use asuran::repository::*;
+
+use anyhow::{anyhow, Result};
+use prettytable::{cell, row, Table};
+
+/// Resolves a chunk id prefix and prints out how that chunk is stored
+pub async fn inspect(options: Opt, prefix: String) -> Result<()> {
+    // Open the repository
+    let (backend, key) = options.open_repo_backend().await?;
+    let chunk_settings = options.get_chunk_settings()?;
+    let mut repo = Repository::with(backend, chunk_settings, key, options.pipeline_tasks())?;
+    // A complete id is used as is, anything else is resolved as a prefix to exactly one chunk
+    let matches = match prefix.parse::<ChunkID>() {
+        Ok(id) => vec![id],
+        Err(_) => repo.find_chunks_by_prefix(&prefix).await,
+    };
+    let id = match matches.as_slice() {
+        [id] => *id,
+        [] => return Err(anyhow!("No chunk matches the prefix {}", prefix)),
+        _ => {
+            return Err(anyhow!(
+                "The prefix {} is ambiguous, it matches {} chunks",
+                prefix,
+                matches.len()
+            ))
+        }
+    };
+    let info = ChunkDescription::from(&repo.chunk_info(id).await?);
+    match options.format {
+        OutputFormat::JSON => print_json(&info)?,
+        OutputFormat::Table => {
+            let mut table = Table::new();
+            table.add_row(row!["ID", info.id]);
+            table.add_row(row!["Compression", info.compression]);
+            table.add_row(row!["Encryption", info.encryption]);
+            table.add_row(row!["HMAC", info.hmac]);
+            table.add_row(row!["Stored length", info.stored_length]);
+            table.add_row(row!["Segment", info.segment]);
+            table.add_row(row!["Position in segment", info.position]);
+            table.printstd();
+        }
+    }
+    repo.close().await;
+    Ok(())
+}
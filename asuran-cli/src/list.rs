@@ -1,4 +1,5 @@
-use crate::cli::Opt;
+use crate::cli::{load_archive, Opt, OutputFormat};
+use crate::output::{print_json, ArchiveList, ArchiveSummary};
 
 use asuran::manifest::*;
 use asuran::repository::*;
@@ -7,36 +8,76 @@ use anyhow::Result;
 use prettytable::{cell, row, Table};
 
 /// Iterates through a repository's manifest and pretty prints all the archives
-pub async fn list(options: Opt) -> Result<()> {
+///
+/// If any tags are provided, only archives carrying all of them are printed. Sealed archives the
+/// user's identity can not open are printed as sealed, and never match any tags.
+pub async fn list(options: Opt, tags: Vec<String>) -> Result<()> {
     // Open the repository
     let (backend, key) = options.open_repo_backend().await?;
-    let chunk_settings = options.get_chunk_settings();
-    let mut repo = Repository::with(backend, chunk_settings, key, options.pipeline_tasks());
+    let chunk_settings = options.get_chunk_settings()?;
+    let mut repo = Repository::with(backend, chunk_settings, key, options.pipeline_tasks())?;
     // load the manifest
     let mut manifest = Manifest::load(&repo);
+    let identity = options.identity()?;
     // Get the list of archives and extract them from the repository
-    let mut archives: Vec<ActiveArchive> = Vec::new();
+    let mut archives: Vec<(StoredArchive, Option<ActiveArchive>)> = Vec::new();
     for stored_archive in manifest.archives().await {
-        let archive = stored_archive.load(&mut repo).await?;
-        archives.push(archive);
+        let archive = load_archive(&mut repo, &stored_archive, identity.as_ref()).await?;
+        archives.push((stored_archive, archive));
     }
-    // Print out basic archive stats
-    println!("Number of archives in repository: {}", archives.len());
-    println!(
-        "Repository last modified: {}",
-        manifest.timestamp().await?.to_rfc2822()
-    );
-    // Iterate through the list of archives, and print them out in a nice table
-    let mut table = Table::new();
-    table.add_row(row!["Index", "Name", "Creation Time"]);
-    for (index, archive) in archives.into_iter().enumerate() {
-        table.add_row(row![
-            index,
-            archive.name(),
-            &archive.timestamp().to_rfc2822()
-        ]);
+    // Indexes are assigned before filtering, so they still refer to the
+    // archive's position in the manifest
+    let archives: Vec<(usize, (StoredArchive, Option<ActiveArchive>))> = archives
+        .into_iter()
+        .enumerate()
+        .filter(|(_, (_, archive))| match archive {
+            Some(archive) => tags.iter().all(|tag| archive.has_tag(tag)),
+            None => tags.is_empty(),
+        })
+        .collect();
+    let last_modified = manifest.timestamp().await?;
+    match options.format {
+        OutputFormat::JSON => {
+            let archives = archives
+                .iter()
+                .map(|(index, (stored_archive, archive))| match archive {
+                    Some(archive) => ArchiveSummary::new(*index, stored_archive.id(), archive),
+                    None => ArchiveSummary::sealed(*index, stored_archive),
+                })
+                .collect();
+            print_json(&ArchiveList {
+                last_modified: last_modified.to_rfc3339(),
+                key_fingerprint: repo.key_fingerprint(),
+                archives,
+            })?;
+        }
+        OutputFormat::Table => {
+            // Print out basic archive stats
+            println!("Number of archives in repository: {}", archives.len());
+            println!("Repository last modified: {}", last_modified.to_rfc2822());
+            println!("Key fingerprint: {}", repo.key_fingerprint());
+            // Iterate through the list of archives, and print them out in a nice table
+            let mut table = Table::new();
+            table.add_row(row!["Index", "Name", "Creation Time", "Tags"]);
+            for (index, (stored_archive, archive)) in archives {
+                match archive {
+                    Some(archive) => table.add_row(row![
+                        index,
+                        archive.name(),
+                        &archive.timestamp().to_rfc2822(),
+                        archive.tags().join(", ")
+                    ]),
+                    None => table.add_row(row![
+                        index,
+                        "<sealed>",
+                        &stored_archive.timestamp().to_rfc2822(),
+                        ""
+                    ]),
+                };
+            }
+            table.printstd();
+        }
     }
-    table.printstd();
     repo.close().await;
     Ok(())
 }
@@ -11,16 +11,32 @@ mod bench;
 #[cfg_attr(tarpaulin, skip)]
 mod contents;
 #[cfg_attr(tarpaulin, skip)]
+mod export;
+#[cfg_attr(tarpaulin, skip)]
 mod extract;
 #[cfg_attr(tarpaulin, skip)]
+mod find;
+#[cfg_attr(tarpaulin, skip)]
+mod import;
+#[cfg_attr(tarpaulin, skip)]
+mod inspect;
+#[cfg_attr(tarpaulin, skip)]
 mod list;
 #[cfg_attr(tarpaulin, skip)]
+mod migrate;
+#[cfg_attr(tarpaulin, skip)]
 mod new;
 #[cfg_attr(tarpaulin, skip)]
+mod output;
+#[cfg_attr(tarpaulin, skip)]
+mod recompress;
+#[cfg_attr(tarpaulin, skip)]
+mod stats;
+#[cfg_attr(tarpaulin, skip)]
 mod store;
 
 use anyhow::Result;
-use cli::{Command, Opt};
+use cli::{Command, ErrorFormat, Opt};
 use std::thread;
 use structopt::StructOpt;
 
@@ -33,26 +49,68 @@ fn main() -> Result<()> {
         let r = r.clone();
         threads.push(thread::spawn(move || smol::run(r.recv())));
     }
+    let options = Opt::from_args();
+    let error_format = options.error_format;
     let result = smol::block_on(async {
-        // Our task in main is dead simple, we only need to parse the options and
-        // match on the subcommand
-        let options = Opt::from_args();
+        // Our task in main is dead simple, we only need to match on the subcommand
         let command = options.command.clone();
         match command {
             Command::New { .. } => new::new(options).await,
-            Command::Store { target, name, .. } => store::store(options, target, name).await,
-            Command::List { .. } => list::list(options).await,
+            Command::Store {
+                target,
+                name,
+                store_opts,
+                tags,
+                comment,
+                recipients,
+                ..
+            } => {
+                let store_options = store_opts.store_options()?;
+                store::store(
+                    options,
+                    target,
+                    name,
+                    store_options,
+                    tags,
+                    comment,
+                    recipients,
+                )
+                .await
+            }
+            Command::List { tags, .. } => list::list(options, tags).await,
             Command::Extract {
                 target,
                 archive,
-                glob_opts,
+                extract_opts,
                 preview,
+                resume,
                 ..
-            } => extract::extract(options, target, archive, glob_opts, preview).await,
+            } => {
+                let extract_options = extract_opts.extract_options()?;
+                extract::extract(options, target, archive, extract_options, preview, resume).await
+            }
             Command::BenchCrypto => bench::bench_crypto().await,
+            Command::BenchChunkers { size } => bench::bench_chunkers(size).await,
+            Command::AutoTune { sample, size } => bench::auto_tune(sample, size).await,
             Command::Contents {
                 archive, glob_opts, ..
             } => contents::contents(options, archive, glob_opts).await,
+            Command::Find {
+                pattern, glob_opts, ..
+            } => find::find(options, pattern, glob_opts).await,
+            Command::ExportObjects {
+                archive, target, ..
+            } => export::export_objects(options, archive, target).await,
+            Command::ExportTar { archive, .. } => export::export_tar(options, archive).await,
+            Command::ImportTar { name, .. } => import::import_tar(options, name).await,
+            Command::Stats { .. } => stats::stats(options).await,
+            Command::Inspect { prefix, .. } => inspect::inspect(options, prefix).await,
+            Command::Recompress { .. } => recompress::recompress(options).await,
+            Command::Migrate {
+                destination,
+                destination_type,
+                ..
+            } => migrate::migrate(options, destination, destination_type).await,
         }
     });
     drop(s);
@@ -61,5 +119,15 @@ fn main() -> Result<()> {
         let _ = t.join();
     }
 
-    result
+    match (result, error_format) {
+        (Err(e), ErrorFormat::JSON) => {
+            eprintln!("{}", serde_json::to_string(&output::ErrorReport::from(&e))?);
+            std::process::exit(1);
+        }
+        (Err(e), ErrorFormat::Text) if output::is_retryable(&e) => {
+            eprintln!("Note: this error may be temporary, retrying the command may succeed");
+            Err(e)
+        }
+        (result, _) => result,
+    }
 }
@@ -0,0 +1,81 @@
+use crate::cli::{Command, Opt, RepositoryType};
+
+use asuran::manifest::*;
+use asuran::repository::*;
+
+use anyhow::{Context, Result};
+
+use std::path::PathBuf;
+
+/// Creates a new repository at `destination`, and copies every archive in the user's repository
+/// into it
+///
+/// The destination gets its own key, so sealed archives, which can only be copied between
+/// repositories sharing a key, are skipped.
+pub async fn migrate(
+    options: Opt,
+    destination: PathBuf,
+    destination_type: RepositoryType,
+) -> Result<()> {
+    // Open the source repository
+    let (backend, key) = options.open_repo_backend().await?;
+    let chunk_settings = options.get_chunk_settings()?;
+    let mut source = Repository::with(backend, chunk_settings, key, options.pipeline_tasks())?;
+    let mut source_manifest = Manifest::load(&source);
+
+    // Create the destination with the same options as the source, other than its location and type
+    let mut dest_opts = options.repo_opts()?.clone();
+    dest_opts.repo = destination;
+    dest_opts.repository_type = destination_type;
+    let dest_options = Opt {
+        command: Command::New {
+            repo_opts: dest_opts.clone(),
+        },
+        quiet: options.quiet,
+        pipeline_tasks: options.pipeline_tasks,
+        queue_depth: options.queue_depth,
+        format: options.format,
+        error_format: options.error_format,
+    };
+    crate::new::new(dest_options)
+        .await
+        .context("Unable to create destination repository")?;
+    let (backend, key) = dest_opts.open_repo_backend(options.queue_depth()).await?;
+    let mut dest = Repository::with(
+        backend,
+        dest_opts.get_chunk_settings(),
+        key,
+        options.pipeline_tasks(),
+    )?;
+    let mut dest_manifest = Manifest::load(&dest);
+
+    // Copy the archives over, oldest first, so they are listed in the same order
+    let mut archives = source_manifest.archives().await;
+    archives.sort_by_key(StoredArchive::timestamp);
+    let mut copied = 0;
+    for archive in &archives {
+        match dest_manifest
+            .copy_archive(&mut dest, &mut source, archive)
+            .await
+        {
+            Ok(_) => copied += 1,
+            Err(ArchiveError::Sealed) => {
+                eprintln!("Skipping sealed archive {}", archive.id().to_hex());
+                continue;
+            }
+            Err(e) => {
+                return Err(e)
+                    .with_context(|| format!("Failed to copy archive {}", archive.id().to_hex()))
+            }
+        }
+        if !options.quiet {
+            println!("Copied archive {}", archive.id().to_hex());
+        }
+    }
+    if !options.quiet {
+        println!("Migrated {} archives", copied);
+    }
+    dest.close().await;
+    source.close().await;
+    Ok(())
+}
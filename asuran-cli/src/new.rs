@@ -13,40 +13,45 @@ use std::path::PathBuf;
 /// Creates a new repository with the user specified settings ad the user
 /// specified location
 pub async fn new(options: Opt) -> Result<()> {
+    let repo_opts = options.repo_opts()?;
     // Ensure that the repository path does not exist
-    if options.repo_opts().repo.exists() {
+    if repo_opts.repo.exists() {
         return Err(anyhow!(
             "Repository location already exists! {:?}",
-            &options.repo_opts().repo
+            &repo_opts.repo
         ));
     }
 
     // Figure out what encryption type the user wants to use and get the encryption length
-    let settings = options.get_chunk_settings();
+    let settings = options.get_chunk_settings()?;
+    settings
+        .validate()
+        .context("Refusing to create a repository with invalid settings")?;
     let key_length = settings.encryption.key_length();
     // Make them a new random key
     let key = Key::random(key_length);
     // Attempt to encrypt that key with the user supplied password
-    let encrypted_key = EncryptedKey::encrypt_defaults(
-        &key,
-        settings.encryption,
-        options.repo_opts().password.as_bytes(),
-    );
+    let encrypted_key =
+        EncryptedKey::encrypt_defaults(&key, settings.encryption, repo_opts.password.as_bytes());
 
     // Figure out which type of repository they want, and create it
-    match options.repo_opts().repository_type {
+    match repo_opts.repository_type {
         RepositoryType::MultiFile => {
             // Create the directory
-            create_dir_all(&options.repo_opts().repo)?;
+            create_dir_all(&repo_opts.repo)?;
             // Open the repository and set the key
-            let mut mf = MultiFile::open_defaults(
-                &options.repo_opts().repo,
+            let mut mf = MultiFile::open_with_durability(
+                &repo_opts.repo,
                 Some(settings),
                 &key,
-                options.pipeline_tasks() * 2,
+                options.queue_depth(),
+                repo_opts.durability(),
             )
             .await
             .with_context(|| "Unable to create MultiFile directory.")?;
+            mf.ping()
+                .await
+                .context("Unable to reach the new repository")?;
             mf.write_key(&encrypted_key)
                 .await
                 .with_context(|| "Failed to write key to new repository.")?;
@@ -55,12 +60,13 @@ pub async fn new(options: Opt) -> Result<()> {
         }
         RepositoryType::FlatFile => {
             // Open the repository setting the key
-            let mut ff = FlatFile::new(
-                &options.repo_opts().repo,
+            let mut ff = FlatFile::new_with_durability(
+                &repo_opts.repo,
                 Some(settings),
                 Some(encrypted_key),
                 key,
-                options.pipeline_tasks() * 2,
+                options.queue_depth(),
+                repo_opts.durability(),
             )
             .with_context(|| "Unable to create flatfile.")?;
             ff.close().await;
@@ -68,27 +74,20 @@ pub async fn new(options: Opt) -> Result<()> {
         }
         RepositoryType::SFTP => {
             use asuran::repository::backend::sftp::*;
-            let opts = options.repo_opts();
-            let path = opts
+            let path = repo_opts
                 .repo
                 .to_str()
                 .context("user/hostname/path string contained non-utf-8")?;
-            let (username, hostname, path) = crate::cli::parse_ssh_path(path)
-                .context("Unable to parse user/hostname/path string")?;
             let chunk_settings = settings;
-            let settings = SFTPSettings {
-                hostname,
-                port: opts.sftp_port,
-                username,
-                password: opts.sftp_password.clone(),
-                path: path.clone(),
-            };
+            let settings = repo_opts
+                .sftp_settings(path)
+                .context("Unable to parse user/hostname/path string")?;
             let mut connection: SFTPConnection = settings.clone().into();
             connection
                 .connect()
                 .context("Unable to make SFTP Connection")?;
             // Create the directory the repository is in
-            let path_as_path = PathBuf::from(path);
+            let path_as_path = PathBuf::from(&settings.path);
             let sftp = connection.sftp().unwrap();
             let mut ancestors = path_as_path.ancestors().collect::<Vec<_>>();
             ancestors.reverse();
@@ -103,13 +102,12 @@ pub async fn new(options: Opt) -> Result<()> {
                 }
             }
 
-            let mut sftp = SFTP::connect(
-                settings,
-                key,
-                Some(chunk_settings),
-                options.pipeline_tasks() * 2,
-            )
-            .context("Failed to connect to SFTP backend")?;
+            let mut sftp =
+                SFTP::connect(settings, key, Some(chunk_settings), options.queue_depth())
+                    .context("Failed to connect to SFTP backend")?;
+            sftp.ping()
+                .await
+                .context("Unable to reach the new repository")?;
 
             sftp.write_key(&encrypted_key)
                 .await
@@ -118,5 +116,34 @@ pub async fn new(options: Opt) -> Result<()> {
             sftp.close().await;
             Ok(())
         }
+        RepositoryType::WebDAV => {
+            #[cfg(feature = "webdav")]
+            {
+                use asuran::repository::backend::webdav::*;
+                let webdav_settings = repo_opts.webdav_settings()?;
+                let mut webdav =
+                    WebDav::connect(webdav_settings, key, Some(settings), options.queue_depth())
+                        .context("Failed to connect to WebDAV backend")?;
+                webdav
+                    .ping()
+                    .await
+                    .context("Unable to reach the new repository")?;
+
+                webdav
+                    .write_key(&encrypted_key)
+                    .await
+                    .context("Failed to write key material to repository")?;
+
+                webdav.close().await;
+                Ok(())
+            }
+            #[cfg(not(feature = "webdav"))]
+            {
+                Err(anyhow!("asuran-cli was not compiled with WebDAV support"))
+            }
+        }
+        RepositoryType::HTTP => Err(anyhow!(
+            "HTTP repositories are read-only, and can not be created by asuran-cli"
+        )),
     }
 }
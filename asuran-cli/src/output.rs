@@ -0,0 +1,242 @@
+/*!
+The `output` module provides the structured representations of command output
+used by `--format json`, and the error reports used by `--error-format json`.
+
+These are deliberately kept separate from the types `asuran` stores on disk, so
+that the JSON format can remain stable for scripts even if the on-disk format
+changes.
+*/
+use asuran::backup::BackupError;
+use asuran::manifest::target::{Node, NodeType};
+use asuran::manifest::{ActiveArchive, ArchiveError, StoredArchive};
+use asuran::repository::backend::BackendError;
+use asuran::repository::{
+    ChunkID, ChunkInfo, Encryption, KeyError, RepoStatistics, RepositoryError,
+};
+use asuran::restore::RestoreError;
+
+use anyhow::Result;
+use serde::{Deserialize, Serialize};
+
+/// Output of the `list` command
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct ArchiveList {
+    /// Time of the last modification of the repository, in RFC 3339 format
+    pub last_modified: String,
+    /// Fingerprint of the repository's key, see `Repository::key_fingerprint`
+    pub key_fingerprint: String,
+    /// The archives in the repository, in manifest order
+    pub archives: Vec<ArchiveSummary>,
+}
+
+/// Output of the `stats` command
+#[derive(Serialize, Debug, Clone)]
+pub struct StatsReport {
+    /// Fingerprint of the repository's key, see `Repository::key_fingerprint`
+    pub key_fingerprint: String,
+    /// Statistics about the contents of the repository
+    #[serde(flatten)]
+    pub statistics: RepoStatistics,
+}
+
+/// Description of a single archive in a repository
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct ArchiveSummary {
+    /// Position of the archive in the manifest, usable in place of its name
+    pub index: usize,
+    /// Name of the archive
+    pub name: String,
+    /// Creation time of the archive, in RFC 3339 format
+    pub timestamp: String,
+    /// Hex encoded ID of the chunk the archive is stored in
+    pub id: String,
+    /// Tags attached to the archive
+    pub tags: Vec<String>,
+    /// Comment attached to the archive, if any
+    pub comment: Option<String>,
+    /// True if the archive is sealed, and could not be opened
+    ///
+    /// The name, tags, and comment of a sealed archive can not be read, and are left empty.
+    #[serde(default)]
+    pub sealed: bool,
+}
+
+impl ArchiveSummary {
+    pub fn new(index: usize, id: ChunkID, archive: &ActiveArchive) -> ArchiveSummary {
+        ArchiveSummary {
+            index,
+            name: archive.name().to_string(),
+            timestamp: archive.timestamp().to_rfc3339(),
+            id: id
+                .to_bytes()
+                .iter()
+                .map(|byte| format!("{:02x}", byte))
+                .collect(),
+            tags: archive.tags().to_vec(),
+            comment: archive.comment().map(str::to_string),
+            sealed: false,
+        }
+    }
+
+    /// Describes a sealed archive that could not be opened
+    pub fn sealed(index: usize, stored_archive: &StoredArchive) -> ArchiveSummary {
+        ArchiveSummary {
+            index,
+            name: String::new(),
+            timestamp: stored_archive.timestamp().to_rfc3339(),
+            id: stored_archive.id().to_hex(),
+            tags: Vec::new(),
+            comment: None,
+            sealed: true,
+        }
+    }
+}
+
+/// Output of the `inspect` command
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct ChunkDescription {
+    /// Hex encoded ID of the chunk
+    pub id: String,
+    /// Debug representation of the chunk's compression algorithm
+    pub compression: String,
+    /// Name of the chunk's encryption algorithm
+    pub encryption: String,
+    /// Debug representation of the chunk's HMAC algorithm
+    pub hmac: String,
+    /// Length of the chunk's body, as stored in the repository
+    pub stored_length: usize,
+    /// ID of the segment the chunk lives in
+    pub segment: u64,
+    /// Position of the chunk within its segment
+    ///
+    /// For split segments this is the index of the chunk's entry in the segment's header file,
+    /// for interleaved segments it is the byte offset of the chunk's record in the segment.
+    pub position: u64,
+}
+
+impl From<&ChunkInfo> for ChunkDescription {
+    fn from(info: &ChunkInfo) -> ChunkDescription {
+        ChunkDescription {
+            id: info.id.to_hex(),
+            compression: format!("{:?}", info.compression),
+            // The debug representation would include the IV
+            encryption: match info.encryption {
+                Encryption::NoEncryption => "NoEncryption",
+                Encryption::AES256CTR { .. } => "AES256CTR",
+                Encryption::ChaCha20 { .. } => "ChaCha20",
+                Encryption::XChaCha20Poly1305 { .. } => "XChaCha20Poly1305",
+            }
+            .to_string(),
+            hmac: format!("{:?}", info.hmac),
+            stored_length: info.stored_length,
+            segment: info.location.segment_id,
+            position: info.location.start,
+        }
+    }
+}
+
+/// Description of a single object in an archive's listing, as output by the
+/// `contents` command
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct ContentsEntry {
+    /// Path of the object
+    pub path: String,
+    /// One of `file`, `directory`, or `link`
+    pub node_type: String,
+    /// Length of the object, including any holes
+    pub total_length: u64,
+    /// Size of the object, not including any holes
+    pub total_size: u64,
+    /// Detected MIME type of the object, if it was recorded when the object was stored
+    pub content_type: Option<String>,
+}
+
+impl From<Node> for ContentsEntry {
+    fn from(node: Node) -> ContentsEntry {
+        let node_type = match node.node_type {
+            NodeType::File | NodeType::InlineFile { .. } => "file",
+            NodeType::Directory { .. } => "directory",
+            NodeType::Link => "link",
+        };
+        ContentsEntry {
+            path: node.path,
+            node_type: node_type.to_string(),
+            total_length: node.total_length,
+            total_size: node.total_size,
+            content_type: node.content_type,
+        }
+    }
+}
+
+/// An archive containing files matched by the `find` command
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct FindResult {
+    /// Name of the archive
+    pub archive: String,
+    /// Hex encoded ID of the chunk the archive is stored in
+    pub id: String,
+    /// The matching files in the archive
+    pub files: Vec<ContentsEntry>,
+}
+
+/// A failed command, as reported by `--error-format json`
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct ErrorReport {
+    /// Stable, `snake_case` name of the kind of error, or `other` if it did not originate in
+    /// `asuran`
+    pub kind: String,
+    /// Human readable description of the error, including its context
+    pub message: String,
+    /// Whether the error was caused by a transient backend failure, and running the command
+    /// again may succeed
+    pub retryable: bool,
+}
+
+impl From<&anyhow::Error> for ErrorReport {
+    fn from(error: &anyhow::Error) -> ErrorReport {
+        ErrorReport {
+            kind: error_kind(error).to_string(),
+            message: format!("{:#}", error),
+            retryable: is_retryable(error),
+        }
+    }
+}
+
+/// Returns true if the first backend error in the error's chain of causes is retryable
+pub fn is_retryable(error: &anyhow::Error) -> bool {
+    error
+        .chain()
+        .find_map(|cause| cause.downcast_ref::<BackendError>())
+        .map_or(false, BackendError::is_retryable)
+}
+
+/// Finds the kind of the first `asuran` error in the error's chain of causes
+fn error_kind(error: &anyhow::Error) -> &'static str {
+    for cause in error.chain() {
+        if let Some(e) = cause.downcast_ref::<BackupError>() {
+            return e.kind();
+        }
+        if let Some(e) = cause.downcast_ref::<RestoreError>() {
+            return e.kind();
+        }
+        if let Some(e) = cause.downcast_ref::<ArchiveError>() {
+            return e.kind();
+        }
+        if let Some(e) = cause.downcast_ref::<RepositoryError>() {
+            return e.kind();
+        }
+        if let Some(e) = cause.downcast_ref::<BackendError>() {
+            return e.kind();
+        }
+        if let Some(e) = cause.downcast_ref::<KeyError>() {
+            return e.kind();
+        }
+    }
+    "other"
+}
+
+/// Writes a value to stdout as pretty printed JSON
+pub fn print_json<T: Serialize>(value: &T) -> Result<()> {
+    println!("{}", serde_json::to_string_pretty(value)?);
+    Ok(())
+}
@@ -0,0 +1,22 @@
+use crate::cli::Opt;
+
+use asuran::repository::*;
+
+use anyhow::{Context, Result};
+
+/// Rewrites every chunk in the repository with the user selected chunk settings
+pub async fn recompress(options: Opt) -> Result<()> {
+    // Open the repository
+    let (backend, key) = options.open_repo_backend().await?;
+    let chunk_settings = options.get_chunk_settings()?;
+    let mut repo = Repository::with(backend, chunk_settings, key, options.pipeline_tasks())?;
+    let rewritten = repo
+        .recompress(chunk_settings)
+        .await
+        .context("Failed to recompress repository")?;
+    if !options.quiet {
+        println!("Rewrote {} chunks", rewritten);
+    }
+    repo.close().await;
+    Ok(())
+}
@@ -0,0 +1,53 @@
+use crate::cli::{Opt, OutputFormat};
+use crate::output::{print_json, StatsReport};
+
+use asuran::manifest::*;
+use asuran::repository::*;
+
+use anyhow::Result;
+use prettytable::{cell, row, Table};
+
+/// Collects and prints statistics about the contents of a repository
+pub async fn stats(options: Opt) -> Result<()> {
+    // Open the repository
+    let (backend, key) = options.open_repo_backend().await?;
+    let chunk_settings = options.get_chunk_settings()?;
+    let mut repo = Repository::with(backend, chunk_settings, key, options.pipeline_tasks())?;
+    // load the manifest
+    let mut manifest = Manifest::load(&repo);
+    let stats = manifest.statistics(&mut repo).await?;
+    if options.format == OutputFormat::JSON {
+        print_json(&StatsReport {
+            key_fingerprint: repo.key_fingerprint(),
+            statistics: stats,
+        })?;
+        repo.close().await;
+        return Ok(());
+    }
+    // Print out the totals
+    let mut table = Table::new();
+    table.add_row(row!["Key fingerprint", repo.key_fingerprint()]);
+    table.add_row(row!["Archives", stats.archives]);
+    table.add_row(row!["Unique chunks", stats.unique_chunks]);
+    table.add_row(row!["Referenced bytes", stats.referenced_bytes]);
+    table.add_row(row!["Unique bytes", stats.logical_bytes]);
+    table.add_row(row!["Stored bytes", stats.stored_bytes]);
+    table.add_row(row![
+        "Deduplication ratio",
+        format!("{:.2}", stats.dedup_ratio)
+    ]);
+    table.add_row(row![
+        "Compression ratio",
+        format!("{:.2}", stats.compression_ratio)
+    ]);
+    table.printstd();
+    // Then the breakdown by compression algorithm
+    let mut table = Table::new();
+    table.add_row(row!["Compression", "Chunks"]);
+    for (algorithm, count) in &stats.chunks_per_compression {
+        table.add_row(row![algorithm, count]);
+    }
+    table.printstd();
+    repo.close().await;
+    Ok(())
+}
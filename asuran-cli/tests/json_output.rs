@@ -0,0 +1,270 @@
+//! Checks the structured output of `--format json` and `--error-format json` against a freshly
+//! created repository
+use serde::Deserialize;
+use tempfile::tempdir;
+
+use std::fs::File;
+use std::io::Write;
+use std::path::Path;
+use std::process::{Command, Output};
+
+/// Mirror of the `list` command's JSON output
+#[derive(Deserialize, Debug)]
+struct ArchiveList {
+    last_modified: String,
+    key_fingerprint: String,
+    archives: Vec<ArchiveSummary>,
+}
+
+#[derive(Deserialize, Debug)]
+struct ArchiveSummary {
+    index: usize,
+    name: String,
+    timestamp: String,
+    id: String,
+    tags: Vec<String>,
+    comment: Option<String>,
+    sealed: bool,
+}
+
+/// Mirror of the `inspect` command's JSON output
+#[derive(Deserialize, Debug)]
+struct ChunkDescription {
+    id: String,
+    compression: String,
+    encryption: String,
+    hmac: String,
+}
+
+/// Mirror of the error report written by `--error-format json`
+#[derive(Deserialize, Debug)]
+struct ErrorReport {
+    kind: String,
+    message: String,
+    retryable: bool,
+}
+
+/// Runs the CLI with the given arguments against the repository at `repo`
+fn asuran(repo: &Path, args: &[&str]) -> Output {
+    let output = Command::new(env!("CARGO_BIN_EXE_asuran-cli"))
+        .args(args)
+        .arg(repo)
+        .env("ASURAN_PASSWORD", "password")
+        .output()
+        .expect("Failed to run asuran-cli");
+    assert!(
+        output.status.success(),
+        "asuran-cli {:?} failed: {}",
+        args,
+        String::from_utf8_lossy(&output.stderr)
+    );
+    output
+}
+
+#[test]
+fn list_json() {
+    let root = tempdir().unwrap();
+    let repo = root.path().join("repo");
+    let target = root.path().join("target");
+    std::fs::create_dir(&target).unwrap();
+    File::create(target.join("file"))
+        .unwrap()
+        .write_all(b"Some test data")
+        .unwrap();
+
+    asuran(&repo, &["new"]);
+    let output = Command::new(env!("CARGO_BIN_EXE_asuran-cli"))
+        .args(&["store", "--name", "fixture"])
+        .arg(&repo)
+        .arg(&target)
+        .env("ASURAN_PASSWORD", "password")
+        .output()
+        .expect("Failed to run asuran-cli");
+    assert!(output.status.success());
+
+    let output = asuran(&repo, &["list", "--format", "json"]);
+    let list: ArchiveList =
+        serde_json::from_slice(&output.stdout).expect("list did not output valid JSON");
+    assert!(!list.last_modified.is_empty());
+    assert!(!list.key_fingerprint.is_empty());
+    assert!(list.key_fingerprint.chars().all(|c| c.is_ascii_hexdigit()));
+    assert_eq!(list.archives.len(), 1);
+    let archive = &list.archives[0];
+    assert_eq!(archive.index, 0);
+    assert_eq!(archive.name, "fixture");
+    assert!(!archive.sealed);
+    assert!(!archive.timestamp.is_empty());
+    assert!(archive.id.chars().all(|c| c.is_ascii_hexdigit()));
+}
+
+#[test]
+fn list_filters_by_tag() {
+    let root = tempdir().unwrap();
+    let repo = root.path().join("repo");
+    let target = root.path().join("target");
+    std::fs::create_dir(&target).unwrap();
+    File::create(target.join("file"))
+        .unwrap()
+        .write_all(b"Some test data")
+        .unwrap();
+
+    asuran(&repo, &["new"]);
+    for args in &[
+        &["store", "--name", "untagged"][..],
+        &[
+            "store",
+            "--name",
+            "tagged",
+            "--tag",
+            "foo",
+            "--tag",
+            "bar",
+            "--comment",
+            "hello",
+        ][..],
+    ] {
+        let output = Command::new(env!("CARGO_BIN_EXE_asuran-cli"))
+            .args(*args)
+            .arg(&repo)
+            .arg(&target)
+            .env("ASURAN_PASSWORD", "password")
+            .output()
+            .expect("Failed to run asuran-cli");
+        assert!(output.status.success());
+    }
+
+    let output = asuran(&repo, &["list", "--format", "json"]);
+    let list: ArchiveList =
+        serde_json::from_slice(&output.stdout).expect("list did not output valid JSON");
+    assert_eq!(list.archives.len(), 2);
+
+    let output = asuran(&repo, &["list", "--tag", "foo", "--format", "json"]);
+    let list: ArchiveList =
+        serde_json::from_slice(&output.stdout).expect("list did not output valid JSON");
+    assert_eq!(list.archives.len(), 1);
+    let archive = &list.archives[0];
+    assert_eq!(archive.name, "tagged");
+    assert_eq!(archive.tags, vec!["bar".to_string(), "foo".to_string()]);
+    assert_eq!(archive.comment.as_deref(), Some("hello"));
+}
+
+#[test]
+fn invalid_password_json_error() {
+    let root = tempdir().unwrap();
+    let repo = root.path().join("repo");
+    asuran(&repo, &["new"]);
+
+    let output = Command::new(env!("CARGO_BIN_EXE_asuran-cli"))
+        .args(&["list", "--error-format", "json"])
+        .arg(&repo)
+        .env("ASURAN_PASSWORD", "not the password")
+        .output()
+        .expect("Failed to run asuran-cli");
+    assert!(!output.status.success());
+    let report: ErrorReport =
+        serde_json::from_slice(&output.stderr).expect("error was not reported as valid JSON");
+    assert_eq!(report.kind, "decryption_failed");
+    assert!(!report.message.is_empty());
+    assert!(!report.retryable);
+}
+
+#[test]
+fn preset_sets_chunk_settings() {
+    let root = tempdir().unwrap();
+    let repo = root.path().join("repo");
+    let target = root.path().join("target");
+    std::fs::create_dir(&target).unwrap();
+    File::create(target.join("file"))
+        .unwrap()
+        .write_all(b"Some test data")
+        .unwrap();
+
+    asuran(&repo, &["new", "--preset", "fast"]);
+    let output = Command::new(env!("CARGO_BIN_EXE_asuran-cli"))
+        .args(&["store", "--preset", "fast", "--name", "fixture"])
+        .arg(&repo)
+        .arg(&target)
+        .env("ASURAN_PASSWORD", "password")
+        .output()
+        .expect("Failed to run asuran-cli");
+    assert!(output.status.success());
+
+    let output = asuran(&repo, &["list", "--format", "json"]);
+    let list: ArchiveList =
+        serde_json::from_slice(&output.stdout).expect("list did not output valid JSON");
+    let id = &list.archives[0].id;
+
+    let output = Command::new(env!("CARGO_BIN_EXE_asuran-cli"))
+        .args(&["inspect", "--preset", "fast", "--format", "json"])
+        .arg(&repo)
+        .arg(id)
+        .env("ASURAN_PASSWORD", "password")
+        .output()
+        .expect("Failed to run asuran-cli");
+    assert!(output.status.success());
+    let chunk: ChunkDescription =
+        serde_json::from_slice(&output.stdout).expect("inspect did not output valid JSON");
+    assert_eq!(&chunk.id, id);
+    assert_eq!(chunk.compression, "LZ4 { level: 1 }");
+    assert_eq!(chunk.encryption, "ChaCha20");
+    assert_eq!(chunk.hmac, "Blake3");
+}
+
+#[test]
+fn migrate_preserves_archives() {
+    let root = tempdir().unwrap();
+    let repo = root.path().join("repo");
+    let dest = root.path().join("dest.asuran");
+    let target = root.path().join("target");
+    std::fs::create_dir(&target).unwrap();
+    File::create(target.join("file"))
+        .unwrap()
+        .write_all(b"Some test data")
+        .unwrap();
+
+    asuran(&repo, &["new"]);
+    for name in &["first", "second"] {
+        let output = Command::new(env!("CARGO_BIN_EXE_asuran-cli"))
+            .args(&["store", "--name", name])
+            .arg(&repo)
+            .arg(&target)
+            .env("ASURAN_PASSWORD", "password")
+            .output()
+            .expect("Failed to run asuran-cli");
+        assert!(output.status.success());
+    }
+
+    let output = Command::new(env!("CARGO_BIN_EXE_asuran-cli"))
+        .args(&["migrate", "--destination-type", "flatfile"])
+        .arg(&repo)
+        .arg(&dest)
+        .env("ASURAN_PASSWORD", "password")
+        .output()
+        .expect("Failed to run asuran-cli");
+    assert!(
+        output.status.success(),
+        "migrate failed: {}",
+        String::from_utf8_lossy(&output.stderr)
+    );
+
+    let list = |repo: &Path, args: &[&str]| -> ArchiveList {
+        let output = asuran(repo, args);
+        serde_json::from_slice(&output.stdout).expect("list did not output valid JSON")
+    };
+    let source = list(&repo, &["list", "--format", "json"]);
+    let copy = list(
+        &dest,
+        &["list", "--format", "json", "--repository-type", "flatfile"],
+    );
+    let summarize = |list: &ArchiveList| {
+        let mut archives: Vec<(String, String)> = list
+            .archives
+            .iter()
+            .map(|x| (x.name.clone(), x.timestamp.clone()))
+            .collect();
+        archives.sort();
+        archives
+    };
+    assert_eq!(summarize(&source), summarize(&copy));
+    assert_eq!(copy.archives.len(), 2);
+}
@@ -3,9 +3,10 @@ This crate contains data structures that can be readily shared between both
 synchronous and non-synchronous implementations of asuran.
 
 When a data structure is present in this crate, and it has a
-Serialize/Deserialize derive, the format that `rmp-serde` produces from
-serializing that structure with the compact representation is considered to be
-the iconically format of that objects on-disk representation.
+Serialize/Deserialize derive, the format that `serde_cbor` produces from
+serializing that structure with its default settings is considered to be the
+canonical format of that objects on-disk representation. Golden vectors for
+these formats are checked by `asuran/tests/format_vectors.rs`.
 */
 
 #![warn(clippy::all)]
@@ -5,7 +5,7 @@ use chrono::prelude::*;
 use serde::{Deserialize, Serialize};
 
 use std::cmp::Ordering;
-use std::collections::HashMap;
+use std::collections::BTreeMap;
 
 /// A pointer to a `Chunk`, annotated with information on what part of the object it
 /// makes up
@@ -29,12 +29,25 @@ impl Ord for ChunkLocation {
 }
 
 /// An Archive, as stored in the repository
+///
+/// The `objects` map is stored in its own chunk, pointed to by `objects_chunk`, so that the
+/// rest of the archive, including its listing, can be read without loading it.
+///
+/// The serialized form is deterministic, the same archive always serializes to the same bytes,
+/// and thus to the same chunks, regardless of the order its contents were added in.
 #[derive(Serialize, Deserialize)]
 pub struct Archive {
     /// The user provided name of the archive
     pub name: String,
     /// The list of objects in this archive, as well as the chunks that make them up
-    pub objects: HashMap<String, Vec<ChunkLocation>>,
+    ///
+    /// This is empty in the stored form of archives that have an `objects_chunk`.
+    pub objects: BTreeMap<String, Vec<ChunkLocation>>,
+    /// Pointer to the chunk the `objects` map is stored in
+    ///
+    /// Archives stored before the map was split out carry it inline, and do not have this.
+    #[serde(default)]
+    pub objects_chunk: Option<ChunkID>,
     /// The namespace this archive is currently viewing
     pub namespace: Vec<String>,
     /// The timestamp of the archive's creation
@@ -42,6 +55,12 @@ pub struct Archive {
     /// The listing of objects in the repository, maintaining their relative structure,
     /// such as the layout of directories and folders.
     pub listing: Listing,
+    /// User provided tags for organizing archives, kept in sorted order without duplicates
+    #[serde(default)]
+    pub tags: Vec<String>,
+    /// User provided free-form description of the archive
+    #[serde(default)]
+    pub comment: Option<String>,
 }
 
 #[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq, Eq, Hash)]
@@ -52,3 +71,64 @@ pub struct Extent {
     pub start: u64,
     pub end: u64,
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::manifest::listing::{Node, NodeType};
+    use crate::repository::key::Key;
+    use crate::repository::HMAC;
+
+    fn build_archive(paths: &[&str]) -> Archive {
+        let mut objects = BTreeMap::new();
+        let mut listing = Listing::default();
+        for path in paths {
+            objects.insert(
+                path.to_string(),
+                vec![ChunkLocation {
+                    id: ChunkID::new(path.as_bytes()),
+                    start: 0,
+                    length: path.len() as u64,
+                }],
+            );
+            listing.add_child(
+                "",
+                Node {
+                    path: path.to_string(),
+                    total_length: 0,
+                    total_size: 0,
+                    extents: None,
+                    content_type: None,
+                    node_type: NodeType::File,
+                },
+            );
+        }
+        Archive {
+            name: "test".to_string(),
+            objects,
+            objects_chunk: None,
+            namespace: Vec::new(),
+            timestamp: DateTime::parse_from_rfc3339("2020-01-01T00:00:00+00:00").unwrap(),
+            listing,
+            tags: Vec::new(),
+            comment: None,
+        }
+    }
+
+    // The same archive, built up in a different order, must serialize to the same bytes
+    #[test]
+    fn serialization_is_deterministic() {
+        let key = Key::random(32);
+        let paths = ["a", "b", "c", "d", "e", "f", "g", "h"];
+        let mut reversed = paths;
+        reversed.reverse();
+
+        let first = serde_cbor::ser::to_vec(&build_archive(&paths)).unwrap();
+        let second = serde_cbor::ser::to_vec(&build_archive(&reversed)).unwrap();
+        assert_eq!(first, second);
+        assert_eq!(
+            HMAC::Blake2b.id(&first, &key),
+            HMAC::Blake2b.id(&second, &key)
+        );
+    }
+}
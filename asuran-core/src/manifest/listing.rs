@@ -4,9 +4,10 @@
 //! it is not contained to only files or directories
 use crate::manifest::archive::Extent;
 
-use serde::{Deserialize, Serialize};
+use serde::{Deserialize, Serialize, Serializer};
 
-use std::collections::HashMap;
+use std::collections::{BTreeMap, HashMap};
+use std::path::{Path, PathBuf};
 
 /// The type of node in the listing
 ///
@@ -16,12 +17,31 @@ use std::collections::HashMap;
 pub enum NodeType {
     /// A node that has assocaited data and potentially associated metadata
     File,
+    /// A file small enough that its data is stored directly in the listing, rather than as an
+    /// object in the archive
+    InlineFile {
+        #[serde(with = "serde_bytes")]
+        data: Vec<u8>,
+    },
     /// A node that has associated metadata, and no child nodes
     Link,
     /// A node that only has associated metadata, and potentially child nodes
     ///
     /// Contains the paths of any child members a node may have
-    Directory { children: Vec<String> },
+    ///
+    /// These are serialized in sorted order.
+    Directory {
+        #[serde(serialize_with = "serialize_sorted")]
+        children: Vec<String>,
+    },
+}
+
+/// Serializes a list of paths in sorted order, so that the serialized form of a `Listing` does
+/// not depend on the order its nodes were added in
+fn serialize_sorted<S: Serializer>(paths: &[String], serializer: S) -> Result<S::Ok, S::Error> {
+    let mut sorted: Vec<&String> = paths.iter().collect();
+    sorted.sort_unstable();
+    serializer.collect_seq(sorted)
 }
 
 /// A node is a description of an object in the listing
@@ -41,6 +61,12 @@ pub struct Node {
     pub extents: Option<Vec<Extent>>,
     /// the type of the node
     pub node_type: NodeType,
+    /// The MIME type of the object's contents, as detected from the start of its data
+    ///
+    /// This will be None if detection was not requested when the object was stored, or if the
+    /// type could not be determined.
+    #[serde(default)]
+    pub content_type: Option<String>,
 }
 
 impl Node {
@@ -52,14 +78,22 @@ impl Node {
         }
     }
 
-    /// Returns true if the Node is a file
+    /// Returns true if the Node is a file, including files stored inline
     pub fn is_file(&self) -> bool {
         match self.node_type {
-            NodeType::File => true,
+            NodeType::File | NodeType::InlineFile { .. } => true,
             _ => false,
         }
     }
 
+    /// Returns the data of the file, if it is stored inline in the listing
+    pub fn inline_data(&self) -> Option<&[u8]> {
+        match &self.node_type {
+            NodeType::InlineFile { data } => Some(data),
+            _ => None,
+        }
+    }
+
     /// Returns a copy of self with any children (in a `NodeType::Directory`) removed
     pub fn drain_children(&self) -> Node {
         let node_type = match &self.node_type {
@@ -82,8 +116,9 @@ pub struct Listing {
     /// Contains a mapping of paths to nodes.
     ///
     /// Two nodes are considered the same if they share the same path
-    nodes: HashMap<String, Node>,
+    nodes: BTreeMap<String, Node>,
     /// Contains the paths of the nodes in the 'root directory' of this listing
+    #[serde(serialize_with = "serialize_sorted")]
     root: Vec<String>,
 }
 
@@ -114,6 +149,33 @@ impl Listing {
         }
     }
 
+    /// Looks up the node with the given path
+    pub fn get(&self, path: impl AsRef<Path>) -> Option<&Node> {
+        self.nodes.get(&*path.as_ref().to_string_lossy())
+    }
+
+    /// Looks up the node with the given path, allowing it to be modified
+    ///
+    /// Changing the path or type of the node will leave the listing in an inconsistent state.
+    pub fn get_mut(&mut self, path: impl AsRef<Path>) -> Option<&mut Node> {
+        self.nodes.get_mut(&*path.as_ref().to_string_lossy())
+    }
+
+    /// Walks the tree of nodes in this listing, yielding each node along with its path
+    ///
+    /// The walk is depth first, with each directory being yielded before its children, and
+    /// siblings being visited in order of their paths, so the order does not depend on the
+    /// order the nodes were added in.
+    pub fn walk(&self) -> ListingWalk<'_> {
+        let mut stack: Vec<&str> = self.root.iter().map(String::as_str).collect();
+        // Reverse sorted, so the smallest path is on top of the stack
+        stack.sort_unstable_by(|a, b| b.cmp(a));
+        ListingWalk {
+            listing: self,
+            stack,
+        }
+    }
+
     /// Creates a by-reference iterator over the Nodes in this listing
     // This is excluded from tarpaulin, since its just a pass through to into_iter
     #[cfg_attr(tarpaulin, skip)]
@@ -131,7 +193,7 @@ pub struct ListingIterator {
     /// The children of nodes already consumed
     children_buffer: Vec<Node>,
     /// Map containing the remaining nodes
-    node_map: HashMap<String, Node>,
+    node_map: BTreeMap<String, Node>,
 }
 
 impl Iterator for ListingIterator {
@@ -175,6 +237,31 @@ impl IntoIterator for Listing {
     }
 }
 
+/// Depth first walk over a borrowed `Listing`, see `Listing::walk`
+pub struct ListingWalk<'a> {
+    listing: &'a Listing,
+    /// Paths of the nodes still to be visited, the next one being on top
+    stack: Vec<&'a str>,
+}
+
+impl<'a> Iterator for ListingWalk<'a> {
+    type Item = (PathBuf, &'a Node);
+    fn next(&mut self) -> Option<Self::Item> {
+        loop {
+            let path = self.stack.pop()?;
+            // Skip over any children that were never actually added
+            if let Some(node) = self.listing.nodes.get(path) {
+                if let NodeType::Directory { children } = &node.node_type {
+                    let start = self.stack.len();
+                    self.stack.extend(children.iter().map(String::as_str));
+                    self.stack[start..].sort_unstable_by(|a, b| b.cmp(a));
+                }
+                return Some((PathBuf::from(path), node));
+            }
+        }
+    }
+}
+
 /// Iterated over a borrowed `Listing`
 ///
 /// Does so in breadth-first order
@@ -244,6 +331,7 @@ mod tests {
             total_length: 1234,
             total_size: 1234,
             extents: None,
+            content_type: None,
             node_type: NodeType::Directory {
                 children: ["test1", "test2", "test3"]
                     .iter()
@@ -264,6 +352,7 @@ mod tests {
             total_length: 1234,
             total_size: 1234,
             extents: None,
+            content_type: None,
             node_type: NodeType::Directory {
                 children: ["test1", "test2", "test3"]
                     .iter()
@@ -295,6 +384,7 @@ mod tests {
                 total_length: 1234,
                 total_size: 1234,
                 extents: None,
+                content_type: None,
                 node_type: NodeType::File,
             })
             .collect();
@@ -314,6 +404,61 @@ mod tests {
         assert_eq!(test_nodes, post_nodes);
     }
 
+    fn test_node(path: &str, directory: bool) -> Node {
+        Node {
+            path: path.to_owned(),
+            total_length: 0,
+            total_size: 0,
+            extents: None,
+            content_type: None,
+            node_type: if directory {
+                NodeType::Directory {
+                    children: Vec::new(),
+                }
+            } else {
+                NodeType::File
+            },
+        }
+    }
+
+    // Walking should visit every node depth first, in sorted order, regardless of insertion order
+    #[test]
+    fn listing_walk_and_get() {
+        let mut listing = Listing::default();
+        listing.add_child("", test_node("zeta", false));
+        listing.add_child("", test_node("alpha", true));
+        listing.add_child("alpha", test_node("alpha/zed", false));
+        listing.add_child("alpha", test_node("alpha/beta", true));
+        listing.add_child("alpha/beta", test_node("alpha/beta/gamma", false));
+        listing.add_child("alpha", test_node("alpha/alef", false));
+        listing.add_child("", test_node("mu", false));
+
+        let walked: Vec<PathBuf> = listing.walk().map(|(path, _)| path).collect();
+        let expected: Vec<PathBuf> = [
+            "alpha",
+            "alpha/alef",
+            "alpha/beta",
+            "alpha/beta/gamma",
+            "alpha/zed",
+            "mu",
+            "zeta",
+        ]
+        .iter()
+        .map(PathBuf::from)
+        .collect();
+        assert_eq!(walked, expected);
+        for (path, node) in listing.walk() {
+            assert_eq!(path, PathBuf::from(&node.path));
+        }
+
+        assert!(listing.get("alpha/beta").unwrap().is_directory());
+        assert_eq!(
+            listing.get(Path::new("alpha/beta/gamma")).unwrap().path,
+            "alpha/beta/gamma"
+        );
+        assert_eq!(listing.get("alpha/missing"), None);
+    }
+
     // Test the by value iterator
     #[test]
     fn listing_to_iter_value() {
@@ -325,6 +470,7 @@ mod tests {
                 total_length: 1234,
                 total_size: 1234,
                 extents: None,
+                content_type: None,
                 node_type: NodeType::File,
             })
             .collect();
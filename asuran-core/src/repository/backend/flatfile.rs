@@ -19,6 +19,12 @@ use std::io::{Read, Write};
 
 pub const MAGIC_NUMBER: [u8; 8] = *b"ASURAN_F";
 
+/// The newest version of the `FlatFile` format this implementation understands, and the version
+/// new `FlatFile`s are created with
+///
+/// Version 1 files predate the format version field, and are identified by its absence.
+pub const FORMAT_VERSION: u16 = 2;
+
 /// An error for things that go wrong with interacting with flatfile transactions and headers
 #[derive(Error, Debug)]
 pub enum FlatFileError {
@@ -34,24 +40,33 @@ pub enum FlatFileError {
     SemverToHigh(u64, Version),
     #[error("Chunk decryption failed: {0}")]
     ChunkError(#[from] crate::repository::chunk::ChunkError),
+    #[error("Invalid offset: {0}")]
+    InvalidOffset(String),
 }
 
 type Result<T> = std::result::Result<T, FlatFileError>;
 
 /// A struct representation of the Asuran `FlatFile` global header.
 ///
-/// The initial/global header contains three components:
+/// The initial/global header contains four components:
 ///
 /// 1. Magic Number
 ///
 ///     The magic number identifying asuran `FlatFile`s is the 8-byte string
 ///     `b"ASURAN_F"`.
 ///
-/// 2. Length of header
+/// 2. Format version
+///
+///     A `u16` of zero, followed by the format version as a `u16`.
+///
+///     Version 1 files do not have this component. As an encrypted key is never
+///     zero bytes long, the leading zero distinguishes the two.
+///
+/// 3. Length of header
 ///
 ///     The total length of the encrypted key, in bytes, as a u16.
 ///
-/// 3. The `EncryptedKey`
+/// 4. The `EncryptedKey`
 ///
 ///     The serialized, encrypted key material for this repository.
 ///
@@ -60,6 +75,7 @@ type Result<T> = std::result::Result<T, FlatFileError>;
 #[derive(Clone, Debug, PartialEq, Eq)]
 pub struct FlatFileHeader {
     pub magic_number: [u8; 8],
+    pub format_version: u16,
     pub length: u16,
     pub enc_key: Vec<u8>,
 }
@@ -83,6 +99,7 @@ impl FlatFileHeader {
 
         Ok(FlatFileHeader {
             magic_number: MAGIC_NUMBER,
+            format_version: FORMAT_VERSION,
             length,
             enc_key,
         })
@@ -106,6 +123,9 @@ impl FlatFileHeader {
     ///
     /// The passed in Read must be seeked to the start of the file.
     ///
+    /// Headers of any format version are read, it is up to the caller to check that
+    /// the version is one it understands.
+    ///
     /// # Errors
     ///
     /// Will return `Err(InvalidMagicNumber)` if the magic number of the header is not
@@ -115,11 +135,17 @@ impl FlatFileHeader {
     pub fn from_read(mut read: impl Read) -> Result<FlatFileHeader> {
         let mut magic_number = [0_u8; 8];
         read.read_exact(&mut magic_number)?;
-        let length: u16 = read.read_u16::<NetworkEndian>()?;
+        let mut length: u16 = read.read_u16::<NetworkEndian>()?;
+        let mut format_version = 1;
+        if length == 0 {
+            format_version = read.read_u16::<NetworkEndian>()?;
+            length = read.read_u16::<NetworkEndian>()?;
+        }
         let mut enc_key = vec![0_u8; length as usize];
         read.read_exact(&mut enc_key[..])?;
         let header = FlatFileHeader {
             magic_number,
+            format_version,
             length,
             enc_key,
         };
@@ -131,6 +157,9 @@ impl FlatFileHeader {
 
     /// Writes the Asuran `FlatFile` Header to the given `Write`
     ///
+    /// The header is written in the layout of its `format_version`, so version 1
+    /// headers are written without the format version component.
+    ///
     /// The provided `Write` must be seeked to the start of the file.
     ///
     /// # Errors
@@ -138,6 +167,10 @@ impl FlatFileHeader {
     /// Will return `Err` if there is an underlying I/O error.
     pub fn to_write(&self, mut write: impl Write) -> Result<()> {
         write.write_all(&MAGIC_NUMBER)?;
+        if self.format_version > 1 {
+            write.write_u16::<NetworkEndian>(0)?;
+            write.write_u16::<NetworkEndian>(self.format_version)?;
+        }
         write.write_u16::<NetworkEndian>(self.length)?;
         write.write_all(&self.enc_key[..])?;
         Ok(())
@@ -147,10 +180,19 @@ impl FlatFileHeader {
     pub fn total_length(&self) -> u64 {
         // This is the length of the encrypted key, plus 2 bytes for the length u16, and 8 bytes for
         // the magic number.
-        u64::from(self.length) + 10
+        let length = u64::from(self.length) + 10;
+        // Versioned headers have an additional 4 bytes for the marker and version u16s
+        if self.format_version > 1 {
+            length + 4
+        } else {
+            length
+        }
     }
 }
 
+/// Length of a serialized `EntryHeader`, in bytes
+pub const ENTRY_HEADER_LENGTH: u64 = 38;
+
 /// A struct representation of the header portion of an entry.
 ///
 /// An entry header is a sequence of 3 `u16`s, followed by two `u64`s, and then a
@@ -280,6 +322,11 @@ pub struct EntryFooterData {
     pub archives: Vec<(ChunkID, DateTime<FixedOffset>)>,
     /// The current default `ChunkSettings` of this repository
     pub chunk_settings: ChunkSettings,
+    /// The `ChunkID`s of the `Chunk`s removed by this entry.
+    ///
+    /// The bodies of these `Chunk`s remain in the file until it is compacted.
+    #[serde(default)]
+    pub removed_chunks: Vec<ChunkID>,
 }
 
 impl EntryFooterData {
@@ -290,6 +337,7 @@ impl EntryFooterData {
             archives: Vec::new(),
             chunk_settings,
             chunk_headers: HashMap::new(),
+            removed_chunks: Vec::new(),
         }
     }
     /// Adds a chunk to the `chunk_locations` list
@@ -300,6 +348,10 @@ impl EntryFooterData {
     pub fn add_header(&mut self, id: ChunkID, header: ChunkHeader) {
         self.chunk_headers.insert(id, header);
     }
+    /// Adds a chunk to the `removed_chunks` list
+    pub fn remove_chunk(&mut self, id: ChunkID) {
+        self.removed_chunks.push(id);
+    }
     /// Adds an archive to the `archives` list
     pub fn add_archive(&mut self, id: ChunkID, timestamp: DateTime<FixedOffset>) {
         self.archives.push((id, timestamp))
@@ -309,6 +361,7 @@ impl EntryFooterData {
         !self.chunk_locations.is_empty()
             || !self.chunk_headers.is_empty()
             || !self.archives.is_empty()
+            || !self.removed_chunks.is_empty()
     }
 }
 
@@ -351,8 +404,8 @@ impl EntryFooter {
     /// - If decrypting/decompressing the `Chunk` fails
     /// - If decoding the `EntryFooterData` from the unpacked bytes fails
     pub fn into_data(self, key: &Key) -> Result<EntryFooterData> {
-        let chunk: Chunk = cbor::de::from_slice(&self.chunk_bytes[..])?;
-        let bytes = chunk.unpack(key)?;
+        // Footers are read from the file, so may be corrupt, and must not be able to cause a panic
+        let bytes = Chunk::try_unpack(&self.chunk_bytes[..], key)?;
         let data: EntryFooterData = cbor::de::from_slice(&bytes[..])?;
         Ok(data)
     }
@@ -362,11 +415,34 @@ impl EntryFooter {
     /// # Errors
     ///
     /// Will return `Err` if there is an underlying I/O error.
-    pub fn from_read(mut read: impl Read) -> Result<EntryFooter> {
+    pub fn from_read(read: impl Read) -> Result<EntryFooter> {
+        EntryFooter::from_read_within(read, u64::MAX)
+    }
+
+    /// Decodes an `EntryFooter` from the provided `Read`, refusing to read a `Chunk` longer than
+    /// `max_length` bytes
+    ///
+    /// The length of the `Chunk` is read from the file, so this should be used with the space
+    /// actually available, to avoid allocating a huge buffer for a corrupt length.
+    ///
+    /// # Errors
+    ///
+    /// Will return `Err` if there is an underlying I/O error, or the `Chunk` is longer than
+    /// `max_length`.
+    pub fn from_read_within(mut read: impl Read, max_length: u64) -> Result<EntryFooter> {
         let length = read.read_u64::<NetworkEndian>()?;
-        let buffer_len: usize = length
-            .try_into()
-            .expect("EntryFooter chunk too large to possibly fit in memory.");
+        if length > max_length {
+            return Err(FlatFileError::InvalidOffset(format!(
+                "EntryFooter chunk of {} bytes does not fit in the {} bytes available",
+                length, max_length
+            )));
+        }
+        let buffer_len: usize = length.try_into().map_err(|_| {
+            FlatFileError::InvalidOffset(format!(
+                "EntryFooter chunk of {} bytes is too large to fit in memory",
+                length
+            ))
+        })?;
         let mut chunk_bytes = vec![0_u8; buffer_len];
         read.read_exact(&mut chunk_bytes[..])?;
         Ok(EntryFooter { chunk_bytes })
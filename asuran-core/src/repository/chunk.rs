@@ -7,12 +7,14 @@ contents (different keys are used for both HMACs).
 
 They can contain any arbitrary sequence of bytes.
 */
-use super::{Compression, Encryption, Key, HMAC};
+use super::{Compression, Encryption, Key, HMAC, LZ4_MAX_LEVEL};
 
 use serde::{Deserialize, Serialize};
 use thiserror::Error;
 
 use std::cmp;
+use std::fmt;
+use std::str::FromStr;
 
 /// Error for all the various things that can go wrong with handling chunks
 #[derive(Error, Debug)]
@@ -25,10 +27,39 @@ pub enum ChunkError {
     KeyError(#[from] super::KeyError),
     #[error("HMAC Vailidation Failed")]
     HMACValidationFailed,
+    #[error("Chunk could not be decoded")]
+    DecodeError(#[from] serde_cbor::Error),
+    #[error("Chunk uses unsupported settings: {0}")]
+    Unsupported(#[from] ConfigError),
 }
 
 type Result<T> = std::result::Result<T, ChunkError>;
 
+/// Error describing why a string could not be parsed as a `ChunkID`
+#[derive(Error, Debug, PartialEq, Eq)]
+pub enum ParseChunkIDError {
+    #[error("A ChunkID must be 64 or 128 hex digits long, not {0}")]
+    InvalidLength(usize),
+    #[error("A ChunkID may only contain hex digits")]
+    InvalidDigit,
+}
+
+/// Error describing why a set of `ChunkSettings` was rejected by `ChunkSettings::validate`
+#[derive(Error, Debug, PartialEq, Eq)]
+pub enum ConfigError {
+    #[error("{0} was selected, but asuran was not compiled with support for it. Rebuild with the {1} feature enabled, or select a different algorithm")]
+    NotCompiled(&'static str, &'static str),
+    #[error("Compression level {level} is not valid for {algorithm}, select a level from {min} to {max}")]
+    InvalidCompressionLevel {
+        algorithm: &'static str,
+        level: i64,
+        min: i64,
+        max: i64,
+    },
+    #[error("Custom HMAC algorithm {0} was selected, but no implementation was registered for it. Register one with `register_hmac` first")]
+    UnregisteredHmac(u8),
+}
+
 /// Key used for indexing a `Chunk` in a repository
 ///
 /// These are usually derived via an HMAC of the chunks plain text, and are used for
@@ -42,6 +73,13 @@ pub struct ChunkID {
     /// truncated and shorter ones (not reccomended) will be padded with zeros at the
     /// end.
     id: [u8; 32],
+    /// The second 32 bytes of an extended, 64 byte, key
+    ///
+    /// This is only present for keys produced by an HMAC algorithm with extended output, such
+    /// as `HMAC::Blake3XOF`, and is omitted entirely when serializing standard keys, so their
+    /// format is unchanged.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    extension: Option<[u8; 32]>,
 }
 
 impl ChunkID {
@@ -53,22 +91,96 @@ impl ChunkID {
         let mut id: [u8; 32] = [0; 32];
         id[..cmp::min(32, input_id.len())]
             .clone_from_slice(&input_id[..cmp::min(32, input_id.len())]);
-        ChunkID { id }
+        ChunkID {
+            id,
+            extension: None,
+        }
+    }
+
+    /// Will create a new extended key from a slice.
+    ///
+    /// Keys longer than 64 bytes will be truncated. Keys of 32 bytes or less produce the same
+    /// standard key as `ChunkID::new`, and keys between 32 and 64 bytes will be padded at the
+    /// end with zeros.
+    pub fn new_extended(input_id: &[u8]) -> ChunkID {
+        let mut chunk_id = ChunkID::new(input_id);
+        if input_id.len() > 32 {
+            let mut extension: [u8; 32] = [0; 32];
+            let rest = &input_id[32..cmp::min(64, input_id.len())];
+            extension[..rest.len()].clone_from_slice(rest);
+            chunk_id.extension = Some(extension);
+        }
+        chunk_id
     }
 
     /// Provides a reference to a key's raw bytes
+    ///
+    /// For extended keys, this is only the first 32 bytes, use `to_bytes` to get the entire key.
     #[cfg_attr(tarpaulin, skip)]
     pub fn get_id(&self) -> &[u8] {
         &self.id
     }
 
-    /// Verifies equaliy of this key with the first 32 bytes of a slice
+    /// Returns all the bytes of this key, including the extension, if there is one
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut bytes = self.id.to_vec();
+        if let Some(extension) = &self.extension {
+            bytes.extend_from_slice(extension);
+        }
+        bytes
+    }
+
+    /// Returns all the bytes of this key, including the extension, as lowercase hex
+    pub fn to_hex(&self) -> String {
+        self.to_bytes()
+            .iter()
+            .map(|byte| format!("{:02x}", byte))
+            .collect()
+    }
+
+    /// Parses a hex string, which may be an abbreviated prefix of a full key, in the style of
+    /// git's abbreviated hashes
+    ///
+    /// The parsed bytes are padded at the end with zeros, in the same way as `ChunkID::new` and
+    /// `ChunkID::new_extended`, and an odd trailing digit is treated as the high half of a byte.
+    /// Returns `None` if the string contains non hex characters, or is longer than an extended
+    /// key.
+    pub fn from_hex_prefix(prefix: &str) -> Option<ChunkID> {
+        if prefix.len() > 128 {
+            return None;
+        }
+        let mut bytes = Vec::with_capacity((prefix.len() + 1) / 2);
+        for pair in prefix.as_bytes().chunks(2) {
+            let high = (pair[0] as char).to_digit(16)?;
+            let low = match pair.get(1) {
+                Some(low) => (*low as char).to_digit(16)?,
+                None => 0,
+            };
+            // Both digits are less than 16, so this can not truncate
+            #[allow(clippy::cast_possible_truncation)]
+            bytes.push((high * 16 + low) as u8);
+        }
+        Some(ChunkID::new_extended(&bytes))
+    }
+
+    /// Returns the length of this key in bytes, either 32 or 64
+    pub fn id_length(&self) -> usize {
+        if self.extension.is_some() {
+            64
+        } else {
+            32
+        }
+    }
+
+    /// Verifies equaliy of this key with the first 32 bytes of a slice, or the first 64 bytes for
+    /// an extended key
     pub fn verify(&self, slice: &[u8]) -> bool {
-        if slice.len() < self.id.len() {
+        let bytes = self.to_bytes();
+        if slice.len() < bytes.len() {
             false
         } else {
             let mut equal = true;
-            for (i, val) in self.id.iter().enumerate() {
+            for (i, val) in bytes.iter().enumerate() {
                 if *val != slice[i] {
                     equal = false;
                 }
@@ -79,13 +191,40 @@ impl ChunkID {
 
     /// Returns the special all-zero key used for the manifest
     pub fn manifest_id() -> ChunkID {
-        ChunkID { id: [0_u8; 32] }
+        ChunkID {
+            id: [0_u8; 32],
+            extension: None,
+        }
     }
 
     /// Returns a random id, used for testing
     pub fn random_id() -> ChunkID {
         let id = rand::random();
-        ChunkID { id }
+        ChunkID {
+            id,
+            extension: None,
+        }
+    }
+}
+
+/// Displays the key as lowercase hex, see `ChunkID::to_hex`
+impl fmt::Display for ChunkID {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.to_hex())
+    }
+}
+
+/// Parses a complete key from hex, the inverse of its `Display` implementation
+///
+/// Unlike `ChunkID::from_hex_prefix`, this does not accept abbreviated keys, and the string must
+/// be exactly 64 hex digits for a standard key, or 128 for an extended one.
+impl FromStr for ChunkID {
+    type Err = ParseChunkIDError;
+    fn from_str(s: &str) -> std::result::Result<ChunkID, ParseChunkIDError> {
+        if s.len() != 64 && s.len() != 128 {
+            return Err(ParseChunkIDError::InvalidLength(s.len()));
+        }
+        ChunkID::from_hex_prefix(s).ok_or(ParseChunkIDError::InvalidDigit)
     }
 }
 
@@ -110,6 +249,169 @@ impl ChunkSettings {
             hmac: HMAC::Blake2b,
         }
     }
+
+    /// Returns a `ChunkSettings` with `Encryption::NoEncryption`,
+    /// `Compression::NoCompression`, and `HMAC::Blake3`.
+    ///
+    /// These settings are intended for threat models where confidentiality is not needed, but
+    /// tamper evidence is. Chunk bodies are stored as plaintext, but are still authenticated by
+    /// their HMAC tag, see `Chunk::verify`.
+    pub fn authenticated_plaintext() -> ChunkSettings {
+        ChunkSettings {
+            compression: Compression::NoCompression,
+            encryption: Encryption::NoEncryption,
+            hmac: HMAC::Blake3,
+        }
+    }
+
+    /// Returns a `ChunkSettings` with `Compression::LZ4 { level: 1 }`,
+    /// `Encryption::ChaCha20` with a fresh IV, and `HMAC::Blake3`.
+    ///
+    /// These settings favor throughput, while still providing confidentiality and integrity.
+    pub fn fast() -> ChunkSettings {
+        ChunkSettings {
+            compression: Compression::LZ4 { level: 1 },
+            encryption: Encryption::new_chacha20(),
+            hmac: HMAC::Blake3,
+        }
+    }
+
+    /// Returns a `ChunkSettings` with `Compression::ZStd { level: 19 }`,
+    /// `Encryption::XChaCha20Poly1305` with a fresh nonce, and `HMAC::Blake3`.
+    ///
+    /// These settings favor the strength of the primitives, and the space saved, over
+    /// throughput. Chunk bodies are encrypted with an AEAD, and the ciphertext is then
+    /// authenticated by its HMAC tag as well.
+    pub fn secure() -> ChunkSettings {
+        ChunkSettings {
+            compression: Compression::ZStd { level: 19 },
+            encryption: Encryption::new_xchacha20poly1305(),
+            hmac: HMAC::Blake3,
+        }
+    }
+
+    /// Returns a `ChunkSettings` with `Compression::LZMA { level: 9 }`,
+    /// `Encryption::XChaCha20Poly1305` with a fresh nonce, and `HMAC::SHA256`.
+    ///
+    /// These settings trade speed for the smallest output, for data that is written once and
+    /// rarely read back.
+    pub fn archival() -> ChunkSettings {
+        ChunkSettings {
+            compression: Compression::LZMA { level: 9 },
+            encryption: Encryption::new_xchacha20poly1305(),
+            hmac: HMAC::SHA256,
+        }
+    }
+
+    /// Checks these settings for combinations that would fail, or silently misbehave, once
+    /// chunks are actually packed with them
+    ///
+    /// Every chunk is always authenticated with the selected HMAC, regardless of the encryption
+    /// in use, so there are no unauthenticated combinations to reject. This instead rejects
+    /// algorithms that support was not compiled in for, which would otherwise cause a panic on
+    /// first use, and compression levels outside of the range the algorithm accepts.
+    ///
+    /// # Errors
+    ///
+    /// Will return `Err` describing the first problem found with these settings.
+    pub fn validate(&self) -> std::result::Result<(), ConfigError> {
+        let (algorithm, feature, compiled, level, min, max) = match self.compression {
+            Compression::NoCompression => ("No compression", "", true, 0, 0, 0),
+            Compression::ZStd { level } => (
+                "ZStd",
+                "zstd",
+                cfg!(feature = "zstd"),
+                i64::from(level),
+                -7,
+                22,
+            ),
+            Compression::LZ4 { level } => (
+                "LZ4",
+                "lz4",
+                cfg!(feature = "lz4"),
+                i64::from(level),
+                0,
+                i64::from(LZ4_MAX_LEVEL),
+            ),
+            Compression::LZ4Frame { level } => (
+                "LZ4Frame",
+                "lz4",
+                cfg!(feature = "lz4"),
+                i64::from(level),
+                0,
+                i64::from(LZ4_MAX_LEVEL),
+            ),
+            Compression::LZMA { level } => (
+                "LZMA",
+                "lzma",
+                cfg!(any(feature = "xz2", feature = "lzma-rs")),
+                i64::from(level),
+                0,
+                9,
+            ),
+            Compression::Auto { level } => (
+                "Auto",
+                "zstd",
+                cfg!(feature = "zstd"),
+                i64::from(level),
+                -7,
+                22,
+            ),
+        };
+        if !compiled {
+            return Err(ConfigError::NotCompiled(algorithm, feature));
+        }
+        if level < min || level > max {
+            return Err(ConfigError::InvalidCompressionLevel {
+                algorithm,
+                level,
+                min,
+                max,
+            });
+        }
+
+        match self.encryption {
+            Encryption::NoEncryption => (),
+            Encryption::AES256CTR { .. } => {
+                if !cfg!(feature = "aes-family") {
+                    return Err(ConfigError::NotCompiled("AES256CTR", "aes-family"));
+                }
+            }
+            Encryption::ChaCha20 { .. } => {
+                if !cfg!(feature = "chacha20") {
+                    return Err(ConfigError::NotCompiled("ChaCha20", "chacha-family"));
+                }
+            }
+            Encryption::XChaCha20Poly1305 { .. } => {
+                if !cfg!(feature = "chacha20poly1305") {
+                    return Err(ConfigError::NotCompiled(
+                        "XChaCha20Poly1305",
+                        "chacha-family",
+                    ));
+                }
+            }
+        }
+
+        let (algorithm, feature, compiled) = match self.hmac {
+            HMAC::SHA256 => ("SHA256", "sha2", cfg!(feature = "sha2")),
+            HMAC::Blake2b => ("Blake2b", "blake2b", cfg!(feature = "blake2b_simd")),
+            HMAC::Blake2bp => ("Blake2bp", "blake2b", cfg!(feature = "blake2b_simd")),
+            HMAC::Blake3 => ("Blake3", "blake3", cfg!(feature = "blake3")),
+            HMAC::SHA3 => ("SHA3", "sha3", cfg!(feature = "sha3")),
+            HMAC::Blake3XOF => ("Blake3XOF", "blake3", cfg!(feature = "blake3")),
+            HMAC::Custom(id) => {
+                if super::hmac::lookup_hmac(id).is_none() {
+                    return Err(ConfigError::UnregisteredHmac(id));
+                }
+                ("Custom", "", true)
+            }
+        };
+        if !compiled {
+            return Err(ConfigError::NotCompiled(algorithm, feature));
+        }
+
+        Ok(())
+    }
 }
 
 /// A split representation of a `Chunk`'s 'header' or metadata.
@@ -123,6 +425,28 @@ pub struct ChunkHeader {
     id: ChunkID,
 }
 
+impl ChunkHeader {
+    /// Returns the compression algorithm used by the chunk
+    pub fn compression(&self) -> Compression {
+        self.compression
+    }
+
+    /// Returns a copy of the encryption method/iv used for the chunk
+    pub fn encryption(&self) -> Encryption {
+        self.encryption
+    }
+
+    /// Returns the HMAC algorithm used by the chunk
+    pub fn hmac(&self) -> HMAC {
+        self.hmac
+    }
+
+    /// Returns the `ChunkID` of the chunk
+    pub fn id(&self) -> ChunkID {
+        self.id
+    }
+}
+
 /// A split representation of a `Chunk`'s body, or contained data
 #[derive(Serialize, Deserialize, Clone, Debug)]
 pub struct ChunkBody(pub Vec<u8>);
@@ -174,7 +498,11 @@ impl Chunk {
         key: &Key,
     ) -> Chunk {
         let id_mac = hmac.id(&data, key);
-        let id = ChunkID::new(&id_mac);
+        let id = if hmac.extended_ids() {
+            ChunkID::new_extended(&id_mac)
+        } else {
+            ChunkID::new(&id_mac)
+        };
         Chunk::pack_with_id(data, compression, encryption, hmac, key, id)
     }
 
@@ -213,6 +541,8 @@ impl Chunk {
         key: &Key,
         id: ChunkID,
     ) -> Chunk {
+        // Record the algorithm actually used, so `Compression::Auto` never ends up in a header
+        let compression = compression.select(&data);
         let compressed_data = compression.compress(data);
         let data = encryption.encrypt(&compressed_data, key);
         let mac = hmac.mac(&data, key);
@@ -226,6 +556,31 @@ impl Chunk {
         }
     }
 
+    /// Produces a new `Chunk` with the same contents, `ChunkID`, and HMAC algorithm as this one,
+    /// but packed with the provided compression and encryption
+    ///
+    /// As the HMAC algorithm is unchanged, the id remains valid for the contents.
+    ///
+    /// # Errors
+    ///
+    /// Will return `Err` if this chunk can not be unpacked, see `unpack`
+    pub fn repack(
+        &self,
+        compression: Compression,
+        encryption: Encryption,
+        key: &Key,
+    ) -> Result<Chunk> {
+        let data = self.unpack(key)?;
+        Ok(Chunk::pack_with_id(
+            data,
+            compression,
+            encryption,
+            self.hmac,
+            key,
+            self.id,
+        ))
+    }
+
     /// Validates, decrypts, and decompresses the data in a `Chunk`.
     ///
     /// # Errors
@@ -239,7 +594,7 @@ impl Chunk {
     /// All of these error values indicate that the `Chunk` is corrupted or otherwise
     /// malformed.
     pub fn unpack(&self, key: &Key) -> Result<Vec<u8>> {
-        if self.hmac.verify_hmac(&self.mac, &self.data, key) {
+        if self.verify(key) {
             let decrypted_data = self.encryption.decrypt(&self.data, key)?;
             let decompressed_data = self.compression.decompress(decrypted_data)?;
 
@@ -249,6 +604,41 @@ impl Chunk {
         }
     }
 
+    /// Decodes a serialized `Chunk` from untrusted bytes and unpacks it, without panicking on
+    /// malformed input
+    ///
+    /// Unlike `unpack`, a chunk using an algorithm that support was not compiled in for, or an
+    /// unregistered custom HMAC, is reported as an error rather than causing a panic. This makes
+    /// it suitable for fuzzing, and for reading chunks that may have been corrupted.
+    ///
+    /// # Errors
+    ///
+    /// Will return `Err(DecodeError)` if the bytes are not a serialized `Chunk`.
+    ///
+    /// Will return `Err(Unsupported)` if the chunk's settings are not usable by this build, see
+    /// `ChunkSettings::validate`.
+    ///
+    /// Will otherwise return any of the errors `unpack` does.
+    pub fn try_unpack(bytes: &[u8], key: &Key) -> Result<Vec<u8>> {
+        let chunk: Chunk = serde_cbor::from_slice(bytes)?;
+        ChunkSettings {
+            compression: chunk.compression,
+            encryption: chunk.encryption,
+            hmac: chunk.hmac,
+        }
+        .validate()?;
+        chunk.unpack(key)
+    }
+
+    /// Checks the HMAC tag of this chunk against its stored bytes, without decrypting or
+    /// decompressing them
+    ///
+    /// The tag is computed over the bytes as stored, so this authenticates chunks regardless of
+    /// the encryption in use, including `Encryption::NoEncryption`.
+    pub fn verify(&self, key: &Key) -> bool {
+        self.hmac.verify_hmac(&self.mac, &self.data, key)
+    }
+
     #[cfg_attr(tarpaulin, skip)]
     /// Returns the length of the data in the `Chunk`
     pub fn len(&self) -> usize {
@@ -308,6 +698,16 @@ impl Chunk {
         self.encryption
     }
 
+    /// Returns the HMAC algorithm used by this chunk
+    pub fn hmac(&self) -> HMAC {
+        self.hmac
+    }
+
+    /// Returns the compression algorithm used by this chunk
+    pub fn compression(&self) -> Compression {
+        self.compression
+    }
+
     #[cfg(test)]
     #[cfg_attr(tarpaulin, skip)]
     /// Testing only function used to corrupt the data
@@ -361,6 +761,7 @@ mod tests {
             Encryption::NoEncryption,
             Encryption::new_aes256ctr(),
             Encryption::new_chacha20(),
+            Encryption::new_xchacha20poly1305(),
         ];
         let hmacs = [
             HMAC::SHA256,
@@ -368,6 +769,7 @@ mod tests {
             HMAC::Blake2bp,
             HMAC::Blake3,
             HMAC::SHA3,
+            HMAC::Blake3XOF,
         ];
         for c in compressions.iter() {
             for e in encryptions.iter() {
@@ -378,6 +780,50 @@ mod tests {
         }
     }
 
+    #[test]
+    fn validate_rejects_bad_levels() {
+        let bad_compressions = [
+            Compression::ZStd { level: 23 },
+            Compression::ZStd { level: -8 },
+            Compression::LZ4 { level: 17 },
+            Compression::LZ4 {
+                level: LZ4_MAX_LEVEL + 1,
+            },
+            Compression::LZ4Frame {
+                level: LZ4_MAX_LEVEL + 1,
+            },
+            Compression::LZMA { level: 10 },
+        ];
+        for compression in bad_compressions.iter() {
+            let settings = ChunkSettings {
+                compression: *compression,
+                encryption: Encryption::new_aes256ctr(),
+                hmac: HMAC::Blake3,
+            };
+            assert!(matches!(
+                settings.validate(),
+                Err(ConfigError::InvalidCompressionLevel { .. })
+            ));
+        }
+    }
+
+    #[test]
+    fn validate_accepts_good_settings() {
+        assert_eq!(ChunkSettings::lightweight().validate(), Ok(()));
+        let settings = ChunkSettings {
+            compression: Compression::ZStd { level: 3 },
+            encryption: Encryption::new_chacha20(),
+            hmac: HMAC::SHA256,
+        };
+        assert_eq!(settings.validate(), Ok(()));
+        let settings = ChunkSettings {
+            compression: Compression::LZMA { level: 9 },
+            encryption: Encryption::new_aes256ctr(),
+            hmac: HMAC::Blake2bp,
+        };
+        assert_eq!(settings.validate(), Ok(()));
+    }
+
     #[test]
     fn detect_bad_data() {
         let data_string = "I am but a humble test string";
@@ -396,6 +842,101 @@ mod tests {
         assert!(result.is_err());
     }
 
+    #[test]
+    #[cfg(feature = "blake3")]
+    fn authenticated_plaintext_detects_tampering() {
+        let data = b"I am but a humble test string".to_vec();
+        let settings = ChunkSettings::authenticated_plaintext();
+        assert_eq!(settings.validate(), Ok(()));
+        let key = Key::random(32);
+
+        let mut packed = Chunk::pack(
+            data.clone(),
+            settings.compression,
+            settings.encryption,
+            settings.hmac,
+            &key,
+        );
+        // The body is stored as is, but still authenticated
+        assert_eq!(packed.get_bytes(), &data[..]);
+        assert!(packed.verify(&key));
+
+        packed.break_data(5);
+        assert!(!packed.verify(&key));
+        assert!(matches!(
+            packed.unpack(&key),
+            Err(ChunkError::HMACValidationFailed)
+        ));
+    }
+
+    #[test]
+    #[cfg(all(
+        feature = "lz4",
+        feature = "zstd",
+        feature = "lzma",
+        feature = "chacha20",
+        feature = "chacha20poly1305",
+        feature = "blake3",
+        feature = "sha2"
+    ))]
+    fn presets_round_trip() {
+        let data = b"I am but a humble test string".to_vec();
+        let key = Key::random(32);
+        for settings in &[
+            ChunkSettings::fast(),
+            ChunkSettings::secure(),
+            ChunkSettings::archival(),
+        ] {
+            assert_eq!(settings.validate(), Ok(()));
+            let packed = Chunk::pack(
+                data.clone(),
+                settings.compression,
+                settings.encryption,
+                settings.hmac,
+                &key,
+            );
+            assert!(packed.verify(&key));
+            assert_eq!(packed.unpack(&key).expect("Failed to unpack"), data);
+        }
+    }
+
+    #[test]
+    fn chunk_id_display_from_str() {
+        for id in &[
+            ChunkID::random_id(),
+            ChunkID::new_extended(&[0xef_u8; 64]),
+            ChunkID::manifest_id(),
+        ] {
+            let displayed = id.to_string();
+            assert_eq!(displayed, id.to_hex());
+            assert_eq!(displayed.parse::<ChunkID>(), Ok(*id));
+            assert_eq!(displayed.to_uppercase().parse::<ChunkID>(), Ok(*id));
+        }
+        assert_eq!(
+            "abcd01".parse::<ChunkID>(),
+            Err(ParseChunkIDError::InvalidLength(6))
+        );
+        assert_eq!(
+            "z".repeat(64).parse::<ChunkID>(),
+            Err(ParseChunkIDError::InvalidDigit)
+        );
+    }
+
+    #[test]
+    fn chunk_id_hex() {
+        let id = ChunkID::new(&[0xab_u8, 0xcd, 0x01]);
+        let hex = id.to_hex();
+        assert_eq!(hex.len(), 64);
+        assert!(hex.starts_with("abcd01"));
+        assert_eq!(ChunkID::from_hex_prefix(&hex), Some(id));
+        assert_eq!(ChunkID::from_hex_prefix("abcd01"), Some(id));
+        assert_eq!(
+            ChunkID::from_hex_prefix("ABC"),
+            Some(ChunkID::new(&[0xab_u8, 0xc0]))
+        );
+        assert_eq!(ChunkID::from_hex_prefix("abcz"), None);
+    }
+
     #[test]
     fn chunk_id_equality() {
         let data1 = [1_u8; 64];
@@ -405,6 +946,82 @@ mod tests {
         assert!(!id.verify(&data2));
     }
 
+    #[test]
+    fn extended_id_round_trip() {
+        let data = b"I am but a humble test string".to_vec();
+        let key = Key::random(32);
+        let packed = Chunk::pack(
+            data,
+            Compression::NoCompression,
+            Encryption::NoEncryption,
+            HMAC::Blake3XOF,
+            &key,
+        );
+        let id = packed.get_id();
+        assert_eq!(id.id_length(), 64);
+        let bytes = serde_cbor::to_vec(&id).unwrap();
+        let output: ChunkID = serde_cbor::from_slice(&bytes).unwrap();
+        assert_eq!(id, output);
+        assert_eq!(id.to_bytes(), output.to_bytes());
+    }
+
+    #[test]
+    fn standard_id_format_unchanged() {
+        // The layout of ChunkID before extended keys were added
+        #[derive(Serialize, Deserialize)]
+        struct OldChunkID {
+            id: [u8; 32],
+        }
+        let id = ChunkID::random_id();
+        let old = OldChunkID { id: id.id };
+        let old_bytes = serde_cbor::to_vec(&old).unwrap();
+        assert_eq!(serde_cbor::to_vec(&id).unwrap(), old_bytes);
+        let output: ChunkID = serde_cbor::from_slice(&old_bytes).unwrap();
+        assert_eq!(id, output);
+    }
+
+    #[test]
+    fn custom_hmac_round_trip() {
+        let custom = crate::repository::hmac::XorHmac(150);
+        crate::repository::hmac::register_hmac(std::sync::Arc::new(custom)).unwrap();
+        let hmac = HMAC::from_algorithm_id(150).expect("Custom HMAC was not registered");
+        assert_eq!(hmac, HMAC::Custom(150));
+        let settings = ChunkSettings {
+            compression: Compression::NoCompression,
+            encryption: Encryption::new_aes256ctr(),
+            hmac,
+        };
+        assert_eq!(settings.validate(), Ok(()));
+
+        let data = b"I am but a humble test string".to_vec();
+        let key = Key::random(32);
+        let packed = Chunk::pack(
+            data.clone(),
+            settings.compression,
+            settings.encryption,
+            settings.hmac,
+            &key,
+        );
+        // The algorithm id is stored with the chunk, so it survives a round trip
+        let bytes = serde_cbor::to_vec(&packed).unwrap();
+        let mut output: Chunk = serde_cbor::from_slice(&bytes).unwrap();
+        assert_eq!(output.hmac(), HMAC::Custom(150));
+        assert_eq!(output.unpack(&key).unwrap(), data);
+        // And the custom implementation is actually used for verification
+        output.break_data(5);
+        assert!(output.unpack(&key).is_err());
+    }
+
+    #[test]
+    fn validate_rejects_unregistered_hmac() {
+        let settings = ChunkSettings {
+            compression: Compression::NoCompression,
+            encryption: Encryption::NoEncryption,
+            hmac: HMAC::Custom(250),
+        };
+        assert_eq!(settings.validate(), Err(ConfigError::UnregisteredHmac(250)));
+    }
+
     #[test]
     fn split_unsplit() {
         let data_string = "I am but a humble test string";
@@ -423,4 +1040,89 @@ mod tests {
 
         assert!(result.is_ok());
     }
+
+    #[test]
+    #[cfg(feature = "zstd")]
+    fn auto_compression_skips_incompressible() {
+        use rand::RngCore;
+        let key = Key::random(32);
+        let compressible = vec![7_u8; 10_000];
+        let mut incompressible = vec![0_u8; 10_000];
+        rand::thread_rng().fill_bytes(&mut incompressible);
+        let auto = Compression::Auto { level: 3 };
+        for (data, expected) in vec![
+            (compressible, Compression::ZStd { level: 3 }),
+            (incompressible, Compression::NoCompression),
+        ] {
+            let chunk = Chunk::pack(
+                data.clone(),
+                auto,
+                Encryption::NoEncryption,
+                HMAC::Blake3,
+                &key,
+            );
+            assert_eq!(chunk.compression(), expected);
+            assert_eq!(chunk.unpack(&key).unwrap(), data);
+        }
+    }
+
+    #[test]
+    fn repack_keeps_id() {
+        let key = Key::random(32);
+        let data = vec![7_u8; 10_000];
+        let chunk = Chunk::pack(
+            data.clone(),
+            Compression::NoCompression,
+            Encryption::NoEncryption,
+            HMAC::Blake3,
+            &key,
+        );
+        let repacked = chunk
+            .repack(
+                Compression::ZStd { level: 3 },
+                Encryption::new_aes256ctr(),
+                &key,
+            )
+            .unwrap();
+        assert_eq!(repacked.get_id(), chunk.get_id());
+        assert_eq!(repacked.hmac(), HMAC::Blake3);
+        assert_eq!(repacked.compression(), Compression::ZStd { level: 3 });
+        assert!(repacked.len() < chunk.len());
+        assert_eq!(repacked.unpack(&key).unwrap(), data);
+    }
+
+    #[test]
+    fn try_unpack_malformed() {
+        let data = b"I am but a humble test string".repeat(8);
+        let key = Key::random(32);
+        let packed = Chunk::pack(
+            data.clone(),
+            Compression::ZStd { level: 1 },
+            Encryption::new_aes256ctr(),
+            HMAC::Blake3,
+            &key,
+        );
+        let bytes = serde_cbor::to_vec(&packed).unwrap();
+        assert_eq!(Chunk::try_unpack(&bytes, &key).unwrap(), data);
+        // Every truncation must be rejected
+        for length in 0..bytes.len() {
+            assert!(Chunk::try_unpack(&bytes[..length], &key).is_err());
+        }
+        // A flipped bit may land in metadata that unpacking does not depend on, such as the id,
+        // but must never cause a panic, or produce the wrong data
+        let body = bytes
+            .windows(packed.len())
+            .position(|x| x == packed.get_bytes())
+            .unwrap();
+        for index in 0..bytes.len() {
+            for bit in 0..8 {
+                let mut flipped = bytes.clone();
+                flipped[index] ^= 1 << bit;
+                if let Ok(output) = Chunk::try_unpack(&flipped, &key) {
+                    assert!(index < body || index >= body + packed.len());
+                    assert_eq!(output, data);
+                }
+            }
+        }
+    }
 }
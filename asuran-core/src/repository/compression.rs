@@ -1,6 +1,6 @@
 use cfg_if::cfg_if;
 #[cfg(feature = "lz4")]
-use lz4::{Decoder, EncoderBuilder};
+use lz4::{BlockSize, ContentChecksum, Decoder, EncoderBuilder};
 use serde::{Deserialize, Serialize};
 use thiserror::Error;
 #[cfg(feature = "xz2")]
@@ -16,23 +16,109 @@ use std::io::Cursor;
 pub enum CompressionError {
     #[error("I/O Error")]
     IOError(#[from] std::io::Error),
+    #[error(
+        "Data can not be decompressed with Compression::Auto, it must be resolved with select"
+    )]
+    Unresolved,
 }
 
 type Result<T> = std::result::Result<T, CompressionError>;
 
+/// Number of bytes from the start of a chunk that `Compression::Auto` test compresses
+pub const AUTO_SAMPLE_SIZE: usize = 4096;
+/// Largest size, as a percentage of the original, that the sample can compress down to for
+/// `Compression::Auto` to consider compression worthwhile
+pub const AUTO_MAX_RATIO_PERCENT: usize = 90;
+
+/// The highest compression level accepted by liblz4, which the `lz4` crate wraps
+///
+/// This is `LZ4HC_CLEVEL_MAX` in liblz4, which silently clamps any higher level down to it. Level
+/// 0 selects the fast compressor, and levels from 3 up select the high compression one.
+pub const LZ4_MAX_LEVEL: u32 = 12;
+
 /// Marker for the type of compression used by a particular chunk
 #[derive(Copy, Clone, Serialize, Deserialize, Debug, PartialEq, Eq, Hash)]
 pub enum Compression {
     NoCompression,
-    ZStd { level: i32 },
-    LZ4 { level: u32 },
-    LZMA { level: u32 },
+    ZStd {
+        level: i32,
+    },
+    LZ4 {
+        level: u32,
+    },
+    LZMA {
+        level: u32,
+    },
+    /// LZ4 with content checksums and 4MiB blocks, better suited to very large chunks
+    ///
+    /// The output is a standard LZ4 frame, and can be decompressed by the `lz4` command line tool.
+    LZ4Frame {
+        level: u32,
+    },
+    /// Chooses between ZStd at the given level and no compression on a chunk by chunk basis,
+    /// skipping compression for data that does not compress well
+    ///
+    /// This is only a setting, chunks always record the algorithm that was actually selected.
+    Auto {
+        level: i32,
+    },
 }
 
 impl Compression {
+    /// Returns the human readable name of the algorithm indicated by the variant of `self`,
+    /// ignoring the level
+    pub fn name(self) -> &'static str {
+        match self {
+            Compression::NoCompression => "None",
+            Compression::ZStd { .. } => "ZStd",
+            Compression::LZ4 { .. } => "LZ4",
+            Compression::LZMA { .. } => "LZMA",
+            Compression::LZ4Frame { .. } => "LZ4Frame",
+            Compression::Auto { .. } => "Auto",
+        }
+    }
+
+    /// Returns true if support for the algorithm indicated by the variant of `self` was compiled
+    /// in
+    pub fn is_available(self) -> bool {
+        match self {
+            Compression::NoCompression => true,
+            Compression::ZStd { .. } | Compression::Auto { .. } => cfg!(feature = "zstd"),
+            Compression::LZ4 { .. } | Compression::LZ4Frame { .. } => cfg!(feature = "lz4"),
+            Compression::LZMA { .. } => cfg!(any(feature = "xz2", feature = "lzma-rs")),
+        }
+    }
+
+    /// Selects the algorithm that will actually be used to compress the given data
+    ///
+    /// For `Compression::Auto`, this compresses the first `AUTO_SAMPLE_SIZE` bytes of the data,
+    /// and selects ZStd if they shrink to at most `AUTO_MAX_RATIO_PERCENT` of their original
+    /// size, and `NoCompression` otherwise. All other variants select themselves.
+    pub fn select(self, data: &[u8]) -> Compression {
+        match self {
+            Compression::Auto { level } => {
+                let sample = &data[..data.len().min(AUTO_SAMPLE_SIZE)];
+                if sample.is_empty() {
+                    return Compression::NoCompression;
+                }
+                let zstd = Compression::ZStd { level };
+                let compressed = zstd.compress(sample.to_vec());
+                if compressed.len() * 100 <= sample.len() * AUTO_MAX_RATIO_PERCENT {
+                    zstd
+                } else {
+                    Compression::NoCompression
+                }
+            }
+            _ => self,
+        }
+    }
+
     /// Compresses the data with the algorithm indicated and level by the variant of
     /// `self`
     ///
+    /// `Compression::Auto` compresses with the algorithm chosen by `select`, which must be
+    /// recorded by the caller, as the output can not be decompressed without knowing it.
+    ///
     /// # Panics
     ///
     /// Will panic if the user selects a compression algorithm for which support has not
@@ -59,19 +145,23 @@ impl Compression {
             Compression::LZ4 { level } => {
                 cfg_if! {
                     if #[cfg(feature = "lz4")] {
-                        let ouput = Vec::<u8>::with_capacity(data.len());
-                        let cursor = Cursor::new(ouput);
-                        let mut encoder = EncoderBuilder::new()
+                        let mut builder = EncoderBuilder::new();
+                        builder.level(level);
+                        lz4_compress(&builder, data)
+                    } else {
+                        unimplemented!("Asuran was not compiled with lz4 support.")
+                    }
+                }
+            }
+            Compression::LZ4Frame { level } => {
+                cfg_if! {
+                    if #[cfg(feature = "lz4")] {
+                        let mut builder = EncoderBuilder::new();
+                        builder
                             .level(level)
-                            .build(cursor)
-                            .expect("Failed to build an LZ4 encoder. Check for OOM or invalid compression level.");
-                        let mut data = Cursor::new(data);
-                        // This unwrap should be infallible, since we are performing IO operations
-                        // on a vector
-                        copy(&mut data, &mut encoder).unwrap();
-                        let (cursor, result) = encoder.finish();
-                        result.expect("Failed to compress data with LZ4. Check for OOM or invalid compression level.");
-                        cursor.into_inner()
+                            .block_size(BlockSize::Max4MB)
+                            .checksum(ContentChecksum::ChecksumEnabled);
+                        lz4_compress(&builder, data)
                     } else {
                         unimplemented!("Asuran was not compiled with lz4 support.")
                     }
@@ -86,11 +176,20 @@ impl Compression {
                         copy(&mut compressor, &mut output)
                             .expect("Failed to compress data with LZMA. Check for invalid compression level or OOM");
                         output.into_inner()
+                    } else if #[cfg(feature = "lzma-rs")] {
+                        let mut output = Vec::new();
+                        lzma_rs::xz_compress(&mut Cursor::new(data), &mut output)
+                            .expect("Failed to compress data with LZMA. Check for OOM");
+                        output
                     } else {
                         unimplemented!("Asuran was not compiled with lzma support")
                     }
                 }
             }
+            Compression::Auto { .. } => {
+                let selected = self.select(&data);
+                selected.compress(data)
+            }
         }
     }
 
@@ -99,7 +198,8 @@ impl Compression {
     ///
     /// # Errors
     ///
-    /// Will return `Err` if decompression fails.
+    /// Will return `Err` if decompression fails, or if `self` is `Compression::Auto`, which does
+    /// not describe a single algorithm.
     ///
     /// # Panics
     ///
@@ -120,7 +220,8 @@ impl Compression {
                     }
                 }
             }
-            Compression::LZ4 { .. } => {
+            // Both modes produce LZ4 frames, so the same decoder handles them
+            Compression::LZ4 { .. } | Compression::LZ4Frame { .. } => {
                 cfg_if! {
                     if #[cfg(feature = "lz4")] {
                         let mut output = Cursor::new(Vec::<u8>::new());
@@ -142,15 +243,48 @@ impl Compression {
                         let mut decompressor = XzDecoder::new(input);
                         copy(&mut decompressor, &mut output)?;
                         Ok(output.into_inner())
+                    } else if #[cfg(feature = "lzma-rs")] {
+                        pure_lzma_decompress(data)
                     } else {
                         unimplemented!("Asuran was not compiled with lzma support")
                     }
                 }
             }
+            Compression::Auto { .. } => Err(CompressionError::Unresolved),
         }
     }
 }
 
+/// Decompresses an xz stream without liblzma, for builds with the `pure-rust-lzma` feature
+///
+/// Chunks written by either LZMA implementation are xz streams, so this can read both. When the
+/// `lzma` feature is also enabled, liblzma is used instead, and this is only compiled for the test
+/// checking that the two agree.
+#[cfg(all(feature = "lzma-rs", any(not(feature = "xz2"), test)))]
+fn pure_lzma_decompress(data: Vec<u8>) -> Result<Vec<u8>> {
+    let mut output = Vec::new();
+    lzma_rs::xz_decompress(&mut Cursor::new(data), &mut output)
+        .map_err(|e| std::io::Error::new(std::io::ErrorKind::InvalidData, format!("{:?}", e)))?;
+    Ok(output)
+}
+
+/// Compresses data into an LZ4 frame with the settings in the given builder
+#[cfg(feature = "lz4")]
+fn lz4_compress(builder: &EncoderBuilder, data: Vec<u8>) -> Vec<u8> {
+    let ouput = Vec::<u8>::with_capacity(data.len());
+    let cursor = Cursor::new(ouput);
+    let mut encoder = builder
+        .build(cursor)
+        .expect("Failed to build an LZ4 encoder. Check for OOM or invalid compression level.");
+    let mut data = Cursor::new(data);
+    // This unwrap should be infallible, since we are performing IO operations
+    // on a vector
+    copy(&mut data, &mut encoder).unwrap();
+    let (cursor, result) = encoder.finish();
+    result.expect("Failed to compress data with LZ4. Check for OOM or invalid compression level.");
+    cursor.into_inner()
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -179,4 +313,34 @@ mod tests {
 
         assert_eq!(data_string, decompressed_string);
     }
+
+    #[cfg(feature = "lz4")]
+    #[test]
+    fn test_lz4_frame() {
+        let compression = Compression::LZ4Frame { level: 4 };
+        let data = "The quick brown fox jumps over the lazy dog. ".repeat(1000);
+        let compressed = compression.compress(data.as_bytes().to_vec());
+        assert!(compressed.len() < data.len());
+        // LZ4 frame magic number, followed by a FLG byte with the content checksum bit set
+        assert_eq!(&compressed[..4], &[0x04, 0x22, 0x4D, 0x18]);
+        assert_ne!(compressed[4] & 0b0000_0100, 0);
+        // Decode with the reference frame decoder directly, bypassing our own wrapper
+        let mut decoder = Decoder::new(Cursor::new(compressed.clone())).unwrap();
+        let mut reference = Vec::new();
+        copy(&mut decoder, &mut reference).unwrap();
+        assert_eq!(reference, data.as_bytes());
+        let decompressed = compression.decompress(compressed).unwrap();
+        assert_eq!(decompressed, data.as_bytes());
+    }
+
+    #[cfg(all(feature = "xz2", feature = "lzma-rs"))]
+    #[test]
+    fn pure_rust_lzma_reads_liblzma() {
+        let compression = Compression::LZMA { level: 6 };
+        let data = "The quick brown fox jumps over the lazy dog. ".repeat(1000);
+        let compressed = compression.compress(data.as_bytes().to_vec());
+        assert!(compressed.len() < data.len());
+        let decompressed = pure_lzma_decompress(compressed).unwrap();
+        assert_eq!(decompressed, data.as_bytes());
+    }
 }
@@ -11,9 +11,15 @@ encryption algorithms.
 // encryption method (this is an encrypting archiver after all)
 
 mod aes_shim;
+#[cfg(feature = "recipients")]
+pub mod recipient;
 
 #[cfg(feature = "chacha20")]
 use chacha20::ChaCha20;
+#[cfg(feature = "chacha20poly1305")]
+use chacha20poly1305::aead::{Aead, NewAead};
+#[cfg(feature = "chacha20poly1305")]
+use chacha20poly1305::XChaCha20Poly1305;
 use rand::prelude::*;
 #[allow(unused_imports)]
 use serde::{Deserialize, Serialize};
@@ -30,19 +36,42 @@ use zeroize::Zeroize;
 #[cfg(feature = "aes-family")]
 use crate::repository::Key;
 
+#[cfg(feature = "recipients")]
+pub use self::recipient::{unwrap_key, wrap_key, PublicKey, RecipientError, SecretKey, WrappedKey};
+
 /// Error describing things that can go wrong with encryption/decryption
 #[derive(Error, Debug)]
-#[allow(clippy::empty_enum)]
-pub enum EncryptionError {}
+pub enum EncryptionError {
+    /// The tag of an authenticated ciphertext did not match its contents
+    #[error("Authenticated decryption failed, the ciphertext or key is incorrect")]
+    AuthenticationFailed,
+}
 
 type Result<T> = std::result::Result<T, EncryptionError>;
 
 /// Tag for the encryption algorthim and IV used by a particular chunk
 #[derive(Copy, Clone, Serialize, Deserialize, Debug, PartialEq, Eq, Hash)]
 pub enum Encryption {
+    /// Leaves chunk bodies as plaintext
+    ///
+    /// This provides no confidentiality, but chunks are still authenticated by their HMAC tag,
+    /// which is computed over the stored bytes regardless of the encryption in use. Tampering
+    /// with a plaintext chunk is detected by `Chunk::verify`, and causes `Chunk::unpack` to fail.
     NoEncryption,
-    AES256CTR { iv: [u8; 16] },
-    ChaCha20 { iv: [u8; 12] },
+    AES256CTR {
+        iv: [u8; 16],
+    },
+    ChaCha20 {
+        iv: [u8; 12],
+    },
+    /// Encrypts and authenticates chunk bodies with the XChaCha20Poly1305 AEAD
+    ///
+    /// The 24 byte nonce is large enough to be safely generated at random. The 16 byte Poly1305
+    /// tag is appended to the ciphertext, and checked, in addition to the chunk's HMAC, when the
+    /// chunk is decrypted.
+    XChaCha20Poly1305 {
+        iv: [u8; 24],
+    },
 }
 
 impl Encryption {
@@ -60,6 +89,13 @@ impl Encryption {
         Encryption::ChaCha20 { iv }
     }
 
+    /// Creates a new `XChaCha20Poly1305` with a random securely generated nonce
+    pub fn new_xchacha20poly1305() -> Encryption {
+        let mut iv: [u8; 24] = [0; 24];
+        thread_rng().fill_bytes(&mut iv);
+        Encryption::XChaCha20Poly1305 { iv }
+    }
+
     /// Returns the key length of this encryption method in bytes
     ///
     /// `NoEncryption` has a key length of 16 bytes, as some things rely on a non-zero key
@@ -69,6 +105,7 @@ impl Encryption {
             Encryption::NoEncryption => 16,
             Encryption::AES256CTR { .. } => 32,
             Encryption::ChaCha20 { .. } => 32,
+            Encryption::XChaCha20Poly1305 { .. } => 32,
         }
     }
 
@@ -126,6 +163,26 @@ impl Encryption {
                     }
                 }
             }
+            Encryption::XChaCha20Poly1305 { iv } => {
+                cfg_if::cfg_if! {
+                    if #[cfg(feature = "chacha20poly1305")] {
+                        let mut proper_key: [u8; 32] = [0; 32];
+                        proper_key[..cmp::min(key.len(), 32)]
+                            .clone_from_slice(&key[..cmp::min(key.len(), 32)]);
+                        let cipher = XChaCha20Poly1305::new(GenericArray::from_slice(&proper_key));
+                        let final_result = cipher
+                            .encrypt(GenericArray::from_slice(&iv[..]), data)
+                            .expect("XChaCha20Poly1305 encryption failed");
+
+                        proper_key.zeroize();
+                        final_result
+                    } else {
+                        unimplemented!(
+                            "Asuran has not been compiled with XChaCha20Poly1305 support"
+                        )
+                    }
+                }
+            }
         }
     }
 
@@ -136,7 +193,8 @@ impl Encryption {
     ///
     /// # Errors
     ///
-    /// Will return `Err` if decryption fails
+    /// Will return `Err` if decryption fails, or, for authenticated algorithms, if the
+    /// ciphertext does not match its tag
     ///
     /// # Panics
     ///
@@ -179,6 +237,26 @@ impl Encryption {
                     }
                 }
             }
+            Encryption::XChaCha20Poly1305 { iv } => {
+                cfg_if::cfg_if! {
+                    if #[cfg(feature = "chacha20poly1305")] {
+                        let mut proper_key: [u8; 32] = [0; 32];
+                        proper_key[..cmp::min(key.len(), 32)]
+                            .clone_from_slice(&key[..cmp::min(key.len(), 32)]);
+                        let cipher = XChaCha20Poly1305::new(GenericArray::from_slice(&proper_key));
+                        let result = cipher
+                            .decrypt(GenericArray::from_slice(&iv[..]), data)
+                            .map_err(|_| EncryptionError::AuthenticationFailed);
+
+                        proper_key.zeroize();
+                        result
+                    } else {
+                        unimplemented!(
+                            "Asuran has not been compiled with XChaCha20Poly1305 support"
+                        )
+                    }
+                }
+            }
         }
     }
 
@@ -189,6 +267,7 @@ impl Encryption {
             Encryption::NoEncryption => Encryption::NoEncryption,
             Encryption::AES256CTR { .. } => Encryption::new_aes256ctr(),
             Encryption::ChaCha20 { .. } => Encryption::new_chacha20(),
+            Encryption::XChaCha20Poly1305 { .. } => Encryption::new_xchacha20poly1305(),
         }
     }
 }
@@ -228,4 +307,29 @@ mod tests {
         let enc = Encryption::new_aes256ctr();
         test_encryption(enc);
     }
+
+    #[test]
+    #[cfg(feature = "chacha20poly1305")]
+    fn test_xchacha20poly1305() {
+        let enc = Encryption::new_xchacha20poly1305();
+        test_encryption(enc);
+    }
+
+    #[test]
+    #[cfg(feature = "chacha20poly1305")]
+    fn xchacha20poly1305_detects_tampering() {
+        let mut key: [u8; 32] = [0; 32];
+        thread_rng().fill_bytes(&mut key);
+        let mut enc = Encryption::new_xchacha20poly1305();
+        let data = b"I am but a humble test string";
+
+        let mut encrypted = enc.encrypt_bytes(data, &key);
+        // The tag is appended to the ciphertext
+        assert_eq!(encrypted.len(), data.len() + 16);
+        encrypted[3] ^= 0x01;
+        assert!(matches!(
+            enc.decrypt_bytes(&encrypted, &key),
+            Err(EncryptionError::AuthenticationFailed)
+        ));
+    }
 }
@@ -0,0 +1,303 @@
+/*!
+Wrapping of key material to the X25519 public keys of one or more recipients
+
+This is used to give individual archives their own key, readable only by their recipients, while
+the rest of the repository stays under the repository key. Wrapping follows the same scheme as
+age's X25519 recipients:
+
+1. A fresh ephemeral X25519 key pair is generated for every recipient
+2. The wrapping key is derived with HKDF-SHA256 from the shared secret, salted with both public
+   keys
+3. The wrapped key is packed into a `Chunk` under the wrapping key, which provides both
+   encryption and authentication, so unwrapping with the wrong secret key fails cleanly
+*/
+use crate::repository::{Chunk, Compression, Encryption, Key, HMAC};
+
+use hkdf::Hkdf;
+use rand::prelude::*;
+use serde::{Deserialize, Serialize};
+use sha2::Sha256;
+use thiserror::Error;
+use x25519_dalek::StaticSecret;
+use zeroize::Zeroize;
+
+use std::fmt;
+use std::str::FromStr;
+
+/// Context string used when deriving wrapping keys
+const WRAP_CONTEXT: &[u8] = b"asuran archive key wrap";
+
+/// Error describing things that can go wrong wrapping or unwrapping keys
+#[derive(Error, Debug)]
+pub enum RecipientError {
+    #[error("The key was not wrapped to this secret key")]
+    NotARecipient,
+    #[error("Public key is not a valid X25519 public key")]
+    InvalidPublicKey,
+    #[error("Secret key is not a valid X25519 secret key")]
+    InvalidSecretKey,
+    #[error("Failed to serialize or deserialize a wrapped key")]
+    Serialization(#[from] serde_cbor::Error),
+}
+
+type Result<T> = std::result::Result<T, RecipientError>;
+
+/// An X25519 public key, that keys can be wrapped to
+///
+/// Displays as, and parses from, 64 hex digits.
+#[derive(Copy, Clone, Serialize, Deserialize, Debug, PartialEq, Eq, Hash)]
+pub struct PublicKey([u8; 32]);
+
+impl PublicKey {
+    /// Creates a public key from its raw bytes
+    pub fn from_bytes(bytes: [u8; 32]) -> PublicKey {
+        PublicKey(bytes)
+    }
+
+    /// Returns the raw bytes of this public key
+    pub fn as_bytes(&self) -> &[u8; 32] {
+        &self.0
+    }
+}
+
+impl fmt::Display for PublicKey {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        for byte in &self.0 {
+            write!(f, "{:02x}", byte)?;
+        }
+        Ok(())
+    }
+}
+
+impl FromStr for PublicKey {
+    type Err = RecipientError;
+    fn from_str(s: &str) -> Result<PublicKey> {
+        parse_hex_key(s)
+            .map(PublicKey)
+            .ok_or(RecipientError::InvalidPublicKey)
+    }
+}
+
+/// Parses 64 hex digits into the 32 bytes of a key
+fn parse_hex_key(s: &str) -> Option<[u8; 32]> {
+    if s.len() != 64 || !s.is_ascii() {
+        return None;
+    }
+    let mut bytes = [0_u8; 32];
+    for (i, byte) in bytes.iter_mut().enumerate() {
+        *byte = u8::from_str_radix(&s[i * 2..i * 2 + 2], 16).ok()?;
+    }
+    Some(bytes)
+}
+
+/// An X25519 secret key, able to unwrap keys wrapped to its `PublicKey`
+///
+/// Parses from 64 hex digits, surrounding whitespace is ignored. It does not implement
+/// `Display`, to make accidentally printing it harder.
+#[derive(Clone, Zeroize)]
+#[zeroize(drop)]
+pub struct SecretKey([u8; 32]);
+
+impl SecretKey {
+    /// Securely generates a new random secret key
+    pub fn random() -> SecretKey {
+        let mut bytes = [0_u8; 32];
+        thread_rng().fill_bytes(&mut bytes);
+        SecretKey(bytes)
+    }
+
+    /// Creates a secret key from its raw bytes
+    pub fn from_bytes(bytes: [u8; 32]) -> SecretKey {
+        SecretKey(bytes)
+    }
+
+    /// Returns the raw bytes of this secret key
+    pub fn to_bytes(&self) -> [u8; 32] {
+        self.0
+    }
+
+    /// Computes the public key corresponding to this secret key
+    pub fn public_key(&self) -> PublicKey {
+        let secret = StaticSecret::from(self.0);
+        PublicKey(*x25519_dalek::PublicKey::from(&secret).as_bytes())
+    }
+
+    /// Performs the Diffie-Hellman exchange with the provided public key
+    ///
+    /// Returns `None` if the exchange produces the all zero shared secret, which only happens for
+    /// public keys of low order, and would make the wrapping key predictable.
+    fn exchange(&self, public: &PublicKey) -> Option<[u8; 32]> {
+        let secret = StaticSecret::from(self.0);
+        let shared = *secret
+            .diffie_hellman(&x25519_dalek::PublicKey::from(public.0))
+            .as_bytes();
+        if shared == [0_u8; 32] {
+            None
+        } else {
+            Some(shared)
+        }
+    }
+}
+
+impl FromStr for SecretKey {
+    type Err = RecipientError;
+    fn from_str(s: &str) -> Result<SecretKey> {
+        parse_hex_key(s.trim())
+            .map(SecretKey)
+            .ok_or(RecipientError::InvalidSecretKey)
+    }
+}
+
+impl fmt::Debug for SecretKey {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "SecretKey({})", self.public_key())
+    }
+}
+
+/// A `Key` wrapped to a single recipient
+#[derive(Clone, Serialize, Deserialize, Debug)]
+pub struct WrappedKey {
+    /// The public half of the ephemeral key pair used for this recipient
+    ephemeral: PublicKey,
+    /// The wrapped key, packed under the wrapping key
+    wrapped: Chunk,
+}
+
+/// Derives the key used to wrap keys for `recipient`, from the shared secret of the exchange
+/// between `ephemeral` and `recipient`
+fn wrapping_key(mut shared: [u8; 32], ephemeral: &PublicKey, recipient: &PublicKey) -> Key {
+    let salt = [&ephemeral.0[..], &recipient.0[..]].concat();
+    let hkdf = Hkdf::<Sha256>::new(Some(&salt), &shared);
+    shared.zeroize();
+    let mut output = [0_u8; 96];
+    hkdf.expand(WRAP_CONTEXT, &mut output)
+        .expect("96 bytes is a valid HKDF-SHA256 output length");
+    let key = Key::from_bytes(&output, 0);
+    output.zeroize();
+    key
+}
+
+impl WrappedKey {
+    /// Wraps `key` to the provided recipient
+    ///
+    /// # Errors
+    ///
+    /// Will return `Err` if the recipient's public key is of low order
+    pub fn wrap(key: &Key, recipient: &PublicKey) -> Result<WrappedKey> {
+        let ephemeral_secret = SecretKey::random();
+        let ephemeral = ephemeral_secret.public_key();
+        let shared = ephemeral_secret
+            .exchange(recipient)
+            .ok_or(RecipientError::InvalidPublicKey)?;
+        let wrapping_key = wrapping_key(shared, &ephemeral, recipient);
+        let mut bytes = serde_cbor::to_vec(key)?;
+        let wrapped = Chunk::pack(
+            bytes.clone(),
+            Compression::NoCompression,
+            Encryption::new_aes256ctr(),
+            HMAC::SHA256,
+            &wrapping_key,
+        );
+        bytes.zeroize();
+        Ok(WrappedKey { ephemeral, wrapped })
+    }
+
+    /// Attempts to unwrap the key with the provided secret key
+    ///
+    /// # Errors
+    ///
+    /// Will return `RecipientError::NotARecipient` if the key was not wrapped to the public key
+    /// of `secret`
+    pub fn unwrap(&self, secret: &SecretKey) -> Result<Key> {
+        let shared = secret
+            .exchange(&self.ephemeral)
+            .ok_or(RecipientError::NotARecipient)?;
+        let wrapping_key = wrapping_key(shared, &self.ephemeral, &secret.public_key());
+        let mut bytes = self
+            .wrapped
+            .unpack(&wrapping_key)
+            .map_err(|_| RecipientError::NotARecipient)?;
+        let key = serde_cbor::from_slice(&bytes);
+        bytes.zeroize();
+        Ok(key?)
+    }
+}
+
+/// Wraps `key` to every one of the provided recipients
+///
+/// # Errors
+///
+/// Will return `Err` if any of the recipients' public keys are of low order
+pub fn wrap_key(key: &Key, recipients: &[PublicKey]) -> Result<Vec<WrappedKey>> {
+    recipients
+        .iter()
+        .map(|recipient| WrappedKey::wrap(key, recipient))
+        .collect()
+}
+
+/// Unwraps the key from the first of the wrapped keys that was wrapped to `secret`
+///
+/// # Errors
+///
+/// Will return `RecipientError::NotARecipient` if none of the keys were wrapped to the public key
+/// of `secret`
+pub fn unwrap_key(wrapped: &[WrappedKey], secret: &SecretKey) -> Result<Key> {
+    wrapped
+        .iter()
+        .find_map(|wrapped| wrapped.unwrap(secret).ok())
+        .ok_or(RecipientError::NotARecipient)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn wrap_unwrap() {
+        let key = Key::random(32);
+        let alice = SecretKey::random();
+        let bob = SecretKey::random();
+        let carol = SecretKey::random();
+        let wrapped = wrap_key(&key, &[alice.public_key(), bob.public_key()]).unwrap();
+
+        assert_eq!(unwrap_key(&wrapped, &alice).unwrap(), key);
+        assert_eq!(unwrap_key(&wrapped, &bob).unwrap(), key);
+        assert!(matches!(
+            unwrap_key(&wrapped, &carol),
+            Err(RecipientError::NotARecipient)
+        ));
+    }
+
+    #[test]
+    fn public_key_display_from_str() {
+        let public = SecretKey::random().public_key();
+        let string = public.to_string();
+        assert_eq!(string.len(), 64);
+        assert_eq!(string.parse::<PublicKey>().unwrap(), public);
+        assert!("abcd".parse::<PublicKey>().is_err());
+        assert!("zz".repeat(32).parse::<PublicKey>().is_err());
+    }
+
+    #[test]
+    fn secret_key_from_str() {
+        let secret = SecretKey::random();
+        let string: String = secret
+            .to_bytes()
+            .iter()
+            .map(|byte| format!("{:02x}", byte))
+            .collect();
+        let parsed = format!("{}\n", string).parse::<SecretKey>().unwrap();
+        assert_eq!(parsed.to_bytes(), secret.to_bytes());
+        assert!("abcd".parse::<SecretKey>().is_err());
+    }
+
+    #[test]
+    fn low_order_recipient_rejected() {
+        let key = Key::random(32);
+        let zero = PublicKey::from_bytes([0_u8; 32]);
+        assert!(matches!(
+            WrappedKey::wrap(&key, &zero),
+            Err(RecipientError::InvalidPublicKey)
+        ));
+    }
+}
@@ -8,6 +8,11 @@ algorithm.
 
 As such, at least one HMAC algorithm feature must be enabled, or else you will
 get a compile time error.
+
+Library users can provide their own algorithms by implementing the `Hmac` trait and
+registering the implementation with `register_hmac`. Chunks tagged with
+`HMAC::Custom(id)` will then use the implementation registered under that id. Ids
+below `CUSTOM_ID_START` are reserved for the built in algorithms.
 */
 #[cfg(feature = "blake2b_simd")]
 use blake2b_simd::blake2bp;
@@ -17,7 +22,8 @@ use cfg_if::cfg_if;
 #[allow(unused_imports)]
 use crypto_mac::NewMac;
 #[allow(unused_imports)]
-use hmac::{Hmac, Mac};
+use hmac::{Hmac as GenericHmac, Mac};
+use lazy_static::lazy_static;
 use serde::{Deserialize, Serialize};
 #[cfg(feature = "sha2")]
 use sha2::Sha256;
@@ -26,7 +32,11 @@ use sha3::Sha3_256;
 
 use crate::repository::Key;
 
+use thiserror::Error;
+
 use std::cmp::min;
+use std::collections::HashMap;
+use std::sync::{Arc, RwLock};
 
 #[cfg(not(any(
     feature = "blake2b_simd",
@@ -37,9 +47,26 @@ use std::cmp::min;
 compile_error!("Asuran requires at least one HMAC algorithim to be enabled.");
 
 #[cfg(feature = "sha2")]
-type HmacSha256 = Hmac<Sha256>;
+type HmacSha256 = GenericHmac<Sha256>;
 #[cfg(feature = "sha3")]
-type HmacSHA3 = Hmac<Sha3_256>;
+type HmacSHA3 = GenericHmac<Sha3_256>;
+
+/// The number of bytes of output produced by `HMAC::Blake3XOF`
+pub const XOF_LENGTH: usize = 64;
+
+/// Converts key material into a Blake3 key, truncating or zero padding it to 32 bytes
+#[cfg(feature = "blake3")]
+fn blake3_key(key: &[u8]) -> [u8; 32] {
+    let mut tmp_key = [0_u8; 32];
+    let len = min(32, key.len());
+    tmp_key[..len].copy_from_slice(&key[..len]);
+    tmp_key
+}
+
+/// Compares two byte strings without short circuiting on the first difference
+fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    a.len() == b.len() && a.iter().zip(b).fold(0, |acc, (x, y)| acc | (x ^ y)) == 0
+}
 
 /// Tag for the HMAC algorithim used by a particular `Chunk`
 #[derive(Deserialize, Serialize, Copy, Clone, Debug, PartialEq, Eq, Hash)]
@@ -49,19 +76,145 @@ pub enum HMAC {
     Blake2bp,
     Blake3,
     SHA3,
+    /// Blake3 in keyed mode, using its extendable output to produce 64 byte tags and
+    /// `ChunkID`s, for users wanting extra collision margin
+    Blake3XOF,
+    /// A user provided algorithm, registered with `register_hmac` under the given id
+    Custom(u8),
+}
+
+/// The lowest id that can be used for a custom `Hmac` implementation
+pub const CUSTOM_ID_START: u8 = 128;
+
+/// An HMAC algorithm that can be plugged into asuran at runtime
+///
+/// Implementations are registered with `register_hmac`, and are then selected by
+/// `HMAC::Custom` with a matching id.
+pub trait Hmac: Send + Sync {
+    /// The id this algorithm is registered and stored under
+    fn id(&self) -> u8;
+    /// Produces a tag for the given data, keyed with the given key material
+    fn mac(&self, key: &[u8], data: &[u8]) -> Vec<u8>;
+}
+
+/// An error for things that can go wrong while registering or looking up a custom `Hmac`
+#[derive(Error, Debug, PartialEq, Eq)]
+pub enum HmacRegistryError {
+    #[error("HMAC id {0} is reserved for built in algorithms")]
+    ReservedId(u8),
+    #[error("An HMAC algorithm is already registered with id {0}")]
+    DuplicateId(u8),
+    #[error("No HMAC algorithm is registered with id {0}")]
+    Unregistered(u8),
+}
+
+/// Wraps a built in algorithm, so that it can be placed in the registry
+struct BuiltinHmac(HMAC);
+
+impl Hmac for BuiltinHmac {
+    fn id(&self) -> u8 {
+        self.0.algorithm_id()
+    }
+    fn mac(&self, key: &[u8], data: &[u8]) -> Vec<u8> {
+        self.0
+            .internal_mac(data, key)
+            .expect("Built in HMAC algorithms are always registered")
+    }
+}
+
+lazy_static! {
+    static ref REGISTRY: RwLock<HashMap<u8, Arc<dyn Hmac>>> = {
+        let mut map: HashMap<u8, Arc<dyn Hmac>> = HashMap::new();
+        // Only the algorithms that were compiled in can actually be used
+        let builtins = [
+            (HMAC::SHA256, cfg!(feature = "sha2")),
+            (HMAC::Blake2b, cfg!(feature = "blake2b_simd")),
+            (HMAC::Blake2bp, cfg!(feature = "blake2b_simd")),
+            (HMAC::Blake3, cfg!(feature = "blake3")),
+            (HMAC::SHA3, cfg!(feature = "sha3")),
+            (HMAC::Blake3XOF, cfg!(feature = "blake3")),
+        ];
+        for (hmac, compiled) in &builtins {
+            if *compiled {
+                map.insert(hmac.algorithm_id(), Arc::new(BuiltinHmac(*hmac)));
+            }
+        }
+        RwLock::new(map)
+    };
+}
+
+/// Registers a custom `Hmac` implementation under its id
+///
+/// # Errors
+///
+/// Will return `Err` if the id is below `CUSTOM_ID_START`, or if another implementation has
+/// already been registered with the same id.
+pub fn register_hmac(hmac: Arc<dyn Hmac>) -> Result<(), HmacRegistryError> {
+    let id = hmac.id();
+    if id < CUSTOM_ID_START {
+        return Err(HmacRegistryError::ReservedId(id));
+    }
+    let mut registry = REGISTRY.write().expect("HMAC registry lock poisoned");
+    if registry.contains_key(&id) {
+        return Err(HmacRegistryError::DuplicateId(id));
+    }
+    registry.insert(id, hmac);
+    Ok(())
+}
+
+/// Looks up the implementation registered under the given id, built in or custom
+///
+/// Built in algorithms are only registered if support for them was compiled in.
+pub fn lookup_hmac(id: u8) -> Option<Arc<dyn Hmac>> {
+    REGISTRY
+        .read()
+        .expect("HMAC registry lock poisoned")
+        .get(&id)
+        .cloned()
 }
 
 impl HMAC {
+    /// Returns the id this algorithm is registered under
+    pub fn algorithm_id(self) -> u8 {
+        match self {
+            HMAC::SHA256 => 0,
+            HMAC::Blake2b => 1,
+            HMAC::Blake2bp => 2,
+            HMAC::Blake3 => 3,
+            HMAC::SHA3 => 4,
+            HMAC::Blake3XOF => 5,
+            HMAC::Custom(id) => id,
+        }
+    }
+
+    /// Returns the algorithm registered under the given id, if there is one
+    pub fn from_algorithm_id(id: u8) -> Option<HMAC> {
+        match id {
+            0 => Some(HMAC::SHA256),
+            1 => Some(HMAC::Blake2b),
+            2 => Some(HMAC::Blake2bp),
+            3 => Some(HMAC::Blake3),
+            4 => Some(HMAC::SHA3),
+            5 => Some(HMAC::Blake3XOF),
+            id if id >= CUSTOM_ID_START && lookup_hmac(id).is_some() => Some(HMAC::Custom(id)),
+            _ => None,
+        }
+    }
+
     /// Produces an HMAC for the given data with the given key, using the algorithm
     /// specified by the variant of `self`.
     ///
+    /// # Errors
+    ///
+    /// Will return `Err` if `self` is a custom algorithm with no registered implementation.
+    ///
     /// # Panics
     ///
     /// Will panic if the user attempts to produce an HMAC using an algorithm for which
     /// support was not compiled in.
     #[allow(unused_variables)]
-    fn internal_mac(self, data: &[u8], key: &[u8]) -> Vec<u8> {
-        match self {
+    fn internal_mac(self, data: &[u8], key: &[u8]) -> Result<Vec<u8>, HmacRegistryError> {
+        let output = match self {
             HMAC::SHA256 => {
                 cfg_if! {
                     if #[cfg(feature = "sha2")] {
@@ -105,10 +258,21 @@ impl HMAC {
             HMAC::Blake3 => {
                 cfg_if! {
                     if #[cfg(feature = "blake3")] {
-                        let mut tmp_key = [0_u8; 32];
-                        let len = min(32, key.len());
-                        tmp_key[..len].copy_from_slice(&key[..len]);
-                        blake3::keyed_hash(&tmp_key, data).as_bytes().to_vec()
+                        blake3::keyed_hash(&blake3_key(key), data).as_bytes().to_vec()
+                    } else {
+                        unimplemented!("Asuran was not compiled with BLAKE3 support")
+                    }
+                }
+            }
+            HMAC::Blake3XOF => {
+                cfg_if! {
+                    if #[cfg(feature = "blake3")] {
+                        let mut output = vec![0_u8; XOF_LENGTH];
+                        blake3::Hasher::new_keyed(&blake3_key(key))
+                            .update(data)
+                            .finalize_xof()
+                            .fill(&mut output);
+                        output
                     } else {
                         unimplemented!("Asuran was not compiled with BLAKE3 support")
                     }
@@ -126,7 +290,11 @@ impl HMAC {
                     }
                 }
             }
-        }
+            HMAC::Custom(id) => lookup_hmac(id)
+                .ok_or(HmacRegistryError::Unregistered(id))?
+                .mac(key, data),
+        };
+        Ok(output)
     }
 
     /// Produces an HMAC tag using the section of the key material reserved for
@@ -135,22 +303,63 @@ impl HMAC {
     /// # Panics
     ///
     /// Will panic if the user has selected an algorithm for which support has not been
-    /// compiled in.
+    /// compiled in, or a custom algorithm that has not been registered. Use `try_mac` to
+    /// handle the latter.
     pub fn mac(self, data: &[u8], key: &Key) -> Vec<u8> {
-        let key = key.hmac_key();
-        self.internal_mac(data, key)
+        self.try_mac(data, key).unwrap_or_else(|e| panic!("{}", e))
     }
 
     /// Produces an HMAC tag using the section of the key material reserved for
-    /// `ChunkID` generation.
+    /// integrity verification.
+    ///
+    /// # Errors
+    ///
+    /// Will return `Err` if `self` is a custom algorithm with no registered implementation.
     ///
     /// # Panics
     ///
     /// Will panic if the user has selected an algorithm for which support has not been
     /// compiled in.
+    pub fn try_mac(self, data: &[u8], key: &Key) -> Result<Vec<u8>, HmacRegistryError> {
+        self.internal_mac(data, key.hmac_key())
+    }
+
+    /// Produces an HMAC tag using the section of the key material reserved for
+    /// `ChunkID` generation.
+    ///
+    /// # Panics
+    ///
+    /// Will panic if the user has selected an algorithm for which support has not been
+    /// compiled in, or a custom algorithm that has not been registered. Use `try_id` to
+    /// handle the latter.
     pub fn id(self, data: &[u8], key: &Key) -> Vec<u8> {
-        let key = key.id_key();
-        self.internal_mac(data, key)
+        self.try_id(data, key).unwrap_or_else(|e| panic!("{}", e))
+    }
+
+    /// Produces an HMAC tag using the section of the key material reserved for
+    /// `ChunkID` generation.
+    ///
+    /// # Errors
+    ///
+    /// Will return `Err` if `self` is a custom algorithm with no registered implementation.
+    ///
+    /// # Panics
+    ///
+    /// Will panic if the user has selected an algorithm for which support has not been
+    /// compiled in.
+    pub fn try_id(self, data: &[u8], key: &Key) -> Result<Vec<u8>, HmacRegistryError> {
+        self.internal_mac(data, key.id_key())
+    }
+
+    /// Returns true if this algorithm can be used, that is, if support for it was compiled in,
+    /// or, for a custom algorithm, if an implementation has been registered for it
+    pub fn is_available(self) -> bool {
+        lookup_hmac(self.algorithm_id()).is_some()
+    }
+
+    /// Returns true if this algorithm produces `ChunkID`s longer than the standard 32 bytes
+    pub fn extended_ids(self) -> bool {
+        matches!(self, HMAC::Blake3XOF)
     }
 
     /// Produces an HMAC for the supplied data, using the portion of the supplied key
@@ -158,6 +367,9 @@ impl HMAC {
     /// of `self`, and verifies it against the supplied HMAC, using constant time
     /// comparisons where possible.
     ///
+    /// A custom algorithm with no registered implementation can not verify anything, so always
+    /// fails verification.
+    ///
     /// # Panics
     ///
     /// Panics if the user has selected an algorithm for which support has not been
@@ -202,6 +414,10 @@ impl HMAC {
             HMAC::Blake3 => {
                 cfg_if! {
                     if #[cfg(feature = "blake3")] {
+                        // A tag of the wrong length can only come from a corrupted chunk
+                        if input_mac.len() != 32 {
+                            return false;
+                        }
                         let mut tmp_hash = [0_u8; 32];
                         tmp_hash.copy_from_slice(&input_mac[..32]);
                         let mut tmp_key = [0_u8; 32];
@@ -214,6 +430,22 @@ impl HMAC {
                     }
                 }
             }
+            HMAC::Blake3XOF => {
+                cfg_if! {
+                    if #[cfg(feature = "blake3")] {
+                        let output = self
+                            .internal_mac(data, key)
+                            .expect("Built in HMAC algorithms are always registered");
+                        constant_time_eq(&output, input_mac)
+                    } else {
+                        unimplemented!("Asuran was not compiled with BLAKE3 support")
+                    }
+                }
+            }
+            HMAC::Custom(_) => match self.internal_mac(data, key) {
+                Ok(output) => constant_time_eq(&output, input_mac),
+                Err(_) => false,
+            },
             HMAC::SHA3 => {
                 cfg_if! {
                     if #[cfg(feature = "sha3")] {
@@ -230,3 +462,103 @@ impl HMAC {
         }
     }
 }
+
+/// A deliberately weak algorithm, only useful for testing custom HMAC support
+#[cfg(test)]
+pub(crate) struct XorHmac(pub u8);
+
+#[cfg(test)]
+impl Hmac for XorHmac {
+    fn id(&self) -> u8 {
+        self.0
+    }
+    fn mac(&self, key: &[u8], data: &[u8]) -> Vec<u8> {
+        let mut output = vec![0_u8; 32];
+        for (i, byte) in key.iter().chain(data.iter()).enumerate() {
+            output[i % 32] = output[i % 32].rotate_left(3) ^ byte;
+        }
+        output
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    #[cfg(feature = "blake3")]
+    fn blake3_matches_reference() {
+        let key = Key::random(32);
+        let data = b"I am but a humble test string";
+        let reference = blake3::keyed_hash(&blake3_key(key.id_key()), data);
+        assert_eq!(HMAC::Blake3.id(data, &key), reference.as_bytes().to_vec());
+        let reference = blake3::keyed_hash(&blake3_key(key.hmac_key()), data);
+        assert_eq!(HMAC::Blake3.mac(data, &key), reference.as_bytes().to_vec());
+    }
+
+    #[test]
+    #[cfg(feature = "blake3")]
+    fn blake3_xof_extends_reference() {
+        let key = Key::random(32);
+        let data = b"I am but a humble test string";
+        let id = HMAC::Blake3XOF.id(data, &key);
+        assert_eq!(id.len(), XOF_LENGTH);
+        // The first 32 bytes of Blake3's extended output are its regular output
+        let reference = blake3::keyed_hash(&blake3_key(key.id_key()), data);
+        assert_eq!(&id[..32], reference.as_bytes());
+    }
+
+    #[test]
+    fn registry_rejects_bad_ids() {
+        assert_eq!(
+            register_hmac(Arc::new(XorHmac(3))),
+            Err(HmacRegistryError::ReservedId(3))
+        );
+        assert_eq!(register_hmac(Arc::new(XorHmac(201))), Ok(()));
+        assert_eq!(
+            register_hmac(Arc::new(XorHmac(201))),
+            Err(HmacRegistryError::DuplicateId(201))
+        );
+    }
+
+    #[test]
+    fn builtins_registered() {
+        for id in 0..6 {
+            let hmac = HMAC::from_algorithm_id(id).unwrap();
+            assert_eq!(hmac.algorithm_id(), id);
+            if let Some(registered) = lookup_hmac(id) {
+                assert_eq!(registered.id(), id);
+            }
+        }
+        assert_eq!(lookup_hmac(3).is_some(), cfg!(feature = "blake3"));
+        assert_eq!(HMAC::from_algorithm_id(202), None);
+    }
+
+    #[test]
+    fn unregistered_custom_hmac() {
+        let key = Key::random(32);
+        let data = b"I am but a humble test string";
+        let hmac = HMAC::Custom(203);
+        assert_eq!(
+            hmac.try_mac(data, &key),
+            Err(HmacRegistryError::Unregistered(203))
+        );
+        assert_eq!(
+            hmac.try_id(data, &key),
+            Err(HmacRegistryError::Unregistered(203))
+        );
+        assert!(!hmac.verify_hmac(&[0_u8; 32], data, &key));
+    }
+
+    #[test]
+    #[cfg(feature = "blake3")]
+    fn blake3_xof_verify() {
+        let key = Key::random(32);
+        let data = b"I am but a humble test string";
+        let mut mac = HMAC::Blake3XOF.mac(data, &key);
+        assert!(HMAC::Blake3XOF.verify_hmac(&mac, data, &key));
+        mac[40] ^= 1;
+        assert!(!HMAC::Blake3XOF.verify_hmac(&mac, data, &key));
+        assert!(!HMAC::Blake3XOF.verify_hmac(&mac[..32], data, &key));
+    }
+}
@@ -5,9 +5,13 @@ key material
 use crate::repository::Encryption;
 
 use argon2::{self, Config, ThreadMode, Variant, Version};
+#[cfg(all(feature = "hkdf", feature = "sha2"))]
+use hkdf::Hkdf;
 use rand::prelude::*;
 use serde::{Deserialize, Serialize};
 use serde_cbor::{de::from_slice, Serializer};
+#[cfg(all(feature = "hkdf", feature = "sha2"))]
+use sha2::Sha256;
 use thiserror::Error;
 use tracing::{error, trace};
 use zeroize::Zeroize;
@@ -23,10 +27,35 @@ pub enum KeyError {
     Argon2Error(#[from] argon2::Error),
     #[error("Something went wrong with Serialization/Deserailization")]
     DecodeError(#[from] serde_cbor::error::Error),
+    #[error("This key requires a second factor, but none was provided")]
+    MissingSecondFactor,
+    #[error("A second factor was provided, but this key does not use one")]
+    UnexpectedSecondFactor,
+}
+
+impl KeyError {
+    /// Returns a short, stable, `snake_case` name for the kind of this error
+    ///
+    /// A key that fails to decrypt or decode is almost always the result of an incorrect
+    /// password, so both are reported as `decryption_failed`.
+    pub fn kind(&self) -> &'static str {
+        match self {
+            KeyError::EncryptionError(_) | KeyError::DecodeError(_) => "decryption_failed",
+            KeyError::Argon2Error(_) => "key_derivation_failed",
+            KeyError::MissingSecondFactor => "second_factor_required",
+            KeyError::UnexpectedSecondFactor => "second_factor_unexpected",
+        }
+    }
 }
 
 type Result<T> = std::result::Result<T, KeyError>;
 
+/// Version of the subkey derivation scheme used by `Key::derive_subkey`
+///
+/// This is mixed into the derivation as part of the salt, so that any future change to the scheme
+/// produces different keys, rather than silently reinterpreting existing ones.
+pub const SUBKEY_DERIVATION_VERSION: u8 = 1;
+
 /// Stores the Key material used by an asuran repository.
 ///
 /// Contains 5 separate pieces of key material:
@@ -120,12 +149,76 @@ impl Key {
     pub fn chunker_nonce(&self) -> u64 {
         self.chunker_nonce
     }
+
+    /// Derives a new, independent, bundle of key material for the given purpose
+    ///
+    /// Uses HKDF-SHA256, with all of this key's material as the input keying material, and the
+    /// context string as the info parameter. Each component of the subkey has the same length as
+    /// the corresponding component of this key, and the chunker nonce is derived as well.
+    ///
+    /// The same key and context will always produce the same subkey, while different contexts
+    /// produce cryptographically separated subkeys. Existing keys are never modified, a repository
+    /// only uses subkeys where it explicitly asks for them.
+    #[cfg(all(feature = "hkdf", feature = "sha2"))]
+    pub fn derive_subkey(&self, context: &str) -> Key {
+        let mut input = Vec::new();
+        for component in &[&self.key, &self.hmac_key, &self.id_key] {
+            input.extend_from_slice(&(component.len() as u64).to_be_bytes());
+            input.extend_from_slice(component);
+        }
+        input.extend_from_slice(&self.chunker_nonce.to_be_bytes());
+        let salt = [b"asuran subkey".as_ref(), &[SUBKEY_DERIVATION_VERSION]].concat();
+        let hkdf = Hkdf::<Sha256>::new(Some(&salt), &input);
+        input.zeroize();
+
+        let lengths = [self.key.len(), self.hmac_key.len(), self.id_key.len()];
+        let mut output = vec![0_u8; lengths.iter().sum::<usize>() + 8];
+        // HKDF-SHA256 can produce up to 8160 bytes, far more than any sensible key
+        hkdf.expand(context.as_bytes(), &mut output)
+            .expect("Key too long for HKDF-SHA256");
+        let (key, rest) = output.split_at(lengths[0]);
+        let (hmac_key, rest) = rest.split_at(lengths[1]);
+        let (id_key, nonce) = rest.split_at(lengths[2]);
+        let mut nonce_bytes = [0_u8; 8];
+        nonce_bytes.copy_from_slice(nonce);
+        let subkey = Key {
+            key: key.to_vec(),
+            hmac_key: hmac_key.to_vec(),
+            id_key: id_key.to_vec(),
+            chunker_nonce: u64::from_be_bytes(nonce_bytes),
+        };
+        output.zeroize();
+        subkey
+    }
+}
+
+/// A secret, in addition to the password, needed to decrypt an `EncryptedKey`
+///
+/// This can be any byte string, such as a value derived from a hardware token. It is used as the
+/// argon2 secret when deriving the key encryption key, and is never stored in the repository.
+#[derive(Clone, PartialEq, Eq, Zeroize)]
+#[zeroize(drop)]
+pub struct SecondFactor(Vec<u8>);
+
+impl SecondFactor {
+    /// Creates a second factor from the given bytes
+    pub fn new(bytes: impl Into<Vec<u8>>) -> SecondFactor {
+        SecondFactor(bytes.into())
+    }
+}
+
+impl std::fmt::Debug for SecondFactor {
+    /// Does not print the secret itself, so it can not end up in logs
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str("SecondFactor(..)")
+    }
 }
 
 /// Stores the key, encrypted with another key derived from the user specified
 /// password/passphrase
 ///
-/// Uses argon2 to derive the key encryption key from the user supplied key.
+/// Uses argon2 to derive the key encryption key from the user supplied key, and the
+/// `SecondFactor`, if the key was encrypted with one.
 ///
 /// Uses a 32 byte salt that is randomly generated
 ///
@@ -137,17 +230,46 @@ pub struct EncryptedKey {
     mem_cost: u32,
     time_cost: u32,
     encryption: Encryption,
+    /// Whether or not a `SecondFactor` is needed to decrypt the key
+    ///
+    /// Left out of the serialized form when false, so keys without one are stored exactly as they
+    /// were before second factors were supported.
+    #[serde(default, skip_serializing_if = "is_false")]
+    second_factor: bool,
+}
+
+#[allow(clippy::trivially_copy_pass_by_ref)]
+fn is_false(value: &bool) -> bool {
+    !*value
 }
 
 impl EncryptedKey {
     /// Produces an encrypted key from the specified user key and encryption method
     #[tracing::instrument(level = "trace")]
     pub fn encrypt(
+        key: &Key,
+        mem_cost: u32,
+        time_cost: u32,
+        encryption: Encryption,
+        user_key: &[u8],
+    ) -> EncryptedKey {
+        EncryptedKey::encrypt_with_second_factor(
+            key, mem_cost, time_cost, encryption, user_key, None,
+        )
+    }
+
+    /// Produces an encrypted key from the specified user key and encryption method, that can
+    /// only be decrypted if `second_factor` is provided as well
+    ///
+    /// Passing `None` is the same as calling `encrypt`.
+    #[tracing::instrument(level = "trace")]
+    pub fn encrypt_with_second_factor(
         key: &Key,
         mem_cost: u32,
         time_cost: u32,
         mut encryption: Encryption,
         user_key: &[u8],
+        second_factor: Option<&SecondFactor>,
     ) -> EncryptedKey {
         // Serialize the key
         let mut key_buffer = Vec::<u8>::new();
@@ -166,7 +288,7 @@ impl EncryptedKey {
             time_cost,
             thread_mode: ThreadMode::Sequential,
             lanes: 1,
-            secret: &[],
+            secret: second_factor.map_or(&[][..], |x| &x.0[..]),
             ad: &[],
             hash_length: encryption
                 .key_length()
@@ -184,9 +306,20 @@ impl EncryptedKey {
             mem_cost,
             time_cost,
             encryption,
+            second_factor: second_factor.is_some(),
         }
     }
 
+    /// Returns true if a `SecondFactor` is needed to decrypt this key
+    pub fn requires_second_factor(&self) -> bool {
+        self.second_factor
+    }
+
+    /// Returns the encryption algorithm the key material is encrypted with
+    pub fn encryption(&self) -> Encryption {
+        self.encryption
+    }
+
     /// Convince function that uses argon2 parameters that the author of this program
     /// believes are reasonable as of time of writing. Please review them and apply your
     /// own common sense before blaming the author for the FBI reading your data.
@@ -205,9 +338,34 @@ impl EncryptedKey {
     ///
     /// # Errors:
     ///
-    /// Will return `Err(KeyError)` if key decryption fails
+    /// Will return `Err(KeyError)` if key decryption fails, including
+    /// `Err(MissingSecondFactor)` if the key requires a second factor
     #[tracing::instrument(level = "error")]
     pub fn decrypt(&self, user_key: &[u8]) -> Result<Key> {
+        self.decrypt_with_second_factor(user_key, None)
+    }
+
+    /// Attempts to decrypt the key material using the user supplied key, and the second factor,
+    /// if the key requires one
+    ///
+    /// # Errors:
+    ///
+    /// - `Err(MissingSecondFactor)` if the key requires a second factor, and none was provided
+    /// - `Err(UnexpectedSecondFactor)` if a second factor was provided, but the key does not use
+    ///   one
+    /// - `Err(KeyError)` if key decryption otherwise fails, such as with the wrong password or
+    ///   second factor
+    #[tracing::instrument(level = "error")]
+    pub fn decrypt_with_second_factor(
+        &self,
+        user_key: &[u8],
+        second_factor: Option<&SecondFactor>,
+    ) -> Result<Key> {
+        match (self.second_factor, second_factor) {
+            (true, None) => return Err(KeyError::MissingSecondFactor),
+            (false, Some(_)) => return Err(KeyError::UnexpectedSecondFactor),
+            _ => (),
+        }
         // Derive the key from the user key
         let config = Config {
             variant: Variant::Argon2id,
@@ -216,7 +374,7 @@ impl EncryptedKey {
             time_cost: self.time_cost,
             thread_mode: ThreadMode::Sequential,
             lanes: 1,
-            secret: &[],
+            secret: second_factor.map_or(&[][..], |x| &x.0[..]),
             ad: &[],
             hash_length: self
                 .encryption
@@ -234,6 +392,27 @@ impl EncryptedKey {
 
         Ok(key)
     }
+
+    /// Decrypts the key material with the user supplied key, and encrypts the same material again
+    /// under a new encryption algorithm
+    ///
+    /// The argon2 parameters of this key are kept, and a fresh salt is generated. Keys requiring a
+    /// second factor can not be rewrapped this way.
+    ///
+    /// # Errors:
+    ///
+    /// Will return `Err(KeyError)` if decrypting this key fails
+    #[tracing::instrument(level = "error")]
+    pub fn rewrap(&self, user_key: &[u8], encryption: Encryption) -> Result<EncryptedKey> {
+        let key = self.decrypt(user_key)?;
+        Ok(EncryptedKey::encrypt(
+            &key,
+            self.mem_cost,
+            self.time_cost,
+            encryption,
+            user_key,
+        ))
+    }
 }
 
 #[cfg(test)]
@@ -251,6 +430,27 @@ mod tests {
         assert_eq!(input_key, output_key);
     }
 
+    #[test]
+    fn rewrap() {
+        let input_key = Key::random(32);
+        let user_key = "A secure password".as_bytes();
+        let enc_key =
+            EncryptedKey::encrypt(&input_key, 1024, 2, Encryption::new_aes256ctr(), user_key);
+        let rewrapped = enc_key
+            .rewrap(user_key, Encryption::new_chacha20())
+            .unwrap();
+        assert!(matches!(
+            rewrapped.encryption(),
+            Encryption::ChaCha20 { .. }
+        ));
+        assert_eq!(rewrapped.mem_cost, 1024);
+        assert_eq!(rewrapped.time_cost, 2);
+        assert_eq!(rewrapped.decrypt(user_key).unwrap(), input_key);
+        assert!(enc_key
+            .rewrap(b"wrong password", Encryption::new_chacha20())
+            .is_err());
+    }
+
     #[test]
     fn from_bytes() {
         let input = [1, 2, 3, 1, 2, 3, 1, 2, 3];
@@ -260,4 +460,100 @@ mod tests {
         assert_eq!(key.id_key, [3, 3, 3]);
         assert_eq!(key.chunker_nonce(), 4);
     }
+
+    #[cfg(all(feature = "hkdf", feature = "sha2"))]
+    #[test]
+    fn derive_subkey() {
+        let key = Key::random(32);
+        let encryption = key.derive_subkey("encryption");
+        let hmac = key.derive_subkey("hmac");
+        assert_ne!(encryption, hmac);
+        assert_ne!(encryption, key);
+        assert_eq!(encryption, key.derive_subkey("encryption"));
+        assert_eq!(encryption.key().len(), 32);
+        assert_eq!(encryption.id_key().len(), 32);
+        // A different master produces different subkeys for the same context
+        assert_ne!(encryption, Key::random(32).derive_subkey("encryption"));
+    }
+
+    /// Pins the output of version 1 of the derivation, so changes to the scheme can not go
+    /// unnoticed
+    #[cfg(all(feature = "hkdf", feature = "sha2"))]
+    #[test]
+    fn derive_subkey_known_answer() {
+        let key = Key::from_bytes(&[0, 1, 2, 3, 4, 5, 6, 7, 8], 4);
+        let subkey = key.derive_subkey("encryption");
+        assert_eq!(subkey.key(), &[2, 245, 214]);
+        assert_eq!(subkey.hmac_key(), &[218, 0, 218]);
+        assert_eq!(subkey.id_key(), &[103, 230, 240]);
+        assert_eq!(subkey.chunker_nonce(), 13_128_675_743_375_775_526);
+    }
+
+    #[test]
+    fn second_factor() {
+        let input_key = Key::random(8);
+        let user_key = "A secure password".as_bytes();
+        let factor = SecondFactor::new(b"token value".to_vec());
+        let encryption = Encryption::new_aes256ctr();
+        let enc_key = EncryptedKey::encrypt_with_second_factor(
+            &input_key,
+            1024,
+            2,
+            encryption,
+            user_key,
+            Some(&factor),
+        );
+        assert!(enc_key.requires_second_factor());
+        assert!(matches!(
+            enc_key.decrypt(user_key),
+            Err(KeyError::MissingSecondFactor)
+        ));
+        let wrong_factor = SecondFactor::new(b"other value".to_vec());
+        assert!(enc_key
+            .decrypt_with_second_factor(user_key, Some(&wrong_factor))
+            .is_err());
+        assert!(enc_key
+            .decrypt_with_second_factor(b"wrong password", Some(&factor))
+            .is_err());
+        let output_key = enc_key
+            .decrypt_with_second_factor(user_key, Some(&factor))
+            .unwrap();
+        assert_eq!(input_key, output_key);
+    }
+
+    // Keys without a second factor must keep the serialized form they had before second factors
+    // existed, and must not accept one
+    #[test]
+    fn no_second_factor_is_unchanged() {
+        #[derive(Serialize)]
+        struct OldEncryptedKey {
+            encrypted_bytes: Vec<u8>,
+            salt: [u8; 32],
+            mem_cost: u32,
+            time_cost: u32,
+            encryption: Encryption,
+        }
+        let input_key = Key::random(8);
+        let user_key = "A secure password".as_bytes();
+        let enc_key =
+            EncryptedKey::encrypt(&input_key, 1024, 2, Encryption::new_aes256ctr(), user_key);
+        assert!(!enc_key.requires_second_factor());
+        let old = OldEncryptedKey {
+            encrypted_bytes: enc_key.encrypted_bytes.clone(),
+            salt: enc_key.salt,
+            mem_cost: enc_key.mem_cost,
+            time_cost: enc_key.time_cost,
+            encryption: enc_key.encryption,
+        };
+        assert_eq!(
+            serde_cbor::to_vec(&old).unwrap(),
+            serde_cbor::to_vec(&enc_key).unwrap()
+        );
+        let factor = SecondFactor::new(b"token value".to_vec());
+        assert!(matches!(
+            enc_key.decrypt_with_second_factor(user_key, Some(&factor)),
+            Err(KeyError::UnexpectedSecondFactor)
+        ));
+        assert_eq!(enc_key.decrypt(user_key).unwrap(), input_key);
+    }
 }
@@ -78,7 +78,7 @@ fn get_repo(key: Key) -> Repository<impl BackendClone> {
         hmac: HMAC::Blake3,
     };
     let backend = Mem::new(settings, key.clone(), num_cpus::get() * 2);
-    Repository::with(backend, settings, key, num_cpus::get())
+    Repository::with(backend, settings, key, num_cpus::get()).unwrap()
 }
 
 fn get_repo_bare(key: Key) -> Repository<impl BackendClone> {
@@ -88,7 +88,7 @@ fn get_repo_bare(key: Key) -> Repository<impl BackendClone> {
         hmac: HMAC::Blake3,
     };
     let backend = Mem::new(settings, key.clone(), num_cpus::get() * 2);
-    Repository::with(backend, settings, key, num_cpus::get())
+    Repository::with(backend, settings, key, num_cpus::get()).unwrap()
 }
 
 fn get_repo_aes_lz4(key: Key) -> Repository<impl BackendClone> {
@@ -98,7 +98,7 @@ fn get_repo_aes_lz4(key: Key) -> Repository<impl BackendClone> {
         hmac: HMAC::Blake3,
     };
     let backend = Mem::new(settings, key.clone(), num_cpus::get() * 2);
-    Repository::with(backend, settings, key, num_cpus::get())
+    Repository::with(backend, settings, key, num_cpus::get()).unwrap()
 }
 
 fn get_repo_chacha_lz4(key: Key) -> Repository<impl BackendClone> {
@@ -108,7 +108,7 @@ fn get_repo_chacha_lz4(key: Key) -> Repository<impl BackendClone> {
         hmac: HMAC::Blake3,
     };
     let backend = Mem::new(settings, key.clone(), num_cpus::get() * 2);
-    Repository::with(backend, settings, key, num_cpus::get())
+    Repository::with(backend, settings, key, num_cpus::get()).unwrap()
 }
 
 fn bench(c: &mut Criterion) {
@@ -39,7 +39,7 @@ fn get_repo(key: Key) -> Repository<impl BackendClone> {
         hmac: HMAC::Blake2bp,
     };
     let backend = Mem::new(settings, key.clone(), num_cpus::get() * 2);
-    Repository::with(backend, settings, key, num_cpus::get())
+    Repository::with(backend, settings, key, num_cpus::get()).unwrap()
 }
 
 fn bench(c: &mut Criterion) {
@@ -0,0 +1,17 @@
+//! Feeds arbitrary bytes to the `FlatFile` parser, which must return an error for malformed
+//! files, rather than panicking or looping forever
+//!
+//! Run with `cargo fuzz run flatfile_new_raw` from the `asuran` directory.
+#![no_main]
+use asuran::repository::backend::common::generic_flatfile::GenericFlatFile;
+use asuran::repository::Key;
+use libfuzzer_sys::fuzz_target;
+
+use std::io::Cursor;
+
+fuzz_target!(|data: &[u8]| {
+    // The key does not matter, footers are authenticated before they are decoded, so the offset
+    // walking is what gets exercised
+    let key = Key::from_bytes(&[0_u8; 96], 0);
+    let _ = GenericFlatFile::new_raw(Cursor::new(data.to_vec()), "fuzz", None, key, None);
+});
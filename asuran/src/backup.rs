@@ -0,0 +1,1272 @@
+//! Storing of directory trees into archives
+//!
+//! This provides the directory walking and object storing loop that most consumers of a
+//! `FileSystemTarget` would otherwise have to write themselves.
+use crate::chunker::AsyncChunker;
+use crate::manifest::archive::NodeType;
+use crate::manifest::driver::{BackupDriver, DriverError};
+use crate::manifest::target::filesystem::FileSystemTarget;
+use crate::manifest::target::BackupObject;
+use crate::manifest::target::BackupTarget;
+use crate::manifest::{ActiveArchive, FilterError, GlobFilter, IgnoreRules};
+use crate::repository::{BackendClone, Repository, WriteStats};
+
+use asuran_core::manifest::listing::{Listing, Node};
+use futures::future::select_all;
+use lazy_static::lazy_static;
+use smol::{block_on, blocking, Task};
+use thiserror::Error;
+use tracing::{debug, warn};
+use walkdir::WalkDir;
+
+#[cfg(feature = "file-dedup")]
+use std::collections::hash_map::Entry;
+use std::collections::HashMap;
+#[cfg(feature = "file-dedup")]
+use std::collections::HashSet;
+use std::fs::File;
+use std::io::Read;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+
+/// An error for things that can go wrong storing a directory
+#[derive(Error, Debug)]
+pub enum BackupError {
+    #[error("Driver Error: {0}")]
+    Driver(#[from] DriverError),
+    #[error("Ignore File Error: {0}")]
+    Filter(#[from] FilterError),
+    #[error("Path is not valid UTF-8: {0:?}")]
+    InvalidPath(PathBuf),
+    #[error("I/O Error: {0}")]
+    IO(#[from] std::io::Error),
+}
+
+impl BackupError {
+    /// Returns a short, stable, `snake_case` name for the kind of this error
+    pub fn kind(&self) -> &'static str {
+        match self {
+            BackupError::Driver(e) => e.kind(),
+            BackupError::Filter(_) => "filter",
+            BackupError::InvalidPath(_) => "invalid_path",
+            BackupError::IO(_) => "io",
+        }
+    }
+}
+
+type Result<T> = std::result::Result<T, BackupError>;
+
+/// Summary of the work done by `store_directory`
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct StoreSummary {
+    /// Number of files recorded in the archive, including inline files, and files reusing the
+    /// contents of an identical file
+    pub files_stored: u64,
+    /// Counts of the chunks written for the files' contents
+    ///
+    /// Only files stored as objects contribute to these, inline files and files reusing an
+    /// identical file's contents do not write any chunks of their own.
+    pub chunks: WriteStats,
+    /// Number of bytes read from files that were only hashed, while looking for identical files
+    ///
+    /// Together with `chunks.bytes_read`, this is the total amount of file data read.
+    pub bytes_hashed: u64,
+}
+
+/// Default maximum number of files to have in flight at once, see `store_directory`
+///
+/// Higher numbers do better with lots of small files, and smaller numbers do better with a small
+/// number of large files.
+pub const DEFAULT_CONCURRENCY: usize = 30;
+
+/// Settings controlling which files `store_directory` stores, and how it stores them
+///
+/// See `store_directory` for the details of each.
+#[derive(Clone, Debug)]
+pub struct StoreOptions {
+    /// Filter that the path of each file, relative to the root, must pass to be stored
+    pub globs: GlobFilter,
+    /// Skip the contents of directories that `is_cache_dir` considers a cache
+    pub exclude_caches: bool,
+    /// Name of the per directory ignore files to honor, if any
+    pub ignore_file: Option<String>,
+    /// Sniff the MIME type of each file, and record it in its `Node`
+    pub detect_content_type: bool,
+    /// Files shorter than this many bytes are stored directly in the listing
+    pub inline_threshold: u64,
+    /// Maximum number of files to have in flight at once
+    pub concurrency: usize,
+}
+
+impl Default for StoreOptions {
+    /// Stores every file as an object, with no filtering or content type detection, and
+    /// `DEFAULT_CONCURRENCY` files in flight
+    fn default() -> Self {
+        StoreOptions {
+            globs: GlobFilter::default(),
+            exclude_caches: false,
+            ignore_file: None,
+            detect_content_type: false,
+            inline_threshold: 0,
+            concurrency: DEFAULT_CONCURRENCY,
+        }
+    }
+}
+
+/// Maximum number of nodes the walk may find ahead of `store_directory` storing them
+const WALK_QUEUE_DEPTH: usize = 256;
+
+/// Number of bytes from the start of a file that are examined when detecting its content type
+const SNIFF_LENGTH: u64 = 8192;
+
+#[cfg_attr(tarpaulin, skip)]
+lazy_static! {
+    /// The matchers used to detect content types, shared, as building them is not free
+    static ref INFER: infer::Infer = infer::Infer::new();
+}
+
+/// Attempts to detect a MIME type from the magic bytes at the start of a file
+///
+/// Returns `None` if the type is not recognized.
+fn content_type_of(head: &[u8]) -> Option<String> {
+    INFER.get(head).map(|x| x.mime)
+}
+
+/// Attempts to detect the MIME type of the file at `path`, reading its start on the blocking
+/// thread pool
+///
+/// Returns `None` if the file can not be read, or its type is not recognized.
+async fn sniff_content_type(path: PathBuf) -> Option<String> {
+    let head = blocking!({
+        let mut buffer = Vec::new();
+        File::open(&path)?
+            .take(SNIFF_LENGTH)
+            .read_to_end(&mut buffer)?;
+        Ok::<_, std::io::Error>(buffer)
+    })
+    .ok()?;
+    content_type_of(&head)
+}
+
+/// Name of the file marking a directory as a cache, as defined by the Cache Directory Tagging
+/// Specification
+pub const CACHEDIR_TAG: &str = "CACHEDIR.TAG";
+
+/// The bytes a `CACHEDIR.TAG` file must start with to be honored
+pub const CACHEDIR_TAG_SIGNATURE: &[u8] = b"Signature: 8a477f597d28d172789f06886806bc55";
+
+/// Name of a marker file requesting that the directory containing it not be backed up
+pub const NOBACKUP_MARKER: &str = ".nobackup";
+
+/// Returns true if the directory at `path` has asked to be left out of backups
+///
+/// This is the case if it contains a `CACHEDIR.TAG` file starting with the proper signature, or a
+/// `.nobackup` file with any contents. A `CACHEDIR.TAG` without the signature is ignored, as
+/// required by the specification.
+pub fn is_cache_dir(path: &Path) -> bool {
+    if path.join(NOBACKUP_MARKER).is_file() {
+        return true;
+    }
+    let mut signature = Vec::new();
+    File::open(path.join(CACHEDIR_TAG))
+        .and_then(|file| {
+            file.take(CACHEDIR_TAG_SIGNATURE.len() as u64)
+                .read_to_end(&mut signature)
+        })
+        .is_ok()
+        && signature == CACHEDIR_TAG_SIGNATURE
+}
+
+/// Hashes the whole contents of the file at `path` on the blocking thread pool, for detecting
+/// identical files
+///
+/// Returns the hash, along with the number of bytes read.
+#[cfg(feature = "file-dedup")]
+async fn hash_file(path: PathBuf) -> std::io::Result<(blake3::Hash, u64)> {
+    blocking!({
+        let mut hasher = blake3::Hasher::new();
+        let length = std::io::copy(&mut File::open(&path)?, &mut hasher)?;
+        Ok((hasher.finalize(), length))
+    })
+}
+
+/// Wraps a reader, hashing everything read through it, and keeping a copy of the first
+/// `SNIFF_LENGTH` bytes read for content type detection
+///
+/// The hasher and the copy are shared, so that they can still be recovered after the reader has
+/// been handed off to a chunker.
+struct TapReader<R> {
+    inner: R,
+    #[cfg(feature = "file-dedup")]
+    hasher: Option<Arc<Mutex<blake3::Hasher>>>,
+    head: Option<Arc<Mutex<Vec<u8>>>>,
+}
+
+impl<R: Read> Read for TapReader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        let count = self.inner.read(buf)?;
+        #[cfg(feature = "file-dedup")]
+        {
+            if let Some(hasher) = &self.hasher {
+                hasher
+                    .lock()
+                    .expect("Hasher lock poisoned")
+                    .update(&buf[..count]);
+            }
+        }
+        if let Some(head) = &self.head {
+            let mut head = head.lock().expect("Head lock poisoned");
+            let wanted = (SNIFF_LENGTH as usize)
+                .saturating_sub(head.len())
+                .min(count);
+            head.extend_from_slice(&buf[..wanted]);
+        }
+        Ok(count)
+    }
+}
+
+/// What a `TapReader` collected while a file was stored
+struct Tapped {
+    #[cfg(feature = "file-dedup")]
+    hash: Option<blake3::Hash>,
+    head: Option<Vec<u8>>,
+}
+
+/// Walks the directory at `root`, sending a node for each directory and file below it, with paths
+/// relative to `root`, to `output`
+///
+/// Nodes are sent as they are found, with every directory sent before any of its contents. The
+/// walk stops early, without error, if the receiving end of `output` is closed.
+///
+/// Symbolic links and other special files are skipped, as are entries that can not be read. If
+/// `ignore_file` is set, the ignore file of each directory is read as the walk enters it. Anything
+/// matching the rules of a directory above it is skipped, and the walk does not descend into
+/// ignored directories at all.
+///
+/// If `exclude_caches` is set, the walk does not descend into directories that `is_cache_dir`
+/// considers a cache. Their nodes are still returned.
+///
+/// This does blocking I/O, and should be run on the blocking thread pool.
+///
+/// # Errors
+///
+/// Will return `Err` if a path is not valid UTF-8, or if an ignore file can not be read or parsed
+fn walk(
+    root: &Path,
+    ignore_file: Option<&str>,
+    exclude_caches: bool,
+    output: &async_channel::Sender<Result<Node>>,
+) -> Result<()> {
+    // The rules from each directory's ignore file, keyed by the directory they apply below
+    let mut ignore_rules: HashMap<PathBuf, IgnoreRules> = HashMap::new();
+    let mut entries = WalkDir::new(root).into_iter();
+    while let Some(entry) = entries.next() {
+        let entry = match entry {
+            Ok(entry) => entry,
+            Err(e) => {
+                warn!(error = %e, "Skipping unreadable entry");
+                continue;
+            }
+        };
+        let relative = entry
+            .path()
+            .strip_prefix(root)
+            .unwrap_or_else(|_| entry.path());
+        let is_dir = entry.file_type().is_dir();
+        if entry.depth() > 0 {
+            // Ignored directories are never entered, so only the rules of the directories above
+            // this entry need to be checked
+            let ignored = relative.ancestors().skip(1).any(|directory| {
+                ignore_rules.get(directory).map_or(false, |rules| {
+                    rules.is_match(relative.strip_prefix(directory).unwrap_or(relative))
+                })
+            });
+            let path = relative
+                .to_str()
+                .ok_or_else(|| BackupError::InvalidPath(entry.path().to_path_buf()))?;
+            if ignored {
+                debug!(path = %path, "Excluding file by ignore file");
+                if is_dir {
+                    entries.skip_current_dir();
+                }
+                continue;
+            }
+            let (length, node_type) = if is_dir {
+                (
+                    0,
+                    NodeType::Directory {
+                        children: Vec::new(),
+                    },
+                )
+            } else if entry.file_type().is_file() {
+                (
+                    entry.metadata().map_err(std::io::Error::from)?.len(),
+                    NodeType::File,
+                )
+            } else {
+                debug!(path = %path, "Skipping link");
+                continue;
+            };
+            let node = Node {
+                path: path.to_string(),
+                total_length: length,
+                total_size: length,
+                extents: None,
+                node_type,
+                content_type: None,
+            };
+            if block_on(output.send(Ok(node))).is_err() {
+                return Ok(());
+            }
+            if is_dir && exclude_caches && is_cache_dir(entry.path()) {
+                debug!(path = %path, "Excluding cache directory contents");
+                entries.skip_current_dir();
+                continue;
+            }
+        }
+        if let (true, Some(ignore_file)) = (is_dir, ignore_file) {
+            let file = entry.path().join(ignore_file);
+            if file.is_file() {
+                ignore_rules.insert(relative.to_path_buf(), IgnoreRules::from_file(file)?);
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Places a node into the listing, replacing any existing node at the same path
+fn insert_node(listing: &mut Listing, node: Node) {
+    match listing.get_mut(&node.path) {
+        Some(existing) => *existing = node,
+        None => {
+            let parent = Path::new(&node.path)
+                .parent()
+                .and_then(Path::to_str)
+                .unwrap_or("")
+                .to_string();
+            listing.add_child(&parent, node);
+        }
+    }
+}
+
+/// The result of processing a single file in `store_directory`
+enum Processed {
+    /// The file was stored as an object
+    Stored {
+        path: String,
+        is_file: bool,
+        content_type: Option<String>,
+        stats: WriteStats,
+        /// Hash of the file's contents, if it was hashed while being stored
+        #[cfg(feature = "file-dedup")]
+        hash: Option<blake3::Hash>,
+    },
+    /// The file was only hashed, as another file of the same length was already being stored
+    ///
+    /// It is stored after all other files are, if it does not turn out to be a duplicate.
+    #[cfg(feature = "file-dedup")]
+    Hashed {
+        node: Node,
+        hash: blake3::Hash,
+        bytes_read: u64,
+    },
+}
+
+/// A single file for `store_directory` to store as an object
+struct StoreJob<B, C> {
+    repo: Repository<B>,
+    chunker: C,
+    archive: ActiveArchive,
+    target: FileSystemTarget,
+    node: Node,
+    full_path: PathBuf,
+    detect_content_type: bool,
+    /// Hash the contents of the file as they are read by the chunker
+    #[cfg(feature = "file-dedup")]
+    hash: bool,
+}
+
+impl<B: BackendClone, C: AsyncChunker + Clone + Send + 'static> StoreJob<B, C> {
+    async fn run(mut self) -> Result<Processed> {
+        let path = self.node.path.clone();
+        let is_file = self.node.is_file();
+        let (stats, tapped) = if self.hashing() || self.detect_content_type {
+            self.store_tapped().await?
+        } else {
+            let stats = self
+                .target
+                .store_object(&mut self.repo, self.chunker, &self.archive, self.node)
+                .await?;
+            (stats, None)
+        };
+        let content_type = if self.detect_content_type {
+            match tapped.as_ref().and_then(|x| x.head.as_ref()) {
+                Some(head) => content_type_of(head),
+                None => sniff_content_type(self.full_path).await,
+            }
+        } else {
+            None
+        };
+        Ok(Processed::Stored {
+            path,
+            is_file,
+            content_type,
+            stats,
+            #[cfg(feature = "file-dedup")]
+            hash: tapped.and_then(|x| x.hash),
+        })
+    }
+
+    /// Returns true if the file should be hashed as it is read by the chunker
+    fn hashing(&self) -> bool {
+        #[cfg(feature = "file-dedup")]
+        {
+            self.hash
+        }
+        #[cfg(not(feature = "file-dedup"))]
+        {
+            false
+        }
+    }
+
+    /// Stores the file, hashing its contents and keeping a copy of their start, as needed, on
+    /// their way to the chunker
+    ///
+    /// Only a file stored as a single range, starting at its beginning, has what was read match
+    /// its contents. Any other file is stored as usual, and `None` is returned in place of what
+    /// was collected.
+    async fn store_tapped(&mut self) -> Result<(WriteStats, Option<Tapped>)> {
+        let mut objects = self.target.backup_object(self.node.clone()).await;
+        if objects.len() == 1 {
+            if let Some(object) = objects.remove("") {
+                let total_size = object.total_size();
+                let mut ranges = object.ranges();
+                if ranges.len() == 1 && ranges[0].start == 0 {
+                    #[cfg(feature = "file-dedup")]
+                    let hasher = if self.hashing() {
+                        Some(Arc::new(Mutex::new(blake3::Hasher::new())))
+                    } else {
+                        None
+                    };
+                    let head = if self.detect_content_type {
+                        Some(Arc::new(Mutex::new(Vec::new())))
+                    } else {
+                        None
+                    };
+                    let reader = TapReader {
+                        inner: ranges.remove(0).object,
+                        #[cfg(feature = "file-dedup")]
+                        hasher: hasher.clone(),
+                        head: head.clone(),
+                    };
+                    let stats = self
+                        .archive
+                        .namespace_append("")
+                        .put_object(&self.chunker, &mut self.repo, &self.node.path, reader)
+                        .await
+                        .map_err(DriverError::from)?;
+                    let tapped = Tapped {
+                        #[cfg(feature = "file-dedup")]
+                        hash: hasher.map(|x| x.lock().expect("Hasher lock poisoned").finalize()),
+                        head: head.map(|x| x.lock().expect("Head lock poisoned").clone()),
+                    };
+                    return Ok((stats, Some(tapped)));
+                }
+                let mut object = BackupObject::new(total_size);
+                for range in ranges {
+                    object.add_range(range);
+                }
+                objects.insert(String::new(), object);
+            }
+        }
+        let stats = self
+            .target
+            .raw_store_object(
+                &mut self.repo,
+                self.chunker.clone(),
+                &self.archive,
+                self.node.clone(),
+                objects,
+            )
+            .await?;
+        Ok((stats, None))
+    }
+}
+
+/// Results collected from the tasks spawned by `store_directory`
+#[derive(Default)]
+struct Collected {
+    summary: StoreSummary,
+    content_types: Vec<(String, String)>,
+    /// The path of the first stored file with each hash
+    #[cfg(feature = "file-dedup")]
+    first_with_hash: HashMap<blake3::Hash, String>,
+    /// Files that were only hashed, waiting to be compared against the stored files
+    #[cfg(feature = "file-dedup")]
+    candidates: Vec<(Node, blake3::Hash)>,
+}
+
+impl Collected {
+    fn record(&mut self, processed: Processed) {
+        match processed {
+            Processed::Stored {
+                path,
+                is_file,
+                content_type,
+                stats,
+                #[cfg(feature = "file-dedup")]
+                hash,
+            } => {
+                #[cfg(feature = "file-dedup")]
+                {
+                    if let Some(hash) = hash {
+                        self.first_with_hash
+                            .entry(hash)
+                            .or_insert_with(|| path.clone());
+                    }
+                }
+                self.content_types.extend(content_type.map(|x| (path, x)));
+                self.summary.files_stored += u64::from(is_file);
+                self.summary.chunks += stats;
+            }
+            #[cfg(feature = "file-dedup")]
+            Processed::Hashed {
+                node,
+                hash,
+                bytes_read,
+            } => {
+                self.summary.bytes_hashed += bytes_read;
+                self.candidates.push((node, hash));
+            }
+        }
+    }
+
+    /// Waits for tasks in the queue to complete, recording their results, until fewer than
+    /// `limit` remain
+    async fn wait_below(
+        &mut self,
+        queue: &mut Vec<Task<Result<Processed>>>,
+        limit: usize,
+    ) -> Result<()> {
+        while !queue.is_empty() && queue.len() >= limit {
+            let (result, _, remaining) = select_all(std::mem::take(queue)).await;
+            *queue = remaining;
+            self.record(result?);
+        }
+        Ok(())
+    }
+}
+
+/// Recursively stores the directory at `root` into `archive`, and sets the archive's listing to
+/// match
+///
+/// Paths in the archive are relative to `root`. Files are only stored if their relative path
+/// passes `options.globs`, directories are always recorded, so that the structure of the tree is
+/// kept even when all of a directory's contents are excluded. Symbolic links are not followed, and
+/// are skipped.
+///
+/// If `options.exclude_caches` is set, the walk does not descend into any directory that
+/// `is_cache_dir` considers a cache. The tagged directory itself is still recorded, but left empty.
+///
+/// If `options.ignore_file` is set, each directory may contain an ignore file with that name,
+/// holding `.gitignore` style patterns, see `IgnoreRules`. Anything below the directory matching
+/// its patterns is skipped, and matching directories are not descended into. The rules of each
+/// directory stack with those of its parents.
+///
+/// If `options.detect_content_type` is set, the MIME type of each file is sniffed from its first
+/// bytes and recorded in its `Node`. The first bytes are taken from what the chunker reads, so
+/// only files stored in multiple ranges, such as sparse files, have their start read again.
+///
+/// Files shorter than `options.inline_threshold` bytes are not stored as objects, and instead have
+/// their data stored directly in the listing, as a `NodeType::InlineFile`. This saves a chunk and
+/// an index entry for each of them. A threshold of zero stores every file as an object.
+///
+/// Files with identical contents are only read and chunked once. The first file of each length is
+/// hashed as it is chunked, any later file of the same length is only hashed, and compared against
+/// the stored files once all of those are done. A file whose hash matches a stored one reuses that
+/// file's chunk locations instead of being stored again, the rest are then read a second time to
+/// store them. This requires the `file-dedup` feature, without it every file is stored on its own.
+///
+/// The directory is walked on the blocking thread pool, and files are stored as the walk finds
+/// them. Up to `options.concurrency` files are stored, or hashed, at once, each running through
+/// its own chunking pipeline. Their objects and nodes are added to the archive behind its existing
+/// locks. A concurrency of zero or one stores files one at a time.
+///
+/// Returns a `StoreSummary` counting the files stored, and how many of their chunks were new to
+/// the repository, or deduplicated against chunks it already held.
+///
+/// # Errors
+///
+/// Will return `Err` if `root` is not valid UTF-8, if an ignore file can not be read or parsed, or
+/// if storing any object fails.
+pub async fn store_directory<B: BackendClone, C: AsyncChunker + Clone + Send + 'static>(
+    archive: &ActiveArchive,
+    chunker: C,
+    repo: &mut Repository<B>,
+    root: &Path,
+    options: &StoreOptions,
+) -> Result<StoreSummary> {
+    let mut collected = Collected::default();
+    let globs = &options.globs;
+    let detect_content_type = options.detect_content_type;
+    let inline_threshold = options.inline_threshold;
+    let concurrency = options.concurrency.max(1);
+    let root_str = root
+        .to_str()
+        .ok_or_else(|| BackupError::InvalidPath(root.to_path_buf()))?;
+    let target = FileSystemTarget::new(root_str);
+    let mut task_queue = Vec::new();
+    let mut inline_files = Vec::new();
+    let (sender, nodes) = async_channel::bounded(WALK_QUEUE_DEPTH);
+    {
+        let root = root.to_path_buf();
+        let ignore_file = options.ignore_file.clone();
+        let exclude_caches = options.exclude_caches;
+        Task::blocking(async move {
+            if let Err(e) = walk(&root, ignore_file.as_deref(), exclude_caches, &sender) {
+                let _ = sender.send(Err(e)).await;
+            }
+        })
+        .detach();
+    }
+    // Lengths that a file is already being stored with
+    #[cfg(feature = "file-dedup")]
+    let mut lengths_stored = HashSet::new();
+    while let Ok(node) = nodes.recv().await {
+        let node = node?;
+        if node.is_directory() {
+            // Directories carry no data, and must be in the listing before any of their children
+            // are added, so these are handled in line, rather than racing the other tasks
+            target
+                .store_object(repo, chunker.clone(), archive, node)
+                .await?;
+            continue;
+        }
+        if !globs.is_match(&node.path) {
+            debug!(path = %node.path, "Excluding file by glob");
+            continue;
+        }
+        let full_path = root.join(&node.path);
+        if node.is_file() && node.total_length < inline_threshold {
+            let data = blocking!(std::fs::read(full_path))?;
+            if detect_content_type {
+                collected
+                    .content_types
+                    .extend(content_type_of(&data).map(|x| (node.path.clone(), x)));
+            }
+            inline_files.push((node, data));
+            continue;
+        }
+        // Only a file sharing its length with an earlier file can be a duplicate of it
+        #[cfg(feature = "file-dedup")]
+        let hash = node.is_file() && node.total_length > 0;
+        #[cfg(feature = "file-dedup")]
+        {
+            if hash && !lengths_stored.insert(node.total_length) {
+                task_queue.push(Task::spawn(async move {
+                    let (hash, bytes_read) = hash_file(full_path).await?;
+                    Ok::<_, BackupError>(Processed::Hashed {
+                        node,
+                        hash,
+                        bytes_read,
+                    })
+                }));
+                collected.wait_below(&mut task_queue, concurrency).await?;
+                continue;
+            }
+        }
+        let job = StoreJob {
+            repo: repo.clone(),
+            chunker: chunker.clone(),
+            archive: archive.clone(),
+            target: target.clone(),
+            node,
+            full_path,
+            detect_content_type,
+            #[cfg(feature = "file-dedup")]
+            hash,
+        };
+        task_queue.push(Task::spawn(job.run()));
+        collected.wait_below(&mut task_queue, concurrency).await?;
+    }
+    collected.wait_below(&mut task_queue, 1).await?;
+    // Every file that could be the original of a duplicate has been stored by now. Candidates
+    // matching none of them are stored as well, the first with each hash becoming the original
+    // for the rest.
+    #[cfg(feature = "file-dedup")]
+    let mut duplicates = Vec::new();
+    #[cfg(feature = "file-dedup")]
+    for (node, hash) in std::mem::take(&mut collected.candidates) {
+        match collected.first_with_hash.entry(hash) {
+            Entry::Occupied(original) => {
+                debug!(path = %node.path, original = %original.get(), "Reusing identical file");
+                duplicates.push((node, original.get().clone()));
+            }
+            Entry::Vacant(entry) => {
+                entry.insert(node.path.clone());
+                let job = StoreJob {
+                    repo: repo.clone(),
+                    chunker: chunker.clone(),
+                    archive: archive.clone(),
+                    target: target.clone(),
+                    full_path: root.join(&node.path),
+                    node,
+                    detect_content_type,
+                    hash: false,
+                };
+                task_queue.push(Task::spawn(job.run()));
+                collected.wait_below(&mut task_queue, concurrency).await?;
+            }
+        }
+    }
+    collected.wait_below(&mut task_queue, 1).await?;
+    let mut listing = target.backup_listing().await;
+    let mut summary = collected.summary;
+    summary.files_stored += inline_files.len() as u64;
+    for (mut node, data) in inline_files {
+        node.total_length = data.len() as u64;
+        node.total_size = node.total_length;
+        node.extents = None;
+        node.node_type = NodeType::InlineFile { data };
+        insert_node(&mut listing, node);
+    }
+    // The originals have all been stored by now, so their locations can be copied
+    #[cfg(feature = "file-dedup")]
+    {
+        summary.files_stored += duplicates.len() as u64;
+        let original_types: HashMap<String, String> =
+            collected.content_types.iter().cloned().collect();
+        for (node, original) in duplicates {
+            archive.copy_object(&original, &node.path);
+            if let Some(content_type) = original_types.get(&original) {
+                collected
+                    .content_types
+                    .push((node.path.clone(), content_type.clone()));
+            }
+            insert_node(&mut listing, node);
+        }
+    }
+    for (path, content_type) in collected.content_types {
+        if let Some(node) = listing.get_mut(&path) {
+            node.content_type = Some(content_type);
+        }
+    }
+    archive.set_listing(listing).await;
+    Ok(summary)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::chunker::{Chunker, FastCDC};
+    use crate::repository::backend::mem::Mem;
+    use crate::repository::{ChunkSettings, Key};
+    use asuran_core::manifest::listing::NodeType;
+
+    use tempfile::tempdir;
+
+    use std::fs;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::sync::Arc;
+
+    #[test]
+    fn store_nested_directory() {
+        smol::run(async {
+            let root = tempdir().unwrap();
+            let root_path = root.path();
+            fs::create_dir_all(root_path.join("A/B")).unwrap();
+            fs::create_dir_all(root_path.join("C")).unwrap();
+            fs::write(root_path.join("1.txt"), b"one").unwrap();
+            fs::write(root_path.join("A/2.txt"), b"two").unwrap();
+            fs::write(root_path.join("A/B/3.txt"), b"three").unwrap();
+            fs::write(root_path.join("A/B/4.log"), b"four").unwrap();
+            #[cfg(unix)]
+            std::os::unix::fs::symlink(root_path.join("1.txt"), root_path.join("C/link")).unwrap();
+
+            let key = Key::random(32);
+            let settings = ChunkSettings::lightweight();
+            let backend = Mem::new(settings, key.clone(), 4);
+            let mut repo = Repository::with(backend, settings, key, 2).unwrap();
+            let archive = ActiveArchive::new("test");
+            let globs = GlobFilter::new(&[] as &[&str], &["*.log"]).unwrap();
+            store_directory(
+                &archive,
+                FastCDC::default(),
+                &mut repo,
+                root_path,
+                &StoreOptions {
+                    globs,
+                    ..StoreOptions::default()
+                },
+            )
+            .await
+            .unwrap();
+
+            let listing = archive.listing().await;
+            let mut paths = listing
+                .walk()
+                .map(|(path, node)| (path.to_string_lossy().to_string(), node.is_directory()))
+                .collect::<Vec<_>>();
+            paths.sort();
+            assert_eq!(
+                paths,
+                vec![
+                    ("1.txt".to_string(), false),
+                    ("A".to_string(), true),
+                    ("A/2.txt".to_string(), false),
+                    ("A/B".to_string(), true),
+                    ("A/B/3.txt".to_string(), false),
+                    ("C".to_string(), true),
+                ]
+            );
+            match &listing.get("A/B").unwrap().node_type {
+                NodeType::Directory { children } => assert_eq!(children, &vec!["A/B/3.txt"]),
+                _ => panic!("A/B was not a directory"),
+            }
+            assert_eq!(listing.get("A/B/3.txt").unwrap().total_length, 5);
+
+            let mut stored = Vec::new();
+            archive
+                .get_object(&mut repo, "A/B/3.txt", &mut stored)
+                .await
+                .unwrap();
+            assert_eq!(stored, b"three".to_vec());
+        });
+    }
+
+    #[test]
+    fn exclude_caches() {
+        smol::run(async {
+            let root = tempdir().unwrap();
+            let root_path = root.path();
+            fs::create_dir_all(root_path.join("cache/nested")).unwrap();
+            fs::create_dir_all(root_path.join("scratch")).unwrap();
+            fs::create_dir_all(root_path.join("untagged")).unwrap();
+            fs::write(root_path.join("kept.txt"), b"kept").unwrap();
+            let mut tag = CACHEDIR_TAG_SIGNATURE.to_vec();
+            tag.extend_from_slice(b"\n# This file is a cache directory tag.\n");
+            fs::write(root_path.join("cache").join(CACHEDIR_TAG), &tag).unwrap();
+            fs::write(root_path.join("cache/data.bin"), b"cached").unwrap();
+            fs::write(root_path.join("cache/nested/more.bin"), b"more").unwrap();
+            fs::write(root_path.join("scratch").join(NOBACKUP_MARKER), b"").unwrap();
+            fs::write(root_path.join("scratch/temp.txt"), b"temp").unwrap();
+            // A tag without the signature must not be honored
+            fs::write(root_path.join("untagged").join(CACHEDIR_TAG), b"Not a tag").unwrap();
+            fs::write(root_path.join("untagged/file.txt"), b"file").unwrap();
+
+            assert!(is_cache_dir(&root_path.join("cache")));
+            assert!(is_cache_dir(&root_path.join("scratch")));
+            assert!(!is_cache_dir(&root_path.join("untagged")));
+
+            let key = Key::random(32);
+            let settings = ChunkSettings::lightweight();
+            let backend = Mem::new(settings, key.clone(), 4);
+            let mut repo = Repository::with(backend, settings, key, 2).unwrap();
+            let archive = ActiveArchive::new("test");
+            store_directory(
+                &archive,
+                FastCDC::default(),
+                &mut repo,
+                root_path,
+                &StoreOptions {
+                    exclude_caches: true,
+                    ..StoreOptions::default()
+                },
+            )
+            .await
+            .unwrap();
+
+            let listing = archive.listing().await;
+            let mut paths = listing
+                .walk()
+                .map(|(path, _)| path.to_string_lossy().to_string())
+                .collect::<Vec<_>>();
+            paths.sort();
+            assert_eq!(
+                paths,
+                vec![
+                    "cache",
+                    "kept.txt",
+                    "scratch",
+                    "untagged",
+                    "untagged/CACHEDIR.TAG",
+                    "untagged/file.txt",
+                ]
+            );
+        });
+    }
+
+    #[test]
+    fn nested_ignore_files() {
+        smol::run(async {
+            let root = tempdir().unwrap();
+            let root_path = root.path();
+            fs::create_dir_all(root_path.join("project/build")).unwrap();
+            fs::create_dir_all(root_path.join("project/src")).unwrap();
+            fs::write(root_path.join(".asuranignore"), b"*.tmp\n").unwrap();
+            fs::write(root_path.join("secret.txt"), b"not ignored up here").unwrap();
+            fs::write(root_path.join("scratch.tmp"), b"scratch").unwrap();
+            fs::write(
+                root_path.join("project/.asuranignore"),
+                b"# Local rules\nsecret.txt\n/build\n",
+            )
+            .unwrap();
+            fs::write(root_path.join("project/notes.txt"), b"notes").unwrap();
+            fs::write(root_path.join("project/secret.txt"), b"secret").unwrap();
+            fs::write(root_path.join("project/build/output.bin"), b"output").unwrap();
+            // Ignored directories are never entered, so this invalid ignore file is never read
+            fs::write(root_path.join("project/build/.asuranignore"), b"[").unwrap();
+            fs::write(root_path.join("project/src/main.rs"), b"fn main() {}").unwrap();
+            // Stacked with the rules from the root
+            fs::write(root_path.join("project/src/swap.tmp"), b"swap").unwrap();
+
+            let key = Key::random(32);
+            let settings = ChunkSettings::lightweight();
+            let backend = Mem::new(settings, key.clone(), 4);
+            let mut repo = Repository::with(backend, settings, key, 2).unwrap();
+            let archive = ActiveArchive::new("test");
+            store_directory(
+                &archive,
+                FastCDC::default(),
+                &mut repo,
+                root_path,
+                &StoreOptions {
+                    ignore_file: Some(".asuranignore".to_string()),
+                    ..StoreOptions::default()
+                },
+            )
+            .await
+            .unwrap();
+
+            let listing = archive.listing().await;
+            let mut paths = listing
+                .walk()
+                .map(|(path, _)| path.to_string_lossy().to_string())
+                .collect::<Vec<_>>();
+            paths.sort();
+            assert_eq!(
+                paths,
+                vec![
+                    ".asuranignore",
+                    "project",
+                    "project/.asuranignore",
+                    "project/notes.txt",
+                    "project/src",
+                    "project/src/main.rs",
+                    "secret.txt",
+                ]
+            );
+        });
+    }
+
+    #[test]
+    fn detect_content_type() {
+        smol::run(async {
+            let root = tempdir().unwrap();
+            let root_path = root.path();
+            let mut png = b"\x89PNG\r\n\x1a\n".to_vec();
+            png.extend_from_slice(&[0_u8; 64]);
+            fs::write(root_path.join("image.png"), &png).unwrap();
+            fs::write(root_path.join("notes.txt"), b"Just some plain text").unwrap();
+
+            let key = Key::random(32);
+            let settings = ChunkSettings::lightweight();
+            let backend = Mem::new(settings, key.clone(), 4);
+            let mut repo = Repository::with(backend, settings, key, 2).unwrap();
+            let archive = ActiveArchive::new("test");
+            store_directory(
+                &archive,
+                FastCDC::default(),
+                &mut repo,
+                root_path,
+                &StoreOptions {
+                    detect_content_type: true,
+                    ..StoreOptions::default()
+                },
+            )
+            .await
+            .unwrap();
+
+            let listing = archive.listing().await;
+            assert_eq!(
+                listing.get("image.png").unwrap().content_type.as_deref(),
+                Some("image/png")
+            );
+            let text = listing.get("notes.txt").unwrap().content_type.as_deref();
+            assert!(matches!(text, None | Some("text/plain")));
+        });
+    }
+
+    /// Wraps `FastCDC`, counting the number of objects it is asked to chunk
+    #[cfg(feature = "file-dedup")]
+    #[derive(Clone)]
+    struct CountingChunker(FastCDC, Arc<AtomicUsize>);
+
+    #[cfg(feature = "file-dedup")]
+    impl Chunker for CountingChunker {
+        type Chunks = <FastCDC as Chunker>::Chunks;
+        fn chunk_boxed(&self, read: Box<dyn Read + Send + 'static>) -> Self::Chunks {
+            self.1.fetch_add(1, Ordering::SeqCst);
+            self.0.chunk_boxed(read)
+        }
+    }
+
+    #[cfg(feature = "file-dedup")]
+    #[test]
+    fn identical_files_stored_once() {
+        smol::run(async {
+            let root = tempdir().unwrap();
+            let root_path = root.path();
+            let contents = b"Licensed under the terms of the BSD 2-clause license\n".repeat(20);
+            for i in 0..100 {
+                fs::write(root_path.join(format!("{}.txt", i)), &contents).unwrap();
+            }
+            // Same length, different contents
+            let mut different = contents.clone();
+            different[0] = b'l';
+            fs::write(root_path.join("different.txt"), &different).unwrap();
+
+            let key = Key::random(32);
+            let settings = ChunkSettings::lightweight();
+            let backend = Mem::new(settings, key.clone(), 4);
+            let mut repo = Repository::with(backend, settings, key, 2).unwrap();
+            let archive = ActiveArchive::new("test");
+            let chunked = Arc::new(AtomicUsize::new(0));
+            store_directory(
+                &archive,
+                CountingChunker(FastCDC::default(), chunked.clone()),
+                &mut repo,
+                root_path,
+                &StoreOptions::default(),
+            )
+            .await
+            .unwrap();
+            assert_eq!(chunked.load(Ordering::SeqCst), 2);
+
+            let listing = archive.listing().await;
+            assert_eq!(listing.walk().count(), 101);
+            for i in 0..100 {
+                let path = format!("{}.txt", i);
+                assert_eq!(
+                    listing.get(&path).unwrap().total_length,
+                    contents.len() as u64
+                );
+                let mut stored = Vec::new();
+                archive
+                    .get_object(&mut repo, &path, &mut stored)
+                    .await
+                    .unwrap();
+                assert_eq!(stored, contents);
+            }
+            let mut stored = Vec::new();
+            archive
+                .get_object(&mut repo, "different.txt", &mut stored)
+                .await
+                .unwrap();
+            assert_eq!(stored, different);
+        });
+    }
+
+    // Each file must be read exactly once, either by the chunker or to hash it
+    #[cfg(feature = "file-dedup")]
+    #[test]
+    fn identical_files_read_once() {
+        smol::run(async {
+            let root = tempdir().unwrap();
+            let root_path = root.path();
+            let contents = vec![7_u8; 4096];
+            for i in 0..100 {
+                fs::write(root_path.join(format!("{}.bin", i)), &contents).unwrap();
+            }
+
+            let key = Key::random(32);
+            let settings = ChunkSettings::lightweight();
+            let backend = Mem::new(settings, key.clone(), 4);
+            let mut repo = Repository::with(backend, settings, key, 2).unwrap();
+            let archive = ActiveArchive::new("test");
+            let summary = store_directory(
+                &archive,
+                FastCDC::default(),
+                &mut repo,
+                root_path,
+                &StoreOptions::default(),
+            )
+            .await
+            .unwrap();
+            assert_eq!(summary.files_stored, 100);
+            assert_eq!(summary.chunks.bytes_read, contents.len() as u64);
+            assert_eq!(
+                summary.chunks.bytes_read + summary.bytes_hashed,
+                100 * contents.len() as u64
+            );
+        });
+    }
+
+    /// Counts the readers a `TrackingChunker` has open at once
+    #[derive(Clone, Default)]
+    struct InFlight {
+        current: Arc<AtomicUsize>,
+        max: Arc<AtomicUsize>,
+    }
+
+    /// Wraps a reader handed to a `TrackingChunker`, counting it as in flight until it reaches
+    /// the end of its input or is dropped
+    struct TrackedReader {
+        inner: Box<dyn Read + Send + 'static>,
+        in_flight: InFlight,
+        open: bool,
+        delayed: bool,
+    }
+
+    impl TrackedReader {
+        fn close(&mut self) {
+            if self.open {
+                self.open = false;
+                self.in_flight.current.fetch_sub(1, Ordering::SeqCst);
+            }
+        }
+    }
+
+    impl Read for TrackedReader {
+        fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+            // Give the other files a chance to start while this one is open
+            if !self.delayed {
+                std::thread::sleep(std::time::Duration::from_millis(50));
+                self.delayed = true;
+            }
+            let count = self.inner.read(buf)?;
+            if count == 0 && !buf.is_empty() {
+                self.close();
+            }
+            Ok(count)
+        }
+    }
+
+    impl Drop for TrackedReader {
+        fn drop(&mut self) {
+            self.close();
+        }
+    }
+
+    /// Wraps `FastCDC`, recording the largest number of objects being read at once
+    #[derive(Clone)]
+    struct TrackingChunker(FastCDC, InFlight);
+
+    impl Chunker for TrackingChunker {
+        type Chunks = <FastCDC as Chunker>::Chunks;
+        fn chunk_boxed(&self, read: Box<dyn Read + Send + 'static>) -> Self::Chunks {
+            let current = self.1.current.fetch_add(1, Ordering::SeqCst) + 1;
+            self.1.max.fetch_max(current, Ordering::SeqCst);
+            let reader = TrackedReader {
+                inner: read,
+                in_flight: self.1.clone(),
+                open: true,
+                delayed: false,
+            };
+            self.0.chunk_boxed(Box::new(reader))
+        }
+    }
+
+    #[test]
+    fn concurrency_limits_files_in_flight() {
+        smol::run(async {
+            let root = tempdir().unwrap();
+            let root_path = root.path();
+            // Distinct lengths, so that no file waits on another to be hashed
+            for i in 0..8_u8 {
+                fs::write(
+                    root_path.join(i.to_string()),
+                    vec![i; 1000 + usize::from(i)],
+                )
+                .unwrap();
+            }
+
+            for concurrency in &[1, 8] {
+                let key = Key::random(32);
+                let settings = ChunkSettings::lightweight();
+                let backend = Mem::new(settings, key.clone(), 4);
+                let mut repo = Repository::with(backend, settings, key, 2).unwrap();
+                let archive = ActiveArchive::new("test");
+                let in_flight = InFlight::default();
+                store_directory(
+                    &archive,
+                    TrackingChunker(FastCDC::default(), in_flight.clone()),
+                    &mut repo,
+                    root_path,
+                    &StoreOptions {
+                        concurrency: *concurrency,
+                        ..StoreOptions::default()
+                    },
+                )
+                .await
+                .unwrap();
+                let max = in_flight.max.load(Ordering::SeqCst);
+                if *concurrency == 1 {
+                    assert_eq!(max, 1);
+                } else {
+                    assert!(max > 1 && max <= *concurrency, "{} files in flight", max);
+                }
+
+                let listing = archive.listing().await;
+                assert_eq!(listing.walk().count(), 8);
+                for i in 0..8_u8 {
+                    let mut stored = Vec::new();
+                    archive
+                        .get_object(&mut repo, &i.to_string(), &mut stored)
+                        .await
+                        .unwrap();
+                    assert_eq!(stored, vec![i; 1000 + usize::from(i)]);
+                }
+            }
+        });
+    }
+
+    // The second store shares half of its chunks with the first, and must only write the rest
+    #[test]
+    fn summary_counts_deduplicated_chunks() {
+        use crate::chunker::StaticSize;
+        use rand::prelude::*;
+        smol::run(async {
+            let first_root = tempdir().unwrap();
+            let second_root = tempdir().unwrap();
+            let mut blocks = vec![vec![0_u8; 1024]; 12];
+            for block in &mut blocks {
+                thread_rng().fill_bytes(block);
+            }
+            fs::write(first_root.path().join("first"), blocks[..8].concat()).unwrap();
+            fs::write(second_root.path().join("second"), blocks[4..].concat()).unwrap();
+
+            let key = Key::random(32);
+            let settings = ChunkSettings::lightweight();
+            let backend = Mem::new(settings, key.clone(), 4);
+            let mut repo = Repository::with(backend, settings, key, 2).unwrap();
+            let mut summaries = Vec::new();
+            for root in &[&first_root, &second_root] {
+                let archive = ActiveArchive::new("test");
+                let summary = store_directory(
+                    &archive,
+                    StaticSize { len: 1024 },
+                    &mut repo,
+                    root.path(),
+                    &StoreOptions::default(),
+                )
+                .await
+                .unwrap();
+                summaries.push(summary);
+            }
+
+            let (first, second) = (summaries[0], summaries[1]);
+            assert_eq!(first.files_stored, 1);
+            assert_eq!(first.chunks.bytes_read, 8192);
+            assert_eq!(first.chunks.new_chunks, 8);
+            assert_eq!(first.chunks.deduplicated_chunks, 0);
+            assert_eq!(second.files_stored, 1);
+            assert_eq!(second.chunks.bytes_read, 8192);
+            assert_eq!(second.chunks.new_chunks, 4);
+            assert_eq!(second.chunks.deduplicated_chunks, 4);
+            assert!(second.chunks.bytes_written > 0);
+            assert!(second.chunks.bytes_written < first.chunks.bytes_written);
+            assert_eq!(repo.count_chunk().await, 12);
+        });
+    }
+}
@@ -0,0 +1,117 @@
+//! Provides reusable measurements of the performance of asuran's components
+//!
+//! These are used by `asuran-cli`'s benchmark commands, but are kept here so that they can be
+//! tested, and used by other frontends.
+use crate::chunker::{BuzHash, Chunker, FastCDC, StaticSize};
+
+use rand::prelude::*;
+use rand::rngs::StdRng;
+
+use std::collections::HashSet;
+use std::time::Instant;
+
+const ONE_MIB: f64 = 1_048_576.0;
+
+/// The results of running a single chunker over a dataset
+#[derive(Debug, Clone, PartialEq)]
+pub struct ChunkerBenchmark {
+    /// Human readable name of the chunker
+    pub name: &'static str,
+    /// Speed at which the chunker sliced the data, in MiB/s
+    pub throughput: f64,
+    /// Total number of chunks produced
+    pub chunk_count: usize,
+    /// Number of distinct chunks produced
+    pub unique_chunks: usize,
+    /// Mean length of the produced chunks, in bytes
+    pub average_chunk_size: f64,
+    /// Fraction of the dataset that would be left after deduplicating the chunks
+    ///
+    /// Lower is better, 1.0 indicates that no duplicate chunks were found.
+    pub dedup_ratio: f64,
+}
+
+/// Generates a dataset of approximately `size` bytes with a known amount of redundancy
+///
+/// The first half of the dataset is random, while the second half is a copy of the first with a
+/// single byte inserted at the start. This makes the dataset useful for demonstrating the
+/// boundary shift problem, content defined chunkers will be able to deduplicate most of the
+/// second half, where fixed size chunkers will not.
+pub fn generate_dataset(size: usize, seed: u64) -> Vec<u8> {
+    let mut rng = StdRng::seed_from_u64(seed);
+    let mut half = vec![0_u8; size / 2];
+    rng.fill_bytes(&mut half);
+    let mut data = Vec::with_capacity(half.len() * 2 + 1);
+    data.extend_from_slice(&half);
+    data.push(rng.gen());
+    data.extend_from_slice(&half);
+    data
+}
+
+/// Runs a chunker over the provided data, timing it and collecting statistics about its output
+///
+/// Only the chunking itself is timed, the deduplication statistics are computed afterwards.
+#[allow(clippy::cast_precision_loss)]
+pub fn bench_chunker<C: Chunker>(name: &'static str, chunker: &C, data: &[u8]) -> ChunkerBenchmark {
+    let start = Instant::now();
+    let chunks = chunker
+        .chunk_borrowed(data)
+        .collect::<Result<Vec<_>, _>>()
+        .expect("Chunking an in-memory slice can not fail");
+    let elapsed = start.elapsed().as_secs_f64();
+
+    let unique: HashSet<&[u8]> = chunks.iter().copied().collect();
+    let unique_bytes: usize = unique.iter().map(|x| x.len()).sum();
+    let chunk_count = chunks.len();
+    ChunkerBenchmark {
+        name,
+        // Guard against a timer too coarse to see a very small input
+        throughput: (data.len() as f64 / ONE_MIB) / elapsed.max(f64::EPSILON),
+        chunk_count,
+        unique_chunks: unique.len(),
+        average_chunk_size: if chunk_count == 0 {
+            0.0
+        } else {
+            data.len() as f64 / chunk_count as f64
+        },
+        dedup_ratio: if data.is_empty() {
+            1.0
+        } else {
+            unique_bytes as f64 / data.len() as f64
+        },
+    }
+}
+
+/// Benchmarks each of asuran's chunkers, with their default settings, over the provided data
+pub fn bench_chunkers(data: &[u8]) -> Vec<ChunkerBenchmark> {
+    vec![
+        bench_chunker("FastCDC", &FastCDC::default(), data),
+        bench_chunker("BuzHash", &BuzHash::with_default(random()), data),
+        bench_chunker("StaticSize", &StaticSize::default(), data),
+    ]
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn chunker_throughput_nonzero() {
+        let data = generate_dataset(256 * 1024, 0);
+        let results = bench_chunkers(&data);
+        assert_eq!(results.len(), 3);
+        for result in results {
+            assert!(result.throughput > 0.0, "{} had no throughput", result.name);
+            assert!(result.chunk_count > 0);
+            assert!(result.unique_chunks <= result.chunk_count);
+            assert!(result.dedup_ratio > 0.0 && result.dedup_ratio <= 1.0);
+        }
+    }
+
+    #[test]
+    fn dataset_is_redundant() {
+        let data = generate_dataset(1024, 1);
+        assert_eq!(data.len(), 1025);
+        assert_eq!(data[..512], data[513..]);
+    }
+}
@@ -0,0 +1,351 @@
+//! This module provides methods for exporting the contents of an archive into formats that can be
+//! consumed by tools that do not understand asuran repositories.
+//!
+//! The `Listing` of the archive is used to reconstruct the structure of the archive, and the
+//! objects are read out of the repository's root namespace, the same place the `FileSystemTarget`
+//! stores them.
+use crate::manifest::archive::{ActiveArchive, ArchiveError};
+use crate::manifest::target::NodeType;
+use crate::repository::{BackendClone, Repository};
+
+use tar::{Builder, EntryType, Header};
+use thiserror::Error;
+
+use std::convert::TryInto;
+use std::fs::{create_dir_all, File};
+use std::io::{BufWriter, Write};
+use std::path::{Component, Path, PathBuf};
+
+/// An error for things that can go wrong while exporting an archive
+#[derive(Error, Debug)]
+pub enum ExportError {
+    #[error("I/O Error: {0}")]
+    IO(#[from] std::io::Error),
+    #[error("Archive Error: {0}")]
+    Archive(#[from] ArchiveError),
+    #[error("Path {0} would escape the output directory")]
+    PathTraversal(String),
+}
+
+type Result<T> = std::result::Result<T, ExportError>;
+
+/// Joins an object path from an archive onto a root directory, ensuring that the result can not
+/// escape the root directory
+///
+/// # Errors
+///
+/// Will return `Err(PathTraversal)` if the path is absolute, or contains any `..` components
+pub fn safe_join(root: impl AsRef<Path>, path: &str) -> Result<PathBuf> {
+    let mut output = root.as_ref().to_path_buf();
+    for component in Path::new(path).components() {
+        match component {
+            Component::Normal(part) => output.push(part),
+            Component::CurDir => (),
+            Component::ParentDir | Component::RootDir | Component::Prefix(_) => {
+                return Err(ExportError::PathTraversal(path.to_string()))
+            }
+        }
+    }
+    Ok(output)
+}
+
+/// Exports every object in an archive as a regular file under `out_dir`, reconstructing the
+/// directory structure from the archive's `Listing`
+///
+/// Sparse objects are written out densely, with their holes filled in with zeros.
+///
+/// # Errors
+///
+/// Will return `Err` if any of the paths in the archive would escape `out_dir`, if reading an
+/// object from the repository fails, or if an underlying I/O error occurs.
+pub async fn export_archive(
+    archive: &ActiveArchive,
+    repo: &mut Repository<impl BackendClone>,
+    out_dir: impl AsRef<Path>,
+) -> Result<()> {
+    let out_dir = out_dir.as_ref();
+    let listing = archive.listing().await;
+    // Objects are stored in the root namespace
+    let archive = archive.namespace_append("");
+    create_dir_all(out_dir)?;
+    for node in listing {
+        let path = safe_join(out_dir, &node.path)?;
+        match node.node_type {
+            NodeType::Directory { .. } => create_dir_all(&path)?,
+            NodeType::File | NodeType::InlineFile { .. } => {
+                if let Some(parent) = path.parent() {
+                    create_dir_all(parent)?;
+                }
+                let mut file = BufWriter::new(File::create(&path)?);
+                if let Some(data) = node.inline_data() {
+                    file.write_all(data)?;
+                } else if node.extents.map_or(false, |extents| !extents.is_empty()) {
+                    // Empty objects have no chunks to retrieve, so creating the file is enough
+                    archive.get_object(repo, &node.path, &mut file).await?;
+                }
+                file.flush()?;
+            }
+            // Links do not currently have any stored data to export
+            NodeType::Link => (),
+        }
+    }
+    Ok(())
+}
+
+/// A `Write` that keeps track of how many bytes have been written through it
+struct CountingWriter<W: Write> {
+    inner: W,
+    count: u64,
+}
+
+impl<W: Write> Write for CountingWriter<W> {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        let written = self.inner.write(buf)?;
+        self.count += written as u64;
+        Ok(written)
+    }
+    fn flush(&mut self) -> std::io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+/// Writes the objects in an archive out as a POSIX tar stream, reconstructing the directory
+/// structure from the archive's `Listing`
+///
+/// The contents of each object are streamed directly from the repository into the output, one
+/// chunk at a time, so whole objects are never buffered in memory. Sparse objects are written out
+/// densely, with their holes filled in with zeros.
+///
+/// # Errors
+///
+/// Will return `Err` if any of the paths in the archive are not suitable for a tar archive, if
+/// reading an object from the repository fails, or if an underlying I/O error occurs.
+pub async fn archive_to_tar<W: Write>(
+    archive: &ActiveArchive,
+    repo: &mut Repository<impl BackendClone>,
+    out: W,
+) -> Result<()> {
+    let listing = archive.listing().await;
+    let mtime: u64 = archive.timestamp().timestamp().try_into().unwrap_or(0);
+    // Objects are stored in the root namespace
+    let archive = archive.namespace_append("");
+    let mut builder = Builder::new(out);
+    for node in listing {
+        // Make sure the path is relative and stays inside the archive
+        safe_join("", &node.path)?;
+        let mut header = Header::new_gnu();
+        header.set_mtime(mtime);
+        match node.node_type {
+            NodeType::Directory { .. } => {
+                header.set_entry_type(EntryType::Directory);
+                header.set_mode(0o755);
+                header.set_size(0);
+                builder.append_data(&mut header, &node.path, std::io::empty())?;
+            }
+            NodeType::File | NodeType::InlineFile { .. } => {
+                header.set_entry_type(EntryType::Regular);
+                header.set_mode(0o644);
+                header.set_size(node.total_length);
+                // Write only the header (and any long name entries) through the builder, as it
+                // only pads the data it copies itself
+                builder.append_data(&mut header, &node.path, std::io::empty())?;
+                // Then stream the object's contents directly into the output
+                let mut writer = CountingWriter {
+                    inner: builder.get_mut(),
+                    count: 0,
+                };
+                if let Some(data) = node.inline_data() {
+                    writer.write_all(data)?;
+                } else if node.extents.map_or(false, |extents| !extents.is_empty()) {
+                    archive.get_object(repo, &node.path, &mut writer).await?;
+                }
+                // Fill in any trailing hole, and make sure the output is exactly as long as the
+                // header claims
+                let zeros = [0_u8; 512];
+                while writer.count < node.total_length {
+                    let remaining = node.total_length - writer.count;
+                    let len = remaining
+                        .min(512)
+                        .try_into()
+                        .expect("Always less than a block");
+                    writer.write_all(&zeros[..len])?;
+                }
+                let written = writer.count;
+                if written != node.total_length {
+                    return Err(ExportError::IO(std::io::Error::new(
+                        std::io::ErrorKind::InvalidData,
+                        format!(
+                            "Object {} was {} bytes long, expected {}",
+                            node.path, written, node.total_length
+                        ),
+                    )));
+                }
+                // Pad out to the next block
+                let remaining = (512 - (written % 512)) % 512;
+                let remaining: usize = remaining
+                    .try_into()
+                    .expect("Padding is always less than a block");
+                writer.write_all(&zeros[..remaining])?;
+            }
+            // Links do not currently have any stored data to export
+            NodeType::Link => (),
+        }
+    }
+    builder.finish()?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::chunker::FastCDC;
+    use crate::manifest::archive::Extent;
+    use crate::manifest::driver::BackupDriver;
+    use crate::manifest::target::{BackupTarget, FileSystemTarget};
+    use crate::manifest::target::{Listing, Node};
+    use crate::repository::backend::mem::Mem;
+    use crate::repository::{ChunkSettings, Key};
+    use rand::prelude::*;
+    use std::collections::HashMap;
+    use std::fs::create_dir;
+    use std::io::{Cursor, Read};
+    use tempfile::{tempdir, TempDir};
+
+    fn make_test_directory() -> TempDir {
+        let root = tempdir().unwrap();
+        let root_path = root.path();
+
+        create_dir(root_path.join("A")).unwrap();
+        create_dir(root_path.join("B")).unwrap();
+        create_dir(root_path.join("B").join("C")).unwrap();
+
+        for (i, path) in [
+            root_path.join("1"),
+            root_path.join("A").join("2"),
+            root_path.join("B").join("C").join("3"),
+        ]
+        .iter()
+        .enumerate()
+        {
+            let mut data = vec![0_u8; 10_000 * i];
+            thread_rng().fill_bytes(&mut data);
+            File::create(path).unwrap().write_all(&data).unwrap();
+        }
+
+        root
+    }
+
+    #[test]
+    fn export_matches_input() {
+        smol::run(async {
+            let input_dir = make_test_directory();
+            let key = Key::random(32);
+            let settings = ChunkSettings::lightweight();
+            let backend = Mem::new(settings, key.clone(), 4);
+            let mut repo = Repository::with(backend, settings, key, 2).unwrap();
+            let chunker = FastCDC::default();
+            // Store the directory in an archive
+            let archive = ActiveArchive::new("test");
+            let target = FileSystemTarget::new(&input_dir.path().display().to_string());
+            for node in target.backup_paths().await {
+                target
+                    .store_object(&mut repo, chunker, &archive, node)
+                    .await
+                    .unwrap();
+            }
+            archive.set_listing(target.backup_listing().await).await;
+            // Export it and compare
+            let output_dir = tempdir().unwrap();
+            export_archive(&archive, &mut repo, output_dir.path())
+                .await
+                .expect("Export failed");
+            assert!(!dir_diff::is_different(&input_dir.path(), &output_dir.path()).unwrap());
+        });
+    }
+
+    #[test]
+    fn safe_join_rejects_traversal() {
+        let root = Path::new("/tmp/export");
+        assert_eq!(
+            safe_join(root, "a/./b").unwrap(),
+            PathBuf::from("/tmp/export/a/b")
+        );
+        assert!(safe_join(root, "../a").is_err());
+        assert!(safe_join(root, "a/../../b").is_err());
+        assert!(safe_join(root, "/etc/passwd").is_err());
+    }
+
+    #[test]
+    fn tar_export() {
+        smol::run(async {
+            let key = Key::random(32);
+            let settings = ChunkSettings::lightweight();
+            let backend = Mem::new(settings, key.clone(), 4);
+            let mut repo = Repository::with(backend, settings, key, 2).unwrap();
+            let chunker = FastCDC::default();
+            // Build an archive with a directory containing two files
+            let archive = ActiveArchive::new("test");
+            let mut listing = Listing::default();
+            listing.add_child(
+                "",
+                Node {
+                    path: "dir".to_string(),
+                    total_length: 0,
+                    total_size: 0,
+                    extents: None,
+                    content_type: None,
+                    node_type: NodeType::Directory {
+                        children: Vec::new(),
+                    },
+                },
+            );
+            let mut files = HashMap::new();
+            for (path, len) in &[("dir/one", 1000_usize), ("dir/two", 100_000)] {
+                let mut data = vec![0_u8; *len];
+                thread_rng().fill_bytes(&mut data);
+                archive
+                    .namespace_append("")
+                    .put_object(&chunker, &mut repo, path, Cursor::new(data.clone()))
+                    .await
+                    .unwrap();
+                listing.add_child(
+                    "dir",
+                    Node {
+                        path: path.to_string(),
+                        total_length: *len as u64,
+                        total_size: *len as u64,
+                        extents: Some(vec![Extent {
+                            start: 0,
+                            end: *len as u64 - 1,
+                        }]),
+                        content_type: None,
+                        node_type: NodeType::File,
+                    },
+                );
+                files.insert(path.to_string(), data);
+            }
+            archive.set_listing(listing).await;
+            // Export it to an in memory tar
+            let mut output = Vec::new();
+            archive_to_tar(&archive, &mut repo, &mut output)
+                .await
+                .expect("Tar export failed");
+            // Read it back and compare
+            let mut tar = tar::Archive::new(Cursor::new(output));
+            let mut seen = 0;
+            for entry in tar.entries().unwrap() {
+                let mut entry = entry.unwrap();
+                let path = entry.path().unwrap().to_str().unwrap().to_string();
+                if entry.header().entry_type() == EntryType::Directory {
+                    assert_eq!(path.trim_end_matches('/'), "dir");
+                } else {
+                    let mut data = Vec::new();
+                    entry.read_to_end(&mut data).unwrap();
+                    assert_eq!(Some(&data), files.get(&path));
+                    seen += 1;
+                }
+            }
+            assert_eq!(seen, files.len());
+        });
+    }
+}
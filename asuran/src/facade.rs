@@ -0,0 +1,286 @@
+//! A high level interface for the common backup and restore workflow
+//!
+//! Making a backup with the lower level API involves opening a backend, wrapping it in a
+//! `Repository`, loading the `Manifest`, creating an `ActiveArchive`, picking a chunker, driving a
+//! `BackupTarget`, and finally committing the archive. `Asuran` bundles all of this up behind a
+//! handful of methods with sensible defaults, for library consumers who just want to back up and
+//! restore directories in a `MultiFile` repository.
+//!
+//! # Example
+//!
+//! ```no_run
+//! use asuran::facade::{Asuran, AsuranOptions};
+//!
+//! smol::run(async {
+//!     let options = AsuranOptions::default();
+//!     let mut asuran = Asuran::create("/tmp/repo", b"password", options).await.unwrap();
+//!     asuran.backup("documents", "/home/user/documents").await.unwrap();
+//!     asuran.restore("documents", "/tmp/restored").await.unwrap();
+//!     asuran.close().await;
+//! });
+//! ```
+use crate::backup::{store_directory, BackupError, StoreOptions};
+use crate::chunker::FastCDC;
+use crate::manifest::{ActiveArchive, ArchiveError, Manifest};
+use crate::repository::backend::multifile::MultiFile;
+use crate::repository::backend::{Backend, BackendError};
+use crate::repository::{
+    BackendObject, ChunkID, ChunkSettings, Compression, EncryptedKey, Encryption, Key, KeyError,
+    Repository, RepositoryError, HMAC,
+};
+use crate::restore::{extract_directory, ExtractOptions, RestoreError};
+
+use chrono::prelude::*;
+use thiserror::Error;
+
+use std::path::{Path, PathBuf};
+
+/// An error for things that can go wrong with the `Asuran` facade
+#[derive(Error, Debug)]
+pub enum AsuranError {
+    #[error("Backend Error: {0}")]
+    Backend(#[from] BackendError),
+    #[error("Repository Error: {0}")]
+    Repository(#[from] RepositoryError),
+    #[error("Archive Error: {0}")]
+    Archive(#[from] ArchiveError),
+    #[error("Backup Error: {0}")]
+    Backup(#[from] BackupError),
+    #[error("Restore Error: {0}")]
+    Restore(#[from] RestoreError),
+    #[error("Unable to decrypt key material, possibly due to an invalid password")]
+    Key(#[from] KeyError),
+    #[error("I/O Error: {0}")]
+    IO(#[from] std::io::Error),
+    #[error("A repository already exists at {0:?}")]
+    AlreadyExists(PathBuf),
+    #[error("No archive named {0:?} in the repository")]
+    ArchiveNotFound(String),
+}
+
+impl AsuranError {
+    /// Returns a short, stable, `snake_case` name for the kind of this error
+    ///
+    /// Errors originating in the backend or repository report the kind of the underlying error.
+    pub fn kind(&self) -> &'static str {
+        match self {
+            AsuranError::Backend(e) => e.kind(),
+            AsuranError::Repository(e) => e.kind(),
+            AsuranError::Archive(e) => e.kind(),
+            AsuranError::Backup(e) => e.kind(),
+            AsuranError::Restore(e) => e.kind(),
+            AsuranError::Key(e) => e.kind(),
+            AsuranError::IO(_) => "io",
+            AsuranError::AlreadyExists(_) => "already_exists",
+            AsuranError::ArchiveNotFound(_) => "archive_not_found",
+        }
+    }
+}
+
+type Result<T> = std::result::Result<T, AsuranError>;
+
+/// Settings used by `Asuran` when creating or opening a repository
+#[derive(Clone, Copy)]
+pub struct AsuranOptions {
+    /// Settings new chunks are written with
+    pub chunk_settings: ChunkSettings,
+    /// Chunker files are split with when backed up
+    pub chunker: FastCDC,
+    /// Number of tasks to spawn for the chunk processing pipeline
+    pub pipeline_tasks: usize,
+    /// Number of requests to queue up for the backend
+    pub queue_depth: usize,
+}
+
+impl Default for AsuranOptions {
+    /// Provides ZStd level 3 compression, AES256CTR encryption, and Blake3 HMACs, matching the
+    /// defaults of asuran-cli, with one pipeline task per CPU
+    fn default() -> Self {
+        let pipeline_tasks = num_cpus::get();
+        AsuranOptions {
+            chunk_settings: ChunkSettings {
+                compression: Compression::ZStd { level: 3 },
+                encryption: Encryption::new_aes256ctr(),
+                hmac: HMAC::Blake3,
+            },
+            chunker: FastCDC::default(),
+            pipeline_tasks,
+            queue_depth: pipeline_tasks * 8,
+        }
+    }
+}
+
+/// Summary of an archive in the repository, as returned by `Asuran::list`
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct ArchiveInfo {
+    /// Pointer to the archive metadata in the repository
+    pub id: ChunkID,
+    /// The user provided name of the archive
+    pub name: String,
+    /// Time the archive was created
+    pub timestamp: DateTime<FixedOffset>,
+}
+
+/// High level handle to a `MultiFile` repository
+pub struct Asuran {
+    repo: Repository<BackendObject>,
+    manifest: Manifest<BackendObject>,
+    chunker: FastCDC,
+}
+
+impl Asuran {
+    /// Creates a new `MultiFile` repository at `path`, with a fresh random key encrypted with
+    /// `password`, and opens it
+    ///
+    /// # Errors
+    ///
+    /// Will return `Err` if something already exists at `path`, or if the repository could not be
+    /// created.
+    pub async fn create(
+        path: impl AsRef<Path>,
+        password: &[u8],
+        options: AsuranOptions,
+    ) -> Result<Asuran> {
+        let path = path.as_ref();
+        if path.exists() {
+            return Err(AsuranError::AlreadyExists(path.to_path_buf()));
+        }
+        let settings = options.chunk_settings;
+        settings.validate().map_err(RepositoryError::from)?;
+        let key = Key::random(settings.encryption.key_length());
+        let encrypted_key = EncryptedKey::encrypt_defaults(&key, settings.encryption, password);
+
+        std::fs::create_dir_all(path)?;
+        let mut backend =
+            MultiFile::open_defaults(path, Some(settings), &key, options.queue_depth).await?;
+        backend.write_key(&encrypted_key).await?;
+        Self::with_backend(backend, key, options)
+    }
+
+    /// Opens the existing `MultiFile` repository at `path`, decrypting its key with `password`
+    ///
+    /// # Errors
+    ///
+    /// Will return `Err` if the key could not be read or decrypted, or if the repository could not
+    /// be opened.
+    pub async fn open(
+        path: impl AsRef<Path>,
+        password: &[u8],
+        options: AsuranOptions,
+    ) -> Result<Asuran> {
+        let path = path.as_ref();
+        let key = MultiFile::read_key(path)?.decrypt(password)?;
+        // The repository's own chunk settings are used for its metadata, the ones in the options
+        // only apply to the chunks written through this handle
+        let backend = MultiFile::open_defaults(path, None, &key, options.queue_depth).await?;
+        Self::with_backend(backend, key, options)
+    }
+
+    fn with_backend(backend: MultiFile, key: Key, options: AsuranOptions) -> Result<Asuran> {
+        let repo = Repository::from_handle(
+            backend.get_object_handle(),
+            options.chunk_settings,
+            key,
+            options.pipeline_tasks,
+        )?;
+        let manifest = Manifest::load(&repo);
+        Ok(Asuran {
+            repo,
+            manifest,
+            chunker: options.chunker,
+        })
+    }
+
+    /// Provides access to the underlying `Repository`, for operations not covered by this
+    /// interface
+    pub fn repository(&mut self) -> &mut Repository<BackendObject> {
+        &mut self.repo
+    }
+
+    /// Lists the archives in the repository, in the order they were committed
+    ///
+    /// Archives sealed to a set of recipients can not be read with the repository key alone, and
+    /// are left out.
+    ///
+    /// # Errors
+    ///
+    /// Will return `Err` if the metadata of any archive could not be loaded.
+    pub async fn list(&mut self) -> Result<Vec<ArchiveInfo>> {
+        let mut output = Vec::new();
+        for stored_archive in self.manifest.archives().await {
+            let archive = match stored_archive.load_metadata(&mut self.repo).await {
+                Ok(archive) => archive,
+                Err(ArchiveError::Sealed) => continue,
+                Err(e) => return Err(e.into()),
+            };
+            output.push(ArchiveInfo {
+                id: stored_archive.id,
+                name: archive.name,
+                timestamp: archive.timestamp,
+            });
+        }
+        Ok(output)
+    }
+
+    /// Stores the contents of the directory at `source` as a new archive named `name`, and
+    /// commits it
+    ///
+    /// # Errors
+    ///
+    /// Will return `Err` if `source` is not valid UTF-8, or if any object could not be stored.
+    pub async fn backup(&mut self, name: &str, source: impl AsRef<Path>) -> Result<()> {
+        let archive = ActiveArchive::new(name);
+        store_directory(
+            &archive,
+            self.chunker,
+            &mut self.repo,
+            source.as_ref(),
+            &StoreOptions::default(),
+        )
+        .await?;
+        self.manifest
+            .commit_archive(&mut self.repo, archive)
+            .await?;
+        self.repo.commit_index().await?;
+        Ok(())
+    }
+
+    /// Restores the most recent archive named `name` into the directory at `dest`
+    ///
+    /// Files that already exist in `dest` are never overwritten, see `ExtractPolicy::Fail`. Sealed
+    /// archives are skipped, as their names can not be read.
+    ///
+    /// # Errors
+    ///
+    /// Will return `Err` if there is no archive named `name`, or if any object could not be
+    /// restored.
+    pub async fn restore(&mut self, name: &str, dest: impl AsRef<Path>) -> Result<()> {
+        let mut found = None;
+        for stored_archive in self.manifest.archives().await.into_iter().rev() {
+            match stored_archive.load_metadata(&mut self.repo).await {
+                Ok(archive) if archive.name == name => {
+                    found = Some(stored_archive);
+                    break;
+                }
+                Ok(_) | Err(ArchiveError::Sealed) => (),
+                Err(e) => return Err(e.into()),
+            }
+        }
+        let stored_archive = found.ok_or_else(|| AsuranError::ArchiveNotFound(name.to_string()))?;
+        let archive = stored_archive.load(&mut self.repo).await?;
+        extract_directory(
+            &archive,
+            &mut self.repo,
+            dest.as_ref(),
+            &ExtractOptions::default(),
+            None,
+        )
+        .await?;
+        Ok(())
+    }
+
+    /// Commits any outstanding changes and closes the repository
+    pub async fn close(mut self) {
+        self.repo.close().await;
+    }
+}
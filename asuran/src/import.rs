@@ -0,0 +1,362 @@
+//! This module provides methods for importing data produced by tools that do not understand
+//! asuran repositories into archives.
+//!
+//! Imported objects are stored in the archive's root namespace, the same place the
+//! `FileSystemTarget` stores them, so they can be restored with any of the normal machinery.
+use crate::chunker::AsyncChunker;
+use crate::manifest::archive::{ActiveArchive, ArchiveError, Extent};
+use crate::manifest::target::{Listing, Node, NodeType};
+use crate::manifest::StoredArchive;
+use crate::repository::backend::{BackendError, Manifest as BackendManifest};
+use crate::repository::{BackendClone, Repository};
+
+use crossbeam_channel::{bounded, Receiver};
+use smol::block_on;
+use thiserror::Error;
+
+use std::collections::HashSet;
+use std::io::{Cursor, Read};
+use std::path::{Component, Path};
+use std::thread;
+
+/// An error for things that can go wrong while importing an archive
+#[derive(Error, Debug)]
+pub enum ImportError {
+    #[error("I/O Error: {0}")]
+    IO(#[from] std::io::Error),
+    #[error("Archive Error: {0}")]
+    Archive(#[from] ArchiveError),
+    #[error("Backend Error: {0}")]
+    Backend(#[from] BackendError),
+    #[error("Path {0} is not valid in an archive")]
+    InvalidPath(String),
+}
+
+type Result<T> = std::result::Result<T, ImportError>;
+
+/// The size of the blocks the contents of tar entries are sent across threads in
+const PIPE_BLOCK_SIZE: usize = 64 * 1024;
+
+/// A `Read` over the blocks of an entry's contents being sent from the tar reading thread
+struct PipeReader {
+    input: Receiver<std::io::Result<Vec<u8>>>,
+    buffer: Vec<u8>,
+    position: usize,
+}
+
+impl Read for PipeReader {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        if self.position == self.buffer.len() {
+            match self.input.recv() {
+                Ok(Ok(block)) => {
+                    self.buffer = block;
+                    self.position = 0;
+                }
+                Ok(Err(e)) => return Err(e),
+                // The sender hanging up signals the end of the entry
+                Err(_) => return Ok(0),
+            }
+        }
+        let len = buf.len().min(self.buffer.len() - self.position);
+        buf[..len].copy_from_slice(&self.buffer[self.position..self.position + len]);
+        self.position += len;
+        Ok(len)
+    }
+}
+
+/// The kinds of tar entries that can be imported
+enum TarEntryKind {
+    Directory,
+    File(PipeReader),
+}
+
+/// The information extracted from a tar entry's headers
+struct TarEntry {
+    path: String,
+    mode: u32,
+    size: u64,
+    kind: TarEntryKind,
+}
+
+/// Converts a path from a tar header into the form used in an archive's `Listing`
+///
+/// Leading `/` and `.` components are stripped, the same way tar itself does when extracting.
+fn normalize_path(path: &Path) -> Result<String> {
+    let mut parts = Vec::new();
+    for component in path.components() {
+        match component {
+            Component::Normal(part) => parts.push(
+                part.to_str()
+                    .ok_or_else(|| ImportError::InvalidPath(path.display().to_string()))?,
+            ),
+            Component::CurDir | Component::RootDir => (),
+            Component::ParentDir | Component::Prefix(_) => {
+                return Err(ImportError::InvalidPath(path.display().to_string()))
+            }
+        }
+    }
+    Ok(parts.join("/"))
+}
+
+/// Reads the entries out of a tar stream, sending the directories and regular files along to the
+/// importer
+///
+/// The contents of regular files are streamed through a bounded channel, so this will only read
+/// as fast as the importer consumes them. Any other kinds of entry are skipped.
+fn read_tar<R: Read>(input: R, output: &async_channel::Sender<Result<TarEntry>>) -> Result<()> {
+    let mut archive = tar::Archive::new(input);
+    for entry in archive.entries()? {
+        let mut entry = entry?;
+        // This handles both GNU long names and PAX extensions for us
+        let path = normalize_path(&entry.path()?)?;
+        if path.is_empty() {
+            continue;
+        }
+        let header = entry.header();
+        let mode = header.mode().unwrap_or(0o644);
+        let size = entry.size();
+        let entry_type = header.entry_type();
+        if entry_type.is_dir() {
+            let entry = TarEntry {
+                path,
+                mode,
+                size,
+                kind: TarEntryKind::Directory,
+            };
+            if block_on(output.send(Ok(entry))).is_err() {
+                return Ok(());
+            }
+        } else if entry_type.is_file() {
+            let (input, data) = bounded(4);
+            let reader = PipeReader {
+                input: data,
+                buffer: Vec::new(),
+                position: 0,
+            };
+            let tar_entry = TarEntry {
+                path,
+                mode,
+                size,
+                kind: TarEntryKind::File(reader),
+            };
+            if block_on(output.send(Ok(tar_entry))).is_err() {
+                return Ok(());
+            }
+            loop {
+                let mut block = vec![0_u8; PIPE_BLOCK_SIZE];
+                match entry.read(&mut block) {
+                    Ok(0) => break,
+                    Ok(len) => {
+                        block.truncate(len);
+                        // If the importer has hung up, it has stopped caring about this entry
+                        if input.send(Ok(block)).is_err() {
+                            break;
+                        }
+                    }
+                    Err(e) => {
+                        let _ = input.send(Err(e));
+                        break;
+                    }
+                }
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Adds a node to a listing, creating any of its ancestor directories that are not yet present
+fn add_to_listing(listing: &mut Listing, directories: &mut HashSet<String>, node: Node) {
+    let parent = match node.path.rfind('/') {
+        Some(index) => node.path[..index].to_string(),
+        None => String::new(),
+    };
+    if !parent.is_empty() && !directories.contains(&parent) {
+        let directory = Node {
+            path: parent.clone(),
+            total_length: 0,
+            total_size: 0,
+            extents: None,
+            content_type: None,
+            node_type: NodeType::Directory {
+                children: Vec::new(),
+            },
+        };
+        add_to_listing(listing, directories, directory);
+    }
+    if node.is_directory() {
+        // Directories may have already been implicitly created by one of their children
+        if !directories.insert(node.path.clone()) {
+            return;
+        }
+    }
+    listing.add_child(&parent, node);
+}
+
+/// Reads a tar stream, storing each of its directories and regular files as objects in a new
+/// archive, which is then committed to the repository's manifest
+///
+/// The `Listing` of the archive is built from the tar headers, with any directories that are
+/// only implied by the paths of their children being created as needed. The mode of each entry is
+/// stored as a big endian `u32` in the `tar:mode` namespace, at the same path as the entry. Other
+/// kinds of entries, such as links and devices, are skipped.
+///
+/// The tar stream is read on its own thread, and the contents of each entry are streamed into the
+/// repository, so entries are never buffered in memory in their entirety.
+///
+/// # Errors
+///
+/// Will return `Err` if the tar stream is malformed, if any of the paths in the tar stream are not
+/// valid in an archive, or if storing an object in the repository fails.
+pub async fn tar_to_archive<R: Read + Send + 'static>(
+    input: R,
+    chunker: &impl AsyncChunker,
+    repo: &mut Repository<impl BackendClone>,
+    name: &str,
+) -> Result<StoredArchive> {
+    let (sender, entries) = async_channel::bounded(1);
+    thread::spawn(move || {
+        if let Err(e) = read_tar(input, &sender) {
+            let _ = block_on(sender.send(Err(e)));
+        }
+    });
+
+    let archive = ActiveArchive::new(name);
+    let mut data_archive = archive.namespace_append("");
+    let mut mode_archive = archive.namespace_append("tar").namespace_append("mode");
+    let mut listing = Listing::default();
+    let mut directories = HashSet::new();
+    while let Ok(entry) = entries.recv().await {
+        let entry = entry?;
+        let node = match entry.kind {
+            TarEntryKind::Directory => Node {
+                path: entry.path.clone(),
+                total_length: 0,
+                total_size: 0,
+                extents: None,
+                content_type: None,
+                node_type: NodeType::Directory {
+                    children: Vec::new(),
+                },
+            },
+            TarEntryKind::File(reader) => {
+                let extents = if entry.size > 0 {
+                    data_archive
+                        .put_object(chunker, repo, &entry.path, reader)
+                        .await?;
+                    Some(vec![Extent {
+                        start: 0,
+                        end: entry.size - 1,
+                    }])
+                } else {
+                    data_archive.put_empty(&entry.path).await;
+                    None
+                };
+                Node {
+                    path: entry.path.clone(),
+                    total_length: entry.size,
+                    total_size: entry.size,
+                    extents,
+                    content_type: None,
+                    node_type: NodeType::File,
+                }
+            }
+        };
+        mode_archive
+            .put_object(
+                chunker,
+                repo,
+                &entry.path,
+                Cursor::new(entry.mode.to_be_bytes()),
+            )
+            .await?;
+        add_to_listing(&mut listing, &mut directories, node);
+    }
+
+    archive.set_listing(listing).await;
+    let stored_archive = archive.store(repo).await;
+    repo.backend_manifest()
+        .write_archive(stored_archive.clone())
+        .await?;
+    repo.commit_index().await.map_err(ArchiveError::from)?;
+    Ok(stored_archive)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::chunker::FastCDC;
+    use crate::manifest::Manifest;
+    use crate::repository::backend::mem::Mem;
+    use crate::repository::{ChunkSettings, Key};
+    use rand::prelude::*;
+    use tar::{Builder, EntryType, Header};
+
+    #[test]
+    fn import_tar() {
+        smol::run(async {
+            let key = Key::random(32);
+            let settings = ChunkSettings::lightweight();
+            let backend = Mem::new(settings, key.clone(), 4);
+            let mut repo = Repository::with(backend, settings, key, 2).unwrap();
+            let chunker = FastCDC::default();
+
+            // Build up a tar in memory
+            let long_name = format!("dir/{}", "a".repeat(150));
+            let files = vec![
+                ("dir/file".to_string(), 50_000_usize),
+                ("empty".to_string(), 0),
+                ("implicit/parent/file".to_string(), 1000),
+                (long_name, 2000),
+            ];
+            let mut builder = Builder::new(Vec::new());
+            let mut header = Header::new_gnu();
+            header.set_entry_type(EntryType::Directory);
+            header.set_mode(0o755);
+            header.set_size(0);
+            builder
+                .append_data(&mut header, "dir/", std::io::empty())
+                .unwrap();
+            let mut contents = Vec::new();
+            for (path, len) in &files {
+                let mut data = vec![0_u8; *len];
+                thread_rng().fill_bytes(&mut data);
+                let mut header = Header::new_gnu();
+                header.set_entry_type(EntryType::Regular);
+                header.set_mode(0o644);
+                header.set_size(*len as u64);
+                builder
+                    .append_data(&mut header, path.as_str(), &data[..])
+                    .unwrap();
+                contents.push((path.clone(), data));
+            }
+            let tar = builder.into_inner().unwrap();
+
+            // Import it
+            let stored_archive = tar_to_archive(Cursor::new(tar), &chunker, &mut repo, "tar")
+                .await
+                .expect("Tar import failed");
+
+            // Make sure it made it into the manifest
+            let mut manifest = Manifest::load(&repo);
+            assert_eq!(manifest.archives().await, vec![stored_archive.clone()]);
+
+            // Check the listing and the contents of each file
+            let archive = stored_archive.load(&mut repo).await.unwrap();
+            let listing = archive.listing().await;
+            let paths: HashSet<String> = listing.into_iter().map(|x| x.path).collect();
+            for dir in &["dir", "implicit", "implicit/parent"] {
+                assert!(paths.contains(*dir));
+            }
+            let archive = archive.namespace_append("");
+            for (path, data) in contents {
+                assert!(paths.contains(&path));
+                let mut output = Vec::new();
+                archive
+                    .get_object(&mut repo, &path, &mut output)
+                    .await
+                    .unwrap();
+                assert_eq!(data, output);
+            }
+        });
+    }
+}
@@ -19,10 +19,22 @@ use uuid::Uuid;
 
 use std::convert::TryInto;
 
+#[cfg(not(target_arch = "wasm32"))]
+pub mod backup;
+pub mod bench;
 pub mod chunker;
+pub mod export;
+#[cfg(not(target_arch = "wasm32"))]
+pub mod facade;
+#[cfg(not(target_arch = "wasm32"))]
+pub mod import;
 pub mod manifest;
 pub mod prelude;
 pub mod repository;
+#[cfg(not(target_arch = "wasm32"))]
+pub mod restore;
+mod runtime;
+pub mod tune;
 
 pub const VERSION: &str = env!("CARGO_PKG_VERSION");
 
@@ -8,16 +8,32 @@
 //! to be triviallly serializeable and deserilazeable.
 pub mod archive;
 pub mod driver;
+pub mod filter;
+pub mod reader;
 pub mod target;
+#[cfg(not(target_arch = "wasm32"))]
+pub mod writer;
 
-pub use self::archive::{ActiveArchive, StoredArchive};
+pub use self::archive::{ActiveArchive, ArchiveError, StoredArchive};
+pub use self::filter::{FilterError, GlobFilter, IgnoreRules};
+pub use self::reader::ObjectReader;
+#[cfg(not(target_arch = "wasm32"))]
+pub use self::writer::ObjectWriter;
+use crate::repository::audit::AuditOperation;
 use crate::repository::backend::Manifest as BackendManifest;
 use crate::repository::backend::Result;
-use crate::repository::{Backend, BackendClone, ChunkSettings, Repository};
+#[cfg(feature = "recipients")]
+use crate::repository::encryption::PublicKey;
+use crate::repository::{
+    Backend, BackendClone, Chunk, ChunkBody, ChunkID, ChunkSettings, RepoStatistics, Repository,
+    RepositoryError,
+};
 
 use chrono::prelude::*;
 use serde::{Deserialize, Serialize};
 
+use std::collections::{HashMap, HashSet};
+
 /// Repository manifest
 ///
 /// This is the root object of the repository, all objects that are active can
@@ -53,7 +69,12 @@ impl<T: BackendClone> Manifest<T> {
 
     /// Commits an archive to the manifest, then the manifest to the repository
     ///
-    /// Consumes the repository while commiting it.
+    /// Consumes the repository while commiting it. The commit is recorded in the audit log, see
+    /// `Repository::audit_log`.
+    ///
+    /// # Errors
+    ///
+    /// Will return `Err` if writing the manifest or the audit log, or committing the index, fails
     ///
     /// # Panics
     ///
@@ -62,10 +83,138 @@ impl<T: BackendClone> Manifest<T> {
         &mut self,
         repo: &mut Repository<impl BackendClone>,
         archive: ActiveArchive,
-    ) -> Result<()> {
+    ) -> std::result::Result<(), ArchiveError> {
         let stored_archive = archive.store(repo).await;
-        self.internal_manifest.write_archive(stored_archive).await?;
-        repo.commit_index().await;
+        self.commit_stored(repo, stored_archive).await
+    }
+
+    /// Commits an archive sealed to the provided recipients to the manifest, then the manifest to
+    /// the repository, see `ActiveArchive::store_for`
+    ///
+    /// # Errors
+    ///
+    /// Will return `Err` if the public key of any of the recipients is invalid, or if writing the
+    /// manifest or the audit log, or committing the index, fails
+    ///
+    /// # Panics
+    ///
+    /// Will panic if commiting the archive to the repository fails
+    #[cfg(feature = "recipients")]
+    pub async fn commit_archive_for(
+        &mut self,
+        repo: &mut Repository<impl BackendClone>,
+        archive: ActiveArchive,
+        recipients: &[PublicKey],
+    ) -> std::result::Result<(), ArchiveError> {
+        let stored_archive = archive.store_for(repo, recipients).await?;
+        self.commit_stored(repo, stored_archive).await
+    }
+
+    /// Adds an archive that has already been stored to the manifest, and records it in the audit
+    /// log
+    async fn commit_stored(
+        &mut self,
+        repo: &mut Repository<impl BackendClone>,
+        stored_archive: StoredArchive,
+    ) -> std::result::Result<(), ArchiveError> {
+        let id = stored_archive.id();
+        self.internal_manifest
+            .write_archive(stored_archive)
+            .await
+            .map_err(RepositoryError::from)?;
+        repo.record_audit(AuditOperation::Store, Some(id)).await?;
+        repo.commit_index().await?;
+        Ok(())
+    }
+
+    /// Copies an archive, along with every chunk it references, out of another repository, and
+    /// commits it to this manifest
+    ///
+    /// Each chunk is read out of `source` and written to `repo` with `repo`'s chunk settings and
+    /// key, so chunk ids are recomputed, and chunks `repo` already has are deduplicated as they
+    /// would be for a new archive. The name, timestamp, listing, tags, and comment of the archive
+    /// are preserved.
+    ///
+    /// If the two repositories share a key and HMAC algorithm, chunk ids do not change, and each
+    /// chunk is instead copied byte for byte with `Repository::read_chunk_raw`, without being
+    /// decoded and packed again. Archives sealed to a set of recipients can only be copied this
+    /// way, as the chunk ids inside of them can not be rewritten without unsealing them.
+    ///
+    /// Returns the pointer to the copy of the archive in `repo`
+    ///
+    /// # Errors
+    ///
+    /// Will return `Err` if the archive can not be loaded from `source`, or reading or writing any
+    /// of its chunks fails. Returns `ArchiveError::Sealed` for sealed archives if the two
+    /// repositories do not share a key and HMAC algorithm.
+    pub async fn copy_archive(
+        &mut self,
+        repo: &mut Repository<impl BackendClone>,
+        source: &mut Repository<impl BackendClone>,
+        archive: &StoredArchive,
+    ) -> std::result::Result<StoredArchive, ArchiveError> {
+        let raw = source.key() == repo.key()
+            && source.chunk_settings().hmac == repo.chunk_settings().hmac;
+        if archive.is_sealed(source).await? {
+            if !raw {
+                return Err(ArchiveError::Sealed);
+            }
+            for id in archive.referenced_chunks(source).await? {
+                let (header, body) = source.read_chunk_raw(id).await?;
+                repo.write_raw(Chunk::unsplit(header, ChunkBody(body)))
+                    .await?;
+            }
+            self.commit_stored(repo, archive.clone()).await?;
+            return Ok(archive.clone());
+        }
+        let mut copy = archive.load(source).await?.into_archive().await;
+        // Chunks are often referenced many times, so only copy each one once
+        let mut copied: HashMap<ChunkID, ChunkID> = HashMap::new();
+        for locations in copy.objects.values_mut() {
+            for location in locations {
+                let id = if let Some(id) = copied.get(&location.id) {
+                    *id
+                } else {
+                    let (id, _) = if raw {
+                        let (header, body) = source.read_chunk_raw(location.id).await?;
+                        repo.write_raw(Chunk::unsplit(header, ChunkBody(body)))
+                            .await?
+                    } else {
+                        let data = source.read_chunk(location.id).await?;
+                        repo.write_chunk(data).await?
+                    };
+                    copied.insert(location.id, id);
+                    id
+                };
+                location.id = id;
+            }
+        }
+        let stored_archive = ActiveArchive::from_archive(copy).store(repo).await;
+        self.commit_stored(repo, stored_archive.clone()).await?;
+        Ok(stored_archive)
+    }
+
+    /// Removes an archive from the manifest
+    ///
+    /// The chunks of the archive are left in the repository until they are found unreachable by
+    /// `gc`. The removal is recorded in the audit log.
+    ///
+    /// # Errors
+    ///
+    /// Will return `Err` if the backend does not support removing archives, or if writing the
+    /// manifest or the audit log, or committing the index, fails
+    pub async fn delete_archive(
+        &mut self,
+        repo: &mut Repository<impl BackendClone>,
+        archive: &StoredArchive,
+    ) -> std::result::Result<(), ArchiveError> {
+        self.internal_manifest
+            .delete_archive(archive.clone())
+            .await
+            .map_err(RepositoryError::from)?;
+        repo.record_audit(AuditOperation::Delete, Some(archive.id()))
+            .await?;
+        repo.commit_index().await?;
         Ok(())
     }
 
@@ -76,6 +225,135 @@ impl<T: BackendClone> Manifest<T> {
         self.internal_manifest.archive_iterator().await.collect()
     }
 
+    /// Finds the archive a user provided string refers to
+    ///
+    /// The query is resolved, in order of preference, as:
+    ///
+    /// 1. The complete hex id of an archive
+    /// 2. The index of an archive in the list returned by `archives`, or the name of an archive
+    /// 3. A hex prefix of exactly one archive's id, in the style of git's abbreviated hashes, see
+    ///    `Repository::find_chunks_by_prefix`
+    ///
+    /// When several archives share a name, the first is returned. Returns `None` if nothing
+    /// matches, or a prefix matches more than one archive.
+    ///
+    /// # Errors
+    ///
+    /// Will return `Err` if the metadata of an archive can not be loaded while searching by name
+    pub async fn find_archive(
+        &mut self,
+        repo: &mut Repository<impl BackendClone>,
+        query: &str,
+    ) -> std::result::Result<Option<StoredArchive>, ArchiveError> {
+        let archives = self.archives().await;
+        if let Ok(id) = query.parse::<ChunkID>() {
+            if let Some(archive) = archives.iter().find(|x| x.id() == id) {
+                return Ok(Some(archive.clone()));
+            }
+        }
+        for (index, stored_archive) in archives.iter().enumerate() {
+            if index.to_string() == query {
+                return Ok(Some(stored_archive.clone()));
+            }
+            // The names of sealed archives can not be read, they can only be found by id
+            match stored_archive.load_metadata(repo).await {
+                Ok(archive) if archive.name == query => return Ok(Some(stored_archive.clone())),
+                Ok(_) | Err(ArchiveError::Sealed) => (),
+                Err(e) => return Err(e),
+            }
+        }
+        // Every id starts with the empty prefix
+        if query.is_empty() {
+            return Ok(None);
+        }
+        let prefixed: HashSet<ChunkID> = repo
+            .find_chunks_by_prefix(query)
+            .await
+            .into_iter()
+            .collect();
+        let mut matching = archives.into_iter().filter(|x| prefixed.contains(&x.id()));
+        match (matching.next(), matching.next()) {
+            (Some(archive), None) => Ok(Some(archive)),
+            _ => Ok(None),
+        }
+    }
+
+    /// Computes the set of chunks reachable from the archives in this manifest
+    ///
+    /// This includes both the chunks the archive metadata is stored in, as well as every chunk
+    /// referenced by the objects in each archive.
+    ///
+    /// # Errors
+    ///
+    /// Will return `Err` if any of the archives can not be loaded
+    pub async fn live_chunks(
+        &mut self,
+        repo: &mut Repository<impl BackendClone>,
+    ) -> std::result::Result<HashSet<ChunkID>, ArchiveError> {
+        let mut live = HashSet::new();
+        for stored_archive in self.archives().await {
+            live.extend(stored_archive.referenced_chunks(repo).await?);
+        }
+        Ok(live)
+    }
+
+    /// Performs a mark-and-sweep garbage collection of the repository
+    ///
+    /// Takes exclusive access to the repository, marks every chunk reachable from the archives in
+    /// the manifest as live, and then removes every other chunk from the repository, reclaiming
+    /// the space they used where the backend supports it. See `Repository::gc`.
+    ///
+    /// Returns the number of chunks that were removed.
+    ///
+    /// # Errors
+    ///
+    /// Will return `Err` if another connection has the repository open, if any of the archives can
+    /// not be loaded, or if the backend fails to remove any of the dead chunks
+    pub async fn gc(
+        &mut self,
+        repo: &mut Repository<impl BackendClone>,
+    ) -> std::result::Result<usize, ArchiveError> {
+        repo.lock_exclusive().await?;
+        let result = match self.live_chunks(repo).await {
+            Ok(live) => repo.sweep(&live).await.map_err(ArchiveError::from),
+            Err(e) => Err(e),
+        };
+        repo.unlock_exclusive().await?;
+        result
+    }
+
+    /// Collects statistics about the repository, see `Repository::statistics`
+    ///
+    /// In addition to the chunk level statistics, this loads every archive in the manifest to
+    /// total up the number of bytes referenced by their objects, and the number of bytes of
+    /// object data held by the unique chunks they reference, allowing the deduplication and
+    /// compression ratios to be computed. The lengths of the chunks' contents are taken from the
+    /// archives, so no chunk holding object data is read or decrypted.
+    ///
+    /// # Errors
+    ///
+    /// Will return `Err` if any of the archives can not be loaded, or if reading any of the
+    /// chunks fails
+    pub async fn statistics(
+        &mut self,
+        repo: &mut Repository<impl BackendClone>,
+    ) -> std::result::Result<RepoStatistics, ArchiveError> {
+        let mut stats = repo.statistics().await?;
+        let mut chunk_lengths = HashMap::new();
+        for stored_archive in self.archives().await {
+            let archive = match stored_archive.load(repo).await {
+                Ok(archive) => archive,
+                Err(ArchiveError::Sealed) => continue,
+                Err(e) => return Err(e),
+            };
+            stats.referenced_bytes += archive.referenced_bytes();
+            chunk_lengths.extend(archive.chunk_lengths());
+        }
+        stats.logical_bytes = chunk_lengths.values().sum();
+        stats.update_ratios();
+        Ok(stats)
+    }
+
     /// Provides the timestamp of the manifest's last modification
     pub async fn timestamp(&mut self) -> Result<DateTime<FixedOffset>> {
         self.internal_manifest.last_modification().await
@@ -85,7 +363,10 @@ impl<T: BackendClone> Manifest<T> {
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::chunker::FastCDC;
     use crate::repository::*;
+    use rand::prelude::*;
+    use std::io::Cursor;
 
     #[test]
     fn chunk_settings_sanity() {
@@ -98,7 +379,7 @@ mod tests {
 
             let key = Key::random(32);
             let backend = crate::repository::backend::mem::Mem::new(settings, key.clone(), 4);
-            let repo = Repository::with(backend, settings, key, 2);
+            let repo = Repository::with(backend, settings, key, 2).unwrap();
             let mut manifest = Manifest::load(&repo);
 
             manifest.set_chunk_settings(settings).await.unwrap();
@@ -114,7 +395,7 @@ mod tests {
             let settings = ChunkSettings::lightweight();
             let key = Key::random(32);
             let backend = crate::repository::backend::mem::Mem::new(settings, key.clone(), 4);
-            let repo = Repository::with(backend.clone(), settings, key, 2);
+            let repo = Repository::with(backend.clone(), settings, key, 2).unwrap();
 
             let mut manifest = Manifest::load(&repo);
 
@@ -129,4 +410,291 @@ mod tests {
             assert!(time2 > time1);
         });
     }
+
+    // Chunks shared with a live archive must survive collection, while chunks only referenced by
+    // an archive that is not in the manifest must be removed
+    #[test]
+    fn gc_removes_unreachable() {
+        smol::run(async {
+            let settings = ChunkSettings::lightweight();
+            let key = Key::random(32);
+            let backend = crate::repository::backend::mem::Mem::new(settings, key.clone(), 4);
+            let mut repo = Repository::with(backend, settings, key, 2).unwrap();
+            let mut manifest = Manifest::load(&repo);
+            let chunker = FastCDC::default();
+
+            let mut shared = vec![0_u8; 16384];
+            let mut unique = vec![0_u8; 16384];
+            thread_rng().fill_bytes(&mut shared);
+            thread_rng().fill_bytes(&mut unique);
+
+            // The kept archive holds only the shared object
+            let mut kept = ActiveArchive::new("kept");
+            kept.put_object(&chunker, &mut repo, "shared", Cursor::new(shared.clone()))
+                .await
+                .unwrap();
+            let shared_ids = kept.chunk_ids();
+            manifest.commit_archive(&mut repo, kept).await.unwrap();
+            // The dropped archive holds both, and is stored but never added to the manifest,
+            // making it unreachable
+            let mut dropped = ActiveArchive::new("dropped");
+            dropped
+                .put_object(&chunker, &mut repo, "shared", Cursor::new(shared.clone()))
+                .await
+                .unwrap();
+            dropped
+                .put_object(&chunker, &mut repo, "unique", Cursor::new(unique))
+                .await
+                .unwrap();
+            let unique_ids: HashSet<ChunkID> = dropped
+                .chunk_ids()
+                .difference(&shared_ids)
+                .copied()
+                .collect();
+            let dropped_id = dropped.store(&mut repo).await.id();
+
+            let removed = manifest
+                .gc(&mut repo)
+                .await
+                .expect("Garbage collection failed");
+            // Both the dropped archive's own chunk and its objects chunk are unreachable
+            assert_eq!(removed, unique_ids.len() + 2);
+            for id in &shared_ids {
+                assert!(repo.has_chunk(*id).await.unwrap());
+            }
+            for id in &unique_ids {
+                assert!(!repo.has_chunk(*id).await.unwrap());
+            }
+            assert!(!repo.has_chunk(dropped_id).await.unwrap());
+
+            // The kept archive should still be fully restorable
+            let archive = manifest.archives().await[0].load(&mut repo).await.unwrap();
+            let mut output = Cursor::new(Vec::new());
+            archive
+                .get_object(&mut repo, "shared", &mut output)
+                .await
+                .unwrap();
+            assert_eq!(shared, output.into_inner());
+        });
+    }
+
+    // Deleting an archive and then collecting must free the chunks only it referenced, and leave
+    // the ones it shared with other archives
+    #[test]
+    fn delete_archive_then_gc_frees_unique_chunks() {
+        smol::run(async {
+            let settings = ChunkSettings::lightweight();
+            let key = Key::random(32);
+            let backend = crate::repository::backend::mem::Mem::new(settings, key.clone(), 4);
+            let mut repo = Repository::with(backend, settings, key, 2).unwrap();
+            let mut manifest = Manifest::load(&repo);
+            let chunker = FastCDC::default();
+
+            let mut shared = vec![0_u8; 16384];
+            let mut unique = vec![0_u8; 16384];
+            thread_rng().fill_bytes(&mut shared);
+            thread_rng().fill_bytes(&mut unique);
+
+            let mut kept = ActiveArchive::new("kept");
+            kept.put_object(&chunker, &mut repo, "shared", Cursor::new(shared.clone()))
+                .await
+                .unwrap();
+            let shared_ids = kept.chunk_ids();
+            manifest.commit_archive(&mut repo, kept).await.unwrap();
+            let mut deleted = ActiveArchive::new("deleted");
+            deleted
+                .put_object(&chunker, &mut repo, "shared", Cursor::new(shared.clone()))
+                .await
+                .unwrap();
+            deleted
+                .put_object(&chunker, &mut repo, "unique", Cursor::new(unique))
+                .await
+                .unwrap();
+            let unique_ids: HashSet<ChunkID> = deleted
+                .chunk_ids()
+                .difference(&shared_ids)
+                .copied()
+                .collect();
+            assert!(!unique_ids.is_empty());
+            manifest.commit_archive(&mut repo, deleted).await.unwrap();
+
+            // Nothing is garbage while both archives are in the manifest
+            assert_eq!(manifest.gc(&mut repo).await.unwrap(), 0);
+            let deleted = manifest
+                .find_archive(&mut repo, "deleted")
+                .await
+                .unwrap()
+                .unwrap();
+            manifest.delete_archive(&mut repo, &deleted).await.unwrap();
+            assert_eq!(manifest.archives().await.len(), 1);
+
+            let removed = manifest.gc(&mut repo).await.unwrap();
+            // The archive's own chunk and its objects chunk go along with its unique data
+            assert_eq!(removed, unique_ids.len() + 2);
+            for id in &unique_ids {
+                assert!(!repo.has_chunk(*id).await.unwrap());
+            }
+            assert!(!repo.has_chunk(deleted.id()).await.unwrap());
+            for id in &shared_ids {
+                assert!(repo.has_chunk(*id).await.unwrap());
+            }
+
+            let operations: Vec<AuditOperation> = repo
+                .audit_log()
+                .await
+                .unwrap()
+                .iter()
+                .map(AuditEntry::operation)
+                .collect();
+            assert!(operations.contains(&AuditOperation::Delete));
+        });
+    }
+
+    #[test]
+    fn statistics_counts_references() {
+        smol::run(async {
+            let settings = ChunkSettings::lightweight();
+            let key = Key::random(32);
+            let backend = crate::repository::backend::mem::Mem::new(settings, key.clone(), 4);
+            let mut repo = Repository::with(backend, settings, key, 2).unwrap();
+            let mut manifest = Manifest::load(&repo);
+            let chunker = FastCDC::default();
+
+            let mut data = vec![0_u8; 16384];
+            thread_rng().fill_bytes(&mut data);
+            // Store the same data twice, so it is referenced twice but only stored once
+            let mut archive = ActiveArchive::new("test");
+            for path in &["one", "two"] {
+                archive
+                    .put_object(&chunker, &mut repo, path, Cursor::new(data.clone()))
+                    .await
+                    .unwrap();
+            }
+            manifest.commit_archive(&mut repo, archive).await.unwrap();
+
+            let stats = manifest
+                .statistics(&mut repo)
+                .await
+                .expect("Collecting statistics failed");
+            assert_eq!(stats.archives, 1);
+            assert_eq!(stats.referenced_bytes, 2 * 16384);
+            assert_eq!(stats.logical_bytes, 16384);
+            assert!((stats.dedup_ratio - 2.0).abs() < f64::EPSILON);
+        });
+    }
+
+    #[test]
+    fn find_archive_by_id_name_and_prefix() {
+        smol::run(async {
+            let settings = ChunkSettings::lightweight();
+            let key = Key::random(32);
+            let backend = crate::repository::backend::mem::Mem::new(settings, key.clone(), 4);
+            let mut repo = Repository::with(backend, settings, key, 2).unwrap();
+            let mut manifest = Manifest::load(&repo);
+
+            for name in &["first", "second"] {
+                manifest
+                    .commit_archive(&mut repo, ActiveArchive::new(name))
+                    .await
+                    .unwrap();
+            }
+            let archives = manifest.archives().await;
+            let ids: Vec<ChunkID> = archives.iter().map(StoredArchive::id).collect();
+
+            // Full ids round trip through Display and FromStr
+            for id in &ids {
+                let found = manifest
+                    .find_archive(&mut repo, &id.to_string())
+                    .await
+                    .unwrap();
+                assert_eq!(found.map(|x| x.id()), Some(*id));
+            }
+            // Names and indexes
+            let found = manifest.find_archive(&mut repo, "second").await.unwrap();
+            assert_eq!(found.map(|x| x.id()), Some(ids[1]));
+            let found = manifest.find_archive(&mut repo, "0").await.unwrap();
+            assert_eq!(found.map(|x| x.id()), Some(ids[0]));
+            // A short prefix that the other archive does not share, long enough to never be
+            // mistaken for an index
+            let hex = ids[0].to_hex();
+            let length = (8..=hex.len())
+                .find(|&length| !ids[1].to_hex().starts_with(&hex[..length]))
+                .unwrap();
+            let found = manifest
+                .find_archive(&mut repo, &hex[..length])
+                .await
+                .unwrap();
+            assert_eq!(found.map(|x| x.id()), Some(ids[0]));
+            // Unknown names and empty queries resolve to nothing
+            assert!(manifest
+                .find_archive(&mut repo, "missing")
+                .await
+                .unwrap()
+                .is_none());
+            assert!(manifest
+                .find_archive(&mut repo, "")
+                .await
+                .unwrap()
+                .is_none());
+        });
+    }
+
+    #[test]
+    fn operations_recorded_in_audit_log() {
+        smol::run(async {
+            let settings = ChunkSettings::lightweight();
+            let key = Key::random(32);
+            let backend = crate::repository::backend::mem::Mem::new(settings, key.clone(), 4);
+            let mut repo = Repository::with(backend, settings, key, 2).unwrap();
+            let mut manifest = Manifest::load(&repo);
+
+            assert!(repo.audit_log().await.unwrap().is_empty());
+            assert!(repo.verify_audit_log().await.unwrap());
+            for name in &["first", "second", "third"] {
+                manifest
+                    .commit_archive(&mut repo, ActiveArchive::new(name))
+                    .await
+                    .unwrap();
+            }
+            let ids: Vec<Option<ChunkID>> = manifest
+                .archives()
+                .await
+                .iter()
+                .map(|x| Some(x.id()))
+                .collect();
+            let deleted = manifest.archives().await.pop().unwrap();
+            manifest.delete_archive(&mut repo, &deleted).await.unwrap();
+            // Collecting garbage must leave the entries of the log in place
+            manifest.gc(&mut repo).await.unwrap();
+
+            let log = repo.audit_log().await.unwrap();
+            let operations: Vec<AuditOperation> = log.iter().map(AuditEntry::operation).collect();
+            assert_eq!(
+                operations,
+                vec![
+                    AuditOperation::Store,
+                    AuditOperation::Store,
+                    AuditOperation::Store,
+                    AuditOperation::Delete,
+                    AuditOperation::Prune
+                ]
+            );
+            assert_eq!(log[0].archive(), ids[0]);
+            assert_eq!(log[1].archive(), ids[1]);
+            assert_eq!(log[3].archive(), Some(deleted.id()));
+            assert_eq!(log[4].archive(), None);
+            assert!(log[0].timestamp() <= log[4].timestamp());
+            assert!(repo.verify_audit_log().await.unwrap());
+
+            // Dropping the last entry no longer matches the length recorded in the manifest
+            repo.backend_manifest()
+                .write_audit_head(AuditHead {
+                    entry: log[4].previous().unwrap(),
+                    length: 5,
+                })
+                .await
+                .unwrap();
+            assert!(!repo.verify_audit_log().await.unwrap());
+        });
+    }
 }
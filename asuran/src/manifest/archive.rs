@@ -1,6 +1,18 @@
 use crate::chunker::AsyncChunker;
+use crate::manifest::reader::ObjectReader;
+#[cfg(not(target_arch = "wasm32"))]
+use crate::manifest::writer::ObjectWriter;
 use crate::repository::backend::common::manifest::ManifestTransaction;
-use crate::repository::{BackendClone, ChunkID, Repository};
+#[cfg(feature = "recipients")]
+use crate::repository::encryption::{
+    unwrap_key, wrap_key, PublicKey, RecipientError, SecretKey, WrappedKey,
+};
+use crate::repository::{BackendClone, Chunk, ChunkID, ChunkSettings, Key, Repository, WriteStats};
+#[cfg(feature = "recipients")]
+use crate::repository::{Encryption, HMAC};
+#[cfg(not(target_arch = "wasm32"))]
+use crate::restore::{extract_directory, ExtractOptions, RestoreError, RestoreProgress};
+use crate::runtime;
 
 pub use asuran_core::manifest::archive::{Archive, ChunkLocation, Extent};
 pub use asuran_core::manifest::listing::{Listing, Node, NodeType};
@@ -9,13 +21,12 @@ use async_lock::Lock;
 use chrono::prelude::*;
 use dashmap::DashMap;
 use futures::future::join_all;
-use futures::stream::StreamExt;
+use futures::stream::{self, StreamExt, TryStreamExt};
 use serde::{Deserialize, Serialize};
 use serde_cbor::Serializer;
-use smol::Task;
 use thiserror::Error;
 
-use std::collections::VecDeque;
+use std::collections::{HashMap, HashSet, VecDeque};
 use std::io::{Read, Write};
 use std::sync::Arc;
 
@@ -30,10 +41,89 @@ pub enum ArchiveError {
     Repository(#[from] crate::repository::RepositoryError),
     #[error("Failed to deserialize archive")]
     ArchiveDeserialization,
+    #[error("Archive is sealed, and can only be loaded with the secret key of a recipient")]
+    Sealed,
+    #[cfg(feature = "recipients")]
+    #[error("Recipient Error: {0}")]
+    Recipient(#[from] RecipientError),
+}
+
+impl ArchiveError {
+    /// Returns a short, stable, `snake_case` name for the kind of this error
+    ///
+    /// Errors originating in the repository report the kind of the underlying
+    /// `RepositoryError`.
+    pub fn kind(&self) -> &'static str {
+        match self {
+            ArchiveError::Chunker(_) => "chunker_error",
+            ArchiveError::IO(_) => "io",
+            ArchiveError::Repository(e) => e.kind(),
+            ArchiveError::ArchiveDeserialization => "archive_deserialization_failed",
+            ArchiveError::Sealed => "archive_sealed",
+            #[cfg(feature = "recipients")]
+            ArchiveError::Recipient(RecipientError::NotARecipient) => "not_a_recipient",
+            #[cfg(feature = "recipients")]
+            ArchiveError::Recipient(_) => "invalid_recipient",
+        }
+    }
 }
 
 type Result<T> = std::result::Result<T, ArchiveError>;
 
+/// Without the `recipients` feature, the wrapped keys of a sealed archive are never unwrapped, so
+/// they are kept opaque. Sealed archives are still recognized, and their chunks kept alive.
+#[cfg(not(feature = "recipients"))]
+type WrappedKey = serde_cbor::Value;
+
+/// The form an archive sealed to a set of recipients is stored in
+///
+/// The archive, and the chunk holding its objects map, are packed under a per-archive key, which
+/// is wrapped to each recipient. The ids of the chunks the archive references are kept readable
+/// with the repository key, as they are already visible in the shared chunk store, and without
+/// them the repository could not be garbage collected.
+#[derive(Serialize, Deserialize)]
+#[cfg_attr(not(feature = "recipients"), allow(dead_code))]
+struct SealedArchive {
+    /// The archive key, wrapped to each recipient
+    recipients: Vec<WrappedKey>,
+    /// The archive, packed under the archive key
+    archive: Chunk,
+    /// Pointer to the chunk the objects map is stored in, packed under the archive key
+    objects_chunk: ChunkID,
+    /// Every chunk referenced by the objects in the archive
+    chunk_ids: Vec<ChunkID>,
+}
+
+/// The two forms an archive can be stored in
+enum StoredForm {
+    Plain(Archive),
+    Sealed(SealedArchive),
+}
+
+/// Packs bytes under an archive key
+///
+/// Encryption is always used, regardless of the settings of the repository, as otherwise sealing
+/// would not hide anything.
+#[cfg(feature = "recipients")]
+fn seal(bytes: Vec<u8>, settings: ChunkSettings, key: &Key) -> Chunk {
+    Chunk::pack(
+        bytes,
+        settings.compression,
+        Encryption::new_aes256ctr(),
+        HMAC::SHA256,
+        key,
+    )
+}
+
+/// Unpacks a serialized chunk produced by `seal`
+fn unseal(bytes: &[u8], key: &Key) -> Result<Vec<u8>> {
+    let chunk: Chunk =
+        serde_cbor::de::from_slice(bytes).map_err(|_| ArchiveError::ArchiveDeserialization)?;
+    chunk
+        .unpack(key)
+        .map_err(|_| ArchiveError::ArchiveDeserialization)
+}
+
 /// A 'heavy' pointer to a an `Archive` in a repository.
 ///
 /// Contains the `ChunkID` of the chunk the `Archive` is serialized in, as well as
@@ -53,14 +143,147 @@ pub struct StoredArchive {
 
 impl StoredArchive {
     /// Loads the archive metadata from the repository and unpacks it for use
+    ///
+    /// Returns `ArchiveError::Sealed` for archives sealed to a set of recipients, see
+    /// `StoredArchive::load_as`.
     pub async fn load(&self, repo: &mut Repository<impl BackendClone>) -> Result<ActiveArchive> {
+        let dumb_archive = self.load_metadata(repo).await?;
+        Self::load_objects(repo, dumb_archive, None).await
+    }
+
+    /// Loads an archive that may be sealed to a set of recipients, using the secret key of one of
+    /// them
+    ///
+    /// Archives that are not sealed are loaded as with `StoredArchive::load`.
+    #[cfg(feature = "recipients")]
+    pub async fn load_as(
+        &self,
+        repo: &mut Repository<impl BackendClone>,
+        identity: &SecretKey,
+    ) -> Result<ActiveArchive> {
+        match self.read_stored(repo).await? {
+            StoredForm::Plain(dumb_archive) => Self::load_objects(repo, dumb_archive, None).await,
+            StoredForm::Sealed(sealed) => {
+                let key = unwrap_key(&sealed.recipients, identity)?;
+                let dumb_archive = Self::open_sealed(&sealed, &key)?;
+                Self::load_objects(repo, dumb_archive, Some(&key)).await
+            }
+        }
+    }
+
+    /// Loads the archive from the repository, without loading the locations of the chunks
+    /// making up its objects
+    ///
+    /// The `objects` map of the returned `Archive` will be empty, unless the archive was stored
+    /// before the map was split out into its own chunk.
+    ///
+    /// Returns `ArchiveError::Sealed` for archives sealed to a set of recipients, see
+    /// `StoredArchive::load_metadata_as`.
+    pub async fn load_metadata(&self, repo: &mut Repository<impl BackendClone>) -> Result<Archive> {
+        match self.read_stored(repo).await? {
+            StoredForm::Plain(dumb_archive) => Ok(dumb_archive),
+            StoredForm::Sealed(_) => Err(ArchiveError::Sealed),
+        }
+    }
+
+    /// Loads the metadata of an archive that may be sealed to a set of recipients, using the
+    /// secret key of one of them, see `StoredArchive::load_metadata`
+    #[cfg(feature = "recipients")]
+    pub async fn load_metadata_as(
+        &self,
+        repo: &mut Repository<impl BackendClone>,
+        identity: &SecretKey,
+    ) -> Result<Archive> {
+        match self.read_stored(repo).await? {
+            StoredForm::Plain(dumb_archive) => Ok(dumb_archive),
+            StoredForm::Sealed(sealed) => {
+                let key = unwrap_key(&sealed.recipients, identity)?;
+                Self::open_sealed(&sealed, &key)
+            }
+        }
+    }
+
+    /// Returns true if this archive is sealed to a set of recipients
+    pub async fn is_sealed(&self, repo: &mut Repository<impl BackendClone>) -> Result<bool> {
+        Ok(matches!(
+            self.read_stored(repo).await?,
+            StoredForm::Sealed(_)
+        ))
+    }
+
+    /// Provides every chunk reachable from this archive, including the chunks the archive itself,
+    /// and its objects map, are stored in
+    ///
+    /// This does not require the archive to be unsealed.
+    pub async fn referenced_chunks(
+        &self,
+        repo: &mut Repository<impl BackendClone>,
+    ) -> Result<HashSet<ChunkID>> {
+        let mut chunks = HashSet::new();
+        chunks.insert(self.id);
+        match self.read_stored(repo).await? {
+            StoredForm::Plain(dumb_archive) => {
+                let archive = Self::load_objects(repo, dumb_archive, None).await?;
+                chunks.extend(archive.objects_chunk());
+                chunks.extend(archive.chunk_ids());
+            }
+            StoredForm::Sealed(sealed) => {
+                chunks.insert(sealed.objects_chunk);
+                chunks.extend(sealed.chunk_ids);
+            }
+        }
+        Ok(chunks)
+    }
+
+    /// Reads the chunk the archive is stored in, in whichever form it was stored
+    async fn read_stored(&self, repo: &mut Repository<impl BackendClone>) -> Result<StoredForm> {
         let bytes = repo.read_chunk(self.id).await?;
-        let dumb_archive: Archive = serde_cbor::de::from_slice(&bytes[..])
+        // A plain archive never has the fields of a sealed one, so this can not misfire
+        if let Ok(sealed) = serde_cbor::de::from_slice(&bytes[..]) {
+            return Ok(StoredForm::Sealed(sealed));
+        }
+        serde_cbor::de::from_slice(&bytes[..])
+            .map(StoredForm::Plain)
+            .map_err(|_| ArchiveError::ArchiveDeserialization)
+    }
+
+    /// Unpacks the archive out of a sealed archive, with the unwrapped archive key
+    #[cfg(feature = "recipients")]
+    fn open_sealed(sealed: &SealedArchive, key: &Key) -> Result<Archive> {
+        let bytes = sealed
+            .archive
+            .unpack(key)
             .map_err(|_| ArchiveError::ArchiveDeserialization)?;
+        serde_cbor::de::from_slice(&bytes[..]).map_err(|_| ArchiveError::ArchiveDeserialization)
+    }
+
+    /// Loads the objects map of an archive out of its own chunk, unsealing it with `key` if the
+    /// archive was sealed
+    async fn load_objects(
+        repo: &mut Repository<impl BackendClone>,
+        mut dumb_archive: Archive,
+        key: Option<&Key>,
+    ) -> Result<ActiveArchive> {
+        if let Some(objects_chunk) = dumb_archive.objects_chunk {
+            let mut bytes = repo.read_chunk(objects_chunk).await?;
+            if let Some(key) = key {
+                bytes = unseal(&bytes, key)?;
+            }
+            dumb_archive.objects = serde_cbor::de::from_slice(&bytes[..])
+                .map_err(|_| ArchiveError::ArchiveDeserialization)?;
+        }
         let archive = ActiveArchive::from_archive(dumb_archive);
         Ok(archive)
     }
 
+    /// Loads only the listing of the archive
+    ///
+    /// This is considerably cheaper than `load` for archives with many objects, as the locations
+    /// of the chunks making up the objects are not read.
+    pub async fn load_listing(&self, repo: &mut Repository<impl BackendClone>) -> Result<Listing> {
+        Ok(self.load_metadata(repo).await?.listing)
+    }
+
     /// Constructs a dummy archive object used for testing
     #[cfg(test)]
     pub fn dummy_archive() -> StoredArchive {
@@ -90,6 +313,59 @@ impl From<ManifestTransaction> for StoredArchive {
     }
 }
 
+/// Maximum number of extents of a sparse object that are chunked and stored at once
+const MAX_CONCURRENT_EXTENTS: usize = 8;
+
+/// Chunks and stores a single extent of an object, returning the locations of its chunks, and the
+/// counts of the chunks it wrote
+async fn put_extent<R: Read + Send + 'static>(
+    chunker: &impl AsyncChunker,
+    repository: Repository<impl BackendClone>,
+    extent: Extent,
+    read: R,
+    settings: Option<ChunkSettings>,
+) -> Result<(Vec<ChunkLocation>, WriteStats)> {
+    let max_futs = 100;
+    let mut locations = Vec::new();
+    let mut stats = WriteStats::default();
+    let mut futs = VecDeque::new();
+    let mut slices = chunker.async_chunk(read, repository.queue_depth);
+    let mut start = extent.start;
+    while let Some(result) = slices.next().await {
+        let data = result?;
+        let end = start + (data.len() as u64);
+
+        let mut repository = repository.clone();
+        futs.push_back(runtime::spawn(async move {
+            let mut stats = WriteStats::default();
+            let (id, _) = repository
+                .write_chunk_counted(data, settings, &mut stats)
+                .await?;
+            let location = ChunkLocation {
+                id,
+                start,
+                length: end - start + 1,
+            };
+            let result: Result<(ChunkLocation, WriteStats)> = Ok((location, stats));
+            result
+        }));
+        while futs.len() >= max_futs {
+            // This unwrap is sound, since we can only be here if futs has elements in it
+            let (loc, chunk_stats) = futs.pop_front().unwrap().await?;
+            locations.push(loc);
+            stats += chunk_stats;
+        }
+        start = end + 1;
+    }
+    let locs = join_all(futs).await;
+    for loc in locs {
+        let (loc, chunk_stats) = loc?;
+        locations.push(loc);
+        stats += chunk_stats;
+    }
+    Ok((locations, stats))
+}
+
 #[derive(Clone, Debug)]
 /// A currently open and able to be modified `Archive`
 ///
@@ -118,6 +394,12 @@ pub struct ActiveArchive {
     timestamp: DateTime<FixedOffset>,
     /// The object listing of the archive
     listing: Arc<Lock<Listing>>,
+    /// The chunk the objects map was stored in, if this archive was loaded from the repository
+    objects_chunk: Option<ChunkID>,
+    /// User provided tags, kept in sorted order without duplicates
+    tags: Vec<String>,
+    /// User provided description of the archive
+    comment: Option<String>,
 }
 
 impl ActiveArchive {
@@ -129,6 +411,9 @@ impl ActiveArchive {
             namespace: Vec::new(),
             timestamp: Local::now().with_timezone(Local::now().offset()),
             listing: Arc::new(Lock::new(Listing::default())),
+            objects_chunk: None,
+            tags: Vec::new(),
+            comment: None,
         }
     }
 
@@ -136,6 +421,9 @@ impl ActiveArchive {
     ///
     /// Will read holes as 0s
     ///
+    /// Returns the counts of the chunks written for the object, and how many of them were already
+    /// present in the repository.
+    ///
     /// This is implemented as a thin wrapper around `put_sparse_object`
     pub async fn put_object<R: Read + Send + 'static>(
         &mut self,
@@ -143,7 +431,7 @@ impl ActiveArchive {
         repository: &mut Repository<impl BackendClone>,
         path: &str,
         from_reader: R,
-    ) -> Result<()> {
+    ) -> Result<WriteStats> {
         // We take advantage of put_sparse_object's behavior of reading past the given end if the
         // given reader is actually longer
         let extent = Extent { start: 0, end: 0 };
@@ -152,55 +440,149 @@ impl ActiveArchive {
             .await
     }
 
+    /// Places an object into a archive, as a whole, using the compression from the provided
+    /// settings for its chunks in place of the repository's default
+    ///
+    /// The settings must be compatible with the repository, see
+    /// `Repository::check_settings_override`. Chunks that are already in the repository are
+    /// deduplicated as usual, and keep the compression they were originally written with.
+    pub async fn put_object_with_settings<R: Read + Send + 'static>(
+        &mut self,
+        chunker: &impl AsyncChunker,
+        repository: &mut Repository<impl BackendClone>,
+        path: &str,
+        from_reader: R,
+        settings: ChunkSettings,
+    ) -> Result<WriteStats> {
+        let extent = Extent { start: 0, end: 0 };
+        let readers = vec![(extent, from_reader)];
+        self.put_sparse_object_with_settings(chunker, repository, path, readers, Some(settings))
+            .await
+    }
+
     /// Inserts a sparse object into the archive
     ///
     /// Requires that the object be pre-split into extents
+    ///
+    /// Returns the counts of the chunks written for the object, see `put_object`.
     pub async fn put_sparse_object<R: Read + Send + 'static>(
         &mut self,
         chunker: &impl AsyncChunker,
         repository: &mut Repository<impl BackendClone>,
         path: &str,
         from_readers: Vec<(Extent, R)>,
-    ) -> Result<()> {
-        let mut locations: Vec<ChunkLocation> = Vec::new();
-        let path = self.canonical_namespace() + path.trim();
+    ) -> Result<WriteStats> {
+        self.put_sparse_object_with_settings(chunker, repository, path, from_readers, None)
+            .await
+    }
 
-        for (extent, read) in from_readers {
-            let max_futs = 100;
-            let mut futs = VecDeque::new();
-            let mut slices = chunker.async_chunk(read, repository.queue_depth);
-            let mut start = extent.start;
-            while let Some(result) = slices.next().await {
-                let data = result?;
-                let end = start + (data.len() as u64);
-
-                let mut repository = repository.clone();
-                futs.push_back(Task::spawn(async move {
-                    let id = repository.write_chunk(data).await?.0;
-                    let result: Result<ChunkLocation> = Ok(ChunkLocation {
-                        id,
-                        start,
-                        length: end - start + 1,
-                    });
-                    result
-                }));
-                while futs.len() >= max_futs {
-                    // This unwrap is sound, since we can only be here if futs has elements in it
-                    let loc = futs.pop_front().unwrap().await?;
-                    locations.push(loc);
-                }
-                start = end + 1;
-            }
-            let locs = join_all(futs).await;
-            for loc in locs {
-                let loc = loc?;
-                locations.push(loc);
-            }
+    /// Inserts a sparse object into the archive, optionally overriding the repository's
+    /// compression for its chunks
+    ///
+    /// See `put_object_with_settings`
+    pub async fn put_sparse_object_with_settings<R: Read + Send + 'static>(
+        &mut self,
+        chunker: &impl AsyncChunker,
+        repository: &mut Repository<impl BackendClone>,
+        path: &str,
+        from_readers: Vec<(Extent, R)>,
+        settings: Option<ChunkSettings>,
+    ) -> Result<WriteStats> {
+        self.put_extents(
+            chunker,
+            repository,
+            path,
+            from_readers,
+            settings,
+            MAX_CONCURRENT_EXTENTS,
+        )
+        .await
+    }
+
+    /// Implementation of `put_sparse_object_with_settings`, storing up to `concurrency` extents
+    /// at once
+    ///
+    /// Each extent runs through its own chunker, so their reads proceed in parallel, but the
+    /// locations of their chunks are recorded in the order the extents were provided.
+    async fn put_extents<R: Read + Send + 'static>(
+        &mut self,
+        chunker: &impl AsyncChunker,
+        repository: &mut Repository<impl BackendClone>,
+        path: &str,
+        from_readers: Vec<(Extent, R)>,
+        settings: Option<ChunkSettings>,
+        concurrency: usize,
+    ) -> Result<WriteStats> {
+        // Reject an incompatible override before any chunks are written
+        if let Some(settings) = settings {
+            repository.check_settings_override(settings)?;
         }
+        let path = self.canonical_namespace() + path.trim();
 
-        self.objects.insert(path.to_string(), locations);
+        let (locations, stats) = stream::iter(from_readers)
+            .map(|(extent, read)| put_extent(chunker, repository.clone(), extent, read, settings))
+            .buffered(concurrency.max(1))
+            .try_fold(
+                (Vec::new(), WriteStats::default()),
+                |(mut locations, mut stats), (extent_locations, extent_stats)| async move {
+                    locations.extend(extent_locations);
+                    stats += extent_stats;
+                    Ok((locations, stats))
+                },
+            )
+            .await?;
+
+        self.objects.insert(path, locations);
+
+        Ok(stats)
+    }
 
-        Ok(())
+    /// Provides an `AsyncWrite` that stores the bytes written to it as an object in the archive
+    ///
+    /// The object is chunked and stored incrementally as it is written, and is only added to the
+    /// archive once the writer has been closed.
+    ///
+    /// Not available on wasm32, as the chunker blocks while waiting for writes.
+    #[cfg(not(target_arch = "wasm32"))]
+    pub fn object_writer<C, T>(
+        &self,
+        chunker: &C,
+        repository: &Repository<T>,
+        path: &str,
+    ) -> ObjectWriter
+    where
+        C: AsyncChunker + 'static,
+        T: BackendClone,
+    {
+        ObjectWriter::new(self, chunker, repository, path)
+    }
+
+    /// Records the object at `to` as having the same contents as the object at `from`, without
+    /// reading or writing any chunks
+    ///
+    /// The object is copied in each namespace, at or below this archive's, that it was stored in.
+    /// Returns false if there was no object at `from` to copy.
+    pub fn copy_object(&self, from: &str, to: &str) -> bool {
+        let base = self.canonical_namespace();
+        let (from, to) = (from.trim(), to.trim());
+        // Collected first, as inserting while iterating would deadlock the map
+        let copies: Vec<(String, Vec<ChunkLocation>)> = self
+            .objects
+            .iter()
+            .filter_map(|entry| {
+                let namespace = entry.key().strip_suffix(from)?;
+                if namespace.starts_with(&base) && namespace.ends_with(':') {
+                    Some((namespace.to_string() + to, entry.value().clone()))
+                } else {
+                    None
+                }
+            })
+            .collect();
+        let found = !copies.is_empty();
+        for (path, locations) in copies {
+            self.objects.insert(path, locations);
+        }
+        found
     }
 
     /// Inserts an object into the archive without writing any bytes
@@ -248,6 +630,24 @@ impl ActiveArchive {
         Ok(())
     }
 
+    /// Provides an `AsyncRead` over an object in the repository
+    ///
+    /// Chunks are fetched lazily as the reader is consumed, and holes are filled with zeros,
+    /// producing the same bytes as `get_object`. An object that does not exist reads as empty.
+    pub fn object_reader<T: BackendClone>(
+        &self,
+        repository: &Repository<T>,
+        path: &str,
+    ) -> ObjectReader<T> {
+        let path = self.canonical_namespace() + path.trim();
+        let locations = self
+            .objects
+            .get(&path)
+            .map(|x| x.value().clone())
+            .unwrap_or_default();
+        ObjectReader::new(repository.clone(), locations)
+    }
+
     /// Retrieve a single extent of an object from the repository
     ///
     /// Will write past the end of the last chunk ends after the extent
@@ -328,9 +728,23 @@ impl ActiveArchive {
     /// Stores archive metatdat in the repository, producing a Stored Archive
     ///  object, and consuming the Archive in the process.
     ///
+    /// The objects map is written to its own chunk, ahead of the rest of the archive.
+    ///
     /// Returns the key of the serialized archive in the repository
     pub async fn store(self, repo: &mut Repository<impl BackendClone>) -> StoredArchive {
-        let dumb_archive = self.into_archive().await;
+        let mut dumb_archive = self.into_archive().await;
+        let objects = std::mem::take(&mut dumb_archive.objects);
+        let mut bytes = Vec::<u8>::new();
+        objects
+            .serialize(&mut Serializer::new(&mut bytes))
+            .expect("Unable to serialize archive objects.");
+        let objects_chunk = repo
+            .write_chunk(bytes)
+            .await
+            .expect("Unable to write archive objects to repository.")
+            .0;
+        dumb_archive.objects_chunk = Some(objects_chunk);
+
         let mut bytes = Vec::<u8>::new();
         dumb_archive
             .serialize(&mut Serializer::new(&mut bytes))
@@ -342,7 +756,9 @@ impl ActiveArchive {
             .expect("Unable to write archive metatdata to repository.")
             .0;
 
-        repo.commit_index().await;
+        repo.commit_index()
+            .await
+            .expect("Unable to commit index to repository.");
 
         StoredArchive {
             id,
@@ -350,6 +766,75 @@ impl ActiveArchive {
         }
     }
 
+    /// Stores the archive in the repository, sealed to the provided recipients
+    ///
+    /// A random key is generated for the archive and wrapped to each recipient. The archive, and
+    /// its objects map, are packed under that key, so only the recipients can load them, see
+    /// `StoredArchive::load_as`. The chunks making up the objects stay in the shared chunk store,
+    /// and are deduplicated as usual.
+    ///
+    /// If no recipients are provided, this behaves exactly as `store`.
+    ///
+    /// # Errors
+    ///
+    /// Will return `Err` if the public key of any of the recipients is invalid
+    ///
+    /// # Panics
+    ///
+    /// Will panic if writing to the repository fails
+    #[cfg(feature = "recipients")]
+    pub async fn store_for(
+        self,
+        repo: &mut Repository<impl BackendClone>,
+        recipients: &[PublicKey],
+    ) -> Result<StoredArchive> {
+        if recipients.is_empty() {
+            return Ok(self.store(repo).await);
+        }
+        // Wrap the key before writing anything, so an invalid recipient leaves nothing behind
+        let key = Key::random(32);
+        let wrapped = wrap_key(&key, recipients)?;
+        let settings = repo.chunk_settings();
+
+        let mut dumb_archive = self.into_archive().await;
+        let objects = std::mem::take(&mut dumb_archive.objects);
+        let chunk_ids: HashSet<ChunkID> = objects
+            .values()
+            .flatten()
+            .map(|location| location.id)
+            .collect();
+        let bytes = serde_cbor::to_vec(&objects).expect("Unable to serialize archive objects.");
+        let bytes = serde_cbor::to_vec(&seal(bytes, settings, &key))
+            .expect("Unable to serialize sealed archive objects.");
+        let objects_chunk = repo
+            .write_chunk(bytes)
+            .await
+            .expect("Unable to write archive objects to repository.")
+            .0;
+        dumb_archive.objects_chunk = Some(objects_chunk);
+
+        let bytes = serde_cbor::to_vec(&dumb_archive).expect("Unable to serialize archive.");
+        let sealed = SealedArchive {
+            recipients: wrapped,
+            archive: seal(bytes, settings, &key),
+            objects_chunk,
+            chunk_ids: chunk_ids.into_iter().collect(),
+        };
+        let bytes = serde_cbor::to_vec(&sealed).expect("Unable to serialize sealed archive.");
+        let id = repo
+            .write_chunk(bytes)
+            .await
+            .expect("Unable to write archive metatdata to repository.")
+            .0;
+
+        repo.commit_index().await?;
+
+        Ok(StoredArchive {
+            id,
+            timestamp: dumb_archive.timestamp,
+        })
+    }
+
     #[cfg_attr(tarpaulin, skip)]
     /// Provides the name of the archive
     pub fn name(&self) -> &str {
@@ -362,6 +847,35 @@ impl ActiveArchive {
         &self.timestamp
     }
 
+    /// Provides the tags of the archive, in sorted order
+    pub fn tags(&self) -> &[String] {
+        &self.tags
+    }
+
+    /// Adds a tag to the archive
+    ///
+    /// Adding a tag the archive already has does nothing.
+    pub fn add_tag(&mut self, tag: &str) {
+        if let Err(index) = self.tags.binary_search_by(|x| x.as_str().cmp(tag)) {
+            self.tags.insert(index, tag.to_string());
+        }
+    }
+
+    /// Returns true if the archive has the given tag
+    pub fn has_tag(&self, tag: &str) -> bool {
+        self.tags.binary_search_by(|x| x.as_str().cmp(tag)).is_ok()
+    }
+
+    /// Provides the comment attached to the archive, if it has one
+    pub fn comment(&self) -> Option<&str> {
+        self.comment.as_deref()
+    }
+
+    /// Sets, or with `None` removes, the comment attached to the archive
+    pub fn set_comment(&mut self, comment: Option<String>) {
+        self.comment = comment;
+    }
+
     /// Converts an Archive into an `ActiveArchive`
     pub fn from_archive(archive: Archive) -> ActiveArchive {
         ActiveArchive {
@@ -370,20 +884,83 @@ impl ActiveArchive {
             namespace: archive.namespace,
             timestamp: archive.timestamp,
             listing: Arc::new(Lock::new(archive.listing)),
+            objects_chunk: archive.objects_chunk,
+            tags: archive.tags,
+            comment: archive.comment,
         }
     }
 
     /// Converts self into an Archive
+    ///
+    /// The objects map is always carried inline, and the returned `Archive` does not point to an
+    /// objects chunk.
     pub async fn into_archive(self) -> Archive {
         Archive {
             name: self.name,
             objects: DashMap::clone(&self.objects).into_iter().collect(),
+            objects_chunk: None,
             namespace: self.namespace,
             timestamp: self.timestamp,
             listing: self.listing.lock().await.clone(),
+            tags: self.tags,
+            comment: self.comment,
         }
     }
 
+    /// Provides the chunk the objects map of this archive was stored in
+    ///
+    /// This is only set for archives loaded from the repository, and is `None` for archives
+    /// stored before the objects map was split out into its own chunk.
+    pub fn objects_chunk(&self) -> Option<ChunkID> {
+        self.objects_chunk
+    }
+
+    /// Provides the set of all chunks referenced by the objects in this archive
+    ///
+    /// This does not include the chunk the archive itself is stored in.
+    pub fn chunk_ids(&self) -> HashSet<ChunkID> {
+        let mut ids = HashSet::new();
+        for entry in self.objects.iter() {
+            ids.extend(entry.value().iter().map(|location| location.id));
+        }
+        ids
+    }
+
+    /// Provides the number of bytes of object data held by each chunk referenced by the objects
+    /// in this archive
+    ///
+    /// This is the length of the chunk's contents before compression and encryption, as recorded
+    /// in the archive, so it is known without reading the chunk.
+    pub fn chunk_lengths(&self) -> HashMap<ChunkID, u64> {
+        let mut lengths = HashMap::new();
+        for entry in self.objects.iter() {
+            lengths.extend(
+                entry
+                    .value()
+                    .iter()
+                    .map(|location| (location.id, location.length)),
+            );
+        }
+        lengths
+    }
+
+    /// Provides the total number of bytes referenced by the objects in this archive
+    ///
+    /// Every reference to a chunk is counted, so chunks that are shared between objects are
+    /// counted once for each object that uses them.
+    pub fn referenced_bytes(&self) -> u64 {
+        self.objects
+            .iter()
+            .map(|entry| {
+                entry
+                    .value()
+                    .iter()
+                    .map(|location| location.length)
+                    .sum::<u64>()
+            })
+            .sum()
+    }
+
     /// Gets a copy of the listing from the archive
     pub async fn listing(&self) -> Listing {
         self.listing.lock().await.clone()
@@ -393,6 +970,32 @@ impl ActiveArchive {
     pub async fn set_listing(&self, listing: Listing) {
         *self.listing.lock().await = listing;
     }
+
+    /// Restores the objects in this archive whose paths match `options.globs` into `dest`
+    ///
+    /// The filter is applied to the listing before anything is restored, so no chunks are read
+    /// for objects that do not match, and no files or directories are created for them, other
+    /// than the parent directories of matching objects.
+    ///
+    /// This is `restore::extract_directory` in method form, see it for the handling of existing
+    /// files and of `progress`.
+    ///
+    /// Returns the number of objects that were restored.
+    ///
+    /// # Errors
+    ///
+    /// Will return `Err` if any matching path is unsafe, or if retrieving or writing any of the
+    /// matching objects fails
+    #[cfg(not(target_arch = "wasm32"))]
+    pub async fn get_matching(
+        &self,
+        repo: &mut Repository<impl BackendClone>,
+        dest: &std::path::Path,
+        options: &ExtractOptions,
+        progress: Option<&mut RestoreProgress>,
+    ) -> std::result::Result<usize, RestoreError> {
+        extract_directory(self, repo, dest, options, progress).await
+    }
 }
 
 #[cfg(test)]
@@ -401,18 +1004,19 @@ mod tests {
     use super::*;
     use crate::chunker::*;
     use crate::repository::backend::mem::Mem;
-    use crate::repository::ChunkSettings;
     use crate::repository::Key;
+    use crate::repository::{Chunk, ChunkSettings};
     use rand::prelude::*;
     use std::fs;
     use std::io::{BufReader, Cursor, Seek, SeekFrom};
     use std::path::Path;
+    use std::sync::atomic::{AtomicUsize, Ordering};
     use tempfile::tempdir;
 
     fn get_repo_mem(key: Key) -> Repository<impl BackendClone> {
         let settings = ChunkSettings::lightweight();
         let backend = Mem::new(settings, key.clone(), 4);
-        Repository::with(backend, settings, key, 2)
+        Repository::with(backend, settings, key, 2).unwrap()
     }
 
     #[test]
@@ -470,6 +1074,31 @@ mod tests {
         });
     }
 
+    #[test]
+    fn empty_add_get() {
+        smol::run(async {
+            let chunker = FastCDC::default();
+            let key = Key::random(32);
+            let mut repo = get_repo_mem(key);
+            let mut archive = ActiveArchive::new("test");
+
+            archive
+                .put_object(&chunker, &mut repo, "empty", Cursor::new(Vec::<u8>::new()))
+                .await
+                .unwrap();
+            // The object should exist, but be made of no chunks
+            let path = archive.canonical_namespace() + "empty";
+            assert_eq!(archive.objects.get(&path).map(|x| x.len()), Some(0));
+
+            let mut buf = Cursor::new(Vec::<u8>::new());
+            archive
+                .get_object(&mut repo, "empty", &mut buf)
+                .await
+                .unwrap();
+            assert!(buf.into_inner().is_empty());
+        });
+    }
+
     #[test]
     fn sparse_add_get() {
         smol::run(async {
@@ -557,6 +1186,103 @@ mod tests {
         });
     }
 
+    /// Wraps a reader, counting it as in flight from its first read until it reaches the end of
+    /// its input, and recording the largest number in flight at once
+    struct TrackedReader {
+        inner: Cursor<Vec<u8>>,
+        current: Arc<AtomicUsize>,
+        max: Arc<AtomicUsize>,
+        started: bool,
+        finished: bool,
+    }
+
+    impl Read for TrackedReader {
+        fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+            if !self.started {
+                self.started = true;
+                let current = self.current.fetch_add(1, Ordering::SeqCst) + 1;
+                self.max.fetch_max(current, Ordering::SeqCst);
+                // Give the other extents a chance to start while this one is in flight
+                std::thread::sleep(std::time::Duration::from_millis(50));
+            }
+            let count = self.inner.read(buf)?;
+            if count == 0 && !buf.is_empty() && !self.finished {
+                self.finished = true;
+                self.current.fetch_sub(1, Ordering::SeqCst);
+            }
+            Ok(count)
+        }
+    }
+
+    #[test]
+    fn concurrent_extents_match_sequential() {
+        smol::run(async {
+            let chunker = FastCDC::default();
+            let mut repo = get_repo_mem(Key::random(32));
+            let mut rng = SmallRng::seed_from_u64(0);
+            let mut extents = Vec::new();
+            for i in 0..16_u64 {
+                let mut data = vec![0_u8; 4096];
+                rng.fill_bytes(&mut data);
+                let start = i * 16384;
+                extents.push((
+                    Extent {
+                        start,
+                        end: start + 4096,
+                    },
+                    data,
+                ));
+            }
+            let readers = |current: &Arc<AtomicUsize>, max: &Arc<AtomicUsize>| {
+                extents
+                    .iter()
+                    .map(|(extent, data)| {
+                        let reader = TrackedReader {
+                            inner: Cursor::new(data.clone()),
+                            current: current.clone(),
+                            max: max.clone(),
+                            started: false,
+                            finished: false,
+                        };
+                        (*extent, reader)
+                    })
+                    .collect::<Vec<_>>()
+            };
+
+            let mut max_in_flight = Vec::new();
+            let mut archives = Vec::new();
+            for concurrency in &[1, 8] {
+                let mut archive = ActiveArchive::new("test");
+                let (current, max) = (Arc::new(AtomicUsize::new(0)), Arc::new(AtomicUsize::new(0)));
+                let readers = readers(&current, &max);
+                archive
+                    .put_extents(&chunker, &mut repo, "sparse", readers, None, *concurrency)
+                    .await
+                    .unwrap();
+                max_in_flight.push(max.load(Ordering::SeqCst));
+                archives.push(archive);
+            }
+
+            let sequential = archives[0].objects.get(":sparse").unwrap().clone();
+            let concurrent = archives[1].objects.get(":sparse").unwrap().clone();
+            assert_eq!(sequential, concurrent);
+            for (extent, data) in &extents {
+                let mut output = Cursor::new(Vec::new());
+                archives[1]
+                    .get_extent(&mut repo, "sparse", *extent, &mut output)
+                    .await
+                    .unwrap();
+                assert_eq!(&output.into_inner(), data);
+            }
+            assert_eq!(max_in_flight[0], 1);
+            assert!(
+                max_in_flight[1] > 1 && max_in_flight[1] <= 8,
+                "{:?}",
+                max_in_flight
+            );
+        });
+    }
+
     #[test]
     fn default_namespace() {
         let archive = ActiveArchive::new("test");
@@ -656,4 +1382,454 @@ mod tests {
             assert_eq!(&obj1.into_inner()[..], &obj_restore.into_inner()[..]);
         });
     }
+
+    #[test]
+    fn get_matching_restores_only_matches() {
+        use crate::manifest::driver::BackupDriver;
+        use crate::manifest::filter::GlobFilter;
+        use crate::manifest::target::{BackupTarget, FileSystemTarget};
+        smol::run(async {
+            let chunker = FastCDC::default();
+            let mut repo = get_repo_mem(Key::random(32));
+            // Build a directory with a mix of matching and non-matching files
+            let input_dir = tempdir().unwrap();
+            let input = input_dir.path();
+            fs::create_dir(input.join("text")).unwrap();
+            fs::create_dir(input.join("binary")).unwrap();
+            let files = [
+                ("one.txt", true),
+                ("two.bin", false),
+                ("text/three.txt", true),
+                ("binary/four.bin", false),
+            ];
+            for (path, _) in &files {
+                let mut data = vec![0_u8; 10_000];
+                thread_rng().fill_bytes(&mut data);
+                fs::write(input.join(path), data).unwrap();
+            }
+            // Store it
+            let archive = ActiveArchive::new("test");
+            let target = FileSystemTarget::new(input.to_str().unwrap());
+            for node in target.backup_paths().await {
+                target
+                    .store_object(&mut repo, chunker, &archive, node)
+                    .await
+                    .unwrap();
+            }
+            archive.set_listing(target.backup_listing().await).await;
+            // Restore only the text files
+            let output_dir = tempdir().unwrap();
+            let output = output_dir.path();
+            let options = ExtractOptions {
+                globs: GlobFilter::new(&["*.txt"], &[] as &[&str]).unwrap(),
+                ..ExtractOptions::default()
+            };
+            let restored = archive
+                .get_matching(&mut repo, output, &options, None)
+                .await
+                .expect("Unable to restore matching objects");
+            assert_eq!(restored, 2);
+            for (path, matches) in &files {
+                if *matches {
+                    assert_eq!(
+                        fs::read(input.join(path)).unwrap(),
+                        fs::read(output.join(path)).unwrap()
+                    );
+                } else {
+                    assert!(!output.join(path).exists());
+                }
+            }
+            assert!(!output.join("binary").exists());
+        });
+    }
+
+    #[test]
+    fn verify_on_read_catches_wrong_chunk() {
+        smol::run(async {
+            let chunker = FastCDC::default();
+            let key = Key::random(32);
+            let settings = ChunkSettings::lightweight();
+            let mut repo = get_repo_mem(key.clone());
+
+            let mut data = vec![0_u8; 1000];
+            thread_rng().fill_bytes(&mut data);
+            let mut wrong_data = vec![0_u8; 1000];
+            thread_rng().fill_bytes(&mut wrong_data);
+            // Plant a validly MACed chunk with the wrong contents under the id of the real data,
+            // as a corrupted backend might return
+            let id = Chunk::pack(
+                data.clone(),
+                settings.compression,
+                settings.encryption,
+                settings.hmac,
+                &key,
+            )
+            .get_id();
+            let forged = Chunk::pack_with_id(
+                wrong_data.clone(),
+                settings.compression,
+                settings.encryption,
+                settings.hmac,
+                &key,
+                id,
+            );
+            repo.write_raw(forged).await.unwrap();
+
+            let mut archive = ActiveArchive::new("test");
+            archive
+                .put_object(&chunker, &mut repo, "file", Cursor::new(data))
+                .await
+                .unwrap();
+
+            // Without verification, the wrong bytes come back
+            let mut output = Vec::new();
+            archive
+                .get_object(&mut repo, "file", &mut output)
+                .await
+                .unwrap();
+            assert_eq!(output, wrong_data);
+
+            // With verification, the read fails
+            repo.set_verify_on_read(true);
+            let mut output = Vec::new();
+            let result = archive.get_object(&mut repo, "file", &mut output).await;
+            assert!(result.is_err());
+        });
+    }
+
+    #[test]
+    fn object_reader_matches_get_object() {
+        smol::run(async {
+            use futures::io::AsyncReadExt;
+            let chunker = FastCDC::default();
+            let key = Key::random(32);
+            let mut repo = get_repo_mem(key);
+
+            let mut data = vec![0_u8; 300_000];
+            thread_rng().fill_bytes(&mut data);
+            let mut archive = ActiveArchive::new("test");
+            archive
+                .put_object(&chunker, &mut repo, "file", Cursor::new(data.clone()))
+                .await
+                .unwrap();
+            // Leave a hole between the extents of a sparse object
+            let extents = vec![
+                (
+                    Extent {
+                        start: 0,
+                        end: 9999,
+                    },
+                    Cursor::new(data[..10_000].to_vec()),
+                ),
+                (
+                    Extent {
+                        start: 20_000,
+                        end: 29_999,
+                    },
+                    Cursor::new(data[20_000..30_000].to_vec()),
+                ),
+            ];
+            archive
+                .put_sparse_object(&chunker, &mut repo, "sparse", extents)
+                .await
+                .unwrap();
+
+            for path in &["file", "sparse"] {
+                let mut expected = Vec::new();
+                archive
+                    .get_object(&mut repo, path, &mut expected)
+                    .await
+                    .unwrap();
+                let mut output = Vec::new();
+                archive
+                    .object_reader(&repo, path)
+                    .read_to_end(&mut output)
+                    .await
+                    .unwrap();
+                assert_eq!(expected, output);
+            }
+        });
+    }
+
+    // A chunk with a length of zero covers no bytes, and must not disturb the reader
+    #[test]
+    fn object_reader_zero_length_chunk() {
+        smol::run(async {
+            use futures::io::AsyncReadExt;
+            let mut repo = get_repo_mem(Key::random(32));
+            let (empty, _) = repo.write_chunk(Vec::new()).await.unwrap();
+            let (full, _) = repo.write_chunk(vec![7_u8; 100]).await.unwrap();
+            let archive = ActiveArchive::new("test");
+            archive.objects.insert(
+                ":object".to_string(),
+                vec![
+                    ChunkLocation {
+                        id: empty,
+                        start: 0,
+                        length: 0,
+                    },
+                    ChunkLocation {
+                        id: full,
+                        start: 0,
+                        length: 100,
+                    },
+                ],
+            );
+            let mut output = Vec::new();
+            archive
+                .object_reader(&repo, "object")
+                .read_to_end(&mut output)
+                .await
+                .unwrap();
+            assert_eq!(output, vec![7_u8; 100]);
+        });
+    }
+
+    #[test]
+    fn object_writer_matches_put_object() {
+        smol::run(async {
+            use futures::io::AsyncWriteExt;
+            let chunker = FastCDC::default();
+            let key = Key::random(32);
+            let mut repo = get_repo_mem(key);
+
+            let mut data = vec![0_u8; 300_000];
+            thread_rng().fill_bytes(&mut data);
+            let mut archive = ActiveArchive::new("test");
+            archive
+                .put_object(&chunker, &mut repo, "single", Cursor::new(data.clone()))
+                .await
+                .unwrap();
+
+            let mut writer = archive.object_writer(&chunker, &repo, "streamed");
+            for piece in data.chunks(777) {
+                writer.write_all(piece).await.unwrap();
+            }
+            writer.close().await.unwrap();
+
+            let mut single = Vec::new();
+            archive
+                .get_object(&mut repo, "single", &mut single)
+                .await
+                .unwrap();
+            let mut streamed = Vec::new();
+            archive
+                .get_object(&mut repo, "streamed", &mut streamed)
+                .await
+                .unwrap();
+            assert_eq!(single, data);
+            assert_eq!(streamed, data);
+        });
+    }
+
+    #[test]
+    fn load_listing_skips_objects() {
+        smol::run(async {
+            let key = Key::random(32);
+            let mut repo = get_repo_mem(key);
+            let chunker = FastCDC::default();
+            let mut archive = ActiveArchive::new("test");
+            let mut listing = Listing::default();
+            for i in 0..100 {
+                let path = format!("object-{}", i);
+                let mut data = vec![0_u8; 1024];
+                thread_rng().fill_bytes(&mut data);
+                archive
+                    .put_object(&chunker, &mut repo, &path, Cursor::new(data))
+                    .await
+                    .unwrap();
+                listing.add_child(
+                    "",
+                    Node {
+                        path,
+                        total_length: 1024,
+                        total_size: 1024,
+                        extents: None,
+                        content_type: None,
+                        node_type: NodeType::File,
+                    },
+                );
+            }
+            archive.set_listing(listing).await;
+            let stored = archive.store(&mut repo).await;
+
+            let partial = stored.load_listing(&mut repo).await.unwrap();
+            let full = stored.load(&mut repo).await.unwrap();
+            assert_eq!(partial, full.listing().await);
+
+            // load_listing only reads the archive's own chunk, which no longer carries the
+            // objects, while load also has to read the objects chunk
+            let metadata = stored.load_metadata(&mut repo).await.unwrap();
+            assert!(metadata.objects.is_empty());
+            let objects_chunk = full.objects_chunk().expect("Objects were stored inline");
+            let listing_bytes = repo.read_chunk(stored.id()).await.unwrap().len();
+            let objects_bytes = repo.read_chunk(objects_chunk).await.unwrap().len();
+            assert!(objects_bytes > 0);
+            // An archive with its objects inline, as a full load used to read, is larger
+            let inline = serde_cbor::ser::to_vec(&full.into_archive().await).unwrap();
+            assert!(listing_bytes < inline.len());
+        });
+    }
+
+    #[test]
+    fn put_object_compression_override() {
+        smol::run(async {
+            use crate::repository::backend::{Backend, Index};
+            use crate::repository::Compression;
+
+            // Highly compressible, so the override makes an obvious difference
+            let data: Vec<u8> = (0..200_000_u32).map(|x| (x % 7) as u8).collect();
+            let chunker = FastCDC::default();
+            let settings = ChunkSettings::lightweight();
+            let mut stored_sizes = Vec::new();
+            for compression in &[None, Some(Compression::ZStd { level: 3 })] {
+                let key = Key::random(32);
+                let backend = Mem::new(settings, key.clone(), 4);
+                let mut repo = Repository::with(backend.clone(), settings, key, 2).unwrap();
+                let mut archive = ActiveArchive::new("test");
+                match *compression {
+                    Some(compression) => {
+                        let mut settings = settings;
+                        settings.compression = compression;
+                        archive
+                            .put_object_with_settings(
+                                &chunker,
+                                &mut repo,
+                                "object",
+                                Cursor::new(data.clone()),
+                                settings,
+                            )
+                            .await
+                            .unwrap();
+                    }
+                    None => archive
+                        .put_object(&chunker, &mut repo, "object", Cursor::new(data.clone()))
+                        .await
+                        .unwrap(),
+                }
+
+                let mut output = Cursor::new(Vec::new());
+                archive
+                    .get_object(&mut repo, "object", &mut output)
+                    .await
+                    .unwrap();
+                assert_eq!(output.into_inner(), data);
+
+                let mut size = 0;
+                for id in archive.chunk_ids() {
+                    let location = backend.get_index().lookup_chunk(id).await.unwrap().unwrap();
+                    size += backend
+                        .read_chunk(location)
+                        .await
+                        .unwrap()
+                        .get_bytes()
+                        .len();
+                }
+                stored_sizes.push(size);
+            }
+            assert!(stored_sizes[1] < stored_sizes[0]);
+        });
+    }
+
+    #[test]
+    fn put_object_rejects_incompatible_override() {
+        smol::run(async {
+            let key = Key::random(32);
+            let mut repo = get_repo_mem(key);
+            let chunker = FastCDC::default();
+            let mut archive = ActiveArchive::new("test");
+            let mut settings = ChunkSettings::lightweight();
+            settings.hmac = crate::repository::HMAC::SHA256;
+            let result = archive
+                .put_object_with_settings(
+                    &chunker,
+                    &mut repo,
+                    "object",
+                    Cursor::new(vec![1_u8; 1024]),
+                    settings,
+                )
+                .await;
+            assert!(matches!(
+                result,
+                Err(ArchiveError::Repository(
+                    crate::repository::RepositoryError::IncompatibleChunkSettings(_)
+                ))
+            ));
+        });
+    }
+
+    #[test]
+    fn tags_and_comment_round_trip() {
+        smol::run(async {
+            let key = Key::random(32);
+            let mut repo = get_repo_mem(key);
+            let mut archive = ActiveArchive::new("test");
+            archive.add_tag("foo");
+            archive.add_tag("bar");
+            archive.add_tag("foo");
+            archive.set_comment(Some("a comment".to_string()));
+            let stored = archive.store(&mut repo).await;
+            let loaded = stored.load(&mut repo).await.unwrap();
+            assert_eq!(loaded.tags(), &["bar".to_string(), "foo".to_string()]);
+            assert!(loaded.has_tag("foo"));
+            assert!(!loaded.has_tag("baz"));
+            assert_eq!(loaded.comment(), Some("a comment"));
+        });
+    }
+
+    #[test]
+    #[cfg(feature = "recipients")]
+    fn sealed_archive_recipients() {
+        smol::run(async {
+            let key = Key::random(32);
+            let mut repo = get_repo_mem(key);
+            let chunker = FastCDC::default();
+            let alice = SecretKey::random();
+            let bob = SecretKey::random();
+
+            let mut data = vec![0_u8; 16384];
+            thread_rng().fill_bytes(&mut data);
+            let mut archive = ActiveArchive::new("alice's archive");
+            archive
+                .put_object(&chunker, &mut repo, "object", Cursor::new(data.clone()))
+                .await
+                .unwrap();
+            let chunk_ids = archive.chunk_ids();
+            let stored = archive
+                .store_for(&mut repo, &[alice.public_key()])
+                .await
+                .unwrap();
+            assert!(stored.is_sealed(&mut repo).await.unwrap());
+
+            // Alice can load the archive, and restore its object
+            let loaded = stored.load_as(&mut repo, &alice).await.unwrap();
+            assert_eq!(loaded.name(), "alice's archive");
+            let mut output = Cursor::new(Vec::new());
+            loaded
+                .get_object(&mut repo, "object", &mut output)
+                .await
+                .unwrap();
+            assert_eq!(data, output.into_inner());
+
+            // Bob can not, and neither can anyone with only the repository key
+            assert!(matches!(
+                stored.load_as(&mut repo, &bob).await,
+                Err(ArchiveError::Recipient(RecipientError::NotARecipient))
+            ));
+            assert!(matches!(
+                stored.load_metadata_as(&mut repo, &bob).await,
+                Err(ArchiveError::Recipient(RecipientError::NotARecipient))
+            ));
+            assert!(matches!(
+                stored.load(&mut repo).await,
+                Err(ArchiveError::Sealed)
+            ));
+
+            // The referenced chunks are still known without unsealing the archive
+            let referenced = stored.referenced_chunks(&mut repo).await.unwrap();
+            assert!(referenced.is_superset(&chunk_ids));
+            assert!(referenced.contains(&stored.id()));
+            assert!(referenced.contains(&loaded.objects_chunk().unwrap()));
+        });
+    }
 }
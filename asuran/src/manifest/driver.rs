@@ -1,7 +1,7 @@
 use crate::chunker::AsyncChunker;
-use crate::manifest::archive::{ActiveArchive, Extent};
+use crate::manifest::archive::{ActiveArchive, ArchiveError, Extent};
 use crate::manifest::target::{BackupObject, BackupTarget, RestoreObject, RestoreTarget};
-use crate::repository::{BackendClone, Repository};
+use crate::repository::{BackendClone, Repository, WriteStats};
 
 use asuran_core::manifest::listing::Node;
 
@@ -18,6 +18,15 @@ pub enum DriverError {
     ArchiveError(#[from] crate::manifest::archive::ArchiveError),
 }
 
+impl DriverError {
+    /// Returns a short, stable, `snake_case` name for the kind of this error
+    pub fn kind(&self) -> &'static str {
+        match self {
+            DriverError::ArchiveError(e) => e.kind(),
+        }
+    }
+}
+
 type Result<T> = std::result::Result<T, DriverError>;
 
 /// Defines a type that can, semi-automatically, drive the storage of objects from
@@ -35,6 +44,8 @@ pub trait BackupDriver<T: Read + Send + 'static>: BackupTarget<T> {
     /// route, otherwise use `store_object`.
     ///
     /// Stores objects in sub-namespaces of the namespace of the archive object provided
+    ///
+    /// Returns the counts of the chunks written for all of the objects
     async fn raw_store_object<B: BackendClone, C: AsyncChunker + Send + 'static>(
         &self,
         repo: &mut Repository<B>,
@@ -42,7 +53,8 @@ pub trait BackupDriver<T: Read + Send + 'static>: BackupTarget<T> {
         archive: &ActiveArchive,
         node: Node,
         objects: HashMap<String, BackupObject<T>>,
-    ) -> Result<()> {
+    ) -> Result<WriteStats> {
+        let mut stats = WriteStats::default();
         if node.is_file() {
             for (namespace, backup_object) in objects {
                 let path = &node.path;
@@ -58,7 +70,7 @@ pub trait BackupDriver<T: Read + Send + 'static>: BackupTarget<T> {
                     archive.put_empty(path).await;
                 } else if range_count == 1 {
                     let object = ranges.remove(0).object;
-                    archive.put_object(&chunker, repo, path, object).await?;
+                    stats += archive.put_object(&chunker, repo, path, object).await?;
                 } else {
                     let mut readers: Vec<(Extent, T)> = Vec::new();
                     for object in ranges {
@@ -69,13 +81,13 @@ pub trait BackupDriver<T: Read + Send + 'static>: BackupTarget<T> {
                         let object = object.object;
                         readers.push((extent, object));
                     }
-                    archive
+                    stats += archive
                         .put_sparse_object(&chunker, repo, path, readers)
                         .await?;
                 }
             }
         }
-        Ok(())
+        Ok(stats)
     }
 
     /// Convenience method that performs a call to `self.backup_object` for you and
@@ -86,7 +98,7 @@ pub trait BackupDriver<T: Read + Send + 'static>: BackupTarget<T> {
         chunker: C,
         archive: &ActiveArchive,
         node: Node,
-    ) -> Result<()> {
+    ) -> Result<WriteStats> {
         let objects = self.backup_object(node.clone()).await;
         self.raw_store_object(repo, chunker, archive, node, objects)
             .await
@@ -115,7 +127,18 @@ pub trait RestoreDriver<T: Write + Send + 'static>: RestoreTarget<T> {
         objects: HashMap<String, RestoreObject<T>>,
     ) -> Result<()> {
         let path = &node.path;
-        if node.is_file() {
+        if let Some(data) = node.inline_data() {
+            // Inline files are stored densely in the listing, and have nothing in any namespace, so
+            // the whole of their data goes into the first range of the root namespace
+            let root = objects
+                .into_iter()
+                .find(|(namespace, _)| namespace.is_empty());
+            if let Some((_, restore_object)) = root {
+                if let Some(mut range) = restore_object.ranges().into_iter().next() {
+                    range.object.write_all(data).map_err(ArchiveError::from)?;
+                }
+            }
+        } else if node.is_file() {
             for (namespace, restore_object) in objects {
                 // TODO (#45): get total size and do something with it
                 // Get a new archive with the specified namespace
@@ -0,0 +1,217 @@
+//! Provides glob based filtering of object paths, for operating on only part of an archive
+use globset::{Glob, GlobBuilder, GlobSet, GlobSetBuilder};
+use thiserror::Error;
+
+use std::fs;
+use std::path::Path;
+
+/// An error for things that can go wrong building a `GlobFilter` from pattern files
+#[derive(Error, Debug)]
+pub enum FilterError {
+    #[error("Invalid glob pattern: {0}")]
+    Glob(#[from] globset::Error),
+    #[error("Unable to read pattern file: {0}")]
+    IO(#[from] std::io::Error),
+}
+
+/// A set of include and exclude globs to match object paths against
+///
+/// A path matches the filter if it matches at least one of the include globs, or there are no
+/// include globs, and it does not match any of the exclude globs.
+#[derive(Clone, Debug, Default)]
+pub struct GlobFilter {
+    includes: Option<GlobSet>,
+    excludes: Option<GlobSet>,
+}
+
+impl GlobFilter {
+    /// Creates a new filter from lists of include and exclude patterns
+    ///
+    /// An empty list of includes will include every path not otherwise excluded.
+    ///
+    /// # Errors
+    ///
+    /// Will return `Err` if any of the patterns are not valid globs
+    pub fn new(
+        includes: &[impl AsRef<str>],
+        excludes: &[impl AsRef<str>],
+    ) -> Result<GlobFilter, globset::Error> {
+        Ok(GlobFilter {
+            includes: build_set(includes)?,
+            excludes: build_set(excludes)?,
+        })
+    }
+
+    /// Creates a new filter from lists of include and exclude patterns, along with files
+    /// containing more of them
+    ///
+    /// Pattern files contain one glob per line. Leading and trailing whitespace is ignored, as are
+    /// blank lines and lines starting with `#`. Patterns read from the files are merged with the
+    /// patterns given inline.
+    ///
+    /// # Errors
+    ///
+    /// Will return `Err` if any of the files can not be read, or any of the patterns are not valid
+    /// globs
+    pub fn from_files(
+        includes: &[impl AsRef<str>],
+        excludes: &[impl AsRef<str>],
+        include_files: &[impl AsRef<Path>],
+        exclude_files: &[impl AsRef<Path>],
+    ) -> Result<GlobFilter, FilterError> {
+        let mut all_includes: Vec<String> =
+            includes.iter().map(|x| x.as_ref().to_string()).collect();
+        let mut all_excludes: Vec<String> =
+            excludes.iter().map(|x| x.as_ref().to_string()).collect();
+        for file in include_files {
+            all_includes.extend(read_patterns(file)?);
+        }
+        for file in exclude_files {
+            all_excludes.extend(read_patterns(file)?);
+        }
+        Ok(GlobFilter::new(&all_includes, &all_excludes)?)
+    }
+
+    /// Determines if the given path passes the filter
+    pub fn is_match(&self, path: &str) -> bool {
+        self.includes.as_ref().map_or(true, |x| x.is_match(path))
+            && self.excludes.as_ref().map_or(true, |x| !x.is_match(path))
+    }
+}
+
+/// The patterns from a single `.gitignore` style ignore file, matched against paths relative to
+/// the directory containing it
+///
+/// Patterns without a `/` match the name of a file or directory at any depth below the ignore file,
+/// and patterns containing one are anchored to the ignore file's directory. A trailing `/` is
+/// accepted, but does not restrict a pattern to directories, and negated patterns are not
+/// supported.
+#[derive(Clone, Debug)]
+pub struct IgnoreRules {
+    names: GlobSet,
+    anchored: GlobSet,
+}
+
+impl IgnoreRules {
+    /// Reads the rules out of an ignore file, in the same format as the pattern files accepted by
+    /// `GlobFilter::from_files`
+    ///
+    /// # Errors
+    ///
+    /// Will return `Err` if the file can not be read, or any of its patterns are not valid globs
+    pub fn from_file(path: impl AsRef<Path>) -> Result<IgnoreRules, FilterError> {
+        let mut names = GlobSetBuilder::new();
+        let mut anchored = GlobSetBuilder::new();
+        for pattern in read_patterns(path)? {
+            let pattern = pattern.trim_end_matches('/');
+            if pattern.is_empty() {
+                continue;
+            } else if pattern.contains('/') {
+                anchored.add(
+                    GlobBuilder::new(pattern.trim_start_matches('/'))
+                        .literal_separator(true)
+                        .build()?,
+                );
+            } else {
+                names.add(Glob::new(pattern)?);
+            }
+        }
+        Ok(IgnoreRules {
+            names: names.build()?,
+            anchored: anchored.build()?,
+        })
+    }
+
+    /// Determines if a path, relative to the directory containing the ignore file, is ignored
+    pub fn is_match(&self, relative: impl AsRef<Path>) -> bool {
+        let relative = relative.as_ref();
+        self.anchored.is_match(relative)
+            || relative
+                .file_name()
+                .map_or(false, |name| self.names.is_match(name))
+    }
+}
+
+/// Reads the patterns out of a pattern file, skipping blank lines and comments
+fn read_patterns(path: impl AsRef<Path>) -> std::io::Result<Vec<String>> {
+    Ok(fs::read_to_string(path)?
+        .lines()
+        .map(str::trim)
+        .filter(|x| !x.is_empty() && !x.starts_with('#'))
+        .map(ToString::to_string)
+        .collect())
+}
+
+/// Builds a `GlobSet` out of a list of patterns, returning `None` if the list is empty
+fn build_set(patterns: &[impl AsRef<str>]) -> Result<Option<GlobSet>, globset::Error> {
+    if patterns.is_empty() {
+        return Ok(None);
+    }
+    let mut builder = GlobSetBuilder::new();
+    for pattern in patterns {
+        builder.add(Glob::new(pattern.as_ref())?);
+    }
+    Ok(Some(builder.build()?))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn include_and_exclude() {
+        let filter = GlobFilter::new(&["*.txt"], &["secret/*"]).unwrap();
+        assert!(filter.is_match("notes.txt"));
+        assert!(filter.is_match("dir/notes.txt"));
+        assert!(!filter.is_match("image.png"));
+        assert!(!filter.is_match("secret/notes.txt"));
+        let everything = GlobFilter::default();
+        assert!(everything.is_match("image.png"));
+    }
+
+    #[test]
+    fn patterns_from_files() {
+        let directory = tempfile::tempdir().unwrap();
+        let excludes = directory.path().join("excludes");
+        fs::write(
+            &excludes,
+            "# Build output\n  target/*  \n\n\t*.log\n   #*.txt\n",
+        )
+        .unwrap();
+        let filter = GlobFilter::from_files(
+            &["*.txt", "*.log"],
+            &["secret/*"],
+            &[] as &[&Path],
+            &[excludes],
+        )
+        .unwrap();
+        assert!(filter.is_match("notes.txt"));
+        assert!(!filter.is_match("image.png"));
+        assert!(!filter.is_match("secret/notes.txt"));
+        assert!(!filter.is_match("target/notes.txt"));
+        assert!(!filter.is_match("debug.log"));
+        // Comments are not treated as patterns
+        assert!(filter.is_match("#notes.txt"));
+
+        let missing = directory.path().join("missing");
+        assert!(matches!(
+            GlobFilter::from_files(&[] as &[&str], &[] as &[&str], &[missing], &[] as &[&Path]),
+            Err(FilterError::IO(_))
+        ));
+    }
+
+    #[test]
+    fn ignore_rules() {
+        let directory = tempfile::tempdir().unwrap();
+        let file = directory.path().join(".asuranignore");
+        fs::write(&file, "# Comment\n*.tmp\n/build/\ndocs/*.pdf\n").unwrap();
+        let rules = IgnoreRules::from_file(&file).unwrap();
+        assert!(rules.is_match("scratch.tmp"));
+        assert!(rules.is_match("deep/down/scratch.tmp"));
+        assert!(rules.is_match("build"));
+        assert!(!rules.is_match("src/build"));
+        assert!(rules.is_match("docs/manual.pdf"));
+        assert!(!rules.is_match("docs/old/manual.pdf"));
+        assert!(!rules.is_match("notes.txt"));
+    }
+}
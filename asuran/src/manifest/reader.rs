@@ -0,0 +1,115 @@
+//! Provides a pull based, `AsyncRead` view of an object stored in an archive
+//!
+//! This is the counterpart to `ActiveArchive::get_object`, which pushes the object into a `Write`.
+//! The reader is built on the executor agnostic `futures::io::AsyncRead`, users of tokio can adapt
+//! it with `tokio_util::compat`.
+use crate::manifest::archive::ChunkLocation;
+use crate::repository::{BackendClone, Repository, RepositoryError};
+
+use futures::future::BoxFuture;
+use futures::io::AsyncRead;
+
+use std::collections::VecDeque;
+use std::future::Future;
+use std::io;
+use std::pin::Pin;
+use std::task::{Context, Poll};
+
+type Fetch<T> = BoxFuture<'static, (Repository<T>, Result<Vec<u8>, RepositoryError>)>;
+
+/// An `AsyncRead` over the contents of an object, fetching its chunks lazily as they are read
+///
+/// Chunks are read in order of their position in the object, and any holes between them are
+/// filled in with zeros, producing the same bytes as `ActiveArchive::get_object`. Only one chunk
+/// is ever held in memory at a time.
+pub struct ObjectReader<T> {
+    /// The repository, which is moved into the pending fetch while one is in flight
+    repository: Option<Repository<T>>,
+    /// The chunks of the object that have not been fetched yet, sorted by position
+    locations: VecDeque<ChunkLocation>,
+    /// Position just past the last byte produced by a chunk
+    end: u64,
+    /// Number of zeros that still need to be produced to fill a hole
+    zeros: u64,
+    /// The contents of the current chunk
+    buffer: Vec<u8>,
+    /// Position of the next byte to produce within the buffer
+    position: usize,
+    /// The chunk currently being fetched, if any
+    pending: Option<Fetch<T>>,
+}
+
+impl<T: BackendClone> ObjectReader<T> {
+    /// Creates a reader over the given chunk locations
+    pub(crate) fn new(repository: Repository<T>, mut locations: Vec<ChunkLocation>) -> Self {
+        locations.sort_unstable();
+        let end = locations.first().map_or(0, |x| x.start);
+        ObjectReader {
+            repository: Some(repository),
+            locations: locations.into(),
+            end,
+            zeros: 0,
+            buffer: Vec::new(),
+            position: 0,
+            pending: None,
+        }
+    }
+}
+
+// No part of the reader is ever pinned in place
+impl<T> Unpin for ObjectReader<T> {}
+
+impl<T: BackendClone> AsyncRead for ObjectReader<T> {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut [u8],
+    ) -> Poll<io::Result<usize>> {
+        let this = &mut *self;
+        loop {
+            if this.position < this.buffer.len() {
+                let len = buf.len().min(this.buffer.len() - this.position);
+                buf[..len].copy_from_slice(&this.buffer[this.position..this.position + len]);
+                this.position += len;
+                return Poll::Ready(Ok(len));
+            }
+            if this.zeros > 0 {
+                #[allow(clippy::cast_possible_truncation)]
+                let len = (buf.len() as u64).min(this.zeros) as usize;
+                for byte in &mut buf[..len] {
+                    *byte = 0;
+                }
+                this.zeros -= len as u64;
+                return Poll::Ready(Ok(len));
+            }
+            if let Some(pending) = this.pending.as_mut() {
+                let (repository, result) = match Pin::new(pending).poll(cx) {
+                    Poll::Ready(x) => x,
+                    Poll::Pending => return Poll::Pending,
+                };
+                this.pending = None;
+                this.repository = Some(repository);
+                this.buffer = result.map_err(|e| io::Error::new(io::ErrorKind::Other, e))?;
+                this.position = 0;
+                continue;
+            }
+            let location = match this.locations.pop_front() {
+                Some(location) => location,
+                None => return Poll::Ready(Ok(0)),
+            };
+            // If a chunk is not included, fill the space inbetween it and the last with zeros
+            // Empty chunks cover no bytes, so this is tracked as an exclusive end
+            this.zeros = location.start.saturating_sub(this.end);
+            this.end = location.start + location.length;
+            let mut repository = this
+                .repository
+                .take()
+                .expect("Repository missing with no fetch in flight");
+            let id = location.id;
+            this.pending = Some(Box::pin(async move {
+                let result = repository.read_chunk(id).await;
+                (repository, result)
+            }));
+        }
+    }
+}
@@ -0,0 +1,143 @@
+//! Provides a push based, `AsyncWrite` interface for storing an object in an archive
+//!
+//! This is the counterpart to `ActiveArchive::put_object`, which pulls the object out of a `Read`.
+//! Like the reader, it is built on the executor agnostic `futures::io::AsyncWrite`.
+use crate::chunker::AsyncChunker;
+use crate::manifest::archive::{ActiveArchive, ArchiveError};
+use crate::repository::{BackendClone, Repository};
+
+use futures::channel::mpsc;
+use futures::io::AsyncWrite;
+use futures::stream::StreamExt;
+use smol::Task;
+
+use std::future::Future;
+use std::io::{self, Read};
+use std::pin::Pin;
+use std::task::{Context, Poll};
+
+/// A `Read` over the buffers written into an `ObjectWriter`
+struct WriterPipe {
+    input: mpsc::Receiver<Vec<u8>>,
+    buffer: Vec<u8>,
+    position: usize,
+}
+
+impl Read for WriterPipe {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        while self.position == self.buffer.len() {
+            match smol::block_on(self.input.next()) {
+                Some(block) => {
+                    self.buffer = block;
+                    self.position = 0;
+                }
+                // The writer hanging up signals the end of the object
+                None => return Ok(0),
+            }
+        }
+        let len = buf.len().min(self.buffer.len() - self.position);
+        buf[..len].copy_from_slice(&self.buffer[self.position..self.position + len]);
+        self.position += len;
+        Ok(len)
+    }
+}
+
+/// An `AsyncWrite` that chunks and stores the bytes written to it as an object in an archive
+///
+/// Writes are handed off to a background task running `ActiveArchive::put_object`, which
+/// buffers across writes as needed to find chunk boundaries. The object is only finalized, and
+/// added to the archive, once the writer is closed, and any errors that occurred while storing it
+/// are reported from `poll_close`.
+pub struct ObjectWriter {
+    /// Sends written buffers to the storing task, dropped once the writer is closed
+    sender: Option<mpsc::Sender<Vec<u8>>>,
+    /// The task storing the object, taken once it has completed
+    task: Option<Task<Result<(), ArchiveError>>>,
+}
+
+impl ObjectWriter {
+    /// Starts a task storing an object at the given path in the archive
+    pub(crate) fn new<C, T>(
+        archive: &ActiveArchive,
+        chunker: &C,
+        repository: &Repository<T>,
+        path: &str,
+    ) -> ObjectWriter
+    where
+        C: AsyncChunker + 'static,
+        T: BackendClone,
+    {
+        let (sender, receiver) = mpsc::channel(repository.queue_depth);
+        let pipe = WriterPipe {
+            input: receiver,
+            buffer: Vec::new(),
+            position: 0,
+        };
+        let mut archive = archive.clone();
+        let chunker = chunker.clone();
+        let mut repository = repository.clone();
+        let path = path.to_string();
+        let task = Task::spawn(async move {
+            archive
+                .put_object(&chunker, &mut repository, &path, pipe)
+                .await
+                .map(|_| ())
+        });
+        ObjectWriter {
+            sender: Some(sender),
+            task: Some(task),
+        }
+    }
+}
+
+/// Produces the error returned once the storing task is no longer accepting data
+fn closed() -> io::Error {
+    io::Error::new(
+        io::ErrorKind::BrokenPipe,
+        "Object writer is closed, or storing the object failed",
+    )
+}
+
+impl AsyncWrite for ObjectWriter {
+    fn poll_write(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        let sender = match self.sender.as_mut() {
+            Some(sender) => sender,
+            None => return Poll::Ready(Err(closed())),
+        };
+        match sender.poll_ready(cx) {
+            Poll::Ready(Ok(())) => {}
+            Poll::Ready(Err(_)) => return Poll::Ready(Err(closed())),
+            Poll::Pending => return Poll::Pending,
+        }
+        if sender.start_send(buf.to_vec()).is_err() {
+            return Poll::Ready(Err(closed()));
+        }
+        Poll::Ready(Ok(buf.len()))
+    }
+
+    /// Everything written is already queued for the storing task, which can not be asked to cut
+    /// a chunk early, so this does nothing. Close the writer to finish storing the object.
+    fn poll_flush(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Poll::Ready(Ok(()))
+    }
+
+    fn poll_close(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        // Hanging up lets the storing task see the end of the object
+        self.sender = None;
+        let task = match self.task.as_mut() {
+            Some(task) => task,
+            None => return Poll::Ready(Ok(())),
+        };
+        match Pin::new(task).poll(cx) {
+            Poll::Ready(result) => {
+                self.task = None;
+                Poll::Ready(result.map_err(|e| io::Error::new(io::ErrorKind::Other, e)))
+            }
+            Poll::Pending => Poll::Pending,
+        }
+    }
+}
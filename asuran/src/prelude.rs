@@ -4,6 +4,7 @@ pub use crate::manifest::target::*;
 pub use crate::manifest::*;
 pub use crate::repository::backend::common::sync_backend::BackendHandle;
 pub use crate::repository::backend::flatfile::FlatFile;
+#[cfg(not(target_arch = "wasm32"))]
 pub use crate::repository::backend::multifile::MultiFile;
 pub use crate::repository::backend::*;
 pub use crate::repository::*;
@@ -40,20 +40,41 @@
 //!
 //! Asuran will not write a chunk whose key already exists in the repository,
 //! effectivly preventing the storage of duplicate chunks.
-pub use crate::repository::backend::{Backend, BackendClone, Index, SegmentDescriptor};
+use crate::manifest::archive::Node;
+use crate::manifest::{ArchiveError, GlobFilter, StoredArchive};
+pub use crate::repository::audit::{AuditEntry, AuditHead, AuditOperation};
+pub use crate::repository::backend::common::DanglingParent;
+use crate::repository::backend::Manifest as BackendManifest;
+pub use crate::repository::backend::{
+    Backend, BackendClone, BackendObject, BackendSettings, Index, RetryPolicy, SegmentDescriptor,
+};
 use crate::repository::pipeline::Pipeline;
+pub use crate::repository::quota::SizeQuota;
+use crate::repository::quota::{quota_id, QuotaState};
+pub use crate::repository::statistics::RepoStatistics;
 
-pub use asuran_core::repository::chunk::{Chunk, ChunkID, ChunkSettings};
+pub use asuran_core::repository::chunk::{
+    Chunk, ChunkBody, ChunkHeader, ChunkID, ChunkSettings, ConfigError, ParseChunkIDError,
+};
 pub use asuran_core::repository::compression::Compression;
+pub use asuran_core::repository::encryption;
 pub use asuran_core::repository::encryption::Encryption;
-pub use asuran_core::repository::hmac::HMAC;
-pub use asuran_core::repository::key::{EncryptedKey, Key};
+pub use asuran_core::repository::hmac::{register_hmac, Hmac, HmacRegistryError, HMAC};
+pub use asuran_core::repository::key::{EncryptedKey, Key, KeyError, SecondFactor};
 
+use async_lock::Lock;
+use chrono::{DateTime, FixedOffset};
 use thiserror::Error;
-use tracing::{debug, info, instrument, span, trace, Level};
+use tracing::{debug, error, info, instrument, span, trace, warn, Level};
 
+use std::collections::HashSet;
+use std::sync::{Arc, Mutex};
+
+pub mod audit;
 pub mod backend;
 pub mod pipeline;
+pub mod quota;
+pub mod statistics;
 
 /// An error for all the various things that can go wrong with handling chunks
 #[derive(Error, Debug)]
@@ -64,10 +85,95 @@ pub enum RepositoryError {
     ChunkerError(#[from] asuran_core::repository::chunk::ChunkError),
     #[error("Backend Error")]
     BackendError(#[from] backend::BackendError),
+    #[error("Invalid Chunk Settings: {0}")]
+    InvalidChunkSettings(#[from] ConfigError),
+    #[error("Chunk failed verification, its contents do not match its id: {0:?}")]
+    VerificationFailed(ChunkID),
+    #[error("Chunk settings override is incompatible with the repository: {0}")]
+    IncompatibleChunkSettings(String),
+    #[error("Unable to decrypt key material, possibly due to an invalid password")]
+    Key(#[from] KeyError),
+}
+
+impl RepositoryError {
+    /// Returns true if this error indicates that the requested data is genuinely absent from the
+    /// repository, rather than that some other failure, such as an I/O error, occurred
+    pub fn is_not_found(&self) -> bool {
+        match self {
+            RepositoryError::ChunkNotFound => true,
+            RepositoryError::BackendError(e) => e.is_not_found(),
+            _ => false,
+        }
+    }
+
+    /// Returns a short, stable, `snake_case` name for the kind of this error
+    ///
+    /// Unlike the `Display` output, these names will not change between releases, and are
+    /// suitable for matching on in scripts.
+    pub fn kind(&self) -> &'static str {
+        match self {
+            RepositoryError::ChunkNotFound => "chunk_not_found",
+            RepositoryError::ChunkerError(_) => "chunk_unpack_failed",
+            RepositoryError::BackendError(e) => e.kind(),
+            RepositoryError::InvalidChunkSettings(_) => "invalid_chunk_settings",
+            RepositoryError::VerificationFailed(_) => "verification_failed",
+            RepositoryError::IncompatibleChunkSettings(_) => "incompatible_chunk_settings",
+            RepositoryError::Key(e) => e.kind(),
+        }
+    }
 }
 
 type Result<T> = std::result::Result<T, RepositoryError>;
 
+/// Constant that is HMACed under the repository's key to produce its fingerprint
+const KEY_FINGERPRINT_CONSTANT: &[u8] = b"asuran key fingerprint";
+/// Number of bytes of the HMAC kept in a key fingerprint
+const KEY_FINGERPRINT_LENGTH: usize = 8;
+
+/// Describes how a single chunk is stored in the repository
+///
+/// Produced by `Repository::chunk_info`, which does not decrypt or decompress the chunk's body.
+#[derive(Debug, Clone, PartialEq)]
+pub struct ChunkInfo {
+    /// The id of the chunk
+    pub id: ChunkID,
+    /// Compression algorithm the chunk's body was packed with
+    pub compression: Compression,
+    /// Encryption algorithm the chunk's body was packed with
+    pub encryption: Encryption,
+    /// HMAC algorithm used to compute the chunk's id and mac
+    pub hmac: HMAC,
+    /// Length of the chunk's body as stored, after compression and encryption
+    pub stored_length: usize,
+    /// Where the chunk lives in the backend
+    pub location: SegmentDescriptor,
+}
+
+/// Counts of the chunks written through a `Repository`, and how many of them were deduplicated
+///
+/// Filled in by `Repository::write_chunk_counted`, and summed up by the archive methods storing
+/// objects. Counts from separate writes can be combined with `+=`.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct WriteStats {
+    /// Number of bytes of data handed to the repository, before compression and encryption
+    pub bytes_read: u64,
+    /// Number of chunks that were not yet in the repository, and were written to the backend
+    pub new_chunks: u64,
+    /// Number of chunks that were already in the repository, and were not written again
+    pub deduplicated_chunks: u64,
+    /// Number of bytes written to the backend, after compression and encryption
+    pub bytes_written: u64,
+}
+
+impl std::ops::AddAssign for WriteStats {
+    fn add_assign(&mut self, other: WriteStats) {
+        self.bytes_read += other.bytes_read;
+        self.new_chunks += other.new_chunks;
+        self.deduplicated_chunks += other.deduplicated_chunks;
+        self.bytes_written += other.bytes_written;
+    }
+}
+
 /// Provides an interface to the storage-backed key value store
 ///
 /// File access is abstracted behind a swappable backend, all backends should
@@ -88,11 +194,28 @@ pub struct Repository<T> {
     pipeline: Pipeline,
     /// Depth of queues to build
     pub queue_depth: usize,
+    /// Whether or not to recompute the id of each chunk after reading it
+    verify_on_read: bool,
+    /// The size quota, lazily loaded from the repository
+    quota: Arc<Mutex<QuotaState>>,
+    /// How to retry writes that fail with a transient error
+    retry_policy: RetryPolicy,
+    /// Serializes appends to the audit log between clones of a `Repository`
+    audit_lock: Lock<()>,
+}
+
+/// Returns true if the chunk with the given id is one of the reserved chunks, the manifest and the
+/// quota, whose ids are not derived from their contents
+fn is_reserved(id: ChunkID) -> bool {
+    id == ChunkID::manifest_id() || id == quota_id()
 }
 
 impl<T: BackendClone + 'static> Repository<T> {
     /// Creates a new repository with the specificed backend and defaults
-    #[instrument(skip(key))]
+    ///
+    /// # Errors
+    ///
+    /// Will return `Err(InvalidChunkSettings)` if the settings fail `ChunkSettings::validate`
     pub fn new(
         backend: T,
         compression: Compression,
@@ -100,34 +223,34 @@ impl<T: BackendClone + 'static> Repository<T> {
         encryption: Encryption,
         key: Key,
         pipeline_tasks: usize,
-    ) -> Repository<T> {
-        info!("Creating a repository with backend {:?}", backend);
-        let pipeline = Pipeline::new(pipeline_tasks);
-        Repository {
-            backend,
+    ) -> Result<Repository<T>> {
+        let settings = ChunkSettings {
             compression,
-            hmac,
             encryption,
-            key,
-            pipeline,
-            queue_depth: pipeline_tasks,
-        }
+            hmac,
+        };
+        Self::with(backend, settings, key, pipeline_tasks)
     }
 
     /// Creates a new repository, accepting a ChunkSettings and a ThreadPool
+    ///
+    /// # Errors
+    ///
+    /// Will return `Err(InvalidChunkSettings)` if the settings fail `ChunkSettings::validate`
     #[instrument(skip(key))]
     pub fn with(
         backend: T,
         settings: ChunkSettings,
         key: Key,
         pipeline_tasks: usize,
-    ) -> Repository<T> {
+    ) -> Result<Repository<T>> {
         info!(
             "Creating a repository with backend {:?} and chunk settings {:?}",
             backend, settings
         );
+        settings.validate()?;
         let pipeline = Pipeline::new(pipeline_tasks);
-        Repository {
+        Ok(Repository {
             backend,
             key,
             pipeline,
@@ -135,21 +258,86 @@ impl<T: BackendClone + 'static> Repository<T> {
             hmac: settings.hmac,
             encryption: settings.encryption,
             queue_depth: pipeline_tasks,
-        }
+            verify_on_read: false,
+            quota: Arc::default(),
+            retry_policy: RetryPolicy::default(),
+            audit_lock: Lock::new(()),
+        })
+    }
+
+    /// Opens the repository described by `backend`, creating it first if it does not exist yet
+    ///
+    /// When creating, a fresh random key is generated and written to the repository encrypted with
+    /// `password`, and `settings` become the repository's default chunk settings. When opening,
+    /// the existing key is decrypted with `password`, and the repository's recorded default chunk
+    /// settings are left untouched. Either way, `settings` are used for chunks written through
+    /// the returned `Repository`.
+    ///
+    /// # Errors
+    ///
+    /// - `Err(Key)` if the key of an existing repository can not be decrypted, such as when the
+    ///   password is wrong
+    /// - `Err(InvalidChunkSettings)` if the settings fail `ChunkSettings::validate`
+    /// - `Err(BackendError)` if reading the key, or opening or creating the backend fails
+    #[instrument(skip(backend, password))]
+    pub async fn open_or_create<S: BackendSettings<Backend = T>>(
+        backend: &S,
+        password: &[u8],
+        settings: ChunkSettings,
+        pipeline_tasks: usize,
+    ) -> Result<Repository<T>> {
+        settings.validate()?;
+        let (backend, key) = match backend.read_key().await? {
+            Some(encrypted_key) => {
+                debug!("Opening existing repository");
+                let key = encrypted_key.decrypt(password)?;
+                (backend.open(&key).await?, key)
+            }
+            None => {
+                info!("Creating new repository");
+                let key = Key::random(settings.encryption.key_length());
+                let encrypted_key =
+                    EncryptedKey::encrypt_defaults(&key, settings.encryption, password);
+                (backend.create(&key, &encrypted_key, settings).await?, key)
+            }
+        };
+        Ok(Self::with(backend, settings, key, pipeline_tasks))
     }
 
     /// Commits the index to storage
     ///
     /// This should be called every time an archive or manifest is written, at
     /// the very least
+    ///
+    /// Also writes out the quota's usage, if it has changed.
+    ///
+    /// # Errors
+    ///
+    /// Will return `Err` if writing the quota or committing the index fails
     #[instrument(skip(self))]
-    pub async fn commit_index(&self) {
+    pub async fn commit_index(&self) -> Result<()> {
         debug!("Commiting Index");
-        self.backend
-            .get_index()
-            .commit_index()
-            .await
-            .expect("Unable to commit index");
+        self.clone().persist_quota().await?;
+        self.backend.get_index().commit_index().await?;
+        Ok(())
+    }
+
+    /// Forces all writes made so far to durable storage, without closing the repository
+    ///
+    /// Writes out the quota's usage if it has changed, and then has the backend flush its
+    /// buffers, commit the index, and fsync its files where it is able to. Chunks written before
+    /// this call will still be present if the process is killed afterwards, making this suitable
+    /// for establishing checkpoints during long running backups.
+    ///
+    /// # Errors
+    ///
+    /// Will return `Err` if writing the quota fails, or the backend is unable to sync.
+    #[instrument(skip(self))]
+    pub async fn sync(&mut self) -> Result<()> {
+        debug!("Syncing repository");
+        self.persist_quota().await?;
+        self.backend.sync().await?;
+        Ok(())
     }
 
     /// Writes a chunk directly to the repository
@@ -159,28 +347,70 @@ impl<T: BackendClone + 'static> Repository<T> {
     /// `Already_Present` will be true if the chunk already exists in the
     /// repository.
     pub async fn write_raw(&mut self, chunk: Chunk) -> Result<(ChunkID, bool)> {
+        self.write_raw_counted(chunk, &mut WriteStats::default())
+            .await
+    }
+
+    /// Implementation of `write_raw`, counting the chunk as new or deduplicated in `stats`
+    async fn write_raw_counted(
+        &mut self,
+        chunk: Chunk,
+        stats: &mut WriteStats,
+    ) -> Result<(ChunkID, bool)> {
         let id = chunk.get_id();
         let span = span!(Level::DEBUG, "Writing Chunk", ?id);
         let _guard = span.enter();
         debug!("Writing chunk with id {:?}", id);
 
         // Check if chunk exists
-        if self.has_chunk(id).await && id != ChunkID::manifest_id() {
+        if self.has_chunk(id).await? && !is_reserved(id) {
             trace!("Chunk already existed, doing nothing.");
+            stats.deduplicated_chunks += 1;
             Ok((id, true))
         } else {
             trace!("Chunk did not exist, continuning");
+            let length = chunk.len() as u64;
+            if id != quota_id() {
+                self.charge_quota(length).await?;
+            }
 
-            // Get highest segment and check to see if has enough space
-            let backend = &mut self.backend;
-            let location = backend.write_chunk(chunk).await?;
+            let written = self.write_with_retry(chunk).await;
+            if written.is_err() && id != quota_id() {
+                self.refund_quota(length);
+            }
+            let location = written?;
 
             self.backend.get_index().set_chunk(id, location).await?;
 
+            stats.new_chunks += 1;
+            stats.bytes_written += length;
             Ok((id, false))
         }
     }
 
+    /// Writes a chunk to the backend, retrying transient failures according to the retry policy
+    ///
+    /// Failures the backend has already retried itself are not retryable, and are returned as is.
+    async fn write_with_retry(&mut self, chunk: Chunk) -> Result<SegmentDescriptor> {
+        let mut attempt = 0;
+        loop {
+            match self.backend.write_chunk(chunk.clone()).await {
+                Err(e) if attempt < self.retry_policy.max_attempts && e.is_retryable() => {
+                    let backoff = self.retry_policy.backoff(attempt);
+                    warn!(
+                        "Writing chunk {:?} failed, retrying in {:?}: {}",
+                        chunk.get_id(),
+                        backoff,
+                        e
+                    );
+                    crate::runtime::sleep(backoff).await;
+                    attempt += 1;
+                }
+                result => return Ok(result?),
+            }
+        }
+    }
+
     /// Writes a chunk to the repo
     ///
     /// Uses all defaults
@@ -192,17 +422,95 @@ impl<T: BackendClone + 'static> Repository<T> {
     /// Repository, and false otherwise
     #[instrument(skip(self, data))]
     pub async fn write_chunk(&mut self, data: Vec<u8>) -> Result<(ChunkID, bool)> {
+        self.write_chunk_counted(data, None, &mut WriteStats::default())
+            .await
+    }
+
+    /// Writes a chunk to the repo, adding the length of `data`, and whether the chunk was new or
+    /// already present, to `stats`
+    ///
+    /// If `settings` are provided, their compression is used in place of the repository's
+    /// default, as with `write_chunk_with_settings`.
+    ///
+    /// # Errors
+    ///
+    /// Will return `Err(IncompatibleChunkSettings)` if the settings do not pass
+    /// `check_settings_override`, or `Err` if writing the chunk fails
+    #[instrument(skip(self, data, stats))]
+    pub async fn write_chunk_counted(
+        &mut self,
+        data: Vec<u8>,
+        settings: Option<ChunkSettings>,
+        stats: &mut WriteStats,
+    ) -> Result<(ChunkID, bool)> {
+        let compression = if let Some(settings) = settings {
+            self.check_settings_override(settings)?;
+            settings.compression
+        } else {
+            self.compression
+        };
+        stats.bytes_read += data.len() as u64;
         let chunk = self
             .pipeline
             .process(
                 data,
-                self.compression,
+                compression,
                 self.encryption,
                 self.hmac,
                 self.key.clone(),
             )
             .await;
-        self.write_raw(chunk).await
+        self.write_raw_counted(chunk, stats).await
+    }
+
+    /// Writes a chunk to the repo, using the compression from the provided settings in place
+    /// of the repository's default
+    ///
+    /// Will not write the chunk if it already exists, in which case it keeps the compression it
+    /// was originally written with.
+    ///
+    /// # Errors
+    ///
+    /// Will return `Err(IncompatibleChunkSettings)` if the settings do not pass
+    /// `check_settings_override`
+    #[instrument(skip(self, data))]
+    pub async fn write_chunk_with_settings(
+        &mut self,
+        data: Vec<u8>,
+        settings: ChunkSettings,
+    ) -> Result<(ChunkID, bool)> {
+        self.write_chunk_counted(data, Some(settings), &mut WriteStats::default())
+            .await
+    }
+
+    /// Checks that a set of chunk settings can be used to override the repository's defaults
+    /// for some chunks
+    ///
+    /// Only the compression may differ from the repository's settings, as chunk ids are derived
+    /// with the repository's HMAC, and the encryption algorithm is part of the security
+    /// properties of the repository. The IV of the encryption is not compared, as a fresh one is
+    /// used for every chunk.
+    ///
+    /// # Errors
+    ///
+    /// - `Err(IncompatibleChunkSettings)` if the encryption algorithm or HMAC differ
+    /// - `Err(InvalidChunkSettings)` if the settings fail `ChunkSettings::validate`
+    pub fn check_settings_override(&self, settings: ChunkSettings) -> Result<()> {
+        if std::mem::discriminant(&settings.encryption) != std::mem::discriminant(&self.encryption)
+        {
+            return Err(RepositoryError::IncompatibleChunkSettings(format!(
+                "encryption {:?} does not match the repository's {:?}",
+                settings.encryption, self.encryption
+            )));
+        }
+        if settings.hmac != self.hmac {
+            return Err(RepositoryError::IncompatibleChunkSettings(format!(
+                "HMAC {:?} does not match the repository's {:?}",
+                settings.hmac, self.hmac
+            )));
+        }
+        settings.validate()?;
+        Ok(())
     }
 
     /// Writes a chunk to the repo
@@ -234,44 +542,652 @@ impl<T: BackendClone + 'static> Repository<T> {
             .await;
         let mac = chunk.mac();
         let encryption = chunk.encryption();
+        let compression = chunk.compression();
         let data = (chunk.split().1).0;
-        chunk = Chunk::from_parts(data, self.compression, encryption, self.hmac, mac, id);
+        chunk = Chunk::from_parts(data, compression, encryption, self.hmac, mac, id);
         self.write_raw(chunk).await
     }
 
     /// Determines if a chunk exists in the index
+    ///
+    /// # Errors
+    ///
+    /// Will return `Err` if the backend's index could not be read
     #[instrument(skip(self))]
-    pub async fn has_chunk(&self, id: ChunkID) -> bool {
-        self.backend.get_index().lookup_chunk(id).await.is_some()
+    pub async fn has_chunk(&self, id: ChunkID) -> Result<bool> {
+        Ok(self.backend.get_index().lookup_chunk(id).await?.is_some())
     }
 
     /// Reads a chunk from the repo
     ///
     /// Returns none if reading the chunk fails
+    ///
+    /// The chunk is unpacked with the compression, encryption, and HMAC recorded in its own
+    /// header, not the repository's current settings, so chunks written with other settings, such
+    /// as by `write_chunk_with_settings` or before a change of defaults, are still read correctly.
+    ///
+    /// If verification on read is enabled, will return `Err(VerificationFailed)` if the contents
+    /// of the chunk do not hash to the requested id.
     #[instrument(skip(self))]
     pub async fn read_chunk(&mut self, id: ChunkID) -> Result<Vec<u8>> {
         // First, check if the chunk exists
-        if self.has_chunk(id).await {
+        if self.has_chunk(id).await? {
             let mut index = self.backend.get_index();
-            let location = index.lookup_chunk(id).await.unwrap_or_else(|| {
-                panic!("Index lied to us about having the chunk with ID {:?}", id)
-            });
+            // The chunk may have been removed since we checked
+            let location = index
+                .lookup_chunk(id)
+                .await?
+                .ok_or(backend::BackendError::ChunkNotFound(id))?;
             let chunk = self.backend.read_chunk(location).await?;
 
             let data = chunk.unpack(&self.key)?;
 
+            if self.verify_on_read && !id.verify(&chunk.hmac().id(&data, &self.key)) {
+                return Err(RepositoryError::VerificationFailed(id));
+            }
+
             Ok(data)
         } else {
             Err(RepositoryError::ChunkNotFound)
         }
     }
 
+    /// Reads a chunk from the repository exactly as it is stored, without verifying or unpacking
+    /// it
+    ///
+    /// Returns the header of the chunk, describing how it was packed, along with its body, still
+    /// compressed and encrypted. The two can be reassembled with `Chunk::unsplit`, and written byte
+    /// for byte into another repository sharing this one's key with `write_raw`.
+    ///
+    /// # Errors
+    ///
+    /// Will return `Err(ChunkNotFound)` if the chunk is not in the index, or `Err` if the backend
+    /// fails to read it.
+    #[instrument(skip(self))]
+    pub async fn read_chunk_raw(&mut self, id: ChunkID) -> Result<(ChunkHeader, Vec<u8>)> {
+        let location = self
+            .backend
+            .get_index()
+            .lookup_chunk(id)
+            .await?
+            .ok_or(RepositoryError::ChunkNotFound)?;
+        let chunk = self.backend.read_chunk(location).await?;
+        let (header, body) = chunk.split();
+        Ok((header, body.0))
+    }
+
+    /// Describes how the chunk with the given id is stored, without unpacking its body
+    ///
+    /// Only the chunk's header is read, where the backend supports it.
+    ///
+    /// # Errors
+    ///
+    /// Will return `Err(ChunkNotFound)` if the chunk is not in the index, or `Err` if the backend
+    /// fails to read it.
+    #[instrument(skip(self))]
+    pub async fn chunk_info(&mut self, id: ChunkID) -> Result<ChunkInfo> {
+        let location = self
+            .backend
+            .get_index()
+            .lookup_chunk(id)
+            .await?
+            .ok_or(RepositoryError::ChunkNotFound)?;
+        let (header, length) = self.backend.read_chunk_header(location).await?;
+        Ok(ChunkInfo {
+            id,
+            compression: header.compression(),
+            encryption: header.encryption(),
+            hmac: header.hmac(),
+            stored_length: length as usize,
+            location,
+        })
+    }
+
+    /// Hints to the backend that the given chunks are about to be read, in the given order
+    ///
+    /// This allows the backend to start warming its caches before the reads actually occur, and
+    /// is most useful when the full list of chunks to be read is known up front, such as during a
+    /// restore. Chunks that are not in the repository are skipped.
+    #[instrument(skip(self, ids))]
+    pub async fn prefetch(&mut self, ids: &[ChunkID]) -> Result<()> {
+        let mut index = self.backend.get_index();
+        let mut locations = Vec::with_capacity(ids.len());
+        for id in ids {
+            if let Some(location) = index.lookup_chunk(*id).await? {
+                locations.push(location);
+            }
+        }
+        self.backend.prefetch(locations).await?;
+        Ok(())
+    }
+
     /// Provides a count of the number of chunks in the repository
     #[instrument(skip(self))]
     pub async fn count_chunk(&self) -> usize {
         self.backend.get_index().count_chunk().await
     }
 
+    /// Provides an iterator over the IDs of every chunk known to the repository's index
+    ///
+    /// This only consults the index, and does not read any chunk bodies from the backend.
+    #[instrument(skip(self))]
+    pub async fn iter_chunk_ids(&self) -> std::collections::hash_set::IntoIter<ChunkID> {
+        self.backend.get_index().known_chunks().await.into_iter()
+    }
+
+    /// Finds the chunks in the repository whose ids start with the provided hex prefix
+    ///
+    /// The prefix is matched case insensitively against `ChunkID::to_hex`, in the style of git's
+    /// abbreviated hashes. This is intended for debugging and inspection, and scans the entire
+    /// index. The results are sorted by their hex representation.
+    #[instrument(skip(self))]
+    pub async fn find_chunks_by_prefix(&self, prefix: &str) -> Vec<ChunkID> {
+        let prefix = prefix.to_ascii_lowercase();
+        let mut matches: Vec<(String, ChunkID)> = self
+            .iter_chunk_ids()
+            .await
+            .map(|id| (id.to_hex(), id))
+            .filter(|(hex, _)| hex.starts_with(&prefix))
+            .collect();
+        matches.sort_unstable_by(|a, b| a.0.cmp(&b.0));
+        matches.into_iter().map(|(_, id)| id).collect()
+    }
+
+    /// Takes exclusive access to the repository, see `Backend::lock_exclusive`
+    ///
+    /// Every other connection to the repository must be closed first, and no new ones can be
+    /// opened until `unlock_exclusive` is called.
+    ///
+    /// # Errors
+    ///
+    /// Will return `Err` if another connection has the repository open, or the lock can not be
+    /// taken
+    pub async fn lock_exclusive(&mut self) -> Result<()> {
+        Ok(self.backend.lock_exclusive().await?)
+    }
+
+    /// Releases the exclusive access taken by `lock_exclusive`
+    ///
+    /// # Errors
+    ///
+    /// Will return `Err` if the lock can not be released
+    pub async fn unlock_exclusive(&mut self) -> Result<()> {
+        Ok(self.backend.unlock_exclusive().await?)
+    }
+
+    /// Removes every chunk in the repository that is not in the provided live set, and reclaims
+    /// the space they used
+    ///
+    /// This is the sweep phase of garbage collection, the live set is expected to be computed by
+    /// the caller, see `Manifest::gc`. The reserved manifest and quota chunks, and the entries of
+    /// the audit log, are never removed. The collection is recorded in the audit log.
+    ///
+    /// The repository is locked with `lock_exclusive` for the duration. As the live set was
+    /// computed before the lock was taken, archives committed in the meantime by other connections
+    /// may lose their chunks, so callers should prefer `Manifest::gc`, which computes the live set
+    /// while holding the lock.
+    ///
+    /// Returns the number of chunks that were removed.
+    ///
+    /// # Errors
+    ///
+    /// Will return `Err` if another connection has the repository open, if the backend does not
+    /// support removing chunks, or if removing a chunk, committing the index, or reclaiming the
+    /// space fails.
+    #[instrument(skip(self, live))]
+    pub async fn gc(&mut self, live: &HashSet<ChunkID>) -> Result<usize> {
+        self.lock_exclusive().await?;
+        let result = self.sweep(live).await;
+        self.unlock_exclusive().await?;
+        result
+    }
+
+    /// Performs the work of `gc`, without taking the lock
+    ///
+    /// Must only be called while holding `lock_exclusive`.
+    pub(crate) async fn sweep(&mut self, live: &HashSet<ChunkID>) -> Result<usize> {
+        let audit = self.audit_chunk_ids().await?;
+        let mut removed = 0;
+        for id in self.iter_chunk_ids().await {
+            if !is_reserved(id) && !live.contains(&id) && !audit.contains(&id) {
+                trace!("Removing dead chunk {:?}", id);
+                self.backend.remove_chunk(id).await?;
+                removed += 1;
+            }
+        }
+        self.record_audit(AuditOperation::Prune, None).await?;
+        self.backend.get_index().commit_index().await?;
+        let freed = self.backend.compact().await?;
+        info!(
+            "Garbage collection removed {} chunks, freeing {} segments",
+            removed, freed
+        );
+        Ok(removed)
+    }
+
+    /// Rewrites every chunk in the repository with the compression and encryption from the
+    /// provided settings, and makes them the repository's defaults
+    ///
+    /// Each chunk is read, repacked with `Chunk::repack`, and written back under the same id, so
+    /// archives do not need to be touched. Chunks already using the requested settings are left
+    /// alone, unless the compression is `Compression::Auto`. The space taken by the old copies is
+    /// not reclaimed, and counts against any quota, see `set_size_quota`.
+    ///
+    /// Returns the number of chunks that were rewritten. The rewrite is recorded in the audit log.
+    ///
+    /// This must only be run while no other connections to the repository are open.
+    ///
+    /// # Errors
+    ///
+    /// - `Err(IncompatibleChunkSettings)` if the settings use a different HMAC than the
+    ///   repository. Changing the HMAC would change the id of every chunk, requiring every
+    ///   archive to be rewritten, which is not supported.
+    /// - `Err(InvalidChunkSettings)` if the settings fail `ChunkSettings::validate`
+    /// - `Err` if reading, unpacking, or writing any of the chunks fails
+    #[instrument(skip(self))]
+    pub async fn recompress(&mut self, settings: ChunkSettings) -> Result<usize> {
+        settings.validate()?;
+        let current_hmac = self.backend_manifest().chunk_settings().await.hmac;
+        if settings.hmac != current_hmac {
+            return Err(RepositoryError::IncompatibleChunkSettings(format!(
+                "HMAC {:?} does not match the repository's {:?}, and chunk ids can not be changed",
+                settings.hmac, current_hmac
+            )));
+        }
+        let mut index = self.backend.get_index();
+        let mut rewritten = 0;
+        for id in self.iter_chunk_ids().await {
+            let location = index
+                .lookup_chunk(id)
+                .await?
+                .ok_or(backend::BackendError::ChunkNotFound(id))?;
+            let chunk = self.backend.read_chunk(location).await?;
+            if chunk.hmac() != settings.hmac {
+                return Err(RepositoryError::IncompatibleChunkSettings(format!(
+                    "chunk {:?} uses HMAC {:?}, and its id can not be changed",
+                    id,
+                    chunk.hmac()
+                )));
+            }
+            let same_encryption = std::mem::discriminant(&chunk.encryption())
+                == std::mem::discriminant(&settings.encryption);
+            let auto = matches!(settings.compression, Compression::Auto { .. });
+            if same_encryption && !auto && chunk.compression() == settings.compression {
+                continue;
+            }
+            trace!("Recompressing chunk {:?}", id);
+            let chunk = chunk.repack(
+                settings.compression,
+                settings.encryption.new_iv(),
+                &self.key,
+            )?;
+            let length = chunk.len() as u64;
+            if id != quota_id() {
+                self.charge_quota(length).await?;
+            }
+            let written = self.write_with_retry(chunk).await;
+            if written.is_err() && id != quota_id() {
+                self.refund_quota(length);
+            }
+            index.set_chunk(id, written?).await?;
+            rewritten += 1;
+        }
+        self.record_audit(AuditOperation::Recompress, None).await?;
+        index.commit_index().await?;
+        self.backend_manifest()
+            .write_chunk_settings(settings)
+            .await?;
+        self.compression = settings.compression;
+        self.encryption = settings.encryption;
+        info!("Recompressed {} chunks", rewritten);
+        Ok(rewritten)
+    }
+
+    /// Re-encrypts the repository's key under a new encryption algorithm, keeping the passphrase
+    ///
+    /// Only the `EncryptedKey` stored in the backend is rewritten, the key material itself does
+    /// not change, so existing chunks remain readable as they are. The rewrap is recorded in the
+    /// audit log.
+    ///
+    /// # Errors
+    ///
+    /// - `Err(Key)` if the stored key can not be decrypted with `passphrase`, such as when it is
+    ///   wrong, or the key requires a second factor
+    /// - `Err(BackendError)` if reading or writing the key fails. This is always the case for
+    ///   `FlatFile` repositories, whose key can not be rewritten once the repository is created.
+    #[instrument(skip(self, passphrase))]
+    pub async fn rewrap_key(
+        &mut self,
+        new_encryption: Encryption,
+        passphrase: &[u8],
+    ) -> Result<()> {
+        let encrypted_key = self.backend.read_key().await?;
+        let rewrapped = encrypted_key.rewrap(passphrase, new_encryption)?;
+        self.backend.write_key(&rewrapped).await?;
+        self.record_audit(AuditOperation::KeyRotation, None).await?;
+        info!("Rewrapped repository key");
+        Ok(())
+    }
+
+    /// Sets the maximum number of bytes, as stored after compression and encryption, that the
+    /// repository may hold
+    ///
+    /// Once set, writing a new chunk that would take the repository's usage past the quota fails
+    /// with `BackendError::QuotaExceeded`. The quota is stored in the repository, so it is
+    /// enforced across sessions, and usage is kept as a running total of the bytes written, which
+    /// is saved with the index. Removing chunks does not reduce the usage, as not all backends
+    /// reclaim the space.
+    ///
+    /// When a quota is first set, the usage is initialized by reading every chunk in the
+    /// repository, so this can be slow on large repositories.
+    ///
+    /// # Errors
+    ///
+    /// Will return `Err` if reading the chunks or writing the quota fails
+    #[instrument(skip(self))]
+    pub async fn set_size_quota(&mut self, bytes: u64) -> Result<()> {
+        let usage = match self.size_quota().await? {
+            Some(quota) => quota.usage,
+            None => self.stored_size().await?,
+        };
+        {
+            let mut state = self.quota.lock().unwrap();
+            state.quota = Some(SizeQuota {
+                limit: bytes,
+                usage,
+            });
+            state.dirty = true;
+        }
+        self.persist_quota().await
+    }
+
+    /// Returns the repository's size quota and current usage, if a quota has been set
+    ///
+    /// # Errors
+    ///
+    /// Will return `Err` if reading the quota from the repository fails
+    pub async fn size_quota(&mut self) -> Result<Option<SizeQuota>> {
+        self.load_quota().await?;
+        Ok(self.quota.lock().unwrap().quota)
+    }
+
+    /// Reads the quota out of the repository, if it has not been already
+    async fn load_quota(&mut self) -> Result<()> {
+        if self.quota.lock().unwrap().loaded {
+            return Ok(());
+        }
+        // Read directly from the backend, as the quota chunk would fail verification on read
+        let location = self.backend.get_index().lookup_chunk(quota_id()).await?;
+        let quota = match location {
+            Some(location) => {
+                let bytes = self.backend.read_chunk(location).await?.unpack(&self.key)?;
+                Some(serde_cbor::de::from_slice(&bytes[..]).map_err(backend::BackendError::from)?)
+            }
+            None => None,
+        };
+        let mut state = self.quota.lock().unwrap();
+        // Another clone may have loaded it while we were reading
+        if !state.loaded {
+            state.quota = quota;
+            state.loaded = true;
+        }
+        Ok(())
+    }
+
+    /// Counts `bytes` against the quota, if there is one
+    ///
+    /// Returns `Err(QuotaExceeded)`, without changing the usage, if this would exceed the quota.
+    async fn charge_quota(&mut self, bytes: u64) -> Result<()> {
+        self.load_quota().await?;
+        let mut state = self.quota.lock().unwrap();
+        if let Some(quota) = state.quota.as_mut() {
+            if quota.would_exceed(bytes) {
+                return Err(backend::BackendError::QuotaExceeded {
+                    limit: quota.limit,
+                    usage: quota.usage,
+                }
+                .into());
+            }
+            quota.usage += bytes;
+            state.dirty = true;
+        }
+        Ok(())
+    }
+
+    /// Takes back a charge made by `charge_quota`, for a write that failed
+    fn refund_quota(&mut self, bytes: u64) {
+        let mut state = self.quota.lock().unwrap();
+        if let Some(quota) = state.quota.as_mut() {
+            quota.usage = quota.usage.saturating_sub(bytes);
+        }
+    }
+
+    /// Writes the quota to the repository, if its usage has changed
+    ///
+    /// If the write fails, the usage is left marked as changed, so the next call will try again.
+    async fn persist_quota(&mut self) -> Result<()> {
+        let quota = {
+            let mut state = self.quota.lock().unwrap();
+            if !state.dirty {
+                return Ok(());
+            }
+            state.dirty = false;
+            state.quota
+        };
+        if let Some(quota) = quota {
+            let result = match serde_cbor::ser::to_vec(&quota) {
+                Ok(bytes) => self
+                    .write_chunk_with_id(bytes, quota_id())
+                    .await
+                    .map(|_| ()),
+                Err(e) => Err(backend::BackendError::from(e).into()),
+            };
+            if result.is_err() {
+                self.quota.lock().unwrap().dirty = true;
+            }
+            result?;
+        }
+        Ok(())
+    }
+
+    /// Reads the audit log of the operations performed on the repository, oldest first
+    ///
+    /// Committing, copying, and deleting archives, garbage collection, and recompression append to
+    /// the log as they complete. Each entry is stored in its own chunk, HMAC tagged, and chained
+    /// to the one before it, and the latest entry is recorded in the manifest, so tampering with
+    /// the log can be detected, see `Repository::verify_audit_log`.
+    ///
+    /// # Errors
+    ///
+    /// Will return `Err` if reading or decoding any of the entries fails
+    pub async fn audit_log(&mut self) -> Result<Vec<AuditEntry>> {
+        let (entries, _) = self.audit_chain().await?;
+        Ok(entries.into_iter().map(|(_, entry)| entry).collect())
+    }
+
+    /// Reads the audit log, and checks that every entry is authentic, chained to the entry before
+    /// it, and that the log ends at the head recorded in the manifest
+    ///
+    /// Backends that do not record the head, such as `FlatFile`, have no head to check against,
+    /// so entries removed from the end of the log can not be detected on them.
+    ///
+    /// # Errors
+    ///
+    /// Will return `Err` if reading or decoding any of the entries fails, such as when the chunk
+    /// holding one has been removed
+    pub async fn verify_audit_log(&mut self) -> Result<bool> {
+        let (entries, head) = self.audit_chain().await?;
+        Ok(audit::verify_chain(&entries, head, &self.key))
+    }
+
+    /// Checks the manifest for transactions referencing previous heads that are missing, as left
+    /// behind by a partially written manifest
+    ///
+    /// Such transactions are treated as roots when the manifest is opened, so the repository
+    /// remains usable, but the archives written before the missing transactions may have been
+    /// lost. Returns an empty list if the manifest is intact.
+    ///
+    /// # Errors
+    ///
+    /// Will return `Err` if the backend fails to read the manifest
+    pub async fn verify_manifest(&mut self) -> Result<Vec<DanglingParent>> {
+        let dangling = self.backend_manifest().dangling_parents().await?;
+        for x in &dangling {
+            warn!(
+                "Manifest transaction {:?} references missing previous head {:?}",
+                x.transaction, x.parent
+            );
+        }
+        Ok(dangling)
+    }
+
+    /// Returns the ids of the chunks holding the entries of the audit log
+    async fn audit_chunk_ids(&mut self) -> Result<HashSet<ChunkID>> {
+        let (entries, _) = self.audit_chain().await?;
+        Ok(entries.into_iter().map(|(id, _)| id).collect())
+    }
+
+    /// Walks the audit log back from the head recorded in the manifest, returning its entries,
+    /// along with the ids of the chunks holding them, oldest first, and the head
+    ///
+    /// Stops after one more entry than the head claims the log has, so a tampered chain can not
+    /// keep the walk going.
+    async fn audit_chain(&mut self) -> Result<(Vec<(ChunkID, AuditEntry)>, Option<AuditHead>)> {
+        let head = self.backend_manifest().audit_head().await?;
+        let mut entries = Vec::new();
+        if let Some(head) = head {
+            let mut next = Some(head.entry);
+            while let Some(id) = next {
+                if entries.len() as u64 > head.length {
+                    break;
+                }
+                let bytes = self.read_chunk(id).await?;
+                let entry: AuditEntry =
+                    serde_cbor::de::from_slice(&bytes[..]).map_err(backend::BackendError::from)?;
+                next = entry.previous();
+                entries.push((id, entry));
+            }
+        }
+        entries.reverse();
+        Ok((entries, head))
+    }
+
+    /// Appends an entry recording an operation to the audit log
+    ///
+    /// The operations provided by `Repository` and `Manifest` record themselves, this is intended
+    /// for operations performed outside of them, such as replacing the repository's key.
+    ///
+    /// # Errors
+    ///
+    /// Will return `Err` if reading or writing the log fails
+    pub async fn record_audit(
+        &mut self,
+        operation: AuditOperation,
+        archive: Option<ChunkID>,
+    ) -> Result<()> {
+        let lock = self.audit_lock.clone();
+        let _guard = lock.lock().await;
+        let head = self.backend_manifest().audit_head().await?;
+        let entry = AuditEntry::new(head, operation, archive, self.hmac, &self.key);
+        let bytes = serde_cbor::ser::to_vec(&entry).map_err(backend::BackendError::from)?;
+        let (id, _) = self.write_chunk(bytes).await?;
+        self.backend_manifest()
+            .write_audit_head(AuditHead {
+                entry: id,
+                length: head.map_or(0, |x| x.length) + 1,
+            })
+            .await?;
+        Ok(())
+    }
+
+    /// Sums the stored length of every chunk in the repository, other than the reserved ones
+    async fn stored_size(&mut self) -> Result<u64> {
+        let mut index = self.backend.get_index();
+        let mut size = 0;
+        for id in self.iter_chunk_ids().await {
+            if is_reserved(id) {
+                continue;
+            }
+            let location = index
+                .lookup_chunk(id)
+                .await?
+                .ok_or(backend::BackendError::ChunkNotFound(id))?;
+            size += self.backend.read_chunk_header(location).await?.1;
+        }
+        Ok(size)
+    }
+
+    /// Collects statistics about the chunks stored in the repository
+    ///
+    /// This reads the header of every chunk in the index from the backend, in order to find its
+    /// stored size and compression algorithm, but does not read or decrypt the chunks' bodies,
+    /// where the backend supports it. The reserved manifest and quota chunks, and the entries of
+    /// the audit log, are not counted.
+    ///
+    /// The size of the chunks' contents, and the number of bytes referenced by archives, are not
+    /// known at this level, so `logical_bytes` and `referenced_bytes` are left at zero, see
+    /// `Manifest::statistics`.
+    ///
+    /// # Errors
+    ///
+    /// Will return `Err` if reading or unpacking any of the chunks fails
+    #[instrument(skip(self))]
+    pub async fn statistics(&mut self) -> Result<RepoStatistics> {
+        let mut stats = RepoStatistics::default();
+        stats.archives = self.backend_manifest().archive_iterator().await.count();
+        let audit = self.audit_chunk_ids().await?;
+        let mut index = self.backend.get_index();
+        for id in self.iter_chunk_ids().await {
+            if is_reserved(id) || audit.contains(&id) {
+                continue;
+            }
+            let location = index
+                .lookup_chunk(id)
+                .await?
+                .ok_or(backend::BackendError::ChunkNotFound(id))?;
+            let (header, length) = self.backend.read_chunk_header(location).await?;
+            stats.unique_chunks += 1;
+            stats.stored_bytes += length;
+            *stats
+                .chunks_per_compression
+                .entry(header.compression().name().to_string())
+                .or_insert(0) += 1;
+        }
+        stats.update_ratios();
+        Ok(stats)
+    }
+
+    /// Sets whether or not the id of each chunk should be recomputed from its plaintext after
+    /// reading it, and checked against the id that was requested
+    ///
+    /// The HMAC checked by `Chunk::unpack` only proves that a chunk is one this repository wrote,
+    /// not that it is the chunk that was asked for. Turning this on catches a backend returning
+    /// the wrong chunk, at the cost of hashing every chunk read twice.
+    ///
+    /// Chunks written with `write_chunk_with_id`, including the manifest, will fail this check, so
+    /// it should only be enabled while reading archive contents.
+    pub fn set_verify_on_read(&mut self, verify: bool) {
+        self.verify_on_read = verify;
+    }
+
+    /// Returns true if chunks are being verified against their ids when read
+    pub fn verify_on_read(&self) -> bool {
+        self.verify_on_read
+    }
+
+    /// Sets how writes that fail with a transient backend error, such as an I/O or connection
+    /// error, are retried
+    ///
+    /// Defaults to `RetryPolicy::default()`.
+    pub fn set_retry_policy(&mut self, policy: RetryPolicy) {
+        self.retry_policy = policy;
+    }
+
+    /// Returns the current retry policy
+    pub fn retry_policy(&self) -> RetryPolicy {
+        self.retry_policy
+    }
+
     /// Returns the current default chunk settings for this repository
     #[instrument(skip(self))]
     pub fn chunk_settings(&self) -> ChunkSettings {
@@ -288,22 +1204,155 @@ impl<T: BackendClone + 'static> Repository<T> {
         &self.key
     }
 
+    /// Returns a short fingerprint identifying the repository's key
+    ///
+    /// The fingerprint is a truncated HMAC of a fixed constant under the repository's key, so
+    /// it is safe to display, and two repositories using the same HMAC algorithm share a
+    /// fingerprint exactly when they share a key.
+    ///
+    /// The repository's own HMAC algorithm is used, as it is the only one guaranteed to be
+    /// compiled in.
+    #[instrument(skip(self))]
+    pub fn key_fingerprint(&self) -> String {
+        self.hmac
+            .mac(KEY_FINGERPRINT_CONSTANT, &self.key)
+            .iter()
+            .take(KEY_FINGERPRINT_LENGTH)
+            .map(|byte| format!("{:02x}", byte))
+            .collect()
+    }
+
     /// Provides a handle to the backend manifest
     #[instrument(skip(self))]
     pub fn backend_manifest(&self) -> T::Manifest {
         self.backend.get_manifest()
     }
 
+    /// Records that an archive has been accessed, for use by retention policies
+    ///
+    /// The archive's creation timestamp is unchanged. Not all backends can record access times,
+    /// see `Manifest::touch_archive`.
+    ///
+    /// # Errors
+    ///
+    /// Will return `Err` if writing to the backend's manifest fails
+    pub async fn touch_archive(&self, archive: &StoredArchive) -> Result<()> {
+        self.backend
+            .get_manifest()
+            .touch_archive(archive.clone())
+            .await?;
+        Ok(())
+    }
+
+    /// Returns the time an archive was last recorded as accessed, if it ever has been
+    ///
+    /// # Errors
+    ///
+    /// Will return `Err` if reading the backend's manifest fails
+    pub async fn last_access(
+        &self,
+        archive: &StoredArchive,
+    ) -> Result<Option<DateTime<FixedOffset>>> {
+        Ok(self
+            .backend
+            .get_manifest()
+            .last_access(archive.clone())
+            .await?)
+    }
+
+    /// Searches every archive in the repository for files with paths matching `pattern`
+    ///
+    /// A file matches if its path passes both `pattern` and `filter`, allowing the usual
+    /// include and exclude globs to narrow down the search. Only the metadata of each archive is
+    /// loaded, see `StoredArchive::load_metadata`.
+    ///
+    /// Returns each archive containing at least one match, along with its name and the nodes of
+    /// the matching files, sorted by path. Archives without any matches are left out.
+    ///
+    /// # Errors
+    ///
+    /// Will return `Err` if any of the archives can not be loaded
+    pub async fn find(
+        &mut self,
+        pattern: &GlobFilter,
+        filter: &GlobFilter,
+    ) -> std::result::Result<Vec<(StoredArchive, String, Vec<Node>)>, ArchiveError> {
+        let archives: Vec<StoredArchive> =
+            self.backend_manifest().archive_iterator().await.collect();
+        let mut results = Vec::new();
+        for stored_archive in archives {
+            // Sealed archives can not be searched without a recipient's secret key
+            let archive = match stored_archive.load_metadata(self).await {
+                Ok(archive) => archive,
+                Err(ArchiveError::Sealed) => continue,
+                Err(e) => return Err(e),
+            };
+            let mut nodes: Vec<Node> = archive
+                .listing
+                .iter()
+                .filter(|node| {
+                    node.is_file() && pattern.is_match(&node.path) && filter.is_match(&node.path)
+                })
+                .cloned()
+                .collect();
+            if !nodes.is_empty() {
+                nodes.sort_by(|a, b| a.path.cmp(&b.path));
+                results.push((stored_archive, archive.name, nodes));
+            }
+        }
+        Ok(results)
+    }
+
     /// Performs any work that would normally be done in a drop impl, but needs to be done
     /// asyncronsyly.
     ///
     /// Calls into the backend's implementation
     #[instrument(skip(self))]
     pub async fn close(mut self) {
+        if let Err(e) = self.persist_quota().await {
+            error!("Failed to write quota while closing: {}", e);
+        }
         self.backend.close().await;
     }
 }
 
+impl Repository<BackendObject> {
+    /// Creates a new repository from a type erased backend handle, such as the ones produced by
+    /// `Backend::get_object_handle`
+    ///
+    /// This allows the backend to be chosen at runtime, without the type of the repository
+    /// depending on it.
+    ///
+    /// # Errors
+    ///
+    /// Will return `Err(InvalidChunkSettings)` if the settings fail `ChunkSettings::validate`
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// use asuran::repository::backend::mem::Mem;
+    /// use asuran::repository::*;
+    ///
+    /// smol::run(async {
+    ///     let key = Key::random(32);
+    ///     let settings = ChunkSettings::lightweight();
+    ///     let handle = Mem::new(settings, key.clone(), 4).get_object_handle();
+    ///     let mut repo = Repository::from_handle(handle, settings, key, 2).unwrap();
+    ///     let (id, _) = repo.write_chunk(b"Hello, world!".to_vec()).await.unwrap();
+    ///     assert_eq!(repo.read_chunk(id).await.unwrap(), b"Hello, world!".to_vec());
+    ///     repo.close().await;
+    /// });
+    /// ```
+    pub fn from_handle(
+        handle: BackendObject,
+        settings: ChunkSettings,
+        key: Key,
+        pipeline_tasks: usize,
+    ) -> Result<Repository<BackendObject>> {
+        Self::with(handle, settings, key, pipeline_tasks)
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -311,6 +1360,62 @@ mod tests {
     use crate::repository::backend::mem::*;
     use rand::prelude::*;
 
+    use async_trait::async_trait;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::time::Duration;
+
+    /// Wraps a `Mem` backend, failing the first `failures` chunk writes with an I/O error
+    #[derive(Clone, Debug)]
+    struct FlakyBackend {
+        inner: BackendHandle<Mem>,
+        failures: Arc<AtomicUsize>,
+        writes: Arc<AtomicUsize>,
+    }
+
+    #[async_trait]
+    impl Backend for FlakyBackend {
+        type Manifest = <BackendHandle<Mem> as Backend>::Manifest;
+        type Index = <BackendHandle<Mem> as Backend>::Index;
+        fn get_index(&self) -> Self::Index {
+            self.inner.get_index()
+        }
+        async fn write_key(&self, key: &EncryptedKey) -> backend::Result<()> {
+            self.inner.write_key(key).await
+        }
+        async fn read_key(&self) -> backend::Result<EncryptedKey> {
+            self.inner.read_key().await
+        }
+        fn get_manifest(&self) -> Self::Manifest {
+            self.inner.get_manifest()
+        }
+        async fn read_chunk(&mut self, location: SegmentDescriptor) -> backend::Result<Chunk> {
+            self.inner.read_chunk(location).await
+        }
+        async fn write_chunk(&mut self, chunk: Chunk) -> backend::Result<SegmentDescriptor> {
+            if self.failures.load(Ordering::SeqCst) > 0 {
+                self.failures.fetch_sub(1, Ordering::SeqCst);
+                return Err(backend::BackendError::IOError(std::io::Error::new(
+                    std::io::ErrorKind::ConnectionReset,
+                    "simulated network blip",
+                )));
+            }
+            self.writes.fetch_add(1, Ordering::SeqCst);
+            self.inner.write_chunk(chunk).await
+        }
+        async fn sync(&mut self) -> backend::Result<()> {
+            self.inner.sync().await
+        }
+        async fn ping(&mut self) -> backend::Result<()> {
+            self.inner.ping().await
+        }
+        async fn close(&mut self) {
+            self.inner.close().await
+        }
+        fn get_object_handle(&self) -> BackendObject {
+            backend::backend_to_object(self.clone())
+        }
+    }
+
     fn get_repo_mem(key: Key) -> Repository<BackendHandle<Mem>> {
         let settings = ChunkSettings {
             compression: Compression::ZStd { level: 1 },
@@ -318,7 +1423,60 @@ mod tests {
             encryption: Encryption::new_aes256ctr(),
         };
         let backend = Mem::new(settings, key.clone(), 4);
-        Repository::with(backend, settings, key, 2)
+        Repository::with(backend, settings, key, 2).unwrap()
+    }
+
+    #[test]
+    fn key_fingerprint_identifies_key() {
+        let key = Key::random(32);
+        let repo_a = get_repo_mem(key.clone());
+        let repo_b = get_repo_mem(key);
+        let repo_c = get_repo_mem(Key::random(32));
+        let fingerprint = repo_a.key_fingerprint();
+        assert_eq!(fingerprint.len(), KEY_FINGERPRINT_LENGTH * 2);
+        assert_eq!(fingerprint, repo_b.key_fingerprint());
+        assert_ne!(fingerprint, repo_c.key_fingerprint());
+    }
+
+    #[test]
+    fn raw_chunk_copy() {
+        smol::run(async {
+            let key = Key::random(32);
+            let mut source = get_repo_mem(key.clone());
+            let mut dest = get_repo_mem(key);
+            let data = b"Copied without being decoded".repeat(32);
+            let (id, _) = source.write_chunk(data.clone()).await.unwrap();
+
+            let (header, body) = source.read_chunk_raw(id).await.unwrap();
+            assert_ne!(body, data);
+            let (copied_id, existed) = dest
+                .write_raw(Chunk::unsplit(header, ChunkBody(body.clone())))
+                .await
+                .unwrap();
+            assert_eq!(copied_id, id);
+            assert!(!existed);
+            assert_eq!(dest.read_chunk(id).await.unwrap(), data);
+            assert_eq!(dest.read_chunk_raw(id).await.unwrap().1, body);
+
+            let missing = source.read_chunk_raw(ChunkID::random_id()).await;
+            assert!(matches!(missing, Err(RepositoryError::ChunkNotFound)));
+        });
+    }
+
+    #[test]
+    fn with_rejects_invalid_settings() {
+        let key = Key::random(32);
+        let settings = ChunkSettings {
+            compression: Compression::LZMA { level: 42 },
+            hmac: HMAC::Blake2b,
+            encryption: Encryption::new_aes256ctr(),
+        };
+        let backend = Mem::new(ChunkSettings::lightweight(), key.clone(), 4);
+        let result = Repository::with(backend, settings, key, 2);
+        assert!(matches!(
+            result,
+            Err(RepositoryError::InvalidChunkSettings(_))
+        ));
     }
 
     #[test]
@@ -351,6 +1509,140 @@ mod tests {
         });
     }
 
+    #[test]
+    fn chunk_info_reports_settings() {
+        smol::run(async {
+            let key = Key::random(32);
+            let mut repo = get_repo_mem(key.clone());
+            let data = vec![7_u8; 4096];
+            let (id, _) = repo.write_chunk(data.clone()).await.unwrap();
+            let info = repo.chunk_info(id).await.unwrap();
+
+            let expected = Chunk::pack(
+                data,
+                Compression::ZStd { level: 1 },
+                Encryption::new_aes256ctr(),
+                HMAC::Blake2b,
+                &key,
+            );
+            assert_eq!(info.id, id);
+            assert_eq!(info.compression, Compression::ZStd { level: 1 });
+            assert!(matches!(info.encryption, Encryption::AES256CTR { .. }));
+            assert_eq!(info.hmac, HMAC::Blake2b);
+            assert_eq!(info.stored_length, expected.get_bytes().len());
+
+            let missing = ChunkID::new(&[0_u8; 32]);
+            assert!(matches!(
+                repo.chunk_info(missing).await,
+                Err(RepositoryError::ChunkNotFound)
+            ));
+        });
+    }
+
+    #[test]
+    fn size_quota() {
+        smol::run(async {
+            let key = Key::random(32);
+            let backend = Mem::new(ChunkSettings::lightweight(), key.clone(), 4);
+            let mut repo = Repository::with(
+                backend.clone(),
+                ChunkSettings::lightweight(),
+                key.clone(),
+                2,
+            )
+            .unwrap();
+            // lightweight does not compress, so each chunk takes its full length
+            repo.write_chunk(vec![1_u8; 1000]).await.unwrap();
+            repo.set_size_quota(2500).await.unwrap();
+            assert_eq!(
+                repo.size_quota().await.unwrap(),
+                Some(SizeQuota {
+                    limit: 2500,
+                    usage: 1000
+                })
+            );
+            // Writing within the quota succeeds
+            repo.write_chunk(vec![2_u8; 1000]).await.unwrap();
+            // Duplicate chunks take no space
+            repo.write_chunk(vec![2_u8; 1000]).await.unwrap();
+            // Writing past it fails
+            let error = repo.write_chunk(vec![3_u8; 1000]).await.unwrap_err();
+            assert_eq!(error.kind(), "quota_exceeded");
+            repo.commit_index().await.unwrap();
+
+            // The quota is enforced by a new session on the same backend
+            let mut repo = Repository::with(backend, ChunkSettings::lightweight(), key, 2).unwrap();
+            assert_eq!(
+                repo.size_quota().await.unwrap(),
+                Some(SizeQuota {
+                    limit: 2500,
+                    usage: 2000
+                })
+            );
+            repo.write_chunk(vec![4_u8; 500]).await.unwrap();
+            assert!(repo.write_chunk(vec![5_u8; 1]).await.is_err());
+        });
+    }
+
+    #[test]
+    fn failed_write_refunds_quota() {
+        smol::run(async {
+            let key = Key::random(32);
+            let settings = ChunkSettings::lightweight();
+            let backend = FlakyBackend {
+                inner: Mem::new(settings, key.clone(), 4),
+                failures: Arc::new(AtomicUsize::new(0)),
+                writes: Arc::new(AtomicUsize::new(0)),
+            };
+            let mut repo = Repository::with(backend.clone(), settings, key, 2).unwrap();
+            repo.set_retry_policy(RetryPolicy {
+                max_attempts: 0,
+                ..RetryPolicy::default()
+            });
+            repo.set_size_quota(1500).await.unwrap();
+            backend.failures.store(1, Ordering::SeqCst);
+            assert!(repo.write_chunk(vec![1_u8; 1000]).await.is_err());
+            assert_eq!(repo.size_quota().await.unwrap().unwrap().usage, 0);
+            // The failed write did not use up any of the quota
+            repo.write_chunk(vec![2_u8; 1000]).await.unwrap();
+            repo.commit_index().await.unwrap();
+        });
+    }
+
+    #[test]
+    fn write_retries_transient_failure() {
+        smol::run(async {
+            let key = Key::random(32);
+            let settings = ChunkSettings::lightweight();
+            let backend = FlakyBackend {
+                inner: Mem::new(settings, key.clone(), 4),
+                failures: Arc::new(AtomicUsize::new(1)),
+                writes: Arc::new(AtomicUsize::new(0)),
+            };
+            let mut repo = Repository::with(backend.clone(), settings, key.clone(), 2).unwrap();
+            repo.set_retry_policy(RetryPolicy {
+                max_attempts: 2,
+                initial_backoff: Duration::from_millis(1),
+                max_backoff: Duration::from_millis(2),
+            });
+            let data = vec![9_u8; 1024];
+            let (id, _) = repo.write_chunk(data.clone()).await.unwrap();
+            assert_eq!(backend.writes.load(Ordering::SeqCst), 1);
+            assert_eq!(repo.count_chunk().await, 1);
+            assert_eq!(repo.read_chunk(id).await.unwrap(), data);
+
+            // Without retries, the same failure is reported to the caller
+            backend.failures.store(1, Ordering::SeqCst);
+            repo.set_retry_policy(RetryPolicy {
+                max_attempts: 0,
+                ..RetryPolicy::default()
+            });
+            let error = repo.write_chunk(vec![10_u8; 1024]).await.unwrap_err();
+            assert_eq!(error.kind(), "io");
+            assert_eq!(backend.writes.load(Ordering::SeqCst), 1);
+        });
+    }
+
     #[test]
     fn double_add() {
         smol::run(async {
@@ -371,6 +1663,80 @@ mod tests {
         });
     }
 
+    // Prefetching chunks should not change what is read back out
+    #[test]
+    fn prefetch_read() {
+        smol::run(async {
+            let mut repo = get_repo_mem(Key::random(32));
+            let mut data = Vec::new();
+            let mut ids = Vec::new();
+            for _ in 0..10 {
+                let mut chunk = vec![0_u8; 8192];
+                thread_rng().fill_bytes(&mut chunk);
+                ids.push(repo.write_chunk(chunk.clone()).await.unwrap().0);
+                data.push(chunk);
+            }
+            // Include an id that is not in the repository, which should be skipped
+            let mut prefetch_ids = ids.clone();
+            prefetch_ids.push(ChunkID::random_id());
+            repo.prefetch(&prefetch_ids)
+                .await
+                .expect("Unable to prefetch chunks");
+            for (id, expected) in ids.iter().zip(data.iter()) {
+                let output = repo.read_chunk(*id).await.unwrap();
+                assert_eq!(expected, &output);
+            }
+        });
+    }
+
+    // The chunk id iterator should yield exactly the chunks that were written
+    #[test]
+    fn iter_chunk_ids() {
+        smol::run(async {
+            let mut repo = get_repo_mem(Key::random(32));
+            let mut ids = HashSet::new();
+            for _ in 0..10 {
+                let mut chunk = vec![0_u8; 8192];
+                thread_rng().fill_bytes(&mut chunk);
+                ids.insert(repo.write_chunk(chunk).await.unwrap().0);
+            }
+            let output: Vec<ChunkID> = repo.iter_chunk_ids().await.collect();
+            assert_eq!(output.len(), 10);
+            assert_eq!(ids, output.into_iter().collect::<HashSet<_>>());
+        });
+    }
+
+    #[test]
+    fn find_chunks_by_prefix() {
+        smol::run(async {
+            let mut repo = get_repo_mem(Key::random(32));
+            let mut ids = Vec::new();
+            for _ in 0..10 {
+                let mut chunk = vec![0_u8; 8192];
+                thread_rng().fill_bytes(&mut chunk);
+                ids.push(repo.write_chunk(chunk).await.unwrap().0);
+            }
+            let target = ids[3];
+            let hex = target.to_hex();
+            // Find the shortest prefix that no other chunk shares
+            let length = (1..=hex.len())
+                .find(|&length| {
+                    ids.iter()
+                        .filter(|id| id.to_hex().starts_with(&hex[..length]))
+                        .count()
+                        == 1
+                })
+                .unwrap();
+            let found = repo.find_chunks_by_prefix(&hex[..length]).await;
+            assert_eq!(found, vec![target]);
+            let found = repo
+                .find_chunks_by_prefix(&hex[..length].to_ascii_uppercase())
+                .await;
+            assert_eq!(found, vec![target]);
+            assert_eq!(repo.find_chunks_by_prefix("").await.len(), 10);
+        });
+    }
+
     // Ensure writing a chunk with an ID works
     #[test]
     fn chunk_with_id() {
@@ -393,4 +1759,222 @@ mod tests {
             assert_eq!(data, data_restore);
         });
     }
+
+    // Duplicate writes must not be counted, and the manifest chunk must be skipped
+    #[test]
+    fn statistics_counts_unique_chunks() {
+        smol::run(async {
+            let mut repo = get_repo_mem(Key::random(32));
+            let mut data1 = vec![0_u8; 7000];
+            thread_rng().fill_bytes(&mut data1);
+            let mut data2 = vec![0_u8; 3000];
+            thread_rng().fill_bytes(&mut data2);
+            repo.write_chunk(data1.clone()).await.unwrap();
+            repo.write_chunk(data2).await.unwrap();
+            repo.write_chunk(data1).await.unwrap();
+            repo.write_chunk_with_id(vec![1_u8; 100], ChunkID::manifest_id())
+                .await
+                .unwrap();
+
+            let stats = repo
+                .statistics()
+                .await
+                .expect("Collecting statistics failed");
+            assert_eq!(stats.archives, 0);
+            assert_eq!(stats.unique_chunks, 2);
+            // The size of the contents is only known to the archives
+            assert_eq!(stats.logical_bytes, 0);
+            assert!(stats.stored_bytes > 0);
+            assert_eq!(stats.chunks_per_compression.get("ZStd"), Some(&2));
+        });
+    }
+
+    // Only the archives holding a matching file should be returned, with only the matching paths
+    #[test]
+    fn find_returns_matching_archives() {
+        use crate::manifest::archive::{Listing, Node, NodeType};
+        use crate::manifest::{ActiveArchive, Manifest};
+        smol::run(async {
+            let mut repo = get_repo_mem(Key::random(32));
+            let mut manifest = Manifest::load(&repo);
+            let contents: [&[&str]; 3] = [
+                &["notes.txt", "image.png"],
+                &["image.png"],
+                &["notes.txt", "secret.txt"],
+            ];
+            for (name, paths) in ["first", "second", "third"].iter().zip(contents.iter()) {
+                let archive = ActiveArchive::new(name);
+                let mut listing = Listing::default();
+                for path in paths.iter() {
+                    listing.add_child(
+                        "",
+                        Node {
+                            path: (*path).to_string(),
+                            total_length: 0,
+                            total_size: 0,
+                            extents: None,
+                            content_type: None,
+                            node_type: NodeType::File,
+                        },
+                    );
+                }
+                archive.set_listing(listing).await;
+                manifest.commit_archive(&mut repo, archive).await.unwrap();
+            }
+
+            let pattern = GlobFilter::new(&["*.txt"], &[] as &[&str]).unwrap();
+            let filter = GlobFilter::new(&[] as &[&str], &["secret.txt"]).unwrap();
+            let results = repo.find(&pattern, &filter).await.unwrap();
+            assert_eq!(results.len(), 2);
+            let mut names = Vec::new();
+            for (_, name, nodes) in results {
+                let paths: Vec<&str> = nodes.iter().map(|node| node.path.as_str()).collect();
+                assert_eq!(paths, vec!["notes.txt"]);
+                names.push(name);
+            }
+            names.sort();
+            assert_eq!(names, vec!["first".to_string(), "third".to_string()]);
+        });
+    }
+
+    // Objects must still be readable after their chunks are rewritten with a new compression
+    #[test]
+    fn recompress_keeps_archives_readable() {
+        use crate::chunker::FastCDC;
+        use crate::manifest::{ActiveArchive, Manifest};
+        use std::io::Cursor;
+        smol::run(async {
+            let key = Key::random(32);
+            let settings = ChunkSettings {
+                compression: Compression::NoCompression,
+                hmac: HMAC::Blake2b,
+                encryption: Encryption::new_aes256ctr(),
+            };
+            let backend = Mem::new(settings, key.clone(), 4);
+            let mut repo = Repository::with(backend, settings, key, 2).unwrap();
+            let mut manifest = Manifest::load(&repo);
+            let data = vec![7_u8; 100_000];
+            let mut archive = ActiveArchive::new("test");
+            archive
+                .put_object(
+                    &FastCDC::default(),
+                    &mut repo,
+                    "object",
+                    Cursor::new(data.clone()),
+                )
+                .await
+                .unwrap();
+            manifest.commit_archive(&mut repo, archive).await.unwrap();
+            let before = repo.statistics().await.unwrap();
+
+            let new_settings = ChunkSettings {
+                compression: Compression::ZStd { level: 3 },
+                ..settings
+            };
+            let rewritten = repo.recompress(new_settings).await.unwrap();
+            assert!(rewritten > 0);
+            let after = repo.statistics().await.unwrap();
+            assert_eq!(after.unique_chunks, before.unique_chunks);
+            assert!(after.stored_bytes < before.stored_bytes);
+            assert_eq!(after.chunks_per_compression.get("None"), None);
+            assert_eq!(
+                manifest.chunk_settings().await.compression,
+                new_settings.compression
+            );
+
+            let stored = manifest.archives().await.pop().unwrap();
+            let archive = stored.load(&mut repo).await.unwrap();
+            let mut output = Cursor::new(Vec::new());
+            archive
+                .get_object(&mut repo, "object", &mut output)
+                .await
+                .unwrap();
+            assert_eq!(output.into_inner(), data);
+        });
+    }
+
+    #[test]
+    fn recompress_rejects_hmac_change() {
+        smol::run(async {
+            let mut repo = get_repo_mem(Key::random(32));
+            repo.write_chunk(vec![1_u8; 100]).await.unwrap();
+            let settings = ChunkSettings {
+                compression: Compression::NoCompression,
+                hmac: HMAC::SHA256,
+                encryption: Encryption::new_aes256ctr(),
+            };
+            assert!(matches!(
+                repo.recompress(settings).await,
+                Err(RepositoryError::IncompatibleChunkSettings(_))
+            ));
+        });
+    }
+
+    // The first call must create the repository, and the second open it, keeping its data
+    #[test]
+    fn open_or_create_creates_then_opens() {
+        use crate::repository::backend::multifile::MultiFileSettings;
+        smol::run(async {
+            let root = tempfile::tempdir().unwrap();
+            let location = MultiFileSettings::new(root.path().join("repo"), 4);
+            let settings = ChunkSettings::lightweight();
+
+            let mut repo = Repository::open_or_create(&location, b"password", settings, 2)
+                .await
+                .unwrap();
+            let (id, _) = repo.write_chunk(vec![7_u8; 1000]).await.unwrap();
+            repo.close().await;
+            assert!(location.read_key().await.unwrap().is_some());
+
+            // Opening with different settings must leave the repository's defaults alone
+            let other = ChunkSettings {
+                compression: Compression::ZStd { level: 1 },
+                ..settings
+            };
+            let mut repo = Repository::open_or_create(&location, b"password", other, 2)
+                .await
+                .unwrap();
+            assert_eq!(repo.read_chunk(id).await.unwrap(), vec![7_u8; 1000]);
+            assert_eq!(repo.backend_manifest().chunk_settings().await, settings);
+            repo.close().await;
+
+            let result = Repository::open_or_create(&location, b"wrong", settings, 2).await;
+            assert!(matches!(result, Err(RepositoryError::Key(_))));
+        });
+    }
+
+    // After rewrapping, the same passphrase must still open the repository, with the key now
+    // stored under the new algorithm, and the existing data must still read
+    #[test]
+    fn rewrap_key_keeps_passphrase_and_data() {
+        use crate::repository::backend::multifile::MultiFileSettings;
+        smol::run(async {
+            let root = tempfile::tempdir().unwrap();
+            let location = MultiFileSettings::new(root.path().join("repo"), 4);
+            let settings = ChunkSettings::lightweight();
+
+            let mut repo = Repository::open_or_create(&location, b"password", settings, 2)
+                .await
+                .unwrap();
+            let (id, _) = repo.write_chunk(vec![7_u8; 1000]).await.unwrap();
+            let result = repo.rewrap_key(Encryption::new_chacha20(), b"wrong").await;
+            assert!(matches!(result, Err(RepositoryError::Key(_))));
+            repo.rewrap_key(Encryption::new_chacha20(), b"password")
+                .await
+                .unwrap();
+            repo.close().await;
+
+            let encrypted_key = location.read_key().await.unwrap().unwrap();
+            assert!(matches!(
+                encrypted_key.encryption(),
+                Encryption::ChaCha20 { .. }
+            ));
+            let mut repo = Repository::open_or_create(&location, b"password", settings, 2)
+                .await
+                .unwrap();
+            assert_eq!(repo.read_chunk(id).await.unwrap(), vec![7_u8; 1000]);
+            assert!(repo.verify_audit_log().await.unwrap());
+            repo.close().await;
+        });
+    }
 }
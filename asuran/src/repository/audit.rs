@@ -0,0 +1,233 @@
+//! Tamper evident log of the operations performed on a repository
+//!
+//! Each entry is stored in a chunk of its own, and carries an HMAC tag over its contents, which
+//! include the id of the chunk holding the entry before it, in the same manner as the transactions
+//! of the manifest. Modifying, reordering, or removing entries from the middle of the log breaks
+//! the chain.
+//!
+//! The latest entry, and the number of entries in the log, are recorded in the manifest as the
+//! log's `AuditHead`, so removing entries from the end of the log is detectable as well. See
+//! `Repository::audit_log`.
+use crate::repository::{ChunkID, Key, HMAC};
+
+use chrono::prelude::*;
+use serde::{Deserialize, Serialize};
+use serde_cbor as cbor;
+
+use std::cmp::min;
+
+/// The kinds of operation recorded in the audit log
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum AuditOperation {
+    /// An archive was added to the manifest
+    Store,
+    /// An archive was removed from the manifest
+    Delete,
+    /// Unreachable chunks were removed by garbage collection
+    Prune,
+    /// The repository key was re-encrypted, or replaced
+    KeyRotation,
+    /// Every chunk was rewritten with new compression or encryption settings
+    Recompress,
+}
+
+/// The latest entry of the audit log, and the number of entries in the log
+///
+/// This is recorded in the manifest every time an entry is added.
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq, Eq)]
+pub struct AuditHead {
+    /// The id of the chunk holding the latest entry
+    pub entry: ChunkID,
+    /// The number of entries in the log
+    pub length: u64,
+}
+
+/// A single entry in the audit log
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq)]
+pub struct AuditEntry {
+    /// The operation this entry records
+    operation: AuditOperation,
+    /// The time the operation was performed at
+    timestamp: DateTime<FixedOffset>,
+    /// The archive the operation acted on, if it acted on a single archive
+    archive: Option<ChunkID>,
+    /// The type of HMAC used for this entry
+    hmac: HMAC,
+    /// The position of this entry in the log, starting from 0
+    sequence: u64,
+    /// The id of the chunk holding the entry before this one, or `None` for the first entry
+    previous: Option<ChunkID>,
+    /// The HMAC tag of this entry
+    ///
+    /// This is calculated over the CBOR encoding of this struct with this value set to all zeros
+    tag: [u8; 32],
+}
+
+impl AuditEntry {
+    /// Constructs a new entry recording an operation performed now, following the entry the
+    /// provided head points to, if there is one
+    pub fn new(
+        head: Option<AuditHead>,
+        operation: AuditOperation,
+        archive: Option<ChunkID>,
+        hmac: HMAC,
+        key: &Key,
+    ) -> AuditEntry {
+        let mut entry = AuditEntry {
+            operation,
+            timestamp: Local::now().with_timezone(Local::now().offset()),
+            archive,
+            hmac,
+            sequence: head.map_or(0, |x| x.length),
+            previous: head.map(|x| x.entry),
+            tag: [0_u8; 32],
+        };
+        entry.update_tag(key);
+        entry
+    }
+
+    /// Serializes the struct, performs the HMAC, and updates the value in place
+    ///
+    /// Will zero the tag before performing the operation. Tags shorter than 32 bytes, as custom
+    /// algorithms may produce, are zero padded.
+    fn update_tag(&mut self, key: &Key) {
+        self.tag = [0_u8; 32];
+        let bytes = cbor::ser::to_vec(self).expect("Serialization in hmac failed");
+        let tag = self.hmac.mac(&bytes[..], key);
+        let len = min(32, tag.len());
+        self.tag[..len].copy_from_slice(&tag[..len]);
+    }
+
+    /// Returns the operation this entry records
+    pub fn operation(&self) -> AuditOperation {
+        self.operation
+    }
+
+    /// Returns the time the operation was performed at
+    pub fn timestamp(&self) -> DateTime<FixedOffset> {
+        self.timestamp
+    }
+
+    /// Returns the archive the operation acted on, if it acted on a single archive
+    pub fn archive(&self) -> Option<ChunkID> {
+        self.archive
+    }
+
+    /// Returns the id of the chunk holding the entry before this one
+    pub fn previous(&self) -> Option<ChunkID> {
+        self.previous
+    }
+
+    /// Verifies the hmac of the entry
+    ///
+    /// This does not check the entry's place in the chain, see `verify_chain`.
+    pub fn verify(&self, key: &Key) -> bool {
+        let mut copy = self.clone();
+        copy.update_tag(key);
+        self.tag == copy.tag
+    }
+}
+
+/// Verifies a log, given as pairs of chunk ids and entries, oldest first, against the head
+/// recorded in the manifest
+///
+/// Checks the tag of every entry, that each entry points to the chunk of the one before it, and
+/// that the log ends at the recorded head with the recorded number of entries.
+pub fn verify_chain(entries: &[(ChunkID, AuditEntry)], head: Option<AuditHead>, key: &Key) -> bool {
+    let mut previous = None;
+    for (sequence, (id, entry)) in entries.iter().enumerate() {
+        if entry.previous != previous || entry.sequence != sequence as u64 || !entry.verify(key) {
+            return false;
+        }
+        previous = Some(*id);
+    }
+    match head {
+        Some(head) => previous == Some(head.entry) && entries.len() as u64 == head.length,
+        None => entries.is_empty(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn log(key: &Key) -> (Vec<(ChunkID, AuditEntry)>, AuditHead) {
+        let mut entries: Vec<(ChunkID, AuditEntry)> = Vec::new();
+        let mut head = None;
+        for operation in &[
+            AuditOperation::Store,
+            AuditOperation::Store,
+            AuditOperation::Prune,
+        ] {
+            let entry = AuditEntry::new(
+                head,
+                *operation,
+                Some(ChunkID::random_id()),
+                HMAC::Blake3,
+                key,
+            );
+            let id = ChunkID::random_id();
+            head = Some(AuditHead {
+                entry: id,
+                length: entry.sequence + 1,
+            });
+            entries.push((id, entry));
+        }
+        (entries, head.unwrap())
+    }
+
+    #[test]
+    fn chain_detects_tampering() {
+        let key = Key::random(32);
+        let (entries, head) = log(&key);
+        assert!(verify_chain(&entries, Some(head), &key));
+        // A different key can not produce the same chain
+        assert!(!verify_chain(&entries, Some(head), &Key::random(32)));
+        // Modifying an entry breaks its tag
+        let mut modified = entries.clone();
+        modified[1].1.operation = AuditOperation::Delete;
+        assert!(!verify_chain(&modified, Some(head), &key));
+        // Removing an entry breaks the link of the one after it
+        let mut removed = entries.clone();
+        removed.remove(1);
+        assert!(!verify_chain(&removed, Some(head), &key));
+        // As does reordering them
+        let mut reordered = entries.clone();
+        reordered.swap(0, 1);
+        assert!(!verify_chain(&reordered, Some(head), &key));
+        // Removing entries from the end no longer matches the recorded head
+        let mut truncated = entries.clone();
+        truncated.pop();
+        assert!(!verify_chain(&truncated, Some(head), &key));
+        let rolled_back = AuditHead {
+            entry: entries[1].0,
+            length: 3,
+        };
+        assert!(!verify_chain(&truncated, Some(rolled_back), &key));
+        // And a log can not exist without a head
+        assert!(!verify_chain(&entries, None, &key));
+        assert!(verify_chain(&[], None, &key));
+    }
+
+    #[test]
+    fn short_custom_tags() {
+        struct ShortHmac;
+        impl crate::repository::Hmac for ShortHmac {
+            fn id(&self) -> u8 {
+                160
+            }
+            fn mac(&self, key: &[u8], data: &[u8]) -> Vec<u8> {
+                let mut output = vec![0_u8; 16];
+                for (i, byte) in key.iter().chain(data.iter()).enumerate() {
+                    output[i % 16] ^= byte;
+                }
+                output
+            }
+        }
+        crate::repository::register_hmac(std::sync::Arc::new(ShortHmac)).unwrap();
+        let key = Key::random(32);
+        let entry = AuditEntry::new(None, AuditOperation::Store, None, HMAC::Custom(160), &key);
+        assert!(entry.verify(&key));
+        assert_eq!(entry.tag[16..], [0_u8; 16]);
+    }
+}
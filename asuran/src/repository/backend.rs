@@ -3,21 +3,34 @@
 #![allow(clippy::used_underscore_binding)] // TODO: Fix this after clippy and thiserror start
                                            // playing nice
 use crate::manifest::StoredArchive;
-use crate::repository::{Chunk, ChunkID, ChunkSettings, EncryptedKey};
+use crate::repository::audit::AuditHead;
+use crate::repository::backend::common::DanglingParent;
+use crate::repository::{Chunk, ChunkID, ChunkSettings, EncryptedKey, Key};
+
+use asuran_core::repository::chunk::ChunkHeader;
 
 use async_trait::async_trait;
 use chrono::prelude::*;
+use futures::stream::{self, LocalBoxStream, StreamExt};
+use semver::Version;
 use serde::{Deserialize, Serialize};
 use thiserror::Error;
 
 use std::collections::HashSet;
+use std::io;
+use std::time::Duration;
 
 pub mod common;
 pub mod flatfile;
+#[cfg(feature = "http")]
+pub mod http;
 pub mod mem;
+#[cfg(not(target_arch = "wasm32"))]
 pub mod multifile;
 #[cfg(feature = "sftp")]
 pub mod sftp;
+#[cfg(feature = "webdav")]
+pub mod webdav;
 
 #[cfg_attr(tarpaulin, skip)]
 pub mod object_wrappers;
@@ -32,6 +45,10 @@ pub enum BackendError {
     DataNotFound,
     #[error("Segment Error: {0}")]
     SegmentError(String),
+    #[error("Chunk not found: {0:?}")]
+    ChunkNotFound(ChunkID),
+    #[error("Segment not found: {0}")]
+    SegmentNotFound(u64),
     #[error("Manifest Error: {0}")]
     ManifestError(String),
     #[error("Index Error: {0}")]
@@ -50,13 +67,188 @@ pub enum BackendError {
     ChannelDroppedSend(#[from] futures::channel::mpsc::SendError),
     #[error("Error connecting to backend: {0}")]
     ConnectionError(String),
+    #[error("Giving up after {attempts} attempts, last error was: {error}")]
+    RetriesExhausted { attempts: u32, error: String },
+    #[error("Backend is read-only")]
+    ReadOnly,
     #[error("FlatFile Format Error: {0}")]
     FlatFile(#[from] asuran_core::repository::backend::flatfile::FlatFileError),
+    #[error("Repository format version {0} is newer than this version of asuran supports")]
+    UnsupportedFormatVersion(u16),
+    #[error(
+        "Repository was written by asuran {written}, which is newer than, and incompatible with, \
+         this version ({current})"
+    )]
+    IncompatibleVersion { written: Version, current: Version },
+    #[error(
+        "Write would exceed the repository's size quota of {limit} bytes, {usage} bytes are in use"
+    )]
+    QuotaExceeded { limit: u64, usage: u64 },
     #[error("Unknown Error: {0}")]
     Unknown(String),
 }
 pub type Result<T> = std::result::Result<T, BackendError>;
 
+impl BackendError {
+    /// Returns a short, stable, `snake_case` name for the kind of this error
+    ///
+    /// Unlike the `Display` output, these names will not change between releases, and are
+    /// suitable for matching on in scripts.
+    pub fn kind(&self) -> &'static str {
+        match self {
+            BackendError::IOError(_) => "io",
+            BackendError::DataNotFound => "data_not_found",
+            BackendError::SegmentError(_) => "segment_error",
+            BackendError::ChunkNotFound(_) => "chunk_not_found",
+            BackendError::SegmentNotFound(_) => "segment_not_found",
+            BackendError::ManifestError(_) => "manifest_error",
+            BackendError::IndexError(_) => "index_error",
+            BackendError::MsgPackEncodeError(_) => "encoding_error",
+            BackendError::FileLockError => "file_lock_failed",
+            BackendError::CancelledOneshotError(_) => "cancelled",
+            BackendError::ChunkUnpackError(_) => "chunk_unpack_failed",
+            BackendError::RepositoryGloballyLocked(_) => "repository_locked",
+            BackendError::ChannelDroppedSend(_) => "channel_closed",
+            BackendError::ConnectionError(_) => "connection_failed",
+            BackendError::RetriesExhausted { .. } => "retries_exhausted",
+            BackendError::ReadOnly => "read_only",
+            BackendError::FlatFile(_) => "flatfile_format",
+            BackendError::UnsupportedFormatVersion(_) => "unsupported_format_version",
+            BackendError::IncompatibleVersion { .. } => "incompatible_version",
+            BackendError::QuotaExceeded { .. } => "quota_exceeded",
+            BackendError::Unknown(_) => "unknown",
+        }
+    }
+
+    /// Returns true if the error may go away if the operation is tried again
+    ///
+    /// Only connection failures, and I/O failures of a kind that indicates a dropped connection,
+    /// timeout, or interruption, are considered transient. Other I/O errors, such as missing files
+    /// or denied permissions, as well as errors in decoding, or in the state of the manifest or
+    /// index, will fail the same way every time. Errors from operations
+    /// the backend has already retried on its own are reported as `RetriesExhausted`, and are not
+    /// retryable, so that callers do not stack another round of retries on top of the backend's.
+    pub fn is_retryable(&self) -> bool {
+        match self {
+            BackendError::IOError(e) => matches!(
+                e.kind(),
+                io::ErrorKind::TimedOut
+                    | io::ErrorKind::Interrupted
+                    | io::ErrorKind::WouldBlock
+                    | io::ErrorKind::ConnectionReset
+                    | io::ErrorKind::ConnectionAborted
+                    | io::ErrorKind::ConnectionRefused
+                    | io::ErrorKind::NotConnected
+                    | io::ErrorKind::BrokenPipe
+                    | io::ErrorKind::UnexpectedEof
+            ),
+            BackendError::ConnectionError(_) => true,
+            _ => false,
+        }
+    }
+
+    /// Returns true if this error indicates that the requested data is genuinely absent from the
+    /// backend
+    pub fn is_not_found(&self) -> bool {
+        matches!(
+            self,
+            BackendError::DataNotFound
+                | BackendError::ChunkNotFound(_)
+                | BackendError::SegmentNotFound(_)
+        )
+    }
+}
+
+// Shared by the WebDAV and HTTP backends
+#[cfg(any(feature = "webdav", feature = "http"))]
+impl From<reqwest::Error> for BackendError {
+    fn from(error: reqwest::Error) -> Self {
+        BackendError::ConnectionError(format!("HTTP Error: {}", error))
+    }
+}
+
+/// Describes how hard to try an operation again after a transient failure, such as a dropped
+/// connection, before giving up
+///
+/// Used both by backends that reconnect on their own, such as SFTP, and by `Repository` when
+/// retrying chunk writes. Only one of the two retries any given failure: once a backend gives up,
+/// it reports `BackendError::RetriesExhausted`, which `Repository` does not retry.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct RetryPolicy {
+    /// Maximum number of times to retry a single operation
+    ///
+    /// A value of zero disables retrying entirely.
+    pub max_attempts: u32,
+    /// Time to wait before the first retry
+    ///
+    /// This is doubled after every failed attempt.
+    pub initial_backoff: Duration,
+    /// Upper bound on the time to wait between retries
+    pub max_backoff: Duration,
+}
+
+impl RetryPolicy {
+    /// Returns the time to wait before the given (zero indexed) retry
+    pub fn backoff(&self, attempt: u32) -> Duration {
+        let factor = 1_u32.checked_shl(attempt).unwrap_or(u32::MAX);
+        self.initial_backoff
+            .checked_mul(factor)
+            .map_or(self.max_backoff, |x| x.min(self.max_backoff))
+    }
+}
+
+impl Default for RetryPolicy {
+    fn default() -> Self {
+        RetryPolicy {
+            max_attempts: 3,
+            initial_backoff: Duration::from_millis(500),
+            max_backoff: Duration::from_secs(30),
+        }
+    }
+}
+
+/// Describes how hard backends that write to local files try to make sure their writes survive a
+/// crash or power loss
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Durability {
+    /// Files are fsynced every time the backend flushes them, such as when the index is committed,
+    /// a segment is closed out, or the repository is synced
+    Full,
+    /// Files are never fsynced, and it is left to the operating system to decide when to write
+    /// them out
+    ///
+    /// This can make writing to the repository dramatically faster, but a crash or power loss can
+    /// lose writes that had already been reported as committed, and may leave the repository
+    /// corrupted. This should only be used for throwaway repositories, such as in testing.
+    None,
+}
+
+impl Durability {
+    /// Fsyncs the given file, if this level of durability calls for it
+    ///
+    /// # Errors
+    ///
+    /// Will propagate any I/O errors reported by the sync
+    pub fn sync(self, file: &mut impl common::SyncData) -> std::io::Result<()> {
+        match self {
+            Durability::Full => file.sync_data(),
+            Durability::None => Ok(()),
+        }
+    }
+}
+
+impl Default for Durability {
+    /// Defaults to `Durability::Full`
+    ///
+    /// Note that this is stricter than the behavior of earlier versions, which only fsynced the
+    /// index on an explicit sync, and never fsynced flatfiles or closed out segments. Writing to a
+    /// repository with the default durability does more fsyncs than it used to, and may be
+    /// noticeably slower on some filesystems. Use `Durability::None` to opt out.
+    fn default() -> Self {
+        Durability::Full
+    }
+}
+
 /// Describes the segment id and location there in of a chunk
 ///
 /// This does not store the length, as segments are responsible for storing chunks
@@ -82,6 +274,17 @@ pub trait Manifest: Send + Sync + std::fmt::Debug + 'static {
     /// Returns an iterator over the list of archives in this repository, in reverse chronological
     /// order (newest first).
     async fn archive_iterator(&mut self) -> Self::Iterator;
+    /// Returns a stream over the list of archives in this repository, in the same order as
+    /// `archive_iterator`
+    ///
+    /// Backends able to produce archives without collecting all of them up front should override
+    /// this, see `common::paged_archive_stream`. The default implementation does nothing until it
+    /// is first polled, and then yields the archives from `archive_iterator` one at a time.
+    fn archive_stream(&mut self) -> LocalBoxStream<'_, Result<StoredArchive>> {
+        stream::once(self.archive_iterator())
+            .flat_map(|archives| stream::iter(archives.map(Ok)))
+            .boxed_local()
+    }
 
     /// Sets the chunk settings in the repository
     async fn write_chunk_settings(&mut self, settings: ChunkSettings) -> Result<()>;
@@ -89,6 +292,49 @@ pub trait Manifest: Send + Sync + std::fmt::Debug + 'static {
     async fn write_archive(&mut self, archive: StoredArchive) -> Result<()>;
     /// Updates the timestamp without performing any other operations
     async fn touch(&mut self) -> Result<()>;
+    /// Records that an archive has been accessed, without changing its creation timestamp
+    ///
+    /// Backends that can not record access times treat this the same as `touch`
+    async fn touch_archive(&mut self, _archive: StoredArchive) -> Result<()> {
+        self.touch().await
+    }
+    /// Returns the time an archive was last recorded as accessed, if it ever has been
+    async fn last_access(
+        &mut self,
+        _archive: StoredArchive,
+    ) -> Result<Option<DateTime<FixedOffset>>> {
+        Ok(None)
+    }
+    /// Removes an archive from the manifest
+    ///
+    /// The chunks of the archive are left in place, until garbage collection finds them
+    /// unreachable. The default implementation returns an error, as not every backend supports
+    /// removing archives.
+    async fn delete_archive(&mut self, _archive: StoredArchive) -> Result<()> {
+        Err(BackendError::Unknown(
+            "This backend does not support removing archives".to_string(),
+        ))
+    }
+    /// Records the head of the repository's audit log, see `Repository::audit_log`
+    ///
+    /// Backends that can not record the head treat this as a no-op, in which case entries removed
+    /// from the end of the log can not be detected.
+    async fn write_audit_head(&mut self, _head: AuditHead) -> Result<()> {
+        Ok(())
+    }
+    /// Returns the latest head of the audit log recorded with `write_audit_head`, if any
+    async fn audit_head(&mut self) -> Result<Option<AuditHead>> {
+        Ok(None)
+    }
+    /// Returns every reference to a previous head that is missing from the manifest
+    ///
+    /// These are left behind by partially written manifests. The transactions holding them are
+    /// treated as roots, so the manifest remains usable, but the archives written before the
+    /// missing transactions may have been lost. Backends that do not store transactions in a DAG
+    /// always return an empty list.
+    async fn dangling_parents(&mut self) -> Result<Vec<DanglingParent>> {
+        Ok(Vec::new())
+    }
 }
 
 /// Index Trait
@@ -97,7 +343,14 @@ pub trait Manifest: Send + Sync + std::fmt::Debug + 'static {
 #[async_trait]
 pub trait Index: Send + Sync + std::fmt::Debug + 'static {
     /// Provides the location of a chunk in the repository
-    async fn lookup_chunk(&mut self, id: ChunkID) -> Option<SegmentDescriptor>;
+    ///
+    /// Returns `Ok(None)` if the chunk is not in the index.
+    ///
+    /// # Errors
+    ///
+    /// Will return `Err` if the index could not be read, such as when a lazily loaded index fails
+    /// to load
+    async fn lookup_chunk(&mut self, id: ChunkID) -> Result<Option<SegmentDescriptor>>;
     /// Sets the location of a chunk in the repository
     async fn set_chunk(&mut self, id: ChunkID, location: SegmentDescriptor) -> Result<()>;
     /// Returns the set of all `ChunkID`s known to exist in the Asuran repository.
@@ -136,6 +389,18 @@ pub trait Backend: 'static + Send + Sync + std::fmt::Debug + 'static {
     ///
     /// The chunk will be written to the oneshot once reading is complete
     async fn read_chunk(&mut self, location: SegmentDescriptor) -> Result<Chunk>;
+    /// Reads the header of the chunk at the given location, along with the length of its body as
+    /// stored
+    ///
+    /// Backends that keep chunk headers apart from, or ahead of, their bodies should override
+    /// this to avoid reading the body. The default implementation reads the whole chunk.
+    async fn read_chunk_header(
+        &mut self,
+        location: SegmentDescriptor,
+    ) -> Result<(ChunkHeader, u64)> {
+        let (header, body) = self.read_chunk(location).await?.split();
+        Ok((header, body.0.len() as u64))
+    }
     /// Starts writing a chunk to the backend
     ///
     /// A segment descriptor describing it will be written to oneshot once reading is complete
@@ -143,6 +408,68 @@ pub trait Backend: 'static + Send + Sync + std::fmt::Debug + 'static {
     /// This must be passed owned data because it will be sent into a task, so the caller has no
     /// control over drop time
     async fn write_chunk(&mut self, chunk: Chunk) -> Result<SegmentDescriptor>;
+    /// Hints to the backend that the chunks at the given locations are about to be read, in order
+    ///
+    /// Backends may use this to start loading the relevant data into their caches ahead of the
+    /// reads actually happening. This is purely advisory, and the default implementation does
+    /// nothing.
+    async fn prefetch(&mut self, _locations: Vec<SegmentDescriptor>) -> Result<()> {
+        Ok(())
+    }
+    /// Removes a chunk from the backend, such that it is no longer present in the index
+    ///
+    /// Backends are free to defer actually reclaiming the space used by the chunk. The default
+    /// implementation returns an error, as not every backend supports removing data.
+    async fn remove_chunk(&mut self, _id: ChunkID) -> Result<()> {
+        Err(BackendError::Unknown(
+            "This backend does not support removing chunks".to_string(),
+        ))
+    }
+    /// Takes exclusive access to the repository, for operations such as garbage collection that
+    /// must not run while anyone else is reading from or writing to it
+    ///
+    /// Once this has returned successfully, other attempts to open the repository must fail until
+    /// `unlock_exclusive` is called, and this handle's views of the index and manifest must
+    /// include everything committed by other connections. The default implementation does nothing,
+    /// and is only suitable for backends that can not be shared between connections.
+    ///
+    /// # Errors
+    ///
+    /// Will return `Err(BackendError::RepositoryGloballyLocked)` if another connection currently
+    /// has the repository open
+    async fn lock_exclusive(&mut self) -> Result<()> {
+        Ok(())
+    }
+    /// Releases the exclusive access taken by `lock_exclusive`
+    async fn unlock_exclusive(&mut self) -> Result<()> {
+        Ok(())
+    }
+    /// Reclaims the space used by chunks that have been removed with `remove_chunk`
+    ///
+    /// The chunks still in the index are moved out of any storage they share with removed ones,
+    /// which is then freed. This must only be called while holding `lock_exclusive`. Returns the
+    /// number of units of storage, such as segment files, that were freed. The default
+    /// implementation does nothing, and is suitable for backends that free space as soon as a
+    /// chunk is removed.
+    async fn compact(&mut self) -> Result<u64> {
+        Ok(0)
+    }
+    /// Forces all pending writes to durable storage, without closing the backend
+    ///
+    /// Once this returns, every chunk written through this handle, and the index entries pointing
+    /// to them, must survive the process being killed. Implementations must write out any chunks
+    /// they are buffering, commit the index, and, where the underlying storage allows it, ask it
+    /// to flush its own caches, such as with `fsync`.
+    async fn sync(&mut self) -> Result<()>;
+    /// Performs a lightweight round trip to the storage behind this backend, to verify that it is
+    /// still reachable
+    ///
+    /// This does not read or write any repository data, and is intended to allow connection
+    /// problems to be reported before any real work is started. Backends with nothing to reach,
+    /// such as those held in memory, can rely on the default, which always succeeds.
+    async fn ping(&mut self) -> Result<()> {
+        Ok(())
+    }
     /// Consumes the current backend handle, and does any work necessary to
     /// close out the backend properly
     ///
@@ -162,8 +489,149 @@ pub trait BackendClone: Backend + Clone {}
 
 impl<T: ?Sized> BackendClone for T where T: Backend + Clone {}
 
+/// Describes where a backend lives, allowing it to be either created or opened
+///
+/// Most backends need the repository's key before they can be opened, so this is split into
+/// reading the encrypted key, and then opening or creating the backend once the key is known.
+/// See `Repository::open_or_create`.
+#[async_trait]
+pub trait BackendSettings: Send + Sync {
+    type Backend: BackendClone;
+    /// Reads the encrypted key of an existing repository
+    ///
+    /// Returns `Ok(None)` if there is no repository at this location yet.
+    async fn read_key(&self) -> Result<Option<EncryptedKey>>;
+    /// Opens the existing repository at this location, leaving its default chunk settings as they
+    /// are
+    async fn open(&self, key: &Key) -> Result<Self::Backend>;
+    /// Creates a new repository at this location, using the provided settings as its defaults,
+    /// and writes the encrypted key to it
+    async fn create(
+        &self,
+        key: &Key,
+        encrypted_key: &EncryptedKey,
+        settings: ChunkSettings,
+    ) -> Result<Self::Backend>;
+}
+
 #[derive(Copy, PartialEq, Eq, Clone, Serialize, Deserialize, Debug)]
 pub enum TransactionType {
     Insert,
     Delete,
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn error_classification() {
+        let errors = vec![
+            (
+                BackendError::IOError(io::Error::new(io::ErrorKind::TimedOut, "io")),
+                true,
+                false,
+            ),
+            (
+                BackendError::IOError(io::Error::new(io::ErrorKind::ConnectionReset, "io")),
+                true,
+                false,
+            ),
+            (
+                BackendError::IOError(io::Error::new(io::ErrorKind::NotFound, "io")),
+                false,
+                false,
+            ),
+            (
+                BackendError::IOError(io::Error::new(io::ErrorKind::PermissionDenied, "io")),
+                false,
+                false,
+            ),
+            (BackendError::DataNotFound, false, true),
+            (
+                BackendError::SegmentError("segment".to_string()),
+                false,
+                false,
+            ),
+            (
+                BackendError::ChunkNotFound(ChunkID::manifest_id()),
+                false,
+                true,
+            ),
+            (BackendError::SegmentNotFound(0), false, true),
+            (
+                BackendError::ManifestError("manifest".to_string()),
+                false,
+                false,
+            ),
+            (BackendError::IndexError("index".to_string()), false, false),
+            (
+                BackendError::MsgPackEncodeError(serde_cbor::from_slice::<u64>(&[]).unwrap_err()),
+                false,
+                false,
+            ),
+            (BackendError::FileLockError, false, false),
+            (
+                BackendError::CancelledOneshotError(futures::channel::oneshot::Canceled),
+                false,
+                false,
+            ),
+            (
+                BackendError::ChunkUnpackError(
+                    asuran_core::repository::chunk::ChunkError::HMACValidationFailed,
+                ),
+                false,
+                false,
+            ),
+            (
+                BackendError::RepositoryGloballyLocked("lock".to_string()),
+                false,
+                false,
+            ),
+            (
+                BackendError::ConnectionError("connection".to_string()),
+                true,
+                false,
+            ),
+            (
+                BackendError::RetriesExhausted {
+                    attempts: 3,
+                    error: "connection".to_string(),
+                },
+                false,
+                false,
+            ),
+            (BackendError::ReadOnly, false, false),
+            (
+                BackendError::FlatFile(
+                    asuran_core::repository::backend::flatfile::FlatFileError::KeyTooLong,
+                ),
+                false,
+                false,
+            ),
+            (
+                BackendError::UnsupportedFormatVersion(u16::MAX),
+                false,
+                false,
+            ),
+            (
+                BackendError::IncompatibleVersion {
+                    written: Version::new(2, 0, 0),
+                    current: Version::new(1, 0, 0),
+                },
+                false,
+                false,
+            ),
+            (
+                BackendError::QuotaExceeded { limit: 1, usage: 2 },
+                false,
+                false,
+            ),
+            (BackendError::Unknown("unknown".to_string()), false, false),
+        ];
+        for (error, retryable, not_found) in errors {
+            assert_eq!(error.is_retryable(), retryable, "{}", error.kind());
+            assert_eq!(error.is_not_found(), not_found, "{}", error.kind());
+        }
+    }
+}
@@ -4,8 +4,46 @@ pub mod index;
 pub mod manifest;
 pub mod segment;
 pub mod sync_backend;
+pub mod version;
 
 pub use files::*;
 pub use index::*;
 pub use manifest::*;
 pub use segment::*;
+
+use crate::repository::backend::SegmentDescriptor;
+
+use futures::channel::oneshot;
+use tracing::warn;
+
+/// Sends the result of an operation back to the caller that requested it
+///
+/// Event loops use this instead of unwrapping the result of `send`, as a caller that has stopped
+/// waiting for its result, such as one whose future was dropped, should not take down the whole
+/// backend. In that case, the result is discarded and a warning is logged.
+pub fn reply<T>(ret: oneshot::Sender<T>, value: T, operation: &str) {
+    if ret.send(value).is_err() {
+        warn!(
+            "Caller stopped waiting for the result of a {} operation, discarding it",
+            operation
+        );
+    }
+}
+
+/// Picks out the segments a backend should open in response to a prefetch hint
+///
+/// Returns the distinct segment IDs of the given locations, in order of first appearance, limited
+/// to `capacity`, so that opening them into a cache of that size does not evict segments opened
+/// earlier for the same hint.
+pub fn segments_to_prefetch(locations: &[SegmentDescriptor], capacity: usize) -> Vec<u64> {
+    let mut segments = Vec::new();
+    for location in locations {
+        if segments.len() >= capacity {
+            break;
+        }
+        if !segments.contains(&location.segment_id) {
+            segments.push(location.segment_id);
+        }
+    }
+    segments
+}
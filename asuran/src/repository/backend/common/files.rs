@@ -1,8 +1,156 @@
-use std::fs::{remove_file, File, OpenOptions};
-use std::io::{Read, Result, Seek, Write};
+use crate::repository::backend::Durability;
+
+use uuid::Uuid;
+
+use std::fs::{create_dir_all, read_dir, remove_file, rename, File, OpenOptions};
+use std::io::{ErrorKind, Read, Result, Seek, SeekFrom, Write};
 use std::ops::{Deref, DerefMut, Drop};
 use std::path::{Path, PathBuf};
 
+/// Prefix of the names of the temporary files written by `write_temp_file`
+///
+/// A crash between writing a temporary file and renaming it into place leaves the temporary file
+/// behind. These are never read, and can be identified by this prefix.
+pub const TEMP_FILE_PREFIX: &str = ".asuran-tmp-";
+
+/// Writes `contents` to a new, uniquely named, file in `temp_dir`, returning its path
+///
+/// The file is fsynced if `durability` calls for it, so that once it has been renamed into place,
+/// the rename can not reach the disk before the contents do. `temp_dir` is created if it does not
+/// exist.
+pub fn write_temp_file(
+    temp_dir: &Path,
+    contents: &[u8],
+    durability: Durability,
+) -> Result<PathBuf> {
+    create_dir_all(temp_dir)?;
+    let path = temp_dir.join(format!(
+        "{}{}",
+        TEMP_FILE_PREFIX,
+        Uuid::new_v4().to_simple()
+    ));
+    let mut file = OpenOptions::new()
+        .create_new(true)
+        .write(true)
+        .open(&path)?;
+    file.write_all(contents)?;
+    durability.sync(&mut file)?;
+    Ok(path)
+}
+
+/// Lists the files in `dir` whose names are base 10 integers, sorted by that integer
+///
+/// Other files, such as lock files and temporary files, are ignored.
+pub fn numbered_files(dir: &Path) -> Result<Vec<(usize, PathBuf)>> {
+    let mut items = read_dir(dir)?
+        .filter_map(std::result::Result::ok)
+        .map(|x| x.path())
+        .filter(|x| x.is_file())
+        .filter_map(|x| {
+            let id = x.file_name()?.to_str()?.parse::<usize>().ok()?;
+            Some((id, x))
+        })
+        .collect::<Vec<_>>();
+    items.sort_by(|a, b| a.0.cmp(&b.0));
+    Ok(items)
+}
+
+/// Renames the temporary file at `temp_path` into `dir`, under a number higher than that of any
+/// file already there, returning the number it was given
+///
+/// The number is reserved by atomically creating an empty file with it before the rename, so
+/// connections racing to add files to the same directory never replace each other's files. A
+/// crash between the two leaves an empty file behind, which contains no entries. If `durability`
+/// calls for it, `dir` is fsynced afterwards, so that the rename itself survives a crash.
+pub fn rename_numbered(dir: &Path, temp_path: &Path, durability: Durability) -> Result<usize> {
+    let id = match reserve_and_rename(dir, temp_path) {
+        Ok(id) => id,
+        Err(e) => {
+            // Don't leave the temporary file behind if it can not be used
+            let _ = remove_file(temp_path);
+            return Err(e);
+        }
+    };
+    // Directories can only be opened for syncing on unix like platforms
+    #[cfg(unix)]
+    durability.sync(&mut File::open(dir)?)?;
+    #[cfg(not(unix))]
+    let _ = durability;
+    Ok(id)
+}
+
+/// Reserves the next free number in `dir`, and renames the file at `temp_path` over it
+fn reserve_and_rename(dir: &Path, temp_path: &Path) -> Result<usize> {
+    let mut id = numbered_files(dir)?.last().map_or(0, |(id, _)| id + 1);
+    loop {
+        match OpenOptions::new()
+            .create_new(true)
+            .write(true)
+            .open(dir.join(id.to_string()))
+        {
+            Ok(_) => break,
+            Err(e) if e.kind() == ErrorKind::AlreadyExists => id += 1,
+            Err(e) => return Err(e),
+        }
+    }
+    rename(temp_path, dir.join(id.to_string()))?;
+    Ok(id)
+}
+
+/// Removes the temporary files, as written by `write_temp_file`, in `dir`, returning how many were
+/// removed
+///
+/// Only call this while no other connection can be writing to `dir`, as files that are still
+/// being written, and have yet to be renamed into place, are removed as well.
+pub fn remove_temp_files(dir: &Path) -> Result<usize> {
+    if !dir.exists() {
+        return Ok(0);
+    }
+    let mut count = 0;
+    for entry in read_dir(dir)? {
+        let entry = entry?;
+        let is_temp = entry
+            .file_name()
+            .to_str()
+            .map_or(false, |x| x.starts_with(TEMP_FILE_PREFIX));
+        if is_temp && entry.path().is_file() {
+            remove_file(entry.path())?;
+            count += 1;
+        }
+    }
+    Ok(count)
+}
+
+/// A handle that can be asked to commit its contents to durable storage
+///
+/// This exists so that code that decides when to fsync can be exercised without touching a real
+/// disk. See `Durability::sync`.
+pub trait SyncData {
+    /// Waits for all data written to this handle to reach durable storage, as `File::sync_data`
+    fn sync_data(&mut self) -> Result<()>;
+}
+
+impl SyncData for File {
+    fn sync_data(&mut self) -> Result<()> {
+        File::sync_data(self)
+    }
+}
+
+/// A handle that can be truncated, or extended, to a given length
+///
+/// Used to cut off records left half written by an interrupted append, see
+/// `Segment::truncate_torn_tail`.
+pub trait SetLen {
+    /// Sets the length of the underlying file, as `File::set_len`
+    fn set_len(&mut self, length: u64) -> Result<()>;
+}
+
+impl SetLen for File {
+    fn set_len(&mut self, length: u64) -> Result<()> {
+        File::set_len(self, length)
+    }
+}
+
 /// Wraps a file with its paired lock file.
 ///
 /// The lock file is deleted upon dropping
@@ -30,28 +178,84 @@ impl LockedFile {
             "lock".to_string()
         };
         let lock_file_path = path.with_extension(extension);
-        // Check to see if the lock file exists
-        if Path::exists(&lock_file_path) {
+        // First, create the lock file, atomically failing if it already exists, so that two
+        // processes racing for the same file can not both believe they hold the lock
+        match OpenOptions::new()
+            .create_new(true)
+            .write(true)
+            .open(&lock_file_path)
+        {
+            Ok(_) => {}
             // Unable to return the lock, failing
-            Ok(None)
-        } else {
-            // First, create the lock file
-            OpenOptions::new()
-                .create(true)
-                .write(true)
-                .open(&lock_file_path)?;
-            // Second, open the real file
-            let file = OpenOptions::new()
-                .create(true)
-                .read(true)
-                .write(true)
-                .open(&path)?;
-            Ok(Some(LockedFile {
-                file,
-                path,
-                lock_file_path,
-            }))
+            Err(e) if e.kind() == ErrorKind::AlreadyExists => return Ok(None),
+            Err(e) => return Err(e),
         }
+        // Second, open the real file
+        let file = match OpenOptions::new()
+            .create(true)
+            .read(true)
+            .write(true)
+            .open(&path)
+        {
+            Ok(file) => file,
+            Err(e) => {
+                // Don't leave behind a lock for a file we failed to open
+                let _ = remove_file(&lock_file_path);
+                return Err(e);
+            }
+        };
+        Ok(Some(LockedFile {
+            file,
+            path,
+            lock_file_path,
+        }))
+    }
+
+    /// Reads the entire current contents of the file
+    pub fn read_contents(&mut self) -> Result<Vec<u8>> {
+        let mut contents = Vec::new();
+        self.file.seek(SeekFrom::Start(0))?;
+        self.file.read_to_end(&mut contents)?;
+        Ok(contents)
+    }
+
+    /// Atomically replaces the contents of the file with `contents`
+    ///
+    /// The new contents are written to a temporary file in `temp_dir` with `write_temp_file`,
+    /// which is then renamed over the file, so a crash at any point leaves the file with either
+    /// its old or its new contents, never a mix of the two. `temp_dir` must be on the same
+    /// filesystem as the file, as renames can not cross filesystems.
+    pub fn replace_contents(
+        &mut self,
+        contents: &[u8],
+        temp_dir: &Path,
+        durability: Durability,
+    ) -> Result<()> {
+        let temp_path = write_temp_file(temp_dir, contents, durability)?;
+        self.rename_into_place(&temp_path, durability)
+    }
+
+    /// Renames the file at `temp_path` over this file, and reopens this handle on it
+    ///
+    /// The lock is held throughout. If `durability` calls for it, the directory containing the
+    /// file is fsynced afterwards, so that the rename itself survives a crash.
+    pub fn rename_into_place(&mut self, temp_path: &Path, durability: Durability) -> Result<()> {
+        if let Err(e) = rename(temp_path, &self.path) {
+            // Don't leave the temporary file behind if it can not be used
+            let _ = remove_file(temp_path);
+            return Err(e);
+        }
+        self.file = OpenOptions::new().read(true).write(true).open(&self.path)?;
+        // Directories can only be opened for syncing on unix like platforms
+        #[cfg(unix)]
+        {
+            if let Some(parent) = self.path.parent() {
+                durability.sync(&mut File::open(parent)?)?;
+            }
+        }
+        #[cfg(not(unix))]
+        let _ = durability;
+        Ok(())
     }
 }
 
@@ -84,6 +288,18 @@ impl Drop for LockedFile {
     }
 }
 
+impl SyncData for LockedFile {
+    fn sync_data(&mut self) -> Result<()> {
+        self.file.sync_data()
+    }
+}
+
+impl SetLen for LockedFile {
+    fn set_len(&mut self, length: u64) -> Result<()> {
+        self.file.set_len(length)
+    }
+}
+
 impl Read for LockedFile {
     fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
         self.file.read(buf)
@@ -3,18 +3,25 @@
 //!
 //! # Initial Header
 //!
-//! The initial header contains three components:
+//! The initial header contains four components:
 //!
 //! 1. Magic Number
 //!
 //!     The magic number identifying asuran `FlatFile`s is the 8-byte string
 //!     `b"ASURAN_F"`.
 //!
-//! 2. Length of header
+//! 2. Format version
+//!
+//!     A `u16` of zero, followed by the format version as a `u16`. Files newer
+//!     than `FORMAT_VERSION` are refused. Version 1 files predate this component,
+//!     and do not have it. Version 1 files are opened without being modified, and
+//!     upgraded to the current version before the first write to them.
+//!
+//! 3. Length of header
 //!
 //!     The total length of the encrypted key, in bytes, as a u16.
 //!
-//! 3. The `EncryptedKey`
+//! 4. The `EncryptedKey`
 //!
 //!     The serialized, encrypted key material for this repository.
 //!
@@ -33,6 +40,8 @@
 //!     by two `u64`s, the first being the location of the footer, and the second
 //!     being the location of the next header. The location of the next header will
 //!     be beyond the end of the file if you are reading the last entry in a file.
+//!     Files with entries written by a newer major version of asuran are refused,
+//!     see `common::version`.
 //!
 //! 2. The Body
 //!
@@ -48,18 +57,28 @@
 //! `FlatFile` repositories are always terminated with an `EntryHeader` with the
 //! `footer_offset` and `next_header_offset` set to 0. This is intended to be
 //! overridden during the next writing session.
+//!
+//! An entry is only considered complete once the header following it has been
+//! written. If a writing session is interrupted, the file may end part way
+//! through its last entry, in which case that entry is discarded on the next
+//! open, and the header pointing to it is rewritten as the terminator. The bytes
+//! of the discarded entry are left in place as dead space, as the file can not
+//! be truncated through a generic handle.
 use super::sync_backend::{SyncBackend, SyncIndex, SyncManifest};
+use super::version::check_version;
 use crate::repository::backend::{
     BackendError, Chunk, ChunkID, ChunkSettings, EncryptedKey, Result, SegmentDescriptor,
     StoredArchive,
 };
 use crate::repository::Key;
+use asuran_chunker::BUZHASH_DEFAULT_MAX_SIZE;
 use asuran_core::repository::backend::flatfile::{
-    EntryFooter, EntryFooterData, EntryHeader, FlatFileHeader,
+    EntryFooter, EntryFooterData, EntryHeader, FlatFileError, FlatFileHeader, ENTRY_HEADER_LENGTH,
 };
 use asuran_core::repository::chunk::{ChunkBody, ChunkHeader};
 
 use chrono::{DateTime, FixedOffset};
+use tracing::warn;
 
 use std::collections::{HashMap, HashSet};
 use std::convert::TryInto;
@@ -67,7 +86,19 @@ use std::fmt::Debug;
 use std::io::{Read, Seek, SeekFrom, Write};
 use std::path::{Path, PathBuf};
 
-pub use asuran_core::repository::backend::flatfile::MAGIC_NUMBER;
+pub use asuran_core::repository::backend::flatfile::{FORMAT_VERSION, MAGIC_NUMBER};
+
+/// Number of bytes used to store the length of an `EntryFooter`'s `Chunk`, ahead of it
+const FOOTER_LENGTH_BYTES: u64 = 8;
+
+/// Default limit on the length of a chunk body that `read_chunk` will attempt to read
+///
+/// This is 128 times the longest chunk the chunkers produce with their default settings, leaving
+/// plenty of headroom for large metadata chunks, such as the listings of archives with many
+/// files, which are not split by a chunker, while still refusing the enormous lengths that result
+/// from corruption. Repositories written with larger chunker settings should raise the limit with
+/// `set_max_chunk_length`.
+pub const DEFAULT_MAX_CHUNK_LENGTH: u64 = BUZHASH_DEFAULT_MAX_SIZE as u64 * 128;
 
 /// A view over a generic `FlatFile` backend.
 ///
@@ -88,6 +119,13 @@ pub struct GenericFlatFile<F: Read + Write + Seek + 'static> {
     key: Key,
     chunk_headers: HashMap<SegmentDescriptor, ChunkHeader>,
     header_offset: u64,
+    /// Format version of the file, as read from its global header
+    format_version: u16,
+    /// Longest chunk body `read_chunk` will attempt to read
+    max_chunk_length: u64,
+    /// Length of the file, kept up to date as it is written to, so reads do not have to seek to
+    /// the end to check it
+    file_length: u64,
 }
 
 impl<F: Read + Write + Seek + 'static> Debug for GenericFlatFile<F> {
@@ -119,6 +157,14 @@ impl<F: Read + Write + Seek + 'static> GenericFlatFile<F> {
     ///   `Err(FlatFileError)`
     /// - If any of the chunks described by the footers do not have an associated `ChunkHeader`
     /// - If an already initalized repository does not contain any footers
+    /// - If any of the offsets in an entry header point outside of its entry, or back to an
+    ///   earlier header, `Err(FlatFileError)`
+    /// - If the repository has a format version newer than `FORMAT_VERSION`,
+    ///   `Err(UnsupportedFormatVersion)`
+    /// - If any entry was written by a newer major version of asuran, `Err(IncompatibleVersion)`
+    ///
+    /// A file that ends part way through its last entry, such as after an interrupted write, is
+    /// not an error. The incomplete entry is discarded, see the module level documentation.
     #[allow(clippy::too_many_lines)]
     pub fn new_raw(
         mut file: F,
@@ -128,7 +174,7 @@ impl<F: Read + Write + Seek + 'static> GenericFlatFile<F> {
         enc_key: Option<EncryptedKey>,
     ) -> Result<GenericFlatFile<F>> {
         // Check to see if file is empty, if so we need to write an initial header
-        let file_length = file.seek(SeekFrom::End(0))?;
+        let mut file_length = file.seek(SeekFrom::End(0))?;
         if file_length == 0 {
             // We need to have chunk settings and an encrypted key in this case
             let settings = settings.ok_or_else(|| {
@@ -144,12 +190,14 @@ impl<F: Read + Write + Seek + 'static> GenericFlatFile<F> {
             // Create the header and write it
             let header = FlatFileHeader::new(&enc_key)?;
             header.to_write(&mut file)?;
+            let format_version = header.format_version;
             let header =
                 EntryHeader::new(&*crate::VERSION_STRUCT, 0, 0, *crate::IMPLEMENTATION_UUID)?;
             // save the header_location
             let header_location = file.seek(SeekFrom::End(0))?;
             // Write the header
             header.to_write(&mut file)?;
+            file_length = file.seek(SeekFrom::Current(0))?;
 
             let flat_file = GenericFlatFile {
                 file,
@@ -164,6 +212,9 @@ impl<F: Read + Write + Seek + 'static> GenericFlatFile<F> {
                 key,
                 chunk_headers: HashMap::new(),
                 header_offset: header_location,
+                format_version,
+                max_chunk_length: DEFAULT_MAX_CHUNK_LENGTH,
+                file_length,
             };
             Ok(flat_file)
         } else {
@@ -171,6 +222,11 @@ impl<F: Read + Write + Seek + 'static> GenericFlatFile<F> {
             // First read the header for the file
             file.seek(SeekFrom::Start(0))?;
             let global_header = FlatFileHeader::from_read(&mut file)?;
+            if global_header.format_version > FORMAT_VERSION {
+                return Err(BackendError::UnsupportedFormatVersion(
+                    global_header.format_version,
+                ));
+            }
             // Extract the encrypted key and flag an error if the user is trying to set ones
             if enc_key.is_some() {
                 return Err(BackendError::ManifestError(
@@ -178,9 +234,13 @@ impl<F: Read + Write + Seek + 'static> GenericFlatFile<F> {
                 ));
             }
             let enc_key = global_header.key()?;
-            // Extract the first entry header
+            // Extract the first entry header, if it was completely written
             let mut header_offset = file.seek(SeekFrom::Current(0))?;
-            let mut entry_header = EntryHeader::from_read(&mut file)?;
+            let mut entry_header = if header_offset + ENTRY_HEADER_LENGTH <= file_length {
+                Some(EntryHeader::from_read(&mut file)?)
+            } else {
+                None
+            };
             // Create a place to put the chunk settings
             let mut chunk_settings: Option<ChunkSettings> = None;
             // Places to put our stuff
@@ -188,11 +248,48 @@ impl<F: Read + Write + Seek + 'static> GenericFlatFile<F> {
             let mut length_map = HashMap::new();
             let mut manifest = Vec::new();
             let mut chunk_headers = HashMap::new();
+            // The version that wrote the previous entry, so runs of entries written by the same
+            // version are only checked once
+            let mut entry_version = None;
             // Parse all the headers and footers
-            while entry_header.footer_offset != 0 && entry_header.next_header_offset != 0 {
+            while let Some(entry) =
+                entry_header.filter(|x| x.footer_offset != 0 && x.next_header_offset != 0)
+            {
+                // Each entry must lie after its header, or a corrupt file could send us in circles
+                if entry.next_header_offset <= header_offset {
+                    return Err(FlatFileError::InvalidOffset(format!(
+                        "Entry header at {} points back to an earlier header at {}",
+                        header_offset, entry.next_header_offset
+                    ))
+                    .into());
+                }
+                // An entry is only complete once the header following it has been written
+                if entry.next_header_offset.saturating_add(ENTRY_HEADER_LENGTH) > file_length {
+                    entry_header = None;
+                    break;
+                }
+                // The footer, and its length, must fit between this header and the next
+                let footer_limit = entry.next_header_offset.saturating_sub(FOOTER_LENGTH_BYTES);
+                if entry.footer_offset < header_offset + ENTRY_HEADER_LENGTH
+                    || entry.footer_offset > footer_limit
+                {
+                    return Err(FlatFileError::InvalidOffset(format!(
+                        "Entry header at {} has its footer at {}, outside of the entry",
+                        header_offset, entry.footer_offset
+                    ))
+                    .into());
+                }
+                // Refuse entries from incompatible versions before trying to parse their footers
+                let version = entry.version();
+                if entry_version.as_ref() != Some(&version) {
+                    check_version(&version)?;
+                    entry_version = Some(version);
+                }
                 // Read the associated footer
-                file.seek(SeekFrom::Start(entry_header.footer_offset))?;
-                let footer = EntryFooter::from_read(&mut file)?.into_data(&key)?;
+                file.seek(SeekFrom::Start(entry.footer_offset))?;
+                let footer =
+                    EntryFooter::from_read_within(&mut file, footer_limit - entry.footer_offset)?
+                        .into_data(&key)?;
                 // Update the chunk settings
                 chunk_settings = Some(footer.chunk_settings);
                 // Parse the chunk locations into segment descriptors
@@ -220,6 +317,11 @@ impl<F: Read + Write + Seek + 'static> GenericFlatFile<F> {
                     chunk_headers.insert(descriptor, header);
                 }
 
+                // Remove any chunks this entry removed
+                for id in footer.removed_chunks {
+                    index.remove(&id);
+                }
+
                 // Load any archives
                 for (id, timestamp) in footer.archives {
                     // Temporary hack, the name field is pending removal
@@ -227,8 +329,21 @@ impl<F: Read + Write + Seek + 'static> GenericFlatFile<F> {
                 }
 
                 // Load up the next header
-                header_offset = file.seek(SeekFrom::Start(entry_header.next_header_offset))?;
-                entry_header = EntryHeader::from_read(&mut file)?;
+                header_offset = file.seek(SeekFrom::Start(entry.next_header_offset))?;
+                entry_header = Some(EntryHeader::from_read(&mut file)?);
+            }
+            // If the last entry was incomplete, make the header that pointed to it the terminator
+            if entry_header.is_none() {
+                warn!(
+                    "FlatFile at {:?} ends part way through an entry, likely due to an \
+                     interrupted write, discarding the incomplete entry",
+                    path
+                );
+                file.seek(SeekFrom::Start(header_offset))?;
+                EntryHeader::new(&*crate::VERSION_STRUCT, 0, 0, *crate::IMPLEMENTATION_UUID)?
+                    .to_write(&mut file)?;
+                // A header that was itself cut off is extended by rewriting it
+                file_length = file_length.max(file.seek(SeekFrom::Current(0))?);
             }
             // If we haven't set chunk settings yet, we have an invalid repository
             let chunk_settings = chunk_settings.ok_or_else(|| {
@@ -251,24 +366,163 @@ impl<F: Read + Write + Seek + 'static> GenericFlatFile<F> {
                 key,
                 chunk_headers,
                 header_offset,
+                format_version: global_header.format_version,
+                max_chunk_length: DEFAULT_MAX_CHUNK_LENGTH,
+                file_length,
             };
 
             Ok(flat_file)
         }
     }
 
+    /// Returns the path of the repository file
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// Returns a mutable reference to the underlying file
+    ///
+    /// Writing to the file through this reference will corrupt the repository, this is intended
+    /// for operations, such as fsyncing, that do not change its contents.
+    pub fn file_mut(&mut self) -> &mut F {
+        &mut self.file
+    }
+
+    /// Sets the longest chunk body that will be read, defaults to `DEFAULT_MAX_CHUNK_LENGTH`
+    ///
+    /// Attempting to read a chunk recorded as longer than this will fail, rather than attempting
+    /// to allocate a buffer for it.
+    pub fn set_max_chunk_length(&mut self, max_chunk_length: u64) {
+        self.max_chunk_length = max_chunk_length;
+    }
+
+    /// Returns the format version of the repository file
+    pub fn format_version(&self) -> u16 {
+        self.format_version
+    }
+
     /// Attempts to read an `EncryptedKey` from the header of the provided repository
     /// file
     ///
     /// # Errors
     ///
     /// - If an underlying I/O error occurs
+    /// - If the repository has a format version newer than `FORMAT_VERSION`
     /// - If decoding the `EncryptedKey` fails
     pub fn load_encrypted_key(mut file: F) -> Result<EncryptedKey> {
         file.seek(SeekFrom::Start(0))?;
         let header = FlatFileHeader::from_read(&mut file)?;
+        if header.format_version > FORMAT_VERSION {
+            return Err(BackendError::UnsupportedFormatVersion(
+                header.format_version,
+            ));
+        }
         Ok(header.key()?)
     }
+
+    /// Brings an older file up to `FORMAT_VERSION` before anything is written to it
+    ///
+    /// Files are never migrated just by opening them, so that older files can still be read
+    /// without being modified. This is called at the start of every operation that modifies the
+    /// file, and does nothing once the file is up to date.
+    ///
+    /// # Errors
+    ///
+    /// Will return `Err` if an underlying I/O error occurs while upgrading
+    fn migrate(&mut self) -> Result<()> {
+        if self.format_version == 1 {
+            self.upgrade_v1()?;
+        }
+        Ok(())
+    }
+
+    /// Upgrades a version 1 file to version 2
+    ///
+    /// The version 2 global header is 4 bytes longer, so rewriting it overwrites the first entry
+    /// header, and the first 4 bytes after it. Rather than shifting the entire file along, the
+    /// current state of the repository is written out as a single new entry at the end of the
+    /// file, and the new first entry header points straight to it. Chunks in the overwritten bytes
+    /// are copied into the new entry, every other chunk is referenced at its existing location,
+    /// and everything else between the first header and the new entry becomes dead space.
+    ///
+    /// The new entry is written in full before the start of the file is touched. The locations of
+    /// copied chunks change, so locations looked up before the upgrade must not be reused.
+    ///
+    /// # Errors
+    ///
+    /// Will return `Err` if an underlying I/O error occurs
+    fn upgrade_v1(&mut self) -> Result<()> {
+        self.file.seek(SeekFrom::Start(0))?;
+        let mut global_header = FlatFileHeader::from_read(&mut self.file)?;
+        let overwritten_start = global_header.total_length() + ENTRY_HEADER_LENGTH;
+        global_header.format_version = FORMAT_VERSION;
+        let first_header = global_header.total_length();
+        let overwritten_end = first_header + ENTRY_HEADER_LENGTH;
+        // Copy the chunks that are about to be overwritten to the end of the file
+        let overwritten: Vec<SegmentDescriptor> = self
+            .length_map
+            .iter()
+            .filter(|(x, length)| {
+                x.start < overwritten_end && x.start.saturating_add(**length) > overwritten_start
+            })
+            .map(|(x, _)| *x)
+            .collect();
+        for old in overwritten {
+            let (header, body) = self.read_chunk(old)?.split();
+            let new = SegmentDescriptor {
+                segment_id: 0,
+                start: self.file.seek(SeekFrom::End(0))?,
+            };
+            self.file.write_all(&body.0[..])?;
+            self.file_length = new.start + body.0.len() as u64;
+            self.length_map.remove(&old);
+            self.length_map.insert(new, body.0.len() as u64);
+            self.chunk_headers.remove(&old);
+            self.chunk_headers.insert(new, header);
+            for location in self.index.values_mut().filter(|x| **x == old) {
+                *location = new;
+            }
+        }
+        // Describe the entire repository in a single footer
+        let mut footer = EntryFooterData::new(self.chunk_settings);
+        for (id, location) in &self.index {
+            if let (Some(length), Some(header)) = (
+                self.length_map.get(location),
+                self.chunk_headers.get(location),
+            ) {
+                footer.add_chunk(*id, location.start, *length);
+                footer.add_header(*id, header.clone());
+            } else {
+                return Err(BackendError::IndexError(format!(
+                    "Chunk {:?} at location {:?} does not have a known length and header",
+                    id, location
+                )));
+            }
+        }
+        for archive in &self.manifest {
+            footer.add_archive(archive.id, archive.timestamp);
+        }
+        let footer = EntryFooter::from_data(&footer, &self.key, self.chunk_settings);
+        let footer_location = self.file.seek(SeekFrom::End(0))?;
+        footer.to_write(Write::by_ref(&mut self.file))?;
+        let header_location = self.file.seek(SeekFrom::End(0))?;
+        EntryHeader::new(&*crate::VERSION_STRUCT, 0, 0, *crate::IMPLEMENTATION_UUID)?
+            .to_write(Write::by_ref(&mut self.file))?;
+        self.file_length = self.file.seek(SeekFrom::Current(0))?;
+        // Only now replace the global header, and point the first entry at the new one
+        self.file.seek(SeekFrom::Start(0))?;
+        global_header.to_write(Write::by_ref(&mut self.file))?;
+        EntryHeader::new(
+            &*crate::VERSION_STRUCT,
+            footer_location,
+            header_location,
+            *crate::IMPLEMENTATION_UUID,
+        )?
+        .to_write(Write::by_ref(&mut self.file))?;
+        self.header_offset = header_location;
+        self.format_version = FORMAT_VERSION;
+        Ok(())
+    }
 }
 
 impl<F: Read + Write + Seek + 'static> SyncManifest for GenericFlatFile<F> {
@@ -298,6 +552,7 @@ impl<F: Read + Write + Seek + 'static> SyncManifest for GenericFlatFile<F> {
     /// only the chunk settings were modified, this change will still get persisted to
     /// the repository.
     fn write_chunk_settings(&mut self, settings: ChunkSettings) -> Result<()> {
+        self.migrate()?;
         self.chunk_settings = settings;
         self.entry_footer_data.chunk_settings = settings;
         self.chunk_settings_modified = true;
@@ -309,6 +564,7 @@ impl<F: Read + Write + Seek + 'static> SyncManifest for GenericFlatFile<F> {
     }
     /// Adds the archive to the cached `manifest` `Vec`, as well as to the `EntryFooterData`
     fn write_archive(&mut self, archive: StoredArchive) -> Result<()> {
+        self.migrate()?;
         self.entry_footer_data
             .add_archive(archive.id, archive.timestamp);
         self.manifest.push(archive);
@@ -330,18 +586,22 @@ impl<F: Read + Write + Seek + 'static> SyncIndex for GenericFlatFile<F> {
     ///
     /// # Errors
     ///
-    /// Will return `Err` if the `Chunk` had not been previously written with
-    /// `write_chunk`, and thus has an unknown length.
+    /// Will return `Err(BackendError::IndexError)` if the `Chunk` had not been previously
+    /// written with `write_chunk`, and thus has an unknown length.
     fn set_chunk(&mut self, id: ChunkID, location: SegmentDescriptor) -> Result<()> {
+        self.migrate()?;
         let length = self.length_map.get(&location).ok_or_else(|| {
             BackendError::IndexError(format!(
-                "Attempted to add chunk with id {:?} to the index, whose length was not known",
-                id
+                "Attempted to index chunk {:?} at location {:?}, but no chunk was written there",
+                id, location
             ))
         })?;
         self.index.insert(id, location);
         let location = location.start;
         self.entry_footer_data.add_chunk(id, location, *length);
+        // Removals are applied after additions when loading, so make sure re-adding a chunk in the
+        // same entry it was removed in sticks
+        self.entry_footer_data.removed_chunks.retain(|x| *x != id);
         Ok(())
     }
     /// Collects the keys from the cached `index` map into a `HashSet`
@@ -368,6 +628,7 @@ impl<F: Read + Write + Seek + 'static> SyncIndex for GenericFlatFile<F> {
             let header_location = file.seek(SeekFrom::End(0))?;
             EntryHeader::new(&*crate::VERSION_STRUCT, 0, 0, *crate::IMPLEMENTATION_UUID)?
                 .to_write(Write::by_ref(file))?;
+            self.file_length = file.seek(SeekFrom::Current(0))?;
             // Go back and update the previous header
             file.seek(SeekFrom::Start(self.header_offset))?;
             EntryHeader::new(
@@ -404,6 +665,9 @@ impl<F: Read + Write + Seek + 'static> SyncBackend for GenericFlatFile<F> {
     }
     /// This operation is not currently supported for `FlatFile` repositories, so we just return an
     /// error
+    ///
+    /// The key is stored in the header of the repository file, which can not currently be
+    /// rewritten. As such, `Repository::rewrap_key` always fails on `FlatFile` repositories.
     fn write_key(&mut self, _key: EncryptedKey) -> Result<()> {
         Err(BackendError::Unknown(
             "Changing the key of a FlatFile repository is not supported at this time.".to_string(),
@@ -422,6 +686,8 @@ impl<F: Read + Write + Seek + 'static> SyncBackend for GenericFlatFile<F> {
     /// - If the location is not present in the length map (the chunk has not been seen
     ///   before, or it has not been written with `write_chunk`
     /// - If the header is not present in the `chunk_headers` map
+    /// - If the recorded length of the chunk runs past the end of the file, or is longer than
+    ///   the maximum chunk length, see `set_max_chunk_length`
     fn read_chunk(&mut self, location: SegmentDescriptor) -> Result<Chunk> {
         // Find the start of its chunk, and lookup its length
         let start = location.start;
@@ -431,13 +697,25 @@ impl<F: Read + Write + Seek + 'static> SyncBackend for GenericFlatFile<F> {
                 location
             ))
         })?;
+        // Make sure the length is sane before allocating a buffer for it, a corrupt length could
+        // otherwise request a huge allocation
+        if length > self.max_chunk_length || start.saturating_add(length) > self.file_length {
+            return Err(BackendError::SegmentError(format!(
+                "Chunk at location {:?} has a length of {} bytes, which is longer than the \
+                 maximum of {} bytes, or runs past the end of the file",
+                location, length, self.max_chunk_length
+            )));
+        }
+        let buffer_len: usize = length.try_into().map_err(|_| {
+            BackendError::SegmentError(format!(
+                "Chunk at location {:?} is too large to fit in memory",
+                location
+            ))
+        })?;
         // Seek to the start of the chunk
         let file = &mut self.file;
         file.seek(SeekFrom::Start(start))?;
         // Allocate a buffer to read the bytes into
-        let buffer_len: usize = length
-            .try_into()
-            .expect("Attempted to read a chunk that could not possibly fit into memory");
         let mut buffer = vec![0_u8; buffer_len];
         // Read the body of the chunk
         file.read_exact(&mut buffer[..])?;
@@ -457,7 +735,26 @@ impl<F: Read + Write + Seek + 'static> SyncBackend for GenericFlatFile<F> {
         let chunk = Chunk::unsplit(header, ChunkBody(buffer));
         Ok(chunk)
     }
+    /// Looks the chunk's header and length up in the `chunk_headers` and `length_map` maps,
+    /// without touching the file
+    fn read_chunk_header(&mut self, location: SegmentDescriptor) -> Result<(ChunkHeader, u64)> {
+        let length = *self.length_map.get(&location).ok_or_else(|| {
+            BackendError::SegmentError(format!(
+                "Attempted to look up chunk with location {:?}, but its length was not known",
+                location
+            ))
+        })?;
+        let header = self.chunk_headers.get(&location).ok_or_else(|| {
+            BackendError::SegmentError(format!(
+                "Attempted to look up chunk with location {:?}, \
+                 but there was no associated chunk header",
+                location
+            ))
+        })?;
+        Ok((header.clone(), length))
+    }
     fn write_chunk(&mut self, chunk: Chunk) -> Result<SegmentDescriptor> {
+        self.migrate()?;
         let id = chunk.get_id();
         // Seek to the end of the file and record that location
         let file = &mut self.file;
@@ -480,9 +777,27 @@ impl<F: Read + Write + Seek + 'static> SyncBackend for GenericFlatFile<F> {
         self.chunk_headers.insert(descriptor, header);
         // Write the chunk to the file
         file.write_all(&body.0[..])?;
+        self.file_length = location + length;
 
         Ok(descriptor)
     }
+    /// Removes the chunk from the cached `index` map, and records the removal in the
+    /// `EntryFooterData`
+    ///
+    /// The body of the chunk is left in place in the file, to be reclaimed during compaction.
+    fn remove_chunk(&mut self, id: ChunkID) -> Result<()> {
+        self.migrate()?;
+        if self.index.remove(&id).is_some() {
+            self.entry_footer_data.remove_chunk(id);
+        }
+        Ok(())
+    }
+    /// Queries the current position of the underlying file, which requires the handle to still be
+    /// usable
+    fn ping(&mut self) -> Result<()> {
+        self.file.seek(SeekFrom::Current(0))?;
+        Ok(())
+    }
 }
 
 impl<T: Read + Write + Seek + 'static> Drop for GenericFlatFile<T> {
@@ -497,3 +812,154 @@ impl<T: Read + Write + Seek + 'static> Drop for GenericFlatFile<T> {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::repository::Encryption;
+
+    use chrono::Local;
+
+    use std::io::Cursor;
+
+    fn new_flatfile() -> GenericFlatFile<Cursor<Vec<u8>>> {
+        let key = Key::random(32);
+        let enc_key = EncryptedKey::encrypt(&key, 512, 1, Encryption::new_aes256ctr(), b"");
+        GenericFlatFile::new_raw(
+            Cursor::new(Vec::new()),
+            "memory",
+            Some(ChunkSettings::lightweight()),
+            key,
+            Some(enc_key),
+        )
+        .unwrap()
+    }
+
+    // A corrupted length must produce an error, rather than an attempt at a huge allocation
+    #[test]
+    fn oversized_length_rejected() {
+        let mut flatfile = new_flatfile();
+        let chunk = Chunk::pack(
+            vec![1_u8; 1024],
+            ChunkSettings::lightweight().compression,
+            ChunkSettings::lightweight().encryption,
+            ChunkSettings::lightweight().hmac,
+            &flatfile.key,
+        );
+        let location = flatfile.write_chunk(chunk.clone()).unwrap();
+        assert_eq!(flatfile.read_chunk(location).unwrap(), chunk);
+
+        flatfile.length_map.insert(location, u64::MAX / 2);
+        assert!(matches!(
+            flatfile.read_chunk(location),
+            Err(BackendError::SegmentError(_))
+        ));
+
+        flatfile.length_map.insert(location, chunk.len() as u64);
+        flatfile.set_max_chunk_length(16);
+        assert!(matches!(
+            flatfile.read_chunk(location),
+            Err(BackendError::SegmentError(_))
+        ));
+    }
+    // Indexing a location nothing was written to is an index error, not a missing chunk
+    #[test]
+    fn set_chunk_unknown_location_rejected() {
+        let mut flatfile = new_flatfile();
+        let location = SegmentDescriptor {
+            segment_id: 0,
+            start: 12345,
+        };
+        assert!(matches!(
+            flatfile.set_chunk(ChunkID::random_id(), location),
+            Err(BackendError::IndexError(_))
+        ));
+    }
+
+    /// Builds a version 1 file, holding a single entry with the given chunk and archive
+    fn v1_file(key: &Key, chunk: &Chunk, archive: &StoredArchive) -> Cursor<Vec<u8>> {
+        let settings = ChunkSettings::lightweight();
+        let enc_key = EncryptedKey::encrypt(key, 512, 1, Encryption::new_aes256ctr(), b"");
+        let mut global_header = FlatFileHeader::new(&enc_key).unwrap();
+        global_header.format_version = 1;
+        let mut file = Cursor::new(Vec::new());
+        global_header.to_write(&mut file).unwrap();
+        // Leave room for the entry header, and fill it in once the offsets are known
+        let header_offset = file.position();
+        file.set_position(header_offset + ENTRY_HEADER_LENGTH);
+        let location = file.position();
+        let (header, body) = chunk.clone().split();
+        file.write_all(&body.0[..]).unwrap();
+        let mut footer = EntryFooterData::new(settings);
+        footer.add_chunk(chunk.get_id(), location, body.0.len() as u64);
+        footer.add_header(chunk.get_id(), header);
+        footer.add_archive(archive.id, archive.timestamp);
+        let footer_offset = file.position();
+        EntryFooter::from_data(&footer, key, settings)
+            .to_write(&mut file)
+            .unwrap();
+        let next_header_offset = file.position();
+        EntryHeader::new(&*crate::VERSION_STRUCT, 0, 0, *crate::IMPLEMENTATION_UUID)
+            .unwrap()
+            .to_write(&mut file)
+            .unwrap();
+        file.set_position(header_offset);
+        EntryHeader::new(
+            &*crate::VERSION_STRUCT,
+            footer_offset,
+            next_header_offset,
+            *crate::IMPLEMENTATION_UUID,
+        )
+        .unwrap()
+        .to_write(&mut file)
+        .unwrap();
+        file
+    }
+
+    // Version 1 files are left alone when opened, and upgraded by the first write to them
+    #[test]
+    fn v1_upgraded_on_first_write() {
+        let key = Key::random(32);
+        let settings = ChunkSettings::lightweight();
+        let pack = |byte: u8| {
+            Chunk::pack(
+                vec![byte; 1024],
+                settings.compression,
+                settings.encryption,
+                settings.hmac,
+                &key,
+            )
+        };
+        let (first, second) = (pack(1), pack(2));
+        let archive = StoredArchive {
+            id: ChunkID::random_id(),
+            timestamp: Local::now().with_timezone(Local::now().offset()),
+        };
+        let file = v1_file(&key, &first, &archive);
+        let original = file.get_ref().clone();
+
+        let mut flatfile =
+            GenericFlatFile::new_raw(file, "memory", None, key.clone(), None).unwrap();
+        assert_eq!(flatfile.format_version(), 1);
+        let location = flatfile.lookup_chunk(first.get_id()).unwrap();
+        assert_eq!(flatfile.read_chunk(location).unwrap(), first);
+        assert_eq!(flatfile.file_mut().get_ref(), &original);
+
+        let location = flatfile.write_chunk(second.clone()).unwrap();
+        flatfile.set_chunk(second.get_id(), location).unwrap();
+        assert_eq!(flatfile.format_version(), FORMAT_VERSION);
+        flatfile.commit_index().unwrap();
+        let upgraded = flatfile.file_mut().get_ref().clone();
+        drop(flatfile);
+
+        let mut flatfile =
+            GenericFlatFile::new_raw(Cursor::new(upgraded), "memory", None, key, None).unwrap();
+        assert_eq!(flatfile.format_version(), FORMAT_VERSION);
+        for chunk in &[first, second] {
+            let location = flatfile.lookup_chunk(chunk.get_id()).unwrap();
+            assert_eq!(&flatfile.read_chunk(location).unwrap(), chunk);
+        }
+        let archives: Vec<ChunkID> = flatfile.archive_iterator().map(|x| x.id).collect();
+        assert_eq!(archives, vec![archive.id]);
+    }
+}
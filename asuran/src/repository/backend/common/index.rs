@@ -10,4 +10,9 @@ pub struct IndexTransaction {
     pub chunk_id: ChunkID,
     /// The location of this `Chunk` on disk
     pub descriptor: SegmentDescriptor,
+    /// Indicates that this transaction removes the `Chunk` from the index, rather than adding it
+    ///
+    /// The descriptor of a removal is the location the `Chunk` was previously stored at
+    #[serde(default)]
+    pub removed: bool,
 }
@@ -1,9 +1,19 @@
+use crate::manifest::StoredArchive;
+use crate::repository::audit::AuditHead;
+use crate::repository::backend::Result;
 use crate::repository::{ChunkID, Key, HMAC};
 
 use chrono::prelude::*;
+use futures::stream::{self, LocalBoxStream, StreamExt};
+use petgraph::Graph;
 use rand::prelude::*;
 use serde::{Deserialize, Serialize};
 use serde_cbor as cbor;
+use tracing::warn;
+
+use std::cmp::min;
+use std::collections::{HashMap, HashSet};
+use std::future::Future;
 
 /// Wrapper around [u8; 32] used for transaction hashes
 #[derive(Clone, Copy, PartialEq, Eq, Debug, Serialize, Deserialize, Hash)]
@@ -34,6 +44,25 @@ pub struct ManifestTransaction {
     /// This is calculated based off the compact (array form) messagepacked encoding of
     /// this struct with this value set to all zeros
     tag: ManifestID,
+    /// If present, this transaction records an access of an existing archive at the given time,
+    /// rather than the addition of a new one
+    ///
+    /// This is omitted entirely when not present, so transactions that add archives serialize,
+    /// and are tagged, exactly as they were before access times existed.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    accessed: Option<DateTime<FixedOffset>>,
+    /// If set, this transaction records the removal of an existing archive from the manifest,
+    /// rather than the addition of a new one
+    ///
+    /// Like `accessed`, this is omitted entirely when not set.
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    deleted: bool,
+    /// If present, this transaction records the head of the repository's audit log, rather than
+    /// an archive, with `pointer` being the latest entry, and this being the number of entries
+    ///
+    /// Like `accessed`, this is omitted entirely when not present.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    audit_length: Option<u64>,
 }
 
 impl ManifestTransaction {
@@ -57,19 +86,69 @@ impl ManifestTransaction {
             nonce,
             hmac,
             tag: ManifestID([0_u8; 32]),
+            accessed: None,
+            deleted: false,
+            audit_length: None,
         };
         tx.update_tag(key);
         tx
     }
 
+    /// Constructs a new `ManifestTransaction` recording an access of the archive with the given
+    /// pointer and creation timestamp, at the given time
+    pub fn new_access(
+        previous_heads: &[ManifestID],
+        pointer: ChunkID,
+        timestamp: DateTime<FixedOffset>,
+        accessed: DateTime<FixedOffset>,
+        hmac: HMAC,
+        key: &Key,
+    ) -> ManifestTransaction {
+        let mut tx = ManifestTransaction::new(previous_heads, pointer, timestamp, hmac, key);
+        tx.accessed = Some(accessed);
+        tx.update_tag(key);
+        tx
+    }
+
+    /// Constructs a new `ManifestTransaction` recording the removal of the archive with the given
+    /// pointer and creation timestamp
+    pub fn new_deletion(
+        previous_heads: &[ManifestID],
+        pointer: ChunkID,
+        timestamp: DateTime<FixedOffset>,
+        hmac: HMAC,
+        key: &Key,
+    ) -> ManifestTransaction {
+        let mut tx = ManifestTransaction::new(previous_heads, pointer, timestamp, hmac, key);
+        tx.deleted = true;
+        tx.update_tag(key);
+        tx
+    }
+
+    /// Constructs a new `ManifestTransaction` recording the head of the repository's audit log
+    pub fn new_audit_head(
+        previous_heads: &[ManifestID],
+        head: AuditHead,
+        timestamp: DateTime<FixedOffset>,
+        hmac: HMAC,
+        key: &Key,
+    ) -> ManifestTransaction {
+        let mut tx = ManifestTransaction::new(previous_heads, head.entry, timestamp, hmac, key);
+        tx.audit_length = Some(head.length);
+        tx.update_tag(key);
+        tx
+    }
+
     /// Serializes the struct, performs the HMAC, and updates the value in place
     ///
-    /// Will zero the hmac value before performing the operation
+    /// Will zero the hmac value before performing the operation. Tags shorter than 32 bytes, as
+    /// custom algorithms may produce, are zero padded.
     fn update_tag(&mut self, key: &Key) {
         self.tag.0 = [0_u8; 32];
         let bytes = cbor::ser::to_vec(self).expect("Serialization in hmac failed");
         let tag = self.hmac.mac(&bytes[..], key);
-        self.tag.0.copy_from_slice(&tag[..32]);
+        let len = min(32, tag.len());
+        self.tag.0[..len].copy_from_slice(&tag[..len]);
     }
 
     /// Returns a refrence to the list of previous heads
@@ -87,6 +166,31 @@ impl ManifestTransaction {
         self.timestamp
     }
 
+    /// Returns the time of the access this transaction records, or `None` if this transaction adds
+    /// an archive
+    pub fn accessed(&self) -> Option<DateTime<FixedOffset>> {
+        self.accessed
+    }
+
+    /// Returns true if this transaction records the removal of an archive
+    pub fn deleted(&self) -> bool {
+        self.deleted
+    }
+
+    /// Returns the audit log head this transaction records, or `None` if it is about an archive
+    pub fn audit_head(&self) -> Option<AuditHead> {
+        self.audit_length.map(|length| AuditHead {
+            entry: self.pointer,
+            length,
+        })
+    }
+
+    /// Returns the latest time recorded in this transaction, either its access time or the
+    /// timestamp of the archive it points to
+    pub fn modified(&self) -> DateTime<FixedOffset> {
+        self.accessed.unwrap_or(self.timestamp)
+    }
+
     /// Returns the HMAC value tag of this transaction
     pub fn tag(&self) -> ManifestID {
         self.tag
@@ -103,10 +207,335 @@ impl ManifestTransaction {
     }
 }
 
+/// Finds the latest access of the archive with the given pointer recorded in a set of
+/// transactions
+pub fn latest_access<'a>(
+    transactions: impl IntoIterator<Item = &'a ManifestTransaction>,
+    pointer: ChunkID,
+) -> Option<DateTime<FixedOffset>> {
+    transactions
+        .into_iter()
+        .filter(|tx| tx.pointer() == pointer)
+        .filter_map(ManifestTransaction::accessed)
+        .max()
+}
+
+/// Finds the latest audit log head recorded in a set of transactions
+///
+/// The log only ever grows, so this is the head with the most entries.
+pub fn latest_audit_head<'a>(
+    transactions: impl IntoIterator<Item = &'a ManifestTransaction>,
+) -> Option<AuditHead> {
+    transactions
+        .into_iter()
+        .filter_map(ManifestTransaction::audit_head)
+        .max_by_key(|head| head.length)
+}
+
+/// Collects the transactions adding archives that have not since been deleted, newest first
+///
+/// Access and deletion transactions point to archives that already have their own transaction,
+/// and are not returned themselves, nor are transactions recording the audit log's head.
+pub fn archive_transactions<'a>(
+    transactions: impl IntoIterator<Item = &'a ManifestTransaction>,
+) -> Vec<ManifestTransaction> {
+    let (deletions, additions): (Vec<_>, Vec<_>) = transactions
+        .into_iter()
+        .filter(|tx| tx.accessed().is_none() && tx.audit_head().is_none())
+        .partition(|tx| tx.deleted());
+    let deleted: HashSet<ChunkID> = deletions.iter().map(|tx| tx.pointer()).collect();
+    let mut items = additions
+        .into_iter()
+        .filter(|tx| !deleted.contains(&tx.pointer()))
+        .cloned()
+        .collect::<Vec<_>>();
+    // Break ties on the archive id, so that the order is the same every time
+    items.sort_by(|a, b| {
+        b.timestamp()
+            .cmp(&a.timestamp())
+            .then_with(|| b.pointer().get_id().cmp(a.pointer().get_id()))
+    });
+    items
+}
+
+/// Number of archives requested at a time by streams built with `paged_archive_stream`
+pub const ARCHIVE_PAGE_SIZE: usize = 64;
+
+/// Builds a stream of archives that requests them from `fetch_page`, given an offset into the list
+/// of archives and a maximum number to return, one page at a time as they are consumed
+///
+/// The stream ends after the first page shorter than requested, or after the first error.
+pub fn paged_archive_stream<'a, F, Fut>(fetch_page: F) -> LocalBoxStream<'a, Result<StoredArchive>>
+where
+    F: FnMut(usize, usize) -> Fut + 'a,
+    Fut: Future<Output = Result<Vec<StoredArchive>>> + 'a,
+{
+    stream::unfold(
+        (fetch_page, 0, false),
+        |(mut fetch_page, offset, done)| async move {
+            if done {
+                return None;
+            }
+            match fetch_page(offset, ARCHIVE_PAGE_SIZE).await {
+                Ok(page) => {
+                    let done = page.len() < ARCHIVE_PAGE_SIZE;
+                    let offset = offset + page.len();
+                    let items = page.into_iter().map(Ok).collect::<Vec<_>>();
+                    Some((stream::iter(items), (fetch_page, offset, done)))
+                }
+                Err(e) => Some((stream::iter(vec![Err(e)]), (fetch_page, offset, true))),
+            }
+        },
+    )
+    .flatten()
+    .boxed_local()
+}
+
+/// A transaction referencing a previous head that is not in the manifest, as left behind by a
+/// partially written manifest
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub struct DanglingParent {
+    /// The tag of the transaction holding the reference
+    pub transaction: ManifestID,
+    /// The tag of the missing previous head
+    pub parent: ManifestID,
+}
+
+/// Finds every reference to a previous head that is not in a set of transactions keyed by their
+/// tags, sorted by the referencing transaction
+pub fn dangling_parents(
+    known_entries: &HashMap<ManifestID, ManifestTransaction>,
+) -> Vec<DanglingParent> {
+    let mut dangling = known_entries
+        .values()
+        .flat_map(|tx| {
+            tx.previous_heads()
+                .iter()
+                .filter(|parent| !known_entries.contains_key(parent))
+                .map(move |parent| DanglingParent {
+                    transaction: tx.tag(),
+                    parent: *parent,
+                })
+        })
+        .collect::<Vec<_>>();
+    dangling.sort_by_key(|x| (x.transaction.0, x.parent.0));
+    dangling
+}
+
+/// Finds the heads, the transactions no other transaction references as a previous head, of a
+/// set of transactions keyed by their tags
+///
+/// References to previous heads that are not in the set, as left behind by a partially written
+/// manifest, are logged and otherwise ignored, so the transactions holding them are treated as
+/// roots. See `dangling_parents` to find them.
+pub fn find_heads(known_entries: &HashMap<ManifestID, ManifestTransaction>) -> Vec<ManifestID> {
+    let dangling = dangling_parents(known_entries);
+    if !dangling.is_empty() {
+        warn!(
+            "{} manifest transaction(s) reference missing previous heads, treating them as roots",
+            dangling.len()
+        );
+    }
+    // Create the graph
+    let mut graph: Graph<ManifestID, ()> = Graph::new();
+    let mut index_map = HashMap::new();
+    // Add each transaction to our map
+    for tx in known_entries.values() {
+        let tag = tx.tag();
+        let id = graph.add_node(tag);
+        index_map.insert(tag, id);
+    }
+    // Go through each transaction in the graph, adding an edge in the new -> old direction
+    // This unwrap is safe because we just added these entries to our hashmap, missing previous
+    // heads are skipped
+    for tx in known_entries.values() {
+        let id = index_map.get(&tx.tag()).unwrap();
+        for other_tx in tx.previous_heads() {
+            if let Some(other_id) = index_map.get(&other_tx) {
+                graph.update_edge(*id, *other_id, ());
+            }
+        }
+    }
+    // reverse all the nodes, so they now point from old to new
+    graph.reverse();
+    // Find all nodes with no outgoing edges, these are our heads
+    let mut heads = Vec::new();
+    for (tag, id) in &index_map {
+        let mut edges = graph.edges(*id);
+        if edges.next() == None {
+            heads.push(*tag);
+        }
+    }
+    heads
+}
+
+/// Verifies the transaction with the given tag, and every transaction it transitively references
+///
+/// Transactions in `verified` are assumed to have already been verified, along with everything
+/// they reference, and are skipped. Every transaction that passes is added to it, so sharing it
+/// between calls verifies each transaction in the DAG at most once. The DAG is walked with an
+/// explicit stack, so arbitrarily long chains can be verified.
+///
+/// Returns false if any of the transactions fail verification, or if the starting transaction is
+/// missing. Missing previous heads are skipped, in the same manner as `find_heads`. After a
+/// failure, `verified` may hold transactions whose parents were never checked, and should be
+/// discarded.
+pub fn verify_transactions(
+    known_entries: &HashMap<ManifestID, ManifestTransaction>,
+    id: ManifestID,
+    key: &Key,
+    verified: &mut HashSet<ManifestID>,
+) -> bool {
+    let mut stack = vec![id];
+    while let Some(id) = stack.pop() {
+        if verified.contains(&id) {
+            continue;
+        }
+        let tx = match known_entries.get(&id) {
+            Some(tx) => tx,
+            None => return false,
+        };
+        if !tx.verify(key) {
+            return false;
+        }
+        verified.insert(id);
+        // Dangling references are treated as roots, see `find_heads`
+        stack.extend(
+            tx.previous_heads()
+                .iter()
+                .filter(|parent| !verified.contains(parent) && known_entries.contains_key(parent)),
+        );
+    }
+    true
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
 
+    // A transaction pointing at a parent that was never written is treated as a root, and reported
+    #[test]
+    fn dangling_parents_are_roots() {
+        let key = Key::random(32);
+        let root = create_tx("root", &key);
+        let child = ManifestTransaction::new(
+            &[root.tag()],
+            ChunkID::new(&[2_u8; 32]),
+            Local::now().with_timezone(Local::now().offset()),
+            HMAC::Blake2b,
+            &key,
+        );
+        let mut entries = HashMap::new();
+        entries.insert(root.tag(), root.clone());
+        entries.insert(child.tag(), child.clone());
+        assert_eq!(find_heads(&entries), vec![child.tag()]);
+        assert!(dangling_parents(&entries).is_empty());
+
+        // A transaction whose parent was lost becomes a head of its own, and still verifies
+        let missing = ManifestID([7_u8; 32]);
+        let orphan = ManifestTransaction::new(
+            &[missing],
+            ChunkID::new(&[3_u8; 32]),
+            Local::now().with_timezone(Local::now().offset()),
+            HMAC::Blake2b,
+            &key,
+        );
+        entries.insert(orphan.tag(), orphan.clone());
+        let mut heads = find_heads(&entries);
+        heads.sort_by_key(|x| x.0);
+        let mut expected = vec![child.tag(), orphan.tag()];
+        expected.sort_by_key(|x| x.0);
+        assert_eq!(heads, expected);
+        assert_eq!(
+            dangling_parents(&entries),
+            vec![DanglingParent {
+                transaction: orphan.tag(),
+                parent: missing,
+            }]
+        );
+        let mut verified = HashSet::new();
+        assert!(verify_transactions(
+            &entries,
+            orphan.tag(),
+            &key,
+            &mut verified
+        ));
+        assert!(!verify_transactions(&entries, missing, &key, &mut verified));
+    }
+
+    // Verifying a very long chain of transactions must not overflow the stack, and must visit
+    // every transaction in it
+    #[test]
+    fn verify_long_chain() {
+        let key = Key::random(32);
+        let mut entries = HashMap::new();
+        let mut previous = create_tx("root", &key);
+        entries.insert(previous.tag(), previous.clone());
+        for i in 0..20_000_u32 {
+            let tx = ManifestTransaction::new(
+                &[previous.tag()],
+                ChunkID::new(&i.to_be_bytes()),
+                Local::now().with_timezone(Local::now().offset()),
+                HMAC::Blake2b,
+                &key,
+            );
+            entries.insert(tx.tag(), tx.clone());
+            previous = tx;
+        }
+        let heads = find_heads(&entries).unwrap();
+        assert_eq!(heads, vec![previous.tag()]);
+        let mut verified = HashSet::new();
+        assert!(verify_transactions(&entries, heads[0], &key, &mut verified));
+        assert_eq!(verified.len(), entries.len());
+
+        // Tampering with any transaction in the chain must be caught
+        let middle = previous.previous_heads()[0];
+        let mut entries = entries;
+        let mut tampered = entries[&middle].clone();
+        tampered.pointer = ChunkID::new(&[9_u8; 32]);
+        entries.insert(middle, tampered);
+        assert!(!verify_transactions(
+            &entries,
+            heads[0],
+            &key,
+            &mut HashSet::new()
+        ));
+    }
+
+    // Transactions recording the audit log's head must not show up as archives
+    #[test]
+    fn audit_heads_are_not_archives() {
+        let key = Key::random(32);
+        let archive = create_tx("archive", &key);
+        let now = Local::now().with_timezone(Local::now().offset());
+        let heads: Vec<ManifestTransaction> = (1..=2)
+            .map(|length| {
+                let head = AuditHead {
+                    entry: ChunkID::random_id(),
+                    length,
+                };
+                ManifestTransaction::new_audit_head(
+                    &[archive.tag()],
+                    head,
+                    now,
+                    HMAC::Blake2b,
+                    &key,
+                )
+            })
+            .collect();
+        assert!(heads.iter().all(|tx| tx.verify(&key)));
+        let transactions: Vec<_> = std::iter::once(&archive).chain(heads.iter()).collect();
+        assert_eq!(
+            archive_transactions(transactions.iter().copied()),
+            vec![archive.clone()]
+        );
+        assert_eq!(
+            latest_audit_head(transactions.iter().copied()),
+            heads[1].audit_head()
+        );
+        assert_eq!(latest_audit_head(std::iter::once(&archive)), None);
+    }
+
     fn create_tx(_name: &str, key: &Key) -> ManifestTransaction {
         let hmac = HMAC::Blake2b;
         let pointer = ChunkID::new(&[1_u8; 32]);
@@ -151,4 +580,54 @@ mod tests {
         let output_tx: ManifestTransaction = cbor::de::from_slice(&bytes[..]).unwrap();
         assert!(output_tx.verify(&key));
     }
+
+    // Deleting an archive must hide it, and only it, from the list of archives
+    #[test]
+    fn deletion_hides_archive() {
+        let key = Key::random(32);
+        let kept = create_tx("kept", &key);
+        let mut dropped = create_tx("dropped", &key);
+        dropped.pointer = ChunkID::new(&[2_u8; 32]);
+        dropped.update_tag(&key);
+        let deletion = ManifestTransaction::new_deletion(
+            &[kept.tag(), dropped.tag()],
+            dropped.pointer(),
+            dropped.timestamp(),
+            HMAC::Blake2b,
+            &key,
+        );
+        assert!(deletion.verify(&key));
+        assert!(deletion.deleted());
+        assert!(!kept.deleted());
+        let bytes = cbor::ser::to_vec(&deletion).unwrap();
+        let output_tx: ManifestTransaction = cbor::de::from_slice(&bytes[..]).unwrap();
+        assert!(output_tx.verify(&key));
+        assert!(output_tx.deleted());
+
+        let archives = archive_transactions(vec![&kept, &dropped, &deletion]);
+        assert_eq!(archives, vec![kept]);
+    }
+
+    // Access transactions should verify, and should survive a round trip
+    #[test]
+    fn access_transaction() {
+        let key = Key::random(32);
+        let tx = create_tx("test", &key);
+        let accessed = Local::now().with_timezone(Local::now().offset());
+        let access = ManifestTransaction::new_access(
+            &[tx.tag()],
+            tx.pointer(),
+            tx.timestamp(),
+            accessed,
+            HMAC::Blake2b,
+            &key,
+        );
+        assert!(access.verify(&key));
+        assert_eq!(tx.accessed(), None);
+        let bytes = cbor::ser::to_vec(&access).unwrap();
+        let output_tx: ManifestTransaction = cbor::de::from_slice(&bytes[..]).unwrap();
+        assert!(output_tx.verify(&key));
+        assert_eq!(output_tx.accessed(), Some(accessed));
+        assert_eq!(output_tx.timestamp(), tx.timestamp());
+    }
 }
@@ -1,20 +1,53 @@
-use crate::repository::backend::{BackendError, Result};
+use crate::repository::backend::common::{SetLen, SyncData};
+use crate::repository::backend::{BackendError, Durability, Result};
 use crate::repository::{Chunk, ChunkSettings, Key};
 
 use asuran_core::repository::chunk::{ChunkBody, ChunkHeader};
 
+use byteorder::{BigEndian, ReadBytesExt, WriteBytesExt};
+use semver::Version;
 use serde::{Deserialize, Serialize};
 use serde_cbor as cbor;
 use uuid::Uuid;
 
 use std::convert::TryInto;
-use std::io::{Read, Seek, SeekFrom, Write};
+use std::io::{self, Read, Seek, SeekFrom, Write};
 
 /// Magic number used for asuran segment files
 ///
 /// More or less completly arbitrary, but used to validate files
 const MAGIC_NUMBER: [u8; 8] = *b"ASURAN_S";
 
+/// Magic number used for asuran segment files in the interleaved format
+///
+/// Distinct from `MAGIC_NUMBER` so that an interleaved segment can never be mistaken for a split
+/// one, or the other way around.
+const INTERLEAVED_MAGIC_NUMBER: [u8; 8] = *b"ASURAN_I";
+
+/// Length of the framing record written before each chunk body
+///
+/// The record is the length of the body as a big endian u64, followed by the CRC32 of the body as
+/// a big endian u32.
+const FRAME_LENGTH: u64 = 12;
+
+/// The on-disk layout of a segment
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+pub enum SegmentFormat {
+    /// Chunk bodies are stored in the segment file, and their headers are stored in a separate,
+    /// encrypted, `.header` file that is rewritten when the segment is flushed
+    ///
+    /// A crash between writing a body and flushing the header file leaves the body unreachable.
+    Split,
+    /// Each chunk is stored as a single record in the segment file, laid out as
+    /// `[header-len][header][body-len][body][crc]`, so a chunk's header and body are written in
+    /// one append
+    ///
+    /// The lengths are big endian u64s, the header is an encrypted, serialized, `ChunkHeader`,
+    /// and the crc is the CRC32 of the header and body, as a big endian u32, written last to mark
+    /// the record as complete.
+    Interleaved,
+}
+
 /// Representation of the header at the start of each file
 ///
 /// Designed to be bincoded directly into a spec compliant format with big endian
@@ -34,11 +67,34 @@ impl Header {
         Self::default()
     }
 
+    /// Creates a new segment header, for a segment of the given format, with correct values for
+    /// this version of libasuran
+    pub fn with_format(format: SegmentFormat) -> Header {
+        let magic_number = match format {
+            SegmentFormat::Split => MAGIC_NUMBER,
+            SegmentFormat::Interleaved => INTERLEAVED_MAGIC_NUMBER,
+        };
+        Header {
+            magic_number,
+            ..Self::default()
+        }
+    }
+
     /// Checks if a header is valid for this version of libasuran
     ///
     /// Currently only checks the header
     pub fn validate(&self) -> bool {
-        self.magic_number == MAGIC_NUMBER
+        self.format().is_some()
+    }
+
+    /// Returns the format of the segment this header belongs to, or `None` if the magic number
+    /// is not recognized
+    pub fn format(&self) -> Option<SegmentFormat> {
+        match self.magic_number {
+            MAGIC_NUMBER => Some(SegmentFormat::Split),
+            INTERLEAVED_MAGIC_NUMBER => Some(SegmentFormat::Interleaved),
+            _ => None,
+        }
     }
 
     /// Returns the implementation UUID
@@ -50,6 +106,28 @@ impl Header {
     pub fn version_string(&self) -> String {
         format!("{}.{}.{}", self.major, self.minor, self.patch)
     }
+
+    /// Returns the version of asuran that wrote the segment
+    pub fn version(&self) -> Version {
+        Version::new(
+            u64::from(self.major),
+            u64::from(self.minor),
+            u64::from(self.patch),
+        )
+    }
+}
+
+/// Reads a segment `Header` from the provided `Read`, which must be at the start of the segment
+///
+/// # Errors
+///
+/// - Will return `Err(BackendError::Unknown)` if deserializing the header fails
+pub fn read_segment_header(read: impl Read) -> Result<Header> {
+    let mut config = bincode::config();
+    config
+        .big_endian()
+        .deserialize_from(read)
+        .map_err(|_| BackendError::Unknown("Header deserialization failed".to_string()))
 }
 
 impl Default for Header {
@@ -71,6 +149,12 @@ pub struct SegmentHeaderEntry {
     pub header: ChunkHeader,
     pub start_offset: u64,
     pub end_offset: u64,
+    /// Whether the body is preceded by a length and CRC framing record
+    ///
+    /// Entries written before framing was introduced do not have this field, and are read
+    /// without validation.
+    #[serde(default)]
+    pub framed: bool,
 }
 
 /// A view over the header portion of a segment
@@ -156,6 +240,11 @@ impl<T: Read + Write + Seek> SegmentHeaderPart<T> {
         self.entries.get(index).cloned()
     }
 
+    /// Returns the number of chunks described by this header
+    pub fn chunk_count(&self) -> usize {
+        self.entries.len()
+    }
+
     /// Will insert the chunk header information and provide its index
     pub fn insert_header(&mut self, header: SegmentHeaderEntry) -> usize {
         let index = self.entries.len();
@@ -175,6 +264,7 @@ impl<T: Read + Write + Seek> Drop for SegmentHeaderPart<T> {
 pub struct SegmentDataPart<T> {
     handle: T,
     size_limit: u64,
+    format: SegmentFormat,
 }
 
 impl<T: Read + Write + Seek> SegmentDataPart<T> {
@@ -186,7 +276,22 @@ impl<T: Read + Write + Seek> SegmentDataPart<T> {
     /// - Will return `Err(BackendError::SegmentError)` if the segment has a header and
     ///   it fails validation
     pub fn new(handle: T, size_limit: u64) -> Result<Self> {
-        let mut s = SegmentDataPart { handle, size_limit };
+        Self::with_format(handle, size_limit, SegmentFormat::Split)
+    }
+
+    /// Will attempt to open the given handle as a `SegmentDataPart` in the given format
+    ///
+    /// # Errors
+    ///
+    /// - Will propagate any IO errors
+    /// - Will return `Err(BackendError::SegmentError)` if the segment has a header and
+    ///   it fails validation, or belongs to a segment of a different format
+    pub fn with_format(handle: T, size_limit: u64, format: SegmentFormat) -> Result<Self> {
+        let mut s = SegmentDataPart {
+            handle,
+            size_limit,
+            format,
+        };
         // Attempt to write the header
         let written = s.write_header()?;
         if written {
@@ -196,12 +301,15 @@ impl<T: Read + Write + Seek> SegmentDataPart<T> {
             // Attempt to read the header
             let header = s.read_header()?;
             // Validate it
-            if header.validate() {
-                Ok(s)
-            } else {
-                Err(BackendError::SegmentError(
+            match header.format() {
+                Some(f) if f == format => Ok(s),
+                Some(f) => Err(BackendError::SegmentError(format!(
+                    "Segment is in the {:?} format, but was opened as {:?}",
+                    f, format
+                ))),
+                None => Err(BackendError::SegmentError(
                     "Segment failed header validation".to_string(),
-                ))
+                )),
             }
         }
     }
@@ -218,7 +326,7 @@ impl<T: Read + Write + Seek> SegmentDataPart<T> {
         let end = self.handle.seek(SeekFrom::End(0))?;
         if end == 0 {
             // If we are empty, then the handle is at the start of the file
-            let header = Header::default();
+            let header = Header::with_format(self.format);
             let mut config = bincode::config();
             config
                 .big_endian()
@@ -238,12 +346,7 @@ impl<T: Read + Write + Seek> SegmentDataPart<T> {
     /// - Will propagate any I/O errors that occur
     pub fn read_header(&mut self) -> Result<Header> {
         self.handle.seek(SeekFrom::Start(0))?;
-        let mut config = bincode::config();
-        let header: Header = config
-            .big_endian()
-            .deserialize_from(&mut self.handle)
-            .map_err(|_| BackendError::Unknown("Header deserialization failed".to_string()))?;
-        Ok(header)
+        read_segment_header(&mut self.handle)
     }
 
     /// Returns the current size of the segment file
@@ -262,34 +365,237 @@ impl<T: Read + Write + Seek> SegmentDataPart<T> {
         Ok(self.size_limit - len)
     }
 
+    /// Reads the chunk described by the given entry
+    ///
+    /// # Errors
+    ///
+    /// - Will return `Err(BackendError::SegmentError)` if the chunk is framed, and either the
+    ///   segment ends before the chunk does, or the chunk does not match its framing record
+    /// - Will propagate any other I/O errors that occur
     pub fn read_chunk(&mut self, header: SegmentHeaderEntry) -> Result<Chunk> {
-        let length: usize = (header.end_offset - header.start_offset)
+        let length = header.end_offset - header.start_offset;
+        let buffer_length: usize = length
             .try_into()
             .expect("Chunk size too big to fit in memory");
-        let mut buffer = vec![0_u8; length];
-        self.handle.seek(SeekFrom::Start(header.start_offset))?;
-        self.handle.read_exact(&mut buffer[..])?;
+        let mut buffer = vec![0_u8; buffer_length];
+        if header.framed {
+            self.handle
+                .seek(SeekFrom::Start(header.start_offset - FRAME_LENGTH))?;
+            let framed_length = self.handle.read_u64::<BigEndian>().map_err(torn)?;
+            let crc = self.handle.read_u32::<BigEndian>().map_err(torn)?;
+            if framed_length != length {
+                return Err(BackendError::SegmentError(format!(
+                    "Chunk at offset {} has length {} in its frame, but {} in the segment header",
+                    header.start_offset, framed_length, length
+                )));
+            }
+            self.handle.read_exact(&mut buffer[..]).map_err(torn)?;
+            if crc32fast::hash(&buffer[..]) != crc {
+                return Err(BackendError::SegmentError(format!(
+                    "Chunk at offset {} failed its CRC check",
+                    header.start_offset
+                )));
+            }
+        } else {
+            self.handle.seek(SeekFrom::Start(header.start_offset))?;
+            self.handle.read_exact(&mut buffer[..])?;
+        }
         let body = ChunkBody(buffer);
         Ok(Chunk::unsplit(header.header, body))
     }
 
+    /// Writes a chunk's body to the end of the segment, preceded by its framing record
+    ///
+    /// # Errors
+    ///
+    /// Will propagate any I/O errors that occur
     pub fn write_chunk(&mut self, chunk: Chunk) -> Result<SegmentHeaderEntry> {
-        let start_offset: u64 = self.handle.seek(SeekFrom::End(1))?;
-        let end_offset: u64 = start_offset + chunk.get_bytes().len() as u64;
+        let frame_offset: u64 = self.handle.seek(SeekFrom::End(1))?;
         let (header, body) = chunk.split();
+        let start_offset = frame_offset + FRAME_LENGTH;
+        let end_offset = start_offset + body.0.len() as u64;
+        self.handle.write_u64::<BigEndian>(body.0.len() as u64)?;
+        self.handle
+            .write_u32::<BigEndian>(crc32fast::hash(&body.0[..]))?;
         self.handle.write_all(&body.0[..])?;
         Ok(SegmentHeaderEntry {
             header,
             start_offset,
             end_offset,
+            framed: true,
         })
     }
 }
 
+impl<T: Read + Write + Seek> SegmentDataPart<T> {
+    /// Appends an interleaved record to the end of the segment, returning the offset it starts
+    /// at
+    ///
+    /// The whole record is assembled in memory and handed to the operating system in a single
+    /// write, see `SegmentFormat::Interleaved` for the layout.
+    ///
+    /// # Errors
+    ///
+    /// Will propagate any I/O errors that occur
+    pub fn write_record(&mut self, header: &[u8], body: &[u8]) -> Result<u64> {
+        let offset = self.handle.seek(SeekFrom::End(0))?;
+        let mut record = Vec::with_capacity(header.len() + body.len() + 20);
+        record.write_u64::<BigEndian>(header.len() as u64)?;
+        record.extend_from_slice(header);
+        record.write_u64::<BigEndian>(body.len() as u64)?;
+        record.extend_from_slice(body);
+        let mut crc = crc32fast::Hasher::new();
+        crc.update(header);
+        crc.update(body);
+        record.write_u32::<BigEndian>(crc.finalize())?;
+        self.handle.write_all(&record[..])?;
+        Ok(offset)
+    }
+
+    /// Reads the header and body of the interleaved record starting at `offset`
+    ///
+    /// # Errors
+    ///
+    /// - Will return `Err(BackendError::SegmentError)` if the segment ends before the record
+    ///   does, including its trailing CRC, or the record fails its CRC check
+    /// - Will propagate any other I/O errors that occur
+    pub fn read_record(&mut self, offset: u64) -> Result<(Vec<u8>, Vec<u8>)> {
+        let end = self.handle.seek(SeekFrom::End(0))?;
+        self.handle.seek(SeekFrom::Start(offset))?;
+        let header = read_field(&mut self.handle, end)?;
+        let body = read_field(&mut self.handle, end)?;
+        let crc = self.handle.read_u32::<BigEndian>().map_err(torn)?;
+        let mut hasher = crc32fast::Hasher::new();
+        hasher.update(&header[..]);
+        hasher.update(&body[..]);
+        if hasher.finalize() != crc {
+            return Err(BackendError::SegmentError(format!(
+                "Chunk at offset {} failed its CRC check",
+                offset
+            )));
+        }
+        Ok((header, body))
+    }
+
+    /// Reads the header of the interleaved record starting at `offset`, along with the length of
+    /// its body, without reading the body
+    ///
+    /// As the body is not read, the record's CRC is not checked.
+    ///
+    /// # Errors
+    ///
+    /// - Will return `Err(BackendError::SegmentError)` if the segment ends before the record does
+    /// - Will propagate any other I/O errors that occur
+    pub fn read_record_header(&mut self, offset: u64) -> Result<(Vec<u8>, u64)> {
+        let end = self.handle.seek(SeekFrom::End(0))?;
+        self.handle.seek(SeekFrom::Start(offset))?;
+        let header = read_field(&mut self.handle, end)?;
+        let body_length = self.handle.read_u64::<BigEndian>().map_err(torn)?;
+        let position = self.handle.seek(SeekFrom::Current(0))?;
+        // The body is followed by the 4 byte CRC
+        if position.saturating_add(body_length).saturating_add(4) > end {
+            return Err(torn(io::ErrorKind::UnexpectedEof.into()));
+        }
+        Ok((header, body_length))
+    }
+
+    /// Returns the offsets of every interleaved record in the segment, in order
+    ///
+    /// Only the lengths are read, so the last offset may belong to a record that was cut short,
+    /// which `read_record` will report.
+    ///
+    /// # Errors
+    ///
+    /// Will propagate any I/O errors that occur
+    pub fn record_offsets(&mut self) -> Result<Vec<u64>> {
+        let end = self.handle.seek(SeekFrom::End(0))?;
+        self.read_header()?;
+        let mut offset = self.handle.seek(SeekFrom::Current(0))?;
+        let mut offsets = Vec::new();
+        while offset < end {
+            offsets.push(offset);
+            let header_length = match self.handle.read_u64::<BigEndian>() {
+                Ok(length) => length,
+                Err(_) => break,
+            };
+            let body_offset = offset.saturating_add(8).saturating_add(header_length);
+            if body_offset >= end {
+                break;
+            }
+            self.handle.seek(SeekFrom::Start(body_offset))?;
+            let body_length = match self.handle.read_u64::<BigEndian>() {
+                Ok(length) => length,
+                Err(_) => break,
+            };
+            offset = body_offset
+                .saturating_add(8)
+                .saturating_add(body_length)
+                .saturating_add(4);
+            self.handle.seek(SeekFrom::Start(offset.min(end)))?;
+        }
+        Ok(offsets)
+    }
+
+    /// Returns the length of the segment up to the end of its last complete interleaved record
+    ///
+    /// This is the length of the whole segment, unless the last record was cut short, or fails
+    /// its CRC check, as happens when an append is interrupted.
+    ///
+    /// # Errors
+    ///
+    /// Will propagate any I/O errors that occur
+    pub fn complete_length(&mut self) -> Result<u64> {
+        let end = self.handle.seek(SeekFrom::End(0))?;
+        match self.record_offsets()?.last() {
+            Some(&offset) => match self.read_record(offset) {
+                Ok(_) => Ok(end),
+                Err(BackendError::SegmentError(_)) => Ok(offset),
+                Err(e) => Err(e),
+            },
+            None => Ok(end),
+        }
+    }
+}
+
+/// Reads a length prefixed field of an interleaved record, refusing to read past `end`
+fn read_field<R: Read + Seek>(handle: &mut R, end: u64) -> Result<Vec<u8>> {
+    let length = handle.read_u64::<BigEndian>().map_err(torn)?;
+    let position = handle.seek(SeekFrom::Current(0))?;
+    if position.saturating_add(length) > end {
+        return Err(torn(io::ErrorKind::UnexpectedEof.into()));
+    }
+    let length: usize = length
+        .try_into()
+        .expect("Chunk size too big to fit in memory");
+    let mut buffer = vec![0_u8; length];
+    handle.read_exact(&mut buffer[..]).map_err(torn)?;
+    Ok(buffer)
+}
+
+/// Converts running off the end of a segment into a `SegmentError`, as this is the signature of
+/// an interrupted write, and passes any other I/O errors through
+fn torn(error: io::Error) -> BackendError {
+    if error.kind() == io::ErrorKind::UnexpectedEof {
+        BackendError::SegmentError(
+            "Segment ends in the middle of a chunk, a write was likely interrupted".to_string(),
+        )
+    } else {
+        BackendError::IOError(error)
+    }
+}
+
+/// Where a `Segment` keeps its chunk headers
+enum SegmentHeaders<T: Read + Write + Seek> {
+    /// In a separate header part, see `SegmentFormat::Split`
+    Split(SegmentHeaderPart<T>),
+    /// Inline with the bodies, encrypted with these settings, see `SegmentFormat::Interleaved`
+    Interleaved { settings: ChunkSettings, key: Key },
+}
+
 /// Generic segment implementation wrapping any Read + Write + Seek
 pub struct Segment<T: Read + Write + Seek> {
     data_handle: SegmentDataPart<T>,
-    header_handle: SegmentHeaderPart<T>,
+    headers: SegmentHeaders<T>,
 }
 
 impl<T: Read + Write + Seek> Segment<T> {
@@ -305,10 +611,36 @@ impl<T: Read + Write + Seek> Segment<T> {
         let header_handle = SegmentHeaderPart::open(header_handle, key, chunk_settings)?;
         Ok(Segment {
             data_handle,
-            header_handle,
+            headers: SegmentHeaders::Split(header_handle),
         })
     }
 
+    /// Creates a new segment in the interleaved format, which needs no separate header handle,
+    /// given a reader and a maximum size
+    ///
+    /// Chunk headers are encrypted with the given settings and key before being written.
+    pub fn new_interleaved(
+        data_handle: T,
+        size_limit: u64,
+        chunk_settings: ChunkSettings,
+        key: Key,
+    ) -> Result<Segment<T>> {
+        let data_handle =
+            SegmentDataPart::with_format(data_handle, size_limit, SegmentFormat::Interleaved)?;
+        Ok(Segment {
+            data_handle,
+            headers: SegmentHeaders::Interleaved {
+                settings: chunk_settings,
+                key,
+            },
+        })
+    }
+
+    /// Returns the format of this segment
+    pub fn format(&self) -> SegmentFormat {
+        self.data_handle.format
+    }
+
     /// Returns the size in bytes of the segment
     pub fn size(&mut self) -> u64 {
         self.data_handle
@@ -323,36 +655,226 @@ impl<T: Read + Write + Seek> Segment<T> {
             .expect("Unable to read size from data handle. Please check file permissions.")
     }
 
-    /// Reads the chunk with the specified index from the segment
+    /// Reads the chunk at the specified location from the segment
+    ///
+    /// For split segments, the location is the chunk's index in the header part, for interleaved
+    /// segments it is the offset of the chunk's record.
     pub fn read_chunk(&mut self, index: u64) -> Result<Chunk> {
-        let index: usize = index
-            .try_into()
-            .expect("Index provided to read_chunk larger than could possibly fit into memory");
-        let entry = self.header_handle.get_header(index).ok_or_else(|| {
-            BackendError::SegmentError(format!("Invalid index {} provided to read_chunk", index))
-        })?;
-        self.data_handle.read_chunk(entry)
+        match &mut self.headers {
+            SegmentHeaders::Split(header_handle) => {
+                let index: usize = index.try_into().expect(
+                    "Index provided to read_chunk larger than could possibly fit into memory",
+                );
+                let entry = header_handle.get_header(index).ok_or_else(|| {
+                    BackendError::SegmentError(format!(
+                        "Invalid index {} provided to read_chunk",
+                        index
+                    ))
+                })?;
+                self.data_handle.read_chunk(entry)
+            }
+            SegmentHeaders::Interleaved { key, .. } => {
+                let (header, body) = self.data_handle.read_record(index)?;
+                let header: Chunk = cbor::de::from_slice(&header[..])?;
+                let header: ChunkHeader = cbor::de::from_slice(&header.unpack(key)?[..])?;
+                Ok(Chunk::unsplit(header, ChunkBody(body)))
+            }
+        }
+    }
+
+    /// Reads the header of the chunk at the specified location, along with the length of its
+    /// body, without reading the body
+    ///
+    /// See `read_chunk` for the meaning of the location.
+    pub fn read_chunk_header(&mut self, index: u64) -> Result<(ChunkHeader, u64)> {
+        match &mut self.headers {
+            SegmentHeaders::Split(header_handle) => {
+                let index: usize = index.try_into().expect(
+                    "Index provided to read_chunk_header larger than could possibly fit into \
+                     memory",
+                );
+                let entry = header_handle.get_header(index).ok_or_else(|| {
+                    BackendError::SegmentError(format!(
+                        "Invalid index {} provided to read_chunk_header",
+                        index
+                    ))
+                })?;
+                Ok((entry.header, entry.end_offset - entry.start_offset))
+            }
+            SegmentHeaders::Interleaved { key, .. } => {
+                let (header, length) = self.data_handle.read_record_header(index)?;
+                let header: Chunk = cbor::de::from_slice(&header[..])?;
+                let header: ChunkHeader = cbor::de::from_slice(&header.unpack(key)?[..])?;
+                Ok((header, length))
+            }
+        }
+    }
+
+    /// Returns the locations of every chunk stored in the segment, suitable for passing to
+    /// `read_chunk`
+    ///
+    /// # Errors
+    ///
+    /// Will propagate any I/O errors that occur while scanning an interleaved segment
+    pub fn chunk_locations(&mut self) -> Result<Vec<u64>> {
+        match &self.headers {
+            SegmentHeaders::Split(header_handle) => {
+                Ok((0..header_handle.chunk_count() as u64).collect())
+            }
+            SegmentHeaders::Interleaved { .. } => self.data_handle.record_offsets(),
+        }
+    }
+
+    /// Returns the number of chunks stored in the segment
+    ///
+    /// # Errors
+    ///
+    /// Will propagate any I/O errors that occur while scanning an interleaved segment
+    pub fn chunk_count(&mut self) -> Result<u64> {
+        match &self.headers {
+            SegmentHeaders::Split(header_handle) => Ok(header_handle.chunk_count() as u64),
+            SegmentHeaders::Interleaved { .. } => Ok(self.chunk_locations()?.len() as u64),
+        }
     }
 
     pub fn write_chunk(&mut self, chunk: Chunk) -> Result<u64> {
-        let entry = self.data_handle.write_chunk(chunk)?;
-        let index = self.header_handle.insert_header(entry);
-        Ok(index as u64)
+        match &mut self.headers {
+            SegmentHeaders::Split(header_handle) => {
+                let entry = self.data_handle.write_chunk(chunk)?;
+                let index = header_handle.insert_header(entry);
+                Ok(index as u64)
+            }
+            SegmentHeaders::Interleaved { settings, key } => {
+                let (header, body) = chunk.split();
+                let header = Chunk::pack(
+                    cbor::ser::to_vec(&header)?,
+                    settings.compression,
+                    settings.encryption,
+                    settings.hmac,
+                    key,
+                );
+                let header = cbor::ser::to_vec(&header)?;
+                self.data_handle.write_record(&header[..], &body.0[..])
+            }
+        }
     }
 
     pub fn read_header(&mut self) -> Result<Header> {
         self.data_handle.read_header()
     }
 
+    /// Provides the underlying handles, the data handle first, followed by the header handle of
+    /// split segments
+    ///
+    /// Used to swap in new handles after a connection drops. The segment seeks before every
+    /// access, so the positions of the new handles do not matter.
+    pub fn handles_mut(&mut self) -> Vec<&mut T> {
+        let mut handles = vec![&mut self.data_handle.handle];
+        if let SegmentHeaders::Split(header_handle) = &mut self.headers {
+            handles.push(&mut header_handle.handle);
+        }
+        handles
+    }
+
     pub fn flush(&mut self) -> Result<()> {
-        self.header_handle.flush()
+        match &mut self.headers {
+            SegmentHeaders::Split(header_handle) => header_handle.flush(),
+            SegmentHeaders::Interleaved { .. } => Ok(()),
+        }
+    }
+}
+
+impl<T: Read + Write + Seek + SyncData> Segment<T> {
+    /// Flushes the header, and then, if the `durability` calls for it, asks the operating system
+    /// to commit both the data and header files to durable storage
+    ///
+    /// # Errors
+    ///
+    /// Will propagate any I/O errors that occur
+    pub fn sync(&mut self, durability: Durability) -> Result<()> {
+        self.flush()?;
+        durability.sync(&mut self.data_handle.handle)?;
+        if let SegmentHeaders::Split(header_handle) = &mut self.headers {
+            durability.sync(&mut header_handle.handle)?;
+        }
+        Ok(())
+    }
+}
+
+impl<T: Read + Write + Seek + SetLen> Segment<T> {
+    /// Cuts off the last record of an interleaved segment if it was left incomplete by an
+    /// interrupted write, returning the number of bytes removed
+    ///
+    /// This must be done before appending to the segment, as records written after a torn one
+    /// could not be found. Split segments are left alone, as their header part never refers to a
+    /// partially written body.
+    ///
+    /// # Errors
+    ///
+    /// Will propagate any I/O errors that occur
+    pub fn truncate_torn_tail(&mut self) -> Result<u64> {
+        if let SegmentHeaders::Split(_) = self.headers {
+            return Ok(0);
+        }
+        let end = self.data_handle.size()?;
+        let complete = self.data_handle.complete_length()?;
+        if complete < end {
+            self.data_handle.handle.set_len(complete)?;
+        }
+        Ok(end - complete)
     }
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
+    use std::cell::Cell;
     use std::io::Cursor;
+    use std::rc::Rc;
+
+    /// An in memory file that counts the number of times it has been fsynced
+    struct CountingFile {
+        inner: Cursor<Vec<u8>>,
+        syncs: Rc<Cell<usize>>,
+    }
+
+    impl CountingFile {
+        fn new(syncs: &Rc<Cell<usize>>) -> CountingFile {
+            CountingFile {
+                inner: Cursor::new(Vec::new()),
+                syncs: syncs.clone(),
+            }
+        }
+    }
+
+    impl Read for CountingFile {
+        fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+            self.inner.read(buf)
+        }
+    }
+
+    impl Write for CountingFile {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.inner.write(buf)
+        }
+        fn flush(&mut self) -> io::Result<()> {
+            self.inner.flush()
+        }
+    }
+
+    impl Seek for CountingFile {
+        fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
+            self.inner.seek(pos)
+        }
+    }
+
+    impl SyncData for CountingFile {
+        fn sync_data(&mut self) -> io::Result<()> {
+            self.syncs.set(self.syncs.get() + 1);
+            Ok(())
+        }
+    }
+
     #[test]
     fn header_sanity() {
         let input = Header::new();
@@ -392,4 +914,235 @@ mod tests {
 
         assert!(segment.read_header().unwrap().validate())
     }
+
+    #[test]
+    fn truncated_chunk_errors() {
+        let key = Key::random(32);
+        let settings = ChunkSettings::lightweight();
+        let tempdir = tempfile::tempdir().unwrap();
+        let data_path = tempdir.path().join("0");
+        let header_path = tempdir.path().join("0.header");
+        let open = |path: &std::path::Path| {
+            std::fs::OpenOptions::new()
+                .read(true)
+                .write(true)
+                .create(true)
+                .open(path)
+                .unwrap()
+        };
+
+        let chunk = Chunk::pack(
+            (0..4096).map(|_| rand::random::<u8>()).collect(),
+            settings.compression,
+            settings.encryption,
+            settings.hmac,
+            &key,
+        );
+        let mut segment = Segment::new(
+            open(&data_path),
+            open(&header_path),
+            1_000_000,
+            settings,
+            key.clone(),
+        )
+        .unwrap();
+        let index = segment.write_chunk(chunk.clone()).unwrap();
+        segment.flush().unwrap();
+        assert_eq!(
+            segment.read_chunk(index).unwrap().get_bytes(),
+            chunk.get_bytes()
+        );
+        std::mem::drop(segment);
+
+        // Cut the segment off partway through the chunk's body
+        let data_file = open(&data_path);
+        let length = data_file.metadata().unwrap().len();
+        data_file.set_len(length - 16).unwrap();
+        std::mem::drop(data_file);
+
+        let mut segment = Segment::new(
+            open(&data_path),
+            open(&header_path),
+            1_000_000,
+            settings,
+            key,
+        )
+        .unwrap();
+        assert!(matches!(
+            segment.read_chunk(index),
+            Err(BackendError::SegmentError(_))
+        ));
+    }
+
+    // Chunks written to an interleaved segment must read back, and cutting the segment off after
+    // the last chunk's body, but before its CRC, must be detected
+    #[test]
+    fn interleaved_round_trip_and_truncation() {
+        let key = Key::random(32);
+        let settings = ChunkSettings::lightweight();
+        let tempdir = tempfile::tempdir().unwrap();
+        let data_path = tempdir.path().join("0");
+        let open = |path: &std::path::Path| {
+            std::fs::OpenOptions::new()
+                .read(true)
+                .write(true)
+                .create(true)
+                .open(path)
+                .unwrap()
+        };
+
+        let chunks: Vec<Chunk> = (0..3)
+            .map(|_| {
+                Chunk::pack(
+                    (0..4096).map(|_| rand::random::<u8>()).collect(),
+                    settings.compression,
+                    settings.encryption,
+                    settings.hmac,
+                    &key,
+                )
+            })
+            .collect();
+        let mut segment =
+            Segment::new_interleaved(open(&data_path), 1_000_000, settings, key.clone()).unwrap();
+        assert_eq!(segment.format(), SegmentFormat::Interleaved);
+        let locations: Vec<u64> = chunks
+            .iter()
+            .map(|chunk| segment.write_chunk(chunk.clone()).unwrap())
+            .collect();
+        segment.flush().unwrap();
+        for (location, chunk) in locations.iter().zip(chunks.iter()) {
+            let read = segment.read_chunk(*location).unwrap();
+            assert_eq!(read.get_bytes(), chunk.get_bytes());
+            assert_eq!(read.get_id(), chunk.get_id());
+        }
+        assert_eq!(segment.chunk_locations().unwrap(), locations);
+        for (location, chunk) in locations.iter().zip(chunks.iter()) {
+            let (header, length) = segment.read_chunk_header(*location).unwrap();
+            assert_eq!(header.id(), chunk.get_id());
+            assert_eq!(length, chunk.len() as u64);
+        }
+        std::mem::drop(segment);
+
+        // Reopening the segment must find the same chunks
+        let mut segment =
+            Segment::new_interleaved(open(&data_path), 1_000_000, settings, key.clone()).unwrap();
+        assert_eq!(segment.chunk_count().unwrap(), 3);
+        assert_eq!(
+            segment.read_chunk(locations[1]).unwrap().get_bytes(),
+            chunks[1].get_bytes()
+        );
+        std::mem::drop(segment);
+
+        // An interleaved segment must not be mistaken for a split one
+        assert!(matches!(
+            Segment::new(
+                open(&data_path),
+                open(&tempdir.path().join("0.header")),
+                1_000_000,
+                settings,
+                key.clone(),
+            ),
+            Err(BackendError::SegmentError(_))
+        ));
+
+        // Cut the segment off just after the last chunk's body, before its CRC
+        let data_file = open(&data_path);
+        let length = data_file.metadata().unwrap().len();
+        data_file.set_len(length - 4).unwrap();
+        std::mem::drop(data_file);
+
+        let mut segment =
+            Segment::new_interleaved(open(&data_path), 1_000_000, settings, key.clone()).unwrap();
+        assert_eq!(segment.chunk_locations().unwrap(), locations);
+        assert!(matches!(
+            segment.read_chunk(locations[2]),
+            Err(BackendError::SegmentError(_))
+        ));
+        // The chunks before it are unaffected
+        assert_eq!(
+            segment.read_chunk(locations[0]).unwrap().get_bytes(),
+            chunks[0].get_bytes()
+        );
+    }
+
+    // A record cut short by an interrupted write must be removed before anything is appended
+    // after it, so the appended chunks can still be found
+    #[test]
+    fn torn_tail_truncated_before_append() {
+        let key = Key::random(32);
+        let settings = ChunkSettings::lightweight();
+        let tempdir = tempfile::tempdir().unwrap();
+        let data_path = tempdir.path().join("0");
+        let open = |path: &std::path::Path| {
+            std::fs::OpenOptions::new()
+                .read(true)
+                .write(true)
+                .create(true)
+                .open(path)
+                .unwrap()
+        };
+        let chunk = |byte: u8| {
+            Chunk::pack(
+                vec![byte; 1024],
+                settings.compression,
+                settings.encryption,
+                settings.hmac,
+                &key,
+            )
+        };
+
+        let mut segment =
+            Segment::new_interleaved(open(&data_path), 1_000_000, settings, key.clone()).unwrap();
+        let first = segment.write_chunk(chunk(1)).unwrap();
+        segment.write_chunk(chunk(2)).unwrap();
+        // An intact segment is left alone
+        assert_eq!(segment.truncate_torn_tail().unwrap(), 0);
+        std::mem::drop(segment);
+
+        let data_file = open(&data_path);
+        let length = data_file.metadata().unwrap().len();
+        data_file.set_len(length - 100).unwrap();
+        std::mem::drop(data_file);
+
+        let mut segment =
+            Segment::new_interleaved(open(&data_path), 1_000_000, settings, key.clone()).unwrap();
+        assert!(segment.truncate_torn_tail().unwrap() > 0);
+        assert_eq!(segment.chunk_locations().unwrap(), vec![first]);
+        let third = segment.write_chunk(chunk(3)).unwrap();
+        assert_eq!(segment.chunk_locations().unwrap(), vec![first, third]);
+        assert_eq!(
+            segment.read_chunk(third).unwrap().unpack(&key).unwrap(),
+            vec![3_u8; 1024]
+        );
+    }
+
+    // Syncing with full durability must fsync both halves of the segment, while no durability
+    // must not fsync at all
+    #[test]
+    fn sync_respects_durability() {
+        let key = Key::random(32);
+        let settings = ChunkSettings::lightweight();
+        let syncs = Rc::new(Cell::new(0));
+        let mut segment = Segment::new(
+            CountingFile::new(&syncs),
+            CountingFile::new(&syncs),
+            1_000_000,
+            settings,
+            key.clone(),
+        )
+        .unwrap();
+        let chunk = Chunk::pack(
+            vec![0_u8; 1024],
+            settings.compression,
+            settings.encryption,
+            settings.hmac,
+            &key,
+        );
+        segment.write_chunk(chunk.clone()).unwrap();
+        segment.sync(Durability::None).unwrap();
+        assert_eq!(syncs.get(), 0);
+        segment.write_chunk(chunk).unwrap();
+        segment.sync(Durability::Full).unwrap();
+        assert_eq!(syncs.get(), 2);
+    }
 }
@@ -12,30 +12,59 @@
 //! Methods in this module are intentionally left undocumented, as they are indented to be syncronus
 //! versions of their async equivlants in the main Backend traits.
 use crate::manifest::StoredArchive;
+use crate::repository::audit::AuditHead;
+use crate::repository::backend::common::{paged_archive_stream, DanglingParent};
 use crate::repository::backend::{
-    backend_to_object, Backend, BackendObject, Index, Manifest, Result, SegmentDescriptor,
+    backend_to_object, Backend, BackendError, BackendObject, Index, Manifest, Result,
+    SegmentDescriptor,
 };
 use crate::repository::{Chunk, ChunkID, ChunkSettings, EncryptedKey};
+use crate::runtime;
+
+use asuran_core::repository::chunk::ChunkHeader;
 
 use async_trait::async_trait;
 use chrono::prelude::*;
 use futures::channel::mpsc;
 use futures::channel::oneshot;
 use futures::sink::SinkExt;
-use futures::stream::StreamExt;
+use futures::stream::{LocalBoxStream, StreamExt};
 
-use smol::block_on;
 use std::collections::HashSet;
-use std::thread;
 
 pub trait SyncManifest: std::fmt::Debug {
     type Iterator: Iterator<Item = StoredArchive> + std::fmt::Debug + Send + 'static;
     fn last_modification(&mut self) -> Result<DateTime<FixedOffset>>;
     fn chunk_settings(&mut self) -> ChunkSettings;
     fn archive_iterator(&mut self) -> Self::Iterator;
+    /// Returns up to `count` archives, starting `offset` archives into the order of
+    /// `archive_iterator`
+    fn archive_page(&mut self, offset: usize, count: usize) -> Result<Vec<StoredArchive>> {
+        Ok(self.archive_iterator().skip(offset).take(count).collect())
+    }
     fn write_chunk_settings(&mut self, settings: ChunkSettings) -> Result<()>;
     fn write_archive(&mut self, archive: StoredArchive) -> Result<()>;
     fn touch(&mut self) -> Result<()>;
+    fn touch_archive(&mut self, _archive: StoredArchive) -> Result<()> {
+        self.touch()
+    }
+    fn last_access(&mut self, _archive: StoredArchive) -> Result<Option<DateTime<FixedOffset>>> {
+        Ok(None)
+    }
+    fn delete_archive(&mut self, _archive: StoredArchive) -> Result<()> {
+        Err(BackendError::Unknown(
+            "This backend does not support removing archives".to_string(),
+        ))
+    }
+    fn write_audit_head(&mut self, _head: AuditHead) -> Result<()> {
+        Ok(())
+    }
+    fn audit_head(&mut self) -> Result<Option<AuditHead>> {
+        Ok(None)
+    }
+    fn dangling_parents(&mut self) -> Result<Vec<DanglingParent>> {
+        Ok(Vec::new())
+    }
 }
 
 pub trait SyncIndex: std::fmt::Debug {
@@ -60,7 +89,36 @@ pub trait SyncBackend: 'static + std::fmt::Debug {
     fn write_key(&mut self, key: EncryptedKey) -> Result<()>;
     fn read_key(&mut self) -> Result<EncryptedKey>;
     fn read_chunk(&mut self, location: SegmentDescriptor) -> Result<Chunk>;
+    fn read_chunk_header(&mut self, location: SegmentDescriptor) -> Result<(ChunkHeader, u64)> {
+        let (header, body) = self.read_chunk(location)?.split();
+        Ok((header, body.0.len() as u64))
+    }
     fn write_chunk(&mut self, chunk: Chunk) -> Result<SegmentDescriptor>;
+    fn prefetch(&mut self, _locations: &[SegmentDescriptor]) -> Result<()> {
+        Ok(())
+    }
+    fn remove_chunk(&mut self, _id: ChunkID) -> Result<()> {
+        Err(BackendError::Unknown(
+            "This backend does not support removing chunks".to_string(),
+        ))
+    }
+    /// See `Backend::sync`
+    fn sync(&mut self) -> Result<()>;
+    /// See `Backend::ping`
+    fn ping(&mut self) -> Result<()> {
+        Ok(())
+    }
+    /// Runs a fallible operation against this backend, such as a write to its index or manifest,
+    /// retrying it if the backend knows how to recover from the failure
+    ///
+    /// The default implementation runs the operation once. Backends that can lose their
+    /// connection override this to reconnect and try again.
+    fn retrying<T>(&mut self, mut operation: impl FnMut(&mut Self) -> Result<T>) -> Result<T>
+    where
+        Self: Sized,
+    {
+        operation(self)
+    }
 }
 
 enum SyncIndexCommand {
@@ -75,14 +133,32 @@ enum SyncManifestCommand<I> {
     LastMod(oneshot::Sender<Result<DateTime<FixedOffset>>>),
     ChunkSettings(oneshot::Sender<ChunkSettings>),
     ArchiveIterator(oneshot::Sender<I>),
+    ArchivePage(usize, usize, oneshot::Sender<Result<Vec<StoredArchive>>>),
     WriteChunkSettings(ChunkSettings, oneshot::Sender<Result<()>>),
     WriteArchive(StoredArchive, oneshot::Sender<Result<()>>),
     Touch(oneshot::Sender<Result<()>>),
+    TouchArchive(StoredArchive, oneshot::Sender<Result<()>>),
+    LastAccess(
+        StoredArchive,
+        oneshot::Sender<Result<Option<DateTime<FixedOffset>>>>,
+    ),
+    DeleteArchive(StoredArchive, oneshot::Sender<Result<()>>),
+    WriteAuditHead(AuditHead, oneshot::Sender<Result<()>>),
+    AuditHead(oneshot::Sender<Result<Option<AuditHead>>>),
+    DanglingParents(oneshot::Sender<Result<Vec<DanglingParent>>>),
 }
 
 enum SyncBackendCommand {
     ReadChunk(SegmentDescriptor, oneshot::Sender<Result<Chunk>>),
+    ReadChunkHeader(
+        SegmentDescriptor,
+        oneshot::Sender<Result<(ChunkHeader, u64)>>,
+    ),
     WriteChunk(Chunk, oneshot::Sender<Result<SegmentDescriptor>>),
+    Prefetch(Vec<SegmentDescriptor>),
+    RemoveChunk(ChunkID, oneshot::Sender<Result<()>>),
+    Sync(oneshot::Sender<Result<()>>),
+    Ping(oneshot::Sender<Result<()>>),
     ReadKey(oneshot::Sender<Result<EncryptedKey>>),
     WriteKey(EncryptedKey, oneshot::Sender<Result<()>>),
     Close(oneshot::Sender<()>),
@@ -109,7 +185,8 @@ where
 {
     /// Constructs a new `BackendHandle`
     ///
-    /// Spawns a new runner thread to handle commands on.
+    /// Spawns a new runner thread to handle commands on, or on wasm32, a task on the browser's
+    /// event loop.
     ///
     /// Takes a closure that produces the required `SyncBackend`, in order to allow
     /// injecting non-`Send` backends into the spawned threads.
@@ -118,61 +195,113 @@ where
     /// number of requests to hold in the processing queue at any given time.
     pub fn new(queue_depth: usize, backend: impl FnOnce() -> B + Send + 'static) -> Self {
         let (input, mut output) = mpsc::channel(queue_depth);
-        thread::spawn(move || {
+        runtime::spawn_loop(move || async move {
             let mut backend = backend();
             let mut final_ret: Option<oneshot::Sender<()>> = None;
-            while let Some(command) = block_on(output.next()) {
+            while let Some(command) = output.next().await {
                 match command {
-                    SyncCommand::Index(index_command) => {
-                        let index = backend.get_index();
-                        match index_command {
-                            SyncIndexCommand::Lookup(id, ret) => {
-                                ret.send(index.lookup_chunk(id)).unwrap();
-                            }
-                            SyncIndexCommand::Set(id, location, ret) => {
-                                ret.send(index.set_chunk(id, location)).unwrap();
-                            }
-                            SyncIndexCommand::KnownChunks(ret) => {
-                                ret.send(index.known_chunks()).unwrap();
-                            }
-                            SyncIndexCommand::Commit(ret) => {
-                                ret.send(index.commit_index()).unwrap();
-                            }
-                            SyncIndexCommand::Count(ret) => {
-                                ret.send(index.chunk_count()).unwrap();
-                            }
-                        };
-                    }
-                    SyncCommand::Manifest(manifest_command) => {
-                        let manifest = backend.get_manifest();
-                        match manifest_command {
-                            SyncManifestCommand::LastMod(ret) => {
-                                ret.send(manifest.last_modification()).unwrap();
-                            }
-                            SyncManifestCommand::ChunkSettings(ret) => {
-                                ret.send(manifest.chunk_settings()).unwrap();
-                            }
-                            SyncManifestCommand::ArchiveIterator(ret) => {
-                                ret.send(manifest.archive_iterator()).unwrap();
-                            }
-                            SyncManifestCommand::WriteChunkSettings(settings, ret) => {
-                                ret.send(manifest.write_chunk_settings(settings)).unwrap();
-                            }
-                            SyncManifestCommand::WriteArchive(archive, ret) => {
-                                ret.send(manifest.write_archive(archive)).unwrap();
-                            }
-                            SyncManifestCommand::Touch(ret) => {
-                                ret.send(manifest.touch()).unwrap();
-                            }
+                    SyncCommand::Index(index_command) => match index_command {
+                        SyncIndexCommand::Lookup(id, ret) => {
+                            ret.send(backend.get_index().lookup_chunk(id)).unwrap();
+                        }
+                        SyncIndexCommand::Set(id, location, ret) => {
+                            let result =
+                                backend.retrying(|b| b.get_index().set_chunk(id, location));
+                            ret.send(result).unwrap();
+                        }
+                        SyncIndexCommand::KnownChunks(ret) => {
+                            ret.send(backend.get_index().known_chunks()).unwrap();
+                        }
+                        SyncIndexCommand::Commit(ret) => {
+                            let result = backend.retrying(|b| b.get_index().commit_index());
+                            ret.send(result).unwrap();
                         }
-                    }
+                        SyncIndexCommand::Count(ret) => {
+                            ret.send(backend.get_index().chunk_count()).unwrap();
+                        }
+                    },
+                    SyncCommand::Manifest(manifest_command) => match manifest_command {
+                        SyncManifestCommand::LastMod(ret) => {
+                            let result = backend.retrying(|b| b.get_manifest().last_modification());
+                            ret.send(result).unwrap();
+                        }
+                        SyncManifestCommand::ChunkSettings(ret) => {
+                            ret.send(backend.get_manifest().chunk_settings()).unwrap();
+                        }
+                        SyncManifestCommand::ArchiveIterator(ret) => {
+                            ret.send(backend.get_manifest().archive_iterator()).unwrap();
+                        }
+                        SyncManifestCommand::ArchivePage(offset, count, ret) => {
+                            let result =
+                                backend.retrying(|b| b.get_manifest().archive_page(offset, count));
+                            ret.send(result).unwrap();
+                        }
+                        SyncManifestCommand::WriteChunkSettings(settings, ret) => {
+                            let result = backend
+                                .retrying(|b| b.get_manifest().write_chunk_settings(settings));
+                            ret.send(result).unwrap();
+                        }
+                        SyncManifestCommand::WriteArchive(archive, ret) => {
+                            let result = backend
+                                .retrying(|b| b.get_manifest().write_archive(archive.clone()));
+                            ret.send(result).unwrap();
+                        }
+                        SyncManifestCommand::Touch(ret) => {
+                            let result = backend.retrying(|b| b.get_manifest().touch());
+                            ret.send(result).unwrap();
+                        }
+                        SyncManifestCommand::TouchArchive(archive, ret) => {
+                            let result = backend
+                                .retrying(|b| b.get_manifest().touch_archive(archive.clone()));
+                            ret.send(result).unwrap();
+                        }
+                        SyncManifestCommand::LastAccess(archive, ret) => {
+                            ret.send(backend.get_manifest().last_access(archive))
+                                .unwrap();
+                        }
+                        SyncManifestCommand::DeleteArchive(archive, ret) => {
+                            let result = backend
+                                .retrying(|b| b.get_manifest().delete_archive(archive.clone()));
+                            ret.send(result).unwrap();
+                        }
+                        SyncManifestCommand::WriteAuditHead(head, ret) => {
+                            let result =
+                                backend.retrying(|b| b.get_manifest().write_audit_head(head));
+                            ret.send(result).unwrap();
+                        }
+                        SyncManifestCommand::AuditHead(ret) => {
+                            let result = backend.retrying(|b| b.get_manifest().audit_head());
+                            ret.send(result).unwrap();
+                        }
+                        SyncManifestCommand::DanglingParents(ret) => {
+                            let result = backend.retrying(|b| b.get_manifest().dangling_parents());
+                            ret.send(result).unwrap();
+                        }
+                    },
                     SyncCommand::Backend(backend_command) => match backend_command {
                         SyncBackendCommand::ReadChunk(location, ret) => {
                             ret.send(backend.read_chunk(location)).unwrap();
                         }
+                        SyncBackendCommand::ReadChunkHeader(location, ret) => {
+                            ret.send(backend.read_chunk_header(location)).unwrap();
+                        }
                         SyncBackendCommand::WriteChunk(chunk, ret) => {
                             ret.send(backend.write_chunk(chunk)).unwrap();
                         }
+                        SyncBackendCommand::Prefetch(locations) => {
+                            // Prefetching is advisory, so failures are left for the reads
+                            // themselves to report
+                            let _ = backend.prefetch(&locations);
+                        }
+                        SyncBackendCommand::RemoveChunk(id, ret) => {
+                            ret.send(backend.remove_chunk(id)).unwrap();
+                        }
+                        SyncBackendCommand::Sync(ret) => {
+                            ret.send(backend.sync()).unwrap();
+                        }
+                        SyncBackendCommand::Ping(ret) => {
+                            ret.send(backend.ping()).unwrap();
+                        }
                         SyncBackendCommand::WriteKey(key, ret) => {
                             ret.send(backend.write_key(key)).unwrap();
                         }
@@ -242,6 +371,24 @@ impl<B: SyncBackend> Manifest for BackendHandle<B> {
             .unwrap();
         o.await.unwrap()
     }
+    /// Requests the archives from the backend a page at a time, as they are consumed, rather than
+    /// all at once
+    fn archive_stream(&mut self) -> LocalBoxStream<'_, Result<StoredArchive>> {
+        let channel = self.channel.clone();
+        paged_archive_stream(move |offset, count| {
+            let mut channel = channel.clone();
+            async move {
+                let (i, o) = oneshot::channel();
+                channel
+                    .send(SyncCommand::Manifest(SyncManifestCommand::ArchivePage(
+                        offset, count, i,
+                    )))
+                    .await
+                    .unwrap();
+                o.await?
+            }
+        })
+    }
     async fn write_chunk_settings(&mut self, settings: ChunkSettings) -> Result<()> {
         let (i, o) = oneshot::channel();
         self.channel
@@ -270,17 +417,78 @@ impl<B: SyncBackend> Manifest for BackendHandle<B> {
             .unwrap();
         o.await?
     }
+    async fn touch_archive(&mut self, archive: StoredArchive) -> Result<()> {
+        let (i, o) = oneshot::channel();
+        self.channel
+            .send(SyncCommand::Manifest(SyncManifestCommand::TouchArchive(
+                archive, i,
+            )))
+            .await
+            .unwrap();
+        o.await?
+    }
+    async fn last_access(
+        &mut self,
+        archive: StoredArchive,
+    ) -> Result<Option<DateTime<FixedOffset>>> {
+        let (i, o) = oneshot::channel();
+        self.channel
+            .send(SyncCommand::Manifest(SyncManifestCommand::LastAccess(
+                archive, i,
+            )))
+            .await
+            .unwrap();
+        o.await?
+    }
+    async fn delete_archive(&mut self, archive: StoredArchive) -> Result<()> {
+        let (i, o) = oneshot::channel();
+        self.channel
+            .send(SyncCommand::Manifest(SyncManifestCommand::DeleteArchive(
+                archive, i,
+            )))
+            .await
+            .unwrap();
+        o.await?
+    }
+    async fn write_audit_head(&mut self, head: AuditHead) -> Result<()> {
+        let (i, o) = oneshot::channel();
+        self.channel
+            .send(SyncCommand::Manifest(SyncManifestCommand::WriteAuditHead(
+                head, i,
+            )))
+            .await
+            .unwrap();
+        o.await?
+    }
+    async fn audit_head(&mut self) -> Result<Option<AuditHead>> {
+        let (i, o) = oneshot::channel();
+        self.channel
+            .send(SyncCommand::Manifest(SyncManifestCommand::AuditHead(i)))
+            .await
+            .unwrap();
+        o.await?
+    }
+    async fn dangling_parents(&mut self) -> Result<Vec<DanglingParent>> {
+        let (i, o) = oneshot::channel();
+        self.channel
+            .send(SyncCommand::Manifest(SyncManifestCommand::DanglingParents(
+                i,
+            )))
+            .await
+            .unwrap();
+        o.await?
+    }
 }
 
 #[async_trait]
 impl<B: SyncBackend> Index for BackendHandle<B> {
-    async fn lookup_chunk(&mut self, id: ChunkID) -> Option<SegmentDescriptor> {
+    async fn lookup_chunk(&mut self, id: ChunkID) -> Result<Option<SegmentDescriptor>> {
         let (i, o) = oneshot::channel();
         self.channel
             .send(SyncCommand::Index(SyncIndexCommand::Lookup(id, i)))
             .await
             .unwrap();
-        o.await.unwrap()
+        Ok(o.await.unwrap())
     }
     async fn set_chunk(&mut self, id: ChunkID, location: SegmentDescriptor) -> Result<()> {
         let (i, o) = oneshot::channel();
@@ -365,6 +573,19 @@ impl<B: SyncBackend> Backend for BackendHandle<B> {
             .unwrap();
         o.await?
     }
+    async fn read_chunk_header(
+        &mut self,
+        location: SegmentDescriptor,
+    ) -> Result<(ChunkHeader, u64)> {
+        let (i, o) = oneshot::channel();
+        self.channel
+            .send(SyncCommand::Backend(SyncBackendCommand::ReadChunkHeader(
+                location, i,
+            )))
+            .await
+            .unwrap();
+        o.await?
+    }
     async fn write_chunk(&mut self, chunk: Chunk) -> Result<SegmentDescriptor> {
         let (i, o) = oneshot::channel();
         self.channel
@@ -375,6 +596,39 @@ impl<B: SyncBackend> Backend for BackendHandle<B> {
             .unwrap();
         o.await?
     }
+    async fn prefetch(&mut self, locations: Vec<SegmentDescriptor>) -> Result<()> {
+        self.channel
+            .send(SyncCommand::Backend(SyncBackendCommand::Prefetch(
+                locations,
+            )))
+            .await
+            .unwrap();
+        Ok(())
+    }
+    async fn remove_chunk(&mut self, id: ChunkID) -> Result<()> {
+        let (i, o) = oneshot::channel();
+        self.channel
+            .send(SyncCommand::Backend(SyncBackendCommand::RemoveChunk(id, i)))
+            .await
+            .unwrap();
+        o.await?
+    }
+    async fn sync(&mut self) -> Result<()> {
+        let (i, o) = oneshot::channel();
+        self.channel
+            .send(SyncCommand::Backend(SyncBackendCommand::Sync(i)))
+            .await
+            .unwrap();
+        o.await?
+    }
+    async fn ping(&mut self) -> Result<()> {
+        let (i, o) = oneshot::channel();
+        self.channel
+            .send(SyncCommand::Backend(SyncBackendCommand::Ping(i)))
+            .await
+            .unwrap();
+        o.await?
+    }
     async fn close(&mut self) {
         let (i, o) = oneshot::channel();
         self.channel
@@ -388,3 +642,52 @@ impl<B: SyncBackend> Backend for BackendHandle<B> {
         backend_to_object(self.clone())
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::repository::backend::mem::Mem;
+    use crate::repository::Key;
+
+    /// Fills the channel of a handle, with the backend held up so it can not drain any of it, and
+    /// returns the number of requests that fit
+    ///
+    /// Every accepted request is checked to still be answered once the backend is released.
+    fn queued_requests(queue_depth: usize) -> usize {
+        let (release, held) = std::sync::mpsc::channel::<()>();
+        let handle = BackendHandle::new(queue_depth, move || {
+            held.recv().unwrap();
+            Mem::new_raw(ChunkSettings::lightweight(), Key::random(32))
+        });
+        let mut sender = handle.channel.clone();
+        let mut replies = Vec::new();
+        loop {
+            let (i, o) = oneshot::channel();
+            if sender
+                .try_send(SyncCommand::Index(SyncIndexCommand::Count(i)))
+                .is_err()
+            {
+                break;
+            }
+            replies.push(o);
+        }
+        release.send(()).unwrap();
+        let queued = replies.len();
+        smol::block_on(async {
+            for reply in replies {
+                assert_eq!(reply.await.unwrap(), 0);
+            }
+        });
+        queued
+    }
+
+    /// Makes sure the channel of a handle holds the number of requests it was asked to
+    ///
+    /// Each sender is allowed one request past the capacity of the channel, which is the extra
+    /// request counted here.
+    #[test]
+    fn explicit_queue_depth() {
+        assert_eq!(queued_requests(3), 4);
+        assert_eq!(queued_requests(64), 65);
+    }
+}
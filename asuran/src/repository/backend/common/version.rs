@@ -0,0 +1,87 @@
+//! Checks the version of asuran that wrote to a repository against the running version
+//!
+//! Backends record the version of asuran writing to them, flatfiles in each entry header, and
+//! multifile repositories in each segment header. Data written by older versions is always
+//! readable, and writing to it only adds to the format, so it is accepted with a warning. A newer
+//! major version may have changed the format in ways this version does not understand, and
+//! writing to it could corrupt the repository, so it is refused.
+use crate::repository::backend::{BackendError, Result};
+
+use semver::Version;
+use tracing::warn;
+
+/// Checks that a repository last written to by asuran `written` can be opened by this version
+///
+/// # Errors
+///
+/// Will return `Err(IncompatibleVersion)` if `written` has a newer major version than this
+/// version of asuran
+pub fn check_version(written: &Version) -> Result<()> {
+    check_version_against(written, &crate::VERSION_STRUCT)
+}
+
+/// Checks that a repository last written to by asuran `written` can be opened by asuran `current`
+///
+/// Only the major, minor, and patch components are compared, as backends do not record
+/// pre-release tags.
+fn check_version_against(written: &Version, current: &Version) -> Result<()> {
+    if written.major > current.major {
+        return Err(BackendError::IncompatibleVersion {
+            written: written.clone(),
+            current: current.clone(),
+        });
+    }
+    if (written.major, written.minor, written.patch) < (current.major, current.minor, current.patch)
+    {
+        warn!(
+            "Repository was last written by asuran {}, an older version than this one ({})",
+            written, current
+        );
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn current() -> Version {
+        Version::new(3, 4, 5)
+    }
+
+    #[test]
+    fn older_versions_accepted() {
+        for written in &[
+            Version::new(3, 4, 4),
+            Version::new(3, 0, 9),
+            Version::new(2, 9, 9),
+            Version::new(0, 1, 0),
+        ] {
+            assert!(check_version_against(written, &current()).is_ok());
+        }
+    }
+
+    #[test]
+    fn same_and_newer_minor_versions_accepted() {
+        for written in &[
+            Version::new(3, 4, 5),
+            Version::new(3, 4, 6),
+            Version::new(3, 9, 0),
+        ] {
+            assert!(check_version_against(written, &current()).is_ok());
+        }
+    }
+
+    #[test]
+    fn newer_major_version_refused() {
+        assert!(matches!(
+            check_version_against(&Version::new(4, 0, 0), &current()),
+            Err(BackendError::IncompatibleVersion { written, current })
+                if written == Version::new(4, 0, 0) && current == Version::new(3, 4, 5)
+        ));
+        // The running version itself must always be accepted, pre-release tag or not
+        let running = &*crate::VERSION_STRUCT;
+        let stored = Version::new(running.major, running.minor, running.patch);
+        assert!(check_version(&stored).is_ok());
+    }
+}
@@ -4,20 +4,24 @@ use crate::repository::backend::common::sync_backend::{
     BackendHandle, SyncBackend, SyncIndex, SyncManifest,
 };
 use crate::repository::backend::{
-    Chunk, ChunkID, ChunkSettings, DateTime, EncryptedKey, FixedOffset, SegmentDescriptor,
-    StoredArchive,
+    Chunk, ChunkID, ChunkSettings, DateTime, Durability, EncryptedKey, FixedOffset,
+    SegmentDescriptor, StoredArchive,
 };
 use crate::repository::Key;
 
+use asuran_core::repository::chunk::ChunkHeader;
+
 use std::collections::HashSet;
 use std::fs::{File, OpenOptions};
+use std::io::Read;
 use std::path::Path;
 
-pub use super::common::generic_flatfile::GenericFlatFile;
+pub use super::common::generic_flatfile::{
+    GenericFlatFile, DEFAULT_MAX_CHUNK_LENGTH, MAGIC_NUMBER,
+};
 
-#[repr(transparent)]
 #[derive(Debug)]
-pub struct FlatFile(GenericFlatFile<File>);
+pub struct FlatFile(GenericFlatFile<File>, Durability);
 
 impl FlatFile {
     /// Constructs a flatfile and wraps it
@@ -30,6 +34,43 @@ impl FlatFile {
         key: Key,
         queue_depth: usize,
     ) -> Result<BackendHandle<FlatFile>> {
+        Self::new_with_durability(
+            repository_path,
+            settings,
+            enc_key,
+            key,
+            queue_depth,
+            Durability::default(),
+        )
+    }
+
+    /// Constructs a flatfile and wraps it, fsyncing the file on every index commit as specified by
+    /// `durability`
+    ///
+    /// See `Durability::None` for the risks of turning off fsyncing.
+    pub fn new_with_durability(
+        repository_path: impl AsRef<Path>,
+        settings: Option<ChunkSettings>,
+        enc_key: Option<EncryptedKey>,
+        key: Key,
+        queue_depth: usize,
+        durability: Durability,
+    ) -> Result<BackendHandle<FlatFile>> {
+        let flat_file = Self::open(repository_path, settings, enc_key, key, durability)?;
+        Ok(BackendHandle::new(queue_depth, move || flat_file))
+    }
+
+    /// Constructs a flatfile without wrapping it, so that it can be configured before use
+    ///
+    /// Pass the result to `BackendHandle::new` to use it as a backend. See the documentation for
+    /// `GenericFlatFile::new_raw` for further details.
+    pub fn open(
+        repository_path: impl AsRef<Path>,
+        settings: Option<ChunkSettings>,
+        enc_key: Option<EncryptedKey>,
+        key: Key,
+        durability: Durability,
+    ) -> Result<FlatFile> {
         let path = repository_path.as_ref().to_owned();
         let file = OpenOptions::new()
             .read(true)
@@ -37,7 +78,27 @@ impl FlatFile {
             .create(true)
             .open(&path)?;
         let flat_file = GenericFlatFile::new_raw(file, path, settings, key, enc_key)?;
-        Ok(BackendHandle::new(queue_depth, move || FlatFile(flat_file)))
+        Ok(FlatFile(flat_file, durability))
+    }
+
+    /// Sets the longest chunk body that will be read, see `GenericFlatFile::set_max_chunk_length`
+    ///
+    /// Repositories written with chunker settings producing chunks longer than
+    /// `DEFAULT_MAX_CHUNK_LENGTH` must raise this to be readable.
+    pub fn set_max_chunk_length(&mut self, max_chunk_length: u64) {
+        self.0.set_max_chunk_length(max_chunk_length);
+    }
+
+    /// Checks if there is a flatfile repository at the given path, without opening it or
+    /// requiring its key
+    ///
+    /// This only checks that the path is a file starting with the `FlatFile` magic number.
+    pub fn exists(repository_path: impl AsRef<Path>) -> bool {
+        let mut magic_number = [0_u8; 8];
+        File::open(repository_path)
+            .and_then(|mut file| file.read_exact(&mut magic_number))
+            .is_ok()
+            && magic_number == MAGIC_NUMBER
     }
 
     /// Attempts to read the key from the flatfile repo at a given path
@@ -81,7 +142,9 @@ impl SyncIndex for FlatFile {
         self.0.known_chunks()
     }
     fn commit_index(&mut self) -> Result<()> {
-        self.0.commit_index()
+        self.0.commit_index()?;
+        self.1.sync(self.0.file_mut())?;
+        Ok(())
     }
     fn chunk_count(&mut self) -> usize {
         self.0.chunk_count()
@@ -106,18 +169,41 @@ impl SyncBackend for FlatFile {
     fn read_chunk(&mut self, location: SegmentDescriptor) -> Result<Chunk> {
         self.0.read_chunk(location)
     }
+    fn read_chunk_header(&mut self, location: SegmentDescriptor) -> Result<(ChunkHeader, u64)> {
+        self.0.read_chunk_header(location)
+    }
     fn write_chunk(&mut self, chunk: Chunk) -> Result<SegmentDescriptor> {
         self.0.write_chunk(chunk)
     }
+    fn remove_chunk(&mut self, id: ChunkID) -> Result<()> {
+        self.0.remove_chunk(id)
+    }
+    /// Chunks are written to the same file as the index, so committing the index, which fsyncs the
+    /// file, covers them as well
+    fn sync(&mut self) -> Result<()> {
+        self.commit_index()
+    }
+    fn ping(&mut self) -> Result<()> {
+        // Make sure the file has not been removed out from under us
+        std::fs::metadata(self.0.path())?;
+        self.0.ping()
+    }
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
-    use crate::repository::backend::Backend;
+    use crate::repository::backend::common::generic_flatfile::FORMAT_VERSION;
+    use crate::repository::backend::{Backend, BackendError};
+    use crate::repository::Repository;
     use crate::repository::{Encryption, Key};
+    use asuran_core::repository::backend::flatfile::{
+        FlatFileError, FlatFileHeader, ENTRY_HEADER_LENGTH,
+    };
     use tempfile::tempdir;
 
+    use std::io::{Seek, SeekFrom, Write};
+
     fn setup() -> (Key, EncryptedKey, ChunkSettings) {
         let key = Key::random(32);
         let pass = b"A Very strong password";
@@ -150,4 +236,249 @@ mod tests {
             assert_eq!(key, new_key);
         });
     }
+
+    // A limit set before wrapping the flatfile must apply to the reads made through the handle
+    #[test]
+    fn max_chunk_length_applies() {
+        smol::run(async {
+            let (key, enc_key, settings) = setup();
+            let directory = tempdir().unwrap();
+            let file = directory.path().join("temp.asuran");
+            let mut flatfile =
+                FlatFile::new(&file, Some(settings), Some(enc_key), key.clone(), 4).unwrap();
+            let chunk = Chunk::pack(
+                vec![1_u8; 1024],
+                settings.compression,
+                settings.encryption,
+                settings.hmac,
+                &key,
+            );
+            let location = flatfile.write_chunk(chunk.clone()).await.unwrap();
+            flatfile.close().await;
+
+            let raw =
+                FlatFile::open(&file, None, None, key.clone(), Durability::default()).unwrap();
+            let mut flatfile = BackendHandle::new(4, move || raw);
+            assert_eq!(flatfile.read_chunk(location).await.unwrap(), chunk);
+            flatfile.close().await;
+
+            let mut raw = FlatFile::open(&file, None, None, key, Durability::default()).unwrap();
+            raw.set_max_chunk_length(16);
+            let mut flatfile = BackendHandle::new(4, move || raw);
+            assert!(flatfile.read_chunk(location).await.is_err());
+            flatfile.close().await;
+        });
+    }
+
+    #[test]
+    fn exists_after_creation() {
+        smol::run(async {
+            let (key, enc_key, settings) = setup();
+            let directory = tempdir().unwrap();
+            let file = directory.path().join("temp.asuran");
+            assert!(!FlatFile::exists(&file));
+            assert!(!FlatFile::exists(directory.path()));
+            std::fs::write(&file, b"Not a repository").unwrap();
+            assert!(!FlatFile::exists(&file));
+            std::fs::remove_file(&file).unwrap();
+
+            let mut flatfile = FlatFile::new(&file, Some(settings), Some(enc_key), key, 4).unwrap();
+            flatfile.close().await;
+            assert!(FlatFile::exists(&file));
+        });
+    }
+
+    // A freshly created flatfile should be written with, and reopen at, the current format version
+    #[test]
+    fn current_version_opens() {
+        smol::run(async {
+            let (key, enc_key, settings) = setup();
+            let directory = tempdir().unwrap();
+            let file = directory.path().join("temp.asuran");
+            let mut flatfile =
+                FlatFile::new(&file, Some(settings), Some(enc_key), key.clone(), 4).unwrap();
+            flatfile.close().await;
+
+            let header = FlatFileHeader::from_read(File::open(&file).unwrap()).unwrap();
+            assert_eq!(header.format_version, FORMAT_VERSION);
+            let mut flatfile = FlatFile::new(&file, None, None, key, 4).unwrap();
+            flatfile.close().await;
+        });
+    }
+
+    // A flatfile that was cut off part way through its last footer should reopen with the
+    // previously committed entries intact, and accept further writes
+    #[test]
+    fn truncated_footer_resumes() {
+        smol::run(async {
+            let (key, enc_key, settings) = setup();
+            let directory = tempdir().unwrap();
+            let file = directory.path().join("temp.asuran");
+            let flatfile =
+                FlatFile::new(&file, Some(settings), Some(enc_key), key.clone(), 4).unwrap();
+            let mut repo = Repository::with(flatfile, settings, key.clone(), 2).unwrap();
+            let (committed, _) = repo.write_chunk(vec![1_u8; 1024]).await.unwrap();
+            repo.commit_index().await.unwrap();
+            let (lost, _) = repo.write_chunk(vec![2_u8; 1024]).await.unwrap();
+            repo.commit_index().await.unwrap();
+            repo.close().await;
+
+            // The last entry's footer immediately precedes the terminating header
+            let length = std::fs::metadata(&file).unwrap().len();
+            let handle = OpenOptions::new().write(true).open(&file).unwrap();
+            handle.set_len(length - ENTRY_HEADER_LENGTH - 16).unwrap();
+            std::mem::drop(handle);
+
+            let flatfile = FlatFile::new(&file, None, None, key.clone(), 4).unwrap();
+            let mut repo = Repository::with(flatfile, settings, key.clone(), 2).unwrap();
+            assert_eq!(repo.read_chunk(committed).await.unwrap(), vec![1_u8; 1024]);
+            assert!(!repo.has_chunk(lost).await.unwrap());
+            let (added, _) = repo.write_chunk(vec![3_u8; 1024]).await.unwrap();
+            repo.commit_index().await.unwrap();
+            repo.close().await;
+
+            let flatfile = FlatFile::new(&file, None, None, key.clone(), 4).unwrap();
+            let mut repo = Repository::with(flatfile, settings, key, 2).unwrap();
+            assert_eq!(repo.read_chunk(committed).await.unwrap(), vec![1_u8; 1024]);
+            assert_eq!(repo.read_chunk(added).await.unwrap(), vec![3_u8; 1024]);
+            repo.close().await;
+        });
+    }
+
+    // A flatfile claiming a newer format version than we support must be refused
+    #[test]
+    fn too_new_version_rejected() {
+        smol::run(async {
+            let (key, enc_key, settings) = setup();
+            let directory = tempdir().unwrap();
+            let file = directory.path().join("temp.asuran");
+            let mut flatfile =
+                FlatFile::new(&file, Some(settings), Some(enc_key), key.clone(), 4).unwrap();
+            flatfile.close().await;
+
+            // Bump the version, which immediately follows the magic number and zero marker
+            let mut handle = OpenOptions::new().write(true).open(&file).unwrap();
+            handle.seek(SeekFrom::Start(10)).unwrap();
+            handle
+                .write_all(&(FORMAT_VERSION + 1).to_be_bytes())
+                .unwrap();
+            std::mem::drop(handle);
+
+            assert!(matches!(
+                FlatFile::new(&file, None, None, key, 4),
+                Err(BackendError::UnsupportedFormatVersion(v)) if v == FORMAT_VERSION + 1
+            ));
+            assert!(matches!(
+                FlatFile::load_encrypted_key(&file),
+                Err(BackendError::UnsupportedFormatVersion(_))
+            ));
+        });
+    }
+
+    /// Creates a flatfile with a single committed entry, returning the offset of its first entry
+    /// header
+    async fn single_entry_file(path: &Path, key: Key) -> u64 {
+        let (_, enc_key, settings) = setup();
+        let flatfile = FlatFile::new(path, Some(settings), Some(enc_key), key.clone(), 4).unwrap();
+        let mut repo = Repository::with(flatfile, settings, key, 2).unwrap();
+        repo.write_chunk(vec![1_u8; 1024]).await.unwrap();
+        repo.commit_index().await.unwrap();
+        repo.close().await;
+        let mut handle = File::open(path).unwrap();
+        FlatFileHeader::from_read(&mut handle).unwrap();
+        handle.seek(SeekFrom::Current(0)).unwrap()
+    }
+
+    /// Overwrites the asuran version recorded in the entry header at `header_offset`
+    fn set_version(path: &Path, header_offset: u64, version: [u16; 3]) {
+        let mut handle = OpenOptions::new().write(true).open(path).unwrap();
+        handle.seek(SeekFrom::Start(header_offset)).unwrap();
+        for component in &version {
+            handle.write_all(&component.to_be_bytes()).unwrap();
+        }
+    }
+
+    /// Overwrites the footer and next header offsets of the entry header at `header_offset`
+    fn set_offsets(path: &Path, header_offset: u64, footer_offset: u64, next_header_offset: u64) {
+        let mut handle = OpenOptions::new().write(true).open(path).unwrap();
+        // The offsets follow the three u16 semver components
+        handle.seek(SeekFrom::Start(header_offset + 6)).unwrap();
+        handle.write_all(&footer_offset.to_be_bytes()).unwrap();
+        handle.write_all(&next_header_offset.to_be_bytes()).unwrap();
+    }
+
+    // A footer offset beyond the end of the file must be reported, not followed
+    #[test]
+    fn footer_past_eof_rejected() {
+        smol::run(async {
+            let (key, _, _) = setup();
+            let directory = tempdir().unwrap();
+            let file = directory.path().join("temp.asuran");
+            let header_offset = single_entry_file(&file, key.clone()).await;
+            let length = std::fs::metadata(&file).unwrap().len();
+            let next_header_offset = length - ENTRY_HEADER_LENGTH;
+            set_offsets(&file, header_offset, length + 1024, next_header_offset);
+
+            assert!(matches!(
+                FlatFile::new(&file, None, None, key, 4),
+                Err(BackendError::FlatFile(FlatFileError::InvalidOffset(_)))
+            ));
+        });
+    }
+
+    // A header pointing back at itself would otherwise be walked forever
+    #[test]
+    fn self_referential_header_rejected() {
+        smol::run(async {
+            let (key, _, _) = setup();
+            let directory = tempdir().unwrap();
+            let file = directory.path().join("temp.asuran");
+            let header_offset = single_entry_file(&file, key.clone()).await;
+            set_offsets(
+                &file,
+                header_offset,
+                header_offset + ENTRY_HEADER_LENGTH,
+                header_offset,
+            );
+
+            assert!(matches!(
+                FlatFile::new(&file, None, None, key, 4),
+                Err(BackendError::FlatFile(FlatFileError::InvalidOffset(_)))
+            ));
+        });
+    }
+
+    // Entries written by older versions, or by this one, must open as normal
+    #[test]
+    fn older_and_same_versions_accepted() {
+        smol::run(async {
+            let (key, _, _) = setup();
+            for version in &[[0, 0, 1], *crate::VERSION_PIECES] {
+                let directory = tempdir().unwrap();
+                let file = directory.path().join("temp.asuran");
+                let header_offset = single_entry_file(&file, key.clone()).await;
+                set_version(&file, header_offset, *version);
+                assert!(FlatFile::new(&file, None, None, key.clone(), 4).is_ok());
+            }
+        });
+    }
+
+    // Entries written by a newer major version must be refused, rather than written to
+    #[test]
+    fn newer_major_version_rejected() {
+        smol::run(async {
+            let (key, _, _) = setup();
+            let directory = tempdir().unwrap();
+            let file = directory.path().join("temp.asuran");
+            let header_offset = single_entry_file(&file, key.clone()).await;
+            let major = crate::VERSION_PIECES[0] + 1;
+            set_version(&file, header_offset, [major, 0, 0]);
+
+            assert!(matches!(
+                FlatFile::new(&file, None, None, key, 4),
+                Err(BackendError::IncompatibleVersion { written, .. })
+                    if written.major == u64::from(major)
+            ));
+        });
+    }
 }
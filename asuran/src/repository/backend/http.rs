@@ -0,0 +1,458 @@
+//! Provides read-only access to a `MultiFile` style repository published over plain HTTP(S), such
+//! as one served by a static web server or CDN.
+//!
+//! Static web servers generally do not provide directory listings, so the numbered index and
+//! manifest files are discovered by requesting them in order until the server responds with `404
+//! Not Found`. Segments are read with the same reader as the other backends, so every segment
+//! format is supported, with the reader's accesses turned into ranged `GET` requests.
+//!
+//! All methods that would modify the repository return `BackendError::ReadOnly`.
+use super::{BackendError, Result, SegmentDescriptor};
+use crate::repository::backend::common::sync_backend::{BackendHandle, SyncBackend, SyncManifest};
+use crate::repository::{Chunk, EncryptedKey, Key};
+
+use asuran_core::repository::chunk::ChunkHeader;
+use reqwest::header::{CONTENT_LENGTH, RANGE};
+use reqwest::{Client, StatusCode};
+use serde_cbor as cbor;
+use tokio::runtime::Runtime;
+
+use std::convert::TryInto;
+use std::fmt::Debug;
+use std::rc::Rc;
+
+pub mod index;
+pub mod manifest;
+pub mod segment;
+
+use self::index::HttpIndex;
+use self::manifest::HttpManifest;
+use self::segment::HttpSegmentHandler;
+
+/// Settings used for connecting to a repository published over HTTP(S)
+#[derive(Clone, Debug)]
+pub struct HttpSettings {
+    /// URL of the root of the repository, e.g. `https://backups.example.com/repo/`
+    pub url: String,
+}
+
+/// A connection to a web server hosting a repository
+///
+/// All requests are made relative to the repository URL, and are driven to completion on a
+/// private single threaded runtime, as the `SyncBackend` traits are blocking.
+#[derive(Clone)]
+pub struct HttpConnection {
+    settings: HttpSettings,
+    client: Client,
+    runtime: Rc<Runtime>,
+}
+
+impl HttpConnection {
+    /// Builds a client for the server described by the given settings
+    ///
+    /// This does not make any requests, so it will not fail if the server is unreachable.
+    pub fn connect(settings: HttpSettings) -> Result<Self> {
+        let runtime = tokio::runtime::Builder::new_current_thread()
+            .enable_all()
+            .build()?;
+        let client = Client::builder().build()?;
+        Ok(HttpConnection {
+            settings,
+            client,
+            runtime: Rc::new(runtime),
+        })
+    }
+
+    /// Provides a reference to the settings of this connection
+    pub fn settings(&self) -> &HttpSettings {
+        &self.settings
+    }
+
+    /// Converts a path relative to the repository into a full URL
+    fn resolve(&self, path: &str) -> String {
+        format!(
+            "{}/{}",
+            self.settings.url.trim_end_matches('/'),
+            path.trim_start_matches('/')
+        )
+    }
+
+    /// Downloads the resource at the given path
+    ///
+    /// Returns `Ok(None)` if the server responds with `404 Not Found`.
+    pub fn get(&self, path: &str) -> Result<Option<Vec<u8>>> {
+        let url = self.resolve(path);
+        self.runtime.block_on(async {
+            let response = self.client.get(&url).send().await?;
+            if response.status() == StatusCode::NOT_FOUND {
+                return Ok(None);
+            }
+            if !response.status().is_success() {
+                return Err(BackendError::ConnectionError(format!(
+                    "Failed to download {}, server responded with {}",
+                    url,
+                    response.status()
+                )));
+            }
+            Ok(Some(response.bytes().await?.to_vec()))
+        })
+    }
+
+    /// Makes a `HEAD` request for the resource at the given path, returning an error unless the
+    /// server reports that it exists
+    pub fn head(&self, path: &str) -> Result<()> {
+        let url = self.resolve(path);
+        self.runtime.block_on(async {
+            let response = self.client.head(&url).send().await?;
+            if response.status().is_success() {
+                Ok(())
+            } else {
+                Err(BackendError::ConnectionError(format!(
+                    "Failed to reach {}, server responded with {}",
+                    url,
+                    response.status()
+                )))
+            }
+        })
+    }
+
+    /// Makes a `HEAD` request for the resource at the given path, returning its length
+    ///
+    /// Returns `Ok(None)` if the server responds with `404 Not Found`.
+    pub fn content_length(&self, path: &str) -> Result<Option<u64>> {
+        let url = self.resolve(path);
+        self.runtime.block_on(async {
+            let response = self.client.head(&url).send().await?;
+            if response.status() == StatusCode::NOT_FOUND {
+                return Ok(None);
+            }
+            if !response.status().is_success() {
+                return Err(BackendError::ConnectionError(format!(
+                    "Failed to reach {}, server responded with {}",
+                    url,
+                    response.status()
+                )));
+            }
+            response
+                .headers()
+                .get(CONTENT_LENGTH)
+                .and_then(|x| x.to_str().ok())
+                .and_then(|x| x.parse::<u64>().ok())
+                .map(Some)
+                .ok_or_else(|| {
+                    BackendError::ConnectionError(format!(
+                        "Server did not report the length of {}",
+                        url
+                    ))
+                })
+        })
+    }
+
+    /// Downloads the bytes in the range `start..end` of the resource at the given path
+    ///
+    /// If the server ignores the range and sends the entire resource, the requested range is
+    /// sliced out of it.
+    pub fn get_range(&self, path: &str, start: u64, end: u64) -> Result<Vec<u8>> {
+        let url = self.resolve(path);
+        self.runtime.block_on(async {
+            let response = self
+                .client
+                .get(&url)
+                .header(RANGE, format!("bytes={}-{}", start, end - 1))
+                .send()
+                .await?;
+            let status = response.status();
+            if !status.is_success() {
+                return Err(BackendError::ConnectionError(format!(
+                    "Failed to download {}, server responded with {}",
+                    url, status
+                )));
+            }
+            let bytes = response.bytes().await?;
+            if status == StatusCode::PARTIAL_CONTENT {
+                Ok(bytes.to_vec())
+            } else {
+                let start: usize = start.try_into().expect("Range too big to fit in memory");
+                let end: usize = end.try_into().expect("Range too big to fit in memory");
+                bytes.get(start..end).map(<[u8]>::to_vec).ok_or_else(|| {
+                    BackendError::ConnectionError(format!(
+                        "{} was too short to contain the range {}..{}",
+                        url, start, end
+                    ))
+                })
+            }
+        })
+    }
+}
+
+impl Debug for HttpConnection {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("HttpConnection")
+            .field("url", &self.settings.url)
+            .finish()
+    }
+}
+
+#[derive(Debug)]
+pub struct Http {
+    manifest: HttpManifest,
+    index: HttpIndex,
+    segment_handler: HttpSegmentHandler,
+    connection: HttpConnection,
+}
+
+impl Http {
+    pub fn connect_raw(settings: HttpSettings, key: &Key) -> Result<Self> {
+        let connection = HttpConnection::connect(settings)?;
+        let mut manifest = HttpManifest::connect(connection.clone(), key)?;
+        let index = HttpIndex::connect(&connection)?;
+        let chunk_settings = manifest.chunk_settings();
+        // This must match the layout used by the `MultiFile` backend
+        let segments_per_directory = 100;
+        let segment_handler = HttpSegmentHandler::connect(
+            connection.clone(),
+            segments_per_directory,
+            chunk_settings,
+            key.clone(),
+        );
+
+        Ok(Http {
+            manifest,
+            index,
+            segment_handler,
+            connection,
+        })
+    }
+
+    pub fn connect(
+        settings: HttpSettings,
+        key: Key,
+        queue_depth: usize,
+    ) -> Result<BackendHandle<Http>> {
+        use crossbeam_channel::bounded;
+        let (s, r) = bounded(1);
+        let handle = BackendHandle::new(queue_depth, move || {
+            let result = Self::connect_raw(settings, &key);
+            match result {
+                Ok(backend) => {
+                    s.send(None).unwrap();
+                    backend
+                }
+                Err(e) => {
+                    s.send(Some(e)).unwrap();
+                    panic!("Opening an HTTP Backend Handle Failed")
+                }
+            }
+        });
+        let error = r
+            .recv()
+            .expect("Backend Handle thread died before it could send us its result");
+
+        if let Some(error) = error {
+            Err(error)
+        } else {
+            Ok(handle)
+        }
+    }
+
+    pub fn read_key(settings: HttpSettings) -> Result<EncryptedKey> {
+        let connection = HttpConnection::connect(settings)?;
+        let bytes = connection.get("key")?.ok_or_else(|| {
+            BackendError::ConnectionError("Failed to read key file, it does not exist".to_string())
+        })?;
+        Ok(cbor::de::from_slice(&bytes[..])?)
+    }
+}
+
+impl SyncBackend for Http {
+    type SyncManifest = HttpManifest;
+    type SyncIndex = HttpIndex;
+    fn get_index(&mut self) -> &mut Self::SyncIndex {
+        &mut self.index
+    }
+    fn get_manifest(&mut self) -> &mut Self::SyncManifest {
+        &mut self.manifest
+    }
+    fn write_key(&mut self, _key: EncryptedKey) -> Result<()> {
+        Err(BackendError::ReadOnly)
+    }
+    fn read_key(&mut self) -> Result<EncryptedKey> {
+        let bytes = self
+            .connection
+            .get("key")?
+            .ok_or(BackendError::DataNotFound)?;
+        Ok(cbor::de::from_slice(&bytes[..])?)
+    }
+    fn read_chunk(&mut self, location: SegmentDescriptor) -> Result<Chunk> {
+        self.segment_handler.read_chunk(location)
+    }
+    fn read_chunk_header(&mut self, location: SegmentDescriptor) -> Result<(ChunkHeader, u64)> {
+        self.segment_handler.read_chunk_header(location)
+    }
+    fn write_chunk(&mut self, _chunk: Chunk) -> Result<SegmentDescriptor> {
+        Err(BackendError::ReadOnly)
+    }
+    fn prefetch(&mut self, locations: &[SegmentDescriptor]) -> Result<()> {
+        self.segment_handler.prefetch(locations)
+    }
+    /// Repositories served over HTTP are read only, so there is never anything to sync
+    fn sync(&mut self) -> Result<()> {
+        Ok(())
+    }
+    /// Checks that the key file, which every repository has, is still being served
+    fn ping(&mut self) -> Result<()> {
+        self.connection.head("key")
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::chunker::FastCDC;
+    use crate::manifest::{ActiveArchive, Manifest};
+    use crate::repository::backend::common::segment::SegmentFormat;
+    use crate::repository::backend::multifile::MultiFile;
+    use crate::repository::{ChunkSettings, Compression, Encryption, Repository, HMAC};
+
+    use hyper::header::RANGE;
+    use hyper::service::{make_service_fn, service_fn};
+    use hyper::{Body, Request, Response, Server, StatusCode};
+    use rand::prelude::*;
+    use tempfile::tempdir;
+
+    use std::convert::Infallible;
+    use std::io::Cursor;
+    use std::path::PathBuf;
+
+    /// Serves a single file out of `root`, honoring `bytes=start-end` ranges
+    async fn serve_file(
+        root: PathBuf,
+        request: Request<Body>,
+    ) -> std::result::Result<Response<Body>, Infallible> {
+        let path = root.join(request.uri().path().trim_start_matches('/'));
+        let data = match std::fs::read(&path) {
+            Ok(data) => data,
+            Err(_) => {
+                return Ok(Response::builder()
+                    .status(StatusCode::NOT_FOUND)
+                    .body(Body::empty())
+                    .unwrap())
+            }
+        };
+        let range = request
+            .headers()
+            .get(RANGE)
+            .and_then(|x| x.to_str().ok())
+            .and_then(|x| x.strip_prefix("bytes="))
+            .and_then(|x| {
+                let mut parts = x.split('-');
+                let start = parts.next()?.parse::<usize>().ok()?;
+                let end = parts.next()?.parse::<usize>().ok()?;
+                Some((start, end))
+            });
+        let response = match range {
+            Some((start, end)) => Response::builder()
+                .status(StatusCode::PARTIAL_CONTENT)
+                .body(Body::from(data[start..=end].to_vec())),
+            None => Response::builder().body(Body::from(data)),
+        };
+        Ok(response.unwrap())
+    }
+
+    /// Starts a static file server for `root` on its own thread, returning its URL
+    fn serve(root: PathBuf) -> String {
+        let (sender, receiver) = std::sync::mpsc::channel();
+        std::thread::spawn(move || {
+            let runtime = tokio::runtime::Builder::new_current_thread()
+                .enable_all()
+                .build()
+                .unwrap();
+            runtime.block_on(async move {
+                let make_service = make_service_fn(move |_| {
+                    let root = root.clone();
+                    async move {
+                        Ok::<_, Infallible>(service_fn(move |request| {
+                            serve_file(root.clone(), request)
+                        }))
+                    }
+                });
+                let server = Server::bind(&([127, 0, 0, 1], 0).into()).serve(make_service);
+                sender.send(server.local_addr()).unwrap();
+                server.await.unwrap();
+            });
+        });
+        format!("http://{}/", receiver.recv().unwrap())
+    }
+
+    #[test]
+    fn restore_over_http() {
+        restore_over_http_in(SegmentFormat::Split);
+        restore_over_http_in(SegmentFormat::Interleaved);
+    }
+
+    fn restore_over_http_in(format: SegmentFormat) {
+        let tempdir = tempdir().unwrap();
+        let key = Key::random(32);
+        let settings = ChunkSettings::lightweight();
+        let mut data = vec![0_u8; 100_000];
+        thread_rng().fill_bytes(&mut data);
+        // Create a repository on disk containing a single archive
+        smol::run(async {
+            let backend = MultiFile::open_with_segment_format(
+                tempdir.path(),
+                Some(settings),
+                &key,
+                4,
+                format,
+            )
+            .await
+            .unwrap();
+            let mut repo = Repository::with(backend, settings, key.clone(), 2).unwrap();
+            let mut manifest = Manifest::load(&repo);
+            let mut archive = ActiveArchive::new("test");
+            archive
+                .put_object(
+                    &FastCDC::default(),
+                    &mut repo,
+                    "data",
+                    Cursor::new(data.clone()),
+                )
+                .await
+                .unwrap();
+            manifest.commit_archive(&mut repo, archive).await.unwrap();
+            repo.close().await;
+        });
+        // Serve it, and restore the archive
+        let http_settings = HttpSettings {
+            url: serve(tempdir.path().to_path_buf()),
+        };
+        smol::run(async {
+            let backend = Http::connect(http_settings.clone(), key.clone(), 4).unwrap();
+            let mut repo = Repository::with(backend, settings, key.clone(), 2).unwrap();
+            let mut manifest = Manifest::load(&repo);
+            let archives = manifest.archives().await;
+            assert_eq!(archives.len(), 1);
+            let archive = archives[0].load(&mut repo).await.unwrap();
+            let mut output = Vec::new();
+            archive
+                .get_object(&mut repo, "data", &mut output)
+                .await
+                .unwrap();
+            assert_eq!(data, output);
+            repo.close().await;
+        });
+        // Make sure writes are refused
+        let mut backend = Http::connect_raw(http_settings, &key).unwrap();
+        let chunk = Chunk::pack(
+            vec![1_u8; 1024],
+            Compression::NoCompression,
+            Encryption::NoEncryption,
+            HMAC::Blake3,
+            &key,
+        );
+        assert!(matches!(
+            backend.write_chunk(chunk),
+            Err(BackendError::ReadOnly)
+        ));
+    }
+}
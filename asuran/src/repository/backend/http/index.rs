@@ -0,0 +1,60 @@
+use super::HttpConnection;
+use crate::repository::backend::common::sync_backend::SyncIndex;
+use crate::repository::backend::common::IndexTransaction;
+use crate::repository::backend::{BackendError, Result, SegmentDescriptor};
+use crate::repository::ChunkID;
+
+use serde_cbor as cbor;
+
+use std::collections::HashMap;
+use std::collections::HashSet;
+
+/// A read-only view of the index of a repository published over HTTP
+///
+/// The entire index is downloaded and replayed when connecting.
+#[derive(Debug)]
+pub struct HttpIndex {
+    state: HashMap<ChunkID, SegmentDescriptor>,
+}
+
+impl HttpIndex {
+    pub fn connect(connection: &HttpConnection) -> Result<Self> {
+        let mut state: HashMap<ChunkID, SegmentDescriptor> = HashMap::new();
+        // Index files are numbered sequentially, so keep requesting them until we run out
+        let mut id = 0_u64;
+        while let Some(bytes) = connection.get(&format!("index/{}", id))? {
+            // Keep deserializing transactions until we encounter an error
+            let de = cbor::Deserializer::from_slice(&bytes[..]);
+            let mut de = de.into_iter::<IndexTransaction>();
+            while let Some(tx) = de.next().and_then(std::result::Result::ok) {
+                if tx.removed {
+                    state.remove(&tx.chunk_id);
+                } else {
+                    state.insert(tx.chunk_id, tx.descriptor);
+                }
+            }
+            id += 1;
+        }
+
+        Ok(HttpIndex { state })
+    }
+}
+
+impl SyncIndex for HttpIndex {
+    fn lookup_chunk(&mut self, id: ChunkID) -> Option<SegmentDescriptor> {
+        self.state.get(&id).copied()
+    }
+    fn set_chunk(&mut self, _id: ChunkID, _location: SegmentDescriptor) -> Result<()> {
+        Err(BackendError::ReadOnly)
+    }
+    fn known_chunks(&mut self) -> HashSet<ChunkID> {
+        self.state.keys().copied().collect()
+    }
+    fn commit_index(&mut self) -> Result<()> {
+        // There can never be any changes to commit
+        Ok(())
+    }
+    fn chunk_count(&mut self) -> usize {
+        self.state.len()
+    }
+}
@@ -0,0 +1,135 @@
+use super::HttpConnection;
+use crate::repository::audit::AuditHead;
+use crate::repository::backend::common::sync_backend::SyncManifest;
+use crate::repository::backend::common::{
+    archive_transactions, dangling_parents, find_heads, latest_audit_head, verify_transactions,
+    DanglingParent, ManifestID, ManifestTransaction,
+};
+use crate::repository::backend::BackendError;
+use crate::repository::{ChunkSettings, Key};
+use crate::{manifest::StoredArchive, repository::backend::Result};
+
+use chrono::prelude::*;
+use serde_cbor as cbor;
+
+use std::collections::{HashMap, HashSet};
+
+/// A read-only view of the manifest of a repository published over HTTP
+///
+/// The entire manifest is downloaded and verified when connecting.
+#[derive(Debug)]
+pub struct HttpManifest {
+    known_entries: HashMap<ManifestID, ManifestTransaction>,
+    verified_memo_pad: HashSet<ManifestID>,
+    heads: Vec<ManifestID>,
+    key: Key,
+    chunk_settings: ChunkSettings,
+}
+
+impl HttpManifest {
+    /// Will attempt to download and verify the manifest of the repository the given connection
+    /// points to
+    pub fn connect(connection: HttpConnection, key: &Key) -> Result<Self> {
+        // Manifest files are numbered sequentially, so keep requesting them until we run out,
+        // collecting all known transactions
+        let mut known_entries = HashMap::new();
+        let mut id = 0_u64;
+        while let Some(bytes) = connection.get(&format!("manifest/{}", id))? {
+            // Keep deserializing transactions until we hit an error
+            let de = cbor::Deserializer::from_slice(&bytes[..]);
+            let mut de = de.into_iter::<ManifestTransaction>();
+            while let Some(tx) = de.next().and_then(std::result::Result::ok) {
+                known_entries.insert(tx.tag(), tx);
+            }
+            id += 1;
+        }
+
+        let bytes = connection.get("manifest/chunk.settings")?.ok_or_else(|| {
+            BackendError::ManifestError(
+                "Repository does not have a chunk.settings file".to_string(),
+            )
+        })?;
+        let chunk_settings = cbor::de::from_slice(&bytes[..])?;
+
+        // Construct the manifest
+        let mut manifest = HttpManifest {
+            known_entries,
+            verified_memo_pad: HashSet::new(),
+            heads: Vec::new(),
+            key: key.clone(),
+            chunk_settings,
+        };
+        // Build the list of heads, transactions referencing a previous head that is missing are
+        // treated as roots
+        manifest.heads = find_heads(&manifest.known_entries);
+        // Verify each head, along with all of its parents
+        for head in manifest.heads.clone() {
+            if !verify_transactions(
+                &manifest.known_entries,
+                head,
+                &manifest.key,
+                &mut manifest.verified_memo_pad,
+            ) {
+                return Err(BackendError::ManifestError(format!(
+                    "Manifest Transaction failed verification! {:?}",
+                    manifest.known_entries.get(&head).ok_or_else(|| BackendError::Unknown("Failed to get the head of the known entries list while reporting an error".to_string()))?
+                )));
+            }
+        }
+
+        Ok(manifest)
+    }
+}
+
+impl SyncManifest for HttpManifest {
+    type Iterator = std::vec::IntoIter<StoredArchive>;
+    fn last_modification(&mut self) -> Result<chrono::DateTime<chrono::FixedOffset>> {
+        if self.heads.is_empty() {
+            Ok(Local::now().with_timezone(Local::now().offset()))
+        } else {
+            let first_head = self
+                .known_entries
+                .get(&self.heads[0])
+                .expect("Item in heads was not in known entries");
+            let mut max = first_head.modified();
+            for id in &self.heads {
+                let tx = self.known_entries.get(id).ok_or_else(|| {
+                    BackendError::ManifestError("Unable to load timestamp".to_string())
+                })?;
+                if tx.modified() > max {
+                    max = tx.modified()
+                }
+            }
+            Ok(max)
+        }
+    }
+    fn chunk_settings(&mut self) -> ChunkSettings {
+        self.chunk_settings
+    }
+    fn archive_iterator(&mut self) -> Self::Iterator {
+        archive_transactions(self.known_entries.values())
+            .into_iter()
+            .map(StoredArchive::from)
+            .collect::<Vec<_>>()
+            .into_iter()
+    }
+    fn write_chunk_settings(&mut self, _chunk_settings: ChunkSettings) -> Result<()> {
+        Err(BackendError::ReadOnly)
+    }
+    fn write_archive(&mut self, _archive: StoredArchive) -> Result<()> {
+        Err(BackendError::ReadOnly)
+    }
+    fn touch(&mut self) -> Result<()> {
+        // There is nothing to touch on a read-only repository
+        Ok(())
+    }
+    fn write_audit_head(&mut self, _head: AuditHead) -> Result<()> {
+        Err(BackendError::ReadOnly)
+    }
+    fn audit_head(&mut self) -> Result<Option<AuditHead>> {
+        Ok(latest_audit_head(self.known_entries.values()))
+    }
+    fn dangling_parents(&mut self) -> Result<Vec<DanglingParent>> {
+        Ok(dangling_parents(&self.known_entries))
+    }
+}
@@ -0,0 +1,220 @@
+use super::HttpConnection;
+use crate::repository::backend::common::segment::{read_segment_header, Segment, SegmentFormat};
+use crate::repository::backend::common::segments_to_prefetch;
+use crate::repository::backend::{BackendError, Result, SegmentDescriptor};
+use crate::repository::{Chunk, ChunkSettings, Key};
+
+use asuran_core::repository::chunk::ChunkHeader;
+use lru::LruCache;
+
+use std::convert::TryFrom;
+use std::io::{self, Read, Seek, SeekFrom, Write};
+
+/// Number of bytes to download past the end of each read
+///
+/// The common segment reader makes many small reads while parsing headers and framing records,
+/// this keeps each of them from turning into a request of its own.
+const READ_AHEAD: u64 = 64 * 1024;
+
+/// A read only view of a file on the server, downloaded in ranges as it is read
+///
+/// This allows segments to be read with the common segment reader, which handles every segment
+/// format. Writing to it always fails.
+pub struct HttpFile {
+    connection: HttpConnection,
+    path: String,
+    length: u64,
+    position: u64,
+    /// The most recently downloaded range of the file
+    buffer: Vec<u8>,
+    /// Offset of the start of `buffer` in the file
+    buffer_start: u64,
+}
+
+impl HttpFile {
+    /// Looks up the length of the file at the given path, without downloading any of it
+    ///
+    /// Returns `Ok(None)` if the file does not exist.
+    pub fn open(connection: HttpConnection, path: String) -> Result<Option<HttpFile>> {
+        let length = match connection.content_length(&path)? {
+            Some(length) => length,
+            None => return Ok(None),
+        };
+        Ok(Some(HttpFile {
+            connection,
+            path,
+            length,
+            position: 0,
+            buffer: Vec::new(),
+            buffer_start: 0,
+        }))
+    }
+
+    /// Returns the end of the downloaded range
+    fn buffer_end(&self) -> u64 {
+        self.buffer_start + self.buffer.len() as u64
+    }
+}
+
+impl Read for HttpFile {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if buf.is_empty() || self.position >= self.length {
+            return Ok(0);
+        }
+        if self.position < self.buffer_start || self.position >= self.buffer_end() {
+            let end = self.length.min(
+                self.position
+                    .saturating_add(buf.len() as u64)
+                    .saturating_add(READ_AHEAD),
+            );
+            self.buffer = self
+                .connection
+                .get_range(&self.path, self.position, end)
+                .map_err(|e| io::Error::new(io::ErrorKind::Other, e.to_string()))?;
+            self.buffer_start = self.position;
+        }
+        // The server may have sent back less than was asked for
+        if self.position >= self.buffer_end() {
+            return Ok(0);
+        }
+        let available = &self.buffer[(self.position - self.buffer_start) as usize..];
+        let count = available.len().min(buf.len());
+        buf[..count].copy_from_slice(&available[..count]);
+        self.position += count as u64;
+        Ok(count)
+    }
+}
+
+impl Seek for HttpFile {
+    fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
+        let (base, offset) = match pos {
+            SeekFrom::Start(offset) => (0, i128::from(offset)),
+            SeekFrom::End(offset) => (self.length, i128::from(offset)),
+            SeekFrom::Current(offset) => (self.position, i128::from(offset)),
+        };
+        self.position = u64::try_from(i128::from(base) + offset).map_err(|_| {
+            io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "Attempted to seek to an invalid position",
+            )
+        })?;
+        Ok(self.position)
+    }
+}
+
+impl Write for HttpFile {
+    fn write(&mut self, _buf: &[u8]) -> io::Result<usize> {
+        Err(io::Error::new(
+            io::ErrorKind::Other,
+            "Repositories served over HTTP are read only",
+        ))
+    }
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+pub struct HttpSegmentHandler {
+    /// The connection this SegmentHandler is using
+    connection: HttpConnection,
+    /// An LRU cache of recently used segments
+    segment_cache: LruCache<u64, Segment<HttpFile>>,
+    /// The number of segments per directory
+    segments_per_directory: u64,
+    /// The chunk settings used for decrypting headers
+    chunk_settings: ChunkSettings,
+    /// The key used for decrypting headers
+    key: Key,
+}
+
+impl HttpSegmentHandler {
+    pub fn connect(
+        connection: HttpConnection,
+        segments_per_directory: u64,
+        chunk_settings: ChunkSettings,
+        key: Key,
+    ) -> HttpSegmentHandler {
+        HttpSegmentHandler {
+            connection,
+            // Open segments only hold their headers and a small buffer in memory, so we can afford
+            // to keep quite a few of them around
+            segment_cache: LruCache::new(64),
+            segments_per_directory,
+            chunk_settings,
+            key,
+        }
+    }
+
+    /// Returns the paths of the data and header resources of a segment
+    fn segment_paths(&self, segment_id: u64) -> (String, String) {
+        let folder_id = segment_id / self.segments_per_directory;
+        (
+            format!("data/{}/{}", folder_id, segment_id),
+            format!("data/{}/{}.header", folder_id, segment_id),
+        )
+    }
+
+    /// Opens a segment into the cache, if it is not already present, and returns it
+    ///
+    /// The format of the segment is read from the magic number at its start, split segments
+    /// additionally have their header file downloaded.
+    fn open_segment(&mut self, segment_id: u64) -> Result<&mut Segment<HttpFile>> {
+        if !self.segment_cache.contains(&segment_id) {
+            let (data_path, header_path) = self.segment_paths(segment_id);
+            let mut data = HttpFile::open(self.connection.clone(), data_path)?
+                .ok_or(BackendError::SegmentNotFound(segment_id))?;
+            let format = read_segment_header(&mut data)?.format().ok_or_else(|| {
+                BackendError::SegmentError("Segment failed header validation".to_string())
+            })?;
+            let segment = match format {
+                SegmentFormat::Split => {
+                    let header = HttpFile::open(self.connection.clone(), header_path)?
+                        .ok_or(BackendError::SegmentNotFound(segment_id))?;
+                    Segment::new(
+                        data,
+                        header,
+                        u64::MAX,
+                        self.chunk_settings,
+                        self.key.clone(),
+                    )?
+                }
+                SegmentFormat::Interleaved => {
+                    Segment::new_interleaved(data, u64::MAX, self.chunk_settings, self.key.clone())?
+                }
+            };
+            self.segment_cache.put(segment_id, segment);
+        }
+        Ok(self
+            .segment_cache
+            .get_mut(&segment_id)
+            .expect("Segment was just loaded into the cache"))
+    }
+
+    pub fn read_chunk(&mut self, location: SegmentDescriptor) -> Result<Chunk> {
+        self.open_segment(location.segment_id)?
+            .read_chunk(location.start)
+    }
+
+    pub fn read_chunk_header(&mut self, location: SegmentDescriptor) -> Result<(ChunkHeader, u64)> {
+        self.open_segment(location.segment_id)?
+            .read_chunk_header(location.start)
+    }
+
+    /// Opens the segments containing the given locations into the cache, in order of first
+    /// appearance, stopping once the cache is full
+    pub fn prefetch(&mut self, locations: &[SegmentDescriptor]) -> Result<()> {
+        let capacity = self.segment_cache.cap();
+        for segment_id in segments_to_prefetch(locations, capacity) {
+            self.open_segment(segment_id)?;
+        }
+        Ok(())
+    }
+}
+
+impl std::fmt::Debug for HttpSegmentHandler {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("HttpSegmentHandler")
+            .field("connection", &self.connection)
+            .finish()
+    }
+}
@@ -1,4 +1,5 @@
 use super::Result;
+use crate::repository::audit::AuditHead;
 use crate::repository::backend::common;
 use crate::repository::backend::common::sync_backend::{
     BackendHandle, SyncBackend, SyncIndex, SyncManifest,
@@ -9,6 +10,9 @@ use crate::repository::backend::{
 };
 use crate::repository::{Chunk, EncryptedKey, Key};
 
+use asuran_core::repository::chunk::ChunkHeader;
+use tracing::debug;
+
 use std::collections::HashMap;
 use std::convert::TryInto;
 use std::io::Cursor;
@@ -17,8 +21,20 @@ pub struct Mem {
     data: common::Segment<Cursor<Vec<u8>>>,
     index: HashMap<ChunkID, SegmentDescriptor>,
     manifest: Vec<StoredArchive>,
+    audit_head: Option<AuditHead>,
     chunk_settings: ChunkSettings,
     key: Option<EncryptedKey>,
+    /// Maximum number of chunk bytes that may be held at once, if any
+    max_bytes: Option<u64>,
+    /// Evict the least recently used chunks instead of failing when `max_bytes` is exceeded
+    evict: bool,
+    /// Number of chunk bytes currently held
+    usage: u64,
+    /// Length of each chunk that has been written, keyed by its location in the segment
+    sizes: HashMap<u64, u64>,
+    /// Tick each indexed chunk was last looked up or set at
+    last_used: HashMap<ChunkID, u64>,
+    tick: u64,
 }
 
 impl Mem {
@@ -37,14 +53,95 @@ impl Mem {
             data,
             index: HashMap::new(),
             manifest: Vec::new(),
+            audit_head: None,
             chunk_settings,
             key: None,
+            max_bytes: None,
+            evict: false,
+            usage: 0,
+            sizes: HashMap::new(),
+            last_used: HashMap::new(),
+            tick: 0,
         }
     }
 
     pub fn new(chunk_settings: ChunkSettings, key: Key, queue_depth: usize) -> BackendHandle<Mem> {
         BackendHandle::new(queue_depth, move || Self::new_raw(chunk_settings, key))
     }
+
+    /// Creates a new `Mem` backend that will hold at most `max_bytes` bytes of chunk data
+    ///
+    /// Writes that would push the backend past `max_bytes` fail with
+    /// `BackendError::QuotaExceeded`, allowing tests to simulate large repositories without
+    /// running the process out of memory.
+    pub fn with_max_bytes(
+        chunk_settings: ChunkSettings,
+        key: Key,
+        queue_depth: usize,
+        max_bytes: u64,
+    ) -> BackendHandle<Mem> {
+        BackendHandle::new(queue_depth, move || {
+            let mut mem = Self::new_raw(chunk_settings, key);
+            mem.max_bytes = Some(max_bytes);
+            mem
+        })
+    }
+
+    /// Creates a new `Mem` backend that evicts the least recently used chunks once it holds
+    /// more than `max_bytes` bytes of chunk data
+    ///
+    /// Evicted chunks are dropped from the index, exactly as with `remove_chunk`, so later reads
+    /// of them fail with a not found error. This is intended for simulating a lossy backend
+    /// in tests, and only fails a write with `BackendError::QuotaExceeded` if the chunk could not
+    /// fit even in an empty backend.
+    ///
+    /// As with `remove_chunk`, the bytes of evicted chunks are not reclaimed from the
+    /// underlying buffer.
+    pub fn with_eviction(
+        chunk_settings: ChunkSettings,
+        key: Key,
+        queue_depth: usize,
+        max_bytes: u64,
+    ) -> BackendHandle<Mem> {
+        BackendHandle::new(queue_depth, move || {
+            let mut mem = Self::new_raw(chunk_settings, key);
+            mem.max_bytes = Some(max_bytes);
+            mem.evict = true;
+            mem
+        })
+    }
+
+    /// Marks the chunk with the given id as the most recently used
+    fn touch_chunk(&mut self, id: ChunkID) {
+        self.tick += 1;
+        self.last_used.insert(id, self.tick);
+    }
+
+    /// Drops the chunk with the given id from the index, and stops counting its bytes
+    fn forget_chunk(&mut self, id: ChunkID) {
+        if let Some(location) = self.index.remove(&id) {
+            if let Some(size) = self.sizes.remove(&location.start) {
+                self.usage -= size;
+            }
+        }
+        self.last_used.remove(&id);
+    }
+
+    /// Evicts the least recently used chunk, returning false if there was nothing to evict
+    fn evict_one(&mut self) -> bool {
+        let oldest = self
+            .last_used
+            .iter()
+            .min_by_key(|(_, tick)| **tick)
+            .map(|(id, _)| *id);
+        if let Some(id) = oldest {
+            debug!(?id, "Evicting chunk from mem backend");
+            self.forget_chunk(id);
+            true
+        } else {
+            false
+        }
+    }
 }
 
 impl SyncManifest for Mem {
@@ -77,14 +174,37 @@ impl SyncManifest for Mem {
         // This method doesnt really make sense on a non-persisting repository
         Ok(())
     }
+    fn delete_archive(&mut self, archive: StoredArchive) -> Result<()> {
+        self.manifest.retain(|x| x.id() != archive.id());
+        Ok(())
+    }
+    fn write_audit_head(&mut self, head: AuditHead) -> Result<()> {
+        self.audit_head = Some(head);
+        Ok(())
+    }
+    fn audit_head(&mut self) -> Result<Option<AuditHead>> {
+        Ok(self.audit_head)
+    }
 }
 
 impl SyncIndex for Mem {
     fn lookup_chunk(&mut self, id: ChunkID) -> Option<SegmentDescriptor> {
-        self.index.get(&id).copied()
+        let location = self.index.get(&id).copied();
+        if location.is_some() {
+            self.touch_chunk(id);
+        }
+        location
     }
     fn set_chunk(&mut self, id: ChunkID, location: SegmentDescriptor) -> Result<()> {
-        self.index.insert(id, location);
+        if let Some(old) = self.index.insert(id, location) {
+            // Overwriting a chunk releases the bytes of its previous copy
+            if old != location {
+                if let Some(size) = self.sizes.remove(&old.start) {
+                    self.usage -= size;
+                }
+            }
+        }
+        self.touch_chunk(id);
         Ok(())
     }
     fn known_chunks(&mut self) -> HashSet<ChunkID> {
@@ -124,13 +244,40 @@ impl SyncBackend for Mem {
     fn read_chunk(&mut self, location: SegmentDescriptor) -> Result<Chunk> {
         self.data.read_chunk(location.start)
     }
+    fn read_chunk_header(&mut self, location: SegmentDescriptor) -> Result<(ChunkHeader, u64)> {
+        self.data.read_chunk_header(location.start)
+    }
     fn write_chunk(&mut self, chunk: Chunk) -> Result<SegmentDescriptor> {
+        let size = chunk.len() as u64;
+        if let Some(limit) = self.max_bytes {
+            if self.evict {
+                while self.usage + size > limit && self.evict_one() {}
+            }
+            if self.usage + size > limit {
+                return Err(BackendError::QuotaExceeded {
+                    limit,
+                    usage: self.usage,
+                });
+            }
+        }
         let start = self.data.write_chunk(chunk)?;
+        self.usage += size;
+        self.sizes.insert(start, size);
         Ok(SegmentDescriptor {
             segment_id: 0,
             start,
         })
     }
+    fn remove_chunk(&mut self, id: ChunkID) -> Result<()> {
+        // The data can not be removed from the middle of the segment, but dropping the index
+        // entry is enough to make the chunk unreachable
+        self.forget_chunk(id);
+        Ok(())
+    }
+    /// Nothing is buffered, and memory can not be made durable, so this only commits the index
+    fn sync(&mut self) -> Result<()> {
+        self.commit_index()
+    }
 }
 
 impl std::fmt::Debug for Mem {
@@ -169,4 +316,89 @@ mod tests {
             assert_eq!(key, output);
         });
     }
+
+    /// Makes sure a removed chunk can no longer be read
+    #[test]
+    fn remove_chunk() {
+        smol::run(async {
+            let key = Key::random(32);
+            let mut backend = Mem::new(ChunkSettings::lightweight(), key.clone(), 8);
+            let mut repo =
+                Repository::with(backend.clone(), ChunkSettings::lightweight(), key, 2).unwrap();
+            let (id, _) = repo.write_chunk(vec![1_u8; 1024]).await.unwrap();
+            assert!(repo.read_chunk(id).await.is_ok());
+            backend.remove_chunk(id).await.unwrap();
+            assert!(matches!(
+                repo.read_chunk(id).await,
+                Err(RepositoryError::ChunkNotFound)
+            ));
+        });
+    }
+
+    #[test]
+    fn ping() {
+        smol::run(async {
+            let key = Key::random(32);
+            let mut backend = Mem::new(ChunkSettings::lightweight(), key, 8);
+            backend
+                .ping()
+                .await
+                .expect("Ping failed on an open backend");
+            let mut object = backend.get_object_handle();
+            object
+                .ping()
+                .await
+                .expect("Ping failed through a BackendObject");
+        });
+    }
+
+    /// Makes sure a capped backend refuses writes past its limit
+    #[test]
+    fn max_bytes_quota() {
+        smol::run(async {
+            let key = Key::random(32);
+            let backend = Mem::with_max_bytes(ChunkSettings::lightweight(), key.clone(), 8, 2048);
+            let mut repo = Repository::with(backend, ChunkSettings::lightweight(), key, 2).unwrap();
+            repo.write_chunk(vec![1_u8; 1024]).await.unwrap();
+            repo.write_chunk(vec![2_u8; 1024]).await.unwrap();
+            let result = repo.write_chunk(vec![3_u8; 1024]).await;
+            assert!(matches!(
+                result,
+                Err(RepositoryError::BackendError(BackendError::QuotaExceeded {
+                    limit: 2048,
+                    usage: 2048
+                }))
+            ));
+        });
+    }
+
+    /// Makes sure an evicting backend drops the least recently used chunk
+    #[test]
+    fn eviction() {
+        smol::run(async {
+            let key = Key::random(32);
+            let backend = Mem::with_eviction(ChunkSettings::lightweight(), key.clone(), 8, 2048);
+            let mut repo = Repository::with(backend, ChunkSettings::lightweight(), key, 2).unwrap();
+            let (first, _) = repo.write_chunk(vec![1_u8; 1024]).await.unwrap();
+            let (second, _) = repo.write_chunk(vec![2_u8; 1024]).await.unwrap();
+            // Use the first chunk, so that the second is now the least recently used
+            repo.read_chunk(first).await.unwrap();
+            let (third, _) = repo.write_chunk(vec![3_u8; 1024]).await.unwrap();
+
+            assert!(repo.read_chunk(first).await.is_ok());
+            assert!(repo.read_chunk(third).await.is_ok());
+            assert!(matches!(
+                repo.read_chunk(second).await,
+                Err(RepositoryError::ChunkNotFound)
+            ));
+
+            // A chunk that could never fit is still refused
+            assert!(matches!(
+                repo.write_chunk(vec![4_u8; 4096]).await,
+                Err(RepositoryError::BackendError(
+                    BackendError::QuotaExceeded { .. }
+                ))
+            ));
+        });
+    }
 }
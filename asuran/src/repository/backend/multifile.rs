@@ -1,16 +1,22 @@
 #![allow(unused_variables)]
 use super::{BackendError, Result};
-use crate::repository::backend::common::files::LockedFile;
+use crate::repository::backend::common::files::{remove_temp_files, LockedFile};
+use crate::repository::backend::common::segment::SegmentFormat;
 use crate::repository::backend::{
-    backend_to_object, Backend, BackendObject, Chunk, EncryptedKey, Manifest, SegmentDescriptor,
+    backend_to_object, Backend, BackendObject, BackendSettings, Chunk, ChunkID, Durability,
+    EncryptedKey, Index, Manifest, SegmentDescriptor,
 };
 use crate::repository::{ChunkSettings, Key};
 
+use asuran_core::repository::chunk::ChunkHeader;
 use async_trait::async_trait;
 use serde_cbor as cbor;
+use smol::blocking;
 use uuid::Uuid;
 
-use std::fs::{create_dir_all, remove_file, File, OpenOptions};
+use std::collections::HashMap;
+use std::fs::{create_dir_all, read_dir, remove_file, File, OpenOptions};
+use std::io::ErrorKind;
 use std::path::{Path, PathBuf};
 use std::sync::Arc;
 
@@ -28,6 +34,10 @@ pub struct MultiFile {
     uuid: Uuid,
     /// Path to readlock for this connection, must be deleted on close
     read_lock_path: Arc<PathBuf>,
+    /// When to force written data to durable storage
+    durability: Durability,
+    /// Where new contents of files are staged before being renamed into place
+    temp_dir: PathBuf,
 }
 
 impl MultiFile {
@@ -45,6 +55,187 @@ impl MultiFile {
         chunk_settings: Option<ChunkSettings>,
         key: &Key,
         queue_depth: usize,
+    ) -> Result<MultiFile> {
+        Self::open_with(
+            path,
+            chunk_settings,
+            key,
+            queue_depth,
+            false,
+            segment::DEFAULT_RO_CACHE_SIZE,
+            Durability::default(),
+            None,
+        )
+        .await
+    }
+
+    /// Opens a new `MultiFile` backend with default settings, other than fsyncing its files as
+    /// specified by `durability`, instead of always doing so
+    ///
+    /// See `Durability::None` for the risks of turning off fsyncing.
+    ///
+    /// # Errors
+    ///
+    /// Will error under the same conditions as `open_defaults`
+    pub async fn open_with_durability(
+        path: impl AsRef<Path>,
+        chunk_settings: Option<ChunkSettings>,
+        key: &Key,
+        queue_depth: usize,
+        durability: Durability,
+    ) -> Result<MultiFile> {
+        Self::open_with(
+            path,
+            chunk_settings,
+            key,
+            queue_depth,
+            false,
+            segment::DEFAULT_RO_CACHE_SIZE,
+            durability,
+            None,
+        )
+        .await
+    }
+
+    /// Opens a new `MultiFile` backend with default settings, other than keeping up to
+    /// `ro_cache_size` segments open for reading, instead of `segment::DEFAULT_RO_CACHE_SIZE`
+    ///
+    /// Each cached segment holds two file descriptors open, so this should be lowered on systems
+    /// with tight file descriptor limits, see `segment::SegmentHandler::open`.
+    ///
+    /// # Errors
+    ///
+    /// Will error under the same conditions as `open_defaults`
+    pub async fn open_with_cache_size(
+        path: impl AsRef<Path>,
+        chunk_settings: Option<ChunkSettings>,
+        key: &Key,
+        queue_depth: usize,
+        ro_cache_size: usize,
+    ) -> Result<MultiFile> {
+        Self::open_with(
+            path,
+            chunk_settings,
+            key,
+            queue_depth,
+            false,
+            ro_cache_size,
+            Durability::default(),
+            None,
+        )
+        .await
+    }
+
+    /// Opens a new `MultiFile` backend with default settings, other than storing segments in the
+    /// given format if the repository is being created
+    ///
+    /// The format of an existing repository is fixed when its first segment is written, so for
+    /// existing repositories `format` is ignored, and the recorded format is used. See
+    /// `SegmentFormat::Interleaved` for the crash safety advantages of that format, which older
+    /// versions of asuran are unable to read.
+    ///
+    /// # Errors
+    ///
+    /// Will error under the same conditions as `open_defaults`, or if recording the format fails
+    pub async fn open_with_segment_format(
+        path: impl AsRef<Path>,
+        chunk_settings: Option<ChunkSettings>,
+        key: &Key,
+        queue_depth: usize,
+        format: SegmentFormat,
+    ) -> Result<MultiFile> {
+        let repository_path = path.as_ref();
+        if !repository_path.join("data").exists() && !repository_path.join("format").exists() {
+            create_dir_all(repository_path)?;
+            segment::write_segment_format(repository_path, format)?;
+        }
+        Self::open_with(
+            path,
+            chunk_settings,
+            key,
+            queue_depth,
+            false,
+            segment::DEFAULT_RO_CACHE_SIZE,
+            Durability::default(),
+            None,
+        )
+        .await
+    }
+
+    /// Opens a new `MultiFile` backend with default settings, deferring loading of the index
+    ///
+    /// The manifest is loaded as normal, but the index is not read until the first operation that
+    /// requires it, making this well suited for operations such as listing the archives in a
+    /// repository with a very large index.
+    ///
+    /// # Errors
+    ///
+    /// Will error if creating or locking any of the manifest files fails, or if any other I/O
+    /// error occurs. Errors opening the index will instead be reported by the first index
+    /// operation.
+    pub async fn open_lazy(
+        path: impl AsRef<Path>,
+        chunk_settings: Option<ChunkSettings>,
+        key: &Key,
+        queue_depth: usize,
+    ) -> Result<MultiFile> {
+        Self::open_with(
+            path,
+            chunk_settings,
+            key,
+            queue_depth,
+            true,
+            segment::DEFAULT_RO_CACHE_SIZE,
+            Durability::default(),
+            None,
+        )
+        .await
+    }
+
+    /// Opens a new `MultiFile` backend with default settings, other than staging the new contents
+    /// of the index and manifest files in `temp_dir`, instead of the repository directory
+    ///
+    /// Index and manifest files are never modified in place, instead, each commit of the index,
+    /// and each manifest transaction, is written to a temporary file, which is then renamed into
+    /// place as a new numbered file, so that a crash part way through a commit can not corrupt
+    /// them. `temp_dir` must be on the same filesystem as the repository, as renames can not
+    /// cross filesystems. Temporary files left behind by a crash are named with
+    /// `common::files::TEMP_FILE_PREFIX`, and are removed when the repository is next opened with
+    /// no other connections to it.
+    ///
+    /// # Errors
+    ///
+    /// Will error under the same conditions as `open_defaults`
+    pub async fn open_with_temp_dir(
+        path: impl AsRef<Path>,
+        chunk_settings: Option<ChunkSettings>,
+        key: &Key,
+        queue_depth: usize,
+        temp_dir: impl AsRef<Path>,
+    ) -> Result<MultiFile> {
+        Self::open_with(
+            path,
+            chunk_settings,
+            key,
+            queue_depth,
+            false,
+            segment::DEFAULT_RO_CACHE_SIZE,
+            Durability::default(),
+            Some(temp_dir.as_ref().to_path_buf()),
+        )
+        .await
+    }
+
+    #[allow(clippy::too_many_arguments)]
+    async fn open_with(
+        path: impl AsRef<Path>,
+        chunk_settings: Option<ChunkSettings>,
+        key: &Key,
+        queue_depth: usize,
+        lazy_index: bool,
+        ro_cache_size: usize,
+        durability: Durability,
+        temp_dir: Option<PathBuf>,
     ) -> Result<MultiFile> {
         // First, check to see if the global lock exists, and return an error early if it does
         let global_lock_path = path.as_ref().join("lock");
@@ -58,11 +249,27 @@ impl MultiFile {
         let uuid = Uuid::new_v4();
         let size_limit = 2_000_000_000;
         let segments_per_directory = 100;
+        // Stage writes in the repository itself unless told otherwise, as it is guaranteed to be
+        // on the same filesystem
+        let temp_dir = temp_dir.unwrap_or_else(|| path.as_ref().to_path_buf());
+        // Make sure the readlocks directory exists
+        create_dir_all(path.as_ref().join("readlocks"))?;
+        remove_stale_temp_files(path.as_ref(), &temp_dir)?;
         // Open up an index connection
-        let index_handle = index::Index::open(&path, queue_depth)?;
+        let index_handle = if lazy_index {
+            index::Index::open_lazy(&path, queue_depth, durability, &temp_dir)
+        } else {
+            index::Index::open_with_temp_dir(&path, queue_depth, durability, &temp_dir)?
+        };
         // Open up a manifest connection
-        let mut manifest_handle =
-            manifest::Manifest::open(&path, chunk_settings, key, queue_depth)?;
+        let mut manifest_handle = manifest::Manifest::open_with_temp_dir(
+            &path,
+            chunk_settings,
+            key,
+            queue_depth,
+            durability,
+            &temp_dir,
+        )?;
         let chunk_settings = if let Some(chunk_settings) = chunk_settings {
             chunk_settings
         } else {
@@ -76,9 +283,9 @@ impl MultiFile {
             chunk_settings,
             key.clone(),
             queue_depth,
+            ro_cache_size,
+            durability,
         )?;
-        // Make sure the readlocks directory exists
-        create_dir_all(path.as_ref().join("readlocks"))?;
         // generate a path to our readlock
         let read_lock_path = path
             .as_ref()
@@ -89,6 +296,15 @@ impl MultiFile {
             .create(true)
             .write(true)
             .open(&read_lock_path)?;
+        // Someone may have taken the global lock between our first check and creating the read
+        // lock, without seeing our read lock, so check again now that it exists
+        if Path::exists(&global_lock_path) {
+            remove_file(&read_lock_path)?;
+            return Err(BackendError::RepositoryGloballyLocked(format!(
+                "Global lock for this repository already exists at: {:?}",
+                global_lock_path
+            )));
+        }
 
         let path = path.as_ref().to_path_buf();
         Ok(MultiFile {
@@ -98,9 +314,51 @@ impl MultiFile {
             path,
             uuid,
             read_lock_path: Arc::new(read_lock_path),
+            durability,
+            temp_dir,
         })
     }
 
+    /// Verifies every chunk in the repository, reading each segment independently across up to
+    /// `threads` threads
+    ///
+    /// Reading chunks one at a time through the backend serializes all the disk I/O through the
+    /// segment handler, this bypasses it for the read only verification case. The segment
+    /// currently being written is flushed first. Chunks written by other connections while this
+    /// is running may or may not be checked.
+    ///
+    /// # Errors
+    ///
+    /// Will error if flushing the current segment fails, or the data directory can not be walked.
+    /// Corrupted chunks and unreadable segments are reported in the returned
+    /// `SegmentVerification`, rather than as errors.
+    pub async fn verify_parallel(
+        &mut self,
+        key: &Key,
+        threads: usize,
+    ) -> Result<segment::SegmentVerification> {
+        self.segment_handle.flush().await?;
+        let chunk_settings = self.manifest_handle.chunk_settings().await;
+        let data_path = self.path.join("data");
+        let key = key.clone();
+        blocking!(segment::verify_segments(
+            data_path,
+            chunk_settings,
+            &key,
+            threads
+        ))
+    }
+
+    /// Checks if there is a multifile repository at the given path, without opening it or
+    /// requiring its key
+    ///
+    /// This only checks that the path is a directory containing a key file, see `read_key`, as well
+    /// as the index and manifest directories.
+    pub fn exists(path: impl AsRef<Path>) -> bool {
+        let path = path.as_ref();
+        path.join("key").is_file() && path.join("index").is_dir() && path.join("manifest").is_dir()
+    }
+
     /// Reads the encrypted key off the disk
     ///
     /// Does not require that the repository be opened first
@@ -117,6 +375,137 @@ impl MultiFile {
     }
 }
 
+/// Removes the temporary files left behind in `temp_dir` by a crash
+///
+/// Temporary files that other connections are still writing can not be told apart from those
+/// left behind by a crash, so this only removes anything if the global lock can be taken, and no
+/// other connection holds a read lock. The global lock is released again before returning.
+fn remove_stale_temp_files(path: &Path, temp_dir: &Path) -> Result<()> {
+    let no_readers = |path: &Path| -> std::io::Result<bool> {
+        Ok(read_dir(path.join("readlocks"))?
+            .filter_map(std::result::Result::ok)
+            .next()
+            .is_none())
+    };
+    // Don't briefly lock out other connections when there is no chance of cleaning up
+    if !no_readers(path)? {
+        return Ok(());
+    }
+    let global_lock_path = path.join("lock");
+    match OpenOptions::new()
+        .write(true)
+        .create_new(true)
+        .open(&global_lock_path)
+    {
+        Ok(_) => (),
+        // Someone else holds the global lock, and may be writing
+        Err(e) if e.kind() == ErrorKind::AlreadyExists => return Ok(()),
+        Err(e) => return Err(e.into()),
+    }
+    let result = no_readers(path).and_then(|no_readers| {
+        if no_readers {
+            remove_temp_files(temp_dir).map(|_| ())
+        } else {
+            Ok(())
+        }
+    });
+    remove_file(&global_lock_path)?;
+    Ok(result?)
+}
+
+/// Location of a `MultiFile` repository, and the settings to open it with
+///
+/// See `Repository::open_or_create`.
+#[derive(Debug, Clone)]
+pub struct MultiFileSettings {
+    /// The root directory of the repository
+    pub path: PathBuf,
+    /// Number of requests to queue up for the backend
+    pub queue_depth: usize,
+    /// When to force written data to durable storage
+    pub durability: Durability,
+    /// Where to stage new index and manifest files before renaming them into place, see
+    /// `set_temp_dir`
+    temp_dir: Option<PathBuf>,
+    /// Defer loading the index until it is first used, see `MultiFile::open_lazy`
+    lazy_index: bool,
+}
+
+impl MultiFileSettings {
+    /// Describes the repository at `path`, with the default durability
+    pub fn new(path: impl AsRef<Path>, queue_depth: usize) -> MultiFileSettings {
+        MultiFileSettings {
+            path: path.as_ref().to_path_buf(),
+            queue_depth,
+            durability: Durability::default(),
+            temp_dir: None,
+            lazy_index: false,
+        }
+    }
+
+    /// Sets whether loading of the index is deferred until it is first used
+    ///
+    /// Off by default. See `MultiFile::open_lazy` for when this is useful.
+    pub fn set_lazy_index(&mut self, lazy_index: bool) {
+        self.lazy_index = lazy_index;
+    }
+
+    /// Sets where new index and manifest files are staged before being renamed into place
+    ///
+    /// Defaults to the repository directory. Must be on the same filesystem as the repository,
+    /// see `MultiFile::open_with_temp_dir`.
+    pub fn set_temp_dir(&mut self, temp_dir: impl AsRef<Path>) {
+        self.temp_dir = Some(temp_dir.as_ref().to_path_buf());
+    }
+
+    /// Opens the repository with these settings, only writing the default chunk settings if they
+    /// are provided
+    async fn open_backend(
+        &self,
+        key: &Key,
+        chunk_settings: Option<ChunkSettings>,
+    ) -> Result<MultiFile> {
+        MultiFile::open_with(
+            &self.path,
+            chunk_settings,
+            key,
+            self.queue_depth,
+            self.lazy_index,
+            segment::DEFAULT_RO_CACHE_SIZE,
+            self.durability,
+            self.temp_dir.clone(),
+        )
+        .await
+    }
+}
+
+#[async_trait]
+impl BackendSettings for MultiFileSettings {
+    type Backend = MultiFile;
+    /// A repository exists if the path contains a key file
+    async fn read_key(&self) -> Result<Option<EncryptedKey>> {
+        if MultiFile::exists(&self.path) {
+            Ok(Some(MultiFile::read_key(&self.path)?))
+        } else {
+            Ok(None)
+        }
+    }
+    async fn open(&self, key: &Key) -> Result<MultiFile> {
+        self.open_backend(key, None).await
+    }
+    async fn create(
+        &self,
+        key: &Key,
+        encrypted_key: &EncryptedKey,
+        settings: ChunkSettings,
+    ) -> Result<MultiFile> {
+        create_dir_all(&self.path)?;
+        let backend = self.open_backend(key, Some(settings)).await?;
+        backend.write_key(encrypted_key).await?;
+        Ok(backend)
+    }
+}
+
 #[async_trait]
 impl Backend for MultiFile {
     type Manifest = manifest::Manifest;
@@ -130,14 +519,18 @@ impl Backend for MultiFile {
     fn get_manifest(&self) -> Self::Manifest {
         self.manifest_handle.clone()
     }
-    /// Locks the keyfile and writes the key
+    /// Locks the keyfile and replaces its contents with the key
+    ///
+    /// The key is staged in a temporary file, and renamed over the keyfile, so a crash part way
+    /// through leaves the previous key intact.
     ///
     /// Will return Err if writing the key fails
     async fn write_key(&self, key: &EncryptedKey) -> Result<()> {
         let key_path = self.path.join("key");
         let mut file =
             LockedFile::open_read_write(&key_path)?.ok_or(BackendError::FileLockError)?;
-        Ok(cbor::ser::to_writer(&mut file, key)?)
+        let bytes = cbor::ser::to_vec(key)?;
+        Ok(file.replace_contents(&bytes, &self.temp_dir, self.durability)?)
     }
     /// Attempts to read the key from the repository
     ///
@@ -153,11 +546,137 @@ impl Backend for MultiFile {
         self.segment_handle.read_chunk(location).await
     }
 
+    /// Reads the chunk's header, from the segment's header file, or the front of its record
+    async fn read_chunk_header(
+        &mut self,
+        location: SegmentDescriptor,
+    ) -> Result<(ChunkHeader, u64)> {
+        self.segment_handle.read_chunk_header(location).await
+    }
+
     /// Starts writing a chunk, and returns a oneshot reciever with the result of that process
     async fn write_chunk(&mut self, chunk: Chunk) -> Result<SegmentDescriptor> {
         self.segment_handle.write_chunk(chunk).await
     }
 
+    /// Starts opening the segments containing the given chunks in the background
+    async fn prefetch(&mut self, locations: Vec<SegmentDescriptor>) -> Result<()> {
+        self.segment_handle.prefetch(locations).await;
+        Ok(())
+    }
+
+    /// Marks the chunk as removed in the index, leaving its data in place in its segment until the
+    /// segment is compacted, see `compact`
+    async fn remove_chunk(&mut self, id: ChunkID) -> Result<()> {
+        self.index_handle.remove_chunk(id).await
+    }
+
+    /// Creates the global lock file, and then checks that no other connection holds a read lock
+    ///
+    /// Connections check for the global lock both before and after creating their read lock, so
+    /// either they will see the global lock, or it will see their read lock. Once locked, the
+    /// index and manifest are reread from disk.
+    async fn lock_exclusive(&mut self) -> Result<()> {
+        let global_lock_path = self.path.join("lock");
+        match OpenOptions::new()
+            .write(true)
+            .create_new(true)
+            .open(&global_lock_path)
+        {
+            Ok(_) => (),
+            Err(e) if e.kind() == ErrorKind::AlreadyExists => {
+                return Err(BackendError::RepositoryGloballyLocked(format!(
+                    "Global lock for this repository already exists at: {:?}",
+                    global_lock_path
+                )))
+            }
+            Err(e) => return Err(e.into()),
+        }
+        let others = read_dir(self.path.join("readlocks"))?
+            .filter_map(std::result::Result::ok)
+            .filter(|x| x.path() != *self.read_lock_path)
+            .count();
+        if others > 0 {
+            remove_file(&global_lock_path)?;
+            return Err(BackendError::RepositoryGloballyLocked(format!(
+                "{} other connections currently have this repository open",
+                others
+            )));
+        }
+        // Connections that came and went since this one was opened may have added archives and
+        // chunks that we have not seen yet
+        let reloaded = match self.index_handle.reload().await {
+            Ok(()) => self.manifest_handle.reload().await,
+            Err(e) => Err(e),
+        };
+        if reloaded.is_err() {
+            remove_file(&global_lock_path)?;
+        }
+        reloaded
+    }
+
+    /// Removes the global lock file
+    async fn unlock_exclusive(&mut self) -> Result<()> {
+        let global_lock_path = self.path.join("lock");
+        if global_lock_path.exists() {
+            remove_file(global_lock_path)?;
+        }
+        Ok(())
+    }
+
+    /// Copies the chunks still in the index out of every segment that holds removed chunks, and
+    /// then deletes those segments
+    ///
+    /// The index is updated, and committed, to point at the copies before any segment is deleted,
+    /// so a crash part way through leaves, at worst, unreferenced segments behind for the next
+    /// compaction to clean up.
+    async fn compact(&mut self) -> Result<u64> {
+        let mut index = self.index_handle.clone();
+        // Committing forces the index to load, so a lazily loaded index that fails to read is
+        // reported here, rather than looking like an empty index, and every chunk being dead
+        index.commit_index().await?;
+        let mut live: HashMap<u64, Vec<(ChunkID, u64)>> = HashMap::new();
+        for id in index.known_chunks().await {
+            if let Some(location) = index.lookup_chunk(id).await? {
+                live.entry(location.segment_id)
+                    .or_default()
+                    .push((id, location.start));
+            }
+        }
+        let compaction = self.segment_handle.compact(live).await?;
+        for (id, location) in compaction.moved {
+            index.set_chunk(id, location).await?;
+        }
+        index.commit_index().await?;
+        let emptied = compaction.emptied.len() as u64;
+        self.segment_handle
+            .remove_segments(compaction.emptied)
+            .await?;
+        Ok(emptied)
+    }
+
+    /// Flushes the segment currently being written, and then commits the index, fsyncing both if
+    /// this backend was opened with `Durability::Full`
+    ///
+    /// The manifest is not touched, as it is written out to disk on every change.
+    async fn sync(&mut self) -> Result<()> {
+        self.segment_handle.sync().await?;
+        self.index_handle.commit_index().await
+    }
+
+    /// Stats the root directory of the repository
+    async fn ping(&mut self) -> Result<()> {
+        let metadata = std::fs::metadata(&self.path)?;
+        if metadata.is_dir() {
+            Ok(())
+        } else {
+            Err(BackendError::Unknown(format!(
+                "Repository path {:?} is not a directory",
+                self.path
+            )))
+        }
+    }
+
     /// Closes out the index, segment handler, and manifest cleanly, making sure all operations are
     /// completed and all drop impls from inside the tasks are called
     async fn close(&mut self) {
@@ -180,7 +699,9 @@ impl Backend for MultiFile {
 #[cfg(test)]
 mod tests {
     use super::*;
-    use crate::repository::Encryption;
+    use crate::manifest::StoredArchive;
+    use crate::repository::{Compression, Encryption, HMAC};
+    use std::collections::HashSet;
     use tempfile::{tempdir, TempDir};
 
     // Utility function, sets up a tempdir and opens a MultiFile Backend
@@ -193,6 +714,24 @@ mod tests {
         (tempdir, mf)
     }
 
+    #[test]
+    fn exists_after_key_written() {
+        smol::run(async {
+            let key = Key::random(32);
+            let empty = tempdir().unwrap();
+            assert!(!MultiFile::exists(empty.path()));
+            let (tempdir, mut mf) = setup(&key).await;
+            let enc_key = EncryptedKey::encrypt(&key, 512, 1, Encryption::new_aes256ctr(), b"");
+            mf.write_key(&enc_key).await.unwrap();
+            mf.close().await;
+            assert!(MultiFile::exists(tempdir.path()));
+            // A stray key file alone is not a repository
+            let stray = tempfile::tempdir().unwrap();
+            std::fs::copy(tempdir.path().join("key"), stray.path().join("key")).unwrap();
+            assert!(!MultiFile::exists(stray.path()));
+        });
+    }
+
     #[test]
     fn key_store_load() {
         smol::run(async {
@@ -245,6 +784,130 @@ mod tests {
         });
     }
 
+    // Temporary files left behind by a crash must be removed on open, but only while no other
+    // connection, which may still be writing its own, has the repository open
+    #[test]
+    fn stale_temp_files_removed() {
+        smol::run(async {
+            let key = Key::random(32);
+            let (tempdir, mut mf) = setup(&key).await;
+            let stale = tempdir.path().join(format!(
+                "{}stale",
+                crate::repository::backend::common::TEMP_FILE_PREFIX
+            ));
+            File::create(&stale).unwrap();
+            let mut other = MultiFile::open_defaults(tempdir.path(), None, &key, 4)
+                .await
+                .unwrap();
+            assert!(stale.exists());
+            other.close().await;
+            mf.close().await;
+            let mut mf = MultiFile::open_defaults(tempdir.path(), None, &key, 4)
+                .await
+                .unwrap();
+            assert!(!stale.exists());
+            assert!(!tempdir.path().join("lock").exists());
+            mf.close().await;
+        });
+    }
+
+    // The exclusive lock must only be taken while no other connection is open, and must keep new
+    // connections out until it is released
+    #[test]
+    fn exclusive_lock() {
+        smol::run(async {
+            let key = Key::random(32);
+            let (tempdir, mut mf) = setup(&key).await;
+            let mut other = MultiFile::open_defaults(tempdir.path(), None, &key, 4)
+                .await
+                .unwrap();
+            assert!(matches!(
+                mf.lock_exclusive().await,
+                Err(BackendError::RepositoryGloballyLocked(_))
+            ));
+            // A failed attempt must not leave the lock behind
+            assert!(!tempdir.path().join("lock").exists());
+            other.close().await;
+
+            mf.lock_exclusive().await.unwrap();
+            let blocked = MultiFile::open_defaults(tempdir.path(), None, &key, 4).await;
+            assert!(matches!(
+                blocked,
+                Err(BackendError::RepositoryGloballyLocked(_))
+            ));
+            mf.unlock_exclusive().await.unwrap();
+            let mut other = MultiFile::open_defaults(tempdir.path(), None, &key, 4)
+                .await
+                .unwrap();
+            other.close().await;
+            mf.close().await;
+        });
+    }
+
+    // Compacting must move the remaining chunks out of segments holding removed ones, delete those
+    // segments, and leave every remaining chunk readable through the index
+    #[test]
+    fn compact_frees_segments() {
+        smol::run(async {
+            let key = Key::random(32);
+            let (tempdir, mut mf) = setup(&key).await;
+            let mut index = mf.get_index();
+            let mut kept = Vec::new();
+            let mut removed = Vec::new();
+            for i in 0..6_u8 {
+                let chunk = Chunk::pack(
+                    vec![i; 1024],
+                    Compression::NoCompression,
+                    Encryption::NoEncryption,
+                    HMAC::Blake3,
+                    &key,
+                );
+                let id = chunk.get_id();
+                let location = mf.write_chunk(chunk).await.unwrap();
+                index.set_chunk(id, location).await.unwrap();
+                if i % 2 == 0 {
+                    kept.push((id, i));
+                } else {
+                    removed.push(id);
+                }
+            }
+            let old_segment = index
+                .lookup_chunk(kept[0].0)
+                .await
+                .unwrap()
+                .unwrap()
+                .segment_id;
+            for id in &removed {
+                mf.remove_chunk(*id).await.unwrap();
+            }
+            mf.sync().await.unwrap();
+
+            mf.lock_exclusive().await.unwrap();
+            assert_eq!(mf.compact().await.unwrap(), 1);
+            mf.unlock_exclusive().await.unwrap();
+            let old_path = tempdir
+                .path()
+                .join("data")
+                .join("0")
+                .join(old_segment.to_string());
+            assert!(!old_path.exists());
+            for (id, i) in &kept {
+                let location = index.lookup_chunk(*id).await.unwrap().unwrap();
+                assert_ne!(location.segment_id, old_segment);
+                let chunk = mf.read_chunk(location).await.unwrap();
+                assert_eq!(chunk.unpack(&key).unwrap(), vec![*i; 1024]);
+            }
+            for id in &removed {
+                assert!(index.lookup_chunk(*id).await.unwrap().is_none());
+            }
+            // Nothing is left to reclaim
+            mf.lock_exclusive().await.unwrap();
+            assert_eq!(mf.compact().await.unwrap(), 0);
+            mf.unlock_exclusive().await.unwrap();
+            mf.close().await;
+        });
+    }
+
     // Tests to make sure that readlocks are created and destroyed properly
     #[test]
     fn read_lock_create_destroy() {
@@ -261,4 +924,149 @@ mod tests {
             assert!(!lock_path.exists());
         });
     }
+
+    // Reading data that was never written should produce a typed not found error, rather than a
+    // generic one
+    #[test]
+    fn read_missing() {
+        smol::run(async {
+            let key = Key::random(32);
+            let (tempdir, mut mf) = setup(&key).await;
+            let location = SegmentDescriptor {
+                segment_id: 1000,
+                start: 0,
+            };
+            let result = mf.read_chunk(location).await;
+            assert!(matches!(result, Err(BackendError::SegmentNotFound(1000))));
+            let mut repo = crate::repository::Repository::with(
+                mf.clone(),
+                ChunkSettings::lightweight(),
+                key,
+                4,
+            )
+            .unwrap();
+            let error = repo
+                .read_chunk(crate::repository::ChunkID::random_id())
+                .await
+                .expect_err("Read a chunk that was never written");
+            assert!(error.is_not_found());
+            mf.close().await;
+        });
+    }
+
+    // Tests to make sure that listing archives in a lazily opened repository does not read the
+    // index
+    #[test]
+    fn lazy_open_list() {
+        smol::run(async {
+            let key = Key::random(32);
+            let (tempdir, mut mf) = setup(&key).await;
+            let archives: HashSet<StoredArchive> =
+                (0..10).map(|_| StoredArchive::dummy_archive()).collect();
+            let mut manifest = mf.get_manifest();
+            for archive in &archives {
+                manifest.write_archive(archive.clone()).await.unwrap();
+            }
+            mf.close().await;
+            std::mem::drop(mf);
+            // Move the index out of the way, so that any attempt to read it recreates it
+            let index_path = tempdir.path().join("index");
+            std::fs::rename(&index_path, tempdir.path().join("saved-index")).unwrap();
+            // Reopen the repository lazily and list the archives
+            let mut mf = MultiFile::open_lazy(tempdir.path(), None, &key, 4)
+                .await
+                .unwrap();
+            let output: HashSet<StoredArchive> =
+                mf.get_manifest().archive_iterator().await.collect();
+            assert_eq!(archives, output);
+            // The index should not have been touched
+            assert!(!index_path.exists());
+            mf.close().await;
+        });
+    }
+
+    // Chunks written before a sync must still be readable after the backend is dropped without
+    // being closed, as it would be if the process were killed
+    #[test]
+    fn sync_survives_drop() {
+        smol::run(async {
+            let key = Key::random(32);
+            let (tempdir, mf) = setup(&key).await;
+            let mut repo = crate::repository::Repository::with(
+                mf,
+                ChunkSettings::lightweight(),
+                key.clone(),
+                4,
+            )
+            .unwrap();
+            let mut ids = Vec::new();
+            for i in 0..10_u8 {
+                let (id, _) = repo.write_chunk(vec![i; 1024]).await.unwrap();
+                ids.push(id);
+            }
+            repo.sync().await.expect("Failed to sync repository");
+            std::mem::drop(repo);
+            let mf = MultiFile::open_defaults(tempdir.path(), None, &key, 4)
+                .await
+                .unwrap();
+            let mut repo =
+                crate::repository::Repository::with(mf, ChunkSettings::lightweight(), key, 4)
+                    .unwrap();
+            for (i, id) in (0..10_u8).zip(ids) {
+                let data = repo.read_chunk(id).await.expect("Synced chunk was lost");
+                assert_eq!(data, vec![i; 1024]);
+            }
+            repo.close().await;
+        });
+    }
+
+    // A repository created in the interleaved format must keep using it when reopened with the
+    // defaults
+    #[test]
+    fn interleaved_format_persists() {
+        smol::run(async {
+            let key = Key::random(32);
+            let tempdir = tempdir().unwrap();
+            let mf = MultiFile::open_with_segment_format(
+                tempdir.path(),
+                Some(ChunkSettings::lightweight()),
+                &key,
+                4,
+                SegmentFormat::Interleaved,
+            )
+            .await
+            .unwrap();
+            let mut repo = crate::repository::Repository::with(
+                mf,
+                ChunkSettings::lightweight(),
+                key.clone(),
+                4,
+            )
+            .unwrap();
+            let (id, _) = repo.write_chunk(vec![7_u8; 1024]).await.unwrap();
+            repo.close().await;
+            assert_eq!(
+                segment::read_segment_format(tempdir.path()).unwrap(),
+                SegmentFormat::Interleaved
+            );
+            let mf = MultiFile::open_defaults(tempdir.path(), None, &key, 4)
+                .await
+                .unwrap();
+            let mut repo =
+                crate::repository::Repository::with(mf, ChunkSettings::lightweight(), key, 4)
+                    .unwrap();
+            assert_eq!(repo.read_chunk(id).await.unwrap(), vec![7_u8; 1024]);
+            repo.close().await;
+        });
+    }
+
+    #[test]
+    fn ping() {
+        smol::run(async {
+            let key = Key::random(32);
+            let (_tempdir, mut mf) = setup(&key).await;
+            mf.ping().await.expect("Ping failed on an open backend");
+            mf.close().await;
+        });
+    }
 }
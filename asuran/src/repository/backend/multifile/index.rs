@@ -1,5 +1,7 @@
-use crate::repository::backend::common::{IndexTransaction, LockedFile};
-use crate::repository::backend::{self, BackendError, Result, SegmentDescriptor};
+use crate::repository::backend::common::{
+    numbered_files, rename_numbered, reply, write_temp_file, IndexTransaction,
+};
+use crate::repository::backend::{self, BackendError, Durability, Result, SegmentDescriptor};
 use crate::repository::ChunkID;
 
 use async_trait::async_trait;
@@ -9,18 +11,22 @@ use futures::sink::SinkExt;
 use futures::stream::StreamExt;
 use serde_cbor as cbor;
 use smol::block_on;
+use tracing::error;
 
 use std::collections::{HashMap, HashSet};
-use std::fs::{create_dir, read_dir, File};
-use std::io::{BufWriter, Seek, SeekFrom};
-use std::path::Path;
+use std::fs::{create_dir_all, File};
+use std::path::{Path, PathBuf};
 use std::thread;
 
 #[derive(Debug)]
 struct InternalIndex {
     state: HashMap<ChunkID, SegmentDescriptor>,
-    file: LockedFile,
     changes: Vec<IndexTransaction>,
+    durability: Durability,
+    /// The path of the index folder
+    path: PathBuf,
+    /// Directory the transactions of each commit are written to before being renamed into place
+    temp_dir: PathBuf,
 }
 
 impl InternalIndex {
@@ -28,7 +34,15 @@ impl InternalIndex {
     ///
     /// The index this creates is not thread safe, see `Index` for the thread safe implementation on
     /// top of this.
-    fn open(repository_path: impl AsRef<Path>) -> Result<InternalIndex> {
+    ///
+    /// Each commit is written to a new index file through a temporary file in `temp_dir`, and
+    /// fsynced as specified by `durability`, see `commit`.
+    fn open(
+        repository_path: impl AsRef<Path>,
+        durability: Durability,
+        temp_dir: impl AsRef<Path>,
+    ) -> Result<InternalIndex> {
+        let temp_dir = temp_dir.as_ref().to_path_buf();
         // construct the path of the index folder
         let index_path = repository_path.as_ref().join("index");
         // Check to see if it exists
@@ -41,88 +55,114 @@ impl InternalIndex {
                 )));
             }
         } else {
-            // Create the index directory
-            create_dir(&index_path)?;
-        }
-        // Create the state map
-        let mut state: HashMap<ChunkID, SegmentDescriptor> = HashMap::new();
-
-        // Get the list of files, and sort them by ID
-        let mut items = read_dir(&index_path)?
-            .filter_map(std::result::Result::ok)
-            .filter(|x| x.path().is_file())
-            .filter_map(|x| {
-                x.path()
-                    .file_name()?
-                    .to_str()
-                    .and_then(|y| std::result::Result::ok(y.parse::<usize>()))
-                    .map(|z| (z, x))
-            })
-            .collect::<Vec<_>>();
-        items.sort_by(|a, b| a.0.cmp(&b.0));
-
-        // Add all the seen transactions to our state hashmap
-        for (_, file) in &items {
-            // Open the file
-            let mut file = File::open(file.path())?;
-            // Keep deserializing transactions until we encouter an error
-            let de = cbor::Deserializer::from_reader(&mut file);
-            let mut de = de.into_iter::<IndexTransaction>();
-            while let Some(tx) = de.next().and_then(std::result::Result::ok) {
-                // Insert each item into the state
-                state.insert(tx.chunk_id, tx.descriptor);
-            }
+            // Create the index directory, tolerating someone else having created it first
+            create_dir_all(&index_path)?;
         }
-
-        // Check to see if there are any unlocked index files, and if so, use the first ones
-        for (_, file) in &items {
-            let locked_file = LockedFile::open_read_write(file.path())?;
-            if let Some(file) = locked_file {
-                return Ok(InternalIndex {
-                    state,
-                    file,
-                    changes: Vec::new(),
-                });
-            }
-        }
-
-        // If we have gotten here there are no unlocked index files, creating one
-
-        // Check the length of the items list, if it is empty, there are no index files,
-        // so we must create the first
-        let id = if items.is_empty() {
-            0
-        } else {
-            items[items.len() - 1].0 + 1
-        };
-
-        let path = index_path.join(id.to_string());
-        let file = LockedFile::open_read_write(path)?
-            .expect("Somehow, our newly created index file is locked.");
+        let state = read_state(&index_path)?;
         Ok(InternalIndex {
             state,
-            file,
             changes: Vec::new(),
+            durability,
+            path: index_path,
+            temp_dir,
         })
     }
 
-    /// Drains the changes out of the internal buffer and commits them to disk
-    fn drain_changes(&mut self) -> Result<()> {
-        let mut file = BufWriter::new(&mut self.file);
-        file.seek(SeekFrom::End(0))?;
-        for tx in self.changes.drain(0..self.changes.len()) {
-            cbor::ser::to_writer(&mut file, &tx)?;
+    /// Commits any outstanding changes, and then rereads every index file, picking up the changes
+    /// committed by other connections since this index was opened
+    fn reload(&mut self) -> Result<()> {
+        self.commit()?;
+        self.state = read_state(&self.path)?;
+        Ok(())
+    }
+
+    /// Writes the outstanding changes to a new temporary file, returning its path
+    ///
+    /// The index folder itself is not touched, see `commit`.
+    fn stage_changes(&mut self) -> Result<PathBuf> {
+        let mut contents = Vec::new();
+        for tx in &self.changes {
+            cbor::ser::to_writer(&mut contents, tx)?;
+        }
+        Ok(write_temp_file(&self.temp_dir, &contents, self.durability)?)
+    }
+
+    /// Commits any outstanding changes to disk
+    ///
+    /// Existing index files are never modified. The changes are staged in a temporary file,
+    /// fsynced if the index's durability calls for it, and then renamed into the index folder as
+    /// a new numbered file, so a crash part way through a commit leaves the previously committed
+    /// index intact. Does nothing if there are no outstanding changes.
+    fn commit(&mut self) -> Result<()> {
+        if self.changes.is_empty() {
+            return Ok(());
         }
+        let temp_path = self.stage_changes()?;
+        rename_numbered(&self.path, &temp_path, self.durability)?;
+        self.changes.clear();
         Ok(())
     }
 }
 
+/// Reads the state of the index out of every index file in the given index folder
+///
+/// Files are read in order of their ids, so later commits take precedence over earlier ones.
+/// Files whose names are not base 10 integers are ignored.
+fn read_state(index_path: &Path) -> Result<HashMap<ChunkID, SegmentDescriptor>> {
+    // Create the state map
+    let mut state: HashMap<ChunkID, SegmentDescriptor> = HashMap::new();
+
+    // Add all the seen transactions to our state hashmap
+    for (_, path) in numbered_files(index_path)? {
+        // Open the file
+        let mut file = File::open(path)?;
+        // Keep deserializing transactions until we encouter an error
+        let de = cbor::Deserializer::from_reader(&mut file);
+        let mut de = de.into_iter::<IndexTransaction>();
+        while let Some(tx) = de.next().and_then(std::result::Result::ok) {
+            // Insert each item into the state
+            if tx.removed {
+                state.remove(&tx.chunk_id);
+            } else {
+                state.insert(tx.chunk_id, tx.descriptor);
+            }
+        }
+    }
+    Ok(state)
+}
+
+/// An `InternalIndex` whose loading may be deferred until it is first used
+enum LazyIndex {
+    Loaded(InternalIndex),
+    Deferred(PathBuf, Durability, PathBuf),
+}
+
+impl LazyIndex {
+    /// Provides the underlying index, loading it first if needed
+    ///
+    /// # Errors
+    ///
+    /// Will return Err if the index was deferred, and loading it fails. See `InternalIndex::open`.
+    fn get(&mut self) -> Result<&mut InternalIndex> {
+        if let LazyIndex::Deferred(path, durability, temp_dir) = self {
+            let index = InternalIndex::open(path, *durability, temp_dir)?;
+            *self = LazyIndex::Loaded(index);
+        }
+        match self {
+            LazyIndex::Loaded(index) => Ok(index),
+            LazyIndex::Deferred(..) => unreachable!("Index was loaded but is still deferred"),
+        }
+    }
+}
+
 enum IndexCommand {
-    Lookup(ChunkID, oneshot::Sender<Option<SegmentDescriptor>>),
+    Lookup(ChunkID, oneshot::Sender<Result<Option<SegmentDescriptor>>>),
     Set(ChunkID, SegmentDescriptor, oneshot::Sender<Result<()>>),
-    KnownChunks(oneshot::Sender<HashSet<ChunkID>>),
+    Remove(ChunkID, oneshot::Sender<Result<()>>),
+    KnownChunks(oneshot::Sender<Result<HashSet<ChunkID>>>),
     Commit(oneshot::Sender<Result<()>>),
-    Count(oneshot::Sender<usize>),
+    Reload(oneshot::Sender<Result<()>>),
+    Count(oneshot::Sender<Result<usize>>),
     Close(oneshot::Sender<()>),
 }
 
@@ -152,25 +192,80 @@ impl Index {
     /// This method only creates the event loop on its own, the actual index is created by
     /// `InternalIndex::open`
     ///
+    /// Every commit of the index is written to a new index file, which will be fsynced as
+    /// specified by `durability`.
+    ///
     /// # Errors
     ///
     /// Will return Err if
     ///
     /// 1. The index folder does not exist and creating it failed
-    /// 2. There are no unlocked index files and creating one fails
-    /// 3. There is a file called "index" in the repository folder
-    /// 4. Some other IO error (such as lack of permissions) occurs
-    /// 5. The path contains non-utf8 characters
+    /// 2. There is a file called "index" in the repository folder
+    /// 3. Some other IO error (such as lack of permissions) occurs
+    /// 4. The path contains non-utf8 characters
     ///
     /// # TODOs:
     ///
     /// 1. Return an error if deserializing a transaction fails before the end of the file is reached
-    /// 2. This function can currently panic if we have to create a new index file, but someone else
-    ///    that while we were parsing the transaction. Resolution for this conflict needs to be
-    ///    implemented.
-    pub fn open(repository_path: impl AsRef<Path>, queue_depth: usize) -> Result<Index> {
+    pub fn open(
+        repository_path: impl AsRef<Path>,
+        queue_depth: usize,
+        durability: Durability,
+    ) -> Result<Index> {
+        Self::open_with_temp_dir(&repository_path, queue_depth, durability, &repository_path)
+    }
+
+    /// Opens and reads the index, as `open`, staging commits in `temp_dir` instead of the
+    /// repository directory
+    ///
+    /// `temp_dir` must be on the same filesystem as the repository, see `InternalIndex::commit`.
+    ///
+    /// # Errors
+    ///
+    /// Will return Err under the same conditions as `open`
+    pub fn open_with_temp_dir(
+        repository_path: impl AsRef<Path>,
+        queue_depth: usize,
+        durability: Durability,
+        temp_dir: impl AsRef<Path>,
+    ) -> Result<Index> {
         // Open the index
-        let mut index = InternalIndex::open(&repository_path)?;
+        let index = InternalIndex::open(&repository_path, durability, temp_dir)?;
+        Ok(Self::spawn(
+            LazyIndex::Loaded(index),
+            &repository_path,
+            queue_depth,
+        ))
+    }
+
+    /// Creates an index handle without reading the index
+    ///
+    /// The index folder is not read or created until the first command is sent to the
+    /// index, making this useful for operations that only need the manifest, such as listing
+    /// archives, on repositories with very large indexes.
+    ///
+    /// Any errors that would have been returned by `open` are instead reported by the first
+    /// operation that returns a `Result`, and loading is attempted again by the next operation.
+    /// Operations that can not report errors log them, and behave as if the index were empty.
+    ///
+    /// Commits are staged in `temp_dir`, see `open_with_temp_dir`.
+    pub fn open_lazy(
+        repository_path: impl AsRef<Path>,
+        queue_depth: usize,
+        durability: Durability,
+        temp_dir: impl AsRef<Path>,
+    ) -> Index {
+        let path = repository_path.as_ref().to_path_buf();
+        let temp_dir = temp_dir.as_ref().to_path_buf();
+        Self::spawn(
+            LazyIndex::Deferred(path, durability, temp_dir),
+            &repository_path,
+            queue_depth,
+        )
+    }
+
+    /// Creates the event processing loop for the given index in its own thread
+    fn spawn(mut index: LazyIndex, repository_path: impl AsRef<Path>, queue_depth: usize) -> Index {
         // Create the communication channel and open the event processing loop in it own task
         let (input, mut output) = mpsc::channel(queue_depth);
         thread::spawn(move || {
@@ -178,27 +273,51 @@ impl Index {
             while let Some(command) = block_on(output.next()) {
                 match command {
                     IndexCommand::Lookup(id, ret) => {
-                        ret.send(index.state.get(&id).copied()).unwrap();
-                    }
-                    IndexCommand::Set(id, descriptor, ret) => {
-                        // TODO: dont insert the item into the changes list if it its already in the index
-                        index.state.insert(id, descriptor);
-                        let transaction = IndexTransaction {
-                            chunk_id: id,
-                            descriptor,
-                        };
-                        index.changes.push(transaction);
-                        ret.send(Ok(())).unwrap();
+                        let result = index.get().map(|index| index.state.get(&id).copied());
+                        reply(ret, result, "lookup");
                     }
+                    IndexCommand::Set(id, descriptor, ret) => match index.get() {
+                        Ok(index) => {
+                            // TODO: dont insert the item into the changes list if it its already in the index
+                            index.state.insert(id, descriptor);
+                            let transaction = IndexTransaction {
+                                chunk_id: id,
+                                descriptor,
+                                removed: false,
+                            };
+                            index.changes.push(transaction);
+                            reply(ret, Ok(()), "set chunk");
+                        }
+                        Err(e) => reply(ret, Err(e), "set chunk"),
+                    },
+                    IndexCommand::Remove(id, ret) => match index.get() {
+                        Ok(index) => {
+                            if let Some(descriptor) = index.state.remove(&id) {
+                                index.changes.push(IndexTransaction {
+                                    chunk_id: id,
+                                    descriptor,
+                                    removed: true,
+                                });
+                            }
+                            reply(ret, Ok(()), "remove chunk");
+                        }
+                        Err(e) => reply(ret, Err(e), "remove chunk"),
+                    },
                     IndexCommand::KnownChunks(ret) => {
-                        ret.send(index.state.keys().copied().collect::<HashSet<_>>())
-                            .unwrap();
+                        let result = index
+                            .get()
+                            .map(|index| index.state.keys().copied().collect::<HashSet<_>>());
+                        reply(ret, result, "known chunks");
                     }
                     IndexCommand::Count(ret) => {
-                        ret.send(index.state.len()).unwrap();
+                        let result = index.get().map(|index| index.state.len());
+                        reply(ret, result, "count");
                     }
                     IndexCommand::Commit(ret) => {
-                        ret.send(index.drain_changes()).unwrap();
+                        reply(ret, index.get().and_then(InternalIndex::commit), "commit");
+                    }
+                    IndexCommand::Reload(ret) => {
+                        reply(ret, index.get().and_then(InternalIndex::reload), "reload");
                     }
                     IndexCommand::Close(ret) => {
                         final_ret = Some(ret);
@@ -211,14 +330,33 @@ impl Index {
             std::mem::drop(index);
             std::mem::drop(output);
             if let Some(ret) = final_ret {
-                ret.send(()).unwrap();
+                reply(ret, (), "close");
             };
         });
 
-        Ok(Index {
+        Index {
             input,
             path: repository_path.as_ref().to_str().unwrap().to_string(),
-        })
+        }
+    }
+
+    /// Removes a chunk from the index
+    ///
+    /// The removal is recorded as a transaction in the index, and, like other changes, will not
+    /// be persisted until the index is committed. The space used by the chunk in its segment is
+    /// only reclaimed once the segment is compacted, see `SegmentHandler::compact`. Removing a chunk that is not in the index does nothing.
+    pub async fn remove_chunk(&mut self, id: ChunkID) -> Result<()> {
+        let (input, output) = oneshot::channel();
+        self.input.send(IndexCommand::Remove(id, input)).await?;
+        output.await?
+    }
+
+    /// Commits any outstanding changes, and then rereads the index from disk, picking up the
+    /// changes committed by other connections since this one was opened
+    pub async fn reload(&mut self) -> Result<()> {
+        let (input, output) = oneshot::channel();
+        self.input.send(IndexCommand::Reload(input)).await?;
+        output.await?
     }
 
     pub async fn close(&mut self) {
@@ -240,15 +378,10 @@ impl std::fmt::Debug for Index {
 
 #[async_trait]
 impl backend::Index for Index {
-    async fn lookup_chunk(&mut self, id: ChunkID) -> Option<SegmentDescriptor> {
+    async fn lookup_chunk(&mut self, id: ChunkID) -> Result<Option<SegmentDescriptor>> {
         let (input, output) = oneshot::channel();
-        self.input
-            .send(IndexCommand::Lookup(id, input))
-            .await
-            .expect("Unable to communicate with index task.");
-        output
-            .await
-            .expect("Unable to communicate with index task.")
+        self.input.send(IndexCommand::Lookup(id, input)).await?;
+        output.await?
     }
     async fn set_chunk(&mut self, id: ChunkID, location: SegmentDescriptor) -> Result<()> {
         let (input, output) = oneshot::channel();
@@ -263,9 +396,13 @@ impl backend::Index for Index {
             .send(IndexCommand::KnownChunks(input))
             .await
             .expect("Unable to communicate with index task.");
-        output
+        let result = output
             .await
-            .expect("Unable to communicate with index task.")
+            .expect("Unable to communicate with index task.");
+        result.unwrap_or_else(|e| {
+            error!("Unable to load index for listing known chunks: {}", e);
+            HashSet::new()
+        })
     }
     async fn commit_index(&mut self) -> Result<()> {
         let (input, output) = oneshot::channel();
@@ -278,15 +415,20 @@ impl backend::Index for Index {
             .send(IndexCommand::Count(input))
             .await
             .expect("Unable to communicate with index task.");
-        output
+        let result = output
             .await
-            .expect("Unable to communicate with index task.")
+            .expect("Unable to communicate with index task.");
+        result.unwrap_or_else(|e| {
+            error!("Unable to load index for counting chunks: {}", e);
+            0
+        })
     }
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::repository::backend::common::TEMP_FILE_PREFIX;
     use backend::Index as OtherIndex;
     use rand;
     use rand::prelude::*;
@@ -304,14 +446,13 @@ mod tests {
     // Test to make sure creating an index in an empty folder
     // 1. Doesn't Panic or error
     // 2. Creates the index directory
-    // 3. Creates the initial index file (index/0)
-    // 4. Locks the initial index file (index/0.lock)
+    // 3. Does not create any index files until something is committed
     #[test]
     fn creation_works() {
         smol::run(async {
             let (tempdir, path) = setup();
             // Create the index
-            let index = Index::open(&path, 4).expect("Index creation failed");
+            let mut index = Index::open(&path, 4, Durability::Full).expect("Index creation failed");
             // Walk the directory and print some debugging info
             for entry in WalkDir::new(&path) {
                 let entry = entry.unwrap();
@@ -321,61 +462,169 @@ mod tests {
             let index_dir = path.join("index");
             assert!(index_dir.exists());
             assert!(index_dir.is_dir());
-            // Check for the initial index file
-            let index_file = index_dir.join("0");
-            assert!(index_file.exists());
-            assert!(index_file.is_file());
-            // Check for the initial index lock file
-            let index_lock = index_dir.join("0.lock");
-            assert!(index_lock.exists());
-            assert!(index_lock.is_file());
+            // Committing nothing writes nothing
+            index.commit_index().await.unwrap();
+            assert!(numbered_files(&index_dir).unwrap().is_empty());
+            index.close().await;
         });
     }
 
-    // Test to make sure creating a second index while the first is open
-    // 1. Doesn't panic or error
-    // 2. Creates and locks a second index file
+    // Test to make sure that every commit is written to a new index file, and that no temporary
+    // files are left behind
     #[test]
-    fn double_creation_works() {
+    fn commit_creates_new_file() {
         smol::run(async {
             let (tempdir, path) = setup();
-            // Create the first index
-            let index1 = Index::open(&path, 4).expect("Index 1 creation failed");
-            let index2 = Index::open(&path, 4).expect("Index 2 creation failed");
-            // Walk the directory and print some debugging info
+            let desc = SegmentDescriptor {
+                segment_id: 1,
+                start: 2,
+            };
+            let mut index = Index::open(&path, 4, Durability::Full).expect("Index creation failed");
+            for expected in 0..3 {
+                index.set_chunk(ChunkID::random_id(), desc).await.unwrap();
+                index.commit_index().await.unwrap();
+                let files = numbered_files(&path.join("index")).unwrap();
+                let ids = files.iter().map(|(id, _)| *id).collect::<Vec<_>>();
+                assert_eq!(ids, (0..=expected).collect::<Vec<_>>());
+            }
+            index.close().await;
             for entry in WalkDir::new(&path) {
                 let entry = entry.unwrap();
-                println!("{}", entry.path().display());
+                let name = entry.file_name().to_string_lossy();
+                assert!(!name.starts_with(TEMP_FILE_PREFIX), "{}", name);
+            }
+        });
+    }
+
+    // Test to make sure that two indexes racing to commit to the same repository
+    // 1. Both succeed
+    // 2. Each get their own index file
+    // 3. Both of their writes are visible when the index is reopened
+    #[test]
+    fn concurrent_creation_works() {
+        smol::run(async {
+            let (tempdir, path) = setup();
+            let tasks = (0..2)
+                .map(|_| {
+                    let path = path.clone();
+                    smol::Task::blocking(async move {
+                        let mut index = Index::open(&path, 4, Durability::Full)
+                            .expect("Concurrent index creation failed");
+                        let id = ChunkID::random_id();
+                        let desc = SegmentDescriptor {
+                            segment_id: 1,
+                            start: 2,
+                        };
+                        index.set_chunk(id, desc).await.unwrap();
+                        index.commit_index().await.unwrap();
+                        (index, id)
+                    })
+                })
+                .collect::<Vec<_>>();
+            let mut indexes = Vec::new();
+            for task in tasks {
+                indexes.push(task.await);
             }
-            // Get index dir and check for index files
+            // Both commits must have ended up in distinct files
             let index_dir = path.join("index");
-            let if1 = index_dir.join("0");
-            let if2 = index_dir.join("1");
-            let il1 = index_dir.join("0.lock");
-            let il2 = index_dir.join("1.lock");
-            assert!(if1.exists() && if1.is_file());
-            assert!(if2.exists() && if2.is_file());
-            assert!(il1.exists() && il1.is_file());
-            assert!(il2.exists() && il2.is_file());
+            assert_eq!(numbered_files(&index_dir).unwrap().len(), 2);
+            let mut ids = Vec::new();
+            for (mut index, id) in indexes {
+                index.close().await;
+                ids.push(id);
+            }
+            // Reopen the index and make sure it has seen both writes
+            let mut index =
+                Index::open(&path, 4, Durability::Full).expect("Index recreation failed");
+            assert_eq!(index.count_chunk().await, 2);
+            for id in ids {
+                assert!(index.lookup_chunk(id).await.unwrap().is_some());
+            }
+            index.close().await;
         });
     }
 
-    // Test to make sure that dropping an Index unlocks the index file
-    // Note: since we are using a single threaded executor, we must manually run all tasks to
-    // completion.
+    // Test to make sure that removals survive reopening the index
     #[test]
-    fn unlock_on_drop() {
+    fn remove_drop_read() {
         smol::run(async {
             let (tempdir, path) = setup();
-            // Open an index and drop it
-            let mut index = Index::open(&path, 4).expect("Index creation failed");
+            let kept = ChunkID::random_id();
+            let removed = ChunkID::random_id();
+            let desc = SegmentDescriptor {
+                segment_id: 1,
+                start: 2,
+            };
+            let mut index = Index::open(&path, 4, Durability::Full).expect("Index creation failed");
+            index.set_chunk(kept, desc).await.unwrap();
+            index.set_chunk(removed, desc).await.unwrap();
+            index.commit_index().await.unwrap();
+            index.remove_chunk(removed).await.unwrap();
+            assert_eq!(index.lookup_chunk(removed).await.unwrap(), None);
+            index.commit_index().await.unwrap();
             index.close().await;
-            // check for the index file and the absense of the lock file
+            // Load the index back up, the removal should still be in effect
+            let mut index =
+                Index::open(&path, 4, Durability::Full).expect("Index recreation failed");
+            assert_eq!(index.count_chunk().await, 1);
+            assert_eq!(index.lookup_chunk(kept).await.unwrap(), Some(desc));
+            assert_eq!(index.lookup_chunk(removed).await.unwrap(), None);
+            index.close().await;
+        });
+    }
+
+    // Test to make sure that a lazily opened index does not touch the index folder until it is
+    // used, and then behaves like a normally opened one
+    #[test]
+    fn lazy_open() {
+        smol::run(async {
+            let (tempdir, path) = setup();
+            // Write a transaction with a normal index
+            let id = ChunkID::random_id();
+            let desc = SegmentDescriptor {
+                segment_id: 1,
+                start: 2,
+            };
+            let mut index = Index::open(&path, 4, Durability::Full).expect("Index creation failed");
+            index.set_chunk(id, desc).await.unwrap();
+            index.commit_index().await.unwrap();
+            index.close().await;
+            // Remove the index folder, opening the index lazily should not recreate it
             let index_dir = path.join("index");
-            let index_file = index_dir.join("0");
-            let index_lock = index_dir.join("0.lock");
-            assert!(index_file.exists() && index_file.is_file());
-            assert!(!index_lock.exists());
+            let saved = path.join("saved-index");
+            std::fs::rename(&index_dir, &saved).unwrap();
+            let mut index = Index::open_lazy(&path, 4, Durability::Full, &path);
+            assert!(!index_dir.exists());
+            std::fs::rename(&saved, &index_dir).unwrap();
+            // Using the index should load it
+            let location = index
+                .lookup_chunk(id)
+                .await
+                .unwrap()
+                .expect("Tx retrieve failed");
+            assert_eq!(desc, location);
+            index.close().await;
+        });
+    }
+
+    // A lazily opened index that fails to load must report the failure, and keep serving
+    // requests, rather than taking down the index thread
+    #[test]
+    fn lazy_open_failure() {
+        smol::run(async {
+            let (tempdir, path) = setup();
+            // A file where the index directory should be makes loading fail
+            File::create(path.join("index")).unwrap();
+            let mut index = Index::open_lazy(&path, 4, Durability::Full, &path);
+            let desc = SegmentDescriptor {
+                segment_id: 1,
+                start: 2,
+            };
+            assert!(index.set_chunk(ChunkID::random_id(), desc).await.is_err());
+            assert!(index.lookup_chunk(ChunkID::random_id()).await.is_err());
+            assert_eq!(index.count_chunk().await, 0);
+            assert!(index.commit_index().await.is_err());
+            index.close().await;
         });
     }
 
@@ -400,7 +649,7 @@ mod tests {
                 txs.insert(chunk_id, descriptor);
             }
             // Open the index
-            let mut index = Index::open(&path, 4).expect("Index creation failed");
+            let mut index = Index::open(&path, 4, Durability::Full).expect("Index creation failed");
             // Insert the transactions
             for (id, desc) in &txs {
                 index
@@ -416,7 +665,8 @@ mod tests {
             // Drop the index and let it complete
             index.close().await;
             // Load the index back up
-            let mut index = Index::open(&path, 4).expect("Index recreation failed");
+            let mut index =
+                Index::open(&path, 4, Durability::Full).expect("Index recreation failed");
             // Walk the directory and print some debugging info
             for entry in WalkDir::new(&path) {
                 let entry = entry.unwrap();
@@ -427,9 +677,46 @@ mod tests {
             assert_eq!(count, txs.len());
             // Confirm that each tx is in the index and has the correct value
             for (id, desc) in txs {
-                let location = index.lookup_chunk(id).await.expect("Tx retrieve failed");
+                let location = index
+                    .lookup_chunk(id)
+                    .await
+                    .unwrap()
+                    .expect("Tx retrieve failed");
                 assert_eq!(desc, location);
             }
         });
     }
+
+    // A crash after the new contents of the index have been staged, but before they were renamed
+    // into place, must leave the previously committed index intact
+    #[test]
+    fn crash_before_rename_keeps_committed_index() {
+        let (_tempdir, path) = setup();
+        let temp_dir = path.join("tmp");
+        let committed = ChunkID::new(&[1; 32]);
+        let uncommitted = ChunkID::new(&[2; 32]);
+        let descriptor = SegmentDescriptor {
+            segment_id: 0,
+            start: 0,
+        };
+        let transaction = |chunk_id| IndexTransaction {
+            chunk_id,
+            descriptor,
+            removed: false,
+        };
+
+        let mut index = InternalIndex::open(&path, Durability::Full, &temp_dir).unwrap();
+        index.changes.push(transaction(committed));
+        index.commit().unwrap();
+        // Stage a second commit, then crash before renaming it into place
+        index.changes.push(transaction(uncommitted));
+        let staged = index.stage_changes().unwrap();
+        assert!(staged.starts_with(&temp_dir));
+        std::mem::drop(index);
+
+        let index = InternalIndex::open(&path, Durability::Full, &temp_dir).unwrap();
+        assert_eq!(index.state.len(), 1);
+        assert_eq!(index.state.get(&committed), Some(&descriptor));
+        assert!(!index.state.contains_key(&uncommitted));
+    }
 }
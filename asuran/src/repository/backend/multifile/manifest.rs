@@ -1,9 +1,14 @@
 #![allow(dead_code)]
 use crate::manifest::StoredArchive;
+use crate::repository::audit::AuditHead;
 use crate::repository::backend::{
     self,
-    common::{LockedFile, ManifestID, ManifestTransaction},
-    BackendError, Result,
+    common::{
+        archive_transactions, dangling_parents, find_heads, latest_access, latest_audit_head,
+        numbered_files, paged_archive_stream, rename_numbered, reply, verify_transactions,
+        write_temp_file, DanglingParent, LockedFile, ManifestID, ManifestTransaction,
+    },
+    BackendError, Durability, Result,
 };
 use crate::repository::{ChunkSettings, Key};
 
@@ -12,26 +17,33 @@ use chrono::prelude::*;
 use futures::channel::mpsc;
 use futures::channel::oneshot;
 use futures::sink::SinkExt;
-use futures::stream::StreamExt;
-use petgraph::Graph;
+use futures::stream::{LocalBoxStream, StreamExt};
 use serde_cbor as cbor;
 use smol::block_on;
 
 use std::collections::{HashMap, HashSet};
-use std::fs::{create_dir, read_dir, File};
-use std::io::{Seek, SeekFrom};
+use std::fs::{create_dir_all, File};
 use std::path::{Path, PathBuf};
 use std::thread;
+use std::time::Duration;
+
+/// Number of times to try locking `chunk.settings` before giving up
+const SETTINGS_LOCK_ATTEMPTS: usize = 50;
+/// How long to wait between attempts to lock `chunk.settings`
+const SETTINGS_LOCK_WAIT: Duration = Duration::from_millis(10);
 
 #[derive(Debug)]
 struct InternalManifest {
     known_entries: HashMap<ManifestID, ManifestTransaction>,
     verified_memo_pad: HashSet<ManifestID>,
     heads: Vec<ManifestID>,
-    file: LockedFile,
     key: Key,
     chunk_settings: ChunkSettings,
     path: PathBuf,
+    durability: Durability,
+    /// Directory transactions, and new contents of the chunk settings, are written to before
+    /// being renamed into place
+    temp_dir: PathBuf,
 }
 
 impl InternalManifest {
@@ -42,12 +54,19 @@ impl InternalManifest {
     ///
     /// Optionally sets the chunk settings.
     ///
+    /// Each transaction is written to a new manifest file, and the chunk settings are replaced
+    /// atomically, through temporary files in `temp_dir`, and fsynced as specified by
+    /// `durability`.
+    ///
     /// Will return error if this is a new repository and the chunk settings are not set
     fn open(
         repository_path: impl AsRef<Path>,
         key: &Key,
         settings: Option<ChunkSettings>,
+        durability: Durability,
+        temp_dir: impl AsRef<Path>,
     ) -> Result<InternalManifest> {
+        let temp_dir = temp_dir.as_ref().to_path_buf();
         // Construct the path of the manifest folder
         let manifest_path = repository_path.as_ref().join("manifest");
         // Check to see if it exists
@@ -60,71 +79,31 @@ impl InternalManifest {
                 )));
             }
         } else {
-            // Create the manifest directory
-            create_dir(&manifest_path)?;
-        }
-
-        // Get the list of manifest files and sort them by ID
-        let mut items = read_dir(&manifest_path)?
-            .filter_map(std::result::Result::ok)
-            .filter(|x| x.path().is_file())
-            .filter_map(|x| {
-                x.path()
-                    .file_name()?
-                    .to_str()
-                    .and_then(|y| std::result::Result::ok(y.parse::<usize>()))
-                    .map(|z| (z, x))
-            })
-            .collect::<Vec<_>>();
-        items.sort_by(|a, b| a.0.cmp(&b.0));
-
-        // Collect all known transactions
-        let mut known_entries = HashMap::new();
-        for (_, file) in &items {
-            // Open the file
-            let mut file = File::open(file.path())?;
-            // Keep deserializing transactions until we encounter an error
-            let de = cbor::Deserializer::from_reader(&mut file);
-            let mut de = de.into_iter::<ManifestTransaction>();
-            while let Some(tx) = de.next().and_then(std::result::Result::ok) {
-                known_entries.insert(tx.tag(), tx);
-            }
+            // Create the manifest directory, tolerating someone else having created it first
+            create_dir_all(&manifest_path)?;
         }
 
-        let mut file = None;
-        // Attempt to find an unlocked file
-        for (_, f) in &items {
-            let locked_file = LockedFile::open_read_write(f.path())?;
-            if let Some(f) = locked_file {
-                file = Some(f);
-                break;
-            }
-        }
-
-        // If we were unable to find an unlocked file, go ahead and make one
-        let file = if let Some(file) = file {
-            file
-        } else {
-            let id = if items.is_empty() {
-                0
-            } else {
-                items[items.len() - 1].0 + 1
-            };
-            let path = manifest_path.join(id.to_string());
-            LockedFile::open_read_write(path)?
-                .expect("Somehow, our newly created manifest file is locked")
-        };
+        let known_entries = read_transactions(&manifest_path)?;
 
         let chunk_settings = if let Some(chunk_settings) = settings {
-            // Attempt to open the chunk settings file and update it
-            let mut sfile = LockedFile::open_read_write(manifest_path.join("chunk.settings"))?
-                .ok_or_else(|| {
-                    BackendError::ManifestError("Unable to lock chunk.settings".to_string())
-                })?;
-            // Clear the file
-            sfile.set_len(0)?;
-            // Write our new chunksettings
-            cbor::ser::to_writer(&mut sfile, &chunk_settings)?;
+            // Attempt to open the chunk settings file and update it. The lock is only ever held
+            // for the length of a single write, so if someone else has it, give them a moment to
+            // finish before giving up.
+            let settings_path = manifest_path.join("chunk.settings");
+            let mut sfile = None;
+            for _ in 0..SETTINGS_LOCK_ATTEMPTS {
+                sfile = LockedFile::open_read_write(&settings_path)?;
+                if sfile.is_some() {
+                    break;
+                }
+                thread::sleep(SETTINGS_LOCK_WAIT);
+            }
+            let mut sfile = sfile.ok_or_else(|| {
+                BackendError::ManifestError("Unable to lock chunk.settings".to_string())
+            })?;
+            // Replace the file with our new chunksettings
+            let bytes = cbor::ser::to_vec(&chunk_settings)?;
+            sfile.replace_contents(&bytes, &temp_dir, durability)?;
             chunk_settings
         } else {
             let mut sfile = File::open(manifest_path.join("chunk.settings"))?;
@@ -136,83 +115,58 @@ impl InternalManifest {
             known_entries,
             verified_memo_pad: HashSet::new(),
             heads: Vec::new(),
-            file,
             key: key.clone(),
             chunk_settings,
             path: manifest_path,
+            durability,
+            temp_dir,
         };
+        manifest.verify_heads()?;
+
+        // Return the manifest
+        Ok(manifest)
+    }
+
+    /// Builds the list of heads, and verifies each of them, along with everything they reference
+    fn verify_heads(&mut self) -> Result<()> {
         // Build the list of heads
-        manifest.build_heads();
+        self.build_heads();
         // Verify each head
-        for head in manifest.heads.clone() {
-            if !manifest.verify_tx(head) {
+        for head in self.heads.clone() {
+            if !self.verify_tx(head) {
                 return Err(BackendError::ManifestError(format!(
                     "Manifest Transaction failed verification! {:?}",
-                    manifest.known_entries.get(&head).ok_or_else(|| BackendError::Unknown("Failed to get the head of the known entries list while reporting an error".to_string()))?
+                    self.known_entries.get(&head).ok_or_else(|| BackendError::Unknown("Failed to get the head of the known entries list while reporting an error".to_string()))?
                 )));
             }
         }
+        Ok(())
+    }
 
-        // Return the manifest
-        Ok(manifest)
+    /// Rereads every manifest file, picking up the transactions written by other connections
+    /// since this manifest was opened
+    fn reload(&mut self) -> Result<()> {
+        self.known_entries = read_transactions(&self.path)?;
+        self.verified_memo_pad.clear();
+        self.verify_heads()
     }
 
     /// Gets the heads from a list of transactions
+    ///
+    /// Transactions referencing a previous head that is missing are treated as roots, see
+    /// `find_heads`
     fn build_heads(&mut self) {
-        // Create the graph
-        let mut graph: Graph<ManifestID, ()> = Graph::new();
-        let mut index_map = HashMap::new();
-        // Add each transaction to our map
-        for (id, tx) in &self.known_entries {
-            let tag = tx.tag();
-            let id = graph.add_node(tag);
-            index_map.insert(tag, id);
-        }
-        // Go through each transaction in the graph, adding an edge in the new -> old direction
-        // These unwraps are safe because we just added these entries to our hashmap
-        for tx in self.known_entries.values() {
-            let id = index_map.get(&tx.tag()).unwrap();
-            for other_tx in tx.previous_heads() {
-                let other_id = index_map.get(&other_tx).unwrap();
-                graph.update_edge(*id, *other_id, ());
-            }
-        }
-        // reverse all the nodes, so they now point from old to new
-        graph.reverse();
-        // Find all nodes with no outgoing edges, these are our heads
-        let mut heads = Vec::new();
-        for (tag, id) in &index_map {
-            let mut edges = graph.edges(*id);
-            if edges.next() == None {
-                heads.push(*tag);
-            }
-        }
-
-        self.heads = heads;
+        self.heads = find_heads(&self.known_entries);
     }
 
-    /// Recursivly verifies a transaction and all its parents
+    /// Verifies a transaction and all of its parents, see `verify_transactions`
     fn verify_tx(&mut self, id: ManifestID) -> bool {
-        if self.verified_memo_pad.contains(&id) {
-            true
-        } else {
-            let tx = self
-                .known_entries
-                .get(&id)
-                .expect("Item in verified memo pad was not in known_entries")
-                .clone();
-            if tx.verify(&self.key) {
-                self.verified_memo_pad.insert(id);
-                for parent in tx.previous_heads() {
-                    if !self.verify_tx(*parent) {
-                        return false;
-                    }
-                }
-                true
-            } else {
-                false
-            }
-        }
+        verify_transactions(
+            &self.known_entries,
+            id,
+            &self.key,
+            &mut self.verified_memo_pad,
+        )
     }
 
     /// Returns the last modification timestamp of the manifest
@@ -226,13 +180,13 @@ impl InternalManifest {
                 .known_entries
                 .get(&self.heads[0])
                 .expect("Item in heads was not in known entries");
-            let mut max = first_head.timestamp();
+            let mut max = first_head.modified();
             for id in &self.heads {
                 let tx = self.known_entries.get(id).ok_or_else(|| {
                     BackendError::ManifestError("Unable to load timestamp".to_string())
                 })?;
-                if tx.timestamp() > max {
-                    max = tx.timestamp()
+                if tx.modified() > max {
+                    max = tx.modified()
                 }
             }
             Ok(max)
@@ -246,30 +200,49 @@ impl InternalManifest {
 
     /// Returns an iterator over the archives in this repository
     fn archive_iterator(&self) -> std::vec::IntoIter<StoredArchive> {
-        let mut items = self.known_entries.values().cloned().collect::<Vec<_>>();
-        items.sort_by(|a, b| a.timestamp().cmp(&b.timestamp()));
-        items.reverse();
-        items
+        archive_transactions(self.known_entries.values())
             .into_iter()
             .map(StoredArchive::from)
             .collect::<Vec<_>>()
             .into_iter()
     }
 
+    /// Returns up to `count` archives, starting `offset` archives into the order of
+    /// `archive_iterator`
+    fn archive_page(&self, offset: usize, count: usize) -> Vec<StoredArchive> {
+        archive_transactions(self.known_entries.values())
+            .into_iter()
+            .skip(offset)
+            .take(count)
+            .map(StoredArchive::from)
+            .collect()
+    }
+
     /// Sets the chunk settings
     fn write_chunk_settings(&mut self, settings: ChunkSettings) -> Result<()> {
         let mut sfile =
             LockedFile::open_read_write(self.path.join("chunk.settings"))?.ok_or_else(|| {
                 BackendError::Unknown("Failed to open chunk settings file for writing.".to_string())
             })?;
-        // Clear the file
-        sfile.set_len(0)?;
-        // Write our new chunksettings
-        cbor::ser::to_writer(&mut sfile, &settings)?;
+        // Replace the file with our new chunksettings
+        let bytes = cbor::ser::to_vec(&settings)?;
+        sfile.replace_contents(&bytes, &self.temp_dir, self.durability)?;
         self.chunk_settings = settings;
         Ok(())
     }
 
+    /// Writes a transaction to a new manifest file
+    ///
+    /// Existing manifest files are never modified. The transaction is written to a temporary file,
+    /// which is then renamed into the manifest folder, so a crash part way through leaves the
+    /// manifest as it was.
+    fn append_transaction(&mut self, tx: &ManifestTransaction) -> Result<()> {
+        let contents = cbor::ser::to_vec(tx)?;
+        let temp_path = write_temp_file(&self.temp_dir, &contents, self.durability)?;
+        rename_numbered(&self.path, &temp_path, self.durability)?;
+        Ok(())
+    }
+
     /// Adds an archive to the manifest
     #[allow(clippy::needless_pass_by_value)]
     fn write_archive(&mut self, archive: StoredArchive) -> Result<()> {
@@ -282,9 +255,7 @@ impl InternalManifest {
             &self.key,
         );
         // Write the transaction to the file
-        let file = &mut self.file;
-        file.seek(SeekFrom::End(0))?;
-        cbor::ser::to_writer(file, &tx)?;
+        self.append_transaction(&tx)?;
         // Add the transaction to our entries list
         let id = tx.tag();
         self.known_entries.insert(id, tx);
@@ -292,14 +263,109 @@ impl InternalManifest {
         self.heads = vec![id];
         Ok(())
     }
+
+    /// Records an access of an archive with a new transaction
+    fn touch_archive(&mut self, archive: &StoredArchive) -> Result<()> {
+        let tx = ManifestTransaction::new_access(
+            &self.heads,
+            archive.id(),
+            archive.timestamp(),
+            Local::now().with_timezone(Local::now().offset()),
+            self.chunk_settings.hmac,
+            &self.key,
+        );
+        self.append_transaction(&tx)?;
+        let id = tx.tag();
+        self.known_entries.insert(id, tx);
+        self.heads = vec![id];
+        Ok(())
+    }
+
+    /// Returns the latest recorded access of an archive
+    fn last_access(&self, archive: &StoredArchive) -> Option<DateTime<FixedOffset>> {
+        latest_access(self.known_entries.values(), archive.id())
+    }
+
+    /// Records the removal of an archive with a new transaction
+    fn delete_archive(&mut self, archive: &StoredArchive) -> Result<()> {
+        let tx = ManifestTransaction::new_deletion(
+            &self.heads,
+            archive.id(),
+            archive.timestamp(),
+            self.chunk_settings.hmac,
+            &self.key,
+        );
+        self.append_transaction(&tx)?;
+        let id = tx.tag();
+        self.known_entries.insert(id, tx);
+        self.heads = vec![id];
+        Ok(())
+    }
+
+    /// Records the head of the audit log with a new transaction
+    fn write_audit_head(&mut self, head: AuditHead) -> Result<()> {
+        let tx = ManifestTransaction::new_audit_head(
+            &self.heads,
+            head,
+            Local::now().with_timezone(Local::now().offset()),
+            self.chunk_settings.hmac,
+            &self.key,
+        );
+        self.append_transaction(&tx)?;
+        let id = tx.tag();
+        self.known_entries.insert(id, tx);
+        self.heads = vec![id];
+        Ok(())
+    }
+
+    /// Returns the latest recorded head of the audit log
+    fn audit_head(&self) -> Option<AuditHead> {
+        latest_audit_head(self.known_entries.values())
+    }
+
+    /// Returns every reference to a previous head that is missing, see `dangling_parents`
+    fn dangling_parents(&self) -> Vec<DanglingParent> {
+        dangling_parents(&self.known_entries)
+    }
+}
+
+/// Reads every transaction out of every manifest file in the given manifest folder
+///
+/// Returns the transactions keyed by their tags. Files whose names are not base 10 integers are
+/// ignored.
+fn read_transactions(manifest_path: &Path) -> Result<HashMap<ManifestID, ManifestTransaction>> {
+    // Collect all known transactions
+    let mut known_entries = HashMap::new();
+    for (_, path) in numbered_files(manifest_path)? {
+        // Open the file
+        let mut file = File::open(path)?;
+        // Keep deserializing transactions until we encounter an error
+        let de = cbor::Deserializer::from_reader(&mut file);
+        let mut de = de.into_iter::<ManifestTransaction>();
+        while let Some(tx) = de.next().and_then(std::result::Result::ok) {
+            known_entries.insert(tx.tag(), tx);
+        }
+    }
+    Ok(known_entries)
 }
 
 enum ManifestCommand {
     LastMod(oneshot::Sender<Result<DateTime<FixedOffset>>>),
     ChunkSettings(oneshot::Sender<ChunkSettings>),
     ArchiveIterator(oneshot::Sender<std::vec::IntoIter<StoredArchive>>),
+    ArchivePage(usize, usize, oneshot::Sender<Vec<StoredArchive>>),
     WriteChunkSettings(ChunkSettings, oneshot::Sender<Result<()>>),
     WriteArchive(StoredArchive, oneshot::Sender<Result<()>>),
+    TouchArchive(StoredArchive, oneshot::Sender<Result<()>>),
+    LastAccess(
+        StoredArchive,
+        oneshot::Sender<Option<DateTime<FixedOffset>>>,
+    ),
+    DeleteArchive(StoredArchive, oneshot::Sender<Result<()>>),
+    WriteAuditHead(AuditHead, oneshot::Sender<Result<()>>),
+    AuditHead(oneshot::Sender<Option<AuditHead>>),
+    DanglingParents(oneshot::Sender<Vec<DanglingParent>>),
+    Reload(oneshot::Sender<Result<()>>),
     Close(oneshot::Sender<()>),
 }
 
@@ -338,41 +404,99 @@ impl Manifest {
     /// Will return Err if
     ///
     /// 1. The manifest folder does not exist and creating it failed
-    /// 2. There are no unlocked manifest folders and creating one fails
-    /// 3. There is a file called "manifest" in the repository folder
-    /// 4. Some other IO error (shuch as lack of permissions) occurs
-    /// 5. The path contains non-utf8 characters
+    /// 2. There is a file called "manifest" in the repository folder
+    /// 3. Some other IO error (shuch as lack of permissions) occurs
+    /// 4. The path contains non-utf8 characters
     ///
     /// # TODOs:
     /// 1. Return an error if deserializing a transaciton fails before the end of the file is reached
-    /// 2. This function can currently panic if we have to create a new manifest file, but someone
-    ///    else creates the same file we are trying to first.
     pub fn open(
         repository_path: impl AsRef<Path>,
         chunk_settings: Option<ChunkSettings>,
         key: &Key,
         queue_depth: usize,
     ) -> Result<Manifest> {
-        let mut manifest = InternalManifest::open(repository_path.as_ref(), key, chunk_settings)?;
+        Self::open_with_temp_dir(
+            &repository_path,
+            chunk_settings,
+            key,
+            queue_depth,
+            Durability::default(),
+            &repository_path,
+        )
+    }
+
+    /// Opens and reads the manifest, as `open`, fsyncing its files as specified by `durability`,
+    /// and staging writes in `temp_dir` instead of the repository directory
+    ///
+    /// `temp_dir` must be on the same filesystem as the repository, so that the staged files can
+    /// be renamed into place.
+    ///
+    /// # Errors
+    ///
+    /// Will return Err under the same conditions as `open`
+    pub fn open_with_temp_dir(
+        repository_path: impl AsRef<Path>,
+        chunk_settings: Option<ChunkSettings>,
+        key: &Key,
+        queue_depth: usize,
+        durability: Durability,
+        temp_dir: impl AsRef<Path>,
+    ) -> Result<Manifest> {
+        let mut manifest = InternalManifest::open(
+            repository_path.as_ref(),
+            key,
+            chunk_settings,
+            durability,
+            temp_dir,
+        )?;
         let (input, mut output) = mpsc::channel(queue_depth);
         thread::spawn(move || {
             let mut final_ret = None;
             while let Some(command) = block_on(output.next()) {
                 match command {
                     ManifestCommand::LastMod(ret) => {
-                        ret.send(manifest.last_modification()).unwrap();
+                        reply(ret, manifest.last_modification(), "last modification");
                     }
                     ManifestCommand::ChunkSettings(ret) => {
-                        ret.send(manifest.chunk_settings()).unwrap();
+                        reply(ret, manifest.chunk_settings(), "chunk settings");
                     }
                     ManifestCommand::ArchiveIterator(ret) => {
-                        ret.send(manifest.archive_iterator()).unwrap();
+                        reply(ret, manifest.archive_iterator(), "archive iterator");
+                    }
+                    ManifestCommand::ArchivePage(offset, count, ret) => {
+                        reply(ret, manifest.archive_page(offset, count), "archive page");
                     }
                     ManifestCommand::WriteChunkSettings(settings, ret) => {
-                        ret.send(manifest.write_chunk_settings(settings)).unwrap();
+                        reply(
+                            ret,
+                            manifest.write_chunk_settings(settings),
+                            "write chunk settings",
+                        );
                     }
                     ManifestCommand::WriteArchive(archive, ret) => {
-                        ret.send(manifest.write_archive(archive)).unwrap();
+                        reply(ret, manifest.write_archive(archive), "write archive");
+                    }
+                    ManifestCommand::TouchArchive(archive, ret) => {
+                        reply(ret, manifest.touch_archive(&archive), "touch archive");
+                    }
+                    ManifestCommand::LastAccess(archive, ret) => {
+                        reply(ret, manifest.last_access(&archive), "last access");
+                    }
+                    ManifestCommand::DeleteArchive(archive, ret) => {
+                        reply(ret, manifest.delete_archive(&archive), "delete archive");
+                    }
+                    ManifestCommand::WriteAuditHead(head, ret) => {
+                        reply(ret, manifest.write_audit_head(head), "write audit head");
+                    }
+                    ManifestCommand::AuditHead(ret) => {
+                        reply(ret, manifest.audit_head(), "audit head");
+                    }
+                    ManifestCommand::DanglingParents(ret) => {
+                        reply(ret, manifest.dangling_parents(), "dangling parents");
+                    }
+                    ManifestCommand::Reload(ret) => {
+                        reply(ret, manifest.reload(), "reload");
                     }
                     ManifestCommand::Close(ret) => {
                         final_ret = Some(ret);
@@ -385,7 +509,7 @@ impl Manifest {
             std::mem::drop(manifest);
             std::mem::drop(output);
             if let Some(ret) = final_ret {
-                ret.send(()).unwrap();
+                reply(ret, (), "close");
             };
         });
 
@@ -400,6 +524,14 @@ impl Manifest {
         })
     }
 
+    /// Rereads the manifest from disk, picking up the archives written by other connections since
+    /// this one was opened
+    pub async fn reload(&mut self) -> Result<()> {
+        let (i, o) = oneshot::channel();
+        self.input.send(ManifestCommand::Reload(i)).await.unwrap();
+        o.await?
+    }
+
     pub async fn close(&mut self) {
         let (i, o) = oneshot::channel();
         self.input.send(ManifestCommand::Close(i)).await.unwrap();
@@ -437,6 +569,22 @@ impl backend::Manifest for Manifest {
             .unwrap();
         o.await.unwrap()
     }
+    /// Requests the archives from the manifest a page at a time, as they are consumed, rather than
+    /// all at once
+    fn archive_stream(&mut self) -> LocalBoxStream<'_, Result<StoredArchive>> {
+        let input = self.input.clone();
+        paged_archive_stream(move |offset, count| {
+            let mut input = input.clone();
+            async move {
+                let (i, o) = oneshot::channel();
+                input
+                    .send(ManifestCommand::ArchivePage(offset, count, i))
+                    .await
+                    .unwrap();
+                Ok(o.await?)
+            }
+        })
+    }
     async fn write_chunk_settings(&mut self, settings: ChunkSettings) -> Result<()> {
         let (i, o) = oneshot::channel();
         self.input
@@ -458,12 +606,64 @@ impl backend::Manifest for Manifest {
     async fn touch(&mut self) -> Result<()> {
         Ok(())
     }
+    async fn touch_archive(&mut self, archive: StoredArchive) -> Result<()> {
+        let (i, o) = oneshot::channel();
+        self.input
+            .send(ManifestCommand::TouchArchive(archive, i))
+            .await
+            .unwrap();
+        o.await?
+    }
+    async fn last_access(
+        &mut self,
+        archive: StoredArchive,
+    ) -> Result<Option<DateTime<FixedOffset>>> {
+        let (i, o) = oneshot::channel();
+        self.input
+            .send(ManifestCommand::LastAccess(archive, i))
+            .await
+            .unwrap();
+        Ok(o.await?)
+    }
+    async fn delete_archive(&mut self, archive: StoredArchive) -> Result<()> {
+        let (i, o) = oneshot::channel();
+        self.input
+            .send(ManifestCommand::DeleteArchive(archive, i))
+            .await
+            .unwrap();
+        o.await?
+    }
+    async fn write_audit_head(&mut self, head: AuditHead) -> Result<()> {
+        let (i, o) = oneshot::channel();
+        self.input
+            .send(ManifestCommand::WriteAuditHead(head, i))
+            .await
+            .unwrap();
+        o.await?
+    }
+    async fn audit_head(&mut self) -> Result<Option<AuditHead>> {
+        let (i, o) = oneshot::channel();
+        self.input
+            .send(ManifestCommand::AuditHead(i))
+            .await
+            .unwrap();
+        Ok(o.await?)
+    }
+    async fn dangling_parents(&mut self) -> Result<Vec<DanglingParent>> {
+        let (i, o) = oneshot::channel();
+        self.input
+            .send(ManifestCommand::DanglingParents(i))
+            .await
+            .unwrap();
+        Ok(o.await?)
+    }
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
     use crate::manifest::StoredArchive;
+    use crate::repository::backend::common::TEMP_FILE_PREFIX;
     use crate::repository::{ChunkSettings, Key};
     use backend::Manifest as OtherManifest;
     use std::path::PathBuf;
@@ -481,9 +681,8 @@ mod tests {
     // Test to make sure creating an manifest in an empty folder
     // 1. Doesn't Panic or error
     // 2. Creates the manifest directory
-    // 3. Creates the initial manifest file (manifest/0)
-    // 4. Locks the initial manifest file (manifest/0.lock)
-    // 5. last_modification works on a new manifest
+    // 3. Does not create any manifest files until a transaction is written
+    // 4. last_modification works on a new manifest
     #[test]
     fn creation_works() {
         smol::run(async {
@@ -502,14 +701,7 @@ mod tests {
             let manifest_dir = path.join("manifest");
             assert!(manifest_dir.exists());
             assert!(manifest_dir.is_dir());
-            // Check for the initial manifest file
-            let manifest_file = manifest_dir.join("0");
-            assert!(manifest_file.exists());
-            assert!(manifest_file.is_file());
-            // Check for the initial manifest lock file
-            let manifest_lock = manifest_dir.join("0.lock");
-            assert!(manifest_lock.exists());
-            assert!(manifest_lock.is_file());
+            assert!(numbered_files(&manifest_dir).unwrap().is_empty());
             // Make sure last_modification works
             let _last_mod = manifest
                 .last_modification()
@@ -519,61 +711,124 @@ mod tests {
         });
     }
 
-    // Test to make sure creating a second manifest while the first is open
-    // 1. Doesn't panic or error
-    // 2. Creates and locks a second manifest file
+    // Test to make sure that each transaction is written to a new manifest file, and that no
+    // temporary files are left behind
     #[test]
-    fn double_creation_works() {
+    fn transactions_create_new_files() {
         smol::run(async {
             let (tempdir, path) = setup();
-            // Create the first manifest
             let settings = ChunkSettings::lightweight();
             let key = Key::random(32);
-            // Create the manifest
-            let mut manifest1 =
-                Manifest::open(&path, Some(settings), &key, 4).expect("Manifest 1 creation failed");
-            let mut manifest2 =
-                Manifest::open(&path, Some(settings), &key, 4).expect("Manifest 2 creation failed");
-            // Walk the directory and print some debugging info
+            let mut manifest =
+                Manifest::open(&path, Some(settings), &key, 4).expect("Manifest creation failed");
+            let manifest_dir = path.join("manifest");
+            for expected in 0..3 {
+                manifest
+                    .write_archive(StoredArchive::dummy_archive())
+                    .await
+                    .unwrap();
+                let files = numbered_files(&manifest_dir).unwrap();
+                let ids = files.iter().map(|(id, _)| *id).collect::<Vec<_>>();
+                assert_eq!(ids, (0..=expected).collect::<Vec<_>>());
+            }
+            manifest.close().await;
             for entry in WalkDir::new(&path) {
                 let entry = entry.unwrap();
-                println!("{}", entry.path().display());
+                let name = entry.file_name().to_string_lossy();
+                assert!(!name.starts_with(TEMP_FILE_PREFIX), "{}", name);
+            }
+        });
+    }
+
+    // Test to make sure that two manifests racing to write to a fresh repository
+    // 1. Both succeed
+    // 2. Each write their transaction to their own, distinct, manifest file
+    #[test]
+    fn concurrent_creation_works() {
+        smol::run(async {
+            let (tempdir, path) = setup();
+            let settings = ChunkSettings::lightweight();
+            let key = Key::random(32);
+            let tasks = (0..2)
+                .map(|_| {
+                    let path = path.clone();
+                    let key = key.clone();
+                    smol::Task::blocking(async move {
+                        let mut manifest = Manifest::open(&path, Some(settings), &key, 4)
+                            .expect("Concurrent manifest creation failed");
+                        manifest
+                            .write_archive(StoredArchive::dummy_archive())
+                            .await
+                            .expect("Concurrent write failed");
+                        manifest
+                    })
+                })
+                .collect::<Vec<_>>();
+            let mut manifests = Vec::new();
+            for task in tasks {
+                manifests.push(task.await);
             }
-            // Get manifest dir and check for manifest files
             let manifest_dir = path.join("manifest");
-            let mf1 = manifest_dir.join("0");
-            let mf2 = manifest_dir.join("1");
-            let ml1 = manifest_dir.join("0.lock");
-            let ml2 = manifest_dir.join("1.lock");
-            assert!(mf1.exists() && mf1.is_file());
-            assert!(mf2.exists() && mf2.is_file());
-            assert!(ml1.exists() && ml1.is_file());
-            assert!(ml2.exists() && ml2.is_file());
-            manifest1.close().await;
-            manifest2.close().await;
+            let files = numbered_files(&manifest_dir).unwrap();
+            assert_eq!(files.len(), 2);
+            for mut manifest in manifests {
+                manifest.close().await;
+            }
+            let mut manifest =
+                Manifest::open(&path, None, &key, 4).expect("Manifest reopening failed");
+            assert_eq!(manifest.archive_iterator().await.count(), 2);
+            manifest.close().await;
         });
     }
 
-    // Test to make sure that dropping an Manifest unlocks the manifest file
-    // Note: since we are using a single threaded executor, we must manually run all tasks to
-    // completion.
+    // A transaction referencing a parent that was never written, as a partial write could leave
+    // behind, must not prevent opening the manifest, and must be reported by the verify step
     #[test]
-    fn unlock_on_drop() {
+    fn dangling_parents_are_reported() {
         smol::run(async {
             let (tempdir, path) = setup();
-            // Open an manifest and drop it
             let settings = ChunkSettings::lightweight();
             let key = Key::random(32);
-            // Create the manifest
             let mut manifest =
-                Manifest::open(&path, Some(settings), &key, 4).expect("Manifest 1 creation failed");
+                Manifest::open(&path, Some(settings), &key, 4).expect("Manifest creation failed");
+            manifest
+                .write_archive(StoredArchive::dummy_archive())
+                .await
+                .unwrap();
+            manifest.close().await;
+
+            // Write a transaction whose parent does not exist into its own manifest file
+            let archive = StoredArchive::dummy_archive();
+            let bogus = ManifestTransaction::new(
+                &[ManifestTransaction::new(
+                    &[],
+                    archive.id(),
+                    archive.timestamp(),
+                    settings.hmac,
+                    &key,
+                )
+                .tag()],
+                archive.id(),
+                archive.timestamp(),
+                settings.hmac,
+                &key,
+            );
+            let file = File::create(path.join("manifest").join("9")).unwrap();
+            cbor::ser::to_writer(file, &bogus).unwrap();
+
+            let mut manifest = Manifest::open(&path, None, &key, 4)
+                .expect("Manifest with a dangling parent failed to open");
+            let archives: Vec<StoredArchive> = manifest.archive_iterator().await.collect();
+            assert_eq!(archives.len(), 2);
+            assert!(archives.iter().any(|x| x.id() == archive.id()));
+            assert_eq!(
+                manifest.dangling_parents().await.unwrap(),
+                vec![DanglingParent {
+                    transaction: bogus.tag(),
+                    parent: bogus.previous_heads()[0],
+                }]
+            );
             manifest.close().await;
-            // check for the manifest file and the absense of the lock file
-            let manifest_dir = path.join("manifest");
-            let manifest_file = manifest_dir.join("0");
-            let manifest_lock = manifest_dir.join("0.lock");
-            assert!(manifest_file.exists() && manifest_file.is_file());
-            assert!(!manifest_lock.exists());
         });
     }
 
@@ -650,4 +905,140 @@ mod tests {
             assert!(mf.is_err());
         });
     }
+
+    // Test to verify that abandoning an operation before its result comes back does not take down
+    // the event loop, and the manifest remains usable afterwards
+    #[test]
+    fn dropped_operation_recovers() {
+        use futures::future::{ready, select};
+        smol::run(async {
+            let (_tempdir, path) = setup();
+            let settings = ChunkSettings::lightweight();
+            let key = Key::random(32);
+            let mut manifest =
+                Manifest::open(&path, Some(settings), &key, 4).expect("Manifest creation failed");
+
+            // Start a write, poll it once so its command is queued, then drop it
+            let dropped = StoredArchive::dummy_archive();
+            {
+                let write = Box::pin(manifest.write_archive(dropped.clone()));
+                let _ = select(write, ready(())).await;
+            }
+            // Send a command directly whose reply channel has already been dropped, so the loop
+            // is guaranteed to see a closed channel
+            let (i, o) = oneshot::channel();
+            std::mem::drop(o);
+            manifest
+                .input
+                .send(ManifestCommand::ArchiveIterator(i))
+                .await
+                .unwrap();
+
+            // The manifest should still work
+            let archive = StoredArchive::dummy_archive();
+            manifest.write_archive(archive.clone()).await.unwrap();
+            let archives: Vec<StoredArchive> = manifest.archive_iterator().await.collect();
+            assert!(archives.contains(&archive));
+            assert_eq!(manifest.chunk_settings().await, settings);
+            manifest.close().await;
+        });
+    }
+
+    // Touching an archive should record an access time that survives reopening the manifest,
+    // without adding a new archive or changing its creation timestamp
+    #[test]
+    fn touch_archive() {
+        use smol::Timer;
+        smol::run(async {
+            let (_tempdir, path) = setup();
+            let settings = ChunkSettings::lightweight();
+            let key = Key::random(32);
+            let mut manifest =
+                Manifest::open(&path, Some(settings), &key, 4).expect("Manifest creation failed");
+            let archive = StoredArchive::dummy_archive();
+            manifest.write_archive(archive.clone()).await.unwrap();
+            assert_eq!(manifest.last_access(archive.clone()).await.unwrap(), None);
+
+            Timer::after(time::Duration::from_millis(5)).await;
+            manifest.touch_archive(archive.clone()).await.unwrap();
+            manifest.close().await;
+
+            let mut manifest =
+                Manifest::open(&path, None, &key, 4).expect("Manifest reopen failed");
+            let accessed = manifest
+                .last_access(archive.clone())
+                .await
+                .unwrap()
+                .expect("Access time was not recorded");
+            assert!(accessed > archive.timestamp());
+            let archives: Vec<StoredArchive> = manifest.archive_iterator().await.collect();
+            assert_eq!(archives, vec![archive]);
+            assert!(manifest.last_modification().await.unwrap() >= accessed);
+            manifest.close().await;
+        });
+    }
+
+    // The archive stream should yield exactly what the archive iterator does, in the same order
+    #[test]
+    fn archive_stream_matches_iterator() {
+        use futures::stream::TryStreamExt;
+        use smol::Timer;
+        smol::run(async {
+            let (_tempdir, path) = setup();
+            let settings = ChunkSettings::lightweight();
+            let key = Key::random(32);
+            let mut manifest =
+                Manifest::open(&path, Some(settings), &key, 4).expect("Manifest creation failed");
+            for _ in 0..10 {
+                manifest
+                    .write_archive(StoredArchive::dummy_archive())
+                    .await
+                    .unwrap();
+                // Pause for a bit to make sure the next one has a sufficiently different timestamp
+                Timer::after(time::Duration::from_millis(5)).await;
+            }
+
+            let iterated: Vec<StoredArchive> = manifest.archive_iterator().await.collect();
+            let streamed: Vec<StoredArchive> =
+                manifest.archive_stream().try_collect().await.unwrap();
+            assert_eq!(iterated.len(), 10);
+            assert_eq!(streamed, iterated);
+            manifest.close().await;
+        });
+    }
+
+    // The archive stream must request archives as they are consumed, so an archive deleted after
+    // the stream has started, but before its page was requested, is not produced
+    #[test]
+    fn archive_stream_is_lazy() {
+        use crate::repository::backend::common::ARCHIVE_PAGE_SIZE;
+        use futures::stream::TryStreamExt;
+        smol::run(async {
+            let (_tempdir, path) = setup();
+            let settings = ChunkSettings::lightweight();
+            let key = Key::random(32);
+            let mut manifest =
+                Manifest::open(&path, Some(settings), &key, 4).expect("Manifest creation failed");
+            for _ in 0..ARCHIVE_PAGE_SIZE + 5 {
+                manifest
+                    .write_archive(StoredArchive::dummy_archive())
+                    .await
+                    .unwrap();
+            }
+            let archives: Vec<StoredArchive> = manifest.archive_iterator().await.collect();
+            let last = archives.last().unwrap().clone();
+
+            let mut other = manifest.clone();
+            let mut stream = manifest.archive_stream();
+            let first = stream.try_next().await.unwrap().unwrap();
+            assert_eq!(first, archives[0]);
+            // The last archive is on the second page, which has not been requested yet
+            other.delete_archive(last.clone()).await.unwrap();
+            let rest: Vec<StoredArchive> = stream.try_collect().await.unwrap();
+            assert_eq!(rest.len(), archives.len() - 2);
+            assert_eq!(rest[..], archives[1..archives.len() - 1]);
+            assert!(!rest.contains(&last));
+            manifest.close().await;
+        });
+    }
 }
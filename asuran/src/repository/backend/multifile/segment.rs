@@ -1,22 +1,55 @@
 use crate::repository::backend::common::files::LockedFile;
-use crate::repository::backend::common::segment::Segment;
-use crate::repository::backend::{BackendError, Result, SegmentDescriptor};
-use crate::repository::{Chunk, ChunkSettings, Key};
+use crate::repository::backend::common::reply;
+use crate::repository::backend::common::segment::{read_segment_header, Segment, SegmentFormat};
+use crate::repository::backend::common::segments_to_prefetch;
+use crate::repository::backend::common::version::check_version;
+use crate::repository::backend::{BackendError, Durability, Result, SegmentDescriptor};
+use crate::repository::{Chunk, ChunkID, ChunkSettings, Key};
 
+use asuran_core::repository::chunk::ChunkHeader;
+
+use crossbeam_channel::unbounded;
 use futures::channel::mpsc;
 use futures::channel::oneshot;
 use futures::sink::SinkExt;
 use futures::stream::StreamExt;
 use lru::LruCache;
 use smol::block_on;
+use tracing::{error, warn};
 use walkdir::WalkDir;
 
-use std::fs::{create_dir, File};
+use std::collections::HashMap;
+use std::fs::{create_dir, remove_file, File};
 use std::io::{Read, Seek, Write};
 use std::path::{Path, PathBuf};
 use std::thread;
 
+/// The newest version of the `MultiFile` segment format this version of asuran supports
+///
+/// - Version 1 stores chunk headers in a `.header` file beside each segment, see
+///   `SegmentFormat::Split`
+/// - Version 2 stores chunk headers inline with their bodies, see `SegmentFormat::Interleaved`
+pub const FORMAT_VERSION: u16 = 2;
+
+/// The default number of read only segment handles to keep open
+///
+/// Each cached segment holds two file descriptors open, one for the segment itself, and one for
+/// its header.
+pub const DEFAULT_RO_CACHE_SIZE: usize = 100;
+
 struct SegmentPair<R: Read + Write + Seek>(u64, Segment<R>);
+
+/// The result of copying the live chunks out of segments holding dead ones, see
+/// `SegmentHandler::compact`
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Compaction {
+    /// The new locations of the chunks that were copied
+    pub moved: Vec<(ChunkID, SegmentDescriptor)>,
+    /// The segments that no longer hold any live chunks, and can be removed once the index points
+    /// at the copies
+    pub emptied: Vec<u64>,
+}
+
 /// An internal struct for handling the state of the segments
 ///
 /// Maintains a handle to the currently being written segment, and will keep it up to date as the
@@ -49,6 +82,10 @@ struct InternalSegmentHandler {
     chunk_settings: ChunkSettings,
     /// They key used for encrypting/decrypting headers
     key: Key,
+    /// Whether or not segments are fsynced when they are closed out or synced
+    durability: Durability,
+    /// The format segments in this repository are stored in
+    format: SegmentFormat,
 }
 
 impl InternalSegmentHandler {
@@ -59,7 +96,13 @@ impl InternalSegmentHandler {
     /// Note: the `repository_path` is the path of the root folder of the repository, not the data
     /// folder
     ///
-    /// Will default to a cache size of 100 file handles.
+    /// Up to `ro_cache_size` segments will be kept open for reading, see `SegmentHandler::open`.
+    ///
+    /// Segments are fsynced when they are closed out, or when `sync` is called, as specified by
+    /// `durability`.
+    ///
+    /// Segments are read and written in the format recorded for the repository, see
+    /// `read_segment_format`.
     ///
     /// This implementation is not thread safe, please see `SegmentHandler` for a thread safe
     /// implementation on top of this
@@ -67,6 +110,8 @@ impl InternalSegmentHandler {
     /// # Errors
     ///
     /// 1. The data folder does not exist and creating it failed
+    /// 2. The repository's format version is not supported
+    /// 3. The newest segment was written by a newer major version of asuran
     ///
     /// # Panics
     ///
@@ -83,7 +128,10 @@ impl InternalSegmentHandler {
         segments_per_directory: u64,
         chunk_settings: ChunkSettings,
         key: Key,
+        ro_cache_size: usize,
+        durability: Durability,
     ) -> Result<InternalSegmentHandler> {
+        let format = read_segment_format(&repository_path)?;
         // Construct the path of the data foler
         let data_path = repository_path.as_ref().join("data");
         // Create it if it does not exist
@@ -105,15 +153,29 @@ impl InternalSegmentHandler {
             .max()
             .unwrap_or(0);
 
+        // Refuse repositories last written to by an incompatible version of asuran, before
+        // anything is added to them
+        let newest_segment = data_path
+            .join((max_segment / segments_per_directory).to_string())
+            .join(max_segment.to_string());
+        if let Ok(mut file) = File::open(&newest_segment) {
+            if file.metadata()?.len() > 0 {
+                check_version(&read_segment_header(&mut file)?.version())?;
+            }
+        }
+
         let mut segment_handler = InternalSegmentHandler {
             current_segment: None,
             highest_segment: max_segment,
             size_limit,
-            ro_segment_cache: LruCache::new(100),
+            // A cache that can't hold anything would make reading impossible
+            ro_segment_cache: LruCache::new(ro_cache_size.max(1)),
             path: data_path,
             segments_per_directory,
             chunk_settings,
             key,
+            durability,
+            format,
         };
 
         // Open the writing segment to ensure that the data directory is lockable
@@ -160,36 +222,46 @@ impl InternalSegmentHandler {
             let folder_id = segment_id / self.segments_per_directory;
             // Find the folder it belongs to and check to see if it exists
             let folder_path = self.path.join(folder_id.to_string());
-            if !(folder_path.exists() && folder_path.is_dir()) {
+            if !folder_path.exists() {
+                return Err(BackendError::SegmentNotFound(segment_id));
+            }
+            if !folder_path.is_dir() {
                 return Err(BackendError::SegmentError(format!(
-                    "Segment directory {} for segment {} does not exist or is not a folder",
+                    "Segment directory {} for segment {} is not a folder",
                     folder_id, segment_id
                 )));
             }
             // Get the path of the segement and check to see if it exists
             let segment_path = folder_path.join(segment_id.to_string());
-            let header_path = folder_path.join(format!("{}.header", segment_id.to_string()));
-            if !(segment_path.exists() && segment_path.is_file()) {
+            if !segment_path.exists() {
+                return Err(BackendError::SegmentNotFound(segment_id));
+            }
+            if !segment_path.is_file() {
                 return Err(BackendError::SegmentError(format!(
-                    "File for segment {} opened in read only mode does not exists",
+                    "File for segment {} opened in read only mode is not a file",
                     segment_id
                 )));
             }
-            // Open the file
-            let segment_file = File::open(segment_path)?;
-            let header_file = File::open(header_path)?;
-            // Pack it and load it into the cache
-            let segment_pair = SegmentPair(
-                segment_id,
-                Segment::new(
-                    segment_file,
-                    header_file,
+            // Open the file, pack it, and load it into the cache
+            let segment = match self.format {
+                SegmentFormat::Split => {
+                    let header_path = folder_path.join(format!("{}.header", segment_id));
+                    Segment::new(
+                        File::open(segment_path)?,
+                        File::open(header_path)?,
+                        self.size_limit,
+                        self.chunk_settings,
+                        self.key.clone(),
+                    )?
+                }
+                SegmentFormat::Interleaved => Segment::new_interleaved(
+                    File::open(segment_path)?,
                     self.size_limit,
                     self.chunk_settings,
                     self.key.clone(),
                 )?,
-            );
-            cache.put(segment_id, segment_pair);
+            };
+            cache.put(segment_id, SegmentPair(segment_id, segment));
         }
 
         // Get the reference and return it
@@ -252,75 +324,102 @@ impl InternalSegmentHandler {
                 if !folder_path.exists() {
                     create_dir(&folder_path)?;
                 }
-                // Construct the path for the segment proper, and construct the segment
-                let segment_path = folder_path.join(segment_id.to_string());
-                let header_path = folder_path.join(format!("{}.header", segment_id.to_string()));
-                let segment_file = LockedFile::open_read_write(&segment_path)?;
-                let header_file = LockedFile::open_read_write(&header_path)?;
-                if let Some(segment_file) = segment_file {
-                    if let Some(header_file) = header_file {
-                        let mut segment = SegmentPair(
-                            segment_id,
-                            Segment::new(
-                                segment_file,
-                                header_file,
-                                self.size_limit,
-                                self.chunk_settings,
-                                self.key.clone(),
-                            )?,
-                        );
-                        if segment.1.size() < self.size_limit {
-                            // If the segment is in the cache, we need to invalidate it
-                            self.ro_segment_cache.pop(&segment.0);
-                            self.current_segment = Some(segment);
-                            return Ok(self.current_segment.as_mut().unwrap());
-                        }
+                // Construct the segment
+                if let Some(segment) = self.lock_segment(&folder_path, segment_id)? {
+                    let mut segment = SegmentPair(segment_id, segment);
+                    if segment.1.size() < self.size_limit {
+                        // If the segment is in the cache, we need to invalidate it
+                        self.ro_segment_cache.pop(&segment.0);
+                        self.current_segment = Some(segment);
+                        return Ok(self.current_segment.as_mut().unwrap());
                     }
                 }
             }
 
-            let segment_id = self.highest_segment;
-            // Find the folder that the segment needs to go into, creating it if it does not exist
-            let folder_id = segment_id / self.segments_per_directory;
-            let folder_path = self.path.join(folder_id.to_string());
-            if !folder_path.exists() {
-                create_dir(&folder_path)?;
-            }
-            // Construct the path for the segment proper, and construct the segment
-            let segment_path = folder_path.join(segment_id.to_string());
-            let header_path = folder_path.join(format!("{}.header", segment_id.to_string()));
-            let segment_file = LockedFile::open_read_write(&segment_path)?.ok_or_else(|| {
-                BackendError::SegmentError(format!(
-                    "Unable to lock newly created segment. File: {:?} Src File: {} Line: {}",
-                    &segment_path,
-                    file!(),
-                    line!()
-                ))
-            })?;
-            let header_file = LockedFile::open_read_write(&header_path)?.ok_or_else(|| {
+            self.create_segment_write()?;
+        }
+
+        // We have ensured that this option is in the Some state in the previous section of the
+        // code, so we can safely unwrap
+        Ok(self.current_segment.as_mut().unwrap())
+    }
+
+    /// Creates a new segment with the id `highest_segment`, and makes it the current segment
+    ///
+    /// # Errors
+    ///
+    /// Will error under the same conditions as `open_segment_write`
+    fn create_segment_write(&mut self) -> Result<()> {
+        let segment_id = self.highest_segment;
+        // Find the folder that the segment needs to go into, creating it if it does not exist
+        let folder_id = segment_id / self.segments_per_directory;
+        let folder_path = self.path.join(folder_id.to_string());
+        if !folder_path.exists() {
+            create_dir(&folder_path)?;
+        }
+        // Construct the segment
+        let segment = self
+            .lock_segment(&folder_path, segment_id)?
+            .ok_or_else(|| {
                 BackendError::SegmentError(format!(
                     "Unable to lock newly created segment. File: {:?} Src File: {} Line: {}",
-                    &header_path,
+                    folder_path.join(segment_id.to_string()),
                     file!(),
                     line!()
                 ))
             })?;
-            let segment = SegmentPair(
-                segment_id,
-                Segment::new(
+        self.current_segment = Some(SegmentPair(segment_id, segment));
+        Ok(())
+    }
+
+    /// Locks, creating if needed, the segment with the given id in the given folder for writing
+    ///
+    /// Returns `Ok(None)` if the segment, or for split segments, its header, is already locked
+    ///
+    /// Interleaved segments have any record left incomplete by an interrupted write cut off, so
+    /// that appending to them is safe.
+    fn lock_segment(
+        &self,
+        folder_path: &Path,
+        segment_id: u64,
+    ) -> Result<Option<Segment<LockedFile>>> {
+        let segment_path = folder_path.join(segment_id.to_string());
+        let segment_file = match LockedFile::open_read_write(&segment_path)? {
+            Some(file) => file,
+            None => return Ok(None),
+        };
+        match self.format {
+            SegmentFormat::Split => {
+                let header_path = folder_path.join(format!("{}.header", segment_id));
+                if let Some(header_file) = LockedFile::open_read_write(&header_path)? {
+                    Ok(Some(Segment::new(
+                        segment_file,
+                        header_file,
+                        self.size_limit,
+                        self.chunk_settings,
+                        self.key.clone(),
+                    )?))
+                } else {
+                    Ok(None)
+                }
+            }
+            SegmentFormat::Interleaved => {
+                let mut segment = Segment::new_interleaved(
                     segment_file,
-                    header_file,
                     self.size_limit,
                     self.chunk_settings,
                     self.key.clone(),
-                )?,
-            );
-            self.current_segment = Some(segment);
+                )?;
+                let removed = segment.truncate_torn_tail()?;
+                if removed > 0 {
+                    warn!(
+                        "Removed {} bytes left by an interrupted write from the end of segment {}",
+                        removed, segment_id
+                    );
+                }
+                Ok(Some(segment))
+            }
         }
-
-        // We have ensured that this option is in the Some state in the previous section of the
-        // code, so we can safely unwrap
-        Ok(self.current_segment.as_mut().unwrap())
     }
 
     /// Attempts to read a chunk from its associated segment
@@ -330,6 +429,13 @@ impl InternalSegmentHandler {
         segment.1.read_chunk(location.start)
     }
 
+    /// Attempts to read a chunk's header, and the length of its body, from its associated segment
+    fn read_chunk_header(&mut self, location: SegmentDescriptor) -> Result<(ChunkHeader, u64)> {
+        let segment_id = location.segment_id;
+        let segment = self.open_segement_read(segment_id)?;
+        segment.1.read_chunk_header(location.start)
+    }
+
     /// Attempts to write a chunk
     ///
     /// Will close out the current segment if the size, after the write completes, execeds the max
@@ -344,12 +450,28 @@ impl InternalSegmentHandler {
         };
         // If we have exceeded the max size, close out the current segment
         if segment.1.size() >= self.size_limit {
-            self.current_segment.as_mut().map(|x| x.1.flush());
+            let durability = self.durability;
+            if let Some(segment) = self.current_segment.as_mut() {
+                segment.1.sync(durability)?;
+            }
             self.current_segment = None
         }
         Ok(descriptor)
     }
 
+    /// Opens the segments containing the given locations into the read cache, in order of first
+    /// appearance
+    ///
+    /// Stops once the cache is full, to avoid evicting segments that were opened earlier in the
+    /// same call.
+    fn prefetch(&mut self, locations: &[SegmentDescriptor]) -> Result<()> {
+        let capacity = self.ro_segment_cache.cap();
+        for segment_id in segments_to_prefetch(locations, capacity) {
+            self.open_segement_read(segment_id)?;
+        }
+        Ok(())
+    }
+
     /// Flushes the changes to the current segment
     fn flush(&mut self) -> Result<()> {
         if let Some(segment) = self.current_segment.as_mut() {
@@ -358,11 +480,323 @@ impl InternalSegmentHandler {
             Ok(())
         }
     }
+
+    /// Flushes the changes to the current segment, and fsyncs its files if the handler's
+    /// durability calls for it
+    ///
+    /// Segments that have already been closed out were synced at that point, and are not
+    /// revisited.
+    fn sync(&mut self) -> Result<()> {
+        if let Some(segment) = self.current_segment.as_mut() {
+            segment.1.sync(self.durability)
+        } else {
+            Ok(())
+        }
+    }
+
+    /// Copies the live chunks out of every segment that also holds dead ones, into new segments
+    ///
+    /// `live` maps the id of each segment to the ids and locations of the chunks in it that are
+    /// still in the index, any other chunk in a segment is considered dead. Segments without any
+    /// dead chunks are left alone. The copies are always written to fresh segments numbered past
+    /// every existing one, and are synced before this returns.
+    ///
+    /// The emptied segments are not removed, as the index must durably point at the copies first,
+    /// see `remove_segments`.
+    fn compact(&mut self, live: &HashMap<u64, Vec<(ChunkID, u64)>>) -> Result<Compaction> {
+        // The segment being written may be one of the ones being compacted, so close it out
+        if let Some(mut segment) = self.current_segment.take() {
+            segment.1.sync(self.durability)?;
+        }
+        let segment_ids = list_segments(&self.path)?;
+        if let Some(highest) = segment_ids.iter().max() {
+            self.highest_segment = self.highest_segment.max(highest + 1);
+        }
+        while self.segment_exists(self.highest_segment) {
+            self.highest_segment += 1;
+        }
+        self.create_segment_write()?;
+
+        let no_chunks = Vec::new();
+        let mut compaction = Compaction::default();
+        for segment_id in segment_ids {
+            let chunks = live.get(&segment_id).unwrap_or(&no_chunks);
+            let stored = self.open_segement_read(segment_id)?.1.chunk_count()?;
+            if stored <= chunks.len() as u64 {
+                continue;
+            }
+            for (id, start) in chunks {
+                let chunk = self.read_chunk(SegmentDescriptor {
+                    segment_id,
+                    start: *start,
+                })?;
+                compaction.moved.push((*id, self.write_chunk(chunk)?));
+            }
+            self.ro_segment_cache.pop(&segment_id);
+            compaction.emptied.push(segment_id);
+        }
+        self.sync()?;
+        Ok(compaction)
+    }
+
+    /// Deletes the files of the given segments
+    ///
+    /// Segments that do not exist are skipped.
+    fn remove_segments(&mut self, segment_ids: &[u64]) -> Result<()> {
+        for segment_id in segment_ids {
+            self.ro_segment_cache.pop(segment_id);
+            let folder_path = self
+                .path
+                .join((segment_id / self.segments_per_directory).to_string());
+            let segment_path = folder_path.join(segment_id.to_string());
+            let header_path = folder_path.join(format!("{}.header", segment_id));
+            for path in &[segment_path, header_path] {
+                if path.exists() {
+                    remove_file(path)?;
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Lists the ids of every segment in the given data directory
+fn list_segments(data_path: &Path) -> Result<Vec<u64>> {
+    let mut segment_ids = Vec::new();
+    for entry in WalkDir::new(data_path) {
+        let entry = entry.map_err(|e| BackendError::Unknown(e.to_string()))?;
+        if !entry.file_type().is_file() {
+            continue;
+        }
+        if let Some(Ok(id)) = entry
+            .path()
+            .file_name()
+            .map(|x| x.to_string_lossy().parse::<u64>())
+        {
+            segment_ids.push(id);
+        }
+    }
+    segment_ids.sort_unstable();
+    Ok(segment_ids)
+}
+
+/// Returns the format segments are stored in, in the `MultiFile` repository at the given path
+///
+/// See `FORMAT_VERSION` for the versions and their formats. Repositories without a `format` file
+/// predate it, and use the split format.
+///
+/// # Errors
+///
+/// - Will return `Err(BackendError::UnsupportedFormatVersion)` if the repository has a format
+///   version this version of asuran does not know about
+/// - Will propagate any I/O errors reading the `format` file
+pub fn read_segment_format(repository_path: impl AsRef<Path>) -> Result<SegmentFormat> {
+    let format_path = repository_path.as_ref().join("format");
+    if !format_path.exists() {
+        return Ok(SegmentFormat::Split);
+    }
+    let mut version = [0_u8; 2];
+    File::open(format_path)?.read_exact(&mut version)?;
+    match u16::from_be_bytes(version) {
+        1 => Ok(SegmentFormat::Split),
+        FORMAT_VERSION => Ok(SegmentFormat::Interleaved),
+        version => Err(BackendError::UnsupportedFormatVersion(version)),
+    }
+}
+
+/// Records the format segments are stored in for the `MultiFile` repository at the given path
+///
+/// This must only be done before any segments have been written, as existing segments are not
+/// converted.
+///
+/// # Errors
+///
+/// Will propagate any I/O errors writing the `format` file
+pub fn write_segment_format(
+    repository_path: impl AsRef<Path>,
+    format: SegmentFormat,
+) -> Result<()> {
+    let version: u16 = match format {
+        SegmentFormat::Split => 1,
+        SegmentFormat::Interleaved => FORMAT_VERSION,
+    };
+    File::create(repository_path.as_ref().join("format"))?.write_all(&version.to_be_bytes())?;
+    Ok(())
+}
+
+/// A chunk that failed verification, see `verify_segments`
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct VerificationFailure {
+    /// Location of the chunk
+    pub location: SegmentDescriptor,
+    /// Description of what went wrong
+    pub reason: String,
+}
+
+/// The results of verifying every chunk in a repository's segments
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct SegmentVerification {
+    /// Number of segments that were checked
+    pub segments: usize,
+    /// Number of chunks that passed verification
+    pub verified: usize,
+    /// Chunks that could be read, but failed verification, sorted by location
+    pub failures: Vec<VerificationFailure>,
+    /// Segments that could not be opened at all, along with the reason, sorted by id
+    pub failed_segments: Vec<(u64, String)>,
+}
+
+impl SegmentVerification {
+    /// Returns true if every segment could be opened, and every chunk passed verification
+    pub fn is_ok(&self) -> bool {
+        self.failures.is_empty() && self.failed_segments.is_empty()
+    }
+}
+
+/// Verifies every chunk in every segment in the given data directory, using up to `threads`
+/// threads
+///
+/// Each segment is opened independently, in read only mode, bypassing the `SegmentHandler`, so
+/// this should only be used on segments that are not currently being written to, see
+/// `MultiFile::verify_parallel`. Chunks are checked against their framing record, if they have
+/// one, and their HMAC tag, but are not decrypted.
+///
+/// # Errors
+///
+/// Only errors walking the data directory are returned, problems with the segments themselves are
+/// reported in the returned `SegmentVerification`.
+pub fn verify_segments(
+    data_path: impl AsRef<Path>,
+    chunk_settings: ChunkSettings,
+    key: &Key,
+    threads: usize,
+) -> Result<SegmentVerification> {
+    let mut segments = Vec::new();
+    for entry in WalkDir::new(data_path.as_ref()) {
+        let entry = entry.map_err(|e| BackendError::Unknown(e.to_string()))?;
+        if !entry.file_type().is_file() {
+            continue;
+        }
+        if let Some(Ok(id)) = entry
+            .path()
+            .file_name()
+            .map(|x| x.to_string_lossy().parse::<u64>())
+        {
+            segments.push((id, entry.path().to_path_buf()));
+        }
+    }
+    let segment_count = segments.len();
+
+    let (work_tx, work_rx) = unbounded::<(u64, PathBuf)>();
+    let (result_tx, result_rx) = unbounded();
+    for segment in segments {
+        work_tx.send(segment).unwrap();
+    }
+    drop(work_tx);
+    let workers: Vec<_> = (0..threads.max(1))
+        .map(|_| {
+            let work_rx = work_rx.clone();
+            let result_tx = result_tx.clone();
+            let key = key.clone();
+            thread::spawn(move || {
+                for (id, path) in &work_rx {
+                    let result = verify_segment(id, &path, chunk_settings, &key);
+                    result_tx.send((id, result)).unwrap();
+                }
+            })
+        })
+        .collect();
+    drop(result_tx);
+
+    let mut verification = SegmentVerification {
+        segments: segment_count,
+        ..SegmentVerification::default()
+    };
+    for (id, result) in &result_rx {
+        match result {
+            Ok((verified, failures)) => {
+                verification.verified += verified;
+                verification.failures.extend(failures);
+            }
+            Err(e) => verification.failed_segments.push((id, e.to_string())),
+        }
+    }
+    for worker in workers {
+        worker
+            .join()
+            .map_err(|_| BackendError::Unknown("Verification thread panicked".to_string()))?;
+    }
+    verification
+        .failures
+        .sort_by_key(|x| (x.location.segment_id, x.location.start));
+    verification.failed_segments.sort_by_key(|x| x.0);
+    Ok(verification)
+}
+
+/// Verifies each chunk in a single segment, returning the number of chunks that passed, and the
+/// failures
+fn verify_segment(
+    segment_id: u64,
+    path: &Path,
+    chunk_settings: ChunkSettings,
+    key: &Key,
+) -> Result<(usize, Vec<VerificationFailure>)> {
+    // The magic number in the segment's header records its format
+    let mut segment_file = File::open(path)?;
+    let format = read_segment_header(&mut segment_file)?
+        .format()
+        .ok_or_else(|| {
+            BackendError::SegmentError("Segment failed header validation".to_string())
+        })?;
+    let mut segment = match format {
+        SegmentFormat::Split => {
+            let header_path = path.with_file_name(format!("{}.header", segment_id));
+            Segment::new(
+                segment_file,
+                File::open(header_path)?,
+                u64::MAX,
+                chunk_settings,
+                key.clone(),
+            )?
+        }
+        SegmentFormat::Interleaved => {
+            Segment::new_interleaved(segment_file, u64::MAX, chunk_settings, key.clone())?
+        }
+    };
+    let mut verified = 0;
+    let mut failures = Vec::new();
+    for start in segment.chunk_locations()? {
+        let location = SegmentDescriptor { segment_id, start };
+        match segment.read_chunk(start) {
+            Ok(chunk) if chunk.verify(key) => verified += 1,
+            Ok(_) => failures.push(VerificationFailure {
+                location,
+                reason: "Chunk failed HMAC verification".to_string(),
+            }),
+            Err(e) => failures.push(VerificationFailure {
+                location,
+                reason: e.to_string(),
+            }),
+        }
+    }
+    Ok((verified, failures))
 }
 
 enum SegmentHandlerCommand {
     ReadChunk(SegmentDescriptor, oneshot::Sender<Result<Chunk>>),
+    ReadChunkHeader(
+        SegmentDescriptor,
+        oneshot::Sender<Result<(ChunkHeader, u64)>>,
+    ),
     WriteChunk(Chunk, oneshot::Sender<Result<SegmentDescriptor>>),
+    Prefetch(Vec<SegmentDescriptor>),
+    Flush(oneshot::Sender<Result<()>>),
+    Sync(oneshot::Sender<Result<()>>),
+    Compact(
+        HashMap<u64, Vec<(ChunkID, u64)>>,
+        oneshot::Sender<Result<Compaction>>,
+    ),
+    RemoveSegments(Vec<u64>, oneshot::Sender<Result<()>>),
     Close(oneshot::Sender<()>),
 }
 
@@ -382,6 +816,16 @@ impl SegmentHandler {
     /// Opens a `SegmentHandler`, creating the data directory and the initial
     /// segment if it does not exist
     ///
+    /// Up to `ro_cache_size` segments, with a minimum of one, are kept open for reading at a
+    /// time, with the least recently used one being closed when another needs to be opened.
+    /// Each cached segment uses two file descriptors, one for the segment and one for its header,
+    /// so this should be kept comfortably below half of the process's file descriptor limit
+    /// (`ulimit -n`), leaving room for the index, manifest, and anything else the process has
+    /// open. Lower values cause more reopening of files during restores that read from many
+    /// segments. `DEFAULT_RO_CACHE_SIZE` is used by `MultiFile::open_defaults`.
+    ///
+    /// `durability` controls whether segments are fsynced when they are closed out or synced.
+    ///
     /// # Errors
     ///
     /// Will error if creating/locking a segment fails, such as if the user does
@@ -393,6 +837,8 @@ impl SegmentHandler {
         chunk_settings: ChunkSettings,
         key: Key,
         queue_depth: usize,
+        ro_cache_size: usize,
+        durability: Durability,
     ) -> Result<SegmentHandler> {
         // Create the internal handler
         let mut handler = InternalSegmentHandler::open(
@@ -401,6 +847,8 @@ impl SegmentHandler {
             segments_per_directory,
             chunk_settings,
             key,
+            ro_cache_size,
+            durability,
         )?;
         // get the path from it
         let path = String::from(handler.path.to_string_lossy());
@@ -411,13 +859,43 @@ impl SegmentHandler {
             while let Some(command) = block_on(output.next()) {
                 match command {
                     SegmentHandlerCommand::ReadChunk(location, ret) => {
-                        ret.send(handler.read_chunk(location)).unwrap();
+                        reply(ret, handler.read_chunk(location), "read chunk");
+                    }
+                    SegmentHandlerCommand::ReadChunkHeader(location, ret) => {
+                        reply(
+                            ret,
+                            handler.read_chunk_header(location),
+                            "read chunk header",
+                        );
                     }
                     SegmentHandlerCommand::WriteChunk(chunk, ret) => {
-                        ret.send(handler.write_chunk(chunk)).unwrap();
+                        reply(ret, handler.write_chunk(chunk), "write chunk");
+                    }
+                    SegmentHandlerCommand::Prefetch(locations) => {
+                        // Prefetching is advisory, so failures are left for the reads themselves
+                        // to report
+                        let _ = handler.prefetch(&locations);
+                    }
+                    SegmentHandlerCommand::Flush(ret) => {
+                        reply(ret, handler.flush(), "flush");
+                    }
+                    SegmentHandlerCommand::Sync(ret) => {
+                        reply(ret, handler.sync(), "sync");
+                    }
+                    SegmentHandlerCommand::Compact(live, ret) => {
+                        reply(ret, handler.compact(&live), "compact");
+                    }
+                    SegmentHandlerCommand::RemoveSegments(segment_ids, ret) => {
+                        reply(
+                            ret,
+                            handler.remove_segments(&segment_ids),
+                            "remove segments",
+                        );
                     }
                     SegmentHandlerCommand::Close(ret) => {
-                        handler.flush().unwrap();
+                        if let Err(e) = handler.flush() {
+                            error!("Failed to flush segment while closing: {}", e);
+                        }
                         final_ret = Some(ret);
                         break;
                     }
@@ -428,7 +906,7 @@ impl SegmentHandler {
             std::mem::drop(handler);
             std::mem::drop(output);
             if let Some(ret) = final_ret {
-                ret.send(()).unwrap();
+                reply(ret, (), "close");
             }
         });
 
@@ -444,6 +922,18 @@ impl SegmentHandler {
         output.await.unwrap()
     }
 
+    pub async fn read_chunk_header(
+        &mut self,
+        location: SegmentDescriptor,
+    ) -> Result<(ChunkHeader, u64)> {
+        let (input, output) = oneshot::channel();
+        self.input
+            .send(SegmentHandlerCommand::ReadChunkHeader(location, input))
+            .await
+            .unwrap();
+        output.await.unwrap()
+    }
+
     pub async fn write_chunk(&mut self, chunk: Chunk) -> Result<SegmentDescriptor> {
         let (input, output) = oneshot::channel();
         self.input
@@ -453,6 +943,63 @@ impl SegmentHandler {
         output.await.unwrap()
     }
 
+    /// Queues the segments containing the given locations to be opened, without waiting for
+    /// them to be
+    pub async fn prefetch(&mut self, locations: Vec<SegmentDescriptor>) {
+        self.input
+            .send(SegmentHandlerCommand::Prefetch(locations))
+            .await
+            .unwrap();
+    }
+
+    /// Flushes the header of the segment currently being written to disk
+    pub async fn flush(&mut self) -> Result<()> {
+        let (input, output) = oneshot::channel();
+        self.input
+            .send(SegmentHandlerCommand::Flush(input))
+            .await
+            .unwrap();
+        output.await.unwrap()
+    }
+
+    /// Flushes the header of the segment currently being written, and fsyncs it and its data if
+    /// the handler's durability calls for it
+    pub async fn sync(&mut self) -> Result<()> {
+        let (input, output) = oneshot::channel();
+        self.input
+            .send(SegmentHandlerCommand::Sync(input))
+            .await
+            .unwrap();
+        output.await.unwrap()
+    }
+
+    /// Copies the live chunks out of every segment that also holds dead ones
+    ///
+    /// `live` maps the id of each segment to the ids and offsets of the chunks in it that are still
+    /// in the index. The returned `Compaction` lists the new locations of the copied chunks, which
+    /// must be recorded in the index, and committed, before the emptied segments are passed to
+    /// `remove_segments`.
+    ///
+    /// This must only be called while no other connection has the repository open.
+    pub async fn compact(&mut self, live: HashMap<u64, Vec<(ChunkID, u64)>>) -> Result<Compaction> {
+        let (input, output) = oneshot::channel();
+        self.input
+            .send(SegmentHandlerCommand::Compact(live, input))
+            .await
+            .unwrap();
+        output.await.unwrap()
+    }
+
+    /// Deletes the files of the given segments
+    pub async fn remove_segments(&mut self, segment_ids: Vec<u64>) -> Result<()> {
+        let (input, output) = oneshot::channel();
+        self.input
+            .send(SegmentHandlerCommand::RemoveSegments(segment_ids, input))
+            .await
+            .unwrap();
+        output.await.unwrap()
+    }
+
     pub async fn close(&mut self) {
         let (input, output) = oneshot::channel();
         self.input
@@ -468,3 +1015,297 @@ impl std::fmt::Debug for SegmentHandler {
         write!(f, "SegmentHandler: {:?}", self.path)
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::repository::{Compression, Encryption, HMAC};
+    use tempfile::tempdir;
+
+    // With room for only two segments in the cache, reading from a third must evict the least
+    // recently used one
+    #[test]
+    fn ro_cache_evicts_lru() {
+        let tempdir = tempdir().unwrap();
+        let key = Key::random(32);
+        let settings = ChunkSettings::lightweight();
+        // A size limit of one byte closes the segment after every write, so each chunk lands in
+        // its own segment
+        let mut handler = InternalSegmentHandler::open(
+            tempdir.path(),
+            1,
+            100,
+            settings,
+            key.clone(),
+            2,
+            Durability::Full,
+        )
+        .unwrap();
+        let mut locations = Vec::new();
+        for i in 0..3_u8 {
+            let chunk = Chunk::pack(
+                vec![i; 1024],
+                Compression::NoCompression,
+                Encryption::NoEncryption,
+                HMAC::Blake3,
+                &key,
+            );
+            locations.push(handler.write_chunk(chunk).unwrap());
+        }
+        let segments: Vec<u64> = locations.iter().map(|x| x.segment_id).collect();
+        assert_eq!(
+            segments
+                .iter()
+                .collect::<std::collections::HashSet<_>>()
+                .len(),
+            3
+        );
+
+        for location in &locations {
+            handler.read_chunk(*location).unwrap();
+        }
+        let cache = &handler.ro_segment_cache;
+        assert_eq!(cache.len(), 2);
+        assert!(!cache.contains(&segments[0]));
+        assert!(cache.contains(&segments[1]));
+        assert!(cache.contains(&segments[2]));
+
+        // The evicted segment can still be read, evicting the next least recently used one
+        let chunk = handler.read_chunk(locations[0]).unwrap();
+        assert_eq!(chunk.unpack(&key).unwrap(), vec![0_u8; 1024]);
+        let cache = &handler.ro_segment_cache;
+        assert!(cache.contains(&segments[0]));
+        assert!(!cache.contains(&segments[1]));
+    }
+
+    // A repository in the interleaved format must write segments without header files, and read
+    // them back, both through the handler and through verification
+    #[test]
+    fn interleaved_segments_round_trip() {
+        let tempdir = tempdir().unwrap();
+        let key = Key::random(32);
+        let settings = ChunkSettings::lightweight();
+        write_segment_format(tempdir.path(), SegmentFormat::Interleaved).unwrap();
+        let mut handler = InternalSegmentHandler::open(
+            tempdir.path(),
+            1_000_000,
+            100,
+            settings,
+            key.clone(),
+            2,
+            Durability::Full,
+        )
+        .unwrap();
+        let mut locations = Vec::new();
+        for i in 0..5_u8 {
+            let chunk = Chunk::pack(
+                vec![i; 1024],
+                Compression::NoCompression,
+                Encryption::NoEncryption,
+                HMAC::Blake3,
+                &key,
+            );
+            locations.push(handler.write_chunk(chunk).unwrap());
+        }
+        handler.flush().unwrap();
+        for (i, location) in locations.iter().enumerate() {
+            let chunk = handler.read_chunk(*location).unwrap();
+            assert_eq!(chunk.unpack(&key).unwrap(), vec![i as u8; 1024]);
+        }
+        std::mem::drop(handler);
+
+        let data_path = tempdir.path().join("data");
+        let segment_dir = data_path.join("0");
+        assert!(segment_dir.join("0").exists());
+        assert!(!segment_dir.join("0.header").exists());
+        let verification = verify_segments(&data_path, settings, &key, 2).unwrap();
+        assert!(verification.is_ok(), "{:?}", verification);
+        assert_eq!(verification.verified, 5);
+        // The format comes from the segment itself, not from what is beside it
+        File::create(segment_dir.join("0.header")).unwrap();
+        let verification = verify_segments(&data_path, settings, &key, 2).unwrap();
+        assert!(verification.is_ok(), "{:?}", verification);
+        assert_eq!(verification.verified, 5);
+
+        // A record cut short by a crash is removed when the segment is next written to
+        let segment_path = segment_dir.join("0");
+        let length = std::fs::metadata(&segment_path).unwrap().len();
+        std::fs::OpenOptions::new()
+            .write(true)
+            .open(&segment_path)
+            .unwrap()
+            .set_len(length - 10)
+            .unwrap();
+        let mut handler = InternalSegmentHandler::open(
+            tempdir.path(),
+            1_000_000,
+            100,
+            settings,
+            key.clone(),
+            2,
+            Durability::Full,
+        )
+        .unwrap();
+        let location = handler
+            .write_chunk(Chunk::pack(
+                vec![5; 1024],
+                Compression::NoCompression,
+                Encryption::NoEncryption,
+                HMAC::Blake3,
+                &key,
+            ))
+            .unwrap();
+        assert_eq!(location.segment_id, 0);
+        assert_eq!(location.start, locations[4].start);
+        let chunk = handler.read_chunk(location).unwrap();
+        assert_eq!(chunk.unpack(&key).unwrap(), vec![5_u8; 1024]);
+    }
+
+    // Repositories from a newer version of asuran must be refused, rather than misread
+    #[test]
+    fn unknown_format_version_is_rejected() {
+        let tempdir = tempdir().unwrap();
+        assert_eq!(
+            read_segment_format(tempdir.path()).unwrap(),
+            SegmentFormat::Split
+        );
+        std::fs::write(
+            tempdir.path().join("format"),
+            (FORMAT_VERSION + 1).to_be_bytes(),
+        )
+        .unwrap();
+        assert!(matches!(
+            read_segment_format(tempdir.path()),
+            Err(BackendError::UnsupportedFormatVersion(v)) if v == FORMAT_VERSION + 1
+        ));
+    }
+
+    /// Writes a chunk to a fresh repository, then overwrites the asuran version recorded in its
+    /// segment's header
+    fn segment_with_version(path: &Path, key: &Key, version: [u16; 3]) {
+        let mut handler = InternalSegmentHandler::open(
+            path,
+            1_000_000,
+            100,
+            ChunkSettings::lightweight(),
+            key.clone(),
+            2,
+            Durability::Full,
+        )
+        .unwrap();
+        let chunk = Chunk::pack(
+            vec![1; 1024],
+            Compression::NoCompression,
+            Encryption::NoEncryption,
+            HMAC::Blake3,
+            key,
+        );
+        handler.write_chunk(chunk).unwrap();
+        handler.flush().unwrap();
+        std::mem::drop(handler);
+
+        let mut bytes = Vec::new();
+        for component in &version {
+            bytes.extend_from_slice(&component.to_be_bytes());
+        }
+        let segment = path.join("data").join("0").join("0");
+        let mut contents = std::fs::read(&segment).unwrap();
+        // The version follows the magic number and the implementation uuid
+        contents[24..30].copy_from_slice(&bytes);
+        std::fs::write(&segment, contents).unwrap();
+    }
+
+    // Segments written by older versions, or by this one, must open as normal
+    #[test]
+    fn older_and_same_versions_accepted() {
+        let key = Key::random(32);
+        for version in &[[0, 0, 1], *crate::VERSION_PIECES] {
+            let tempdir = tempdir().unwrap();
+            segment_with_version(tempdir.path(), &key, *version);
+            assert!(InternalSegmentHandler::open(
+                tempdir.path(),
+                1_000_000,
+                100,
+                ChunkSettings::lightweight(),
+                key.clone(),
+                2,
+                Durability::Full,
+            )
+            .is_ok());
+        }
+    }
+
+    // Segments written by a newer major version must be refused, rather than written to
+    #[test]
+    fn newer_major_version_rejected() {
+        let key = Key::random(32);
+        let tempdir = tempdir().unwrap();
+        let major = crate::VERSION_PIECES[0] + 1;
+        segment_with_version(tempdir.path(), &key, [major, 0, 0]);
+        assert!(matches!(
+            InternalSegmentHandler::open(
+                tempdir.path(),
+                1_000_000,
+                100,
+                ChunkSettings::lightweight(),
+                key,
+                2,
+                Durability::Full,
+            ),
+            Err(BackendError::IncompatibleVersion { written, .. })
+                if written.major == u64::from(major)
+        ));
+    }
+
+    // Every chunk in every segment should verify, until one of them is corrupted
+    #[test]
+    fn verify_segments_reports_corruption() {
+        let tempdir = tempdir().unwrap();
+        let key = Key::random(32);
+        let settings = ChunkSettings::lightweight();
+        let mut handler = InternalSegmentHandler::open(
+            tempdir.path(),
+            1,
+            100,
+            settings,
+            key.clone(),
+            2,
+            Durability::Full,
+        )
+        .unwrap();
+        let mut locations = Vec::new();
+        for i in 0..5_u8 {
+            let chunk = Chunk::pack(
+                vec![i; 1024],
+                Compression::NoCompression,
+                Encryption::NoEncryption,
+                HMAC::Blake3,
+                &key,
+            );
+            locations.push(handler.write_chunk(chunk).unwrap());
+        }
+        handler.flush().unwrap();
+        std::mem::drop(handler);
+        let data_path = tempdir.path().join("data");
+
+        let verification = verify_segments(&data_path, settings, &key, 3).unwrap();
+        assert!(verification.is_ok(), "{:?}", verification);
+        assert_eq!(verification.verified, 5);
+        assert!(verification.segments >= 5);
+
+        // Flip the last byte of the third chunk's segment, which lies in its body
+        let target = locations[2];
+        let segment_path = data_path
+            .join((target.segment_id / 100).to_string())
+            .join(target.segment_id.to_string());
+        let mut bytes = std::fs::read(&segment_path).unwrap();
+        *bytes.last_mut().unwrap() ^= 0xFF;
+        std::fs::write(&segment_path, bytes).unwrap();
+
+        let verification = verify_segments(&data_path, settings, &key, 3).unwrap();
+        assert!(!verification.is_ok());
+        assert_eq!(verification.verified, 4);
+        assert_eq!(verification.failures.len(), 1);
+        assert_eq!(verification.failures[0].location, target);
+    }
+}
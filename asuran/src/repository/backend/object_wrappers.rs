@@ -22,6 +22,9 @@ impl<T: Manifest> Manifest for ManifestWrapper<T> {
     async fn archive_iterator(&mut self) -> Self::Iterator {
         Box::new(self.0.archive_iterator().await)
     }
+    fn archive_stream(&mut self) -> LocalBoxStream<'_, Result<StoredArchive>> {
+        self.0.archive_stream()
+    }
     async fn write_chunk_settings(&mut self, settings: ChunkSettings) -> Result<()> {
         self.0.write_chunk_settings(settings).await
     }
@@ -31,6 +34,27 @@ impl<T: Manifest> Manifest for ManifestWrapper<T> {
     async fn touch(&mut self) -> Result<()> {
         self.0.touch().await
     }
+    async fn touch_archive(&mut self, archive: StoredArchive) -> Result<()> {
+        self.0.touch_archive(archive).await
+    }
+    async fn last_access(
+        &mut self,
+        archive: StoredArchive,
+    ) -> Result<Option<DateTime<FixedOffset>>> {
+        self.0.last_access(archive).await
+    }
+    async fn delete_archive(&mut self, archive: StoredArchive) -> Result<()> {
+        self.0.delete_archive(archive).await
+    }
+    async fn write_audit_head(&mut self, head: AuditHead) -> Result<()> {
+        self.0.write_audit_head(head).await
+    }
+    async fn audit_head(&mut self) -> Result<Option<AuditHead>> {
+        self.0.audit_head().await
+    }
+    async fn dangling_parents(&mut self) -> Result<Vec<DanglingParent>> {
+        self.0.dangling_parents().await
+    }
 }
 
 #[async_trait]
@@ -45,6 +69,9 @@ impl Manifest for ManifestObject {
     async fn archive_iterator(&mut self) -> Self::Iterator {
         (**self).archive_iterator().await
     }
+    fn archive_stream(&mut self) -> LocalBoxStream<'_, Result<StoredArchive>> {
+        (**self).archive_stream()
+    }
     async fn write_chunk_settings(&mut self, settings: ChunkSettings) -> Result<()> {
         (**self).write_chunk_settings(settings).await
     }
@@ -54,11 +81,32 @@ impl Manifest for ManifestObject {
     async fn touch(&mut self) -> Result<()> {
         (**self).touch().await
     }
+    async fn touch_archive(&mut self, archive: StoredArchive) -> Result<()> {
+        (**self).touch_archive(archive).await
+    }
+    async fn last_access(
+        &mut self,
+        archive: StoredArchive,
+    ) -> Result<Option<DateTime<FixedOffset>>> {
+        (**self).last_access(archive).await
+    }
+    async fn delete_archive(&mut self, archive: StoredArchive) -> Result<()> {
+        (**self).delete_archive(archive).await
+    }
+    async fn write_audit_head(&mut self, head: AuditHead) -> Result<()> {
+        (**self).write_audit_head(head).await
+    }
+    async fn audit_head(&mut self) -> Result<Option<AuditHead>> {
+        (**self).audit_head().await
+    }
+    async fn dangling_parents(&mut self) -> Result<Vec<DanglingParent>> {
+        (**self).dangling_parents().await
+    }
 }
 
 #[async_trait]
 impl Index for IndexObject {
-    async fn lookup_chunk(&mut self, id: ChunkID) -> Option<SegmentDescriptor> {
+    async fn lookup_chunk(&mut self, id: ChunkID) -> Result<Option<SegmentDescriptor>> {
         (**self).lookup_chunk(id).await
     }
     async fn set_chunk(&mut self, id: ChunkID, location: SegmentDescriptor) -> Result<()> {
@@ -98,9 +146,36 @@ impl<T: Backend> Backend for BackendWrapper<T> {
     async fn read_chunk(&mut self, location: SegmentDescriptor) -> Result<Chunk> {
         self.0.read_chunk(location).await
     }
+    async fn read_chunk_header(
+        &mut self,
+        location: SegmentDescriptor,
+    ) -> Result<(ChunkHeader, u64)> {
+        self.0.read_chunk_header(location).await
+    }
     async fn write_chunk(&mut self, chunk: Chunk) -> Result<SegmentDescriptor> {
         self.0.write_chunk(chunk).await
     }
+    async fn prefetch(&mut self, locations: Vec<SegmentDescriptor>) -> Result<()> {
+        self.0.prefetch(locations).await
+    }
+    async fn remove_chunk(&mut self, id: ChunkID) -> Result<()> {
+        self.0.remove_chunk(id).await
+    }
+    async fn lock_exclusive(&mut self) -> Result<()> {
+        self.0.lock_exclusive().await
+    }
+    async fn unlock_exclusive(&mut self) -> Result<()> {
+        self.0.unlock_exclusive().await
+    }
+    async fn compact(&mut self) -> Result<u64> {
+        self.0.compact().await
+    }
+    async fn sync(&mut self) -> Result<()> {
+        self.0.sync().await
+    }
+    async fn ping(&mut self) -> Result<()> {
+        self.0.ping().await
+    }
     async fn close(&mut self) {
         self.0.close().await
     }
@@ -128,9 +203,36 @@ impl Backend for BackendObject {
     async fn read_chunk(&mut self, location: SegmentDescriptor) -> Result<Chunk> {
         (**self).read_chunk(location).await
     }
+    async fn read_chunk_header(
+        &mut self,
+        location: SegmentDescriptor,
+    ) -> Result<(ChunkHeader, u64)> {
+        (**self).read_chunk_header(location).await
+    }
     async fn write_chunk(&mut self, chunk: Chunk) -> Result<SegmentDescriptor> {
         (**self).write_chunk(chunk).await
     }
+    async fn prefetch(&mut self, locations: Vec<SegmentDescriptor>) -> Result<()> {
+        (**self).prefetch(locations).await
+    }
+    async fn remove_chunk(&mut self, id: ChunkID) -> Result<()> {
+        (**self).remove_chunk(id).await
+    }
+    async fn lock_exclusive(&mut self) -> Result<()> {
+        (**self).lock_exclusive().await
+    }
+    async fn unlock_exclusive(&mut self) -> Result<()> {
+        (**self).unlock_exclusive().await
+    }
+    async fn compact(&mut self) -> Result<u64> {
+        (**self).compact().await
+    }
+    async fn sync(&mut self) -> Result<()> {
+        (**self).sync().await
+    }
+    async fn ping(&mut self) -> Result<()> {
+        (**self).ping().await
+    }
     async fn close(&mut self) {
         (**self).close().await
     }
@@ -1,16 +1,21 @@
 //! Provides access to a remote `MultiFile` repository over SFTP as if it were a local Multi-File
 //! Repository
+pub use super::RetryPolicy;
 use super::{BackendError, Result, SegmentDescriptor};
-use crate::repository::backend::common::sync_backend::{BackendHandle, SyncBackend, SyncManifest};
-use crate::repository::{Chunk, ChunkSettings, EncryptedKey, Key};
+use crate::repository::backend::common::sync_backend::{
+    BackendHandle, SyncBackend, SyncIndex, SyncManifest,
+};
+use crate::repository::{Chunk, ChunkID, ChunkSettings, EncryptedKey, Key};
 
 use serde_cbor as cbor;
-use ssh2::{Session, Sftp};
+use ssh2::{ErrorCode, Session, Sftp};
+use tracing::warn;
 
 use std::fmt::Debug;
 use std::net::TcpStream;
 use std::path::PathBuf;
 use std::rc::Rc;
+use std::thread;
 
 pub mod index;
 pub mod manifest;
@@ -23,15 +28,84 @@ use self::segment::SFTPSegmentHandler;
 use self::util::LockedFile;
 
 // Allow our result type to accept the ssh2 errors easily
-// Maps to `BackendError::ConnectionError(error.to_string())`
+// Errors reported by the SFTP subsystem (missing files, permissions, etc) map to
+// `BackendError::IOError`, everything else maps to `BackendError::ConnectionError`
 impl From<ssh2::Error> for BackendError {
     fn from(error: ssh2::Error) -> Self {
-        BackendError::ConnectionError(format!("libssh2 Error: {}", error))
+        match error.code() {
+            ErrorCode::SFTP(_) => BackendError::IOError(error.into()),
+            ErrorCode::Session(_) => {
+                BackendError::ConnectionError(format!("libssh2 Error: {}", error))
+            }
+        }
+    }
+}
+
+/// Converts an `ssh2::Error` the same way as the `From` impl, but with some added context in the
+/// message
+fn describe_error(error: ssh2::Error, context: &str) -> BackendError {
+    let message = format!("{}, Error was: {}", context, error);
+    match error.code() {
+        ErrorCode::SFTP(_) => {
+            let kind = std::io::Error::from(error).kind();
+            BackendError::IOError(std::io::Error::new(kind, message))
+        }
+        ErrorCode::Session(_) => BackendError::ConnectionError(message),
+    }
+}
+
+/// Something holding a connection that can be re-established after it drops
+pub trait Reconnect {
+    /// Tears down the current connection, if any, and connects again
+    fn reconnect(&mut self) -> Result<()>;
+    /// The policy to follow when retrying operations on this connection
+    fn retry_policy(&self) -> RetryPolicy;
+}
+
+/// Runs an operation, reconnecting and retrying it according to the target's `RetryPolicy` if it
+/// fails with `BackendError::ConnectionError`
+///
+/// Any other kind of error is returned immediately. If the policy's attempts are exhausted, a
+/// `BackendError::RetriesExhausted` describing the last failure is returned, so that callers know
+/// not to retry it again.
+pub fn with_reconnect<C, T, F>(target: &mut C, mut operation: F) -> Result<T>
+where
+    C: Reconnect + ?Sized,
+    F: FnMut(&mut C) -> Result<T>,
+{
+    let policy = target.retry_policy();
+    let mut attempt = 0;
+    loop {
+        let error = match operation(target) {
+            Err(BackendError::ConnectionError(error)) => error,
+            result => return result,
+        };
+        if attempt >= policy.max_attempts {
+            return Err(BackendError::RetriesExhausted {
+                attempts: attempt,
+                error,
+            });
+        }
+        warn!(
+            "SFTP operation failed with a connection error, reconnecting (attempt {} of {}): {}",
+            attempt + 1,
+            policy.max_attempts,
+            error
+        );
+        thread::sleep(policy.backoff(attempt));
+        attempt += 1;
+        // A failed reconnect is treated like any other failure of the operation, the next pass
+        // will either retry it or give up
+        if let Err(e) = target.reconnect() {
+            warn!("Failed to reconnect to SFTP server: {}", e);
+        }
     }
 }
 
 /// Settings used for connecting to an SFTP server.
-#[derive(Clone, Debug)]
+///
+/// The `Default` settings have no host, user, or path set, and use the default `RetryPolicy`.
+#[derive(Clone, Debug, Default)]
 pub struct SFTPSettings {
     /// Hostname of the SFTP server to connect to.
     pub hostname: String,
@@ -45,6 +119,8 @@ pub struct SFTPSettings {
     pub password: Option<String>,
     /// Path of the repository on the server
     pub path: String,
+    /// How to go about reconnecting if the connection drops
+    pub retry: RetryPolicy,
 }
 
 #[derive(Clone)]
@@ -155,6 +231,40 @@ impl SFTPConnection {
     }
 }
 
+impl Reconnect for SFTPConnection {
+    fn reconnect(&mut self) -> Result<()> {
+        *self = SFTPConnection::NotConnected {
+            settings: self.settings().clone(),
+        };
+        self.connect()
+    }
+    fn retry_policy(&self) -> RetryPolicy {
+        self.settings().retry
+    }
+}
+
+impl SFTPSettings {
+    /// Creates settings for connecting to the repository at `path` on `hostname` as `username`,
+    /// on the default port, using ssh-agent authentication and the default `RetryPolicy`
+    pub fn new(
+        hostname: impl Into<String>,
+        username: impl Into<String>,
+        path: impl Into<String>,
+    ) -> SFTPSettings {
+        SFTPSettings {
+            hostname: hostname.into(),
+            username: username.into(),
+            path: path.into(),
+            ..SFTPSettings::default()
+        }
+    }
+
+    /// Sets how to go about reconnecting if the connection drops
+    pub fn set_retry_policy(&mut self, retry: RetryPolicy) {
+        self.retry = retry;
+    }
+}
+
 impl From<SFTPSettings> for SFTPConnection {
     fn from(settings: SFTPSettings) -> Self {
         SFTPConnection::NotConnected { settings }
@@ -244,26 +354,68 @@ impl SFTP {
         }
     }
 
+    /// Checks if there is a repository at the location described by the settings, without
+    /// opening it or requiring its key
+    ///
+    /// This only checks that the repository's key file exists.
+    ///
+    /// # Errors
+    ///
+    /// Will return `Err` if connecting to the server fails.
+    pub fn exists<S>(settings: S) -> Result<bool>
+    where
+        S: Into<SFTPConnection>,
+    {
+        let mut connection = settings.into();
+        with_reconnect(&mut connection, |connection| {
+            connection.connect()?;
+            let sftp = connection
+                .sftp()
+                .ok_or_else(|| BackendError::ConnectionError("Not connected".to_string()))?;
+            let key_path = PathBuf::from(&connection.settings().path).join("key");
+            Ok(sftp.stat(&key_path).is_ok())
+        })
+    }
+
     pub fn read_key<S>(settings: S) -> Result<EncryptedKey>
     where
         S: Into<SFTPConnection>,
     {
-        let connection = settings.into().with_connection()?;
-        let sftp = connection.sftp().unwrap();
-        let key_path = PathBuf::from(&connection.settings().path).join("key");
-        let key_path = sftp.realpath(&key_path).map_err(|e| {
-            BackendError::ConnectionError(format!(
-                "Failed to resolve path of key file at: {:?}, Error was: {}",
-                key_path, e
-            ))
-        })?;
-        let file = sftp.open(&key_path).map_err(|e| {
-            BackendError::ConnectionError(format!(
-                "Failed to open key file at: {:?} Error was: {}",
-                key_path, e
-            ))
-        })?;
-        Ok(cbor::de::from_reader(file)?)
+        let mut connection = settings.into();
+        with_reconnect(&mut connection, |connection| {
+            connection.connect()?;
+            let sftp = connection
+                .sftp()
+                .ok_or_else(|| BackendError::ConnectionError("Not connected".to_string()))?;
+            let key_path = PathBuf::from(&connection.settings().path).join("key");
+            let key_path = sftp.realpath(&key_path).map_err(|e| {
+                describe_error(
+                    e,
+                    &format!("Failed to resolve path of key file at: {:?}", key_path),
+                )
+            })?;
+            let file = sftp.open(&key_path).map_err(|e| {
+                describe_error(e, &format!("Failed to open key file at: {:?}", key_path))
+            })?;
+            Ok(cbor::de::from_reader(file)?)
+        })
+    }
+}
+
+impl Reconnect for SFTP {
+    /// Reconnects to the server, and reopens the files held by the manifest, index, and segment
+    /// handler on the new session
+    ///
+    /// The lock files they hold are left in place on the server, so their locks carry over.
+    fn reconnect(&mut self) -> Result<()> {
+        self.connection.reconnect()?;
+        self.manifest.set_connection(&self.connection)?;
+        self.index.set_connection(&self.connection)?;
+        self.segment_handler.set_connection(&self.connection)?;
+        Ok(())
+    }
+    fn retry_policy(&self) -> RetryPolicy {
+        self.connection.retry_policy()
     }
 }
 
@@ -277,25 +429,65 @@ impl SyncBackend for SFTP {
         &mut self.manifest
     }
     fn write_key(&mut self, key: EncryptedKey) -> Result<()> {
-        let key_path = PathBuf::from(&self.connection.settings().path).join("key");
-        let sftp = self.connection.sftp().expect("Somehow not connected");
-        let mut file =
-            LockedFile::open_read_write(&key_path, sftp)?.ok_or(BackendError::FileLockError)?;
-
-        cbor::ser::to_writer(&mut file, &key)?;
-        Ok(())
+        with_reconnect(self, |backend| {
+            let key_path = PathBuf::from(&backend.connection.settings().path).join("key");
+            let sftp = backend
+                .connection
+                .sftp()
+                .ok_or_else(|| BackendError::ConnectionError("Not connected".to_string()))?;
+            let mut file = LockedFile::open_read_write(&key_path, Rc::clone(&sftp))?
+                .ok_or(BackendError::FileLockError)?;
+            // Stage the key beside the old one, rather than truncating the old one in place
+            let bytes = cbor::ser::to_vec(&key)?;
+            file.replace_contents(&bytes)?;
+            Ok(())
+        })
     }
     fn read_key(&mut self) -> Result<EncryptedKey> {
-        let key_path = PathBuf::from(&self.connection.settings().path).join("key");
-        let sftp = self.connection.sftp().expect("Somehow not connected");
-        let file = sftp.open(&key_path)?;
-        Ok(cbor::de::from_reader(file)?)
+        with_reconnect(self, |backend| {
+            let key_path = PathBuf::from(&backend.connection.settings().path).join("key");
+            let sftp = backend
+                .connection
+                .sftp()
+                .ok_or_else(|| BackendError::ConnectionError("Not connected".to_string()))?;
+            let file = sftp.open(&key_path)?;
+            Ok(cbor::de::from_reader(file)?)
+        })
     }
     fn read_chunk(&mut self, location: SegmentDescriptor) -> Result<Chunk> {
-        self.segment_handler.read_chunk(location)
+        with_reconnect(self, |backend| backend.segment_handler.read_chunk(location))
     }
     fn write_chunk(&mut self, chunk: Chunk) -> Result<SegmentDescriptor> {
-        self.segment_handler.write_chunk(chunk)
+        with_reconnect(self, |backend| {
+            backend.segment_handler.write_chunk(chunk.clone())
+        })
+    }
+    fn prefetch(&mut self, locations: &[SegmentDescriptor]) -> Result<()> {
+        with_reconnect(self, |backend| backend.segment_handler.prefetch(locations))
+    }
+    fn remove_chunk(&mut self, id: ChunkID) -> Result<()> {
+        self.index.remove_chunk(id)
+    }
+    fn retrying<T>(&mut self, operation: impl FnMut(&mut Self) -> Result<T>) -> Result<T> {
+        with_reconnect(self, operation)
+    }
+    /// Writes out the buffered part of the current segment, and commits the index
+    fn sync(&mut self) -> Result<()> {
+        with_reconnect(self, |backend| {
+            backend.segment_handler.flush()?;
+            backend.index.commit_index()
+        })
+    }
+    /// Stats the repository directory on the server
+    fn ping(&mut self) -> Result<()> {
+        with_reconnect(self, |backend| {
+            let sftp = backend
+                .connection
+                .sftp()
+                .ok_or_else(|| BackendError::ConnectionError("Not connected".to_string()))?;
+            sftp.stat(&PathBuf::from(&backend.connection.settings().path))?;
+            Ok(())
+        })
     }
 }
 
@@ -328,6 +520,7 @@ mod tests {
             port: Some(port),
             password: Some(password),
             path,
+            retry: RetryPolicy::default(),
         }
     }
 
@@ -428,6 +621,7 @@ mod tests {
             port: Some(port),
             password: None,
             path: "OhNo!".to_string(),
+            retry: RetryPolicy::default(),
         };
 
         let connection: SFTPConnection = settings.into();
@@ -461,6 +655,7 @@ mod tests {
             port: Some(port),
             password: Some(password),
             path: "yes".to_string(),
+            retry: RetryPolicy::default(),
         };
 
         let connection: SFTPConnection = settings.into();
@@ -468,4 +663,91 @@ mod tests {
         let connection = connection.with_connection().unwrap();
         assert!(connection.session().is_some());
     }
+
+    /// Stands in for a connection that drops the first `failures` operations run on it
+    struct FlakyConnection {
+        failures: u32,
+        calls: u32,
+        reconnects: u32,
+        policy: RetryPolicy,
+    }
+
+    impl Reconnect for FlakyConnection {
+        fn reconnect(&mut self) -> Result<()> {
+            self.reconnects += 1;
+            Ok(())
+        }
+        fn retry_policy(&self) -> RetryPolicy {
+            self.policy
+        }
+    }
+
+    fn flaky(failures: u32) -> FlakyConnection {
+        FlakyConnection {
+            failures,
+            calls: 0,
+            reconnects: 0,
+            policy: RetryPolicy {
+                max_attempts: 3,
+                initial_backoff: std::time::Duration::from_millis(1),
+                max_backoff: std::time::Duration::from_millis(4),
+            },
+        }
+    }
+
+    fn flaky_operation(connection: &mut FlakyConnection) -> Result<u32> {
+        connection.calls += 1;
+        if connection.calls <= connection.failures {
+            Err(BackendError::ConnectionError(
+                "Connection dropped".to_string(),
+            ))
+        } else {
+            Ok(connection.calls)
+        }
+    }
+
+    // Operations should be retried after reconnecting until they succeed or the policy runs out
+    #[test]
+    fn reconnect_retries() {
+        let mut connection = flaky(2);
+        let result = with_reconnect(&mut connection, flaky_operation);
+        assert_eq!(result.unwrap(), 3);
+        assert_eq!(connection.reconnects, 2);
+
+        let mut connection = flaky(4);
+        let result = with_reconnect(&mut connection, flaky_operation);
+        assert!(matches!(
+            result,
+            Err(BackendError::RetriesExhausted { attempts: 3, .. })
+        ));
+        assert!(!result.unwrap_err().is_retryable());
+        assert_eq!(connection.calls, 4);
+        assert_eq!(connection.reconnects, 3);
+    }
+
+    // Errors that are not connection errors should not be retried
+    #[test]
+    fn reconnect_ignores_other_errors() {
+        let mut connection = flaky(0);
+        let result: Result<()> = with_reconnect(&mut connection, |connection| {
+            connection.calls += 1;
+            Err(BackendError::FileLockError)
+        });
+        assert!(matches!(result, Err(BackendError::FileLockError)));
+        assert_eq!(connection.calls, 1);
+        assert_eq!(connection.reconnects, 0);
+    }
+
+    #[test]
+    fn backoff_doubles_and_caps() {
+        let policy = RetryPolicy {
+            max_attempts: 10,
+            initial_backoff: std::time::Duration::from_secs(1),
+            max_backoff: std::time::Duration::from_secs(5),
+        };
+        assert_eq!(policy.backoff(0), std::time::Duration::from_secs(1));
+        assert_eq!(policy.backoff(2), std::time::Duration::from_secs(4));
+        assert_eq!(policy.backoff(3), std::time::Duration::from_secs(5));
+        assert_eq!(policy.backoff(40), std::time::Duration::from_secs(5));
+    }
 }
@@ -9,7 +9,7 @@ use serde_cbor as cbor;
 
 use std::collections::HashMap;
 use std::collections::HashSet;
-use std::io::{BufWriter, Seek, SeekFrom};
+use std::io::{BufWriter, Seek, SeekFrom, Write};
 use std::path::PathBuf;
 use std::rc::Rc;
 
@@ -18,6 +18,11 @@ pub struct SFTPIndex {
     state: HashMap<ChunkID, SegmentDescriptor>,
     file: LockedFile,
     changes: Vec<IndexTransaction>,
+    /// Length of the index file before a commit that has not yet succeeded
+    ///
+    /// Anything past it is the partial tail of the failed commit, and is truncated away before
+    /// the next one.
+    uncommitted: Option<u64>,
 }
 
 impl SFTPIndex {
@@ -77,7 +82,11 @@ impl SFTPIndex {
             let de = cbor::Deserializer::from_reader(&mut file);
             let mut de = de.into_iter::<IndexTransaction>();
             while let Some(tx) = de.next().and_then(std::result::Result::ok) {
-                state.insert(tx.chunk_id, tx.descriptor);
+                if tx.removed {
+                    state.remove(&tx.chunk_id);
+                } else {
+                    state.insert(tx.chunk_id, tx.descriptor);
+                }
             }
         }
 
@@ -89,6 +98,7 @@ impl SFTPIndex {
                     state,
                     file,
                     changes: Vec::new(),
+                    uncommitted: None,
                 });
             }
         }
@@ -108,8 +118,37 @@ impl SFTPIndex {
             state,
             file,
             changes: Vec::new(),
+            uncommitted: None,
         })
     }
+
+    /// Reopens the index file on a new connection, such as after a reconnect
+    pub fn set_connection(&mut self, connection: &SFTPConnection) -> Result<()> {
+        let sftp = connection
+            .sftp()
+            .ok_or_else(|| BackendError::ConnectionError("Not connected".to_string()))?;
+        self.file.reopen(sftp)?;
+        Ok(())
+    }
+
+    /// Removes a chunk from the index, recording the removal in the index log
+    ///
+    /// Removing a chunk that is not in the index does nothing.
+    ///
+    /// The space used by the chunk is not reclaimed, its body stays in its segment on the server,
+    /// as the SFTP backend does not implement `Backend::compact`. The repository is in the
+    /// `MultiFile` format, so the space can be reclaimed by opening it with the `MultiFile`
+    /// backend on the server and compacting it there.
+    pub fn remove_chunk(&mut self, id: ChunkID) -> Result<()> {
+        if let Some(descriptor) = self.state.remove(&id) {
+            self.changes.push(IndexTransaction {
+                chunk_id: id,
+                descriptor,
+                removed: true,
+            });
+        }
+        Ok(())
+    }
 }
 
 impl SyncIndex for SFTPIndex {
@@ -123,26 +162,38 @@ impl SyncIndex for SFTPIndex {
             let transaction = IndexTransaction {
                 chunk_id: id,
                 descriptor: location,
+                removed: false,
             };
             self.changes.push(transaction);
         }
         Ok(())
     }
+    /// Returns the ids of every chunk in the index
+    ///
+    /// The in-memory state already reflects uncommitted additions and removals, so chunks removed
+    /// since the last commit are not included.
     fn known_chunks(&mut self) -> HashSet<ChunkID> {
-        self.state
-            .keys()
-            .copied()
-            .chain(self.changes.iter().map(|x| x.chunk_id))
-            .collect()
+        self.state.keys().copied().collect()
     }
+    /// Appends the pending changes to the index file
+    ///
+    /// The changes are only discarded once they have all been written, so a commit that fails
+    /// part way through, such as from the connection dropping, can be retried.
     fn commit_index(&mut self) -> Result<()> {
+        if let Some(length) = self.uncommitted {
+            self.file.truncate(length)?;
+        }
+        let length = self.file.seek(SeekFrom::End(0))?;
+        self.uncommitted = Some(length);
         let mut file = BufWriter::new(&mut self.file);
-        file.seek(SeekFrom::End(0))?;
-        for tx in self.changes.drain(0..self.changes.len()) {
-            cbor::ser::to_writer(&mut file, &tx)?;
+        for tx in &self.changes {
+            cbor::ser::to_writer(&mut file, tx)?;
         }
+        file.flush()?;
         drop(file);
         self.file.fsync()?;
+        self.changes.clear();
+        self.uncommitted = None;
         Ok(())
     }
     fn chunk_count(&mut self) -> usize {
@@ -153,7 +204,7 @@ impl SyncIndex for SFTPIndex {
 #[cfg(test)]
 mod tests {
     use super::*;
-    use crate::prelude::sftp::SFTPSettings;
+    use crate::prelude::sftp::{RetryPolicy, SFTPSettings};
     use std::env;
 
     fn get_settings(path: String) -> SFTPSettings {
@@ -178,6 +229,7 @@ mod tests {
             port: Some(port),
             password: Some(password),
             path,
+            retry: RetryPolicy::default(),
         }
     }
 
@@ -251,4 +303,32 @@ mod tests {
         let new_chunks = index.known_chunks();
         assert!(new_chunks == chunks);
     }
+    #[test]
+    fn removed_chunks_not_known() {
+        let mut index = get_index("asuran/index_removed_chunks_not_known");
+        let descriptor = SegmentDescriptor {
+            segment_id: 42,
+            start: 43,
+        };
+        let chunks: Vec<ChunkID> = (0..10).map(|_| ChunkID::random_id()).collect();
+        for chunk in &chunks {
+            index
+                .set_chunk(*chunk, descriptor)
+                .expect("Unable to set chunk");
+        }
+        // Remove one chunk before the commit, and one after it
+        index
+            .remove_chunk(chunks[0])
+            .expect("Unable to remove chunk");
+        index.commit_index().expect("Unable to commit index");
+        index
+            .remove_chunk(chunks[1])
+            .expect("Unable to remove chunk");
+        let expected: HashSet<ChunkID> = chunks[2..].iter().copied().collect();
+        assert_eq!(index.known_chunks(), expected);
+        index.commit_index().expect("Unable to commit index");
+        drop(index);
+        let mut index = get_index("asuran/index_removed_chunks_not_known");
+        assert_eq!(index.known_chunks(), expected);
+    }
 }
@@ -1,13 +1,16 @@
 use super::util::LockedFile;
 use super::SFTPConnection;
+use crate::repository::audit::AuditHead;
 use crate::repository::backend::common::sync_backend::SyncManifest;
-use crate::repository::backend::common::{ManifestID, ManifestTransaction};
+use crate::repository::backend::common::{
+    archive_transactions, dangling_parents, find_heads, latest_access, latest_audit_head,
+    verify_transactions, DanglingParent, ManifestID, ManifestTransaction,
+};
 use crate::repository::backend::BackendError;
 use crate::repository::{ChunkSettings, Key};
 use crate::{manifest::StoredArchive, repository::backend::Result};
 
 use chrono::prelude::*;
-use petgraph::Graph;
 use serde_cbor as cbor;
 use ssh2::FileStat;
 
@@ -26,6 +29,8 @@ pub struct SFTPManifest {
     key: Key,
     chunk_settings: ChunkSettings,
     path: PathBuf,
+    /// Length of the manifest file before a write that has not yet succeeded, see `append`
+    uncommitted: Option<u64>,
 }
 
 impl SFTPManifest {
@@ -154,6 +159,7 @@ impl SFTPManifest {
             key: key.clone(),
             chunk_settings,
             path: manifest_path,
+            uncommitted: None,
         };
         // Build the list of heads
         manifest.build_heads();
@@ -170,62 +176,51 @@ impl SFTPManifest {
         Ok(manifest)
     }
 
-    /// Gets the heads from a list of transactions
-    fn build_heads(&mut self) {
-        // Create the graph
-        let mut graph: Graph<ManifestID, ()> = Graph::new();
-        let mut index_map = HashMap::new();
-        // Add each transaction to our map
-        for tx in self.known_entries.values() {
-            let tag = tx.tag();
-            let id = graph.add_node(tag);
-            index_map.insert(tag, id);
-        }
-        // Go through each transaction in the graph, adding an edge in the new -> old direction
-        // These unwraps are safe because we just added these entries to our hashmap
-        for tx in self.known_entries.values() {
-            let id = index_map.get(&tx.tag()).unwrap();
-            for other_tx in tx.previous_heads() {
-                let other_id = index_map.get(&other_tx).unwrap();
-                graph.update_edge(*id, *other_id, ());
-            }
-        }
-        // reverse all the nodes, so they now point from old to new
-        graph.reverse();
-        // Find all nodes with no outgoing edges, these are our heads
-        let mut heads = Vec::new();
-        for (tag, id) in &index_map {
-            let mut edges = graph.edges(*id);
-            if edges.next() == None {
-                heads.push(*tag);
-            }
+    /// Switches over to a new connection, such as after a reconnect, reopening the manifest file
+    pub fn set_connection(&mut self, connection: &SFTPConnection) -> Result<()> {
+        let sftp = connection
+            .sftp()
+            .ok_or_else(|| BackendError::ConnectionError("Not connected".to_string()))?;
+        self.file.reopen(sftp)?;
+        self.connection = connection.clone();
+        Ok(())
+    }
+
+    /// Appends a transaction to the manifest file, and makes it the only head
+    ///
+    /// If a previous write failed part way through, such as from the connection dropping, its
+    /// partial tail is truncated away first, as it would otherwise hide every transaction written
+    /// after it from readers.
+    fn append(&mut self, tx: ManifestTransaction) -> Result<()> {
+        if let Some(length) = self.uncommitted {
+            self.file.truncate(length)?;
         }
+        let length = self.file.seek(SeekFrom::End(0))?;
+        self.uncommitted = Some(length);
+        cbor::ser::to_writer(&mut self.file, &tx)?;
+        self.uncommitted = None;
+        let id = tx.tag();
+        self.known_entries.insert(id, tx);
+        self.heads = vec![id];
+        Ok(())
+    }
 
-        self.heads = heads;
+    /// Gets the heads from a list of transactions
+    ///
+    /// Transactions referencing a previous head that is missing are treated as roots, see
+    /// `find_heads`
+    fn build_heads(&mut self) {
+        self.heads = find_heads(&self.known_entries);
     }
 
-    /// Verifies a transaction and all of its parents
+    /// Verifies a transaction and all of its parents, see `verify_transactions`
     fn verify_tx(&mut self, id: ManifestID) -> bool {
-        if self.verified_memo_pad.contains(&id) {
-            true
-        } else {
-            let tx = self
-                .known_entries
-                .get(&id)
-                .expect("Item in verified memo pad was not in known_entries")
-                .clone();
-            if tx.verify(&self.key) {
-                self.verified_memo_pad.insert(id);
-                for parent in tx.previous_heads() {
-                    if !self.verify_tx(*parent) {
-                        return false;
-                    }
-                }
-                true
-            } else {
-                false
-            }
-        }
+        verify_transactions(
+            &self.known_entries,
+            id,
+            &self.key,
+            &mut self.verified_memo_pad,
+        )
     }
 }
 
@@ -239,13 +234,13 @@ impl SyncManifest for SFTPManifest {
                 .known_entries
                 .get(&self.heads[0])
                 .expect("Item in heads was not in known entries");
-            let mut max = first_head.timestamp();
+            let mut max = first_head.modified();
             for id in &self.heads {
                 let tx = self.known_entries.get(id).ok_or_else(|| {
                     BackendError::ManifestError("Unable to load timestamp".to_string())
                 })?;
-                if tx.timestamp() > max {
-                    max = tx.timestamp()
+                if tx.modified() > max {
+                    max = tx.modified()
                 }
             }
             Ok(max)
@@ -255,10 +250,7 @@ impl SyncManifest for SFTPManifest {
         self.chunk_settings
     }
     fn archive_iterator(&mut self) -> Self::Iterator {
-        let mut items = self.known_entries.values().cloned().collect::<Vec<_>>();
-        items.sort_by(|a, b| a.timestamp().cmp(&b.timestamp()));
-        items.reverse();
-        items
+        archive_transactions(self.known_entries.values())
             .into_iter()
             .map(StoredArchive::from)
             .collect::<Vec<_>>()
@@ -298,28 +290,59 @@ impl SyncManifest for SFTPManifest {
             self.chunk_settings.hmac,
             &self.key,
         );
-        // Write the transaction to the file
-        let file = &mut self.file;
-        file.seek(SeekFrom::End(0))?;
-        cbor::ser::to_writer(file, &tx)?;
-        // Add the transaction to our entries list
-        let id = tx.tag();
-        self.known_entries.insert(id, tx);
-        // Update our heads to only contain this transaction
-        self.heads = vec![id];
-        Ok(())
+        self.append(tx)
     }
     fn touch(&mut self) -> Result<()> {
         // Touch doesn't actually do anything with this implementation
         Ok(())
     }
+    fn touch_archive(&mut self, archive: StoredArchive) -> Result<()> {
+        let tx = ManifestTransaction::new_access(
+            &self.heads,
+            archive.id(),
+            archive.timestamp(),
+            Local::now().with_timezone(Local::now().offset()),
+            self.chunk_settings.hmac,
+            &self.key,
+        );
+        self.append(tx)
+    }
+    fn last_access(&mut self, archive: StoredArchive) -> Result<Option<DateTime<FixedOffset>>> {
+        Ok(latest_access(self.known_entries.values(), archive.id()))
+    }
+    fn delete_archive(&mut self, archive: StoredArchive) -> Result<()> {
+        let tx = ManifestTransaction::new_deletion(
+            &self.heads,
+            archive.id(),
+            archive.timestamp(),
+            self.chunk_settings.hmac,
+            &self.key,
+        );
+        self.append(tx)
+    }
+    fn write_audit_head(&mut self, head: AuditHead) -> Result<()> {
+        let tx = ManifestTransaction::new_audit_head(
+            &self.heads,
+            head,
+            Local::now().with_timezone(Local::now().offset()),
+            self.chunk_settings.hmac,
+            &self.key,
+        );
+        self.append(tx)
+    }
+    fn audit_head(&mut self) -> Result<Option<AuditHead>> {
+        Ok(latest_audit_head(self.known_entries.values()))
+    }
+    fn dangling_parents(&mut self) -> Result<Vec<DanglingParent>> {
+        Ok(dangling_parents(&self.known_entries))
+    }
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
     use crate::prelude::{Compression, Encryption, HMAC};
-    use crate::repository::backend::sftp::SFTPSettings;
+    use crate::repository::backend::sftp::{RetryPolicy, SFTPSettings};
     use std::collections::HashSet;
     use std::env;
 
@@ -345,6 +368,7 @@ mod tests {
             port: Some(port),
             password: Some(password),
             path,
+            retry: RetryPolicy::default(),
         }
     }
 
@@ -442,4 +466,31 @@ mod tests {
 
         assert!(dummy_archives == output);
     }
+
+    #[test]
+    fn touch_archive() {
+        let key = Key::random(32);
+        let mut manifest = get_manifest(
+            "asuran/manifest_touch_archive",
+            &key,
+            Some(ChunkSettings::lightweight()),
+        );
+        let archive = StoredArchive::dummy_archive();
+        manifest.write_archive(archive.clone()).unwrap();
+        std::thread::sleep(std::time::Duration::from_millis(5));
+        manifest.touch_archive(archive.clone()).unwrap();
+        drop(manifest);
+        let mut manifest = get_manifest("asuran/manifest_touch_archive", &key, None);
+        let accessed = manifest
+            .last_access(archive.clone())
+            .unwrap()
+            .expect("Access time was not recorded");
+        assert!(accessed > archive.timestamp());
+        let archives: Vec<StoredArchive> = manifest.archive_iterator().collect();
+        assert!(archives.contains(&archive));
+        assert_eq!(
+            archives.iter().filter(|x| x.id() == archive.id()).count(),
+            1
+        );
+    }
 }
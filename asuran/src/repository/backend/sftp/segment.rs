@@ -1,6 +1,7 @@
 use super::util::LockedFile;
 use super::SFTPConnection;
 use crate::repository::backend::common::segment::Segment;
+use crate::repository::backend::common::segments_to_prefetch;
 use crate::repository::backend::{BackendError, Result, SegmentDescriptor};
 use crate::repository::{Chunk, ChunkSettings, Key};
 
@@ -34,6 +35,8 @@ pub struct SFTPSegmentHandler {
     chunk_settings: ChunkSettings,
     /// The key used for encrypting/decrypting headers
     key: Key,
+    /// The segment and length of its data file before a write that has not yet succeeded
+    uncommitted: Option<(u64, u64)>,
 }
 
 impl SFTPSegmentHandler {
@@ -98,6 +101,7 @@ impl SFTPSegmentHandler {
             segments_per_directory,
             chunk_settings,
             key,
+            uncommitted: None,
         };
         // Open the writing segment, to ensure that the data directory is lockable
         segment_handler.open_segment_write()?;
@@ -117,10 +121,7 @@ impl SFTPSegmentHandler {
         // Insert the segment into the cache if it doesn't exist
         if !self.ro_segment_cache.contains(&segment_id) {
             if !self.segment_exists(segment_id) {
-                return Err(BackendError::SegmentError(format!(
-                    "Segment with id {} or its containing folder does not exist",
-                    segment_id
-                )));
+                return Err(BackendError::SegmentNotFound(segment_id));
             }
 
             let sftp = self.connection.sftp().unwrap();
@@ -256,28 +257,69 @@ impl SFTPSegmentHandler {
         Ok(self.current_segment.as_mut().unwrap())
     }
 
+    /// Switches this handler over to a new connection, such as after a reconnect
+    ///
+    /// The read only segments belong to the old session and are discarded, while the segment
+    /// being written to is reopened on the new one, keeping its lock.
+    pub fn set_connection(&mut self, connection: &SFTPConnection) -> Result<()> {
+        self.ro_segment_cache.clear();
+        self.connection = connection.clone();
+        if let Some(segment) = self.current_segment.as_mut() {
+            let sftp = self
+                .connection
+                .sftp()
+                .ok_or_else(|| BackendError::ConnectionError("Not connected".to_string()))?;
+            for handle in segment.1.handles_mut() {
+                handle.reopen(Rc::clone(&sftp))?;
+            }
+        }
+        Ok(())
+    }
+
     pub fn read_chunk(&mut self, location: SegmentDescriptor) -> Result<Chunk> {
         let segment_id = location.segment_id;
         let segment = self.open_segment_read(segment_id)?;
         segment.1.read_chunk(location.start)
     }
 
+    /// Writes a chunk to the current segment
+    ///
+    /// If a previous write to the segment failed part way through, such as from the connection
+    /// dropping, its partial tail is truncated away first.
     pub fn write_chunk(&mut self, chunk: Chunk) -> Result<SegmentDescriptor> {
-        // Write the chunk
+        let uncommitted = self.uncommitted.take();
         let segment = self.open_segment_write()?;
+        let segment_id = segment.0;
+        if let Some((uncommitted_id, length)) = uncommitted {
+            if uncommitted_id == segment_id {
+                segment.1.handles_mut()[0].truncate(length)?;
+            }
+        }
+        let length = segment.1.size();
+        self.uncommitted = Some((segment_id, length));
+        // Write the chunk
+        let segment = self.current_segment.as_mut().unwrap();
         let start = segment.1.write_chunk(chunk)?;
-        let descriptor = SegmentDescriptor {
-            segment_id: segment.0,
-            start,
-        };
+        self.uncommitted = None;
+        let descriptor = SegmentDescriptor { segment_id, start };
         // If we have exceeded the max size, close out the current segment
         if segment.1.size() >= self.size_limit {
-            self.current_segment.as_mut().map(|x| x.1.flush());
+            segment.1.flush()?;
             self.current_segment = None
         }
         Ok(descriptor)
     }
 
+    /// Opens the segments containing the given locations into the read cache, in order of first
+    /// appearance, stopping once the cache is full
+    pub fn prefetch(&mut self, locations: &[SegmentDescriptor]) -> Result<()> {
+        let capacity = self.ro_segment_cache.cap();
+        for segment_id in segments_to_prefetch(locations, capacity) {
+            self.open_segment_read(segment_id)?;
+        }
+        Ok(())
+    }
+
     pub fn flush(&mut self) -> Result<()> {
         if let Some(segment) = self.current_segment.as_mut() {
             segment.1.flush()
@@ -1,4 +1,7 @@
-use ssh2::{Error, File, OpenFlags, OpenType, Sftp};
+use crate::repository::backend::common::files::TEMP_FILE_PREFIX;
+
+use ssh2::{Error, File, FileStat, OpenFlags, OpenType, Sftp};
+use uuid::Uuid;
 
 use std::io::{Read, Seek, Write};
 use std::ops::{Deref, DerefMut, Drop};
@@ -56,6 +59,97 @@ impl LockedFile {
             sftp,
         }))
     }
+
+    /// Reopens this file on a new SFTP session, such as after a reconnect
+    ///
+    /// The lock file outlives the session that created it, so it is kept, and only created again
+    /// if it has gone missing in the meantime. The position in the file is not preserved.
+    pub fn reopen(&mut self, sftp: Rc<Sftp>) -> Result<()> {
+        if sftp.stat(&self.lock_file_path).is_err() {
+            sftp.open_mode(
+                &self.lock_file_path,
+                OpenFlags::READ | OpenFlags::WRITE | OpenFlags::CREATE | OpenFlags::EXCLUSIVE,
+                0o644,
+                OpenType::File,
+            )?;
+        }
+        self.file = sftp.open_mode(
+            &self.path,
+            OpenFlags::READ | OpenFlags::WRITE,
+            0o644,
+            OpenType::File,
+        )?;
+        self.sftp = sftp;
+        Ok(())
+    }
+
+    /// Truncates this file to the given length
+    ///
+    /// Used to discard the partial tail left behind by a write that failed part way through.
+    pub fn truncate(&mut self, length: u64) -> Result<()> {
+        self.file.setstat(FileStat {
+            size: Some(length),
+            uid: None,
+            gid: None,
+            perm: None,
+            atime: None,
+            mtime: None,
+        })
+    }
+
+    /// Replaces the contents of this file, by writing them to a temporary file in the same
+    /// directory, and then renaming it over this one
+    ///
+    /// The file is never truncated in place, so an interrupted write leaves the previous contents
+    /// intact. Many SFTP servers refuse to rename over an existing file, in which case the
+    /// existing file is first moved aside, and only removed once the new one is in place. The
+    /// lock is held throughout, and this handle is reopened on the new file.
+    pub fn replace_contents(&mut self, contents: &[u8]) -> std::io::Result<()> {
+        let directory = self
+            .path
+            .parent()
+            .map(Path::to_path_buf)
+            .unwrap_or_default();
+        let temp_path = directory.join(format!(
+            "{}{}",
+            TEMP_FILE_PREFIX,
+            Uuid::new_v4().to_simple()
+        ));
+        let mut temp = self.sftp.create(&temp_path)?;
+        let written = temp
+            .write_all(contents)
+            .and_then(|_| temp.fsync().map_err(std::io::Error::from));
+        drop(temp);
+        if let Err(e) = written {
+            let _ = self.sftp.unlink(&temp_path);
+            return Err(e);
+        }
+        if self.sftp.rename(&temp_path, &self.path, None).is_err() {
+            let old_path = directory.join(format!(
+                "{}{}",
+                TEMP_FILE_PREFIX,
+                Uuid::new_v4().to_simple()
+            ));
+            if let Err(e) = self.sftp.rename(&self.path, &old_path, None) {
+                let _ = self.sftp.unlink(&temp_path);
+                return Err(e.into());
+            }
+            if let Err(e) = self.sftp.rename(&temp_path, &self.path, None) {
+                // Put the previous contents back
+                let _ = self.sftp.rename(&old_path, &self.path, None);
+                let _ = self.sftp.unlink(&temp_path);
+                return Err(e.into());
+            }
+            self.sftp.unlink(&old_path)?;
+        }
+        self.file = self.sftp.open_mode(
+            &self.path,
+            OpenFlags::READ | OpenFlags::WRITE,
+            0o644,
+            OpenType::File,
+        )?;
+        Ok(())
+    }
 }
 
 impl Drop for LockedFile {
@@ -142,6 +236,7 @@ mod tests {
             port: Some(port),
             password: Some(password),
             path,
+            retry: RetryPolicy::default(),
         }
     }
 
@@ -0,0 +1,474 @@
+//! Provides access to a `MultiFile` style repository stored on a WebDAV server, such as a Nextcloud
+//! or ownCloud instance.
+//!
+//! WebDAV does not support appending to, or seeking within, remote resources, so each resource
+//! this backend works with is buffered in memory, and uploaded in full when it is closed. For this
+//! reason, this backend uses much smaller segments than the local `MultiFile` backend does.
+use super::{BackendError, Result, SegmentDescriptor};
+use crate::repository::backend::common::sync_backend::{BackendHandle, SyncBackend, SyncIndex};
+use crate::repository::{Chunk, ChunkID, ChunkSettings, EncryptedKey, Key};
+
+use reqwest::header::{HeaderMap, HeaderValue, AUTHORIZATION, IF_NONE_MATCH};
+use reqwest::{Method, StatusCode};
+use reqwest_dav::list_cmd::ListEntity;
+use reqwest_dav::{Auth, Client, ClientBuilder, Depth};
+use serde_cbor as cbor;
+use tokio::runtime::Runtime;
+
+use std::fmt::Debug;
+use std::rc::Rc;
+
+pub mod index;
+pub mod manifest;
+pub mod segment;
+pub mod util;
+
+use self::index::WebDavIndex;
+use self::manifest::WebDavManifest;
+use self::segment::WebDavSegmentHandler;
+use self::util::DavFile;
+
+// Allow our result type to accept the WebDAV client errors easily
+// Maps to `BackendError::ConnectionError(error.to_string())`
+impl From<reqwest_dav::Error> for BackendError {
+    fn from(error: reqwest_dav::Error) -> Self {
+        BackendError::ConnectionError(format!("WebDAV Error: {:?}", error))
+    }
+}
+
+/// Credentials used to authenticate with a WebDAV server
+#[derive(Clone, Debug)]
+pub enum WebDavAuth {
+    /// Do not send any credentials
+    Anonymous,
+    /// HTTP basic authentication
+    Basic { username: String, password: String },
+    /// An `Authorization: Bearer` token
+    Bearer(String),
+}
+
+/// Settings used for connecting to a WebDAV server.
+#[derive(Clone, Debug)]
+pub struct WebDavSettings {
+    /// Base URL of the WebDAV share, e.g. `https://cloud.example.com/remote.php/dav/files/user/`
+    pub url: String,
+    /// Credentials to connect with
+    pub auth: WebDavAuth,
+    /// Path of the repository, relative to the base URL
+    pub path: String,
+}
+
+/// A connection to a WebDAV server
+///
+/// All requests are made relative to the repository path, and are driven to completion on a
+/// private single threaded runtime, as the `SyncBackend` traits are blocking.
+#[derive(Clone)]
+pub struct WebDavConnection {
+    settings: WebDavSettings,
+    client: Client,
+    runtime: Rc<Runtime>,
+}
+
+impl WebDavConnection {
+    /// Builds a client for the server described by the given settings
+    ///
+    /// This does not make any requests, so it will not fail if the server is unreachable.
+    pub fn connect(settings: WebDavSettings) -> Result<Self> {
+        let runtime = tokio::runtime::Builder::new_current_thread()
+            .enable_all()
+            .build()?;
+        let builder = ClientBuilder::new().set_host(settings.url.clone());
+        let builder = match &settings.auth {
+            WebDavAuth::Anonymous => builder.set_auth(Auth::Anonymous),
+            WebDavAuth::Basic { username, password } => {
+                builder.set_auth(Auth::Basic(username.clone(), password.clone()))
+            }
+            WebDavAuth::Bearer(token) => {
+                let value = HeaderValue::from_str(&format!("Bearer {}", token)).map_err(|_| {
+                    BackendError::ConnectionError(
+                        "WebDAV bearer token contained invalid characters".to_string(),
+                    )
+                })?;
+                let mut headers = HeaderMap::new();
+                headers.insert(AUTHORIZATION, value);
+                let agent = reqwest::Client::builder()
+                    .default_headers(headers)
+                    .build()?;
+                builder.set_auth(Auth::Anonymous).set_agent(agent)
+            }
+        };
+        let client = builder.build()?;
+
+        Ok(WebDavConnection {
+            settings,
+            client,
+            runtime: Rc::new(runtime),
+        })
+    }
+
+    /// Provides a reference to the settings of this connection
+    pub fn settings(&self) -> &WebDavSettings {
+        &self.settings
+    }
+
+    /// Converts a path relative to the repository into one relative to the base URL
+    fn resolve(&self, path: &str) -> String {
+        let base = self.settings.path.trim_matches('/');
+        let path = path.trim_matches('/');
+        if base.is_empty() {
+            path.to_string()
+        } else if path.is_empty() {
+            base.to_string()
+        } else {
+            format!("{}/{}", base, path)
+        }
+    }
+
+    /// Returns true if a resource or collection exists at the given path
+    pub fn exists(&self, path: &str) -> bool {
+        let path = self.resolve(path);
+        self.runtime
+            .block_on(self.client.list(&path, Depth::Number(0)))
+            .is_ok()
+    }
+
+    /// Downloads the resource at the given path
+    pub fn get(&self, path: &str) -> Result<Vec<u8>> {
+        let full_path = self.resolve(path);
+        self.runtime.block_on(async {
+            let response = self.client.get(&full_path).await?;
+            if !response.status().is_success() {
+                return Err(BackendError::ConnectionError(format!(
+                    "Failed to download {}, server responded with {}",
+                    full_path,
+                    response.status()
+                )));
+            }
+            Ok(response.bytes().await?.to_vec())
+        })
+    }
+
+    /// Requests the properties of the repository collection itself
+    ///
+    /// Unlike `exists`, this reports the reason the request failed.
+    pub fn ping(&self) -> Result<()> {
+        let path = self.resolve("");
+        self.runtime
+            .block_on(self.client.list(&path, Depth::Number(0)))?;
+        Ok(())
+    }
+
+    /// Uploads the given bytes to the given path, replacing any existing resource
+    pub fn put(&self, path: &str, data: Vec<u8>) -> Result<()> {
+        let path = self.resolve(path);
+        self.runtime.block_on(self.client.put(&path, data))?;
+        Ok(())
+    }
+
+    /// Uploads the given bytes to the given path, only if no resource exists there yet
+    ///
+    /// The check is made by the server, with `If-None-Match: *`, so it is atomic. Returns
+    /// `Ok(false)` if the resource already existed.
+    pub fn create_new(&self, path: &str, data: Vec<u8>) -> Result<bool> {
+        let full_path = self.resolve(path);
+        self.runtime.block_on(async {
+            let response = self
+                .client
+                .start_request(Method::PUT, &full_path)
+                .await?
+                .header(IF_NONE_MATCH, "*")
+                .body(data)
+                .send()
+                .await?;
+            let status = response.status();
+            if status == StatusCode::PRECONDITION_FAILED {
+                Ok(false)
+            } else if status.is_success() {
+                Ok(true)
+            } else {
+                Err(BackendError::ConnectionError(format!(
+                    "Failed to create {}, server responded with {}",
+                    full_path, status
+                )))
+            }
+        })
+    }
+
+    /// Deletes the resource at the given path
+    pub fn delete(&self, path: &str) -> Result<()> {
+        let path = self.resolve(path);
+        self.runtime.block_on(self.client.delete(&path))?;
+        Ok(())
+    }
+
+    /// Creates the collection at the given path, and any missing parents, if it does not exist
+    pub fn mkcol(&self, path: &str) -> Result<()> {
+        let full_path = self.resolve(path);
+        let mut current = String::new();
+        for part in full_path.split('/').filter(|x| !x.is_empty()) {
+            if !current.is_empty() {
+                current.push('/');
+            }
+            current.push_str(part);
+            let exists = self
+                .runtime
+                .block_on(self.client.list(&current, Depth::Number(0)))
+                .is_ok();
+            if !exists {
+                self.runtime.block_on(self.client.mkcol(&current))?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Lists the immediate members of the collection at the given path
+    ///
+    /// Returns the name of each member, and whether or not it is a collection.
+    pub fn list(&self, path: &str) -> Result<Vec<(String, bool)>> {
+        let full_path = self.resolve(path);
+        let entities = self
+            .runtime
+            .block_on(self.client.list(&full_path, Depth::Number(1)))?;
+        let own_name = full_path.rsplit('/').next().unwrap_or("").to_string();
+        Ok(entities
+            .into_iter()
+            .filter_map(|entity| {
+                let (href, is_collection) = match entity {
+                    ListEntity::File(file) => (file.href, false),
+                    ListEntity::Folder(folder) => (folder.href, true),
+                };
+                let name = href.trim_end_matches('/').rsplit('/').next()?.to_string();
+                // The server includes the listed collection itself in the results
+                if is_collection && name == own_name {
+                    None
+                } else {
+                    Some((name, is_collection))
+                }
+            })
+            .collect())
+    }
+}
+
+impl Debug for WebDavConnection {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("WebDavConnection")
+            .field("url", &self.settings.url)
+            .field("path", &self.settings.path)
+            .finish()
+    }
+}
+
+#[derive(Debug)]
+pub struct WebDav {
+    manifest: WebDavManifest,
+    index: WebDavIndex,
+    segment_handler: WebDavSegmentHandler,
+    connection: WebDavConnection,
+}
+
+impl WebDav {
+    pub fn connect_raw(
+        settings: WebDavSettings,
+        key: &Key,
+        chunk_settings: Option<ChunkSettings>,
+    ) -> Result<Self> {
+        let connection = WebDavConnection::connect(settings)?;
+        connection.mkcol("")?;
+        let mut manifest = WebDavManifest::connect(connection.clone(), key, chunk_settings)?;
+        let index = WebDavIndex::connect(connection.clone())?;
+        let chunk_settings = manifest.chunk_settings();
+        // Segments are held in memory until they are closed, so keep them small
+        let size_limit = 64_000_000;
+        let segments_per_directory = 100;
+        let segment_handler = WebDavSegmentHandler::connect(
+            connection.clone(),
+            size_limit,
+            segments_per_directory,
+            chunk_settings,
+            key.clone(),
+        )?;
+
+        Ok(WebDav {
+            manifest,
+            index,
+            segment_handler,
+            connection,
+        })
+    }
+
+    pub fn connect(
+        settings: WebDavSettings,
+        key: Key,
+        chunk_settings: Option<ChunkSettings>,
+        queue_depth: usize,
+    ) -> Result<BackendHandle<WebDav>> {
+        use crossbeam_channel::bounded;
+        let (s, r) = bounded(1);
+        let handle = BackendHandle::new(queue_depth, move || {
+            let result = Self::connect_raw(settings, &key, chunk_settings);
+            match result {
+                Ok(backend) => {
+                    s.send(None).unwrap();
+                    backend
+                }
+                Err(e) => {
+                    s.send(Some(e)).unwrap();
+                    panic!("Opening a WebDAV Backend Handle Failed")
+                }
+            }
+        });
+        let error = r
+            .recv()
+            .expect("Backend Handle thread died before it could send us its result");
+
+        if let Some(error) = error {
+            Err(error)
+        } else {
+            Ok(handle)
+        }
+    }
+
+    pub fn read_key(settings: WebDavSettings) -> Result<EncryptedKey> {
+        let connection = WebDavConnection::connect(settings)?;
+        let bytes = connection.get("key").map_err(|e| {
+            BackendError::ConnectionError(format!("Failed to read key file. Error was: {}", e))
+        })?;
+        Ok(cbor::de::from_slice(&bytes[..])?)
+    }
+}
+
+impl SyncBackend for WebDav {
+    type SyncManifest = WebDavManifest;
+    type SyncIndex = WebDavIndex;
+    fn get_index(&mut self) -> &mut Self::SyncIndex {
+        &mut self.index
+    }
+    fn get_manifest(&mut self) -> &mut Self::SyncManifest {
+        &mut self.manifest
+    }
+    fn write_key(&mut self, key: EncryptedKey) -> Result<()> {
+        let mut file = DavFile::open_locked(self.connection.clone(), "key")?
+            .ok_or(BackendError::FileLockError)?;
+        file.truncate();
+        cbor::ser::to_writer(&mut file, &key)?;
+        file.sync()
+    }
+    fn read_key(&mut self) -> Result<EncryptedKey> {
+        let bytes = self.connection.get("key")?;
+        Ok(cbor::de::from_slice(&bytes[..])?)
+    }
+    fn read_chunk(&mut self, location: SegmentDescriptor) -> Result<Chunk> {
+        self.segment_handler.read_chunk(location)
+    }
+    fn write_chunk(&mut self, chunk: Chunk) -> Result<SegmentDescriptor> {
+        self.segment_handler.write_chunk(chunk)
+    }
+    fn prefetch(&mut self, locations: &[SegmentDescriptor]) -> Result<()> {
+        self.segment_handler.prefetch(locations)
+    }
+    fn remove_chunk(&mut self, id: ChunkID) -> Result<()> {
+        self.index.remove_chunk(id)
+    }
+    /// Uploads the current segment, without closing it out, and commits the index
+    fn sync(&mut self) -> Result<()> {
+        self.segment_handler.sync()?;
+        self.index.commit_index()
+    }
+    fn ping(&mut self) -> Result<()> {
+        self.connection.ping()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::repository::{Compression, HMAC};
+    use crate::repository::{Encryption, Key};
+    use std::env;
+
+    pub(super) fn get_settings(path: String) -> WebDavSettings {
+        let url = env::var_os("ASURAN_WEBDAV_URL")
+            .map(|x| x.into_string().unwrap())
+            .expect("Server must be set");
+        let username = env::var_os("ASURAN_WEBDAV_USER")
+            .map(|x| x.into_string().unwrap())
+            .unwrap_or("asuran".to_string());
+        let password = env::var_os("ASURAN_WEBDAV_PASS")
+            .map(|x| x.into_string().unwrap())
+            .unwrap_or("asuran".to_string());
+
+        WebDavSettings {
+            url,
+            auth: WebDavAuth::Basic { username, password },
+            path,
+        }
+    }
+
+    #[test]
+    fn webdav_handle_connect() {
+        let settings = get_settings("asuran/handle_connect".to_string());
+        let handle = WebDav::connect(
+            settings,
+            Key::random(32),
+            Some(ChunkSettings::lightweight()),
+            2,
+        );
+        assert!(handle.is_ok())
+    }
+
+    fn get_backend(path: impl AsRef<str>, key: &Key) -> WebDav {
+        let path = path.as_ref().to_string();
+        WebDav::connect_raw(get_settings(path), key, Some(ChunkSettings::lightweight()))
+            .expect("Unable to connect to backend")
+    }
+
+    #[test]
+    fn key_read_write() {
+        let key = Key::random(32);
+        let enc_key = EncryptedKey::encrypt_defaults(
+            &key,
+            Encryption::new_aes256ctr(),
+            "ASecurePassword".as_bytes(),
+        );
+
+        let mut backend = get_backend("asuran/key_read_write", &key);
+        backend
+            .write_key(enc_key.clone())
+            .expect("Unable to write key");
+
+        drop(backend);
+        let mut backend = get_backend("asuran/key_read_write", &key);
+
+        let result = backend.read_key().expect("Unable to read key");
+        let dec_result = result.decrypt("ASecurePassword".as_bytes()).unwrap();
+        assert!(key == dec_result);
+
+        let settings = backend.connection.settings().clone();
+        let result = WebDav::read_key(settings).expect("Unable to read key");
+        let dec_result = result.decrypt("ASecurePassword".as_bytes()).unwrap();
+        assert!(key == dec_result);
+    }
+
+    #[test]
+    fn chunk_read_write() {
+        let key = Key::random(32);
+        let chunk = Chunk::pack(
+            vec![1_u8; 1024],
+            Compression::NoCompression,
+            Encryption::NoEncryption,
+            HMAC::Blake3,
+            &key,
+        );
+
+        let mut backend = get_backend("asuran/chunk_read_write", &key);
+        let desc = backend
+            .write_chunk(chunk.clone())
+            .expect("Unable to write chunk");
+
+        drop(backend);
+        let mut backend = get_backend("asuran/chunk_read_write", &key);
+        let ret_chunk = backend.read_chunk(desc).expect("Unable to read chunk");
+
+        assert!(chunk == ret_chunk);
+    }
+}
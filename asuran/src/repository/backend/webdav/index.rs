@@ -0,0 +1,193 @@
+use super::util::DavFile;
+use super::WebDavConnection;
+use crate::repository::backend::common::sync_backend::SyncIndex;
+use crate::repository::backend::common::IndexTransaction;
+use crate::repository::backend::{Result, SegmentDescriptor};
+use crate::repository::ChunkID;
+
+use serde_cbor as cbor;
+
+use std::collections::HashMap;
+use std::collections::HashSet;
+use std::io::{Seek, SeekFrom};
+
+#[derive(Debug)]
+pub struct WebDavIndex {
+    state: HashMap<ChunkID, SegmentDescriptor>,
+    file: DavFile,
+    changes: Vec<IndexTransaction>,
+}
+
+impl WebDavIndex {
+    pub fn connect(connection: WebDavConnection) -> Result<Self> {
+        // Create the index collection if it does not exist
+        connection.mkcol("index")?;
+        // Create the state map
+        let mut state: HashMap<ChunkID, SegmentDescriptor> = HashMap::new();
+
+        // Get the list of files and sort them by id
+        let mut items = connection
+            .list("index")?
+            .into_iter()
+            // Make sure its a file
+            .filter(|(_name, is_collection)| !is_collection)
+            // Make sure the name is a number, and map to (number, path)
+            .filter_map(|(name, _)| {
+                name.parse::<u64>()
+                    .ok()
+                    .map(|x| (x, format!("index/{}", name)))
+            })
+            .collect::<Vec<_>>();
+        // Sort the list of files by id
+        items.sort_by(|a, b| a.0.cmp(&b.0));
+
+        // Iterate through each file, adding all the transactions to our state hashmap
+        for (_, path) in &items {
+            let bytes = connection.get(path)?;
+            // Keep deserializing transactions until we encounter an error
+            let de = cbor::Deserializer::from_slice(&bytes[..]);
+            let mut de = de.into_iter::<IndexTransaction>();
+            while let Some(tx) = de.next().and_then(std::result::Result::ok) {
+                if tx.removed {
+                    state.remove(&tx.chunk_id);
+                } else {
+                    state.insert(tx.chunk_id, tx.descriptor);
+                }
+            }
+        }
+
+        // Check to see if there are any unlocked files, and if so, use the first
+        for (_, path) in &items {
+            if let Some(file) = DavFile::open_locked(connection.clone(), path)? {
+                return Ok(WebDavIndex {
+                    state,
+                    file,
+                    changes: Vec::new(),
+                });
+            }
+        }
+
+        // If we have gotten here, there are no unlocked files, creating one
+        let id = if items.is_empty() {
+            0
+        } else {
+            items[items.len() - 1].0 + 1
+        };
+
+        let path = format!("index/{}", id);
+        let file = DavFile::open_locked(connection, &path)?
+            .expect("Somehow, we aren't able to lock our newly created index file");
+
+        Ok(WebDavIndex {
+            state,
+            file,
+            changes: Vec::new(),
+        })
+    }
+
+    /// Removes a chunk from the index, recording the removal in the index log
+    ///
+    /// The space used by the chunk is not reclaimed. Removing a chunk that is not in the index
+    /// does nothing.
+    pub fn remove_chunk(&mut self, id: ChunkID) -> Result<()> {
+        if let Some(descriptor) = self.state.remove(&id) {
+            self.changes.push(IndexTransaction {
+                chunk_id: id,
+                descriptor,
+                removed: true,
+            });
+        }
+        Ok(())
+    }
+}
+
+impl SyncIndex for WebDavIndex {
+    fn lookup_chunk(&mut self, id: ChunkID) -> Option<SegmentDescriptor> {
+        self.state.get(&id).copied()
+    }
+    #[allow(clippy::map_entry)]
+    fn set_chunk(&mut self, id: ChunkID, location: SegmentDescriptor) -> Result<()> {
+        if !self.state.contains_key(&id) {
+            self.state.insert(id, location);
+            let transaction = IndexTransaction {
+                chunk_id: id,
+                descriptor: location,
+                removed: false,
+            };
+            self.changes.push(transaction);
+        }
+        Ok(())
+    }
+    /// Returns the ids of every chunk in the index
+    ///
+    /// The in-memory state already reflects uncommitted additions and removals, so chunks removed
+    /// since the last commit are not included.
+    fn known_chunks(&mut self) -> HashSet<ChunkID> {
+        self.state.keys().copied().collect()
+    }
+    fn commit_index(&mut self) -> Result<()> {
+        self.file.seek(SeekFrom::End(0))?;
+        for tx in self.changes.drain(0..self.changes.len()) {
+            cbor::ser::to_writer(&mut self.file, &tx)?;
+        }
+        self.file.sync()
+    }
+    fn chunk_count(&mut self) -> usize {
+        self.state.len()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::repository::backend::webdav::tests::get_settings;
+
+    fn get_index(path: impl AsRef<str>) -> WebDavIndex {
+        let path = path.as_ref().to_string();
+        let connection =
+            WebDavConnection::connect(get_settings(path)).expect("Unable to build connection");
+        connection.mkcol("").expect("Unable to create repository");
+        WebDavIndex::connect(connection).expect("Unable to connect to index")
+    }
+
+    #[test]
+    fn set_lookup_chunk() {
+        let mut index = get_index("asuran/index_set_lookup_chunk");
+        let chunk_id = ChunkID::random_id();
+        let descriptor = SegmentDescriptor {
+            segment_id: 42,
+            start: 43,
+        };
+        index
+            .set_chunk(chunk_id, descriptor)
+            .expect("Unable to write chunk location");
+        index.commit_index().expect("Unable to commit index");
+        drop(index);
+        let mut index = get_index("asuran/index_set_lookup_chunk");
+        let result = index
+            .lookup_chunk(chunk_id)
+            .expect("Unable to read chunk location");
+
+        assert!(descriptor == result);
+    }
+
+    #[test]
+    fn known_chunks() {
+        let mut index = get_index("asuran/index_known_chunks");
+        let descriptor = SegmentDescriptor {
+            segment_id: 42,
+            start: 43,
+        };
+        let chunks: HashSet<ChunkID> = (0..10).map(|_| ChunkID::random_id()).collect();
+        for chunk in &chunks {
+            index
+                .set_chunk(*chunk, descriptor)
+                .expect("Unable to set chunk");
+        }
+        index.commit_index().expect("unable to commit index");
+        drop(index);
+        let mut index = get_index("asuran/index_known_chunks");
+        let new_chunks = index.known_chunks();
+        assert!(new_chunks == chunks);
+    }
+}
@@ -0,0 +1,291 @@
+use super::util::DavFile;
+use super::WebDavConnection;
+use crate::repository::audit::AuditHead;
+use crate::repository::backend::common::sync_backend::SyncManifest;
+use crate::repository::backend::common::{
+    archive_transactions, dangling_parents, find_heads, latest_audit_head, verify_transactions,
+    DanglingParent, ManifestID, ManifestTransaction,
+};
+use crate::repository::backend::BackendError;
+use crate::repository::{ChunkSettings, Key};
+use crate::{manifest::StoredArchive, repository::backend::Result};
+
+use chrono::prelude::*;
+use serde_cbor as cbor;
+
+use std::collections::{HashMap, HashSet};
+use std::io::{Seek, SeekFrom};
+
+#[derive(Debug)]
+pub struct WebDavManifest {
+    connection: WebDavConnection,
+    known_entries: HashMap<ManifestID, ManifestTransaction>,
+    verified_memo_pad: HashSet<ManifestID>,
+    heads: Vec<ManifestID>,
+    file: DavFile,
+    key: Key,
+    chunk_settings: ChunkSettings,
+}
+
+impl WebDavManifest {
+    /// Will attempt to open or create a manifest in the repository the given connection points to
+    pub fn connect(
+        connection: WebDavConnection,
+        key: &Key,
+        chunk_settings: Option<ChunkSettings>,
+    ) -> Result<Self> {
+        // Create the manifest collection if it does not exist
+        connection.mkcol("manifest")?;
+
+        // Get the list of manifest files and sort them by ID
+        let mut items = connection
+            .list("manifest")?
+            .into_iter()
+            // Make sure its a file
+            .filter(|(_name, is_collection)| !is_collection)
+            // Make sure the name is a number, and map to (number, path)
+            .filter_map(|(name, _)| {
+                name.parse::<u64>()
+                    .ok()
+                    .map(|x| (x, format!("manifest/{}", name)))
+            })
+            .collect::<Vec<_>>();
+        // Sort the list of files by id
+        items.sort_by(|a, b| a.0.cmp(&b.0));
+
+        // Collect all known transactions
+        let mut known_entries = HashMap::new();
+        for (_, path) in &items {
+            let bytes = connection.get(path)?;
+            // Keep deserializing transactions until we hit an error
+            let de = cbor::Deserializer::from_slice(&bytes[..]);
+            let mut de = de.into_iter::<ManifestTransaction>();
+            while let Some(tx) = de.next().and_then(std::result::Result::ok) {
+                known_entries.insert(tx.tag(), tx);
+            }
+        }
+
+        let mut file = None;
+        // Attempt to find an unlocked file
+        for (_, path) in &items {
+            if let Some(f) = DavFile::open_locked(connection.clone(), path)? {
+                file = Some(f);
+                break;
+            }
+        }
+
+        // If we were unable to find an unlocked file, go ahead and make one
+        let file = if let Some(file) = file {
+            file
+        } else {
+            let id = if items.is_empty() {
+                0
+            } else {
+                items[items.len() - 1].0 + 1
+            };
+            DavFile::open_locked(connection.clone(), &format!("manifest/{}", id))?
+                .expect("Somehow, our newly created lock file is already locked")
+        };
+
+        let chunk_settings = if let Some(chunk_settings) = chunk_settings {
+            Self::store_chunk_settings(&connection, chunk_settings)?;
+            chunk_settings
+        } else {
+            let bytes = connection.get("manifest/chunk.settings")?;
+            cbor::de::from_slice(&bytes[..])?
+        };
+
+        // Construct the manifest
+        let mut manifest = WebDavManifest {
+            connection,
+            known_entries,
+            verified_memo_pad: HashSet::new(),
+            heads: Vec::new(),
+            file,
+            key: key.clone(),
+            chunk_settings,
+        };
+        // Build the list of heads
+        manifest.build_heads();
+        // Verify each head
+        for head in manifest.heads.clone() {
+            if !manifest.verify_tx(head) {
+                return Err(BackendError::ManifestError(format!(
+                    "Manifest Transaction failed verification! {:?}",
+                    manifest.known_entries.get(&head).ok_or_else(|| BackendError::Unknown("Failed to get the head of the known entries list while reporting an error".to_string()))?
+                )));
+            }
+        }
+
+        Ok(manifest)
+    }
+
+    /// Overwrites the chunk settings stored in the repository
+    fn store_chunk_settings(
+        connection: &WebDavConnection,
+        chunk_settings: ChunkSettings,
+    ) -> Result<()> {
+        let mut sfile = DavFile::open_locked(connection.clone(), "manifest/chunk.settings")?
+            .ok_or_else(|| {
+                BackendError::ManifestError("Unable to lock chunk.settings".to_string())
+            })?;
+        sfile.truncate();
+        cbor::ser::to_writer(&mut sfile, &chunk_settings)?;
+        sfile.sync()
+    }
+
+    /// Gets the heads from a list of transactions
+    ///
+    /// Transactions referencing a previous head that is missing are treated as roots, see
+    /// `find_heads`
+    fn build_heads(&mut self) {
+        self.heads = find_heads(&self.known_entries);
+    }
+
+    /// Verifies a transaction and all of its parents, see `verify_transactions`
+    fn verify_tx(&mut self, id: ManifestID) -> bool {
+        verify_transactions(
+            &self.known_entries,
+            id,
+            &self.key,
+            &mut self.verified_memo_pad,
+        )
+    }
+
+    /// Appends a transaction to the manifest file and uploads it, then makes it the only head
+    fn append(&mut self, tx: ManifestTransaction) -> Result<()> {
+        let file = &mut self.file;
+        file.seek(SeekFrom::End(0))?;
+        cbor::ser::to_writer(&mut *file, &tx)?;
+        file.sync()?;
+        let id = tx.tag();
+        self.known_entries.insert(id, tx);
+        self.heads = vec![id];
+        Ok(())
+    }
+}
+
+impl SyncManifest for WebDavManifest {
+    type Iterator = std::vec::IntoIter<StoredArchive>;
+    fn last_modification(&mut self) -> Result<chrono::DateTime<chrono::FixedOffset>> {
+        if self.heads.is_empty() {
+            Ok(Local::now().with_timezone(Local::now().offset()))
+        } else {
+            let first_head = self
+                .known_entries
+                .get(&self.heads[0])
+                .expect("Item in heads was not in known entries");
+            let mut max = first_head.modified();
+            for id in &self.heads {
+                let tx = self.known_entries.get(id).ok_or_else(|| {
+                    BackendError::ManifestError("Unable to load timestamp".to_string())
+                })?;
+                if tx.modified() > max {
+                    max = tx.modified()
+                }
+            }
+            Ok(max)
+        }
+    }
+    fn chunk_settings(&mut self) -> ChunkSettings {
+        self.chunk_settings
+    }
+    fn archive_iterator(&mut self) -> Self::Iterator {
+        archive_transactions(self.known_entries.values())
+            .into_iter()
+            .map(StoredArchive::from)
+            .collect::<Vec<_>>()
+            .into_iter()
+    }
+    fn write_chunk_settings(&mut self, chunk_settings: ChunkSettings) -> Result<()> {
+        Self::store_chunk_settings(&self.connection, chunk_settings)?;
+        self.chunk_settings = chunk_settings;
+        Ok(())
+    }
+    fn write_archive(&mut self, archive: StoredArchive) -> Result<()> {
+        // Create the transaction
+        let tx = ManifestTransaction::new(
+            &self.heads,
+            archive.id(),
+            archive.timestamp(),
+            self.chunk_settings.hmac,
+            &self.key,
+        );
+        self.append(tx)
+    }
+    fn touch(&mut self) -> Result<()> {
+        // Touch doesn't actually do anything with this implementation
+        Ok(())
+    }
+    fn delete_archive(&mut self, archive: StoredArchive) -> Result<()> {
+        let tx = ManifestTransaction::new_deletion(
+            &self.heads,
+            archive.id(),
+            archive.timestamp(),
+            self.chunk_settings.hmac,
+            &self.key,
+        );
+        self.append(tx)
+    }
+    fn write_audit_head(&mut self, head: AuditHead) -> Result<()> {
+        let tx = ManifestTransaction::new_audit_head(
+            &self.heads,
+            head,
+            Local::now().with_timezone(Local::now().offset()),
+            self.chunk_settings.hmac,
+            &self.key,
+        );
+        self.append(tx)
+    }
+    fn audit_head(&mut self) -> Result<Option<AuditHead>> {
+        Ok(latest_audit_head(self.known_entries.values()))
+    }
+    fn dangling_parents(&mut self) -> Result<Vec<DanglingParent>> {
+        Ok(dangling_parents(&self.known_entries))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::repository::backend::webdav::tests::get_settings;
+
+    fn get_manifest(
+        path: impl AsRef<str>,
+        key: &Key,
+        chunk_settings: Option<ChunkSettings>,
+    ) -> WebDavManifest {
+        let path = path.as_ref().to_string();
+        let connection =
+            WebDavConnection::connect(get_settings(path)).expect("Unable to build connection");
+        connection.mkcol("").expect("Unable to create repository");
+        WebDavManifest::connect(connection, key, chunk_settings)
+            .expect("Unable to connect to manifest")
+    }
+
+    #[test]
+    fn archives() {
+        let key = Key::random(32);
+        let dummy_archives: HashSet<StoredArchive> =
+            (0..10).map(|_| StoredArchive::dummy_archive()).collect();
+
+        let mut manifest = get_manifest(
+            "asuran/manifest_archives",
+            &key,
+            Some(ChunkSettings::lightweight()),
+        );
+
+        for archive in &dummy_archives {
+            manifest
+                .write_archive(archive.clone())
+                .expect("Unable to write archive");
+        }
+
+        drop(manifest);
+        let mut manifest = get_manifest("asuran/manifest_archives", &key, None);
+
+        let output: HashSet<StoredArchive> = manifest.archive_iterator().collect();
+
+        assert!(dummy_archives == output);
+    }
+}
@@ -0,0 +1,248 @@
+use super::util::DavFile;
+use super::WebDavConnection;
+use crate::repository::backend::common::segment::Segment;
+use crate::repository::backend::common::segments_to_prefetch;
+use crate::repository::backend::{BackendError, Result, SegmentDescriptor};
+use crate::repository::{Chunk, ChunkSettings, Key};
+
+use lru::LruCache;
+use tracing::error;
+
+pub struct SegmentPair(u64, Segment<DavFile>);
+
+pub struct WebDavSegmentHandler {
+    /// The connection this SegmentHandler is using
+    connection: WebDavConnection,
+    /// The Segment we are currently writing too, if it exists
+    current_segment: Option<SegmentPair>,
+    /// The ID of the higest segment we have encountered
+    highest_segment: u64,
+    /// The size limit of each segment in bytes
+    ///
+    /// This is currently a soft limit, segments are closed after the write in which they go over
+    size_limit: u64,
+    /// An LRU cache of recently used segments, opened in RO mode
+    ro_segment_cache: LruCache<u64, SegmentPair>,
+    /// The number of segments per directory
+    segments_per_directory: u64,
+    /// The chunk settings used for encrypting headers
+    chunk_settings: ChunkSettings,
+    /// The key used for encrypting/decrypting headers
+    key: Key,
+}
+
+impl WebDavSegmentHandler {
+    pub fn connect(
+        connection: WebDavConnection,
+        size_limit: u64,
+        segments_per_directory: u64,
+        chunk_settings: ChunkSettings,
+        key: Key,
+    ) -> Result<WebDavSegmentHandler> {
+        // Create the data collection if it does not exist
+        connection.mkcol("data")?;
+
+        // Walk the data directory to find the highest numbered segment
+        let mut max_segment: Option<u64> = None;
+        for (folder, is_collection) in connection.list("data")? {
+            if !is_collection || folder.parse::<u64>().is_err() {
+                continue;
+            }
+            let highest = connection
+                .list(&format!("data/{}", folder))?
+                .into_iter()
+                .filter(|(_, is_collection)| !is_collection)
+                .filter_map(|(name, _)| name.parse::<u64>().ok())
+                .max();
+            max_segment = max_segment.max(highest);
+        }
+
+        let mut segment_handler = WebDavSegmentHandler {
+            connection,
+            current_segment: None,
+            highest_segment: max_segment.unwrap_or(0),
+            size_limit,
+            // Segments are held in memory, so keep the cache small
+            ro_segment_cache: LruCache::new(4),
+            segments_per_directory,
+            chunk_settings,
+            key,
+        };
+        // Open the writing segment, to ensure that the data directory is lockable
+        segment_handler.open_segment_write()?;
+        Ok(segment_handler)
+    }
+
+    /// Returns the paths of the data and header resources of a segment
+    fn segment_paths(&self, segment_id: u64) -> (String, String) {
+        let folder_id = segment_id / self.segments_per_directory;
+        (
+            format!("data/{}/{}", folder_id, segment_id),
+            format!("data/{}/{}.header", folder_id, segment_id),
+        )
+    }
+
+    pub fn open_segment_read(&mut self, segment_id: u64) -> Result<&mut SegmentPair> {
+        // If we were writing to the segment, flush it and discard it
+        if let Some(segment) = self.current_segment.as_mut() {
+            if segment.0 == segment_id {
+                segment.1.flush()?;
+                self.current_segment = None;
+            }
+        }
+
+        // Insert the segment into the cache if it doesn't exist
+        if !self.ro_segment_cache.contains(&segment_id) {
+            if !self.segment_exists(segment_id) {
+                return Err(BackendError::SegmentNotFound(segment_id));
+            }
+            let (segment_path, header_path) = self.segment_paths(segment_id);
+            let segment_file = DavFile::open(self.connection.clone(), &segment_path)?;
+            let header_file = DavFile::open(self.connection.clone(), &header_path)?;
+            let segment_pair = SegmentPair(
+                segment_id,
+                Segment::new(
+                    segment_file,
+                    header_file,
+                    self.size_limit,
+                    self.chunk_settings,
+                    self.key.clone(),
+                )?,
+            );
+            self.ro_segment_cache.put(segment_id, segment_pair);
+        }
+
+        let segment_pair = self.ro_segment_cache.get_mut(&segment_id).unwrap();
+        Ok(segment_pair)
+    }
+
+    pub fn segment_exists(&self, segment_id: u64) -> bool {
+        let (segment_path, _) = self.segment_paths(segment_id);
+        self.connection.exists(&segment_path)
+    }
+
+    /// Locks and opens the data and header resources of a segment, returning `None` if either
+    /// is already locked
+    fn open_segment_locked(&self, segment_id: u64) -> Result<Option<SegmentPair>> {
+        let (segment_path, header_path) = self.segment_paths(segment_id);
+        let segment_file = DavFile::open_locked(self.connection.clone(), &segment_path)?;
+        let header_file = DavFile::open_locked(self.connection.clone(), &header_path)?;
+        if let (Some(segment_file), Some(header_file)) = (segment_file, header_file) {
+            Ok(Some(SegmentPair(
+                segment_id,
+                Segment::new(
+                    segment_file,
+                    header_file,
+                    self.size_limit,
+                    self.chunk_settings,
+                    self.key.clone(),
+                )?,
+            )))
+        } else {
+            Ok(None)
+        }
+    }
+
+    pub fn open_segment_write(&mut self) -> Result<&mut SegmentPair> {
+        // Check to see if we already have an open segment
+        if self.current_segment.is_none() {
+            while self.segment_exists(self.highest_segment) {
+                self.highest_segment += 1;
+            }
+
+            // Check to see if higest numbered existing segment is lockable and not oversized
+            if self.highest_segment > 0 {
+                if let Some(mut segment) = self.open_segment_locked(self.highest_segment - 1)? {
+                    if segment.1.size() < self.size_limit {
+                        self.ro_segment_cache.pop(&segment.0);
+                        self.current_segment = Some(segment);
+                        return Ok(self.current_segment.as_mut().unwrap());
+                    }
+                }
+            }
+
+            let segment_id = self.highest_segment;
+            let folder_id = segment_id / self.segments_per_directory;
+            self.connection.mkcol(&format!("data/{}", folder_id))?;
+            let segment = self.open_segment_locked(segment_id)?.ok_or_else(|| {
+                BackendError::SegmentError(format!(
+                    "Unable to lock newly created segment {}. Src File: {} Line: {}",
+                    segment_id,
+                    file!(),
+                    line!()
+                ))
+            })?;
+            self.current_segment = Some(segment);
+        }
+
+        // We can now safely unwrap the current segment, since we have insured its existence
+        Ok(self.current_segment.as_mut().unwrap())
+    }
+
+    pub fn read_chunk(&mut self, location: SegmentDescriptor) -> Result<Chunk> {
+        let segment_id = location.segment_id;
+        let segment = self.open_segment_read(segment_id)?;
+        segment.1.read_chunk(location.start)
+    }
+
+    pub fn write_chunk(&mut self, chunk: Chunk) -> Result<SegmentDescriptor> {
+        // Write the chunk
+        let segment = self.open_segment_write()?;
+        let start = segment.1.write_chunk(chunk)?;
+        let descriptor = SegmentDescriptor {
+            segment_id: segment.0,
+            start,
+        };
+        // If we have exceeded the max size, close out the current segment
+        if segment.1.size() >= self.size_limit {
+            self.flush()?;
+        }
+        Ok(descriptor)
+    }
+
+    /// Opens the segments containing the given locations into the read cache, in order of first
+    /// appearance, stopping once the cache is full
+    pub fn prefetch(&mut self, locations: &[SegmentDescriptor]) -> Result<()> {
+        let capacity = self.ro_segment_cache.cap();
+        for segment_id in segments_to_prefetch(locations, capacity) {
+            self.open_segment_read(segment_id)?;
+        }
+        Ok(())
+    }
+
+    /// Uploads the current segment to the server, leaving it open for further writes
+    pub fn sync(&mut self) -> Result<()> {
+        if let Some(segment) = self.current_segment.as_mut() {
+            segment.1.flush()?;
+        }
+        Ok(())
+    }
+
+    /// Closes out the current segment, uploading it to the server
+    ///
+    /// The segment will be reopened on the next write
+    pub fn flush(&mut self) -> Result<()> {
+        if let Some(mut segment) = self.current_segment.take() {
+            segment.1.flush()?;
+        }
+        Ok(())
+    }
+}
+
+impl std::fmt::Debug for WebDavSegmentHandler {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("WebDavSegmentHandler")
+            .field("connection", &self.connection)
+            .finish()
+    }
+}
+
+impl Drop for WebDavSegmentHandler {
+    fn drop(&mut self) {
+        // Panicking here could abort the process if we are already unwinding, so the best we can
+        // do is report the failure
+        if let Err(e) = self.flush() {
+            error!("Failed to upload the current WebDAV segment: {}", e);
+        }
+    }
+}
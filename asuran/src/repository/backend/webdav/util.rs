@@ -0,0 +1,122 @@
+use super::WebDavConnection;
+use crate::repository::backend::Result;
+
+use tracing::error;
+
+use std::io::{Cursor, Read, Seek, Write};
+use std::ops::Drop;
+
+/// An in-memory view of a remote resource
+///
+/// The contents of the resource are downloaded when it is opened, and uploaded, in full, when it
+/// is synced or dropped. Resources opened with `open_locked` are paired with a remote lock file,
+/// which is deleted upon dropping.
+pub struct DavFile {
+    connection: WebDavConnection,
+    path: String,
+    lock_file_path: Option<String>,
+    buffer: Cursor<Vec<u8>>,
+    dirty: bool,
+}
+
+impl DavFile {
+    /// Opens a resource without locking it, treating a missing resource as empty
+    ///
+    /// Writes to a file opened this way are never uploaded.
+    pub fn open(connection: WebDavConnection, path: &str) -> Result<DavFile> {
+        let data = if connection.exists(path) {
+            connection.get(path)?
+        } else {
+            Vec::new()
+        };
+        Ok(DavFile {
+            connection,
+            path: path.to_string(),
+            lock_file_path: None,
+            buffer: Cursor::new(data),
+            dirty: false,
+        })
+    }
+
+    /// Attempts to lock and open a resource for reading and writing, creating it if it does not
+    /// exist
+    ///
+    /// Returns `Ok(None)` if the resource is already locked.
+    pub fn open_locked(connection: WebDavConnection, path: &str) -> Result<Option<DavFile>> {
+        let lock_file_path = format!("{}.lock", path);
+        // The lock file is created conditionally, so the server decides which of two racing
+        // clients gets the lock
+        if !connection.create_new(&lock_file_path, Vec::new())? {
+            return Ok(None);
+        }
+        let mut file = Self::open(connection, path)?;
+        file.lock_file_path = Some(lock_file_path);
+        Ok(Some(file))
+    }
+
+    /// Discards the contents of the resource
+    pub fn truncate(&mut self) {
+        self.buffer = Cursor::new(Vec::new());
+        self.dirty = true;
+    }
+
+    /// Uploads the contents of the resource, if it is locked and has been modified
+    pub fn sync(&mut self) -> Result<()> {
+        if self.dirty && self.lock_file_path.is_some() {
+            self.connection
+                .put(&self.path, self.buffer.get_ref().clone())?;
+            self.dirty = false;
+        }
+        Ok(())
+    }
+}
+
+impl Drop for DavFile {
+    fn drop(&mut self) {
+        // Panicking here could abort the process if we are already unwinding, so failures are
+        // only reported
+        if let Err(e) = self.sync() {
+            error!("Unable to upload {}, error was: {}", self.path, e);
+        }
+        if let Some(lock_file_path) = self.lock_file_path.as_ref() {
+            if let Err(e) = self.connection.delete(lock_file_path) {
+                error!(
+                    "Unable to delete lock file for {}, error was: {}",
+                    self.path, e
+                );
+            }
+        }
+    }
+}
+
+impl Read for DavFile {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        self.buffer.read(buf)
+    }
+}
+
+impl Write for DavFile {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        self.dirty = true;
+        self.buffer.write(buf)
+    }
+    fn flush(&mut self) -> std::io::Result<()> {
+        self.sync()
+            .map_err(|e| std::io::Error::new(std::io::ErrorKind::Other, e.to_string()))
+    }
+}
+
+impl Seek for DavFile {
+    fn seek(&mut self, pos: std::io::SeekFrom) -> std::io::Result<u64> {
+        self.buffer.seek(pos)
+    }
+}
+
+impl std::fmt::Debug for DavFile {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("DavFile")
+            .field("path", &self.path)
+            .field("lock_file_path", &self.lock_file_path)
+            .finish()
+    }
+}
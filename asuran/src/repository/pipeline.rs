@@ -1,8 +1,7 @@
 use crate::repository::{Chunk, Compression, Encryption, Key, HMAC};
+use crate::runtime;
 
 use futures::channel::oneshot;
-use smol::block_on;
-use std::thread;
 use tracing::instrument;
 
 #[derive(Debug)]
@@ -29,8 +28,8 @@ impl Pipeline {
 
         for _ in 0..task_count {
             let rx = rx.clone();
-            thread::spawn(move || {
-                while let Ok(input) = block_on(rx.recv()) {
+            runtime::spawn_loop(move || async move {
+                while let Ok(input) = rx.recv().await {
                     let (chunk, message): (Vec<u8>, Message) = input;
                     let c = Chunk::pack(
                         chunk,
@@ -0,0 +1,43 @@
+//! Size quotas for repositories on shared or budgeted storage
+//!
+//! The quota, along with the running total of bytes written against it, is stored in the
+//! repository itself, in a chunk with a reserved id, so that it is enforced across sessions and
+//! regardless of the backend in use. See `Repository::set_size_quota`.
+use crate::repository::ChunkID;
+
+use serde::{Deserialize, Serialize};
+
+/// A repository's size quota, and the number of bytes counted against it
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq, Eq)]
+pub struct SizeQuota {
+    /// Maximum number of stored bytes the repository may hold
+    pub limit: u64,
+    /// Number of stored bytes written so far, after compression and encryption
+    pub usage: u64,
+}
+
+impl SizeQuota {
+    /// Returns true if writing an additional `bytes` bytes would exceed the quota
+    pub fn would_exceed(&self, bytes: u64) -> bool {
+        self.usage.saturating_add(bytes) > self.limit
+    }
+}
+
+/// Returns the reserved id of the chunk the quota is stored in
+///
+/// Like the manifest's id, this is not derived from the chunk's contents, and the chunk may be
+/// overwritten.
+pub fn quota_id() -> ChunkID {
+    ChunkID::new(&[0xFF_u8; 32])
+}
+
+/// In memory view of the quota, shared between clones of a `Repository`
+#[derive(Debug, Default)]
+pub(crate) struct QuotaState {
+    /// Whether the quota has been read from the repository yet
+    pub loaded: bool,
+    /// The quota, if one has been set
+    pub quota: Option<SizeQuota>,
+    /// Whether the usage has changed since the quota was last written to the repository
+    pub dirty: bool,
+}
@@ -0,0 +1,59 @@
+//! Aggregate statistics about the contents of a repository
+//!
+//! The chunk level statistics are collected by `Repository::statistics`, which walks the index and
+//! reads the header of every chunk. Statistics that require knowledge of the archives, such as
+//! the number of bytes referenced by them, or the size of the chunks' contents, are filled in by
+//! `Manifest::statistics`.
+use serde::{Deserialize, Serialize};
+
+use std::collections::BTreeMap;
+
+/// Statistics describing the contents of a repository
+#[derive(Serialize, Deserialize, Clone, Debug, Default, PartialEq)]
+pub struct RepoStatistics {
+    /// Number of archives in the repository's manifest
+    pub archives: usize,
+    /// Number of unique chunks in the repository's index
+    pub unique_chunks: usize,
+    /// Total size of the object data held by the unique chunks referenced by archives, before
+    /// compression and encryption
+    ///
+    /// This is only filled in by `Manifest::statistics`, and will be zero otherwise.
+    pub logical_bytes: u64,
+    /// Total size of the unique chunks, as stored in the backend after compression and
+    /// encryption
+    pub stored_bytes: u64,
+    /// Total number of bytes referenced by the objects of all the archives in the repository,
+    /// counting each reference to a chunk separately
+    ///
+    /// This is only filled in by `Manifest::statistics`, and will be zero otherwise.
+    pub referenced_bytes: u64,
+    /// Ratio of referenced bytes to unique logical bytes
+    ///
+    /// Higher is better, a value of 1.0 indicates that no data has been deduplicated.
+    pub dedup_ratio: f64,
+    /// Ratio of unique logical bytes to stored bytes
+    ///
+    /// Higher is better, a value of 1.0 indicates that compression has had no effect.
+    pub compression_ratio: f64,
+    /// Number of chunks using each compression algorithm, keyed by the algorithm's name
+    pub chunks_per_compression: BTreeMap<String, usize>,
+}
+
+impl RepoStatistics {
+    /// Recomputes the ratios from the current byte totals
+    ///
+    /// A ratio with a zero denominator is reported as 1.0
+    #[allow(clippy::cast_precision_loss)]
+    pub(crate) fn update_ratios(&mut self) {
+        let ratio = |numerator: u64, denominator: u64| {
+            if denominator == 0 {
+                1.0
+            } else {
+                numerator as f64 / denominator as f64
+            }
+        };
+        self.dedup_ratio = ratio(self.referenced_bytes, self.logical_bytes);
+        self.compression_ratio = ratio(self.logical_bytes, self.stored_bytes);
+    }
+}
@@ -0,0 +1,908 @@
+//! Extraction of archives back into directory trees
+//!
+//! This is the counterpart to `backup`, recreating the tree recorded in an archive's `Listing`
+//! underneath a destination directory.
+use crate::manifest::{ActiveArchive, ArchiveError, GlobFilter};
+use crate::repository::{BackendClone, ChunkID, Repository};
+
+use asuran_core::manifest::listing::NodeType;
+
+use serde::{Deserialize, Serialize};
+use smol::blocking;
+use thiserror::Error;
+use tracing::{debug, warn};
+
+use std::collections::HashSet;
+use std::fs::{create_dir_all, File, OpenOptions};
+use std::io::{Seek, SeekFrom, Write};
+use std::path::{Component, Path, PathBuf};
+
+/// Size of the blocks `SparseWriter` checks for zeros in
+const SPARSE_BLOCK_SIZE: u64 = 4096;
+/// Suffix appended to the name of a destination to form the name of its progress sidecar
+const PROGRESS_SUFFIX: &str = ".asuran-progress";
+
+/// An error for things that can go wrong extracting a directory
+#[derive(Error, Debug)]
+pub enum RestoreError {
+    #[error("Archive Error: {0}")]
+    Archive(#[from] ArchiveError),
+    #[error("I/O Error: {0}")]
+    IO(#[from] std::io::Error),
+    #[error("Refusing to extract {0:?}, as it would be written outside of the destination")]
+    UnsafePath(String),
+    #[error("Refusing to overwrite existing file {0:?}")]
+    AlreadyExists(PathBuf),
+}
+
+impl RestoreError {
+    /// Returns a short, stable, `snake_case` name for the kind of this error
+    pub fn kind(&self) -> &'static str {
+        match self {
+            RestoreError::Archive(e) => e.kind(),
+            RestoreError::IO(_) => "io",
+            RestoreError::UnsafePath(_) => "unsafe_path",
+            RestoreError::AlreadyExists(_) => "already_exists",
+        }
+    }
+}
+
+/// What to do when a file being extracted already exists in the destination
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ExtractPolicy {
+    /// Replace the existing file with the one from the archive
+    Overwrite,
+    /// Leave the existing file alone, and do not extract the one from the archive
+    SkipExisting,
+    /// Refuse the extraction, before anything is written
+    Fail,
+    /// Move the existing file aside to `<name>.<n>`, using the first unused `n`, and then extract
+    /// the one from the archive
+    RenameExisting,
+}
+
+impl Default for ExtractPolicy {
+    /// Defaults to `Fail`, so that no data is ever lost without asking
+    fn default() -> Self {
+        ExtractPolicy::Fail
+    }
+}
+
+/// Settings controlling which files `extract_directory` restores, and how it writes them
+///
+/// See `extract_directory` for the details of each. The default extracts everything, refusing to
+/// overwrite any existing file, and writes files densely.
+#[derive(Clone, Debug, Default)]
+pub struct ExtractOptions {
+    /// Filter that the path of each object must pass to be extracted
+    pub globs: GlobFilter,
+    /// What to do when a file being extracted already exists
+    pub policy: ExtractPolicy,
+    /// Leave blocks of zeros as holes in the restored files
+    pub sparse: bool,
+}
+
+/// A writer that leaves holes in place of blocks of zeros
+///
+/// Writes are split up along `SPARSE_BLOCK_SIZE` aligned boundaries, and any piece consisting
+/// entirely of zeros is seeked over instead of written. On platforms and file systems that support
+/// sparse files, this leaves those blocks unallocated.
+///
+/// Seeking over the end of the file does not extend it, so the caller must set the length of the
+/// file once writing is finished, in case it ends in a hole.
+pub struct SparseWriter<W: Write + Seek> {
+    inner: W,
+    position: u64,
+}
+
+impl<W: Write + Seek> SparseWriter<W> {
+    /// Wraps a writer, which must be positioned at the start of a file with no existing contents
+    pub fn new(inner: W) -> SparseWriter<W> {
+        SparseWriter { inner, position: 0 }
+    }
+}
+
+impl<W: Write + Seek> Write for SparseWriter<W> {
+    // Pieces are never larger than `SPARSE_BLOCK_SIZE`, so these casts can not truncate or wrap
+    #[allow(clippy::cast_possible_truncation, clippy::cast_possible_wrap)]
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        let mut remaining = buf;
+        while !remaining.is_empty() {
+            let to_boundary = SPARSE_BLOCK_SIZE - self.position % SPARSE_BLOCK_SIZE;
+            let length = std::cmp::min(to_boundary, remaining.len() as u64) as usize;
+            let (piece, rest) = remaining.split_at(length);
+            if piece.iter().all(|x| *x == 0) {
+                self.inner.seek(SeekFrom::Current(length as i64))?;
+            } else {
+                self.inner.write_all(piece)?;
+            }
+            self.position += length as u64;
+            remaining = rest;
+        }
+        Ok(buf.len())
+    }
+    fn flush(&mut self) -> std::io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+/// A single entry in a restore progress sidecar
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+enum ProgressRecord {
+    /// The id of the archive being extracted, always the first record
+    Archive(ChunkID),
+    /// Writing of the file at this path in the archive has begun
+    Started(String),
+    /// The file at this path in the archive has been completely written and synced
+    Completed(String),
+}
+
+/// Tracks which files of an extraction have been restored, so that an interrupted extraction can
+/// be resumed
+///
+/// Progress is recorded in a sidecar file next to the destination, see `sidecar_path`, as a
+/// sequence of CBOR records. Each file is recorded as started before it is created, and as
+/// completed once it has been fully written and synced. A file that was started but never
+/// completed may be partially written, and is truncated and restored again on resume.
+///
+/// The sidecar starts with the id of the archive being extracted. Progress recorded for any other
+/// archive does not describe the files in the destination, and is discarded when the sidecar is
+/// opened.
+///
+/// An interruption while appending a record can leave a torn record at the end of the sidecar,
+/// this is discarded when the sidecar is opened.
+#[derive(Debug)]
+pub struct RestoreProgress {
+    path: PathBuf,
+    file: File,
+    started: HashSet<String>,
+    completed: HashSet<String>,
+}
+
+impl RestoreProgress {
+    /// Returns the location of the progress sidecar for an extraction into `dest`
+    ///
+    /// This is a sibling of `dest`, named after it, unless `dest` has no name of its own (such
+    /// as `.`), in which case the sidecar is placed inside of it.
+    pub fn sidecar_path(dest: &Path) -> PathBuf {
+        match dest.file_name() {
+            Some(name) => {
+                let mut name = name.to_owned();
+                name.push(PROGRESS_SUFFIX);
+                dest.with_file_name(name)
+            }
+            None => dest.join(PROGRESS_SUFFIX),
+        }
+    }
+
+    /// Opens the progress sidecar for an extraction of the archive with id `archive` into `dest`,
+    /// loading any progress recorded by a previous, interrupted, extraction of the same archive
+    ///
+    /// The sidecar is created if it does not exist. If it records the progress of a different
+    /// archive, that progress is discarded, and the sidecar is started over.
+    ///
+    /// # Errors
+    ///
+    /// Will return `Err` if the sidecar can not be read, created, or repaired.
+    pub fn open(dest: &Path, archive: ChunkID) -> Result<RestoreProgress> {
+        let path = Self::sidecar_path(dest);
+        let mut recorded_archive = None;
+        let mut started = HashSet::new();
+        let mut completed = HashSet::new();
+        let bytes = if path.exists() {
+            std::fs::read(&path)?
+        } else {
+            Vec::new()
+        };
+        let mut records =
+            serde_cbor::Deserializer::from_slice(&bytes).into_iter::<ProgressRecord>();
+        let mut valid_length = 0;
+        while let Some(record) = records.next() {
+            match record {
+                Ok(ProgressRecord::Archive(id)) => {
+                    recorded_archive = Some(id);
+                }
+                Ok(ProgressRecord::Started(entry)) => {
+                    started.insert(entry);
+                }
+                Ok(ProgressRecord::Completed(entry)) => {
+                    completed.insert(entry);
+                }
+                Err(e) => {
+                    warn!(
+                        ?e,
+                        "Discarding torn record at the end of the restore progress"
+                    );
+                    break;
+                }
+            }
+            valid_length = records.byte_offset();
+        }
+        if recorded_archive != Some(archive) {
+            if !bytes.is_empty() {
+                warn!(
+                    ?path,
+                    "Discarding restore progress recorded for a different archive"
+                );
+            }
+            started.clear();
+            completed.clear();
+            valid_length = 0;
+        }
+        let mut file = OpenOptions::new().create(true).write(true).open(&path)?;
+        // Drop any torn record, so that new records are not appended after it
+        file.set_len(valid_length as u64)?;
+        file.seek(SeekFrom::End(0))?;
+        let mut progress = RestoreProgress {
+            path,
+            file,
+            started,
+            completed,
+        };
+        if valid_length == 0 {
+            progress.append(&ProgressRecord::Archive(archive))?;
+        }
+        debug!(
+            path = ?progress.path,
+            started = progress.started.len(),
+            completed = progress.completed.len(),
+            "Opened restore progress"
+        );
+        Ok(progress)
+    }
+
+    /// Returns true if the file at `path` in the archive has been completely restored
+    pub fn is_completed(&self, path: &str) -> bool {
+        self.completed.contains(path)
+    }
+
+    /// Returns true if the file at `path` in the archive was started, but never completed, and
+    /// so may be partially written
+    pub fn is_interrupted(&self, path: &str) -> bool {
+        self.started.contains(path) && !self.completed.contains(path)
+    }
+
+    /// Returns the number of files that have been completely restored
+    pub fn completed_count(&self) -> usize {
+        self.completed.len()
+    }
+
+    /// Records that writing of the file at `path` in the archive is about to begin
+    ///
+    /// # Errors
+    ///
+    /// Will return `Err` if the record could not be written to the sidecar.
+    pub fn mark_started(&mut self, path: &str) -> Result<()> {
+        self.append(&ProgressRecord::Started(path.to_string()))?;
+        self.started.insert(path.to_string());
+        Ok(())
+    }
+
+    /// Records that the file at `path` in the archive has been completely written
+    ///
+    /// The file must have already been synced, as this is what allows a resumed extraction to
+    /// skip it.
+    ///
+    /// # Errors
+    ///
+    /// Will return `Err` if the record could not be written to the sidecar.
+    pub fn mark_completed(&mut self, path: &str) -> Result<()> {
+        self.append(&ProgressRecord::Completed(path.to_string()))?;
+        self.completed.insert(path.to_string());
+        Ok(())
+    }
+
+    /// Removes the sidecar, once the extraction it tracks has finished
+    ///
+    /// # Errors
+    ///
+    /// Will return `Err` if the sidecar could not be removed.
+    pub fn finish(self) -> Result<()> {
+        drop(self.file);
+        std::fs::remove_file(&self.path)?;
+        Ok(())
+    }
+
+    fn append(&mut self, record: &ProgressRecord) -> Result<()> {
+        let bytes = serde_cbor::to_vec(record).expect("Serializing a path can not fail");
+        self.file.write_all(&bytes)?;
+        self.file.sync_data()?;
+        Ok(())
+    }
+}
+
+/// Finds the first path of the form `<path>.<n>` that does not exist
+fn rename_target(path: &Path) -> PathBuf {
+    let mut n = 1_u64;
+    loop {
+        let mut candidate = path.as_os_str().to_owned();
+        candidate.push(format!(".{}", n));
+        let candidate = PathBuf::from(candidate);
+        if !candidate.exists() {
+            return candidate;
+        }
+        n += 1;
+    }
+}
+
+type Result<T> = std::result::Result<T, RestoreError>;
+
+/// Resolves the path of an object in an archive to a location underneath `dest`
+///
+/// Only plain relative paths are accepted, anything containing a root, a prefix, or a parent
+/// directory component could resolve to somewhere outside of `dest`, and is rejected.
+///
+/// Symbolic links are never followed. A path is also rejected if any part of it below `dest`
+/// already exists as a symbolic link, as writing through the link could land anywhere. This
+/// inspects the destination, and should be run on the blocking thread pool.
+fn resolve(dest: &Path, path: &str) -> Result<PathBuf> {
+    let relative = Path::new(path);
+    let safe = relative
+        .components()
+        .all(|component| matches!(component, Component::Normal(_) | Component::CurDir));
+    if !safe {
+        return Err(RestoreError::UnsafePath(path.to_string()));
+    }
+    let mut resolved = dest.to_path_buf();
+    for component in relative.components() {
+        resolved.push(component);
+        match std::fs::symlink_metadata(&resolved) {
+            Ok(metadata) if metadata.file_type().is_symlink() => {
+                return Err(RestoreError::UnsafePath(path.to_string()));
+            }
+            Ok(_) => {}
+            // Nothing can exist below a missing path
+            Err(_) => break,
+        }
+    }
+    Ok(resolved)
+}
+
+/// Recreates the tree recorded in the listing of `archive` underneath `dest`
+///
+/// Only objects whose path passes `options.globs` are extracted, the parent directories of
+/// extracted files are created as needed. Every path is checked before anything is written, and
+/// the whole extraction is refused if any of them would land outside of `dest`, including through
+/// a symbolic link already present in the destination.
+///
+/// Files that already exist in the destination are handled according to `options.policy`. With
+/// `ExtractPolicy::Fail`, every file is checked before anything is written.
+///
+/// If `options.sparse` is set, blocks of zeros are left as holes in the restored files, see
+/// `SparseWriter`.
+///
+/// If `progress` is provided, each file is recorded in it as it is restored, and files it records
+/// as already completed are skipped. Files it records as interrupted are truncated and restored
+/// again, regardless of the policy. The caller is responsible for calling
+/// `RestoreProgress::finish` once the extraction succeeds.
+///
+/// Links are skipped, as their targets are not recorded in the listing. The listing does not yet
+/// record permissions or timestamps, so the only metadata applied is the length of each file,
+/// which keeps any trailing hole in a sparse file.
+///
+/// Returns the number of objects that were restored, not counting skipped files.
+///
+/// # Errors
+///
+/// Will return `Err` if any path in the listing is unsafe, if a file already exists and the policy
+/// is `ExtractPolicy::Fail`, or if retrieving or writing any object fails.
+pub async fn extract_directory<B: BackendClone>(
+    archive: &ActiveArchive,
+    repo: &mut Repository<B>,
+    dest: &Path,
+    options: &ExtractOptions,
+    mut progress: Option<&mut RestoreProgress>,
+) -> Result<usize> {
+    let globs = &options.globs;
+    let (policy, sparse) = (options.policy, options.sparse);
+    let listing = archive.listing().await;
+    let mut nodes = Vec::new();
+    for (_, node) in listing.walk() {
+        if !globs.is_match(&node.path) {
+            debug!(path = %node.path, "Excluding object by glob");
+            continue;
+        }
+        let path = {
+            let (dest, object) = (dest.to_path_buf(), node.path.clone());
+            blocking!(resolve(&dest, &object))?
+        };
+        // Files already tracked by the progress were written by an earlier extraction
+        let tracked = progress.as_ref().map_or(false, |progress| {
+            progress.is_completed(&node.path) || progress.is_interrupted(&node.path)
+        });
+        if policy == ExtractPolicy::Fail && node.is_file() && path.exists() && !tracked {
+            return Err(RestoreError::AlreadyExists(path));
+        }
+        nodes.push((path, node));
+    }
+
+    let mut restored = 0;
+    for (path, node) in nodes {
+        match &node.node_type {
+            NodeType::Directory { .. } => {
+                blocking!(create_dir_all(path))?;
+                restored += 1;
+            }
+            NodeType::Link => {
+                warn!(path = %node.path, "Skipping link");
+            }
+            NodeType::File | NodeType::InlineFile { .. } => {
+                let mut interrupted = false;
+                if let Some(progress) = &progress {
+                    if progress.is_completed(&node.path) {
+                        debug!(path = %node.path, "Skipping already restored file");
+                        continue;
+                    }
+                    interrupted = progress.is_interrupted(&node.path);
+                }
+                if interrupted {
+                    debug!(path = %node.path, "Redoing partially restored file");
+                } else if path.exists() {
+                    match policy {
+                        ExtractPolicy::Overwrite | ExtractPolicy::Fail => {}
+                        ExtractPolicy::SkipExisting => {
+                            debug!(path = %node.path, "Skipping existing file");
+                            continue;
+                        }
+                        ExtractPolicy::RenameExisting => {
+                            let renamed = rename_target(&path);
+                            debug!(path = %node.path, ?renamed, "Moving existing file aside");
+                            std::fs::rename(&path, renamed)?;
+                        }
+                    }
+                }
+                if let Some(parent) = path.parent() {
+                    let parent = parent.to_path_buf();
+                    blocking!(create_dir_all(parent))?;
+                }
+                if let Some(progress) = &mut progress {
+                    progress.mark_started(&node.path)?;
+                }
+                // Creating the file truncates anything left by an interrupted extraction
+                let file = {
+                    let path = path.clone();
+                    blocking!(File::create(path))?
+                };
+                if let Some(data) = node.inline_data() {
+                    (&file).write_all(data)?;
+                } else if node.total_length > 0 {
+                    // Empty objects have no chunks to retrieve
+                    if sparse {
+                        let writer = SparseWriter::new(&file);
+                        archive.get_object(repo, &node.path, writer).await?;
+                    } else {
+                        archive.get_object(repo, &node.path, &file).await?;
+                    }
+                }
+                // A completed file must be durable before it is recorded as such
+                let (length, sync) = (node.total_length, progress.is_some());
+                blocking!({
+                    file.set_len(length)?;
+                    if sync {
+                        file.sync_data()?;
+                    }
+                    Ok::<_, std::io::Error>(())
+                })?;
+                if let Some(progress) = &mut progress {
+                    progress.mark_completed(&node.path)?;
+                }
+                restored += 1;
+            }
+        }
+    }
+    Ok(restored)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::backup::{store_directory, StoreOptions};
+    use crate::chunker::FastCDC;
+    use crate::repository::backend::mem::Mem;
+    use crate::repository::{ChunkSettings, Key};
+    use asuran_core::manifest::listing::{Listing, Node};
+
+    use tempfile::tempdir;
+
+    use std::fs;
+
+    fn get_repo() -> Repository<impl BackendClone> {
+        let key = Key::random(32);
+        let settings = ChunkSettings::lightweight();
+        let backend = Mem::new(settings, key.clone(), 4);
+        Repository::with(backend, settings, key, 2).unwrap()
+    }
+
+    #[test]
+    fn extract_stored_tree() {
+        smol::run(async {
+            let input = tempdir().unwrap();
+            let input_path = input.path();
+            fs::create_dir_all(input_path.join("A/B")).unwrap();
+            fs::create_dir_all(input_path.join("empty")).unwrap();
+            fs::write(input_path.join("1"), b"one").unwrap();
+            fs::write(input_path.join("A/2"), vec![9_u8; 200_000]).unwrap();
+            fs::write(input_path.join("A/B/3"), b"three").unwrap();
+            fs::write(input_path.join("A/B/nothing"), b"").unwrap();
+
+            let mut repo = get_repo();
+            let archive = ActiveArchive::new("test");
+            store_directory(
+                &archive,
+                FastCDC::default(),
+                &mut repo,
+                input_path,
+                &StoreOptions::default(),
+            )
+            .await
+            .unwrap();
+
+            let output = tempdir().unwrap();
+            extract_directory(
+                &archive,
+                &mut repo,
+                output.path(),
+                &ExtractOptions::default(),
+                None,
+            )
+            .await
+            .unwrap();
+            assert!(!dir_diff::is_different(input_path, output.path()).unwrap());
+            assert_eq!(fs::read(output.path().join("A/B/3")).unwrap(), b"three");
+
+            // Only the matching file, and the directories needed to hold it, are extracted
+            let filtered = tempdir().unwrap();
+            let globs = GlobFilter::new(&["A/B/3"], &[] as &[&str]).unwrap();
+            extract_directory(
+                &archive,
+                &mut repo,
+                filtered.path(),
+                &ExtractOptions {
+                    globs,
+                    ..ExtractOptions::default()
+                },
+                None,
+            )
+            .await
+            .unwrap();
+            assert!(filtered.path().join("A/B/3").exists());
+            assert!(!filtered.path().join("1").exists());
+            assert!(!filtered.path().join("empty").exists());
+        });
+    }
+
+    #[test]
+    fn refuses_path_traversal() {
+        smol::run(async {
+            let mut repo = get_repo();
+            let archive = ActiveArchive::new("test");
+            let mut listing = Listing::default();
+            for path in &["fine", "../escaped"] {
+                listing.add_child(
+                    "",
+                    Node {
+                        path: (*path).to_string(),
+                        total_length: 0,
+                        total_size: 0,
+                        extents: None,
+                        content_type: None,
+                        node_type: NodeType::File,
+                    },
+                );
+            }
+            archive.set_listing(listing).await;
+
+            let root = tempdir().unwrap();
+            let dest = root.path().join("dest");
+            let result =
+                extract_directory(&archive, &mut repo, &dest, &ExtractOptions::default(), None)
+                    .await;
+            assert!(matches!(result, Err(RestoreError::UnsafePath(_))));
+            // Nothing at all is written once an unsafe path is found
+            assert!(!dest.join("fine").exists());
+            assert!(!root.path().join("escaped").exists());
+        });
+    }
+
+    #[test]
+    #[cfg(unix)]
+    fn refuses_symlink_traversal() {
+        smol::run(async {
+            let input = tempdir().unwrap();
+            fs::create_dir(input.path().join("A")).unwrap();
+            fs::write(input.path().join("A/1"), b"one").unwrap();
+            let mut repo = get_repo();
+            let archive = ActiveArchive::new("test");
+            store_directory(
+                &archive,
+                FastCDC::default(),
+                &mut repo,
+                input.path(),
+                &StoreOptions::default(),
+            )
+            .await
+            .unwrap();
+
+            // A link in the destination, in place of a directory in the archive
+            let outside = tempdir().unwrap();
+            let dest = tempdir().unwrap();
+            std::os::unix::fs::symlink(outside.path(), dest.path().join("A")).unwrap();
+            let result = extract_directory(
+                &archive,
+                &mut repo,
+                dest.path(),
+                &ExtractOptions {
+                    policy: ExtractPolicy::Overwrite,
+                    ..ExtractOptions::default()
+                },
+                None,
+            )
+            .await;
+            assert!(matches!(result, Err(RestoreError::UnsafePath(_))));
+            assert!(!outside.path().join("1").exists());
+        });
+    }
+
+    #[test]
+    fn conflict_policies() {
+        smol::run(async {
+            let input = tempdir().unwrap();
+            fs::write(input.path().join("1"), b"archived one").unwrap();
+            fs::write(input.path().join("2"), b"archived two").unwrap();
+            let mut repo = get_repo();
+            let archive = ActiveArchive::new("test");
+            store_directory(
+                &archive,
+                FastCDC::default(),
+                &mut repo,
+                input.path(),
+                &StoreOptions::default(),
+            )
+            .await
+            .unwrap();
+
+            // Each destination starts out with a conflicting copy of "1"
+            let dest = || {
+                let dest = tempdir().unwrap();
+                fs::write(dest.path().join("1"), b"existing").unwrap();
+                dest
+            };
+            let read = |path: &Path| fs::read(path).unwrap();
+
+            let overwrite = dest();
+            extract_directory(
+                &archive,
+                &mut repo,
+                overwrite.path(),
+                &ExtractOptions {
+                    policy: ExtractPolicy::Overwrite,
+                    ..ExtractOptions::default()
+                },
+                None,
+            )
+            .await
+            .unwrap();
+            assert_eq!(read(&overwrite.path().join("1")), b"archived one");
+            assert_eq!(read(&overwrite.path().join("2")), b"archived two");
+
+            let skip = dest();
+            extract_directory(
+                &archive,
+                &mut repo,
+                skip.path(),
+                &ExtractOptions {
+                    policy: ExtractPolicy::SkipExisting,
+                    ..ExtractOptions::default()
+                },
+                None,
+            )
+            .await
+            .unwrap();
+            assert_eq!(read(&skip.path().join("1")), b"existing");
+            assert_eq!(read(&skip.path().join("2")), b"archived two");
+
+            let fail = dest();
+            let result = extract_directory(
+                &archive,
+                &mut repo,
+                fail.path(),
+                &ExtractOptions {
+                    policy: ExtractPolicy::Fail,
+                    ..ExtractOptions::default()
+                },
+                None,
+            )
+            .await;
+            assert!(matches!(result, Err(RestoreError::AlreadyExists(_))));
+            assert_eq!(read(&fail.path().join("1")), b"existing");
+            assert!(!fail.path().join("2").exists());
+
+            let rename = dest();
+            fs::write(rename.path().join("1.1"), b"older").unwrap();
+            extract_directory(
+                &archive,
+                &mut repo,
+                rename.path(),
+                &ExtractOptions {
+                    policy: ExtractPolicy::RenameExisting,
+                    ..ExtractOptions::default()
+                },
+                None,
+            )
+            .await
+            .unwrap();
+            assert_eq!(read(&rename.path().join("1")), b"archived one");
+            assert_eq!(read(&rename.path().join("1.1")), b"older");
+            assert_eq!(read(&rename.path().join("1.2")), b"existing");
+        });
+    }
+
+    #[test]
+    fn resume_interrupted_restore() {
+        smol::run(async {
+            let input = tempdir().unwrap();
+            fs::write(input.path().join("1"), b"archived one").unwrap();
+            fs::write(input.path().join("2"), vec![7_u8; 100_000]).unwrap();
+            fs::write(input.path().join("3"), b"archived three").unwrap();
+            let mut repo = get_repo();
+            let archive = ActiveArchive::new("test");
+            store_directory(
+                &archive,
+                FastCDC::default(),
+                &mut repo,
+                input.path(),
+                &StoreOptions::default(),
+            )
+            .await
+            .unwrap();
+
+            // Simulate an extraction that was interrupted while writing "2", after finishing "1"
+            let root = tempdir().unwrap();
+            let dest = root.path().join("dest");
+            let archive_id = ChunkID::random_id();
+            let mut progress = RestoreProgress::open(&dest, archive_id).unwrap();
+            extract_directory(
+                &archive,
+                &mut repo,
+                &dest,
+                &ExtractOptions {
+                    globs: GlobFilter::new(&["1"], &[] as &[&str]).unwrap(),
+                    policy: ExtractPolicy::Fail,
+                    ..ExtractOptions::default()
+                },
+                Some(&mut progress),
+            )
+            .await
+            .unwrap();
+            progress.mark_started("2").unwrap();
+            fs::write(dest.join("2"), vec![7_u8; 5000]).unwrap();
+            drop(progress);
+            // Along with a torn record at the end of the sidecar
+            let sidecar = RestoreProgress::sidecar_path(&dest);
+            assert_eq!(sidecar, root.path().join("dest.asuran-progress"));
+            let mut torn = fs::read(&sidecar).unwrap();
+            torn.extend_from_slice(&[0x81, 0x69]);
+            fs::write(&sidecar, torn).unwrap();
+            let modified = fs::metadata(dest.join("1")).unwrap().modified().unwrap();
+
+            // Progress recorded for a different archive is discarded
+            let other = root.path().join("other");
+            fs::copy(&sidecar, RestoreProgress::sidecar_path(&other)).unwrap();
+            let discarded = RestoreProgress::open(&other, ChunkID::random_id()).unwrap();
+            assert_eq!(discarded.completed_count(), 0);
+            assert!(!discarded.is_interrupted("2"));
+
+            let mut progress = RestoreProgress::open(&dest, archive_id).unwrap();
+            assert!(progress.is_completed("1"));
+            assert!(progress.is_interrupted("2"));
+            assert!(!progress.is_interrupted("3"));
+            extract_directory(
+                &archive,
+                &mut repo,
+                &dest,
+                &ExtractOptions {
+                    policy: ExtractPolicy::Fail,
+                    ..ExtractOptions::default()
+                },
+                Some(&mut progress),
+            )
+            .await
+            .unwrap();
+            assert_eq!(progress.completed_count(), 3);
+            progress.finish().unwrap();
+
+            assert!(!dir_diff::is_different(input.path(), &dest).unwrap());
+            // The completed file was skipped, rather than being written again
+            let after = fs::metadata(dest.join("1")).unwrap().modified().unwrap();
+            assert_eq!(modified, after);
+            assert!(!sidecar.exists());
+        });
+    }
+
+    #[test]
+    #[cfg(target_os = "linux")]
+    fn sparse_restore() {
+        use std::os::unix::fs::MetadataExt;
+        smol::run(async {
+            let input = tempdir().unwrap();
+            let mut data = vec![1_u8; 4096];
+            data.extend(vec![0_u8; 8 * 1024 * 1024]);
+            data.extend(vec![2_u8; 4096]);
+            fs::write(input.path().join("sparse"), &data).unwrap();
+            let mut repo = get_repo();
+            let archive = ActiveArchive::new("test");
+            store_directory(
+                &archive,
+                FastCDC::default(),
+                &mut repo,
+                input.path(),
+                &StoreOptions::default(),
+            )
+            .await
+            .unwrap();
+
+            let output = tempdir().unwrap();
+            extract_directory(
+                &archive,
+                &mut repo,
+                output.path(),
+                &ExtractOptions {
+                    sparse: true,
+                    ..ExtractOptions::default()
+                },
+                None,
+            )
+            .await
+            .unwrap();
+            let path = output.path().join("sparse");
+            assert_eq!(fs::read(&path).unwrap(), data);
+            let metadata = fs::metadata(&path).unwrap();
+            // `blocks` is always in units of 512 bytes
+            assert!(metadata.blocks() * 512 < metadata.len());
+        });
+    }
+
+    // Files under the inline threshold must be restored from the listing, without any chunks
+    #[test]
+    fn inline_small_files() {
+        smol::run(async {
+            let input = tempdir().unwrap();
+            fs::write(input.path().join("small"), b"0123456789").unwrap();
+            let mut repo = get_repo();
+            let archive = ActiveArchive::new("test");
+            let before = repo.count_chunk().await;
+            store_directory(
+                &archive,
+                FastCDC::default(),
+                &mut repo,
+                input.path(),
+                &StoreOptions {
+                    inline_threshold: 64,
+                    ..StoreOptions::default()
+                },
+            )
+            .await
+            .unwrap();
+            assert_eq!(repo.count_chunk().await, before);
+            let listing = archive.listing().await;
+            let node = listing.get("small").unwrap();
+            assert_eq!(node.inline_data(), Some(&b"0123456789"[..]));
+            assert_eq!(node.total_length, 10);
+
+            let output = tempdir().unwrap();
+            extract_directory(
+                &archive,
+                &mut repo,
+                output.path(),
+                &ExtractOptions::default(),
+                None,
+            )
+            .await
+            .unwrap();
+            assert_eq!(
+                fs::read(output.path().join("small")).unwrap(),
+                b"0123456789".to_vec()
+            );
+        });
+    }
+}
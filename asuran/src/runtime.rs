@@ -0,0 +1,66 @@
+//! Spawning of background tasks, independent of the platform
+//!
+//! Natively, the event loops of the sync backends and the pipeline get dedicated threads, and
+//! everything else is spawned onto smol's executor. There are no threads in the browser, so on
+//! wasm32, everything is spawned onto the browser's event loop with `wasm-bindgen-futures` instead.
+use std::future::Future;
+use std::time::Duration;
+
+#[cfg(not(target_arch = "wasm32"))]
+pub use smol::Task as JoinHandle;
+
+/// A handle to a spawned task, resolving to its output
+///
+/// Like smol's `Task`, dropping this cancels the task.
+#[cfg(target_arch = "wasm32")]
+pub type JoinHandle<T> = futures::future::RemoteHandle<T>;
+
+/// Runs a long lived event loop in the background, until it completes
+///
+/// The loop is constructed by a closure on its own thread, as it will be performing blocking work,
+/// so it does not have to be `Send` itself.
+#[cfg(not(target_arch = "wasm32"))]
+pub fn spawn_loop<F>(event_loop: impl FnOnce() -> F + Send + 'static)
+where
+    F: Future<Output = ()>,
+{
+    std::thread::spawn(move || smol::block_on(event_loop()));
+}
+
+/// Runs a long lived event loop in the background, until it completes
+///
+/// The loop is run on the browser's event loop, so it must yield regularly.
+#[cfg(target_arch = "wasm32")]
+pub fn spawn_loop<F>(event_loop: impl FnOnce() -> F + 'static)
+where
+    F: Future<Output = ()> + 'static,
+{
+    wasm_bindgen_futures::spawn_local(event_loop());
+}
+
+/// Spawns a task onto the executor, returning a handle that resolves to its output
+#[cfg(not(target_arch = "wasm32"))]
+pub fn spawn<T: Send + 'static>(task: impl Future<Output = T> + Send + 'static) -> JoinHandle<T> {
+    smol::Task::spawn(task)
+}
+
+/// Spawns a task onto the browser's event loop, returning a handle that resolves to its output
+#[cfg(target_arch = "wasm32")]
+pub fn spawn<T: 'static>(task: impl Future<Output = T> + 'static) -> JoinHandle<T> {
+    use futures::future::FutureExt;
+    let (task, handle) = task.remote_handle();
+    wasm_bindgen_futures::spawn_local(task);
+    handle
+}
+
+/// Waits for the given duration without blocking the executor
+#[cfg(not(target_arch = "wasm32"))]
+pub async fn sleep(duration: Duration) {
+    smol::Timer::after(duration).await;
+}
+
+/// Waits for the given duration without blocking the browser's event loop
+#[cfg(target_arch = "wasm32")]
+pub async fn sleep(duration: Duration) {
+    futures_timer::Delay::new(duration).await;
+}
@@ -0,0 +1,251 @@
+//! Recommends chunk settings by benchmarking them against a sample of the user's own data
+//!
+//! The throughput of compression, and how much space it saves, depend heavily on the data being
+//! stored, so the combinations are measured on a user provided sample rather than on synthetic
+//! data. This is used by `asuran-cli auto-tune`, but is kept here so that it can be tested.
+use crate::chunker::{Chunker, FastCDC};
+use crate::repository::{Chunk, ChunkSettings, Compression, Encryption, Key, HMAC};
+
+use walkdir::WalkDir;
+
+use std::fs::File;
+use std::io::{self, Read};
+use std::path::Path;
+use std::time::Instant;
+
+const ONE_MIB: f64 = 1_048_576.0;
+
+/// Smallest fraction of the sample that a compression algorithm must save for it to be
+/// recommended over no compression at all
+pub const MIN_SAVINGS: f64 = 0.05;
+
+/// Compression algorithms, and levels, that are tried on the sample
+///
+/// Only the algorithms that support was compiled in for are actually tried, see
+/// `tune_compressions`.
+pub const TUNE_COMPRESSIONS: [Compression; 6] = [
+    Compression::NoCompression,
+    Compression::LZ4 { level: 4 },
+    Compression::ZStd { level: 1 },
+    Compression::ZStd { level: 3 },
+    Compression::ZStd { level: 9 },
+    Compression::LZMA { level: 6 },
+];
+
+/// HMAC algorithms that are tried on the sample
+///
+/// SHA2 is left out for the reasons given by `asuran-cli bench-crypto`, and BLAKE3 XOF is only
+/// worth its cost for users who have specifically asked for the extra collision margin. Only the
+/// algorithms that support was compiled in for are actually tried, see `tune_hmacs`.
+pub const TUNE_HMACS: [HMAC; 4] = [HMAC::Blake2b, HMAC::Blake2bp, HMAC::Blake3, HMAC::SHA3];
+
+/// HMAC algorithms that are tried if support for none of `TUNE_HMACS` was compiled in
+const FALLBACK_HMACS: [HMAC; 2] = [HMAC::Blake3XOF, HMAC::SHA256];
+
+/// Returns the members of `TUNE_COMPRESSIONS` that support was compiled in for
+///
+/// This always includes `Compression::NoCompression`.
+pub fn tune_compressions() -> Vec<Compression> {
+    TUNE_COMPRESSIONS
+        .iter()
+        .copied()
+        .filter(|x| x.is_available())
+        .collect()
+}
+
+/// Returns the members of `TUNE_HMACS` that support was compiled in for
+///
+/// If none of them were compiled in, the algorithms left out of `TUNE_HMACS` are returned
+/// instead, so that a recommendation can still be made.
+pub fn tune_hmacs() -> Vec<HMAC> {
+    let hmacs: Vec<HMAC> = TUNE_HMACS
+        .iter()
+        .copied()
+        .filter(|x| x.is_available())
+        .collect();
+    if hmacs.is_empty() {
+        FALLBACK_HMACS
+            .iter()
+            .copied()
+            .filter(|x| x.is_available())
+            .collect()
+    } else {
+        hmacs
+    }
+}
+
+/// The results of packing a sample with a single combination of settings
+#[derive(Debug, Clone, PartialEq)]
+pub struct TuneResult {
+    /// The compression algorithm the sample was packed with
+    pub compression: Compression,
+    /// The HMAC algorithm the sample was packed with
+    pub hmac: HMAC,
+    /// Speed at which the sample was packed, in MiB/s
+    pub throughput: f64,
+    /// Size of the packed sample as a fraction of its original size
+    ///
+    /// Lower is better, 1.0 indicates that the data did not shrink at all.
+    pub ratio: f64,
+}
+
+impl TuneResult {
+    /// Fraction of the sample saved by these settings
+    pub fn savings(&self) -> f64 {
+        (1.0 - self.ratio).max(0.0)
+    }
+
+    /// Balances throughput against the space saved, as the rate at which space is saved in MiB/s
+    ///
+    /// Settings that do not save at least `MIN_SAVINGS` score zero.
+    pub fn score(&self) -> f64 {
+        if self.savings() < MIN_SAVINGS {
+            0.0
+        } else {
+            self.throughput * self.savings()
+        }
+    }
+}
+
+/// The results of benchmarking every combination of settings, along with the recommended one
+#[derive(Debug, Clone, PartialEq)]
+pub struct TuneReport {
+    /// The result for each combination of settings, in the order they were tried
+    pub results: Vec<TuneResult>,
+    /// The settings recommended for data resembling the sample
+    pub recommendation: ChunkSettings,
+}
+
+/// Reads up to `limit` bytes of sample data from a file, or from the files under a directory
+///
+/// Files are read in the order they are encountered, so a directory sample is biased towards
+/// whichever files happen to be walked first.
+pub fn read_sample(path: impl AsRef<Path>, limit: usize) -> io::Result<Vec<u8>> {
+    let mut sample = Vec::new();
+    for entry in WalkDir::new(path).sort_by(|a, b| a.file_name().cmp(b.file_name())) {
+        if sample.len() >= limit {
+            break;
+        }
+        let entry = entry?;
+        if entry.file_type().is_file() {
+            let remaining = (limit - sample.len()) as u64;
+            File::open(entry.path())?
+                .take(remaining)
+                .read_to_end(&mut sample)?;
+        }
+    }
+    Ok(sample)
+}
+
+/// Chunks the sample with the default chunker and packs every chunk with the given settings,
+/// timing the packing
+///
+/// Only the packing is timed, the chunking is done beforehand.
+#[allow(clippy::cast_precision_loss)]
+pub fn bench_settings(
+    sample: &[u8],
+    compression: Compression,
+    encryption: Encryption,
+    hmac: HMAC,
+) -> TuneResult {
+    let key = Key::random(encryption.key_length());
+    let chunks = FastCDC::default()
+        .chunk_borrowed(sample)
+        .collect::<Result<Vec<_>, _>>()
+        .expect("Chunking an in-memory slice can not fail");
+    let mut stored = 0;
+    let start = Instant::now();
+    for chunk in chunks {
+        let packed = Chunk::pack(chunk.to_vec(), compression, encryption, hmac, &key);
+        stored += packed.get_bytes().len();
+    }
+    let elapsed = start.elapsed().as_secs_f64();
+    TuneResult {
+        compression,
+        hmac,
+        // Guard against a timer too coarse to see a very small input
+        throughput: (sample.len() as f64 / ONE_MIB) / elapsed.max(f64::EPSILON),
+        ratio: if sample.is_empty() {
+            1.0
+        } else {
+            stored as f64 / sample.len() as f64
+        },
+    }
+}
+
+/// Benchmarks every combination of `tune_compressions` and `tune_hmacs` on the sample, and
+/// recommends the settings that best balance throughput against the space saved
+///
+/// The combination with the highest `TuneResult::score` is recommended. If no compression
+/// algorithm saves at least `MIN_SAVINGS` of the sample, the data is considered incompressible,
+/// and no compression is recommended, with the fastest HMAC.
+///
+/// # Panics
+///
+/// Will panic if support for no HMAC algorithm at all was compiled in
+pub fn auto_tune(sample: &[u8], encryption: Encryption) -> TuneReport {
+    let mut results = Vec::new();
+    for compression in tune_compressions() {
+        for hmac in tune_hmacs() {
+            results.push(bench_settings(sample, compression, encryption, hmac));
+        }
+    }
+    let best_compressed = results
+        .iter()
+        .filter(|x| x.score() > 0.0)
+        .max_by(|a, b| a.score().partial_cmp(&b.score()).unwrap());
+    let best = best_compressed.unwrap_or_else(|| {
+        results
+            .iter()
+            .filter(|x| x.compression == Compression::NoCompression)
+            .max_by(|a, b| a.throughput.partial_cmp(&b.throughput).unwrap())
+            .expect("No compression is always benchmarked, with at least one HMAC")
+    });
+    let recommendation = ChunkSettings {
+        compression: best.compression,
+        encryption,
+        hmac: best.hmac,
+    };
+    TuneReport {
+        results,
+        recommendation,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rand::prelude::*;
+    use rand::rngs::StdRng;
+
+    const SAMPLE_SIZE: usize = 256 * 1024;
+
+    #[test]
+    fn compressible_sample_recommends_compression() {
+        let line = b"Lorem ipsum dolor sit amet, consectetur adipiscing elit.\n";
+        let sample: Vec<u8> = line.iter().copied().cycle().take(SAMPLE_SIZE).collect();
+        let report = auto_tune(&sample, Encryption::new_aes256ctr());
+        assert_eq!(
+            report.results.len(),
+            tune_compressions().len() * tune_hmacs().len()
+        );
+        assert_ne!(
+            report.recommendation.compression,
+            Compression::NoCompression
+        );
+        assert!(report.recommendation.validate().is_ok());
+    }
+
+    #[test]
+    fn incompressible_sample_recommends_none() {
+        let mut rng = StdRng::seed_from_u64(0);
+        let mut sample = vec![0_u8; SAMPLE_SIZE];
+        rng.fill_bytes(&mut sample);
+        let report = auto_tune(&sample, Encryption::new_aes256ctr());
+        assert_eq!(
+            report.recommendation.compression,
+            Compression::NoCompression
+        );
+        assert!(report.recommendation.validate().is_ok());
+    }
+}
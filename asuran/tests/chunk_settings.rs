@@ -0,0 +1,52 @@
+use asuran::prelude::*;
+use tempfile::tempdir;
+
+mod common;
+
+// Chunks must be unpacked with the settings recorded in their own headers, not with whatever the
+// repository's defaults happen to be at read time
+#[test]
+fn mixed_compression_restores() {
+    smol::run(async {
+        let directory = tempdir().unwrap();
+        let path = directory.path().to_str().unwrap();
+        let key = Key::random(32);
+        let zstd_data = b"Stored with the repository's default compression".repeat(64);
+        let lz4_data = b"Stored with an overridden compression".repeat(64);
+
+        let mut repo = common::get_repo_bare(path, key.clone()).await;
+        let (zstd_id, _) = repo.write_chunk(zstd_data.clone()).await.unwrap();
+        let lz4 = ChunkSettings {
+            compression: Compression::LZ4 { level: 4 },
+            ..repo.chunk_settings()
+        };
+        let (lz4_id, _) = repo
+            .write_chunk_with_settings(lz4_data.clone(), lz4)
+            .await
+            .unwrap();
+        repo.commit_index().await.unwrap();
+        repo.close().await;
+
+        // Reopen the repository with a default compression matching neither chunk
+        let settings = ChunkSettings {
+            compression: Compression::LZMA { level: 6 },
+            hmac: HMAC::Blake2b,
+            encryption: Encryption::new_aes256ctr(),
+        };
+        let backend = MultiFile::open_defaults(path, Some(settings), &key, 4)
+            .await
+            .unwrap();
+        let mut repo = Repository::with(backend, settings, key, 2).unwrap();
+        assert_eq!(
+            repo.chunk_info(zstd_id).await.unwrap().compression,
+            Compression::ZStd { level: 1 }
+        );
+        assert_eq!(
+            repo.chunk_info(lz4_id).await.unwrap().compression,
+            Compression::LZ4 { level: 4 }
+        );
+        assert_eq!(repo.read_chunk(zstd_id).await.unwrap(), zstd_data);
+        assert_eq!(repo.read_chunk(lz4_id).await.unwrap(), lz4_data);
+        repo.close().await;
+    });
+}
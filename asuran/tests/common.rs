@@ -19,7 +19,7 @@ pub fn get_repo_mem(key: Key) -> Repository<impl BackendClone> {
         encryption: Encryption::new_aes256ctr(),
     };
     let backend = asuran::repository::backend::mem::Mem::new(settings, key.clone(), 4);
-    Repository::with(backend, settings, key, 2)
+    Repository::with(backend, settings, key, 2).unwrap()
 }
 
 #[allow(dead_code)]
@@ -37,7 +37,7 @@ pub async fn get_repo_bare(path: &str, key: Key) -> Repository<impl BackendClone
     )
     .await
     .unwrap();
-    Repository::with(backend, settings, key, 2)
+    Repository::with(backend, settings, key, 2).unwrap()
 }
 
 #[allow(dead_code)]
@@ -55,7 +55,7 @@ pub fn get_repo_flat(
         4,
     )
     .unwrap();
-    Repository::with(backend, settings, key, 2)
+    Repository::with(backend, settings, key, 2).unwrap()
 }
 
 #[allow(dead_code)]
@@ -85,9 +85,10 @@ pub fn get_sftp_repo(path: impl AsRef<Path>, key: Key) -> Repository<impl Backen
         port: Some(port),
         password: Some(password),
         path: String::from(path.to_string_lossy()),
+        ..SFTPSettings::default()
     };
     let handle =
         SFTP::connect(settings, key.clone(), Some(ChunkSettings::lightweight()), 2).unwrap();
 
-    Repository::with(handle, ChunkSettings::lightweight(), key, 2)
+    Repository::with(handle, ChunkSettings::lightweight(), key, 2).unwrap()
 }
@@ -0,0 +1,54 @@
+use asuran::facade::*;
+use tempfile::tempdir;
+
+use std::fs;
+
+#[test]
+fn backup_restore_facade() {
+    smol::run(async {
+        let input_tempdir = tempdir().unwrap();
+        let input_dir = input_tempdir.path();
+        fs::create_dir_all(input_dir.join("nested/deeper")).unwrap();
+        fs::write(input_dir.join("top"), b"Some data at the top").unwrap();
+        fs::write(input_dir.join("nested/middle"), vec![7_u8; 100_000]).unwrap();
+        fs::write(
+            input_dir.join("nested/deeper/bottom"),
+            b"Some data at the bottom",
+        )
+        .unwrap();
+
+        let repo_root = tempdir().unwrap();
+        let repo_path = repo_root.path().join("repo");
+        let password = b"A Very Strong Password";
+
+        let mut asuran = Asuran::create(&repo_path, password, AsuranOptions::default())
+            .await
+            .unwrap();
+        asuran.backup("test", input_dir).await.unwrap();
+        asuran.close().await;
+
+        // Creating over the top of an existing repository must fail
+        assert!(matches!(
+            Asuran::create(&repo_path, password, AsuranOptions::default()).await,
+            Err(AsuranError::AlreadyExists(_))
+        ));
+
+        let mut asuran = Asuran::open(&repo_path, password, AsuranOptions::default())
+            .await
+            .unwrap();
+        let archives = asuran.list().await.unwrap();
+        assert_eq!(archives.len(), 1);
+        assert_eq!(archives[0].name, "test");
+
+        let output_tempdir = tempdir().unwrap();
+        let output_dir = output_tempdir.path();
+        asuran.restore("test", output_dir).await.unwrap();
+        assert!(!dir_diff::is_different(input_dir, output_dir).unwrap());
+
+        assert!(matches!(
+            asuran.restore("missing", output_dir).await,
+            Err(AsuranError::ArchiveNotFound(_))
+        ));
+        asuran.close().await;
+    });
+}
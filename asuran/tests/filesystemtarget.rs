@@ -39,7 +39,7 @@ fn backup_restore_no_empty_dirs_filesystem() {
 
         let mut manifest = Manifest::load(&mut repo);
         manifest.commit_archive(&mut repo, archive).await.unwrap();
-        repo.commit_index().await;
+        repo.commit_index().await.unwrap();
 
         let mut manifest = Manifest::load(&mut repo);
         let stored_archive = &manifest.archives().await[0];
@@ -97,7 +97,7 @@ fn backup_restore_no_empty_dirs_flatfile() {
 
         let mut manifest = Manifest::load(&mut repo);
         manifest.commit_archive(&mut repo, archive).await.unwrap();
-        repo.commit_index().await;
+        repo.commit_index().await.unwrap();
 
         repo.close().await;
 
@@ -153,7 +153,7 @@ fn backup_restore_no_empty_dirs_mem() {
 
         let mut manifest = Manifest::load(&mut repo);
         manifest.commit_archive(&mut repo, archive).await.unwrap();
-        repo.commit_index().await;
+        repo.commit_index().await.unwrap();
 
         let mut manifest = Manifest::load(&mut repo);
         let stored_archive = &manifest.archives().await[0];
@@ -206,7 +206,7 @@ fn backup_restore_no_empty_dirs_sftp() {
 
         let mut manifest = Manifest::load(&mut repo);
         manifest.commit_archive(&mut repo, archive).await.unwrap();
-        repo.commit_index().await;
+        repo.commit_index().await.unwrap();
 
         repo.close().await;
         let mut repo = common::get_sftp_repo("backup_restore_no_empty_dirs", key.clone());
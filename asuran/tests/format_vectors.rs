@@ -0,0 +1,189 @@
+//! Golden vectors for the on-disk formats
+//!
+//! Each test serializes a fixed instance of a type that ends up on disk, exactly as the
+//! repository would, and compares the result against the bytes checked in under
+//! `tests/vectors`. Any change to the serialized form of these types makes existing repositories
+//! unreadable, or silently changes their contents, so it must fail here rather than slip through.
+//!
+//! # Regenerating
+//!
+//! If a format change is intentional, bump `FORMAT_VERSION` or the relevant version marker, then
+//! rewrite the vectors with:
+//!
+//! ```text
+//! ASURAN_REGENERATE_VECTORS=1 cargo test -p asuran --test format_vectors
+//! ```
+//!
+//! Run the tests again without the variable, and commit the changed files in `tests/vectors` in
+//! the same commit as the format change. Only the vectors for the types that were meant to change
+//! should show up in the diff.
+use asuran::manifest::archive::{Archive, ChunkLocation, Extent};
+use asuran::repository::backend::common::manifest::ManifestTransaction;
+use asuran::repository::*;
+use asuran_core::manifest::listing::{Listing, Node, NodeType};
+use asuran_core::repository::backend::flatfile::EntryFooterData;
+
+use chrono::prelude::*;
+use serde::Serialize;
+
+use std::collections::BTreeMap;
+use std::env;
+use std::fs;
+use std::path::PathBuf;
+
+/// Returns the path of the named vector
+fn vector_path(name: &str) -> PathBuf {
+    PathBuf::from(env!("CARGO_MANIFEST_DIR"))
+        .join("tests")
+        .join("vectors")
+        .join(name)
+}
+
+/// Compares the bytes against the named vector, first overwriting it if
+/// `ASURAN_REGENERATE_VECTORS` is set
+fn check_vector(name: &str, bytes: &[u8]) {
+    let path = vector_path(name);
+    if env::var_os("ASURAN_REGENERATE_VECTORS").is_some() {
+        fs::write(&path, bytes).expect("Unable to write vector");
+    }
+    let expected = fs::read(&path).expect("Unable to read vector");
+    assert!(
+        expected == bytes,
+        "Serialized form of {} no longer matches its vector, see the docs of this file if the \
+         change is intentional",
+        name
+    );
+}
+
+/// Serializes a value the same way the repository does when writing it to disk
+fn serialize(value: &impl Serialize) -> Vec<u8> {
+    serde_cbor::ser::to_vec(value).unwrap()
+}
+
+fn timestamp() -> DateTime<FixedOffset> {
+    DateTime::parse_from_rfc3339("2020-01-01T00:00:00+00:00").unwrap()
+}
+
+fn first_id() -> ChunkID {
+    let bytes: Vec<u8> = (1..=32).collect();
+    ChunkID::new(&bytes)
+}
+
+fn second_id() -> ChunkID {
+    ChunkID::new(&[0xBB; 32])
+}
+
+fn chunk_header() -> ChunkHeader {
+    Chunk::from_parts(
+        Vec::new(),
+        Compression::ZStd { level: 3 },
+        Encryption::AES256CTR { iv: [7; 16] },
+        HMAC::Blake3,
+        vec![0xAB; 32],
+        first_id(),
+    )
+    .split()
+    .0
+}
+
+#[test]
+fn chunk_header_vector() {
+    check_vector("chunk_header.cbor", &serialize(&chunk_header()));
+}
+
+#[test]
+fn entry_footer_data_vector() {
+    let mut footer = EntryFooterData::new(ChunkSettings {
+        compression: Compression::NoCompression,
+        encryption: Encryption::NoEncryption,
+        hmac: HMAC::SHA256,
+    });
+    footer.add_chunk(first_id(), 128, 4096);
+    footer.add_header(first_id(), chunk_header());
+    footer.add_archive(second_id(), timestamp());
+    footer.remove_chunk(second_id());
+    check_vector("entry_footer_data.cbor", &serialize(&footer));
+}
+
+#[test]
+fn archive_vector() {
+    let mut objects = BTreeMap::new();
+    objects.insert(
+        "dir/file".to_string(),
+        vec![ChunkLocation {
+            id: first_id(),
+            start: 0,
+            length: 4096,
+        }],
+    );
+    let mut listing = Listing::default();
+    listing.add_child(
+        "",
+        Node {
+            path: "dir".to_string(),
+            total_length: 0,
+            total_size: 0,
+            extents: None,
+            node_type: NodeType::Directory {
+                children: Vec::new(),
+            },
+            content_type: None,
+        },
+    );
+    listing.add_child(
+        "dir",
+        Node {
+            path: "dir/file".to_string(),
+            total_length: 4096,
+            total_size: 4096,
+            extents: Some(vec![Extent {
+                start: 0,
+                end: 4095,
+            }]),
+            node_type: NodeType::File,
+            content_type: Some("text/plain".to_string()),
+        },
+    );
+    listing.add_child(
+        "",
+        Node {
+            path: "small".to_string(),
+            total_length: 5,
+            total_size: 5,
+            extents: None,
+            node_type: NodeType::InlineFile {
+                data: b"hello".to_vec(),
+            },
+            content_type: None,
+        },
+    );
+    let archive = Archive {
+        name: "vectors".to_string(),
+        objects,
+        objects_chunk: None,
+        namespace: vec!["ns".to_string()],
+        timestamp: timestamp(),
+        listing,
+        tags: vec!["daily".to_string(), "home".to_string()],
+        comment: Some("golden vector".to_string()),
+    };
+    check_vector("archive.cbor", &serialize(&archive));
+}
+
+// Transactions always carry a random nonce, so this vector can not be built from scratch.
+// Instead, it must decode to the expected transaction, with a valid tag, and encode back to
+// exactly the same bytes.
+#[test]
+fn manifest_transaction_vector() {
+    let bytes = fs::read(vector_path("manifest_transaction.cbor")).unwrap();
+    let transaction: ManifestTransaction = serde_cbor::de::from_slice(&bytes).unwrap();
+    let key_bytes: Vec<u8> = (0..96).collect();
+    let key = Key::from_bytes(&key_bytes, 0);
+
+    assert_eq!(transaction.pointer(), second_id());
+    assert_eq!(transaction.timestamp(), timestamp());
+    assert_eq!(transaction.previous_heads().len(), 1);
+    assert_eq!(transaction.accessed(), None);
+    assert!(transaction.verify(&key));
+    check_vector("manifest_transaction.cbor", &serialize(&transaction));
+}
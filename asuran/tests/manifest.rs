@@ -65,6 +65,194 @@ fn put_drop_get_multifile() {
     });
 }
 
+// Every archive copied out of a Mem repository into a FlatFile must come out with the same name,
+// timestamp, and contents
+#[test]
+fn copy_archives_mem_to_flatfile() {
+    smol::run(async {
+        let key = Key::random(32);
+        let mut source = common::get_repo_mem(key);
+        let chunker = FastCDC::default();
+
+        // One object is shared between both archives, to exercise deduplication on the way over
+        let mut shared = vec![0_u8; 16384];
+        thread_rng().fill_bytes(&mut shared);
+        let mut objects: Vec<Vec<Vec<u8>>> = Vec::new();
+        let mut source_manifest = Manifest::load(&mut source);
+        for name in &["first", "second"] {
+            let mut archive_objects = vec![shared.clone()];
+            for _ in 0..3 {
+                let mut object = vec![0_u8; 16384];
+                thread_rng().fill_bytes(&mut object);
+                archive_objects.push(object);
+            }
+            let mut archive = ActiveArchive::new(name);
+            for (i, object) in archive_objects.iter().enumerate() {
+                archive
+                    .put_object(
+                        &chunker,
+                        &mut source,
+                        &i.to_string(),
+                        Cursor::new(object.clone()),
+                    )
+                    .await
+                    .unwrap();
+            }
+            source_manifest
+                .commit_archive(&mut source, archive)
+                .await
+                .unwrap();
+            objects.push(archive_objects);
+        }
+
+        let tempdir = tempdir().unwrap();
+        let path = tempdir.path().join("copy.asuran");
+        let dest_key = Key::random(32);
+        let enc_key = EncryptedKey::encrypt(&dest_key, 512, 1, Encryption::new_aes256ctr(), b"");
+        let mut dest = common::get_repo_flat(&path, dest_key.clone(), Some(enc_key));
+        let mut dest_manifest = Manifest::load(&mut dest);
+        let source_archives = source_manifest.archives().await;
+        for archive in &source_archives {
+            dest_manifest
+                .copy_archive(&mut dest, &mut source, archive)
+                .await
+                .unwrap();
+        }
+        dest.close().await;
+
+        // Reopen the destination from disk, and check every archive against its original
+        let mut dest = common::get_repo_flat(&path, dest_key, None);
+        let mut dest_manifest = Manifest::load(&mut dest);
+        let dest_archives = dest_manifest.archives().await;
+        assert_eq!(dest_archives.len(), source_archives.len());
+        for dest_archive in &dest_archives {
+            let copy = dest_archive.load(&mut dest).await.unwrap();
+            let index = ["first", "second"]
+                .iter()
+                .position(|x| *x == copy.name())
+                .unwrap();
+            let mut original = None;
+            for source_archive in &source_archives {
+                let archive = source_archive.load(&mut source).await.unwrap();
+                if archive.name() == copy.name() {
+                    original = Some(source_archive);
+                }
+            }
+            assert_eq!(original.unwrap().timestamp(), dest_archive.timestamp());
+            for (i, object) in objects[index].iter().enumerate() {
+                let mut buffer = Cursor::new(Vec::<u8>::new());
+                copy.get_object(&mut dest, &i.to_string(), &mut buffer)
+                    .await
+                    .unwrap();
+                assert_eq!(object, &buffer.into_inner());
+            }
+        }
+        dest.close().await;
+    });
+}
+
+// Repositories sharing a key get byte for byte copies of each chunk, under the same ids
+#[test]
+fn copy_archive_same_key() {
+    smol::run(async {
+        let key = Key::random(32);
+        let mut source = common::get_repo_mem(key.clone());
+        let mut object = vec![0_u8; 16384];
+        thread_rng().fill_bytes(&mut object);
+        let mut archive = ActiveArchive::new("raw");
+        archive
+            .put_object(
+                &FastCDC::default(),
+                &mut source,
+                "object",
+                Cursor::new(object.clone()),
+            )
+            .await
+            .unwrap();
+        let ids = archive.chunk_ids();
+        let mut source_manifest = Manifest::load(&mut source);
+        source_manifest
+            .commit_archive(&mut source, archive)
+            .await
+            .unwrap();
+
+        let mut dest = common::get_repo_mem(key);
+        let mut dest_manifest = Manifest::load(&mut dest);
+        let stored = source_manifest.archives().await.remove(0);
+        let copy = dest_manifest
+            .copy_archive(&mut dest, &mut source, &stored)
+            .await
+            .unwrap();
+        let copy = copy.load(&mut dest).await.unwrap();
+        assert_eq!(copy.chunk_ids(), ids);
+        for id in ids {
+            assert_eq!(
+                dest.read_chunk_raw(id).await.unwrap().1,
+                source.read_chunk_raw(id).await.unwrap().1
+            );
+        }
+        let mut buffer = Cursor::new(Vec::<u8>::new());
+        copy.get_object(&mut dest, "object", &mut buffer)
+            .await
+            .unwrap();
+        assert_eq!(object, buffer.into_inner());
+    });
+}
+
+// Sealed archives can only be copied between repositories sharing a key, and stay sealed
+#[cfg(feature = "recipients")]
+#[test]
+fn copy_sealed_archive() {
+    use asuran::repository::encryption::SecretKey;
+    smol::run(async {
+        let key = Key::random(32);
+        let identity = SecretKey::random();
+        let mut source = common::get_repo_mem(key.clone());
+        let mut object = vec![0_u8; 16384];
+        thread_rng().fill_bytes(&mut object);
+        let mut archive = ActiveArchive::new("sealed");
+        archive
+            .put_object(
+                &FastCDC::default(),
+                &mut source,
+                "object",
+                Cursor::new(object.clone()),
+            )
+            .await
+            .unwrap();
+        let mut source_manifest = Manifest::load(&mut source);
+        source_manifest
+            .commit_archive_for(&mut source, archive, &[identity.public_key()])
+            .await
+            .unwrap();
+        let stored = source_manifest.archives().await.remove(0);
+
+        let mut other = common::get_repo_mem(Key::random(32));
+        let mut other_manifest = Manifest::load(&mut other);
+        assert!(matches!(
+            other_manifest
+                .copy_archive(&mut other, &mut source, &stored)
+                .await,
+            Err(ArchiveError::Sealed)
+        ));
+        assert!(other_manifest.archives().await.is_empty());
+
+        let mut dest = common::get_repo_mem(key);
+        let mut dest_manifest = Manifest::load(&mut dest);
+        let copy = dest_manifest
+            .copy_archive(&mut dest, &mut source, &stored)
+            .await
+            .unwrap();
+        assert!(copy.is_sealed(&mut dest).await.unwrap());
+        let copy = copy.load_as(&mut dest, &identity).await.unwrap();
+        let mut buffer = Cursor::new(Vec::<u8>::new());
+        copy.get_object(&mut dest, "object", &mut buffer)
+            .await
+            .unwrap();
+        assert_eq!(object, buffer.into_inner());
+    });
+}
+
 #[test]
 fn put_drop_get_mem() {
     smol::run(async {
@@ -0,0 +1,47 @@
+//! Tests run in the browser, with the wasm feature
+//!
+//! Run with `wasm-pack test --headless --firefox -- --no-default-features --features
+//! wasm,aes-family,all-hmac --test wasm` from the asuran directory.
+#![cfg(target_arch = "wasm32")]
+use asuran::chunker::*;
+use asuran::manifest::*;
+use asuran::repository::backend::mem::Mem;
+use asuran::repository::*;
+use rand::prelude::*;
+use std::io::Cursor;
+use wasm_bindgen_test::*;
+
+wasm_bindgen_test_configure!(run_in_browser);
+
+#[wasm_bindgen_test]
+async fn put_get_mem() {
+    let key = Key::random(32);
+    let settings = ChunkSettings {
+        compression: Compression::NoCompression,
+        hmac: HMAC::Blake3,
+        encryption: Encryption::new_aes256ctr(),
+    };
+    let backend = Mem::new(settings, key.clone(), 4);
+    let mut repo = Repository::with(backend, settings, key, 2).unwrap();
+    let chunker = FastCDC::default();
+
+    let mut object = vec![0_u8; 16384];
+    thread_rng().fill_bytes(&mut object);
+
+    let mut manifest = Manifest::load(&mut repo);
+    let mut archive = ActiveArchive::new("test");
+    archive
+        .put_object(&chunker, &mut repo, "object", Cursor::new(object.clone()))
+        .await
+        .unwrap();
+    manifest.commit_archive(&mut repo, archive).await.unwrap();
+
+    let archive = manifest.archives().await[0].load(&mut repo).await.unwrap();
+    let mut buffer = Cursor::new(Vec::<u8>::new());
+    archive
+        .get_object(&mut repo, "object", &mut buffer)
+        .await
+        .unwrap();
+    assert_eq!(object, buffer.into_inner());
+    repo.close().await;
+}